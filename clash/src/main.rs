@@ -10,6 +10,7 @@ struct Cli {
     #[clap(short, long, value_parser, value_name = "DIRECTORY")]
     directory: Option<PathBuf>,
 
+    /// a file path, an http(s) url, or `-` to read from stdin
     #[clap(
         short,
         long,
@@ -17,28 +18,73 @@ struct Cli {
         value_name = "FILE",
         default_value = "config.yaml"
     )]
-    config: PathBuf,
+    config: String,
+
+    /// extra header sent when fetching the config from a url, e.g.
+    /// `Authorization: Bearer xxx`, can be repeated
+    #[clap(short = 'H', long = "header", value_name = "HEADER")]
+    headers: Vec<String>,
+
+    /// check every outbound protocol against its reference server in
+    /// docker, then exit
+    #[cfg(feature = "conformance")]
+    #[clap(long)]
+    conformance: bool,
 }
 
 fn main() {
     let cli = Cli::parse();
-    let file = cli
-        .directory
-        .as_ref()
-        .unwrap_or(&std::env::current_dir().unwrap())
-        .join(cli.config)
-        .to_string_lossy()
-        .to_string();
-
-    if !Path::new(&file).exists() {
-        panic!("config file not found: {}", file);
+
+    #[cfg(feature = "conformance")]
+    {
+        if cli.conformance {
+            let reports = clash::conformance::run_all(&clash::conformance::builtin()).unwrap();
+            for report in reports.iter() {
+                println!("{}", report);
+            }
+            std::process::exit(if reports.iter().all(|x| x.passed()) {
+                0
+            } else {
+                1
+            });
+        }
     }
 
+    let config = if cli.config == "-" {
+        clash::Config::Stdin
+    } else if cli.config.starts_with("http://") || cli.config.starts_with("https://") {
+        clash::Config::Url(cli.config)
+    } else {
+        let file = cli
+            .directory
+            .as_ref()
+            .unwrap_or(&std::env::current_dir().unwrap())
+            .join(cli.config)
+            .to_string_lossy()
+            .to_string();
+
+        if !Path::new(&file).exists() {
+            panic!("config file not found: {}", file);
+        }
+        clash::Config::File(file)
+    };
+
+    let config_headers = cli
+        .headers
+        .iter()
+        .map(|x| match x.split_once(':') {
+            Some((k, v)) => (k.trim().to_owned(), v.trim().to_owned()),
+            None => panic!("invalid header: {}", x),
+        })
+        .collect();
+
     clash::start(clash::Options {
-        config: clash::Config::File(file),
+        config,
         cwd: cli.directory.map(|x| x.to_string_lossy().to_string()),
         rt: Some(TokioRuntime::MultiThread),
         log_file: None,
+        config_headers,
+        observers: vec![],
     })
     .unwrap();
 }
@@ -0,0 +1,141 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::State,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::app::{
+    api::AppState,
+    outbound::manager::ThreadSafeOutboundManager,
+    profile::{Db, ThreadSafeCacheFile},
+    remote_content_manager::providers::{Provider, ProviderVehicleType},
+    router::ThreadSafeRouter,
+};
+
+#[derive(Clone)]
+struct CacheState {
+    cache_store: ThreadSafeCacheFile,
+    outbound_manager: ThreadSafeOutboundManager,
+    router: ThreadSafeRouter,
+}
+
+pub fn routes(
+    cache_store: ThreadSafeCacheFile,
+    outbound_manager: ThreadSafeOutboundManager,
+    router: ThreadSafeRouter,
+) -> Router<Arc<AppState>> {
+    let state = CacheState {
+        cache_store,
+        outbound_manager,
+        router,
+    };
+    Router::new()
+        .route("/export", get(export_cache))
+        .route("/import", post(import_cache))
+        .with_state(state)
+}
+
+/// what `/cache/export` returns and `/cache/import` takes
+#[derive(Serialize, Deserialize)]
+struct Export {
+    #[serde(flatten)]
+    db: Db,
+    /// the proxy and rule providers, by name
+    #[serde(default)]
+    providers: HashMap<String, ProviderMeta>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProviderMeta {
+    #[serde(rename = "type")]
+    typ: String,
+    vehicle_type: String,
+    /// when the content was last fetched, none for the inline providers
+    updated_at: Option<DateTime<Utc>>,
+}
+
+impl ProviderMeta {
+    async fn of(p: &(dyn Provider + Send + Sync)) -> Self {
+        Self {
+            typ: p.typ().to_string(),
+            vehicle_type: p.vehicle_type().to_string(),
+            updated_at: p.updated_at().await,
+        }
+    }
+}
+
+/// the selected proxies, fake-ip mappings and provider metadata, to carry
+/// over to another device
+async fn export_cache(State(state): State<CacheState>) -> impl IntoResponse {
+    let mut providers = HashMap::new();
+    for (name, p) in state.outbound_manager.get_proxy_providers() {
+        let p = p.read().await;
+        providers.insert(name, ProviderMeta::of(&*p).await);
+    }
+    for (name, p) in state.router.get_rule_providers() {
+        providers.insert(name.clone(), ProviderMeta::of(p.as_ref()).await);
+    }
+
+    Json(Export {
+        db: state.cache_store.export().await,
+        providers,
+    })
+}
+
+async fn import_cache(
+    State(state): State<CacheState>,
+    Json(export): Json<Export>,
+) -> impl IntoResponse {
+    state.cache_store.import(export.db).await;
+
+    // the selections take effect right away, not only after a restart
+    for (group, server) in state.cache_store.get_selected_map().await {
+        if let Some(ctrl) = state.outbound_manager.get_selector_control(&group) {
+            if let Err(err) = ctrl.lock().await.select(&server).await {
+                warn!("failed to select {} for {}: {}", server, group, err);
+            }
+        }
+    }
+
+    // the HTTP providers fetched later on the other device are fetched
+    // again here, so the selections point at the same proxies
+    let newer = |name: &str, ours: Option<DateTime<Utc>>| {
+        export
+            .providers
+            .get(name)
+            .and_then(|x| x.updated_at)
+            .zip(ours)
+            .is_some_and(|(theirs, ours)| theirs > ours)
+    };
+    for (name, p) in state.outbound_manager.get_proxy_providers() {
+        let p = p.read().await;
+        if matches!(p.vehicle_type(), ProviderVehicleType::Http)
+            && newer(&name, p.updated_at().await)
+        {
+            info!("updating proxy provider {} older than the import", name);
+            if let Err(err) = p.update().await {
+                warn!("failed to update proxy provider {}: {}", name, err);
+            }
+        }
+    }
+    for (name, p) in state.router.get_rule_providers() {
+        if matches!(p.vehicle_type(), ProviderVehicleType::Http)
+            && newer(name, p.updated_at().await)
+        {
+            info!("updating rule provider {} older than the import", name);
+            if let Err(err) = p.update().await {
+                warn!("failed to update rule provider {}: {}", name, err);
+            }
+        }
+    }
+
+    StatusCode::NO_CONTENT
+}
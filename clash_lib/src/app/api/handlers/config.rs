@@ -1,6 +1,11 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
-use axum::{extract::State, response::IntoResponse, routing::get, Json, Router};
+use axum::{
+    extract::State,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
 use http::StatusCode;
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
@@ -13,7 +18,14 @@ use crate::{
         dns::ThreadSafeDNSResolver,
         inbound::manager::{Ports, ThreadSafeInboundManager},
     },
-    config::{def, internal::config::BindAddress},
+    common::offline,
+    config::{
+        def,
+        internal::{
+            config::{BindAddress, Config},
+            diff::{self, ConfigSummary, ListenerKind},
+        },
+    },
     GlobalState,
 };
 
@@ -36,6 +48,7 @@ pub fn routes(
             "/",
             get(get_configs).put(update_configs).patch(patch_configs),
         )
+        .route("/preview", post(preview_configs))
         .with_state(ConfigState {
             inbound_manager,
             dispatcher,
@@ -70,6 +83,7 @@ async fn get_configs(State(state): State<ConfigState>) -> impl IntoResponse {
                 crate::proxy::utils::Interface::Name(iface) => iface != "lo",
             },
         }),
+        offline: Some(offline::is_offline()),
     })
 }
 
@@ -80,6 +94,58 @@ async fn update_configs() -> impl IntoResponse {
     )
 }
 
+#[derive(Deserialize)]
+struct PreviewRequest {
+    path: Option<String>,
+    payload: Option<String>,
+}
+
+/// what reloading the given config would change, nothing is applied
+async fn preview_configs(
+    State(state): State<ConfigState>,
+    Json(req): Json<PreviewRequest>,
+) -> impl IntoResponse {
+    let candidate = match (req.payload, req.path) {
+        (Some(payload), _) => payload.parse::<def::Config>(),
+        (None, Some(path)) => def::Config::try_from(PathBuf::from(path)),
+        (None, None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "either path or payload is required",
+            )
+                .into_response()
+        }
+    };
+    let candidate = match candidate.and_then(Config::try_from) {
+        Ok(c) => ConfigSummary::from(&c),
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+
+    let mut running = state.global_state.lock().await.running_config.clone();
+    // the ports may have been patched since
+    let inbound_manager = state.inbound_manager.lock().await;
+    let ports = inbound_manager.get_ports();
+    running
+        .listeners
+        .retain(|_, kind| *kind == ListenerKind::Tun);
+    running.listeners.extend(diff::listeners(
+        inbound_manager.get_bind_address(),
+        &[
+            ("http", ports.port),
+            ("socks", ports.socks_port),
+            ("redir", ports.redir_port),
+            ("tproxy", ports.tproxy_port),
+            ("mixed", ports.mixed_port),
+        ],
+    ));
+    running.listeners.extend(diff::named_listeners(
+        inbound_manager.get_bind_address(),
+        inbound_manager.get_listeners(),
+    ));
+
+    Json(running.diff(&candidate)).into_response()
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 struct ConfigRequest {
@@ -93,6 +159,7 @@ struct ConfigRequest {
     log_level: Option<def::LogLevel>,
     ipv6: Option<bool>,
     allow_lan: Option<bool>,
+    offline: Option<bool>,
 }
 
 impl ConfigRequest {
@@ -169,5 +236,9 @@ async fn patch_configs(
         state.dns_resolver.set_ipv6(ipv6);
     }
 
+    if let Some(enable) = payload.offline {
+        offline::set(enable);
+    }
+
     StatusCode::ACCEPTED.into_response()
 }
@@ -1,31 +1,39 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use axum::{
     extract::{ws::Message, FromRequest, Path, Query, State, WebSocketUpgrade},
     response::IntoResponse,
-    routing::{delete, get},
+    routing::{delete, get, post},
     Json, Router,
 };
-use http::{HeaderMap, Request};
+use http::{HeaderMap, Request, StatusCode};
 use hyper::{body::HttpBody, Body};
 use serde::Deserialize;
 use tracing::{debug, warn};
 
 use crate::app::{
     api::{handlers::utils::is_request_websocket, AppState},
-    dispatcher::StatisticsManager,
+    dispatcher::{Capture, StatisticsManager},
 };
 
 #[derive(Clone)]
 struct ConnectionState {
     statistics_manager: Arc<StatisticsManager>,
+    capture_dir: Option<PathBuf>,
 }
 
-pub fn routes(statistics_manager: Arc<StatisticsManager>) -> Router<Arc<AppState>> {
+pub fn routes(
+    statistics_manager: Arc<StatisticsManager>,
+    capture_dir: Option<PathBuf>,
+) -> Router<Arc<AppState>> {
     Router::new()
         .route("/", get(get_connections).delete(close_all_connection))
         .route("/:id", delete(close_connection))
-        .with_state(ConnectionState { statistics_manager })
+        .route("/:id/capture", post(capture_connection))
+        .with_state(ConnectionState {
+            statistics_manager,
+            capture_dir,
+        })
 }
 
 #[derive(Deserialize)]
@@ -91,6 +99,70 @@ async fn close_connection(
     format!("connection {} closed", id).into_response()
 }
 
+#[derive(Deserialize)]
+struct CaptureQuery {
+    /// payload bytes, 10 MiB by default
+    max_bytes: Option<usize>,
+    /// 60 by default
+    seconds: Option<u64>,
+}
+
+async fn capture_connection(
+    State(state): State<ConnectionState>,
+    Path(id): Path<uuid::Uuid>,
+    q: Query<CaptureQuery>,
+) -> impl IntoResponse {
+    let dir = match state.capture_dir {
+        Some(dir) => dir,
+        None => {
+            return (StatusCode::FORBIDDEN, "capture-dir is not configured").into_response();
+        }
+    };
+    let tracker = match state.statistics_manager.tracker_info(id).await {
+        Some(tracker) => tracker,
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                format!("connection {} not found", id),
+            )
+                .into_response();
+        }
+    };
+    if tracker.capture.get().is_some() {
+        return (
+            StatusCode::CONFLICT,
+            format!("connection {} is already captured", id),
+        )
+            .into_response();
+    }
+
+    if let Err(e) = tokio::fs::create_dir_all(&dir).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    let path = dir.join(format!("{}.pcap", id));
+    let capture = match Capture::start(
+        path.clone(),
+        &tracker.session_holder,
+        q.max_bytes.unwrap_or(10 * 1024 * 1024),
+        Duration::from_secs(q.seconds.unwrap_or(60)),
+    )
+    .await
+    {
+        Ok(capture) => capture,
+        Err(e) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    };
+    if tracker.capture.set(capture).is_err() {
+        return (
+            StatusCode::CONFLICT,
+            format!("connection {} is already captured", id),
+        )
+            .into_response();
+    }
+    format!("capturing connection {} to {}", id, path.display()).into_response()
+}
+
 async fn close_all_connection(State(state): State<ConnectionState>) -> impl IntoResponse {
     let mgr = state.statistics_manager;
     mgr.close_all().await;
@@ -1,23 +1,114 @@
-use std::sync::Arc;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
-use axum::{response::IntoResponse, routing::get, Router};
+use axum::{
+    extract::{ws::Message, ConnectInfo, Path, State, WebSocketUpgrade},
+    response::IntoResponse,
+    routing::{get, put},
+    Json, Router,
+};
 use http::StatusCode;
+use hyper::body::HttpBody;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
 
-use crate::app::{api::AppState, dns::ThreadSafeDNSResolver};
+use crate::app::{api::AppState, dispatcher::StatisticsManager, dns::ThreadSafeDNSResolver};
 
 #[derive(Clone)]
 struct DNSState {
-    #[allow(dead_code)]
     resolver: ThreadSafeDNSResolver,
+    statistics_manager: Arc<StatisticsManager>,
 }
 
-pub fn routes(resolver: ThreadSafeDNSResolver) -> Router<Arc<AppState>> {
-    let state = DNSState { resolver };
+pub fn routes(
+    resolver: ThreadSafeDNSResolver,
+    statistics_manager: Arc<StatisticsManager>,
+) -> Router<Arc<AppState>> {
+    let state = DNSState {
+        resolver,
+        statistics_manager,
+    };
     Router::new()
         .route("/dns", get(query_dns))
+        .route("/stats", get(get_stats))
+        .route("/queries", get(stream_queries))
+        .route("/fakeip/skip", get(get_fake_ip_skipped))
+        .route(
+            "/fakeip/skip/:domain",
+            put(add_fake_ip_skipped).delete(remove_fake_ip_skipped),
+        )
         .with_state(state)
 }
 
 async fn query_dns() -> impl IntoResponse {
     StatusCode::NOT_IMPLEMENTED
 }
+
+/// the latency and error rate of each nameserver and the cache hit ratio
+async fn get_stats(State(state): State<DNSState>) -> impl IntoResponse {
+    Json(state.statistics_manager.dns().snapshot())
+}
+
+/// the queries the resolver answers from now on, one JSON message each
+async fn stream_queries(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<DNSState>,
+) -> impl IntoResponse {
+    ws.on_failed_upgrade(move |e| {
+        warn!("ws upgrade error: {} with {}", e, addr);
+    })
+    .on_upgrade(move |mut socket| async move {
+        let mut rx = state.statistics_manager.dns().subscribe();
+        loop {
+            let entry = match rx.recv().await {
+                Ok(entry) => entry,
+                // a slow reader just misses some
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            let res = Json(entry).into_response().data().await.unwrap().unwrap();
+
+            if let Err(e) = socket
+                .send(Message::Text(String::from_utf8(res.to_vec()).unwrap()))
+                .await
+            {
+                warn!("ws send error: {}", e);
+                break;
+            }
+        }
+    })
+}
+
+/// the domains `fake-ip-auto-skip` learned or were added by hand
+async fn get_fake_ip_skipped(State(state): State<DNSState>) -> impl IntoResponse {
+    match state.resolver.fake_ip_auto_skipped().await {
+        Some(domains) => {
+            let mut r = HashMap::new();
+            r.insert("domains", domains);
+            Json(r).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "fake-ip-auto-skip is disabled").into_response(),
+    }
+}
+
+async fn add_fake_ip_skipped(
+    State(state): State<DNSState>,
+    Path(domain): Path<String>,
+) -> impl IntoResponse {
+    set_fake_ip_skipped(state, &domain, true).await
+}
+
+async fn remove_fake_ip_skipped(
+    State(state): State<DNSState>,
+    Path(domain): Path<String>,
+) -> impl IntoResponse {
+    set_fake_ip_skipped(state, &domain, false).await
+}
+
+async fn set_fake_ip_skipped(state: DNSState, domain: &str, skip: bool) -> impl IntoResponse {
+    if state.resolver.set_fake_ip_auto_skipped(domain, skip).await {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "fake-ip-auto-skip is disabled").into_response()
+    }
+}
@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use http::StatusCode;
+
+use crate::app::{api::AppState, health::ThreadSafeHealthReport};
+
+pub fn routes(report: ThreadSafeHealthReport) -> Router<Arc<AppState>> {
+    Router::new().route("/", get(get_health)).with_state(report)
+}
+
+/// 503 until the startup checks are done, and when any of them failed
+async fn get_health(State(report): State<ThreadSafeHealthReport>) -> Response {
+    match report.read().await.as_ref() {
+        Some(report) => {
+            let status = if report.healthy {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            (status, Json(report.clone())).into_response()
+        }
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "health checks are still running",
+        )
+            .into_response(),
+    }
+}
@@ -1,11 +1,15 @@
+pub mod cache;
 pub mod config;
 pub mod connection;
 pub mod dns;
+pub mod health;
 pub mod hello;
 pub mod log;
 pub mod provider;
 pub mod proxy;
 pub mod rule;
 pub mod traffic;
+pub mod upgrade;
+pub mod user;
 mod utils;
 pub mod version;
@@ -15,7 +15,9 @@ use serde::Deserialize;
 use crate::{
     app::{
         api::AppState, outbound::manager::ThreadSafeOutboundManager, profile::ThreadSafeCacheFile,
+        remote_content_manager::unlock::StreamingService,
     },
+    config::internal::proxy::OutboundProxyProtocol,
     proxy::AnyOutboundHandler,
 };
 
@@ -34,12 +36,14 @@ pub fn routes(
         cache_store,
     };
     Router::new()
-        .route("/", get(get_proxies))
+        .route("/", get(get_proxies).post(create_proxy))
         .nest(
             "/:name",
             Router::new()
-                .route("/", get(get_proxy).put(update_proxy))
+                .route("/", get(get_proxy).put(update_proxy).delete(delete_proxy))
                 .route("/delay", get(get_proxy_delay))
+                .route("/unlock-test", get(get_proxy_unlock_test))
+                .route("/share-link", get(get_proxy_share_link))
                 .route_layer(middleware::from_fn_with_state(
                     state.clone(),
                     find_proxy_by_name,
@@ -57,6 +61,24 @@ async fn get_proxies(State(state): State<ProxyState>) -> impl IntoResponse {
     axum::response::Json(res)
 }
 
+async fn create_proxy(
+    State(state): State<ProxyState>,
+    Json(payload): Json<HashMap<String, serde_yaml::Value>>,
+) -> impl IntoResponse {
+    let proto = match OutboundProxyProtocol::try_from(payload) {
+        Ok(proto) => proto,
+        Err(err) => return (StatusCode::BAD_REQUEST, format!("invalid proxy: {}", err)),
+    };
+    let name = proto.name().to_owned();
+    match state.outbound_manager.add_proxy(proto).await {
+        Ok(_) => (StatusCode::CREATED, format!("proxy {} created", name)),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            format!("create proxy {} failed with error: {}", name, err),
+        ),
+    }
+}
+
 async fn find_proxy_by_name<B>(
     State(state): State<ProxyState>,
     Path(name): Path<String>,
@@ -120,6 +142,19 @@ async fn update_proxy(
     }
 }
 
+async fn delete_proxy(
+    State(state): State<ProxyState>,
+    Extension(proxy): Extension<AnyOutboundHandler>,
+) -> impl IntoResponse {
+    match state.outbound_manager.remove_proxy(proxy.name()).await {
+        Ok(_) => (StatusCode::NO_CONTENT, String::new()),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            format!("delete proxy {} failed with error: {}", proxy.name(), err),
+        ),
+    }
+}
+
 #[derive(Deserialize)]
 struct DelayRequest {
     url: String,
@@ -150,3 +185,48 @@ async fn get_proxy_delay(
             .into_response(),
     }
 }
+
+#[derive(Deserialize)]
+struct UnlockTestRequest {
+    service: StreamingService,
+    /// in milliseconds
+    timeout: Option<u64>,
+}
+async fn get_proxy_unlock_test(
+    State(state): State<ProxyState>,
+    Extension(proxy): Extension<AnyOutboundHandler>,
+    Query(q): Query<UnlockTestRequest>,
+) -> impl IntoResponse {
+    let timeout = Duration::from_millis(q.timeout.unwrap_or(10000));
+    let n = proxy.name().to_owned();
+    match state
+        .outbound_manager
+        .unlock_test(proxy, q.service, timeout)
+        .await
+    {
+        Ok(result) => axum::response::Json(result).into_response(),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            format!("unlock test for {} failed with error: {}", n, err),
+        )
+            .into_response(),
+    }
+}
+
+/// the link carries the credentials of the proxy, as the config does
+async fn get_proxy_share_link(
+    State(state): State<ProxyState>,
+    Extension(proxy): Extension<AnyOutboundHandler>,
+) -> impl IntoResponse {
+    match state.outbound_manager.share_link(proxy.name()) {
+        Some(link) => (StatusCode::OK, link),
+        None => (
+            StatusCode::NOT_FOUND,
+            format!(
+                "proxy {} has no share link, only the ss, vmess, trojan, anytls and socks5 \
+                 servers of the config have one",
+                proxy.name()
+            ),
+        ),
+    }
+}
@@ -1,8 +1,25 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
-use axum::{extract::State, response::IntoResponse, routing::get, Router};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
 
-use crate::app::{api::AppState, router::ThreadSafeRouter};
+use crate::{
+    app::{
+        api::AppState,
+        router::{RuleMatcher, ThreadSafeRouter},
+    },
+    session::{Inbound, Network, Session, SocksAddr, Type},
+};
 
 #[derive(Clone)]
 struct RuleState {
@@ -12,15 +29,95 @@ struct RuleState {
 pub fn routes(router: ThreadSafeRouter) -> Router<Arc<AppState>> {
     Router::new()
         .route("/", get(get_rules))
+        .route("/trace", get(trace_rules))
         .with_state(RuleState { router })
 }
 
+type RuleMap = HashMap<String, Box<dyn erased_serde::Serialize + Send>>;
+
+#[derive(Serialize)]
+struct Rules {
+    rules: Vec<RuleMap>,
+    #[serde(rename = "sub-rules")]
+    sub_rules: HashMap<String, Vec<RuleMap>>,
+}
+
 async fn get_rules(State(state): State<RuleState>) -> impl IntoResponse {
-    let rules = state.router.get_all_rules();
-    let mut r = HashMap::new();
-    r.insert(
-        "rules",
-        rules.iter().map(|r| r.as_map()).collect::<Vec<_>>(),
-    );
-    axum::response::Json(r)
+    let maps = |rules: &[Box<dyn RuleMatcher>]| -> Vec<RuleMap> {
+        rules.iter().map(|r| r.as_map()).collect()
+    };
+    axum::response::Json(Rules {
+        rules: maps(state.router.get_all_rules()),
+        sub_rules: state
+            .router
+            .get_sub_rules()
+            .iter()
+            .map(|(name, rules)| (name.clone(), maps(rules)))
+            .collect(),
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TraceQuery {
+    /// a domain or an IP
+    host: String,
+    port: u16,
+    /// `tcp` or `udp`, tcp if not given
+    network: Option<String>,
+    /// for the source rules
+    src: Option<SocketAddr>,
+    /// for the `IN-NAME`, `IN-PORT` and `IN-TYPE` rules, the session comes
+    /// from no inbound if not given
+    inbound: Option<String>,
+    in_port: Option<u16>,
+    /// as in `IN-TYPE`, e.g. `SOCKS5`
+    in_type: Option<String>,
+    /// the user the inbound authenticated
+    user: Option<String>,
+    /// for the `UID` rules, rather than the owner of `src`
+    uid: Option<u32>,
+}
+
+/// which rule a session to `host` would be routed by and the rules tried
+/// before it. Domains are resolved as for a connection when a rule needs
+/// the IP
+async fn trace_rules(
+    State(state): State<RuleState>,
+    Query(q): Query<TraceQuery>,
+) -> impl IntoResponse {
+    let network = match q.network.as_deref().map(str::to_lowercase).as_deref() {
+        None | Some("tcp") => Network::Tcp,
+        Some("udp") => Network::Udp,
+        Some(x) => {
+            return (StatusCode::BAD_REQUEST, format!("invalid network: {}", x)).into_response()
+        }
+    };
+    let destination = match q.host.parse::<IpAddr>() {
+        Ok(ip) => SocksAddr::from((ip, q.port)),
+        Err(_) => SocksAddr::Domain(q.host, q.port),
+    };
+    let mut sess = Session {
+        network,
+        destination,
+        source: q.src.unwrap_or(SocketAddr::from(([0, 0, 0, 0], 0))),
+        inbound: Inbound {
+            name: q.inbound.unwrap_or_default(),
+            port: q.in_port,
+            mode: None,
+        },
+        user: q.user,
+        ..Default::default()
+    };
+    if let Some(typ) = q.in_type {
+        match typ.parse::<Type>() {
+            Ok(typ) => sess.typ = typ,
+            Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        }
+    }
+    if let Some(uid) = q.uid {
+        let _ = sess.uid.set(Some(uid));
+    }
+
+    Json(state.router.trace_route(&sess).await).into_response()
 }
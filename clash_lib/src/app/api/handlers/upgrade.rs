@@ -0,0 +1,37 @@
+use std::sync::Arc;
+
+use axum::{extract::State, response::IntoResponse, routing::get, Json, Router};
+use http::StatusCode;
+
+use crate::{
+    app::{api::AppState, updater::Updater},
+    Error,
+};
+
+#[derive(Clone)]
+struct UpgradeState {
+    updater: Arc<Updater>,
+}
+
+pub fn routes(updater: Updater) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(check).post(upgrade))
+        .with_state(UpgradeState {
+            updater: Arc::new(updater),
+        })
+}
+
+async fn check(State(state): State<UpgradeState>) -> impl IntoResponse {
+    match state.updater.check().await {
+        Ok(info) => Json(info).into_response(),
+        Err(e) => (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+    }
+}
+
+async fn upgrade(State(state): State<UpgradeState>) -> impl IntoResponse {
+    match state.updater.upgrade().await {
+        Ok(info) => Json(info).into_response(),
+        Err(e @ Error::InvalidConfig(_)) => (StatusCode::FORBIDDEN, e.to_string()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use http::StatusCode;
+
+use crate::app::{api::AppState, dispatcher::StatisticsManager};
+
+#[derive(Clone)]
+struct UserState {
+    statistics_manager: Arc<StatisticsManager>,
+}
+
+pub fn routes(statistics_manager: Arc<StatisticsManager>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(get_users))
+        .route("/:name/stats", get(get_user_stats).delete(reset_user_stats))
+        .with_state(UserState { statistics_manager })
+}
+
+/// the traffic of each user, by name
+async fn get_users(State(state): State<UserState>) -> impl IntoResponse {
+    Json(state.statistics_manager.user_snapshots())
+}
+
+async fn get_user_stats(
+    State(state): State<UserState>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    match state.statistics_manager.user_snapshots().remove(&name) {
+        Some(s) => Json(s).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("user {} not found", name)).into_response(),
+    }
+}
+
+/// zeroes the counters, which gives the user its quota back
+async fn reset_user_stats(
+    State(state): State<UserState>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    if state.statistics_manager.reset_user(&name) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
@@ -14,8 +14,10 @@ use crate::{config::internal::config::Controller, GlobalState, Runner};
 
 use super::dispatcher::StatisticsManager;
 use super::dns::ThreadSafeDNSResolver;
+use super::health::ThreadSafeHealthReport;
 use super::logging::LogEvent;
 use super::profile::ThreadSafeCacheFile;
+use super::updater::Updater;
 use super::{
     dispatcher, inbound::manager::ThreadSafeInboundManager,
     outbound::manager::ThreadSafeOutboundManager, router::ThreadSafeRouter,
@@ -40,6 +42,7 @@ pub fn get_api_runner(
     statistics_manager: Arc<StatisticsManager>,
     cache_store: ThreadSafeCacheFile,
     router: ThreadSafeRouter,
+    health_report: ThreadSafeHealthReport,
     cwd: String,
 ) -> Option<Runner> {
     if let Some(bind_addr) = controller_cfg.external_controller {
@@ -51,7 +54,13 @@ pub fn get_api_runner(
         let addr = bind_addr.parse().unwrap();
 
         let cors = CorsLayer::new()
-            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH])
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ])
             .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
             .allow_origin(Any);
 
@@ -71,20 +80,40 @@ pub fn get_api_runner(
                         dns_resolver.clone(),
                     ),
                 )
-                .nest("/rules", handlers::rule::routes(router))
+                .nest("/rules", handlers::rule::routes(router.clone()))
                 .nest(
                     "/proxies",
-                    handlers::proxy::routes(outbound_manager.clone(), cache_store),
+                    handlers::proxy::routes(outbound_manager.clone(), cache_store.clone()),
+                )
+                .nest(
+                    "/cache",
+                    handlers::cache::routes(cache_store, outbound_manager.clone(), router),
                 )
                 .nest(
                     "/connections",
-                    handlers::connection::routes(statistics_manager),
+                    handlers::connection::routes(
+                        statistics_manager.clone(),
+                        controller_cfg
+                            .capture_dir
+                            .map(|x| PathBuf::from(&cwd).join(x)),
+                    ),
                 )
                 .nest(
                     "/providers/proxies",
                     handlers::provider::routes(outbound_manager),
                 )
-                .nest("/dns", handlers::dns::routes(dns_resolver))
+                .nest(
+                    "/dns",
+                    handlers::dns::routes(dns_resolver, statistics_manager.clone()),
+                )
+                .nest("/users", handlers::user::routes(statistics_manager))
+                .nest("/health", handlers::health::routes(health_report));
+
+            if let Some(updater) = controller_cfg.updater {
+                app = app.nest("/upgrade", handlers::upgrade::routes(Updater::new(updater)));
+            }
+
+            let mut app = app
                 .route_layer(middlewares::auth::AuthMiddlewareLayer::new(
                     controller_cfg.secret.unwrap_or_default(),
                 ))
@@ -0,0 +1,224 @@
+//! Maps LAN source IPs to device names, from a DHCP lease file
+//! and/or a static mapping, for the `SRC-DEVICE` rule.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use tracing::{debug, warn};
+
+use crate::config::def::{DeviceLeaseFormat, Devices};
+
+pub type ThreadSafeDeviceTable = Arc<DeviceTable>;
+
+struct Lease {
+    ip: IpAddr,
+    mac: Option<String>,
+    hostname: Option<String>,
+}
+
+pub struct DeviceTable {
+    lease_file: Option<(PathBuf, DeviceLeaseFormat)>,
+    interval: Duration,
+    /// static names keyed by IP
+    static_ips: HashMap<IpAddr, String>,
+    /// static names keyed by lower case MAC, applied to leased IPs
+    static_macs: HashMap<String, String>,
+
+    leases: RwLock<HashMap<IpAddr, String>>,
+}
+
+impl DeviceTable {
+    pub fn new(cfg: Devices, cwd: &str) -> Self {
+        let mut static_ips = HashMap::new();
+        let mut static_macs = HashMap::new();
+        for (k, name) in cfg.static_mapping {
+            match k.parse::<IpAddr>() {
+                Ok(ip) => {
+                    static_ips.insert(ip, name);
+                }
+                Err(_) => {
+                    static_macs.insert(k.to_lowercase(), name);
+                }
+            }
+        }
+
+        Self {
+            lease_file: cfg
+                .lease_file
+                .map(|p| (PathBuf::from(cwd).join(p), cfg.lease_format)),
+            interval: Duration::from_secs(cfg.interval.max(1)),
+            static_ips,
+            static_macs,
+            leases: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// the device name for a source IP, static mappings take precedence
+    pub fn lookup(&self, ip: &IpAddr) -> Option<String> {
+        if let Some(name) = self.static_ips.get(ip) {
+            return Some(name.clone());
+        }
+        self.leases.read().unwrap().get(ip).cloned()
+    }
+
+    /// reloads the lease file
+    pub async fn refresh(&self) -> io::Result<()> {
+        let (path, format) = match &self.lease_file {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let content = tokio::fs::read_to_string(path).await?;
+        let leases = match format {
+            DeviceLeaseFormat::Dnsmasq => parse_dnsmasq_leases(&content),
+            DeviceLeaseFormat::Kea => parse_kea_leases(&content),
+        };
+
+        let mut table = HashMap::new();
+        for lease in leases {
+            let name = lease
+                .mac
+                .as_ref()
+                .and_then(|mac| self.static_macs.get(mac).cloned())
+                .or(lease.hostname)
+                .or(lease.mac);
+            if let Some(name) = name {
+                table.insert(lease.ip, name);
+            }
+        }
+
+        debug!(
+            "loaded {} device leases from {}",
+            table.len(),
+            path.display()
+        );
+        *self.leases.write().unwrap() = table;
+        Ok(())
+    }
+
+    /// keeps reloading the lease file in the background
+    pub fn kick_off(self: &Arc<Self>) {
+        if self.lease_file.is_none() {
+            return;
+        }
+
+        let table = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(table.interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = table.refresh().await {
+                    warn!("failed to load device leases: {}", e);
+                }
+            }
+        });
+    }
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    let s = s.trim().trim_end_matches('.');
+    if s.is_empty() || s == "*" {
+        None
+    } else {
+        Some(s.to_owned())
+    }
+}
+
+/// `<expiry> <mac> <ip> <hostname> <client-id>`, hostname is `*` if unknown
+fn parse_dnsmasq_leases(content: &str) -> Vec<Lease> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let _expiry = parts.next()?;
+            let mac = parts.next()?;
+            let ip = parts.next()?.parse().ok()?;
+            let hostname = parts.next().and_then(non_empty);
+            Some(Lease {
+                ip,
+                mac: non_empty(mac).map(|x| x.to_lowercase()),
+                hostname,
+            })
+        })
+        .collect()
+}
+
+/// Kea memfile CSV, columns are located by the header.
+/// only leases in the default state (0) are used
+fn parse_kea_leases(content: &str) -> Vec<Lease> {
+    let mut lines = content.lines();
+    let header: Vec<&str> = match lines.next() {
+        Some(h) => h.split(',').map(str::trim).collect(),
+        None => return vec![],
+    };
+    let col = |name: &str| header.iter().position(|x| *x == name);
+    let (address, hwaddr, hostname, state) = match col("address") {
+        Some(address) => (address, col("hwaddr"), col("hostname"), col("state")),
+        None => return vec![],
+    };
+
+    // later lines override earlier ones for the same address
+    let mut leases: HashMap<IpAddr, Lease> = HashMap::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(',').collect();
+        if let Some(s) = state.and_then(|i| fields.get(i)) {
+            if s.trim() != "0" {
+                continue;
+            }
+        }
+        let ip = match fields.get(address).and_then(|x| x.trim().parse().ok()) {
+            Some(ip) => ip,
+            None => continue,
+        };
+        leases.insert(
+            ip,
+            Lease {
+                ip,
+                mac: hwaddr
+                    .and_then(|i| fields.get(i))
+                    .and_then(|x| non_empty(x))
+                    .map(|x| x.to_lowercase()),
+                hostname: hostname
+                    .and_then(|i| fields.get(i))
+                    .and_then(|x| non_empty(x)),
+            },
+        );
+    }
+    leases.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{parse_dnsmasq_leases, parse_kea_leases};
+
+    #[test]
+    fn test_parse_dnsmasq_leases() {
+        let content = "1700000000 aa:bb:cc:dd:ee:ff 192.168.1.10 phone 01:aa:bb:cc:dd:ee:ff\n\
+                       1700000000 11:22:33:44:55:66 192.168.1.11 * *\n";
+        let leases = parse_dnsmasq_leases(content);
+        assert_eq!(leases.len(), 2);
+        assert_eq!(leases[0].ip, "192.168.1.10".parse::<IpAddr>().unwrap());
+        assert_eq!(leases[0].hostname.as_deref(), Some("phone"));
+        assert_eq!(leases[1].hostname, None);
+        assert_eq!(leases[1].mac.as_deref(), Some("11:22:33:44:55:66"));
+    }
+
+    #[test]
+    fn test_parse_kea_leases() {
+        let content = "address,hwaddr,client_id,valid_lifetime,expire,subnet_id,fqdn_fwd,fqdn_rev,hostname,state,user_context\n\
+                       192.168.1.10,AA:BB:CC:DD:EE:FF,,3600,1700000000,1,0,0,laptop.lan.,0,\n\
+                       192.168.1.11,11:22:33:44:55:66,,3600,1700000000,1,0,0,tv,1,\n";
+        let leases = parse_kea_leases(content);
+        assert_eq!(leases.len(), 1);
+        assert_eq!(leases[0].hostname.as_deref(), Some("laptop.lan"));
+        assert_eq!(leases[0].mac.as_deref(), Some("aa:bb:cc:dd:ee:ff"));
+    }
+}
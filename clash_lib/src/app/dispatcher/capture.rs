@@ -0,0 +1,300 @@
+//! Writes the traffic of one connection, as the inbound sees it before the
+//! outbound encrypts it, to a pcap file for debugging. The packets are made
+//! up from the session addresses, a domain destination shows as the
+//! unspecified address.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+    time::Instant,
+};
+use tracing::{error, info};
+
+use crate::session::{Network, Session};
+
+/// LINKTYPE_RAW, each packet starts with its IPv4 or IPv6 header
+const LINKTYPE_RAW: u32 = 101;
+/// the most payload put in one made up packet
+const MAX_SEGMENT: usize = 16384;
+
+const TCP_PSH_ACK: u8 = 0x18;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+
+pub struct Capture {
+    network: Network,
+    client: SocketAddr,
+    remote: SocketAddr,
+    /// the next TCP sequence numbers of the client and of the remote
+    seq: Mutex<(u32, u32)>,
+    /// payload bytes left to capture
+    left: AtomicUsize,
+    deadline: Instant,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl Capture {
+    /// starts writing to `path` for at most `max_bytes` of payload or
+    /// `duration`, whichever ends first
+    pub async fn start(
+        path: PathBuf,
+        sess: &Session,
+        max_bytes: usize,
+        duration: Duration,
+    ) -> io::Result<Self> {
+        let file = tokio::fs::File::create(&path).await?;
+        let deadline = Instant::now() + duration;
+        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+
+        tokio::spawn(async move {
+            let mut w = BufWriter::new(file);
+            let rv = async {
+                w.write_all(&global_header()).await?;
+                loop {
+                    tokio::select! {
+                        packet = rx.recv() => match packet {
+                            Some(packet) => w.write_all(&packet).await?,
+                            None => break,
+                        },
+                        _ = tokio::time::sleep_until(deadline) => break,
+                    }
+                }
+                w.flush().await
+            }
+            .await;
+            match rv {
+                Ok(_) => info!("capture {} done", path.display()),
+                Err(e) => error!("failed to write capture {}: {}", path.display(), e),
+            }
+        });
+
+        let remote_ip = sess.destination.ip().unwrap_or(match sess.source {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+        Ok(Self {
+            network: sess.network,
+            client: sess.source,
+            remote: SocketAddr::new(remote_ip, sess.destination.port()),
+            seq: Mutex::new((1, 1)),
+            left: AtomicUsize::new(max_bytes),
+            deadline,
+            tx,
+        })
+    }
+
+    /// `upload` for the bytes the client sent
+    pub fn record(&self, upload: bool, data: &[u8]) {
+        if data.is_empty() || Instant::now() >= self.deadline {
+            return;
+        }
+        let taken = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                Some(x.saturating_sub(data.len()))
+            })
+            .unwrap_or_default();
+        let data = &data[..data.len().min(taken)];
+
+        let (src, dst) = match upload {
+            true => (self.client, self.remote),
+            false => (self.remote, self.client),
+        };
+        for chunk in data.chunks(MAX_SEGMENT) {
+            let transport = match self.network {
+                Network::Tcp => {
+                    let mut guard = self.seq.lock().unwrap();
+                    let seq = &mut *guard;
+                    let (ours, theirs) = match upload {
+                        true => (&mut seq.0, seq.1),
+                        false => (&mut seq.1, seq.0),
+                    };
+                    let header = tcp_header(src.port(), dst.port(), *ours, theirs);
+                    *ours = ours.wrapping_add(chunk.len() as u32);
+                    header
+                }
+                Network::Udp => udp_header(src.port(), dst.port(), chunk.len()),
+            };
+            let packet = ip_packet(src.ip(), dst.ip(), self.protocol(), &transport, chunk);
+            if self.tx.send(record(&packet)).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn protocol(&self) -> u8 {
+        match self.network {
+            Network::Tcp => PROTO_TCP,
+            Network::Udp => PROTO_UDP,
+        }
+    }
+}
+
+fn global_header() -> Vec<u8> {
+    let mut h = Vec::with_capacity(24);
+    h.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    h.extend_from_slice(&2u16.to_le_bytes());
+    h.extend_from_slice(&4u16.to_le_bytes());
+    h.extend_from_slice(&0i32.to_le_bytes());
+    h.extend_from_slice(&0u32.to_le_bytes());
+    h.extend_from_slice(&65535u32.to_le_bytes());
+    h.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    h
+}
+
+/// a packet with its pcap record header
+fn record(packet: &[u8]) -> Vec<u8> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut r = Vec::with_capacity(16 + packet.len());
+    r.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+    r.extend_from_slice(&now.subsec_micros().to_le_bytes());
+    r.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    r.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    r.extend_from_slice(packet);
+    r
+}
+
+fn tcp_header(src: u16, dst: u16, seq: u32, ack: u32) -> Vec<u8> {
+    let mut h = Vec::with_capacity(20);
+    h.extend_from_slice(&src.to_be_bytes());
+    h.extend_from_slice(&dst.to_be_bytes());
+    h.extend_from_slice(&seq.to_be_bytes());
+    h.extend_from_slice(&ack.to_be_bytes());
+    // 5 words, no options
+    h.push(5 << 4);
+    h.push(TCP_PSH_ACK);
+    h.extend_from_slice(&u16::MAX.to_be_bytes());
+    // checksum, not checked by Wireshark by default, and urgent pointer
+    h.extend_from_slice(&[0; 4]);
+    h
+}
+
+fn udp_header(src: u16, dst: u16, len: usize) -> Vec<u8> {
+    let mut h = Vec::with_capacity(8);
+    h.extend_from_slice(&src.to_be_bytes());
+    h.extend_from_slice(&dst.to_be_bytes());
+    h.extend_from_slice(&((8 + len) as u16).to_be_bytes());
+    h.extend_from_slice(&[0; 2]);
+    h
+}
+
+/// IPv4 if both ends are, IPv6 with the IPv4 ones mapped otherwise
+fn ip_packet(src: IpAddr, dst: IpAddr, protocol: u8, transport: &[u8], payload: &[u8]) -> Vec<u8> {
+    let len = transport.len() + payload.len();
+    let mut p = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut h = Vec::with_capacity(20 + len);
+            h.push(0x45);
+            h.push(0);
+            h.extend_from_slice(&((20 + len) as u16).to_be_bytes());
+            // id, flags and fragment offset
+            h.extend_from_slice(&[0; 4]);
+            h.push(64);
+            h.push(protocol);
+            h.extend_from_slice(&[0; 2]);
+            h.extend_from_slice(&src.octets());
+            h.extend_from_slice(&dst.octets());
+            let checksum = ipv4_checksum(&h);
+            h[10..12].copy_from_slice(&checksum.to_be_bytes());
+            h
+        }
+        (src, dst) => {
+            let v6 = |x: IpAddr| match x {
+                IpAddr::V4(x) => x.to_ipv6_mapped(),
+                IpAddr::V6(x) => x,
+            };
+            let mut h = Vec::with_capacity(40 + len);
+            h.extend_from_slice(&[0x60, 0, 0, 0]);
+            h.extend_from_slice(&(len as u16).to_be_bytes());
+            h.push(protocol);
+            h.push(64);
+            h.extend_from_slice(&v6(src).octets());
+            h.extend_from_slice(&v6(dst).octets());
+            h
+        }
+    };
+    p.extend_from_slice(transport);
+    p.extend_from_slice(payload);
+    p
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|x| u16::from_be_bytes([x[0], x[1]]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use crate::session::{Network, Session, SocksAddr};
+
+    use super::{ip_packet, ipv4_checksum, Capture, PROTO_TCP};
+
+    #[test]
+    fn test_ip_packet() {
+        let p = ip_packet(
+            Ipv4Addr::new(192, 168, 1, 2).into(),
+            Ipv4Addr::new(1, 1, 1, 1).into(),
+            PROTO_TCP,
+            &[0; 20],
+            b"hello",
+        );
+        assert_eq!(p.len(), 45);
+        assert_eq!(u16::from_be_bytes([p[2], p[3]]), 45);
+        assert_eq!(ipv4_checksum(&p[..20]), 0);
+
+        let p = ip_packet(
+            Ipv4Addr::new(192, 168, 1, 2).into(),
+            "2001:db8::1".parse().unwrap(),
+            PROTO_TCP,
+            &[0; 20],
+            b"hello",
+        );
+        assert_eq!(p[0] >> 4, 6);
+        assert_eq!(p.len(), 65);
+    }
+
+    #[tokio::test]
+    async fn test_capture() {
+        let path = std::env::temp_dir().join("test_capture.pcap");
+        let sess = Session {
+            network: Network::Tcp,
+            source: "192.168.1.2:50000".parse().unwrap(),
+            destination: SocksAddr::Domain("example.com".to_owned(), 80),
+            ..Default::default()
+        };
+        let capture = Capture::start(path.clone(), &sess, 8, Duration::from_secs(5))
+            .await
+            .unwrap();
+        capture.record(true, b"GET / HTTP/1.1\r\n");
+        capture.record(false, b"HTTP/1.1 200 OK\r\n");
+        drop(capture);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let pcap = std::fs::read(&path).unwrap();
+        // the header and one packet cut to the 8 bytes left
+        assert_eq!(pcap.len(), 24 + 16 + 20 + 20 + 8);
+        assert!(pcap.ends_with(b"GET / HT"));
+        std::fs::remove_file(path).ok();
+    }
+}
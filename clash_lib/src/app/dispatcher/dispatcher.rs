@@ -1,23 +1,32 @@
+use crate::app::device::ThreadSafeDeviceTable;
+use crate::app::dispatcher::nat::Association;
+use crate::app::dispatcher::priority;
+use crate::app::dispatcher::sniffer;
 use crate::app::dispatcher::tracked::TrackedDatagram;
 use crate::app::dispatcher::tracked::TrackedStream;
 use crate::app::outbound::manager::ThreadSafeOutboundManager;
 use crate::app::router::ThreadSafeRouter;
 use crate::common::io::copy_buf_bidirectional_with_timeout;
 use crate::config::def::RunMode;
+use crate::config::internal::config::DnsHijack;
 use crate::config::internal::proxy::PROXY_DIRECT;
 use crate::config::internal::proxy::PROXY_GLOBAL;
 use crate::proxy::datagram::UdpPacket;
 use crate::proxy::AnyInboundDatagram;
+use crate::proxy::OutboundType;
+use crate::session::Network;
 use crate::session::Session;
+use crate::session::SocksAddr;
+use crate::session::Type;
 use futures::SinkExt;
 use futures::StreamExt;
+use lru_time_cache::LruCache;
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
-use std::time::Instant;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
@@ -27,15 +36,29 @@ use tracing::trace;
 use tracing::Instrument;
 use tracing::{debug, error, info, warn};
 
-use crate::app::dns::ThreadSafeDNSResolver;
+use crate::app::dns;
+use crate::app::dns::{ThreadSafeDNSResolver, ThreadSafeDnsBlocker};
 
 use super::statistics_manager::Manager;
 
+/// how long a connection is waited on for its first bytes to be sniffed
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(100);
+/// how long a connection is waited on for its first bytes to start when
+/// only the rules look at them, a client speaking first sends them right
+/// away, the others shouldn't be held up as some rule looks for `ws`
+const RULE_SNIFF_TIMEOUT: Duration = Duration::from_millis(10);
+
 pub struct Dispatcher {
     outbound_manager: ThreadSafeOutboundManager,
     router: ThreadSafeRouter,
     resolver: ThreadSafeDNSResolver,
     mode: Arc<Mutex<RunMode>>,
+    devices: Option<ThreadSafeDeviceTable>,
+    sniffer: Option<Arc<sniffer::Sniffer>>,
+    dns_hijack: Arc<DnsHijacks>,
+    dns_blocker: Option<ThreadSafeDnsBlocker>,
+    #[cfg(feature = "mitm")]
+    mitm: Option<Arc<crate::app::mitm::Mitm>>,
 
     manager: Arc<Manager>,
 }
@@ -52,6 +75,7 @@ impl Dispatcher {
         router: ThreadSafeRouter,
         resolver: ThreadSafeDNSResolver,
         mode: RunMode,
+        devices: Option<ThreadSafeDeviceTable>,
 
         statistics_manager: Arc<Manager>,
     ) -> Self {
@@ -60,10 +84,54 @@ impl Dispatcher {
             router,
             resolver,
             mode: Arc::new(Mutex::new(mode)),
+            devices,
+            sniffer: None,
+            dns_hijack: Default::default(),
+            dns_blocker: None,
+            #[cfg(feature = "mitm")]
+            mitm: None,
             manager: statistics_manager,
         }
     }
 
+    /// takes the destination domain of sessions to IPs from their first bytes
+    pub fn with_sniffer(mut self, sniffer: Option<Arc<sniffer::Sniffer>>) -> Self {
+        self.sniffer = sniffer;
+        self
+    }
+
+    /// answers the DNS queries of tun and tproxy sessions to these, or for
+    /// the named tun listeners in `inbounds`, to their own
+    pub fn with_dns_hijack(
+        mut self,
+        dns_hijack: Vec<DnsHijack>,
+        inbounds: HashMap<String, Vec<DnsHijack>>,
+    ) -> Self {
+        self.dns_hijack = Arc::new(DnsHijacks {
+            global: dns_hijack,
+            inbounds,
+        });
+        self
+    }
+
+    /// answers the hijacked queries for the blocked domains itself
+    pub fn with_dns_blocker(mut self, dns_blocker: Option<ThreadSafeDnsBlocker>) -> Self {
+        self.dns_blocker = dns_blocker;
+        self
+    }
+
+    /// intercepts the sessions `mitm` matches
+    #[cfg(feature = "mitm")]
+    pub fn with_mitm(mut self, mitm: Option<Arc<crate::app::mitm::Mitm>>) -> Self {
+        self.mitm = mitm;
+        self
+    }
+
+    /// tun has to keep the DSCP of the packets it reads for the rules
+    pub fn needs_dscp(&self) -> bool {
+        self.router.needs_dscp()
+    }
+
     pub async fn set_mode(&self, mode: RunMode) {
         info!("run mode switched to {}", mode);
 
@@ -74,12 +142,38 @@ impl Dispatcher {
         *self.mode.lock().unwrap()
     }
 
+    /// relays `lhs` to where `sess` is routed, returning whether the
+    /// connection was rejected and is to be reset rather than closed
     #[instrument(skip(lhs))]
-    pub async fn dispatch_stream<S>(&self, sess: Session, mut lhs: S)
+    pub async fn dispatch_stream<S>(&self, mut sess: Session, lhs: S) -> bool
     where
         S: AsyncRead + AsyncWrite + Unpin + Send,
     {
-        let sess = if self.resolver.fake_ip_enabled() {
+        if self.manager.over_quota(&sess) {
+            warn!("{} rejected, its user is over quota", sess);
+            return false;
+        }
+
+        if let Some(devices) = &self.devices {
+            sess.device = devices.lookup(&sess.source.ip());
+        }
+
+        if self.dns_hijack.matches(&sess) {
+            debug!("hijacking dns {}", sess);
+            if let Err(err) =
+                dns::serve_stream(&self.resolver, self.dns_blocker.as_ref(), lhs).await
+            {
+                debug!("hijacked dns {} closed with error {}", sess, err);
+            }
+            return false;
+        }
+
+        // only sessions to an IP may have their domain sniffed
+        let sniffable = matches!(sess.destination, SocksAddr::Ip(_));
+
+        // the domain, if the session came through its fake ip
+        let mut fake_ip_host = None;
+        let mut sess = if self.resolver.fake_ip_enabled() {
             match sess.destination {
                 crate::session::SocksAddr::Ip(addr) => {
                     let ip = addr.ip();
@@ -87,6 +181,7 @@ impl Dispatcher {
                         let host = self.resolver.reverse_lookup(ip).await;
                         match host {
                             Some(host) => {
+                                fake_ip_host = Some(host.clone());
                                 let mut sess = sess;
                                 sess.destination =
                                     crate::session::SocksAddr::Domain(host, addr.port());
@@ -94,7 +189,7 @@ impl Dispatcher {
                             }
                             None => {
                                 error!("failed to reverse lookup fake ip: {}", ip);
-                                return;
+                                return false;
                             }
                         }
                     } else {
@@ -107,17 +202,56 @@ impl Dispatcher {
             sess
         };
 
-        let mode = *self.mode.lock().unwrap();
+        // a listener may force its own mode
+        let mode = sess
+            .inbound
+            .mode
+            .unwrap_or_else(|| *self.mode.lock().unwrap());
+
+        let port = sess.destination.port();
+        let domain_sniffer = self
+            .sniffer
+            .as_ref()
+            .filter(|x| sniffable && sess.network == Network::Tcp && x.sniffs_stream(port));
+        let mut lhs = lhs;
+        let mut sniffed = Vec::new();
+        if domain_sniffer.is_some()
+            || (matches!(mode, RunMode::Rule)
+                && sess.network == Network::Tcp
+                && self.router.needs_sniffing())
+        {
+            let first = if domain_sniffer.is_some() {
+                SNIFF_TIMEOUT
+            } else {
+                RULE_SNIFF_TIMEOUT
+            };
+            sniffed = match sniffer::peek(&mut lhs, first, SNIFF_TIMEOUT).await {
+                Ok(sniffed) => sniffed,
+                Err(err) => {
+                    debug!("failed to sniff {}: {}", sess, err);
+                    return false;
+                }
+            };
+            if sniffer::is_websocket_upgrade(&sniffed) {
+                sess.subprotocol = Some("ws".to_owned());
+            }
+            if let Some(domain) = domain_sniffer.and_then(|x| x.sniff_stream(port, &sniffed)) {
+                debug!("sniffed {} for {}", domain, sess);
+                sess.destination = SocksAddr::Domain(domain, port);
+            }
+        }
+        let mut lhs = sniffer::SniffedStream::new(lhs, sniffed);
+
         let (outbound_name, rule) = match mode {
-            RunMode::Global => (PROXY_GLOBAL, None),
+            RunMode::Global => (PROXY_GLOBAL.into(), None),
             RunMode::Rule => self.router.match_route(&sess).await,
-            RunMode::Direct => (PROXY_DIRECT, None),
+            RunMode::Direct => (PROXY_DIRECT.into(), None),
         };
 
         debug!("dispatching {} to {}[{}]", sess, outbound_name, mode);
 
         let mgr = self.outbound_manager.clone();
-        let handler = mgr.get_outbound(outbound_name).unwrap_or_else(|| {
+        let handler = mgr.get_outbound(&outbound_name).unwrap_or_else(|| {
             debug!("unknown rule: {}, fallback to direct", outbound_name);
             mgr.get_outbound(PROXY_DIRECT).unwrap()
         });
@@ -126,7 +260,7 @@ impl Dispatcher {
             .connect_stream(&sess, self.resolver.clone())
             .instrument(info_span!(
                 "connect_stream",
-                outbound_name = outbound_name,
+                outbound_name = &*outbound_name,
                 session = %sess,
             ))
             .await
@@ -135,6 +269,19 @@ impl Dispatcher {
                 debug!("remote connection established {}", sess);
                 let mut rhs =
                     TrackedStream::new(rhs, self.manager.clone(), sess.clone(), rule).await;
+
+                #[cfg(feature = "mitm")]
+                {
+                    if let Some(mitm) = self.mitm.as_ref().filter(|x| x.matches(&sess)) {
+                        debug!("intercepting {}", sess);
+                        if let Err(err) = mitm.intercept(&sess, &mut lhs, rhs).await {
+                            debug!("intercepted connection {} closed with error {}", sess, err);
+                        }
+                        return false;
+                    }
+                }
+
+                let mut lhs = priority::PrioritizedStream::new(lhs, priority::classify(&sess));
                 match copy_buf_bidirectional_with_timeout(
                     &mut lhs,
                     &mut rhs,
@@ -144,7 +291,7 @@ impl Dispatcher {
                 )
                 .instrument(info_span!(
                     "copy_bidirectional",
-                    outbound_name = outbound_name,
+                    outbound_name = &*outbound_name,
                     session = %sess,
                 ))
                 .await
@@ -154,6 +301,17 @@ impl Dispatcher {
                             "connection {} closed with {} bytes up, {} bytes down",
                             sess, up, down
                         );
+                        // only the client giving up without sending anything
+                        // counts against the fake ip, a server not answering
+                        // says nothing about it
+                        if let Some(host) = &fake_ip_host {
+                            if down > 0 || up == 0 {
+                                self.resolver.report_fake_ip_result(host, down > 0).await;
+                            }
+                        }
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                        debug!("connection {} timed out", sess);
                     }
                     Err(err) => match err.kind() {
                         std::io::ErrorKind::UnexpectedEof
@@ -167,6 +325,10 @@ impl Dispatcher {
                     },
                 }
             }
+            Err(err) if matches!(handler.proto(), OutboundType::Reject) => {
+                debug!("connection {} rejected by {}: {}", sess, outbound_name, err);
+                return true;
+            }
             Err(err) => {
                 warn!(
                     "failed to establish remote connection {}, error: {}",
@@ -177,6 +339,7 @@ impl Dispatcher {
                 }
             }
         }
+        false
     }
 
     /// Dispatch a UDP packet to outbound handler
@@ -193,7 +356,11 @@ impl Dispatcher {
         let outbound_manager = self.outbound_manager.clone();
         let resolver = self.resolver.clone();
         let mode = self.mode.clone();
+        let devices = self.devices.clone();
         let manager = self.manager.clone();
+        let domain_sniffer = self.sniffer.clone();
+        let dns_hijack = self.dns_hijack.clone();
+        let dns_blocker = self.dns_blocker.clone();
 
         let (mut local_w, mut local_r) = udp_inbound.split();
         let (remote_receiver_w, mut remote_receiver_r) = tokio::sync::mpsc::channel(32);
@@ -201,12 +368,50 @@ impl Dispatcher {
         let s = sess.clone();
         let ss = sess.clone();
         let t1 = tokio::spawn(async move {
+            // the domains sniffed from the first packet of each flow, the
+            // packets after it can't tell
+            let mut sniffed_domains: LruCache<(SocketAddr, SocketAddr), String> =
+                LruCache::with_expiry_duration_and_capacity(UDP_NAT_TIMEOUT, 1024);
+
             while let Some(packet) = local_r.next().await {
                 let mut sess = sess.clone();
                 sess.source = packet.src_addr.clone().must_into_socket_addr();
                 sess.destination = packet.dst_addr.clone();
+                if manager.over_quota(&sess) {
+                    debug!("{} dropped, its user is over quota", sess);
+                    continue;
+                }
+                if let Some(devices) = &devices {
+                    sess.device = devices.lookup(&sess.source.ip());
+                }
 
-                // populate fake ip for route matching
+                if dns_hijack.matches(&sess) {
+                    let resolver = resolver.clone();
+                    let dns_blocker = dns_blocker.clone();
+                    let remote_receiver_w = remote_receiver_w.clone();
+                    tokio::spawn(async move {
+                        if let Some(answer) =
+                            dns::answer_query(&resolver, dns_blocker.as_ref(), &packet.data).await
+                        {
+                            let reply = UdpPacket {
+                                data: answer,
+                                src_addr: packet.dst_addr,
+                                dst_addr: packet.src_addr,
+                            };
+                            if let Err(err) = remote_receiver_w.send(reply).await {
+                                warn!("failed to send hijacked dns answer to local: {}", err);
+                            }
+                        }
+                    });
+                    continue;
+                }
+
+                // the address the local side sent to, replies must come from it
+                // even if it's a fake ip
+                let local_dst = packet.dst_addr.clone();
+
+                // populate fake ip for route matching, outbounds get the domain
+                // so that the remote side resolves it
                 let sess = if resolver.fake_ip_enabled() {
                     trace!("fake ip enabled");
                     match sess.destination {
@@ -238,16 +443,31 @@ impl Dispatcher {
                     sess
                 };
 
+                let mut sess = sess;
+                if let (Some(domain_sniffer), SocksAddr::Ip(dst)) = (&domain_sniffer, &local_dst) {
+                    let flow = (sess.source, *dst);
+                    let cached = sniffed_domains.get(&flow).cloned();
+                    let domain = cached.or_else(|| {
+                        let domain = domain_sniffer.sniff_datagram(dst.port(), &packet.data)?;
+                        debug!("sniffed {} for {}", domain, sess);
+                        sniffed_domains.insert(flow, domain.clone());
+                        Some(domain)
+                    });
+                    if let Some(domain) = domain {
+                        sess.destination = SocksAddr::Domain(domain, dst.port());
+                    }
+                }
+
                 // mutate packet for fake ip
                 let mut packet = packet;
                 packet.dst_addr = sess.destination.clone();
 
-                let mode = *mode.lock().unwrap();
+                let mode = sess.inbound.mode.unwrap_or_else(|| *mode.lock().unwrap());
 
                 let (outbound_name, rule) = match mode {
-                    RunMode::Global => (PROXY_GLOBAL, None),
+                    RunMode::Global => (PROXY_GLOBAL.into(), None),
                     RunMode::Rule => router.match_route(&sess).await,
-                    RunMode::Direct => (PROXY_DIRECT, None),
+                    RunMode::Direct => (PROXY_DIRECT.into(), None),
                 };
 
                 let outbound_name = outbound_name.to_string();
@@ -292,14 +512,16 @@ impl Dispatcher {
 
                         let (mut remote_w, mut remote_r) = outbound_datagram.split();
                         let (remote_sender, mut remote_forwarder) =
-                            tokio::sync::mpsc::channel::<UdpPacket>(32);
+                            tokio::sync::mpsc::channel::<(UdpPacket, SocksAddr)>(32);
+                        let assoc = Arc::new(Association::new());
 
                         // remote -> local
+                        let assoc_r = assoc.clone();
                         let r_handle = tokio::spawn(async move {
                             while let Some(packet) = remote_r.next().await {
                                 // NAT
                                 let mut packet = packet;
-                                packet.src_addr = sess.destination.clone().into();
+                                packet.src_addr = assoc_r.reply_from(packet.src_addr);
                                 packet.dst_addr = sess.source.into();
 
                                 debug!("UDP NAT for packet: {:?}, session: {}", packet, sess);
@@ -312,8 +534,10 @@ impl Dispatcher {
                             }
                         });
                         // local -> remote
+                        let assoc_w = assoc.clone();
                         let w_handle = tokio::spawn(async move {
-                            while let Some(packet) = remote_forwarder.recv().await {
+                            while let Some((packet, local_dst)) = remote_forwarder.recv().await {
+                                assoc_w.record(&packet.dst_addr, &local_dst);
                                 match remote_w.send(packet).await {
                                     Ok(_) => {}
                                     Err(err) => {
@@ -330,17 +554,18 @@ impl Dispatcher {
                                 r_handle,
                                 w_handle,
                                 remote_sender.clone(),
+                                assoc,
                             )
                             .await;
 
-                        match remote_sender.send(packet).await {
+                        match remote_sender.send((packet, local_dst)).await {
                             Ok(_) => {}
                             Err(err) => {
                                 error!("failed to send packet to remote: {}", err);
                             }
                         };
                     }
-                    Some(handle) => match handle.send(packet).await {
+                    Some(handle) => match handle.send((packet, local_dst)).await {
                         Ok(_) => {
                             debug!("reusing {} sent to remote", sess);
                         }
@@ -383,7 +608,39 @@ impl Dispatcher {
     }
 }
 
-type OutboundPacketSender = tokio::sync::mpsc::Sender<UdpPacket>; // outbound packet sender
+/// the `tun.dns-hijack` addresses, and the ones of each named tun listener
+#[derive(Default)]
+struct DnsHijacks {
+    global: Vec<DnsHijack>,
+    inbounds: HashMap<String, Vec<DnsHijack>>,
+}
+
+impl DnsHijacks {
+    /// whether `sess` goes to one of the addresses of its inbound, only the
+    /// transparent inbounds are hijacked as the others ask for their
+    /// destination
+    fn matches(&self, sess: &Session) -> bool {
+        let dns_hijack = self
+            .inbounds
+            .get(&sess.inbound.name)
+            .unwrap_or(&self.global);
+        match &sess.destination {
+            SocksAddr::Ip(dst) if matches!(sess.typ, Type::Tun | Type::Tproxy) => {
+                dns_hijack.iter().any(|x| x.matches(sess.network, dst))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// how long a UDP association lives without packets either way
+const UDP_NAT_TIMEOUT: Duration = Duration::from_secs(60);
+/// how often expired UDP associations are looked for
+const UDP_NAT_SCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// outbound packet sender, each packet with the destination the client sent
+/// it to
+type OutboundPacketSender = tokio::sync::mpsc::Sender<(UdpPacket, SocksAddr)>;
 
 struct TimeoutUdpSessionManager {
     map: Arc<RwLock<OutboundHandleMap>>,
@@ -401,13 +658,12 @@ impl Drop for TimeoutUdpSessionManager {
 impl TimeoutUdpSessionManager {
     fn new() -> Self {
         let map = Arc::new(RwLock::new(OutboundHandleMap::new()));
-        let timeout = Duration::from_secs(10);
 
         let map_cloned = map.clone();
 
         let cleaner = tokio::spawn(async move {
             trace!("timeout udp session cleaner scanning");
-            let mut interval = tokio::time::interval(timeout);
+            let mut interval = tokio::time::interval(UDP_NAT_SCAN_INTERVAL);
 
             loop {
                 interval.tick().await;
@@ -417,9 +673,8 @@ impl TimeoutUdpSessionManager {
                 let mut alived = 0;
                 let mut expired = 0;
                 g.0.retain(|k, x| {
-                    let (h1, h2, _, last) = x;
-                    let now = Instant::now();
-                    let alive = now.duration_since(*last) < timeout;
+                    let (h1, h2, _, assoc) = x;
+                    let alive = assoc.idle() < UDP_NAT_TIMEOUT;
                     if !alive {
                         expired += 1;
                         trace!("udp session expired: {:?}", k);
@@ -452,9 +707,17 @@ impl TimeoutUdpSessionManager {
         recv_handle: JoinHandle<()>,
         send_handle: JoinHandle<()>,
         sender: OutboundPacketSender,
+        assoc: Arc<Association>,
     ) {
         let mut map = self.map.write().await;
-        map.insert(outbound_name, src_addr, recv_handle, send_handle, sender);
+        map.insert(
+            outbound_name,
+            src_addr,
+            recv_handle,
+            send_handle,
+            sender,
+            assoc,
+        );
     }
 
     async fn get_outbound_sender_mut(
@@ -474,7 +737,7 @@ struct OutboundHandleMap(
             JoinHandle<()>,
             JoinHandle<()>,
             OutboundPacketSender,
-            Instant,
+            Arc<Association>,
         ),
     >,
 );
@@ -491,10 +754,11 @@ impl OutboundHandleMap {
         recv_handle: JoinHandle<()>,
         send_handle: JoinHandle<()>,
         sender: OutboundPacketSender,
+        assoc: Arc<Association>,
     ) {
         self.0.insert(
             (outbound_name.to_string(), src_addr),
-            (recv_handle, send_handle, sender, Instant::now()),
+            (recv_handle, send_handle, sender, assoc),
         );
     }
 
@@ -505,12 +769,12 @@ impl OutboundHandleMap {
     ) -> Option<OutboundPacketSender> {
         self.0
             .get_mut(&(outbound_name.to_owned(), src_addr))
-            .map(|(_, _, sender, last)| {
+            .map(|(_, _, sender, assoc)| {
                 trace!(
                     "updating last access time for outbound {:?}",
                     (outbound_name, src_addr)
                 );
-                *last = Instant::now();
+                assoc.touch();
                 sender.clone()
             })
     }
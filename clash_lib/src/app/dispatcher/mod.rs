@@ -1,9 +1,19 @@
+mod capture;
 mod dispatcher;
+mod nat;
+mod observer;
+mod priority;
+mod sniffer;
 mod statistics_manager;
 mod tracked;
 
+pub use capture::Capture;
 pub use dispatcher::Dispatcher;
+pub use observer::{ConnectionInfo, ConnectionObserver};
+pub use sniffer::Sniffer;
 pub use statistics_manager::Manager as StatisticsManager;
+pub use statistics_manager::ProxyChain;
+pub use statistics_manager::{DnsQueryLog, DnsStats};
 pub use tracked::BoxedChainedDatagram;
 pub use tracked::BoxedChainedStream;
 pub use tracked::ChainedDatagram;
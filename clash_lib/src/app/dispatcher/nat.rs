@@ -0,0 +1,107 @@
+//! The UDP associations of the dispatcher, one per client and outbound.
+//!
+//! An association takes packets from its client to any destination and
+//! relays replies from any remote back, so the mapping is full-cone: a peer
+//! the client never sent to can reach it too once the association is up,
+//! which is what games and other peer to peer UDP rely on.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::session::SocksAddr;
+
+/// the most translated destinations remembered for one client, a client
+/// scanning for peers starts over once it's reached
+const MAX_TRANSLATIONS: usize = 1024;
+
+pub struct Association {
+    /// destination as sent by the outbound -> as sent by the client, for the
+    /// ones that differ, e.g. a fake ip sent on as its domain
+    translations: Mutex<HashMap<SocksAddr, SocksAddr>>,
+    /// packets either way keep the association alive
+    last_active: Mutex<Instant>,
+}
+
+impl Association {
+    pub fn new() -> Self {
+        Self {
+            translations: Mutex::new(HashMap::new()),
+            last_active: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.last_active.lock().unwrap().elapsed()
+    }
+
+    /// a packet the client sent to `local` leaves the outbound for `remote`
+    pub fn record(&self, remote: &SocksAddr, local: &SocksAddr) {
+        self.touch();
+        if remote == local {
+            return;
+        }
+        let mut translations = self.translations.lock().unwrap();
+        if translations.len() >= MAX_TRANSLATIONS && !translations.contains_key(remote) {
+            translations.clear();
+        }
+        translations.insert(remote.clone(), local.clone());
+    }
+
+    /// the source the client sees for a reply from `remote`, the address it
+    /// sent to if any. Replies from anyone else keep their own address
+    pub fn reply_from(&self, remote: SocksAddr) -> SocksAddr {
+        self.touch();
+        let translations = self.translations.lock().unwrap();
+        if let Some(local) = translations.get(&remote) {
+            return local.clone();
+        }
+        // a domain resolved on the remote side is answered from one of its
+        // IPs, on the same port
+        if let SocksAddr::Ip(addr) = &remote {
+            let resolved = translations.iter().find_map(|(sent, local)| match sent {
+                SocksAddr::Domain(_, port) if *port == addr.port() => Some(local),
+                _ => None,
+            });
+            if let Some(local) = resolved {
+                return local.clone();
+            }
+        }
+        remote
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::session::SocksAddr;
+
+    use super::Association;
+
+    #[test]
+    fn test_reply_from() {
+        let assoc = Association::new();
+        let fake_ip = SocksAddr::Ip("198.18.0.3:443".parse().unwrap());
+        let domain = SocksAddr::Domain("quic.example.com".to_owned(), 443);
+        let peer = SocksAddr::Ip("1.2.3.4:27015".parse().unwrap());
+
+        assoc.record(&domain, &fake_ip);
+        assoc.record(&peer, &peer);
+
+        assert_eq!(assoc.reply_from(domain.clone()), fake_ip);
+        assert_eq!(
+            assoc.reply_from(SocksAddr::Ip("93.184.216.34:443".parse().unwrap())),
+            fake_ip
+        );
+        assert_eq!(assoc.reply_from(peer.clone()), peer);
+
+        // full-cone, an unknown peer keeps its address
+        let stranger = SocksAddr::Ip("5.6.7.8:3478".parse().unwrap());
+        assert_eq!(assoc.reply_from(stranger.clone()), stranger);
+    }
+}
@@ -0,0 +1,34 @@
+use std::net::SocketAddr;
+
+/// a connection as the dispatcher tracks it, handed to the observers when
+/// it is opened
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// the id the connection goes by in the API and the other callbacks
+    pub id: uuid::Uuid,
+    /// `TCP` or `UDP`
+    pub network: String,
+    /// the kind of inbound it came in from, e.g. `Socks5` or `Tun`
+    pub inbound: String,
+    pub source: SocketAddr,
+    /// `host:port` or `ip:port`
+    pub destination: String,
+    /// the outbounds it goes through, the last one is the proxy server
+    pub chain: Vec<String>,
+    pub rule: String,
+    pub rule_payload: String,
+}
+
+/// Lets an embedder follow the connections and their traffic as they go,
+/// e.g. to draw live stats in a GUI without polling the API.
+///
+/// The callbacks are made inline on the data path, so they must return
+/// quickly and hand anything heavier off to a channel or a task.
+pub trait ConnectionObserver: Send + Sync {
+    fn on_connection_open(&self, _conn: &ConnectionInfo) {}
+
+    /// bytes sent to and received from the remote since the last call
+    fn on_traffic(&self, _id: uuid::Uuid, _upload: usize, _download: usize) {}
+
+    fn on_connection_close(&self, _id: uuid::Uuid) {}
+}
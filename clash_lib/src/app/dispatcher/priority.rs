@@ -0,0 +1,181 @@
+//! Keeps bulk transfers from starving latency-sensitive sessions.
+//!
+//! Every connection starts out interactive, which covers TLS handshakes and
+//! small requests, and turns bulk once it has moved enough bytes. Bulk
+//! streams hand the worker back to the scheduler after each slice of their
+//! budget, so DNS, SSH or game traffic relayed by the same process gets
+//! polled in between instead of queueing behind a download.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::session::{Network, Session};
+
+/// bytes a connection moves before it counts as bulk
+const INTERACTIVE_BYTES: u64 = 64 * 1024;
+/// bytes a bulk stream moves between two yields
+const BULK_SLICE: usize = 64 * 1024;
+
+/// destination ports whose sessions are always interactive:
+/// SSH, DNS, DoT, RDP and VNC
+const INTERACTIVE_PORTS: [u16; 5] = [22, 53, 853, 3389, 5900];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Priority {
+    /// never yields early
+    Interactive,
+    /// interactive until it moved `INTERACTIVE_BYTES`, then bulk
+    Adaptive,
+}
+
+pub fn classify(sess: &Session) -> Priority {
+    // UDP relays are packet by packet and never hog a worker
+    if sess.network == Network::Udp || INTERACTIVE_PORTS.contains(&sess.destination.port()) {
+        Priority::Interactive
+    } else {
+        Priority::Adaptive
+    }
+}
+
+/// Wraps the local side of a relay and yields to the scheduler between
+/// slices once the session turns bulk.
+pub struct PrioritizedStream<S> {
+    inner: S,
+    priority: Priority,
+    transferred: u64,
+    /// bytes left in the current slice
+    slice: usize,
+}
+
+impl<S> PrioritizedStream<S> {
+    pub fn new(inner: S, priority: Priority) -> Self {
+        Self {
+            inner,
+            priority,
+            transferred: 0,
+            slice: BULK_SLICE,
+        }
+    }
+
+    fn is_bulk(&self) -> bool {
+        self.priority == Priority::Adaptive && self.transferred >= INTERACTIVE_BYTES
+    }
+
+    /// `Pending` with an immediate wake up when a bulk slice is used up,
+    /// which puts the task at the back of the run queue
+    fn poll_yield(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.is_bulk() && self.slice == 0 {
+            self.slice = BULK_SLICE;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        Poll::Ready(())
+    }
+
+    fn account(&mut self, n: usize) {
+        let before = self.transferred;
+        self.transferred += n as u64;
+        if self.priority == Priority::Adaptive {
+            let bulk = self
+                .transferred
+                .saturating_sub(before.max(INTERACTIVE_BYTES));
+            self.slice = self.slice.saturating_sub(bulk as usize);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrioritizedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_yield(cx));
+
+        let before = buf.filled().len();
+        let rv = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.account(buf.filled().len() - before);
+        rv
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrioritizedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_yield(cx));
+
+        let rv = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = rv {
+            this.account(n);
+        }
+        rv
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use futures::task::noop_waker_ref;
+    use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+
+    use crate::session::{Network, Session, SocksAddr};
+
+    use super::{classify, PrioritizedStream, Priority, BULK_SLICE, INTERACTIVE_BYTES};
+
+    #[test]
+    fn test_classify() {
+        let sess = |network, port| Session {
+            network,
+            destination: SocksAddr::Domain("example.com".to_owned(), port),
+            ..Default::default()
+        };
+        assert_eq!(classify(&sess(Network::Tcp, 22)), Priority::Interactive);
+        assert_eq!(classify(&sess(Network::Udp, 27015)), Priority::Interactive);
+        assert_eq!(classify(&sess(Network::Tcp, 443)), Priority::Adaptive);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_yields() {
+        let total = INTERACTIVE_BYTES as usize + BULK_SLICE;
+        let (mut client, server) = tokio::io::duplex(total * 2);
+        client.write_all(&vec![0u8; total * 2]).await.unwrap();
+
+        let mut s = PrioritizedStream::new(server, Priority::Adaptive);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut chunk = vec![0u8; 4096];
+        let mut read = 0;
+        // interactive, then a whole bulk slice without yielding
+        while read < total {
+            let mut buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut s).poll_read(&mut cx, &mut buf) {
+                Poll::Ready(Ok(())) => read += buf.filled().len(),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        let mut buf = ReadBuf::new(&mut chunk);
+        assert!(Pin::new(&mut s).poll_read(&mut cx, &mut buf).is_pending());
+        assert!(Pin::new(&mut s).poll_read(&mut cx, &mut buf).is_ready());
+    }
+}
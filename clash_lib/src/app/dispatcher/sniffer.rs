@@ -0,0 +1,690 @@
+use std::{
+    io,
+    net::IpAddr,
+    ops::RangeInclusive,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use boring::symm::Cipher;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::{
+    common::{crypto::aes_gcm_open, trie},
+    config::def,
+    Error,
+};
+
+/// the most bytes read from a connection before giving up sniffing it
+const MAX_SNIFF_SIZE: usize = 4096;
+
+/// reads the first bytes the client sends, waiting at most `first` for
+/// them to start as the other side may be expected to speak first, and at
+/// most `timeout` in all.
+/// The bytes must be replayed with a [`SniffedStream`].
+pub async fn peek<S>(stream: &mut S, first: Duration, timeout: Duration) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(MAX_SNIFF_SIZE);
+    let read = tokio::time::timeout(timeout, async {
+        let mut chunk = [0u8; MAX_SNIFF_SIZE];
+        while buf.len() < MAX_SNIFF_SIZE {
+            let read = stream.read(&mut chunk[..MAX_SNIFF_SIZE - buf.len()]);
+            let n = if buf.is_empty() {
+                match tokio::time::timeout(first, read).await {
+                    Ok(n) => n?,
+                    Err(_) => break,
+                }
+            } else {
+                read.await?
+            };
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            if !is_partial_http_request(&buf) && !is_partial_tls_record(&buf) {
+                break;
+            }
+        }
+        Ok::<_, io::Error>(())
+    })
+    .await;
+    if let Ok(res) = read {
+        res?;
+    }
+
+    Ok(buf)
+}
+
+fn is_partial_http_request(buf: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    matches!(
+        httparse::Request::new(&mut headers).parse(buf),
+        Ok(httparse::Status::Partial)
+    )
+}
+
+/// a ClientHello may take more than one segment
+fn is_partial_tls_record(buf: &[u8]) -> bool {
+    match buf {
+        [TLS_HANDSHAKE, _, _, hi, lo, rest @ ..] => {
+            rest.len() < u16::from_be_bytes([*hi, *lo]) as usize
+        }
+        [TLS_HANDSHAKE, ..] => true,
+        _ => false,
+    }
+}
+
+/// whether `buf` starts with an HTTP request upgrading to WebSocket
+pub fn is_websocket_upgrade(buf: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(buf) {
+        Ok(httparse::Status::Complete(_)) => req.headers.iter().any(|h| {
+            h.name.eq_ignore_ascii_case("upgrade")
+                && std::str::from_utf8(h.value)
+                    .is_ok_and(|v| v.trim().eq_ignore_ascii_case("websocket"))
+        }),
+        _ => false,
+    }
+}
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXT_SERVER_NAME: u16 = 0x00;
+
+const QUIC_V1: u32 = 0x00000001;
+/// RFC 9001 5.2
+const QUIC_V1_INITIAL_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+/// Finds the domain a session to an IP is for, see `sniffer` in the config.
+pub struct Sniffer {
+    tls: Vec<RangeInclusive<u16>>,
+    http: Vec<RangeInclusive<u16>>,
+    quic: Vec<RangeInclusive<u16>>,
+    skip: trie::StringTrie<bool>,
+}
+
+impl Sniffer {
+    pub fn new(cfg: def::Sniffer) -> Result<Self, Error> {
+        let ports = |x: Option<def::SniffPorts>| -> Result<Vec<_>, Error> {
+            x.map_or(Ok(vec![]), |x| {
+                x.ports.iter().map(parse_port_range).collect()
+            })
+        };
+
+        let mut skip = trie::StringTrie::new();
+        for domain in cfg.skip_domain.iter() {
+            if !skip.insert(domain, Arc::new(true)) {
+                return Err(Error::InvalidConfig(format!(
+                    "invalid sniffer skip domain: {}",
+                    domain
+                )));
+            }
+        }
+
+        Ok(Self {
+            tls: ports(cfg.sniff.tls)?,
+            http: ports(cfg.sniff.http)?,
+            quic: ports(cfg.sniff.quic)?,
+            skip,
+        })
+    }
+
+    /// whether the first bytes of streams to `port` are to be sniffed
+    pub fn sniffs_stream(&self, port: u16) -> bool {
+        self.tls
+            .iter()
+            .chain(self.http.iter())
+            .any(|x| x.contains(&port))
+    }
+
+    /// the domain a stream to `port` starting with `buf` is for
+    pub fn sniff_stream(&self, port: u16, buf: &[u8]) -> Option<String> {
+        let domain =
+            if self.tls.iter().any(|x| x.contains(&port)) && buf.first() == Some(&TLS_HANDSHAKE) {
+                tls_sni(buf)
+            } else if self.http.iter().any(|x| x.contains(&port)) {
+                http_host(buf)
+            } else {
+                None
+            };
+        domain.and_then(|x| self.accept(x))
+    }
+
+    /// the domain of a QUIC connection to `port`, from its Initial packet.
+    /// A ClientHello split over several packets isn't put together.
+    pub fn sniff_datagram(&self, port: u16, pkt: &[u8]) -> Option<String> {
+        if !self.quic.iter().any(|x| x.contains(&port)) {
+            return None;
+        }
+        quic_sni(pkt).and_then(|x| self.accept(x))
+    }
+
+    fn accept(&self, domain: String) -> Option<String> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if domain.is_empty() || domain.parse::<IpAddr>().is_ok() {
+            return None;
+        }
+        if self.skip.search(&domain).is_some() {
+            return None;
+        }
+        Some(domain)
+    }
+}
+
+fn parse_port_range(port: &def::SniffPort) -> Result<RangeInclusive<u16>, Error> {
+    let invalid = |x: &str| Error::InvalidConfig(format!("invalid sniffer port: {}", x));
+    match port {
+        def::SniffPort::Port(port) => Ok(*port..=*port),
+        def::SniffPort::Range(range) => {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let start = start.trim().parse::<u16>().map_err(|_| invalid(range))?;
+            let end = end.trim().parse::<u16>().map_err(|_| invalid(range))?;
+            if start > end {
+                return Err(invalid(range));
+            }
+            Ok(start..=end)
+        }
+    }
+}
+
+/// reads the big endian fields of the TLS and QUIC messages
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|x| x[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|x| u16::from_be_bytes([x[0], x[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]]))
+    }
+
+    /// RFC 9000 16
+    fn varint(&mut self) -> Option<u64> {
+        let first = self.u8()?;
+        let rest = self.take((1 << (first >> 6)) - 1)?;
+        Some(
+            rest.iter()
+                .fold((first & 0x3f) as u64, |acc, x| acc << 8 | *x as u64),
+        )
+    }
+}
+
+/// the Host of an HTTP request, without the port
+fn http_host(buf: &[u8]) -> Option<String> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    req.parse(buf).ok()?;
+    let host = req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("host"))?;
+    let host = std::str::from_utf8(host.value).ok()?.trim();
+    // an IPv6 literal is no domain
+    if host.starts_with('[') {
+        return None;
+    }
+    Some(host.split(':').next()?.to_owned())
+}
+
+/// the SNI of a TLS record carrying a ClientHello
+fn tls_sni(buf: &[u8]) -> Option<String> {
+    let mut r = Reader(buf);
+    if r.u8()? != TLS_HANDSHAKE {
+        return None;
+    }
+    r.take(2)?;
+    let len = r.u16()? as usize;
+    client_hello_sni(&r.0[..len.min(r.0.len())])
+}
+
+/// the server name in a ClientHello handshake message, which may be cut off
+/// after the extension
+fn client_hello_sni(msg: &[u8]) -> Option<String> {
+    let mut r = Reader(msg);
+    if r.u8()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+    // length, version and random
+    r.take(3 + 2 + 32)?;
+    let n = r.u8()? as usize;
+    r.take(n)?;
+    let n = r.u16()? as usize;
+    r.take(n)?;
+    let n = r.u8()? as usize;
+    r.take(n)?;
+    let n = r.u16()? as usize;
+
+    let mut exts = Reader(&r.0[..n.min(r.0.len())]);
+    loop {
+        let typ = exts.u16()?;
+        let n = exts.u16()? as usize;
+        let ext = exts.take(n)?;
+        if typ != TLS_EXT_SERVER_NAME {
+            continue;
+        }
+        let mut ext = Reader(ext);
+        ext.u16()?;
+        // host_name
+        if ext.u8()? != 0 {
+            return None;
+        }
+        let n = ext.u16()? as usize;
+        return String::from_utf8(ext.take(n)?.to_vec()).ok();
+    }
+}
+
+fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(salt).expect("hmac takes any key size");
+    mac.update(ikm);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// RFC 8446 7.1 with an empty context, up to one hash long
+fn hkdf_expand_label(secret: &[u8], label: &str, len: usize) -> Vec<u8> {
+    let label = format!("tls13 {}", label);
+    let mut info = (len as u16).to_be_bytes().to_vec();
+    info.push(label.len() as u8);
+    info.extend_from_slice(label.as_bytes());
+    info.push(0);
+
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("hmac takes any key size");
+    mac.update(&info);
+    mac.update(&[1]);
+    mac.finalize().into_bytes()[..len].to_vec()
+}
+
+/// the client key, iv and header protection key of the Initial packets to
+/// `dcid`, RFC 9001 5.2
+fn quic_initial_keys(dcid: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let secret = hkdf_extract(&QUIC_V1_INITIAL_SALT, dcid);
+    let secret = hkdf_expand_label(&secret, "client in", 32);
+    (
+        hkdf_expand_label(&secret, "quic key", 16),
+        hkdf_expand_label(&secret, "quic iv", 12),
+        hkdf_expand_label(&secret, "quic hp", 16),
+    )
+}
+
+/// the decrypted payload of a QUIC v1 Initial packet
+fn quic_initial_payload(pkt: &[u8]) -> Option<Vec<u8>> {
+    let mut r = Reader(pkt);
+    // long header, fixed bit and the Initial type
+    if r.u8()? & 0xf0 != 0xc0 || r.u32()? != QUIC_V1 {
+        return None;
+    }
+    let n = r.u8()? as usize;
+    let dcid = r.take(n)?;
+    let n = r.u8()? as usize;
+    r.take(n)?;
+    let n = r.varint()? as usize;
+    r.take(n)?;
+    let len = r.varint()? as usize;
+    let pn_offset = pkt.len() - r.0.len();
+    // the header protection sample starts 4 bytes in
+    if len < 4 + 16 || r.0.len() < len {
+        return None;
+    }
+
+    let (key, iv, hp) = quic_initial_keys(dcid);
+    let sample = &pkt[pn_offset + 4..pn_offset + 4 + 16];
+    let mask = boring::symm::encrypt(Cipher::aes_128_ecb(), &hp, None, sample).ok()?;
+
+    let mut header = pkt[..pn_offset + 4].to_vec();
+    header[0] ^= mask[0] & 0x0f;
+    let pn_len = (header[0] & 0x03) as usize + 1;
+    header.truncate(pn_offset + pn_len);
+    let mut nonce = iv;
+    for i in 0..pn_len {
+        header[pn_offset + i] ^= mask[1 + i];
+        nonce[12 - pn_len + i] ^= header[pn_offset + i];
+    }
+
+    aes_gcm_open(
+        &key,
+        &nonce,
+        &pkt[pn_offset + pn_len..pn_offset + len],
+        Some(&header),
+    )
+    .ok()
+}
+
+/// the CRYPTO stream at the start of a QUIC Initial payload
+fn quic_crypto_stream(payload: &[u8]) -> Option<Vec<u8>> {
+    let mut r = Reader(payload);
+    let mut frames = vec![];
+    while !r.0.is_empty() {
+        match r.varint()? {
+            // PADDING and PING
+            0x00 | 0x01 => {}
+            // ACK, with the ECN counts for 0x03
+            typ @ (0x02 | 0x03) => {
+                r.varint()?;
+                r.varint()?;
+                let ranges = r.varint()?;
+                r.varint()?;
+                for _ in 0..ranges * 2 {
+                    r.varint()?;
+                }
+                if typ == 0x03 {
+                    for _ in 0..3 {
+                        r.varint()?;
+                    }
+                }
+            }
+            // CRYPTO
+            0x06 => {
+                let offset = r.varint()? as usize;
+                let n = r.varint()? as usize;
+                frames.push((offset, r.take(n)?));
+            }
+            _ => break,
+        }
+    }
+
+    // clients may send the frames out of order
+    frames.sort_by_key(|(offset, _)| *offset);
+    let mut stream = vec![];
+    for (offset, data) in frames {
+        if offset > stream.len() {
+            break;
+        }
+        if offset + data.len() > stream.len() {
+            stream.extend_from_slice(&data[stream.len() - offset..]);
+        }
+    }
+    Some(stream)
+}
+
+/// the SNI of a QUIC v1 Initial packet
+fn quic_sni(pkt: &[u8]) -> Option<String> {
+    let payload = quic_initial_payload(pkt)?;
+    client_hello_sni(&quic_crypto_stream(&payload)?)
+}
+
+/// a stream that replays the sniffed bytes before reading on
+pub struct SniffedStream<S> {
+    inner: S,
+    sniffed: Vec<u8>,
+    pos: usize,
+}
+
+impl<S> SniffedStream<S> {
+    pub fn new(inner: S, sniffed: Vec<u8>) -> Self {
+        Self {
+            inner,
+            sniffed,
+            pos: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SniffedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.sniffed.len() {
+            let n = std::cmp::min(buf.remaining(), self.sniffed.len() - self.pos);
+            buf.put_slice(&self.sniffed[self.pos..self.pos + n]);
+            self.pos += n;
+            if self.pos == self.sniffed.len() {
+                self.sniffed = Vec::new();
+                self.pos = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SniffedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        common::crypto::aes_gcm_seal,
+        config::def::{self, SniffPort},
+    };
+
+    use super::{
+        is_partial_tls_record, is_websocket_upgrade, parse_port_range, peek, quic_initial_keys,
+        SniffedStream, Sniffer,
+    };
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn sniffer() -> Sniffer {
+        Sniffer::new(def::Sniffer {
+            enable: true,
+            skip_domain: vec!["+.apple.com".to_owned()],
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    /// a ClientHello handshake message with a supported_versions extension
+    /// ahead of the server_name
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let name = sni.as_bytes();
+        let mut server_name = ((name.len() + 3) as u16).to_be_bytes().to_vec();
+        server_name.push(0);
+        server_name.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        server_name.extend_from_slice(name);
+
+        let mut exts = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04, 0x00, 0x00];
+        exts.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+        exts.extend_from_slice(&server_name);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0x42; 32]);
+        body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend_from_slice(&(exts.len() as u16).to_be_bytes());
+        body.extend_from_slice(&exts);
+
+        let mut msg = vec![0x01];
+        msg.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        msg.extend_from_slice(&body);
+        msg
+    }
+
+    #[tokio::test]
+    async fn test_sniff_websocket_upgrade() {
+        let req = b"GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&req[..20]).await.unwrap();
+        let writer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            client.write_all(&req[20..]).await.unwrap();
+            client.write_all(b"frame").await.unwrap();
+            client
+        });
+
+        let sniffed = peek(&mut server, Duration::from_secs(1), Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert!(is_websocket_upgrade(&sniffed));
+        let _client = writer.await.unwrap();
+
+        let mut stream = SniffedStream::new(server, sniffed);
+        let mut buf = vec![0u8; req.len() + 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..req.len()], req);
+        assert_eq!(&buf[req.len()..], b"frame");
+
+        assert!(!is_websocket_upgrade(
+            b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peek_server_first() {
+        let (_client, mut server) = tokio::io::duplex(1024);
+        let start = tokio::time::Instant::now();
+        let sniffed = peek(
+            &mut server,
+            Duration::from_millis(10),
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+        assert!(sniffed.is_empty());
+        assert_eq!(start.elapsed(), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_sniff_tls() {
+        let hello = client_hello("WWW.Example.com");
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        record.extend_from_slice(&hello);
+
+        let sniffer = sniffer();
+        assert_eq!(
+            sniffer.sniff_stream(443, &record),
+            Some("www.example.com".to_owned())
+        );
+        assert_eq!(sniffer.sniff_stream(8443, &record), None);
+        assert!(is_partial_tls_record(&record[..50]));
+        assert!(!is_partial_tls_record(&record));
+
+        let hello = client_hello("gateway.icloud.apple.com");
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        record.extend_from_slice(&hello);
+        assert_eq!(sniffer.sniff_stream(443, &record), None);
+    }
+
+    #[test]
+    fn test_sniff_http() {
+        let sniffer = sniffer();
+        assert_eq!(
+            sniffer.sniff_stream(80, b"GET / HTTP/1.1\r\nHost: example.com:80\r\n\r\n"),
+            Some("example.com".to_owned())
+        );
+        assert_eq!(
+            sniffer.sniff_stream(80, b"GET / HTTP/1.1\r\nHost: 1.2.3.4\r\n\r\n"),
+            None
+        );
+        assert_eq!(sniffer.sniff_stream(80, b"\x00\x01binary"), None);
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(parse_port_range(&SniffPort::Port(443)).unwrap(), 443..=443);
+        assert_eq!(
+            parse_port_range(&SniffPort::Range("8080-8880".to_owned())).unwrap(),
+            8080..=8880
+        );
+        assert!(parse_port_range(&SniffPort::Range("8880-8080".to_owned())).is_err());
+    }
+
+    #[test]
+    fn test_quic_initial_keys() {
+        // RFC 9001 A.1
+        let (key, iv, hp) = quic_initial_keys(&unhex("8394c8f03e515708"));
+        assert_eq!(key, unhex("1f369613dd76d5467730efcbe3b1a22d"));
+        assert_eq!(iv, unhex("fa044b2f42a3fd3b46fb255c"));
+        assert_eq!(hp, unhex("9f50449e04a0e810283a1e9933adedd2"));
+    }
+
+    #[test]
+    fn test_sniff_quic() {
+        let dcid = unhex("8394c8f03e515708");
+        let (key, iv, hp) = quic_initial_keys(&dcid);
+
+        // the ClientHello in two CRYPTO frames, the second one first
+        let hello = client_hello("quic.example.com");
+        let (a, b) = hello.split_at(20);
+        let mut payload = vec![0x06, 0x14, 0x40 | (b.len() >> 8) as u8, b.len() as u8];
+        payload.extend_from_slice(b);
+        payload.extend_from_slice(&[0x06, 0x00, a.len() as u8]);
+        payload.extend_from_slice(a);
+        payload.resize(1100, 0);
+
+        let pn = [0x00, 0x00, 0x00, 0x02];
+        let len = pn.len() + payload.len() + 16;
+        let mut header = vec![0xc3, 0x00, 0x00, 0x00, 0x01, dcid.len() as u8];
+        header.extend_from_slice(&dcid);
+        header.extend_from_slice(&[0x00, 0x00, 0x40 | (len >> 8) as u8, len as u8]);
+        let pn_offset = header.len();
+        header.extend_from_slice(&pn);
+
+        let mut nonce = iv;
+        nonce[8..].iter_mut().zip(pn).for_each(|(x, y)| *x ^= y);
+        let sealed = aes_gcm_seal(&key, &nonce, &payload, Some(&header)).unwrap();
+
+        let mask = boring::symm::encrypt(
+            boring::symm::Cipher::aes_128_ecb(),
+            &hp,
+            None,
+            &sealed[..16],
+        )
+        .unwrap();
+        let mut pkt = header;
+        pkt[0] ^= mask[0] & 0x0f;
+        for i in 0..4 {
+            pkt[pn_offset + i] ^= mask[1 + i];
+        }
+        pkt.extend_from_slice(&sealed);
+
+        let sniffer = sniffer();
+        assert_eq!(
+            sniffer.sniff_datagram(443, &pkt),
+            Some("quic.example.com".to_owned())
+        );
+        assert_eq!(sniffer.sniff_datagram(8443, &pkt), None);
+        pkt[30] ^= 0xff;
+        assert_eq!(sniffer.sniff_datagram(443, &pkt), None);
+    }
+}
@@ -2,17 +2,22 @@ use std::{
     collections::HashMap,
     sync::{
         atomic::{AtomicI64, AtomicU64, Ordering},
-        Arc,
+        Arc, OnceLock,
     },
+    time::Duration,
 };
 
 use chrono::Utc;
 use serde::Serialize;
-use tokio::sync::{oneshot::Sender, Mutex, RwLock};
+use tokio::sync::{broadcast, oneshot::Sender, Mutex, RwLock};
 
 use crate::session::Session;
 
-use super::tracked::Tracked;
+use super::{
+    capture::Capture,
+    observer::{ConnectionInfo, ConnectionObserver},
+    tracked::Tracked,
+};
 
 #[derive(Default, Clone, Debug)]
 pub struct ProxyChain(Arc<RwLock<Vec<String>>>);
@@ -47,6 +52,12 @@ pub struct TrackerInfo {
     pub proxy_chain_holder: ProxyChain,
     #[serde(skip)]
     pub session_holder: Session,
+    /// the traffic of the user of the session, if it has one
+    #[serde(skip)]
+    pub user: Option<Arc<UserStats>>,
+    /// set by `POST /connections/{id}/capture`
+    #[serde(skip)]
+    pub capture: OnceLock<Capture>,
 }
 
 #[derive(Serialize)]
@@ -57,6 +68,198 @@ pub struct Snapshot {
     connections: Vec<TrackerInfo>,
 }
 
+/// the traffic of an authenticated user, as served by `GET
+/// /users/{name}/stats`
+#[derive(Serialize)]
+pub struct UserSnapshot {
+    upload: u64,
+    download: u64,
+    /// bytes, upload and download together
+    quota: Option<u64>,
+}
+
+/// the traffic of the sessions of an authenticated user, since the start or
+/// the last reset
+pub struct UserStats {
+    upload: AtomicU64,
+    download: AtomicU64,
+    quota: Option<u64>,
+}
+
+impl UserStats {
+    fn new(quota: Option<u64>) -> Self {
+        Self {
+            upload: AtomicU64::new(0),
+            download: AtomicU64::new(0),
+            quota,
+        }
+    }
+
+    pub fn push_uploaded(&self, n: usize) {
+        self.upload.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn push_downloaded(&self, n: usize) {
+        self.download.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// whether the user has used up its quota, so that its new sessions are
+    /// rejected
+    pub fn over_quota(&self) -> bool {
+        self.quota.is_some_and(|x| {
+            self.upload.load(Ordering::Relaxed) + self.download.load(Ordering::Relaxed) >= x
+        })
+    }
+
+    fn reset(&self) {
+        self.upload.store(0, Ordering::Relaxed);
+        self.download.store(0, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> UserSnapshot {
+        UserSnapshot {
+            upload: self.upload.load(Ordering::Relaxed),
+            download: self.download.load(Ordering::Relaxed),
+            quota: self.quota,
+        }
+    }
+}
+
+/// a query answered by the resolver, as streamed by `GET /dns/queries`
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsQueryLog {
+    pub time: chrono::DateTime<Utc>,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub query_type: String,
+    /// `rule`, `cache` or `upstream`, empty if it failed
+    pub source: String,
+    pub rcode: String,
+    pub answers: Vec<String>,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+struct UpstreamStats {
+    queries: u64,
+    errors: u64,
+    /// of the answered queries
+    latency_total: Duration,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamSnapshot {
+    queries: u64,
+    errors: u64,
+    error_rate: f64,
+    avg_latency_ms: f64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsSnapshot {
+    cache_hits: u64,
+    cache_misses: u64,
+    cache_hit_ratio: f64,
+    /// by nameserver
+    upstreams: HashMap<String, UpstreamSnapshot>,
+}
+
+/// what the resolver reports of its nameservers and cache
+pub struct DnsStats {
+    upstreams: std::sync::Mutex<HashMap<String, UpstreamStats>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    queries: broadcast::Sender<DnsQueryLog>,
+}
+
+impl DnsStats {
+    fn new() -> Self {
+        Self {
+            upstreams: Default::default(),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            queries: broadcast::channel(256).0,
+        }
+    }
+
+    /// a question asked to a nameserver, the latency is of the answer
+    pub fn record_upstream(&self, upstream: &str, latency: Duration, ok: bool) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let s = upstreams.entry(upstream.to_owned()).or_default();
+        s.queries += 1;
+        if ok {
+            s.latency_total += latency;
+        } else {
+            s.errors += 1;
+        }
+    }
+
+    pub fn record_cache(&self, hit: bool) {
+        if hit {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// whether anyone is reading the query log, so that the entries are
+    /// only made then
+    pub fn watching(&self) -> bool {
+        self.queries.receiver_count() > 0
+    }
+
+    pub fn log_query(&self, entry: DnsQueryLog) {
+        let _ = self.queries.send(entry);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DnsQueryLog> {
+        self.queries.subscribe()
+    }
+
+    pub fn snapshot(&self) -> DnsSnapshot {
+        let ratio = |n: u64, total: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                n as f64 / total as f64
+            }
+        };
+        let cache_hits = self.cache_hits.load(Ordering::Relaxed);
+        let cache_misses = self.cache_misses.load(Ordering::Relaxed);
+        DnsSnapshot {
+            cache_hits,
+            cache_misses,
+            cache_hit_ratio: ratio(cache_hits, cache_hits + cache_misses),
+            upstreams: self
+                .upstreams
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(k, v)| {
+                    let answered = v.queries - v.errors;
+                    (
+                        k.clone(),
+                        UpstreamSnapshot {
+                            queries: v.queries,
+                            errors: v.errors,
+                            error_rate: ratio(v.errors, v.queries),
+                            avg_latency_ms: if answered == 0 {
+                                0.0
+                            } else {
+                                v.latency_total.as_secs_f64() * 1000.0 / answered as f64
+                            },
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
 pub struct Manager {
     connections: Arc<Mutex<HashMap<uuid::Uuid, (Tracked, Sender<()>)>>>,
     upload_temp: AtomicI64,
@@ -65,10 +268,16 @@ pub struct Manager {
     download_blip: AtomicI64,
     upload_total: AtomicI64,
     download_total: AtomicI64,
+    observers: Vec<Arc<dyn ConnectionObserver>>,
+    dns: Arc<DnsStats>,
+    /// by name, made on their first session
+    users: std::sync::Mutex<HashMap<String, Arc<UserStats>>>,
+    /// bytes, by user
+    user_quotas: OnceLock<HashMap<String, u64>>,
 }
 
 impl Manager {
-    pub fn new() -> Arc<Self> {
+    pub fn new(observers: Vec<Arc<dyn ConnectionObserver>>) -> Arc<Self> {
         let v = Arc::new(Self {
             connections: Arc::new(Mutex::new(HashMap::new())),
             upload_temp: AtomicI64::new(0),
@@ -77,6 +286,10 @@ impl Manager {
             download_blip: AtomicI64::new(0),
             upload_total: AtomicI64::new(0),
             download_total: AtomicI64::new(0),
+            observers,
+            dns: Arc::new(DnsStats::new()),
+            users: Default::default(),
+            user_quotas: OnceLock::new(),
         });
         let c = v.clone();
         tokio::spawn(async move {
@@ -85,7 +298,87 @@ impl Manager {
         v
     }
 
+    pub fn dns(&self) -> &Arc<DnsStats> {
+        &self.dns
+    }
+
+    /// set once, before the first session
+    pub fn set_user_quotas(&self, quotas: HashMap<String, u64>) {
+        let _ = self.user_quotas.set(quotas);
+    }
+
+    pub fn user(&self, name: &str) -> Arc<UserStats> {
+        self.users
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_insert_with(|| {
+                let quota = self.user_quotas.get().and_then(|x| x.get(name)).copied();
+                Arc::new(UserStats::new(quota))
+            })
+            .clone()
+    }
+
+    /// whether `sess` is of a user that has used up its quota
+    pub fn over_quota(&self, sess: &Session) -> bool {
+        sess.user.as_ref().is_some_and(|x| {
+            self.users
+                .lock()
+                .unwrap()
+                .get(x)
+                .is_some_and(|x| x.over_quota())
+        })
+    }
+
+    /// the users that had a session or have a quota
+    pub fn user_snapshots(&self) -> HashMap<String, UserSnapshot> {
+        let mut rv = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.snapshot()))
+            .collect::<HashMap<_, _>>();
+        for (name, quota) in self.user_quotas.get().into_iter().flatten() {
+            rv.entry(name.clone()).or_insert(UserSnapshot {
+                upload: 0,
+                download: 0,
+                quota: Some(*quota),
+            });
+        }
+        rv
+    }
+
+    /// gives the user its quota back, false if it is unknown
+    pub fn reset_user(&self, name: &str) -> bool {
+        match self.users.lock().unwrap().get(name) {
+            Some(x) => {
+                x.reset();
+                true
+            }
+            None => self.user_quotas.get().is_some_and(|x| x.contains_key(name)),
+        }
+    }
+
     pub async fn track(&self, item: Tracked, close_notify: Sender<()>) {
+        if !self.observers.is_empty() {
+            let t = item.tracker_info();
+            let sess = &t.session_holder;
+            let conn = ConnectionInfo {
+                id: item.id(),
+                network: sess.network.to_string(),
+                inbound: format!("{:?}", sess.typ),
+                source: sess.source,
+                destination: sess.destination.to_string(),
+                chain: t.proxy_chain_holder.0.read().await.clone(),
+                rule: t.rule.clone(),
+                rule_payload: t.rule_payload.clone(),
+            };
+            for o in &self.observers {
+                o.on_connection_open(&conn);
+            }
+        }
+
         let mut connections = self.connections.lock().await;
 
         connections.insert(item.id(), (item, close_notify));
@@ -94,6 +387,10 @@ impl Manager {
     /// Untrack a connection.
     /// this method is not async because it is called in Drop.
     pub fn untrack(&self, id: uuid::Uuid) {
+        for o in &self.observers {
+            o.on_connection_close(id);
+        }
+
         let connections = self.connections.clone();
 
         tokio::spawn(async move {
@@ -102,6 +399,14 @@ impl Manager {
         });
     }
 
+    pub async fn tracker_info(&self, id: uuid::Uuid) -> Option<Arc<TrackerInfo>> {
+        self.connections
+            .lock()
+            .await
+            .get(&id)
+            .map(|(t, _)| t.tracker_info())
+    }
+
     pub async fn close(&self, id: uuid::Uuid) {
         let connections = self.connections.clone();
 
@@ -122,14 +427,24 @@ impl Manager {
         }
     }
 
-    pub fn push_uploaded(&self, n: usize) {
+    pub fn push_uploaded(&self, id: uuid::Uuid, n: usize) {
+        if n > 0 {
+            for o in &self.observers {
+                o.on_traffic(id, n, 0);
+            }
+        }
         self.upload_temp
             .fetch_add(n as i64, std::sync::atomic::Ordering::Relaxed);
         self.upload_total
             .fetch_add(n as i64, std::sync::atomic::Ordering::Relaxed);
     }
 
-    pub fn push_downloaded(&self, n: usize) {
+    pub fn push_downloaded(&self, id: uuid::Uuid, n: usize) {
+        if n > 0 {
+            for o in &self.observers {
+                o.on_traffic(id, 0, n);
+            }
+        }
         self.download_temp
             .fetch_add(n as i64, std::sync::atomic::Ordering::Relaxed);
         self.download_total
@@ -198,3 +513,114 @@ impl Manager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{app::dispatcher::observer::ConnectionObserver, session::Session};
+
+    use super::{DnsQueryLog, Manager};
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(uuid::Uuid, usize, usize)>>);
+
+    impl ConnectionObserver for Recorder {
+        fn on_traffic(&self, id: uuid::Uuid, upload: usize, download: usize) {
+            self.0.lock().unwrap().push((id, upload, download));
+        }
+
+        fn on_connection_close(&self, id: uuid::Uuid) {
+            self.0.lock().unwrap().push((id, 0, 0));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_observers() {
+        let recorder = Arc::new(Recorder::default());
+        let manager = Manager::new(vec![recorder.clone() as _]);
+
+        let id = uuid::Uuid::new_v4();
+        manager.push_uploaded(id, 10);
+        manager.push_downloaded(id, 0);
+        manager.push_downloaded(id, 20);
+        manager.untrack(id);
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![(id, 10, 0), (id, 0, 20), (id, 0, 0)]
+        );
+        assert_eq!(manager.snapshot().await.upload_total, 10);
+    }
+
+    #[tokio::test]
+    async fn test_user_stats() {
+        let manager = Manager::new(vec![]);
+        manager.set_user_quotas([("kid".to_owned(), 100)].into());
+        let sess = |user: &str| Session {
+            user: Some(user.to_owned()),
+            ..Default::default()
+        };
+
+        let kid = manager.user("kid");
+        kid.push_uploaded(40);
+        kid.push_downloaded(50);
+        assert!(!manager.over_quota(&sess("kid")));
+        kid.push_downloaded(10);
+        assert!(manager.over_quota(&sess("kid")));
+
+        manager.user("me").push_downloaded(1000);
+        assert!(!manager.over_quota(&sess("me")));
+        assert!(!manager.over_quota(&Session::default()));
+
+        let s = manager.user_snapshots();
+        assert_eq!((s["kid"].upload, s["kid"].download), (40, 60));
+        assert_eq!((s["me"].download, s["me"].quota), (1000, None));
+
+        assert!(manager.reset_user("kid"));
+        assert!(!manager.over_quota(&sess("kid")));
+        assert!(!manager.reset_user("nobody"));
+    }
+
+    #[tokio::test]
+    async fn test_dns_stats() {
+        let manager = Manager::new(vec![]);
+        let dns = manager.dns();
+        dns.record_upstream("udp://1.1.1.1:53", Duration::from_millis(10), true);
+        dns.record_upstream("udp://1.1.1.1:53", Duration::from_millis(30), true);
+        dns.record_upstream("udp://1.1.1.1:53", Duration::from_secs(3), false);
+        dns.record_upstream("udp://8.8.8.8:53", Duration::from_secs(3), false);
+        dns.record_cache(true);
+        dns.record_cache(false);
+        dns.record_cache(true);
+        dns.record_cache(true);
+
+        let s = dns.snapshot();
+        assert_eq!((s.cache_hits, s.cache_misses), (3, 1));
+        assert_eq!(s.cache_hit_ratio, 0.75);
+        let a = &s.upstreams["udp://1.1.1.1:53"];
+        assert_eq!((a.queries, a.errors), (3, 1));
+        assert!((a.error_rate - 1.0 / 3.0).abs() < 1e-9);
+        assert!((a.avg_latency_ms - 20.0).abs() < 1e-9);
+        let b = &s.upstreams["udp://8.8.8.8:53"];
+        assert_eq!((b.error_rate, b.avg_latency_ms), (1.0, 0.0));
+
+        assert!(!dns.watching());
+        let mut rx = dns.subscribe();
+        assert!(dns.watching());
+        dns.log_query(DnsQueryLog {
+            time: chrono::Utc::now(),
+            name: "example.com.".to_owned(),
+            query_type: "A".to_owned(),
+            source: "cache".to_owned(),
+            rcode: "No Error".to_owned(),
+            answers: vec!["93.184.216.34".to_owned()],
+            latency_ms: 0,
+            error: None,
+        });
+        assert_eq!(rx.recv().await.unwrap().name, "example.com.");
+    }
+}
@@ -133,6 +133,7 @@ impl TrackedStream {
             manager: manager.clone(),
             tracker: Arc::new(TrackerInfo {
                 uuid,
+                user: sess.user.as_deref().map(|x| manager.user(x)),
                 session_holder: sess,
 
                 start_time: chrono::Utc::now(),
@@ -188,9 +189,16 @@ impl AsyncRead for TrackedStream {
             },
         }
 
+        let filled = buf.filled().len();
         let v = Pin::new(self.inner.as_mut()).poll_read(cx, buf);
-        let download = buf.filled().len();
-        self.manager.push_downloaded(download);
+        if let Some(capture) = self.tracker.capture.get() {
+            capture.record(false, &buf.filled()[filled..]);
+        }
+        let download = buf.filled().len() - filled;
+        self.manager.push_downloaded(self.id(), download);
+        if let Some(user) = &self.tracker.user {
+            user.push_downloaded(download);
+        }
         self.tracker
             .download_total
             .fetch_add(download as u64, std::sync::atomic::Ordering::Release);
@@ -220,7 +228,13 @@ impl AsyncWrite for TrackedStream {
             Poll::Ready(Ok(n)) => n,
             _ => return v,
         };
-        self.manager.push_uploaded(upload);
+        if let Some(capture) = self.tracker.capture.get() {
+            capture.record(true, &buf[..upload]);
+        }
+        self.manager.push_uploaded(self.id(), upload);
+        if let Some(user) = &self.tracker.user {
+            user.push_uploaded(upload);
+        }
         self.tracker
             .upload_total
             .fetch_add(upload as u64, std::sync::atomic::Ordering::Release);
@@ -369,6 +383,7 @@ impl TrackedDatagram {
             manager: manager.clone(),
             tracker: Arc::new(TrackerInfo {
                 uuid,
+                user: sess.user.as_deref().map(|x| manager.user(x)),
                 session_holder: sess,
 
                 start_time: chrono::Utc::now(),
@@ -421,7 +436,13 @@ impl Stream for TrackedDatagram {
 
         let r = Pin::new(self.inner.as_mut()).poll_next(cx);
         if let Poll::Ready(Some(ref pkt)) = r {
-            self.manager.push_downloaded(pkt.data.len());
+            if let Some(capture) = self.tracker.capture.get() {
+                capture.record(false, &pkt.data);
+            }
+            self.manager.push_downloaded(self.id(), pkt.data.len());
+            if let Some(user) = &self.tracker.user {
+                user.push_downloaded(pkt.data.len());
+            }
             self.tracker
                 .download_total
                 .fetch_add(pkt.data.len() as u64, std::sync::atomic::Ordering::Relaxed);
@@ -459,7 +480,13 @@ impl Sink<UdpPacket> for TrackedDatagram {
         }
 
         let upload = item.data.len();
-        self.manager.push_uploaded(upload);
+        if let Some(capture) = self.tracker.capture.get() {
+            capture.record(true, &item.data);
+        }
+        self.manager.push_uploaded(self.id(), upload);
+        if let Some(user) = &self.tracker.user {
+            user.push_uploaded(upload);
+        }
         self.tracker
             .upload_total
             .fetch_add(upload as u64, std::sync::atomic::Ordering::Relaxed);
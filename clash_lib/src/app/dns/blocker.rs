@@ -0,0 +1,151 @@
+//! Answers the queries for blocked domains in the DNS server itself, so the
+//! LAN clients asking it never reach the ad and tracker hosts. Lookups clash
+//! makes for its own connections aren't blocked, the rules handle those.
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
+
+use hickory_proto::{
+    op::{Message, Query, ResponseCode},
+    rr::{
+        rdata::{A, AAAA},
+        Name, RData, Record, RecordType,
+    },
+};
+
+use crate::{
+    app::{
+        remote_content_manager::providers::rule_provider::ThreadSafeRuleProvider,
+        router::ThreadSafeRouter,
+    },
+    config::def::DNSBlockMode,
+    session::{Session, SocksAddr},
+    Error,
+};
+
+use super::Config;
+
+/// short, so that unblocking a domain takes effect soon
+const BLOCKED_TTL: u32 = 60;
+
+pub struct DnsBlocker {
+    mode: DNSBlockMode,
+    providers: Vec<ThreadSafeRuleProvider>,
+    /// to also block the domains the rules reject
+    router: Option<ThreadSafeRouter>,
+}
+
+pub type ThreadSafeDnsBlocker = Arc<DnsBlocker>;
+
+impl DnsBlocker {
+    /// None if nothing is to be blocked
+    pub fn new(
+        cfg: &Config,
+        router: &ThreadSafeRouter,
+    ) -> Result<Option<ThreadSafeDnsBlocker>, Error> {
+        let providers = cfg
+            .blocklist
+            .iter()
+            .map(|name| {
+                router
+                    .get_rule_providers()
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| {
+                        Error::InvalidConfig(format!(
+                            "dns blocklist: unknown rule provider {}",
+                            name
+                        ))
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if providers.is_empty() && !cfg.block_rejected {
+            return Ok(None);
+        }
+        Ok(Some(Arc::new(Self {
+            mode: cfg.block_mode,
+            providers,
+            router: cfg.block_rejected.then(|| router.clone()),
+        })))
+    }
+
+    pub fn blocks(&self, name: &Name) -> bool {
+        let domain = name.to_ascii();
+        let domain = domain.trim_end_matches('.');
+        if domain.is_empty() {
+            return false;
+        }
+
+        let sess = Session {
+            destination: SocksAddr::Domain(domain.to_owned(), 0),
+            ..Default::default()
+        };
+        self.providers.iter().any(|x| x.search(&sess))
+            || self.router.as_ref().is_some_and(|x| x.rejects(&sess))
+    }
+
+    /// the answer to a blocked query, without its id and question
+    pub fn answer(&self, query: &Query) -> Message {
+        let mut m = Message::new();
+        match self.mode {
+            DNSBlockMode::Nxdomain => {
+                m.set_response_code(ResponseCode::NXDomain);
+            }
+            DNSBlockMode::NullIp => {
+                let rdata = match query.query_type() {
+                    RecordType::A => Some(RData::A(A(Ipv4Addr::UNSPECIFIED))),
+                    RecordType::AAAA => Some(RData::AAAA(AAAA(Ipv6Addr::UNSPECIFIED))),
+                    _ => None,
+                };
+                if let Some(rdata) = rdata {
+                    m.add_answer(Record::from_rdata(query.name().clone(), BLOCKED_TTL, rdata));
+                }
+            }
+            DNSBlockMode::Empty => {}
+        }
+        m
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::{
+        op::{Query, ResponseCode},
+        rr::{Name, RData, RecordType},
+    };
+
+    use crate::config::def::DNSBlockMode;
+
+    use super::DnsBlocker;
+
+    #[test]
+    fn test_block_answer() {
+        let blocker = |mode| DnsBlocker {
+            mode,
+            providers: vec![],
+            router: None,
+        };
+        let a = Query::query(Name::from_ascii("ads.example.com.").unwrap(), RecordType::A);
+        let mx = Query::query(
+            Name::from_ascii("ads.example.com.").unwrap(),
+            RecordType::MX,
+        );
+
+        let m = blocker(DNSBlockMode::Nxdomain).answer(&a);
+        assert_eq!(m.response_code(), ResponseCode::NXDomain);
+        assert!(m.answers().is_empty());
+
+        let m = blocker(DNSBlockMode::NullIp).answer(&a);
+        assert_eq!(m.response_code(), ResponseCode::NoError);
+        assert!(matches!(m.answers()[0].data(), Some(RData::A(ip)) if ip.0.is_unspecified()));
+        let m = blocker(DNSBlockMode::NullIp).answer(&mx);
+        assert!(m.answers().is_empty());
+
+        let m = blocker(DNSBlockMode::Empty).answer(&a);
+        assert_eq!(m.response_code(), ResponseCode::NoError);
+        assert!(m.answers().is_empty());
+    }
+}
@@ -1,25 +1,29 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Display,
     io::BufReader,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    str::FromStr,
 };
 
+use hickory_proto::{op::ResponseCode, rr::RecordType};
+use indexmap::IndexMap;
 use ipnet::AddrParseError;
 use regex::Regex;
 use rustls::{Certificate, PrivateKey};
+use tracing::warn;
 use url::Url;
 
 use crate::{
-    common::trie,
-    config::def::{DNSListen, DNSMode},
+    common::{tls, trie},
+    config::def::{self, DNSBlockMode, DNSListen, DNSMode, DNSStrategy, NameServerDef},
     Error,
 };
 
 use super::{
     dns_client::DNSNetMode,
     dummy_keys::{TEST_CERT, TEST_KEY},
+    hosts::Hosts,
 };
 
 #[derive(Clone, Debug)]
@@ -27,6 +31,145 @@ pub struct NameServer {
     pub net: DNSNetMode,
     pub address: String,
     pub interface: Option<String>,
+    /// the share of questions it gets with the weighted strategy
+    pub weight: u32,
+    pub doh: DoHOptions,
+    /// the proxy or group the nameserver is asked through
+    pub proxy: Option<String>,
+}
+
+impl NameServer {
+    /// `#en0` names the interface and `#proxy=ProxyGroupA` the proxy to go
+    /// through, a bare name is taken as a proxy later if there is one. DoH
+    /// nameservers also take an HTTP version and headers, e.g.
+    /// `#h2&header=X-Token:abc&interface=en0`
+    fn parse_fragment(&mut self, fragment: &str) -> Result<(), Error> {
+        for (k, v) in url::form_urlencoded::parse(fragment.as_bytes()) {
+            match (k.as_ref(), v.as_ref()) {
+                ("header", header) => {
+                    let (name, value) = header.split_once(':').ok_or_else(|| {
+                        Error::InvalidConfig(format!("invalid header: {}", header))
+                    })?;
+                    self.doh
+                        .headers
+                        .push((name.trim().to_owned(), value.trim().to_owned()));
+                }
+                ("interface", iface) => self.interface = Some(iface.to_owned()),
+                ("proxy", proxy) => self.proxy = Some(proxy.to_owned()),
+                (token, "") => match token.parse::<DoHVersion>() {
+                    Ok(version) => self.doh.http_version = Some(version),
+                    Err(_) => self.interface = Some(token.to_owned()),
+                },
+                (k, _) => return Err(Error::InvalidConfig(format!("unknown option: {}", k))),
+            }
+        }
+        Ok(())
+    }
+
+    /// `#h3` makes a DoH nameserver a DoH3 one, then the HTTP options are
+    /// checked
+    fn finish_doh(&mut self) -> Result<(), Error> {
+        if self.net == DNSNetMode::DoH && self.doh.http_version == Some(DoHVersion::H3) {
+            self.net = DNSNetMode::DoH3;
+            self.doh.http_version = None;
+        }
+        if !self.doh.is_custom() {
+            return Ok(());
+        }
+        match self.net {
+            DNSNetMode::DoH => {}
+            // the h3 client only asks `/dns-query`, with no extra headers
+            DNSNetMode::DoH3 => {
+                return Err(Error::InvalidConfig(format!(
+                    "{}: DoH over HTTP/3 takes no path, headers or http version",
+                    self
+                )))
+            }
+            _ => {
+                return Err(Error::InvalidConfig(format!(
+                    "{} is not DoH, it takes no path, headers or http version",
+                    self
+                )))
+            }
+        }
+        for (name, value) in &self.doh.headers {
+            if http::HeaderName::from_bytes(name.as_bytes()).is_err()
+                || http::HeaderValue::from_str(value).is_err()
+            {
+                return Err(Error::InvalidConfig(format!(
+                    "{}: invalid header {}: {}",
+                    self, name, value
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// with `prefer-h3`, a plain DoH nameserver is asked over HTTP/3 first,
+    /// as `#h3` does
+    fn prefer_h3(&mut self) {
+        if self.net == DNSNetMode::DoH && !self.doh.is_custom() && self.proxy.is_none() {
+            self.net = DNSNetMode::DoH3;
+        }
+    }
+
+    /// a bare `#name` is the proxy or group of that name if there is one,
+    /// an interface otherwise
+    fn resolve_proxy(&mut self, proxies: &HashSet<&str>) -> Result<(), Error> {
+        if self.proxy.is_none()
+            && self
+                .interface
+                .as_deref()
+                .is_some_and(|x| proxies.contains(x))
+        {
+            self.proxy = self.interface.take();
+        }
+        if self.proxy.is_some() && self.interface.is_some() {
+            return Err(Error::InvalidConfig(format!(
+                "{}: a nameserver asked through a proxy has no interface",
+                self
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DoHVersion {
+    Http11,
+    H2,
+    H3,
+}
+
+impl FromStr for DoHVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "1.1" | "h1" | "http/1.1" => Ok(Self::Http11),
+            "2" | "h2" => Ok(Self::H2),
+            "3" | "h3" => Ok(Self::H3),
+            _ => Err(Error::InvalidConfig(format!("invalid http version: {}", s))),
+        }
+    }
+}
+
+/// HTTP settings of a DoH nameserver, for the ones behind CDNs or on
+/// non-standard paths
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DoHOptions {
+    /// `/dns-query` if not set
+    pub path: Option<String>,
+    pub headers: Vec<(String, String)>,
+    /// negotiated if not set
+    pub http_version: Option<DoHVersion>,
+}
+
+impl DoHOptions {
+    /// whether the nameserver needs more than what a plain DoH client does
+    pub fn is_custom(&self) -> bool {
+        self.path.is_some() || !self.headers.is_empty() || self.http_version.is_some()
+    }
 }
 impl Display for NameServer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -40,12 +183,48 @@ impl Display for NameServer {
     }
 }
 
+/// a `nameserver-policy` key naming a set of domains
+#[derive(Clone, Debug, PartialEq)]
+pub enum PolicySet {
+    /// `rule-set:<provider>`
+    RuleSet(String),
+    /// `geosite:<code>`
+    GeoSite(String),
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct FallbackFilter {
     pub geo_ip: bool,
     pub geo_ip_code: String,
     pub ip_cidr: Option<Vec<ipnet::IpNet>>,
     pub domain: Vec<String>,
+    pub geosite: Vec<String>,
+}
+
+/// a `dns.rules` entry
+#[derive(Clone, Debug)]
+pub struct DNSRule {
+    /// domains and wildcards
+    pub domains: Vec<String>,
+    /// all if empty
+    pub query_types: Vec<RecordType>,
+    pub action: DNSRuleAction,
+}
+
+#[derive(Clone, Debug)]
+pub enum DNSRuleAction {
+    StaticIp { ips: Vec<IpAddr>, ttl: u32 },
+    Rcode(ResponseCode),
+    Upstream(Vec<NameServer>),
+}
+
+impl DNSRule {
+    fn nameservers_mut(&mut self) -> &mut [NameServer] {
+        match &mut self.action {
+            DNSRuleAction::Upstream(ns) => ns,
+            _ => Default::default(),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -59,6 +238,21 @@ pub struct DoTConfig {
     pub certificate_and_key: (Vec<Certificate>, PrivateKey),
 }
 
+/// the `dns.example.com` pair DoH and DoT fall back to without `cert` and
+/// `key`
+fn dummy_certificate_and_key() -> (Vec<Certificate>, PrivateKey) {
+    let mut buf_read: Box<dyn std::io::BufRead> = Box::new(BufReader::new(TEST_CERT.as_bytes()));
+    let certs = rustls_pemfile::certs(&mut buf_read)
+        .unwrap()
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+
+    let mut buf_read: Box<dyn std::io::BufRead> = Box::new(BufReader::new(TEST_KEY.as_bytes()));
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut buf_read).unwrap();
+    (certs, PrivateKey(keys.remove(0)))
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct DNSListenAddr {
     pub udp: Option<SocketAddr>,
@@ -79,9 +273,24 @@ pub struct Config {
     pub default_nameserver: Vec<NameServer>,
     pub fake_ip_range: ipnet::IpNet,
     pub fake_ip_filter: Vec<String>,
+    pub fake_ip_auto_skip: bool,
     pub store_fake_ip: bool,
-    pub hosts: Option<trie::StringTrie<IpAddr>>,
+    pub store_dns_cache: bool,
+    pub hosts: Option<Hosts>,
     pub nameserver_policy: HashMap<String, NameServer>,
+    /// the `rule-set:` and `geosite:` keys of `nameserver-policy`, in their
+    /// order, tried after the domains
+    pub set_policy: Vec<(PolicySet, NameServer)>,
+    pub strategy: DNSStrategy,
+    /// seconds, 0 if off
+    pub health_check_interval: u64,
+    pub cache_min_ttl: u32,
+    pub cache_max_ttl: u32,
+    pub serve_stale: bool,
+    pub blocklist: Vec<String>,
+    pub block_rejected: bool,
+    pub block_mode: DNSBlockMode,
+    pub rules: Vec<DNSRule>,
 }
 
 impl Config {
@@ -100,7 +309,6 @@ impl Config {
 
             let host = url.host_str().expect("dns host must be valid");
 
-            let iface = url.fragment();
             let addr: String;
             let net: &str;
 
@@ -121,6 +329,10 @@ impl Config {
                     addr = Config::host_with_default_port(&host, "443")?;
                     net = "DoH";
                 }
+                "h3" => {
+                    addr = Config::host_with_default_port(&host, "443")?;
+                    net = "DoH3";
+                }
                 "dhcp" => {
                     addr = host.to_string();
                     net = "DHCP";
@@ -134,34 +346,189 @@ impl Config {
                 }
             }
 
-            nameservers.push(NameServer {
+            let weight = match url.query_pairs().find(|(k, _)| k == "weight") {
+                Some((_, w)) => w.parse::<u32>().ok().filter(|w| *w > 0).ok_or_else(|| {
+                    Error::InvalidConfig(format!("DNS nameserver [{}] invalid weight: {}", i, w))
+                })?,
+                None => 1,
+            };
+
+            let mut ns = NameServer {
                 address: addr,
                 net: net.parse()?,
-                interface: iface.map(String::from),
-            });
+                interface: None,
+                weight,
+                doh: Default::default(),
+                proxy: None,
+            };
+            if let Some(fragment) = url.fragment() {
+                ns.parse_fragment(fragment)
+                    .map_err(|x| Error::InvalidConfig(format!("DNS nameserver [{}] {}", i, x)))?;
+            }
+            if matches!(ns.net, DNSNetMode::DoH | DNSNetMode::DoH3)
+                && !matches!(url.path(), "" | "/" | "/dns-query")
+            {
+                ns.doh.path = Some(url.path().to_owned());
+            }
+            ns.finish_doh()?;
+
+            nameservers.push(ns);
         }
 
         Ok(nameservers)
     }
 
+    /// nameservers given either as URLs or as objects
+    pub fn parse_nameserver_defs(servers: &[NameServerDef]) -> Result<Vec<NameServer>, Error> {
+        let mut nameservers = vec![];
+
+        for server in servers {
+            match server {
+                NameServerDef::Url(url) => {
+                    nameservers.append(&mut Config::parse_nameserver(&vec![url.clone()])?)
+                }
+                NameServerDef::Detailed(def) => {
+                    let mut ns = Config::parse_nameserver(&vec![def.address.clone()])?.remove(0);
+                    if let Some(path) = &def.path {
+                        ns.doh.path = Some(path.clone());
+                    }
+                    ns.doh
+                        .headers
+                        .extend(def.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+                    if let Some(version) = &def.http_version {
+                        ns.doh.http_version = Some(version.parse()?);
+                    }
+                    if let Some(iface) = &def.interface {
+                        ns.interface = Some(iface.clone());
+                    }
+                    if let Some(weight) = def.weight {
+                        ns.weight = weight.max(1);
+                    }
+                    if let Some(proxy) = &def.proxy {
+                        ns.proxy = Some(proxy.clone());
+                    }
+                    ns.finish_doh()?;
+                    nameservers.push(ns);
+                }
+            }
+        }
+
+        Ok(nameservers)
+    }
+
+    /// a key is a comma separated list of domains, wildcards,
+    /// `rule-set:<provider>` and `geosite:<code>`. The domains go into the
+    /// map, the sets into the list, in the order of the config
     pub fn parse_nameserver_policy(
-        policy_map: &HashMap<String, String>,
-    ) -> Result<HashMap<String, NameServer>, Error> {
+        policy_map: &IndexMap<String, String>,
+    ) -> Result<(HashMap<String, NameServer>, Vec<(PolicySet, NameServer)>), Error> {
         let mut policy = HashMap::new();
+        let mut sets = vec![];
 
-        for (domain, server) in policy_map {
+        for (key, server) in policy_map {
             let nameservers = Config::parse_nameserver(&vec![server.to_owned()])?;
 
-            let (_, valid) = trie::valid_and_split_domain(&domain);
-            if !valid {
-                return Err(Error::InvalidConfig(format!(
-                    "DNS ResolverRule invalid domain: {}",
-                    &domain
-                )));
+            for key in key.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+                match key.split_once(':') {
+                    Some(("rule-set", name)) => {
+                        sets.push((PolicySet::RuleSet(name.to_owned()), nameservers[0].clone()));
+                    }
+                    // the codes are checked as the geosite is loaded
+                    Some(("geosite", code)) => {
+                        sets.push((PolicySet::GeoSite(code.to_owned()), nameservers[0].clone()));
+                    }
+                    _ => {
+                        let (_, valid) = trie::valid_and_split_domain(key);
+                        if !valid || key.contains(':') {
+                            return Err(Error::InvalidConfig(format!(
+                                "DNS ResolverRule invalid domain: {}",
+                                key
+                            )));
+                        }
+                        policy.insert(key.into(), nameservers[0].clone());
+                    }
+                }
             }
-            policy.insert(domain.into(), nameservers[0].clone());
         }
-        Ok(policy)
+        Ok((policy, sets))
+    }
+
+    pub fn parse_rules(rules: &[def::DNSRule]) -> Result<Vec<DNSRule>, Error> {
+        rules
+            .iter()
+            .map(|rule| {
+                let invalid = |msg: String| {
+                    Error::InvalidConfig(format!("dns rule {}: {}", rule.domain, msg))
+                };
+
+                let domains = rule
+                    .domain
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|x| !x.is_empty())
+                    .map(|x| {
+                        let (_, valid) = trie::valid_and_split_domain(x);
+                        if valid && !x.contains(':') {
+                            Ok(x.to_owned())
+                        } else {
+                            Err(invalid(format!("invalid domain {}", x)))
+                        }
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                if domains.is_empty() {
+                    return Err(invalid("no domain".to_owned()));
+                }
+
+                let query_types = rule
+                    .query_type
+                    .iter()
+                    .map(|x| {
+                        RecordType::from_str(&x.to_uppercase())
+                            .map_err(|_| invalid(format!("invalid query type {}", x)))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let action = match rule.action {
+                    def::DNSRuleAction::StaticIp => {
+                        if rule.ip.is_empty() {
+                            return Err(invalid("static-ip needs ip".to_owned()));
+                        }
+                        DNSRuleAction::StaticIp {
+                            ips: rule.ip.clone(),
+                            ttl: rule.ttl.unwrap_or(60),
+                        }
+                    }
+                    def::DNSRuleAction::Nxdomain => DNSRuleAction::Rcode(ResponseCode::NXDomain),
+                    def::DNSRuleAction::Rcode => {
+                        let rcode = rule
+                            .rcode
+                            .as_deref()
+                            .ok_or_else(|| invalid("rcode needs rcode".to_owned()))?;
+                        DNSRuleAction::Rcode(match rcode.to_lowercase().as_str() {
+                            "noerror" => ResponseCode::NoError,
+                            "formerr" => ResponseCode::FormErr,
+                            "servfail" => ResponseCode::ServFail,
+                            "nxdomain" => ResponseCode::NXDomain,
+                            "notimp" => ResponseCode::NotImp,
+                            "refused" => ResponseCode::Refused,
+                            _ => return Err(invalid(format!("unknown rcode {}", rcode))),
+                        })
+                    }
+                    def::DNSRuleAction::Upstream => {
+                        if rule.nameserver.is_empty() {
+                            return Err(invalid("upstream needs nameserver".to_owned()));
+                        }
+                        DNSRuleAction::Upstream(Config::parse_nameserver(&rule.nameserver)?)
+                    }
+                };
+
+                Ok(DNSRule {
+                    domains,
+                    query_types,
+                    action,
+                })
+            })
+            .collect()
     }
 
     pub fn parse_fallback_ip_cidr(ipcidr: &Vec<String>) -> anyhow::Result<Vec<ipnet::IpNet>> {
@@ -177,23 +544,6 @@ impl Config {
         Ok(output)
     }
 
-    pub fn parse_hosts(
-        hosts_mapping: &HashMap<String, String>,
-    ) -> anyhow::Result<trie::StringTrie<IpAddr>> {
-        let mut tree = trie::StringTrie::new();
-        tree.insert(
-            "localhost",
-            Arc::new("127.0.0.1".parse::<IpAddr>().unwrap()),
-        );
-
-        for (host, ip_str) in hosts_mapping.into_iter() {
-            let ip = ip_str.parse::<IpAddr>()?;
-            tree.insert(host.as_str(), Arc::new(ip));
-        }
-
-        Ok(tree)
-    }
-
     pub fn host_with_default_port(host: &str, port: &str) -> Result<String, Error> {
         let has_port_suffix = Regex::new(r":\d+$").unwrap();
 
@@ -224,9 +574,30 @@ impl TryFrom<&crate::config::def::Config> for Config {
             )));
         }
 
-        let nameservers = Config::parse_nameserver(&dc.nameserver)?;
-        let fallback = Config::parse_nameserver(&dc.fallback)?;
-        let nameserver_policy = Config::parse_nameserver_policy(&dc.nameserver_policy)?;
+        let mut nameservers = Config::parse_nameserver_defs(&dc.nameserver)?;
+        let mut fallback = Config::parse_nameserver_defs(&dc.fallback)?;
+        let (mut nameserver_policy, mut set_policy) =
+            Config::parse_nameserver_policy(&dc.nameserver_policy)?;
+        let mut rules = Config::parse_rules(&dc.rules)?;
+
+        let proxies = c
+            .proxy
+            .iter()
+            .chain(&c.proxy_group)
+            .filter_map(|x| x.get("name")?.as_str())
+            .collect::<HashSet<_>>();
+        for ns in nameservers
+            .iter_mut()
+            .chain(&mut fallback)
+            .chain(nameserver_policy.values_mut())
+            .chain(set_policy.iter_mut().map(|(_, ns)| ns))
+            .chain(rules.iter_mut().flat_map(DNSRule::nameservers_mut))
+        {
+            ns.resolve_proxy(&proxies)?;
+            if dc.prefer_h3 {
+                ns.prefer_h3();
+            }
+        }
 
         if dc.default_nameserver.len() == 0 {
             return Err(Error::InvalidConfig(String::from(
@@ -240,6 +611,18 @@ impl TryFrom<&crate::config::def::Config> for Config {
             })?;
         }
         let default_nameserver = Config::parse_nameserver(&dc.default_nameserver)?;
+        let no_hosts = HashMap::new();
+        let hosts = Hosts::parse(
+            if dc.use_hosts { &c.hosts } else { &no_hosts },
+            dc.use_system_hosts,
+        )?;
+
+        if dc.cache_min_ttl > dc.cache_max_ttl {
+            return Err(Error::InvalidConfig(format!(
+                "dns cache-min-ttl {} is above cache-max-ttl {}",
+                dc.cache_min_ttl, dc.cache_max_ttl
+            )));
+        }
 
         Ok(Self {
             enable: dc.enable,
@@ -262,7 +645,25 @@ impl TryFrom<&crate::config::def::Config> for Config {
                             ..Default::default()
                         })
                     }
-                    DNSListen::Multiple(map) => {
+                    DNSListen::Multiple(mut map) => {
+                        let certificate_and_key = match (map.remove("cert"), map.remove("key")) {
+                            (Some(cert), Some(key)) => {
+                                Some(tls::load_certificate_and_key(&cert, &key).map_err(|x| {
+                                    Error::InvalidConfig(format!(
+                                        "invalid dns listen cert {} or key {}: {}",
+                                        cert, key, x
+                                    ))
+                                })?)
+                            }
+                            (None, None) => None,
+                            _ => {
+                                return Err(Error::InvalidConfig(
+                                    "dns listen needs both cert and key".to_owned(),
+                                ))
+                            }
+                        };
+                        let dns_hostname = map.remove("hostname");
+
                         let mut udp = None;
                         let mut tcp = None;
                         let mut doh = None;
@@ -278,39 +679,19 @@ impl TryFrom<&crate::config::def::Config> for Config {
                                 "udp" => udp = Some(addr),
                                 "tcp" => tcp = Some(addr),
                                 "doh" => {
-                                    let mut buf_read: Box<dyn std::io::BufRead> =
-                                        Box::new(BufReader::new(TEST_CERT.as_bytes()));
-                                    let certs = rustls_pemfile::certs(&mut buf_read)
-                                        .unwrap()
-                                        .into_iter()
-                                        .map(Certificate)
-                                        .collect::<Vec<_>>();
-
-                                    let mut buf_read: Box<dyn std::io::BufRead> =
-                                        Box::new(BufReader::new(TEST_KEY.as_bytes()));
-                                    let mut keys =
-                                        rustls_pemfile::pkcs8_private_keys(&mut buf_read).unwrap();
                                     let c = DoHConfig {
-                                        certificate_and_key: (certs, PrivateKey(keys.remove(0))),
-                                        dns_hostname: Some("dns.example.com".to_owned()),
+                                        certificate_and_key: certificate_and_key
+                                            .clone()
+                                            .unwrap_or_else(dummy_certificate_and_key),
+                                        dns_hostname: dns_hostname.clone(),
                                     };
                                     doh = Some((addr, c))
                                 }
                                 "dot" => {
-                                    let mut buf_read: Box<dyn std::io::BufRead> =
-                                        Box::new(BufReader::new(TEST_CERT.as_bytes()));
-                                    let certs = rustls_pemfile::certs(&mut buf_read)
-                                        .unwrap()
-                                        .into_iter()
-                                        .map(Certificate)
-                                        .collect::<Vec<_>>();
-
-                                    let mut buf_read: Box<dyn std::io::BufRead> =
-                                        Box::new(BufReader::new(TEST_KEY.as_bytes()));
-                                    let mut keys =
-                                        rustls_pemfile::pkcs8_private_keys(&mut buf_read).unwrap();
                                     let c = DoTConfig {
-                                        certificate_and_key: (certs, PrivateKey(keys.remove(0))),
+                                        certificate_and_key: certificate_and_key
+                                            .clone()
+                                            .unwrap_or_else(dummy_certificate_and_key),
                                     };
                                     dot = Some((addr, c))
                                 }
@@ -322,6 +703,12 @@ impl TryFrom<&crate::config::def::Config> for Config {
                                 }
                             }
                         }
+                        if certificate_and_key.is_none() && (doh.is_some() || dot.is_some()) {
+                            warn!(
+                                "dns listen cert and key are not set, DoH and DoT are served \
+                                 with a dummy certificate clients won't trust"
+                            );
+                        }
 
                         Ok(DNSListenAddr { udp, tcp, doh, dot })
                     }
@@ -335,18 +722,21 @@ impl TryFrom<&crate::config::def::Config> for Config {
                 .parse::<ipnet::IpNet>()
                 .map_err(|_| Error::InvalidConfig(String::from("invalid fake ip range")))?,
             fake_ip_filter: dc.fake_ip_filter.clone(),
+            fake_ip_auto_skip: dc.fake_ip_auto_skip,
             store_fake_ip: c.profile.store_fake_ip,
-            hosts: if dc.user_hosts && c.hosts.len() > 0 {
-                Config::parse_hosts(&c.hosts).ok()
-            } else {
-                let mut tree = trie::StringTrie::new();
-                tree.insert(
-                    "localhost",
-                    Arc::new("127.0.0.1".parse::<IpAddr>().unwrap()),
-                );
-                Some(tree)
-            },
+            store_dns_cache: c.profile.store_dns_cache,
+            hosts: Some(hosts),
             nameserver_policy,
+            set_policy,
+            strategy: dc.strategy,
+            health_check_interval: dc.health_check_interval,
+            cache_min_ttl: dc.cache_min_ttl,
+            cache_max_ttl: dc.cache_max_ttl,
+            serve_stale: dc.serve_stale,
+            blocklist: dc.blocklist.clone(),
+            block_rejected: dc.block_rejected,
+            block_mode: dc.block_mode,
+            rules,
         })
     }
 }
@@ -359,6 +749,242 @@ impl From<crate::config::def::FallbackFilter> for FallbackFilter {
             geo_ip_code: c.geo_ip_code,
             ip_cidr: ipcidr.ok(),
             domain: c.domain,
+            geosite: c.geosite,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        app::dns::{
+            dns_client::DNSNetMode,
+            dummy_keys::{TEST_CERT, TEST_KEY},
+        },
+        config::def,
+    };
+    use hickory_proto::{op::ResponseCode, rr::RecordType};
+
+    use super::{Config, DNSRuleAction, DoHVersion, PolicySet};
+
+    #[test]
+    fn test_parse_listen() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("dns.crt");
+        let key = dir.path().join("dns.key");
+        std::fs::write(&cert, TEST_CERT).unwrap();
+        std::fs::write(&key, TEST_KEY).unwrap();
+
+        let cfg = format!(
+            r#"
+dns:
+  listen:
+    tcp: 127.0.0.1:5353
+    dot: 127.0.0.1:5355
+    cert: {}
+    key: {}
+    hostname: dns.example.com
+"#,
+            cert.display(),
+            key.display()
+        )
+        .parse::<def::Config>()
+        .unwrap();
+        let listen = Config::try_from(&cfg).unwrap().listen;
+        assert_eq!(listen.tcp, Some("127.0.0.1:5353".parse().unwrap()));
+        assert!(listen.dot.is_some());
+        assert!(listen.udp.is_none() && listen.doh.is_none());
+
+        let cfg = format!(
+            "dns:\n  listen:\n    dot: 127.0.0.1:5355\n    cert: {}\n",
+            cert.display()
+        )
+        .parse::<def::Config>()
+        .unwrap();
+        assert!(Config::try_from(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_prefer_h3() {
+        let cfg = r#"
+dns:
+  enable: true
+  prefer-h3: true
+  nameserver:
+    - https://1.1.1.1/dns-query
+    - https://dns.example/resolve#h2
+    - tls://8.8.8.8
+"#
+        .parse::<def::Config>()
+        .unwrap();
+        let ns = Config::try_from(&cfg).unwrap().nameserver;
+        assert_eq!(ns[0].net, DNSNetMode::DoH3);
+        // the custom ones keep what they ask for
+        assert_eq!(ns[1].net, DNSNetMode::DoH);
+        assert_eq!(ns[2].net, DNSNetMode::DoT);
+    }
+
+    #[test]
+    fn test_parse_doh_options() {
+        let ns = Config::parse_nameserver(&vec![
+            "https://dns.example/resolve#h2&header=X-Token:abc&interface=en0".to_owned(),
+            "https://1.1.1.1/dns-query".to_owned(),
+            "udp://8.8.8.8#en1".to_owned(),
+        ])
+        .unwrap();
+
+        assert_eq!(ns[0].net, DNSNetMode::DoH);
+        assert_eq!(ns[0].doh.path.as_deref(), Some("/resolve"));
+        assert_eq!(ns[0].doh.http_version, Some(DoHVersion::H2));
+        assert_eq!(
+            ns[0].doh.headers,
+            vec![("X-Token".to_owned(), "abc".to_owned())]
+        );
+        assert_eq!(ns[0].interface.as_deref(), Some("en0"));
+        assert!(!ns[1].doh.is_custom());
+        assert_eq!(ns[2].interface.as_deref(), Some("en1"));
+
+        assert!(Config::parse_nameserver(&vec!["tls://1.1.1.1#h2".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_parse_nameserver_policy() {
+        let (policy, sets) = Config::parse_nameserver_policy(
+            &[
+                ("rule-set:ads".to_owned(), "10.0.0.2".to_owned()),
+                ("+.corp.example, *.lan".to_owned(), "10.0.0.1".to_owned()),
+                (
+                    "geosite:cn, rule-set:cn-sites".to_owned(),
+                    "223.5.5.5".to_owned(),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        )
+        .unwrap();
+
+        assert_eq!(policy.len(), 2);
+        assert_eq!(policy["+.corp.example"].address, "10.0.0.1:53");
+        assert_eq!(policy["*.lan"].address, "10.0.0.1:53");
+        // in the order of the config
+        assert_eq!(
+            sets.iter().map(|x| &x.0).collect::<Vec<_>>(),
+            [
+                &PolicySet::RuleSet("ads".to_owned()),
+                &PolicySet::GeoSite("cn".to_owned()),
+                &PolicySet::RuleSet("cn-sites".to_owned()),
+            ]
+        );
+        assert_eq!(sets[0].1.address, "10.0.0.2:53");
+        assert_eq!(sets[2].1.address, "223.5.5.5:53");
+
+        for key in ["unknown:cn", "bad..domain"] {
+            let policy = [(key.to_owned(), "10.0.0.1".to_owned())]
+                .into_iter()
+                .collect();
+            assert!(Config::parse_nameserver_policy(&policy).is_err());
         }
     }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules: Vec<def::DNSRule> = serde_yaml::from_str(
+            r#"
+- domain: nas.home.arpa
+  action: static-ip
+  ip: [192.168.1.10]
+- domain: +.ads.example, tracker.example
+  query-type: [a, HTTPS]
+  action: nxdomain
+- domain: +.example.org
+  action: rcode
+  rcode: Refused
+- domain: +.corp.example
+  action: upstream-group
+  nameserver: [10.0.0.1, tls://10.0.0.2:853]
+"#,
+        )
+        .unwrap();
+        let rules = Config::parse_rules(&rules).unwrap();
+
+        assert!(matches!(
+            &rules[0].action,
+            DNSRuleAction::StaticIp { ips, ttl: 60 } if ips == &["192.168.1.10".parse::<std::net::IpAddr>().unwrap()]
+        ));
+        assert_eq!(rules[1].domains, vec!["+.ads.example", "tracker.example"]);
+        assert_eq!(rules[1].query_types, vec![RecordType::A, RecordType::HTTPS]);
+        assert!(matches!(
+            rules[1].action,
+            DNSRuleAction::Rcode(ResponseCode::NXDomain)
+        ));
+        assert!(matches!(
+            rules[2].action,
+            DNSRuleAction::Rcode(ResponseCode::Refused)
+        ));
+        match &rules[3].action {
+            DNSRuleAction::Upstream(ns) => {
+                assert_eq!(ns[0].address, "10.0.0.1:53");
+                assert_eq!(ns[1].net, DNSNetMode::DoT);
+            }
+            x => panic!("unexpected {:?}", x),
+        }
+
+        for rule in [
+            "{domain: a.example, action: static-ip}",
+            "{domain: a.example, action: rcode, rcode: bogus}",
+            "{domain: a.example, action: upstream}",
+            "{domain: a.example, query-type: [NOPE], action: nxdomain}",
+            "{domain: 'bad..domain', action: nxdomain}",
+        ] {
+            let rule: def::DNSRule = serde_yaml::from_str(rule).unwrap();
+            assert!(Config::parse_rules(&[rule]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_doh3() {
+        let ns = Config::parse_nameserver(&vec![
+            "h3://dns.google/dns-query".to_owned(),
+            "https://1.1.1.1#h3".to_owned(),
+        ])
+        .unwrap();
+
+        assert_eq!(ns[0].net, DNSNetMode::DoH3);
+        assert_eq!(ns[0].address, "dns.google:443");
+        assert!(!ns[0].doh.is_custom());
+        assert_eq!(ns[1].net, DNSNetMode::DoH3);
+        assert_eq!(ns[1].doh.http_version, None);
+
+        assert!(Config::parse_nameserver(&vec!["h3://dns.example/resolve".to_owned()]).is_err());
+        assert!(
+            Config::parse_nameserver(&vec!["h3://1.1.1.1#header=X-Token:abc".to_owned()]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_nameserver_proxy() {
+        let mut ns = Config::parse_nameserver(&vec![
+            "https://1.1.1.1/dns-query#ProxyGroupA".to_owned(),
+            "tls://1.1.1.1#proxy=ProxyGroupB".to_owned(),
+            "udp://8.8.8.8#en0".to_owned(),
+        ])
+        .unwrap();
+        let proxies = ["ProxyGroupA", "ProxyGroupB"].into_iter().collect();
+        for ns in &mut ns {
+            ns.resolve_proxy(&proxies).unwrap();
+        }
+
+        assert_eq!(ns[0].proxy.as_deref(), Some("ProxyGroupA"));
+        assert_eq!(ns[0].interface, None);
+        assert_eq!(ns[1].proxy.as_deref(), Some("ProxyGroupB"));
+        assert_eq!(ns[2].proxy, None);
+        assert_eq!(ns[2].interface.as_deref(), Some("en0"));
+
+        let mut ns = Config::parse_nameserver(&vec![
+            "tcp://1.1.1.1#proxy=ProxyGroupA&interface=en0".to_owned(),
+        ])
+        .unwrap()
+        .remove(0);
+        assert!(ns.resolve_proxy(&proxies).is_err());
+    }
 }
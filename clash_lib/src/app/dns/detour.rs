@@ -0,0 +1,247 @@
+//! Nameservers asked through an outbound, e.g.
+//! `https://1.1.1.1/dns-query#ProxyGroupA`, so the queries for the domains
+//! resolved on the remote side leave through the tunnel too.
+//!
+//! The proxy servers themselves are resolved with `default-nameserver`,
+//! which are always asked directly, so a query never waits on the proxy it
+//! is sent through.
+
+use std::{
+    fmt::{Debug, Formatter},
+    io,
+    sync::{Arc, OnceLock, Weak},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt};
+use hickory_proto::op::Message;
+use rustls::{ClientConfig, ServerName};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::TlsConnector;
+
+use crate::{
+    app::outbound::manager::{OutboundManager, ThreadSafeOutboundManager},
+    common::{
+        errors::{map_io_error, new_io_error},
+        tls::{self, GLOBAL_ROOT_STORE},
+    },
+    proxy::{datagram::UdpPacket, AnyOutboundHandler, AnyStream},
+    session::{Network, Session, SocksAddr},
+    Error,
+};
+
+use super::{dns_client::DNSNetMode, Client, ThreadSafeDNSResolver};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the outbounds of a resolver, set once they are built, after the resolver
+pub type OutboundSlot = Arc<OnceLock<Weak<OutboundManager>>>;
+
+pub fn set_outbounds(slot: &OutboundSlot, outbounds: &ThreadSafeOutboundManager) {
+    slot.set(Arc::downgrade(outbounds)).ok();
+}
+
+/// how to reach a nameserver through the outbound named `proxy`
+#[derive(Clone)]
+pub struct Detour {
+    proxy: String,
+    outbounds: OutboundSlot,
+    /// hands the proxy server addresses to the outbound
+    resolver: ThreadSafeDNSResolver,
+    host: String,
+    port: u16,
+}
+
+impl Detour {
+    pub fn new(
+        proxy: String,
+        outbounds: OutboundSlot,
+        resolver: ThreadSafeDNSResolver,
+        host: String,
+        port: u16,
+    ) -> Self {
+        Self {
+            proxy,
+            outbounds,
+            resolver,
+            host,
+            port,
+        }
+    }
+
+    /// looked up on each query, so a group follows its selection
+    fn handler(&self) -> io::Result<AnyOutboundHandler> {
+        let outbounds = self
+            .outbounds
+            .get()
+            .and_then(Weak::upgrade)
+            .ok_or_else(|| new_io_error("the outbounds are not loaded yet"))?;
+        outbounds
+            .get_outbound(&self.proxy)
+            .ok_or_else(|| new_io_error(&format!("unknown proxy {}", self.proxy)))
+    }
+
+    /// the nameserver hostname is resolved on the remote side
+    fn session(&self, network: Network) -> io::Result<Session> {
+        Ok(Session {
+            network,
+            destination: SocksAddr::try_from((self.host.clone(), self.port))?,
+            ..Default::default()
+        })
+    }
+
+    pub async fn connect_stream(&self) -> io::Result<AnyStream> {
+        let sess = self.session(Network::Tcp)?;
+        let stream = self
+            .handler()?
+            .connect_stream(&sess, self.resolver.clone())
+            .await?;
+        Ok(Box::new(stream) as AnyStream)
+    }
+
+    async fn exchange_datagram(&self, query: Vec<u8>) -> io::Result<Vec<u8>> {
+        let sess = self.session(Network::Udp)?;
+        let handler = self.handler()?;
+        let mut datagram = handler
+            .connect_datagram(&sess, self.resolver.clone())
+            .await?;
+        datagram
+            .send(UdpPacket {
+                data: query,
+                src_addr: SocksAddr::any_ipv4(),
+                dst_addr: sess.destination.clone(),
+            })
+            .await?;
+        datagram
+            .next()
+            .await
+            .map(|x| x.data)
+            .ok_or_else(|| new_io_error("proxy closed the datagram"))
+    }
+
+    fn id(&self, net: &DNSNetMode) -> String {
+        format!("{}#{}:{}#{}", net, self.host, self.port, self.proxy)
+    }
+}
+
+impl Debug for Detour {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Detour")
+            .field("proxy", &self.proxy)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+/// a UDP, TCP or DoT nameserver behind a proxy, with a connection per query
+/// as the proxy may be switched in between
+#[derive(Debug)]
+pub struct ProxiedClient {
+    net: DNSNetMode,
+    detour: Detour,
+    tls: Option<Arc<ClientConfig>>,
+}
+
+impl ProxiedClient {
+    pub fn new(net: DNSNetMode, detour: Detour) -> Result<Self, Error> {
+        let tls = match net {
+            DNSNetMode::UDP | DNSNetMode::TCP => None,
+            DNSNetMode::DoT => {
+                let mut tls_config = ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(GLOBAL_ROOT_STORE.clone())
+                    .with_no_client_auth();
+                tls_config.alpn_protocols = vec!["dot".into()];
+                if detour.host.parse::<std::net::IpAddr>().is_ok() {
+                    tls_config
+                        .dangerous()
+                        .set_certificate_verifier(Arc::new(tls::NoHostnameTlsVerifier));
+                }
+                Some(Arc::new(tls_config))
+            }
+            _ => {
+                return Err(Error::InvalidConfig(format!(
+                    "{} nameservers can't be asked through a proxy",
+                    net
+                )))
+            }
+        };
+        Ok(Self { net, detour, tls })
+    }
+}
+
+/// RFC 1035 4.2.2, each message is prefixed with its length
+async fn exchange_stream<S>(mut stream: S, query: &[u8]) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let len = u16::try_from(query.len()).map_err(|_| new_io_error("query too long"))?;
+    let mut buf = len.to_be_bytes().to_vec();
+    buf.extend_from_slice(query);
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+
+    let len = stream.read_u16().await?;
+    let mut answer = vec![0; len as usize];
+    stream.read_exact(&mut answer).await?;
+    Ok(answer)
+}
+
+#[async_trait]
+impl Client for ProxiedClient {
+    fn id(&self) -> String {
+        self.detour.id(&self.net)
+    }
+
+    async fn exchange(&self, msg: &Message) -> anyhow::Result<Message> {
+        let query = msg.to_vec()?;
+        let answer = tokio::time::timeout(TIMEOUT, async {
+            match &self.tls {
+                None if self.net == DNSNetMode::UDP => self.detour.exchange_datagram(query).await,
+                None => {
+                    let stream = self.detour.connect_stream().await?;
+                    exchange_stream(stream, &query).await
+                }
+                Some(tls_config) => {
+                    let stream = self.detour.connect_stream().await?;
+                    let server_name =
+                        ServerName::try_from(self.detour.host.as_str()).map_err(map_io_error)?;
+                    let stream = TlsConnector::from(tls_config.clone())
+                        .connect(server_name, stream)
+                        .await?;
+                    exchange_stream(stream, &query).await
+                }
+            }
+        })
+        .await
+        .map_err(|_| Error::DNSError(format!("{} query timeout", self.id())))??;
+
+        Ok(Message::from_vec(&answer)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::exchange_stream;
+
+    #[tokio::test]
+    async fn test_exchange_stream() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let nameserver = tokio::spawn(async move {
+            let len = server.read_u16().await.unwrap();
+            let mut query = vec![0; len as usize];
+            server.read_exact(&mut query).await.unwrap();
+            assert_eq!(query, b"query");
+
+            server.write_all(b"\x00\x06answer").await.unwrap();
+        });
+
+        let answer = exchange_stream(client, b"query").await.unwrap();
+        assert_eq!(answer, b"answer");
+        nameserver.await.unwrap();
+    }
+}
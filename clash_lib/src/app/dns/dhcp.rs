@@ -1,3 +1,11 @@
+//! `dhcp://en0`, the nameservers handed out by DHCP on an interface, or on
+//! several, `dhcp://en0,wlan0`, in which case the servers of all those that
+//! are up are asked.
+//!
+//! The interfaces are checked in the background, a server list is probed
+//! again once its lease is half through, or right away when the address of
+//! the interface changes, e.g. on switching networks.
+
 use crate::dns::dns_client::DNSNetMode;
 use crate::dns::helper::make_clients;
 use crate::dns::{Client, Resolver, ThreadSafeDNSClient};
@@ -5,12 +13,13 @@ use crate::proxy::utils::{new_udp_socket, Interface};
 use crate::{dns_debug, dns_warn};
 use async_trait::async_trait;
 use dhcproto::{Decodable, Encodable};
+use futures::future::BoxFuture;
 use futures::FutureExt;
 use network_interface::{Addr, NetworkInterfaceConfig};
+use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
 use std::net::Ipv4Addr;
-use std::ops::Add;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::time::{Duration, Instant};
 use std::{env, io};
 use tokio::net::UdpSocket;
@@ -18,31 +27,45 @@ use tokio::sync::Mutex;
 use tokio::task::yield_now;
 
 use hickory_proto::op::Message;
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 
 use super::config::NameServer;
 
+/// how often the interfaces are checked for changes
 const IFACE_TTL: Duration = Duration::from_secs(20);
+/// how long a server list is used if the offer has no lease time
 const DHCP_TTL: Duration = Duration::from_secs(3600);
+/// the shortest a server list is used, for the leases of a few seconds
+const MIN_DHCP_TTL: Duration = Duration::from_secs(60);
 const DHCP_TIMEOUT: Duration = Duration::from_secs(60);
 
-struct Inner {
-    clients: Vec<ThreadSafeDNSClient>,
-    iface_expires_at: std::time::Instant,
-    dns_expires_at: std::time::Instant,
+#[derive(Debug)]
+struct Offer {
+    dns: Vec<Ipv4Addr>,
+    lease_time: Option<Duration>,
+}
+
+/// the nameservers learned on one interface
+struct Lease {
+    /// the network of the interface when they were probed
     iface_addr: ipnet::IpNet,
+    clients: Vec<ThreadSafeDNSClient>,
+    expires_at: Instant,
 }
 
 pub struct DhcpClient {
-    iface: String,
+    ifaces: Vec<String>,
 
-    inner: Mutex<Inner>,
+    /// by interface, those down or without a DHCP server have none
+    leases: Mutex<HashMap<String, Lease>>,
+    /// one refresh at a time
+    refreshing: Mutex<()>,
 }
 
 impl Debug for DhcpClient {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("DhcpClient")
-            .field("iface", &self.iface)
+            .field("ifaces", &self.ifaces)
             .finish()
     }
 }
@@ -50,7 +73,7 @@ impl Debug for DhcpClient {
 #[async_trait]
 impl Client for DhcpClient {
     fn id(&self) -> String {
-        format!("dhcp#{}", self.iface)
+        format!("dhcp#{}", self.ifaces.join(","))
     }
 
     async fn exchange(&self, msg: &Message) -> anyhow::Result<Message> {
@@ -60,112 +83,165 @@ impl Client for DhcpClient {
             dbg_str.push(format!("{:?}", c));
         }
         debug!("using clients: {:?}", dbg_str);
-        tokio::time::timeout(DHCP_TIMEOUT, Resolver::batch_exchange(&clients, msg)).await?
+        tokio::time::timeout(DHCP_TIMEOUT, Resolver::batch_exchange(&clients, msg, None)).await?
     }
 }
 
 impl DhcpClient {
-    pub async fn new(iface: &str) -> Self {
-        Self {
-            iface: iface.to_owned(),
-            inner: Mutex::new(Inner {
-                clients: vec![],
-                iface_expires_at: Instant::now(),
-                dns_expires_at: Instant::now(),
-                iface_addr: ipnet::IpNet::default(),
-            }),
-        }
+    /// `ifaces` is a comma separated list of interface names
+    pub async fn new(ifaces: &str) -> Arc<Self> {
+        let client = Arc::new(Self {
+            ifaces: ifaces
+                .split(',')
+                .map(|x| x.trim().to_owned())
+                .filter(|x| !x.is_empty())
+                .collect(),
+            leases: Default::default(),
+            refreshing: Default::default(),
+        });
+        tokio::spawn(watch(Arc::downgrade(&client)));
+        client
     }
 
     async fn resolve(&self) -> io::Result<Vec<ThreadSafeDNSClient>> {
-        let expired = self.update_if_lease_expired().await?;
-        if expired {
-            let dns = probe_dns_server(&self.iface).await?;
-            let mut inner = self.inner.lock().await;
-
-            inner.clients = make_clients(
-                dns.into_iter()
-                    .map(|s| NameServer {
-                        net: DNSNetMode::UDP,
-                        address: format!("{}:53", s.to_string()),
-                        interface: None,
-                    })
-                    .collect(),
-                None,
-            )
-            .await;
-        }
-
-        Ok(self.inner.lock().await.clients.clone())
-    }
-
-    /// Check if interface updated or DHCP changed
-    /// and update if necessary
-    async fn update_if_lease_expired(&self) -> io::Result<bool> {
-        let mut inner = self.inner.lock().await;
-        if inner.clients.is_empty() {
-            return Ok(true);
+        if self.leases.lock().await.is_empty() {
+            // the first query may come before the first refresh is done
+            self.refresh().await;
         }
 
-        if Instant::now() < inner.iface_expires_at {
-            return Ok(false);
+        let clients = self
+            .leases
+            .lock()
+            .await
+            .values()
+            .flat_map(|x| x.clients.clone())
+            .collect::<Vec<_>>();
+        if clients.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("no nameservers from DHCP on {}", self.ifaces.join(",")),
+            ));
         }
+        Ok(clients)
+    }
 
-        inner.iface_expires_at = Instant::now().add(IFACE_TTL);
+    /// probes the interfaces whose address changed or whose lease is
+    /// half through, a lease is kept if probing it again fails but the
+    /// address is the same, the servers are likely still there
+    async fn refresh(&self) {
+        let _refreshing = self.refreshing.lock().await;
+
+        let ifaces = match network_interface::NetworkInterface::show() {
+            Ok(x) => x,
+            Err(e) => {
+                dns_warn!("failed to list interfaces: {:?}", e);
+                return;
+            }
+        };
 
-        let iface = network_interface::NetworkInterface::show()
-            .map_err(|x| io::Error::new(io::ErrorKind::Other, format!("list ifaces: {:?}", x)))?
-            .into_iter()
-            .find(|x| {
-                x.name == self.iface && x.addr.first().map(|x| x.ip().is_ipv4()).unwrap_or(false)
-            })
-            .ok_or(io::Error::new(
-                io::ErrorKind::Other,
-                format!("can not find interface: {}", self.iface),
-            ))?;
+        for name in &self.ifaces {
+            let addr = ifaces
+                .iter()
+                .filter(|x| &x.name == name)
+                .find_map(|x| iface_net(&x.addr));
+            let addr = match addr {
+                Some(addr) => addr,
+                None => {
+                    if self.leases.lock().await.remove(name).is_some() {
+                        info!("DHCP interface {} is down or has no IPv4", name);
+                    }
+                    continue;
+                }
+            };
 
-        // TODO: this API changed, need to check if .first() is expected. same to L103
-        let addr = iface.addr.first().ok_or(io::Error::new(
-            io::ErrorKind::Other,
-            format!("no address on interface: {}", self.iface),
-        ))?;
-
-        match addr {
-            Addr::V4(v4) => {
-                if Instant::now() < inner.dns_expires_at
-                    && inner.iface_addr.addr() == v4.ip
-                    && inner.iface_addr.netmask()
-                        == v4.netmask.ok_or(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("no netmask on iface: {}", self.iface),
-                        ))?
-                {
-                    Ok(false)
-                } else {
-                    inner.dns_expires_at = Instant::now().add(DHCP_TTL);
-                    inner.iface_addr = ipnet::IpNet::new(
-                        v4.ip.into(),
-                        u32::from(
-                            v4.netmask
-                                .ok_or(io::Error::new(io::ErrorKind::Other, "no netmask"))?,
-                        )
-                        .count_ones() as _,
+            let now = Instant::now();
+            let same_addr = match self.leases.lock().await.get(name) {
+                Some(lease) if lease.iface_addr == addr => {
+                    if now < lease.expires_at {
+                        continue;
+                    }
+                    true
+                }
+                _ => false,
+            };
+
+            match probe_dns_server(name).await {
+                Ok(offer) => {
+                    info!("got nameservers {:?} from DHCP on {}", offer.dns, name);
+                    let clients = make_clients(
+                        offer
+                            .dns
+                            .iter()
+                            .map(|s| NameServer {
+                                net: DNSNetMode::UDP,
+                                address: format!("{}:53", s),
+                                interface: Some(name.clone()),
+                                weight: 1,
+                                doh: Default::default(),
+                                proxy: None,
+                            })
+                            .collect(),
+                        None,
+                        &Default::default(),
                     )
-                    .map_err(|_x| {
-                        io::Error::new(
-                            io::ErrorKind::Other,
-                            format!(
-                                "invalid netmask: {}",
-                                v4.netmask.expect("expect netmask parse error")
-                            ),
-                        )
-                    })?;
-                    Ok(true)
+                    .await;
+                    self.leases.lock().await.insert(
+                        name.clone(),
+                        Lease {
+                            iface_addr: addr,
+                            clients,
+                            expires_at: now + refresh_after(offer.lease_time),
+                        },
+                    );
                 }
+                Err(e) if same_addr => {
+                    dns_warn!(
+                        "failed to probe DHCP on {}, keeping the nameservers: {}",
+                        name,
+                        e
+                    );
+                }
+                Err(e) => {
+                    dns_warn!("failed to probe DHCP on {}: {}", name, e);
+                    self.leases.lock().await.remove(name);
+                }
+            }
+        }
+    }
+}
+
+/// checks the interfaces until the client is dropped. Boxed, as the
+/// clients a refresh makes may be DHCP ones watched in turn
+fn watch(client: Weak<DhcpClient>) -> BoxFuture<'static, ()> {
+    async move {
+        let mut interval = tokio::time::interval(IFACE_TTL);
+        loop {
+            interval.tick().await;
+            match client.upgrade() {
+                Some(client) => client.refresh().await,
+                None => return,
             }
-            Addr::V6(_) => unreachable!("should only run on V4"),
         }
     }
+    .boxed()
+}
+
+/// the IPv4 network of an interface, it may have IPv6 ones before it
+fn iface_net(addrs: &[Addr]) -> Option<ipnet::IpNet> {
+    addrs.iter().find_map(|x| match x {
+        Addr::V4(v4) => {
+            let prefix = u32::from(v4.netmask?).count_ones() as u8;
+            ipnet::IpNet::new(v4.ip.into(), prefix).ok()
+        }
+        Addr::V6(_) => None,
+    })
+}
+
+/// DHCP clients renew at half the lease time, so do the nameservers
+fn refresh_after(lease_time: Option<Duration>) -> Duration {
+    lease_time
+        .map(|x| (x / 2).max(MIN_DHCP_TTL))
+        .unwrap_or(DHCP_TTL)
 }
 
 async fn listen_dhcp_client(iface: &str) -> io::Result<UdpSocket> {
@@ -183,7 +259,7 @@ async fn listen_dhcp_client(iface: &str) -> io::Result<UdpSocket> {
     .await
 }
 
-async fn probe_dns_server(iface: &str) -> io::Result<Vec<Ipv4Addr>> {
+async fn probe_dns_server(iface: &str) -> io::Result<Offer> {
     dns_debug!("probing NS servers from DHCP");
     let socket = listen_dhcp_client(iface).await?;
 
@@ -221,9 +297,10 @@ async fn probe_dns_server(iface: &str) -> io::Result<Vec<Ipv4Addr>> {
             dhcproto::v4::OptionCode::Router,
             dhcproto::v4::OptionCode::DomainNameServer,
             dhcproto::v4::OptionCode::DomainName,
+            dhcproto::v4::OptionCode::AddressLeaseTime,
         ]));
 
-    let (mut tx, rx) = tokio::sync::oneshot::channel::<Vec<Ipv4Addr>>();
+    let (mut tx, rx) = tokio::sync::oneshot::channel::<Offer>();
 
     let mut rx = rx.fuse();
 
@@ -258,7 +335,19 @@ async fn probe_dns_server(iface: &str) -> io::Result<Vec<Ipv4Addr>> {
                                                         "got NS servers {:?} from DHCP",
                                                         dns
                                                     );
-                                                    return dns.clone();
+                                                    let lease_time = match reply
+                                                        .opts()
+                                                        .get(dhcproto::v4::OptionCode::AddressLeaseTime)
+                                                    {
+                                                        Some(dhcproto::v4::DhcpOption::AddressLeaseTime(x)) => {
+                                                            Some(Duration::from_secs(*x as u64))
+                                                        }
+                                                        _ => None,
+                                                    };
+                                                    return Offer {
+                                                        dns: dns.clone(),
+                                                        lease_time,
+                                                    };
                                                 }
                                                 _ => yield_now().await,
                                             }
@@ -297,12 +386,44 @@ async fn probe_dns_server(iface: &str) -> io::Result<Vec<Ipv4Addr>> {
 
 #[cfg(test)]
 mod test {
-    use crate::dns::dhcp::probe_dns_server;
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use network_interface::{Addr, V4IfAddr, V6IfAddr};
+
+    use crate::dns::dhcp::{iface_net, probe_dns_server, refresh_after, DHCP_TTL, MIN_DHCP_TTL};
 
     #[tokio::test]
     #[ignore]
     async fn test_probe_ns() {
-        let ns = probe_dns_server("en0").await.expect("must prob");
-        assert!(!ns.is_empty());
+        let offer = probe_dns_server("en0").await.expect("must prob");
+        assert!(!offer.dns.is_empty());
+    }
+
+    #[test]
+    fn test_iface_net() {
+        let addrs = vec![
+            Addr::V6(V6IfAddr {
+                ip: "fe80::1".parse().unwrap(),
+                broadcast: None,
+                netmask: None,
+            }),
+            Addr::V4(V4IfAddr {
+                ip: Ipv4Addr::new(192, 168, 1, 2),
+                broadcast: None,
+                netmask: Some(Ipv4Addr::new(255, 255, 255, 0)),
+            }),
+        ];
+        assert_eq!(iface_net(&addrs), Some("192.168.1.2/24".parse().unwrap()));
+        assert_eq!(iface_net(&addrs[..1]), None);
+    }
+
+    #[test]
+    fn test_refresh_after() {
+        assert_eq!(
+            refresh_after(Some(Duration::from_secs(86400))),
+            Duration::from_secs(43200)
+        );
+        assert_eq!(refresh_after(Some(Duration::from_secs(30))), MIN_DHCP_TTL);
+        assert_eq!(refresh_after(None), DHCP_TTL);
     }
 }
@@ -1,6 +1,7 @@
 use std::fmt::{Debug, Display, Formatter};
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::time::Instant;
 use std::{net, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
@@ -15,10 +16,12 @@ use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
 use tracing::warn;
 
+use crate::common::nat64;
 use crate::common::tls::{self, GLOBAL_ROOT_STORE};
 use crate::dns::dhcp::DhcpClient;
 use crate::dns::ThreadSafeDNSClient;
 use hickory_proto::h2::HttpsClientStreamBuilder;
+use hickory_proto::h3::H3ClientStream;
 use hickory_proto::op::Message;
 use hickory_proto::rustls::tls_client_connect_with_bind_addr;
 use hickory_proto::{
@@ -39,6 +42,8 @@ pub enum DNSNetMode {
     TCP,
     DoT,
     DoH,
+    /// DoH over HTTP/3
+    DoH3,
     DHCP,
 }
 
@@ -49,6 +54,7 @@ impl Display for DNSNetMode {
             Self::TCP => write!(f, "TCP"),
             Self::DoT => write!(f, "DoT"),
             Self::DoH => write!(f, "DoH"),
+            Self::DoH3 => write!(f, "DoH3"),
             Self::DHCP => write!(f, "DHCP"),
         }
     }
@@ -62,6 +68,7 @@ impl FromStr for DNSNetMode {
             "UDP" => Ok(Self::UDP),
             "TCP" => Ok(Self::TCP),
             "DoH" => Ok(Self::DoH),
+            "DoH3" => Ok(Self::DoH3),
             "DoT" => Ok(Self::DoT),
             "DHCP" => Ok(Self::DHCP),
             _ => Err(Error::DNSError("unsupported protocol".into())),
@@ -83,6 +90,7 @@ enum DnsConfig {
     Tcp(net::SocketAddr, Option<Interface>),
     Tls(net::SocketAddr, String, Option<Interface>),
     Https(net::SocketAddr, String, Option<Interface>),
+    H3(net::SocketAddr, String, Option<Interface>),
 }
 
 struct Inner {
@@ -107,7 +115,7 @@ impl DnsClient {
     pub async fn new(opts: Opts) -> anyhow::Result<ThreadSafeDNSClient> {
         // TODO: use proxy to connect?
         match &opts.net {
-            DNSNetMode::DHCP => Ok(Arc::new(DhcpClient::new(&opts.host).await)),
+            DNSNetMode::DHCP => Ok(DhcpClient::new(&opts.host).await),
 
             other => {
                 let ip = if let Some(r) = opts.r {
@@ -219,6 +227,42 @@ impl DnsClient {
                             iface: opts.iface,
                         }))
                     }
+                    DNSNetMode::DoH3 => {
+                        let addr = net::SocketAddr::new(ip, opts.port);
+                        let h3 = Self::connect(
+                            DnsConfig::H3(addr, opts.host.clone(), opts.iface.clone()),
+                            opts.host.clone(),
+                            opts.port,
+                            DNSNetMode::DoH3,
+                            opts.iface.clone(),
+                        )
+                        .await;
+                        let h2 = Self::connect(
+                            DnsConfig::Https(addr, opts.host.clone(), opts.iface.clone()),
+                            opts.host,
+                            opts.port,
+                            DNSNetMode::DoH,
+                            opts.iface,
+                        )
+                        .await;
+
+                        match (h3, h2) {
+                            (Ok(h3), Ok(h2)) => Ok(Arc::new(H3Client {
+                                h3,
+                                h2,
+                                h3_failed_at: std::sync::Mutex::new(None),
+                            })),
+                            (Ok(h3), Err(e)) => {
+                                warn!("{} has no DoH fallback: {}", h3.id(), e);
+                                Ok(Arc::new(h3))
+                            }
+                            (Err(e), Ok(h2)) => {
+                                warn!("{} is not reachable over HTTP/3: {}", h2.id(), e);
+                                Ok(Arc::new(h2))
+                            }
+                            (Err(e), Err(_)) => Err(e.into()),
+                        }
+                    }
                     _ => unreachable!("."),
                 }
             }
@@ -226,6 +270,30 @@ impl DnsClient {
     }
 }
 
+impl DnsClient {
+    async fn connect(
+        cfg: DnsConfig,
+        host: String,
+        port: u16,
+        net: DNSNetMode,
+        iface: Option<Interface>,
+    ) -> Result<Self, Error> {
+        let (client, bg) = dns_stream_builder(&cfg).await?;
+        Ok(Self {
+            inner: Arc::new(RwLock::new(Inner {
+                c: client,
+                bg_handle: Some(bg),
+            })),
+
+            cfg,
+            host,
+            port,
+            net,
+            iface,
+        })
+    }
+}
+
 impl Debug for DnsClient {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("DnsClient")
@@ -272,13 +340,54 @@ impl Client for DnsClient {
     }
 }
 
+/// how long DoH3 stays on HTTP/2 after HTTP/3 failed
+const H3_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// DoH3 with DoH over HTTP/2 to the same server for when QUIC doesn't get
+/// through, e.g. UDP blocked by a firewall. Both keep their connection
+/// across queries
+#[derive(Debug)]
+struct H3Client {
+    h3: DnsClient,
+    h2: DnsClient,
+    h3_failed_at: std::sync::Mutex<Option<Instant>>,
+}
+
+#[async_trait]
+impl Client for H3Client {
+    fn id(&self) -> String {
+        self.h3.id()
+    }
+
+    async fn exchange(&self, msg: &Message) -> anyhow::Result<Message> {
+        let try_h3 = self
+            .h3_failed_at
+            .lock()
+            .unwrap()
+            .map_or(true, |x| x.elapsed() > H3_RETRY_AFTER);
+        if try_h3 {
+            match self.h3.exchange(msg).await {
+                Ok(answer) => {
+                    self.h3_failed_at.lock().unwrap().take();
+                    return Ok(answer);
+                }
+                Err(e) => {
+                    warn!("{} failed, falling back to HTTP/2: {}", self.h3.id(), e);
+                    self.h3_failed_at.lock().unwrap().replace(Instant::now());
+                }
+            }
+        }
+        self.h2.exchange(msg).await
+    }
+}
+
 async fn dns_stream_builder(
     cfg: &DnsConfig,
 ) -> Result<(AsyncClient, JoinHandle<Result<(), ProtoError>>), Error> {
     match cfg {
         DnsConfig::Udp(addr, iface) => {
             let stream = UdpClientStream::<TokioUdpSocket>::with_bind_addr_and_timeout(
-                net::SocketAddr::new(addr.ip(), addr.port()),
+                nat64::translate(*addr),
                 // TODO: simplify this match
                 match iface {
                     Some(iface) => match iface {
@@ -297,7 +406,7 @@ async fn dns_stream_builder(
         DnsConfig::Tcp(addr, iface) => {
             let (stream, sender) =
                 TcpClientStream::<AsyncIoTokioAsStd<TokioTcpStream>>::with_bind_addr_and_timeout(
-                    net::SocketAddr::new(addr.ip(), addr.port()),
+                    nat64::translate(*addr),
                     match iface {
                         Some(iface) => match iface {
                             Interface::IpAddr(ip) => Some(SocketAddr::new(ip.clone(), 0)),
@@ -322,7 +431,7 @@ async fn dns_stream_builder(
 
             let (stream, sender) =
                 tls_client_connect_with_bind_addr::<AsyncIoTokioAsStd<TokioTcpStream>>(
-                    net::SocketAddr::new(addr.ip(), addr.port()),
+                    nat64::translate(*addr),
                     match iface {
                         Some(iface) => match iface {
                             Interface::IpAddr(ip) => Some(SocketAddr::new(ip.clone(), 0)),
@@ -339,6 +448,31 @@ async fn dns_stream_builder(
                 .map(|(x, y)| (x, tokio::spawn(y)))
                 .map_err(|x| Error::DNSError(x.to_string()))
         }
+        DnsConfig::H3(addr, host, iface) => {
+            let mut tls_config = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(GLOBAL_ROOT_STORE.clone())
+                .with_no_client_auth();
+            tls_config.alpn_protocols = vec!["h3".into()];
+
+            if host == &addr.ip().to_string() {
+                tls_config
+                    .dangerous()
+                    .set_certificate_verifier(Arc::new(tls::NoHostnameTlsVerifier));
+            }
+
+            let mut stream_builder = H3ClientStream::builder();
+            stream_builder.crypto_config(tls_config);
+            if let Some(Interface::IpAddr(ip)) = iface {
+                stream_builder.bind_addr(net::SocketAddr::new(*ip, 0));
+            }
+            let stream = stream_builder.build(nat64::translate(*addr), host.clone());
+
+            client::AsyncClient::connect(stream)
+                .await
+                .map(|(x, y)| (x, tokio::spawn(y)))
+                .map_err(|x| Error::DNSError(x.to_string()))
+        }
         DnsConfig::Https(addr, host, iface) => {
             let mut tls_config = ClientConfig::builder()
                 .with_safe_defaults()
@@ -362,10 +496,8 @@ async fn dns_stream_builder(
                     _ => {}
                 }
             }
-            let stream = stream_builder.build::<AsyncIoTokioAsStd<TokioTcpStream>>(
-                net::SocketAddr::new(addr.ip(), addr.port()),
-                host.clone(),
-            );
+            let stream = stream_builder
+                .build::<AsyncIoTokioAsStd<TokioTcpStream>>(nat64::translate(*addr), host.clone());
 
             client::AsyncClient::connect(stream)
                 .await
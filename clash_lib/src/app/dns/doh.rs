@@ -0,0 +1,245 @@
+//! A DoH client for the nameservers with their own path, headers or HTTP
+//! version, which the hickory client can't talk to.
+
+use std::{
+    fmt::{Debug, Formatter},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use boring::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use futures::Future;
+use hickory_proto::op::Message;
+use http::{
+    header::{ACCEPT, CONTENT_TYPE},
+    HeaderMap, HeaderName, HeaderValue, Request, Uri,
+};
+use hyper_boring::HttpsConnector;
+use tokio::net::TcpSocket;
+use tower::Service;
+
+use crate::{
+    common::{errors::map_io_error, nat64},
+    dns::ThreadSafeDNSClient,
+    proxy::{utils::Interface, AnyStream},
+    Error,
+};
+
+use super::{
+    config::{DoHOptions, DoHVersion},
+    detour::Detour,
+    ClashResolver, Client,
+};
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const DEFAULT_PATH: &str = "/dns-query";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// connects to the resolved address of the nameserver whatever the URL says,
+/// so the hostname is only used for SNI and the `Host` header
+#[derive(Clone)]
+enum NameServerConnector {
+    Direct {
+        addr: SocketAddr,
+        iface: Option<Interface>,
+    },
+    /// through an outbound, which resolves the hostname on its side
+    Proxied(Detour),
+}
+
+impl Service<Uri> for NameServerConnector {
+    type Response = AnyStream;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: Uri) -> Self::Future {
+        let (addr, iface) = match self {
+            Self::Direct { addr, iface } => (nat64::translate(*addr), iface.clone()),
+            Self::Proxied(detour) => {
+                let detour = detour.clone();
+                return Box::pin(async move {
+                    tokio::time::timeout(TIMEOUT, detour.connect_stream()).await?
+                });
+            }
+        };
+
+        Box::pin(async move {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            // as the other DNS clients, only addresses are bound to
+            if let Some(Interface::IpAddr(ip)) = iface {
+                socket.bind(SocketAddr::new(ip, 0))?;
+            }
+            let stream = tokio::time::timeout(TIMEOUT, socket.connect(addr)).await??;
+            stream.set_nodelay(true)?;
+            Ok(Box::new(stream) as AnyStream)
+        })
+    }
+}
+
+pub struct DohClient {
+    client: hyper::Client<HttpsConnector<NameServerConnector>>,
+    uri: Uri,
+    headers: HeaderMap,
+}
+
+impl DohClient {
+    pub async fn new(
+        host: String,
+        port: u16,
+        opts: DoHOptions,
+        iface: Option<Interface>,
+        r: Option<Arc<dyn ClashResolver>>,
+        detour: Option<Detour>,
+    ) -> anyhow::Result<ThreadSafeDNSClient> {
+        let connector = match detour {
+            Some(detour) => NameServerConnector::Proxied(detour),
+            None => {
+                let ip = match host.parse::<IpAddr>() {
+                    Ok(ip) => ip,
+                    Err(_) => r
+                        .ok_or_else(|| Error::DNSError(format!("no resolver for {}", host)))?
+                        .resolve(&host, false)
+                        .await
+                        .map_err(|x| anyhow!("resolve hostname failure: {}", x))?
+                        .ok_or_else(|| {
+                            Error::InvalidConfig(format!("can't resolve default DNS: {}", host))
+                        })?,
+                };
+                NameServerConnector::Direct {
+                    addr: SocketAddr::new(ip, port),
+                    iface,
+                }
+            }
+        };
+
+        let mut ssl = SslConnector::builder(SslMethod::tls()).map_err(map_io_error)?;
+        let alpn: &[u8] = match opts.http_version {
+            Some(DoHVersion::Http11) => b"\x08http/1.1",
+            Some(DoHVersion::H2) => b"\x02h2",
+            _ => b"\x02h2\x08http/1.1",
+        };
+        ssl.set_alpn_protos(alpn).map_err(map_io_error)?;
+        // an address has no name to verify, like in the hickory DoH client
+        if host.parse::<IpAddr>().is_ok() {
+            ssl.set_verify(SslVerifyMode::NONE);
+        }
+        let connector = HttpsConnector::with_connector(connector, ssl).map_err(map_io_error)?;
+
+        let client = hyper::Client::builder()
+            .http2_only(opts.http_version == Some(DoHVersion::H2))
+            .build(connector);
+
+        let host = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("[{}]", host),
+            _ => host,
+        };
+        let path = opts.path.as_deref().unwrap_or(DEFAULT_PATH);
+        let uri = format!("https://{}:{}{}", host, port, path)
+            .parse::<Uri>()
+            .map_err(|x| Error::InvalidConfig(format!("invalid DoH url: {}", x)))?;
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in opts.headers {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(&value)?,
+            );
+        }
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE));
+        headers.insert(ACCEPT, HeaderValue::from_static(DNS_MESSAGE));
+
+        Ok(Arc::new(Self {
+            client,
+            uri,
+            headers,
+        }))
+    }
+}
+
+impl Debug for DohClient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DohClient").field("uri", &self.uri).finish()
+    }
+}
+
+#[async_trait]
+impl Client for DohClient {
+    fn id(&self) -> String {
+        format!("DoH#{}", self.uri)
+    }
+
+    async fn exchange(&self, msg: &Message) -> anyhow::Result<Message> {
+        let mut query = msg.clone();
+        // RFC 8484 4.1, a 0 id makes the answers cacheable by HTTP caches
+        query.set_id(0);
+
+        let mut req = Request::post(self.uri.clone()).body(hyper::Body::from(query.to_vec()?))?;
+        *req.headers_mut() = self.headers.clone();
+
+        let res = tokio::time::timeout(TIMEOUT, self.client.request(req))
+            .await
+            .map_err(|_| Error::DNSError("DoH query timeout".into()))??;
+        if !res.status().is_success() {
+            return Err(Error::DNSError(format!("{} answered {}", self.id(), res.status())).into());
+        }
+
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let mut answer = Message::from_vec(&body)?;
+        answer.set_id(msg.id());
+        Ok(answer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::{
+        op::{Message, Query},
+        rr::{Name, RecordType},
+    };
+
+    use crate::app::dns::{
+        config::{DoHOptions, DoHVersion},
+        resolver::Resolver,
+    };
+
+    use super::DohClient;
+
+    #[tokio::test]
+    #[ignore = "network unstable on CI"]
+    async fn test_doh_custom_path() {
+        let c = DohClient::new(
+            "1.1.1.1".to_owned(),
+            443,
+            DoHOptions {
+                path: Some("/dns-query".to_owned()),
+                headers: vec![("X-Test".to_owned(), "clash".to_owned())],
+                http_version: Some(DoHVersion::Http11),
+            },
+            None,
+            None,
+            None,
+        )
+        .await
+        .expect("build client");
+
+        let mut m = Message::new();
+        let mut q = Query::new();
+        q.set_name(Name::from_utf8("www.google.com").unwrap());
+        q.set_query_type(RecordType::A);
+        m.add_query(q);
+
+        let r = c.exchange(&m).await.expect("should exchange");
+        assert!(!Resolver::ip_list_of_message(&r).is_empty());
+    }
+}
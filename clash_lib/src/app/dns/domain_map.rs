@@ -0,0 +1,101 @@
+//! `enhanced-mode: redir-host`: the domain each IP was last answered for,
+//! so the domain rules still match the connections that redir, tproxy or
+//! tun hand over with only the IP.
+
+use std::{
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hickory_proto::{op, rr};
+
+const CAPACITY: usize = 4096;
+/// apps keep using an IP for a while past its TTL
+const MIN_AGE: Duration = Duration::from_secs(60);
+
+pub struct DomainMap {
+    /// IP -> the domain asked for and when it expires
+    cache: Mutex<lru_time_cache::LruCache<IpAddr, (String, Instant)>>,
+}
+
+impl Default for DomainMap {
+    fn default() -> Self {
+        Self {
+            cache: Mutex::new(lru_time_cache::LruCache::with_capacity(CAPACITY)),
+        }
+    }
+}
+
+impl DomainMap {
+    /// maps the IPs in the answer of `msg` to the domain of its question,
+    /// the one the rules are written for, rather than a CNAME target
+    pub fn record(&self, msg: &op::Message) {
+        let domain = match msg.query() {
+            Some(q) => q.name().to_ascii().trim_end_matches('.').to_owned(),
+            None => return,
+        };
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        for record in msg.answers() {
+            let ip = match record.data() {
+                Some(rr::RData::A(a)) => IpAddr::V4(a.0),
+                Some(rr::RData::AAAA(aaaa)) => IpAddr::V6(aaaa.0),
+                _ => continue,
+            };
+            let age = Duration::from_secs(record.ttl() as u64).max(MIN_AGE);
+            cache.insert(ip, (domain.clone(), now + age));
+        }
+    }
+
+    pub fn get(&self, ip: IpAddr) -> Option<String> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(&ip) {
+            Some((domain, expires)) if *expires > Instant::now() => Some(domain.clone()),
+            Some(_) => {
+                cache.remove(&ip);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, str::FromStr};
+
+    use hickory_proto::{
+        op,
+        rr::{self, rdata},
+    };
+
+    use super::DomainMap;
+
+    #[test]
+    fn test_domain_map() {
+        let mut msg = op::Message::new();
+        msg.add_query(op::Query::query(
+            rr::Name::from_str("www.example.com.").unwrap(),
+            rr::RecordType::A,
+        ));
+        msg.add_answer(rr::Record::from_rdata(
+            rr::Name::from_str("www.example.com.").unwrap(),
+            300,
+            rr::RData::CNAME(rdata::CNAME(rr::Name::from_str("edge.cdn.net.").unwrap())),
+        ));
+        msg.add_answer(rr::Record::from_rdata(
+            rr::Name::from_str("edge.cdn.net.").unwrap(),
+            5,
+            rr::RData::A(rdata::A(Ipv4Addr::new(1, 2, 3, 4))),
+        ));
+
+        let map = DomainMap::default();
+        map.record(&msg);
+        assert_eq!(
+            map.get(Ipv4Addr::new(1, 2, 3, 4).into()).as_deref(),
+            Some("www.example.com")
+        );
+        assert_eq!(map.get(Ipv4Addr::new(4, 3, 2, 1).into()), None);
+    }
+}
@@ -0,0 +1,113 @@
+use std::{collections::BTreeSet, time::Duration};
+
+use lru_time_cache::LruCache;
+use tracing::info;
+
+use crate::app::profile::ThreadSafeCacheFile;
+
+/// failed connections in a row after which a domain stops getting fake ips
+const FAILURE_THRESHOLD: u32 = 3;
+/// the domains whose failures are counted at once, the failures of those
+/// not seen for the longest or for an hour are forgotten
+const FAILURES_KEPT: usize = 1024;
+const FAILURES_TTL: Duration = Duration::from_secs(3600);
+
+/// `fake-ip-auto-skip`: learns the domains whose connections keep failing
+/// through fake ips, e.g. apps validating the DNS answers themselves.
+/// The learned domains are kept in the cache file.
+pub struct AutoSkip {
+    failures: LruCache<String, u32>,
+    learned: BTreeSet<String>,
+    store: Option<ThreadSafeCacheFile>,
+}
+
+impl AutoSkip {
+    pub async fn new(store: Option<ThreadSafeCacheFile>) -> Self {
+        let learned = match &store {
+            Some(store) => store.get_fake_ip_skipped().await.into_iter().collect(),
+            None => BTreeSet::new(),
+        };
+        Self {
+            failures: LruCache::with_expiry_duration_and_capacity(FAILURES_TTL, FAILURES_KEPT),
+            learned,
+            store,
+        }
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        self.learned.contains(host)
+    }
+
+    /// counts a connection to `host` through its fake ip, failed when the
+    /// client gave up on it rather than the proxy failing
+    pub async fn report(&mut self, host: &str, ok: bool) {
+        if ok {
+            self.failures.remove(host);
+            return;
+        }
+
+        let failures = self.failures.get(host).copied().unwrap_or_default() + 1;
+        self.failures.insert(host.to_owned(), failures);
+        if failures >= FAILURE_THRESHOLD {
+            info!(
+                "{} failed {} times through fake ip, skipping it from now on",
+                host, failures
+            );
+            self.set(host, true).await;
+        }
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.learned.iter().cloned().collect()
+    }
+
+    /// adds or removes `host` by hand
+    pub async fn set(&mut self, host: &str, skip: bool) {
+        self.failures.remove(host);
+        let changed = if skip {
+            self.learned.insert(host.to_owned())
+        } else {
+            self.learned.remove(host)
+        };
+
+        if changed {
+            if let Some(store) = &self.store {
+                store.set_fake_ip_skipped(self.list()).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AutoSkip;
+
+    #[tokio::test]
+    async fn test_auto_skip() {
+        let mut skip = AutoSkip::new(None).await;
+
+        skip.report("foo.com", false).await;
+        skip.report("foo.com", false).await;
+        skip.report("foo.com", true).await;
+        skip.report("foo.com", false).await;
+        assert!(!skip.contains("foo.com"));
+
+        skip.report("foo.com", false).await;
+        skip.report("foo.com", false).await;
+        assert!(skip.contains("foo.com"));
+        assert_eq!(skip.list(), vec!["foo.com"]);
+
+        skip.set("foo.com", false).await;
+        assert!(!skip.contains("foo.com"));
+
+        // the failures of the domains seen the longest ago are forgotten
+        skip.report("bar.com", false).await;
+        skip.report("bar.com", false).await;
+        for i in 0..super::FAILURES_KEPT {
+            skip.report(&format!("{}.com", i), false).await;
+        }
+        skip.report("bar.com", false).await;
+        assert!(!skip.contains("bar.com"));
+        assert!(skip.failures.len() <= super::FAILURES_KEPT);
+    }
+}
@@ -9,15 +9,18 @@ use async_trait::async_trait;
 use byteorder::{BigEndian, ByteOrder};
 use tokio::sync::RwLock;
 
+mod auto_skip;
 mod file_store;
 mod mem_store;
 
+pub use auto_skip::AutoSkip;
 pub use file_store::FileStore;
 pub use mem_store::InMemStore;
 
 pub struct Opts {
     pub ipnet: ipnet::IpNet,
     pub skipped_hostnames: Option<trie::StringTrie<bool>>,
+    pub auto_skip: Option<AutoSkip>,
     pub store: Box<dyn Store>,
 }
 
@@ -41,6 +44,7 @@ pub struct FakeDns {
     gateway: u32,
     offset: u32,
     skipped_hostnames: Option<trie::StringTrie<bool>>,
+    auto_skip: Option<AutoSkip>,
     ipnet: ipnet::IpNet,
     store: Box<dyn Store>,
 }
@@ -65,6 +69,7 @@ impl FakeDns {
             gateway: min - 1,
             offset: 0,
             skipped_hostnames: opt.skipped_hostnames,
+            auto_skip: opt.auto_skip,
             ipnet: opt.ipnet,
             store: opt.store,
         })
@@ -89,12 +94,36 @@ impl FakeDns {
     }
 
     pub fn should_skip(&self, domain: &str) -> bool {
+        if self.auto_skip.as_ref().is_some_and(|x| x.contains(domain)) {
+            return true;
+        }
         match &self.skipped_hostnames {
             None => false,
             Some(host) => host.search(domain).is_some(),
         }
     }
 
+    /// counts a connection to `host` through its fake ip, if auto skip is on
+    pub async fn report(&mut self, host: &str, ok: bool) {
+        if let Some(auto_skip) = self.auto_skip.as_mut() {
+            auto_skip.report(host, ok).await;
+        }
+    }
+
+    pub fn auto_skipped(&self) -> Option<Vec<String>> {
+        self.auto_skip.as_ref().map(|x| x.list())
+    }
+
+    pub async fn set_auto_skipped(&mut self, host: &str, skip: bool) -> bool {
+        match self.auto_skip.as_mut() {
+            Some(auto_skip) => {
+                auto_skip.set(host, skip).await;
+                true
+            }
+            None => false,
+        }
+    }
+
     pub async fn exist(&mut self, ip: net::IpAddr) -> bool {
         if !ip.is_ipv4() {
             false
@@ -170,6 +199,7 @@ mod tests {
         let mut pool = FakeDns::new(Opts {
             ipnet,
             skipped_hostnames: None,
+            auto_skip: None,
             store,
         })
         .unwrap();
@@ -202,6 +232,7 @@ mod tests {
         let mut pool = FakeDns::new(Opts {
             ipnet,
             skipped_hostnames: None,
+            auto_skip: None,
             store,
         })
         .unwrap();
@@ -230,6 +261,7 @@ mod tests {
         let pool = FakeDns::new(Opts {
             ipnet,
             skipped_hostnames: Some(tree),
+            auto_skip: None,
             store,
         })
         .unwrap();
@@ -246,6 +278,7 @@ mod tests {
         let mut pool = FakeDns::new(Opts {
             ipnet,
             skipped_hostnames: None,
+            auto_skip: None,
             store,
         })
         .unwrap();
@@ -268,6 +301,7 @@ mod tests {
         let mut pool = FakeDns::new(Opts {
             ipnet,
             skipped_hostnames: None,
+            auto_skip: None,
             store,
         })
         .unwrap();
@@ -282,6 +316,7 @@ mod tests {
         let mut new_pool = FakeDns::new(Opts {
             ipnet,
             skipped_hostnames: None,
+            auto_skip: None,
             store,
         })
         .unwrap();
@@ -1,6 +1,6 @@
 use std::{net, sync::Arc};
 
-use crate::common::{mmdb::MMDB, trie};
+use crate::common::{geosite::DomainMatcher, mmdb::MMDB, trie};
 
 pub trait FallbackIPFilter: Sync + Send {
     fn apply(&self, ip: &net::IpAddr) -> bool;
@@ -16,10 +16,7 @@ impl GeoIPFilter {
 
 impl FallbackIPFilter for GeoIPFilter {
     fn apply(&self, ip: &net::IpAddr) -> bool {
-        self.1
-            .lookup(*ip)
-            .map(|x| x.country)
-            .is_ok_and(|x| x.is_some_and(|x| x.iso_code == Some(self.0.as_str())))
+        self.1.contains(*ip, &self.0)
     }
 }
 
@@ -58,3 +55,21 @@ impl FallbackDomainFilter for DomainFilter {
         self.0.search(domain).is_some()
     }
 }
+
+/// the domains of a geosite category, or with `negate` those not in it
+pub struct GeoSiteFilter {
+    matcher: Arc<DomainMatcher>,
+    negate: bool,
+}
+
+impl GeoSiteFilter {
+    pub fn new(matcher: Arc<DomainMatcher>, negate: bool) -> Self {
+        Self { matcher, negate }
+    }
+}
+
+impl FallbackDomainFilter for GeoSiteFilter {
+    fn apply(&self, domain: &str) -> bool {
+        self.matcher.matches(domain) != self.negate
+    }
+}
@@ -1,17 +1,23 @@
+use crate::dns::detour::{Detour, OutboundSlot, ProxiedClient};
 use crate::dns::dns_client::{DNSNetMode, DnsClient, Opts};
-use crate::dns::{ClashResolver, ThreadSafeDNSClient};
+use crate::dns::doh::DohClient;
+use crate::dns::{ClashResolver, Client, ThreadSafeDNSClient};
 use crate::dns_debug;
 use crate::proxy::utils::Interface;
+use async_trait::async_trait;
+use hickory_proto::op::Message;
 use std::sync::Arc;
 use tracing::{debug, warn};
 
 use super::config::NameServer;
 
+/// `outbounds` are for the nameservers asked through a proxy
 pub async fn make_clients(
     servers: Vec<NameServer>,
     resolver: Option<Arc<dyn ClashResolver>>,
+    outbounds: &OutboundSlot,
 ) -> Vec<ThreadSafeDNSClient> {
-    let mut rv = Vec::new();
+    let mut rv: Vec<ThreadSafeDNSClient> = Vec::new();
 
     for s in servers {
         dns_debug!("building nameserver: {:?}", s);
@@ -27,17 +33,60 @@ pub async fn make_clients(
             (host, port)
         };
 
-        match DnsClient::new(Opts {
-            r: resolver.as_ref().map(|x| x.clone()),
-            host: host.to_string(),
-            port: port
-                .parse::<u16>()
-                .expect(format!("no port for DNS server: {}", s.address).as_str()),
-            net: s.net.to_owned(),
-            iface: s.interface.as_ref().map(|x| Interface::Name(x.to_owned())),
-        })
-        .await
-        {
+        let port = port
+            .parse::<u16>()
+            .expect(format!("no port for DNS server: {}", s.address).as_str());
+        let iface = s.interface.as_ref().map(|x| Interface::Name(x.to_owned()));
+
+        let client = if let Some(proxy) = &s.proxy {
+            match resolver.clone() {
+                Some(r) => {
+                    let detour =
+                        Detour::new(proxy.clone(), outbounds.clone(), r, host.to_string(), port);
+                    if s.net == DNSNetMode::DoH {
+                        DohClient::new(
+                            host.to_string(),
+                            port,
+                            s.doh.clone(),
+                            None,
+                            None,
+                            Some(detour),
+                        )
+                        .await
+                    } else {
+                        ProxiedClient::new(s.net.clone(), detour)
+                            .map(|x| Arc::new(x) as ThreadSafeDNSClient)
+                            .map_err(Into::into)
+                    }
+                }
+                None => Err(anyhow!("default-nameserver can't be asked through a proxy")),
+            }
+        } else if s.net == DNSNetMode::DoH && s.doh.is_custom() {
+            DohClient::new(
+                host.to_string(),
+                port,
+                s.doh.clone(),
+                iface,
+                resolver.clone(),
+                None,
+            )
+            .await
+        } else {
+            DnsClient::new(Opts {
+                r: resolver.as_ref().map(|x| x.clone()),
+                host: host.to_string(),
+                port,
+                net: s.net.to_owned(),
+                iface,
+            })
+            .await
+        };
+
+        match client {
+            Ok(c) if s.weight != 1 => rv.push(Arc::new(Weighted {
+                inner: c,
+                weight: s.weight,
+            })),
             Ok(c) => rv.push(c),
             Err(e) => warn!("initializing DNS client {} with error {}", &s, e),
         }
@@ -45,3 +94,25 @@ pub async fn make_clients(
 
     rv
 }
+
+/// a nameserver configured with a `weight` other than 1
+#[derive(Debug)]
+struct Weighted {
+    inner: ThreadSafeDNSClient,
+    weight: u32,
+}
+
+#[async_trait]
+impl Client for Weighted {
+    fn id(&self) -> String {
+        self.inner.id()
+    }
+
+    fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    async fn exchange(&self, msg: &Message) -> anyhow::Result<Message> {
+        self.inner.exchange(msg).await
+    }
+}
@@ -0,0 +1,359 @@
+//! `hosts:`, where a hostname, which may be a wildcard, maps to one or more
+//! IPs or to another domain:
+//! ```yaml
+//! hosts:
+//!   '*.internal.corp': 10.0.0.1
+//!   'cdn.example.com': [1.1.1.1, 1.0.0.1] # taken in turn
+//!   'git.example.com': 'code.example.org' # resolved as that one
+//! ```
+//!
+//! With `dns.use-system-hosts`, the hosts file of the system is looked up
+//! after those, and reloaded when it changes.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock, Weak,
+    },
+    time::{Duration, SystemTime},
+};
+
+use tracing::{debug, warn};
+
+use crate::{common::trie, config::def::HostsValue, Error};
+
+/// how many domains a name may go through before an IP
+const MAX_ALIASES: usize = 8;
+/// how often the system hosts file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// hostname -> IPs, in the order of the file
+type SystemEntries = HashMap<String, Vec<IpAddr>>;
+
+#[derive(Clone)]
+enum Entry {
+    Ips {
+        ips: Vec<IpAddr>,
+        /// where the next lookup starts in `ips`
+        next: Arc<AtomicUsize>,
+    },
+    Alias(String),
+}
+
+/// what the hosts say about a hostname
+#[derive(Debug, PartialEq)]
+pub enum HostsAnswer {
+    /// starting from a different one on each lookup
+    Ips(Vec<IpAddr>),
+    /// a domain the hosts have no entry for, to be resolved by DNS
+    Domain(String),
+}
+
+/// a plain domain, with no wildcards
+fn is_domain(x: &str) -> bool {
+    let x = x.trim_end_matches('.');
+    !x.is_empty()
+        && x.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+fn system_hosts_path() -> PathBuf {
+    if cfg!(windows) {
+        let root = std::env::var("SystemRoot").unwrap_or_else(|_| r"C:\Windows".to_owned());
+        PathBuf::from(root).join(r"System32\drivers\etc\hosts")
+    } else {
+        PathBuf::from("/etc/hosts")
+    }
+}
+
+/// `<ip> <hostname> [aliases...]` lines, `#` starts a comment
+fn parse_system_hosts(content: &str) -> SystemEntries {
+    let mut entries = SystemEntries::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        // a link local IPv6 may come with its zone, which can't be dialed
+        let ip = match fields
+            .next()
+            .and_then(|x| x.split('%').next())
+            .and_then(|x| x.parse::<IpAddr>().ok())
+        {
+            Some(ip) => ip,
+            None => continue,
+        };
+        for name in fields {
+            let ips = entries
+                .entry(name.trim_end_matches('.').to_ascii_lowercase())
+                .or_default();
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+    }
+    entries
+}
+
+fn load_system_hosts(path: &Path) -> SystemEntries {
+    match std::fs::read_to_string(path) {
+        Ok(content) => parse_system_hosts(&content),
+        Err(e) => {
+            warn!("failed to read hosts file {}: {}", path.display(), e);
+            Default::default()
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Hosts {
+    trie: trie::StringTrie<Entry>,
+    /// the hosts file of the system, if it is to be used
+    system: Option<Arc<RwLock<SystemEntries>>>,
+}
+
+impl Hosts {
+    /// `use_system_hosts` loads the system hosts file too, see `watch` to
+    /// keep it up to date
+    pub fn parse(
+        mapping: &HashMap<String, HostsValue>,
+        use_system_hosts: bool,
+    ) -> Result<Self, Error> {
+        let mut trie = trie::StringTrie::new();
+        trie.insert(
+            "localhost",
+            Arc::new(Entry::Ips {
+                ips: vec![IpAddr::from([127, 0, 0, 1])],
+                next: Default::default(),
+            }),
+        );
+
+        for (host, value) in mapping {
+            let entry = match value {
+                HostsValue::One(x) => match x.parse::<IpAddr>() {
+                    Ok(ip) => Entry::Ips {
+                        ips: vec![ip],
+                        next: Default::default(),
+                    },
+                    Err(_) if is_domain(x) => Entry::Alias(x.trim_end_matches('.').to_owned()),
+                    Err(_) => {
+                        return Err(Error::InvalidConfig(format!(
+                            "hosts: {} is neither an IP nor a domain",
+                            x
+                        )))
+                    }
+                },
+                HostsValue::Many(x) => Entry::Ips {
+                    ips: x
+                        .iter()
+                        .map(|x| {
+                            x.parse::<IpAddr>().map_err(|_| {
+                                Error::InvalidConfig(format!("hosts: {}: invalid IP {}", host, x))
+                            })
+                        })
+                        .collect::<Result<_, _>>()?,
+                    next: Default::default(),
+                },
+            };
+            if matches!(&entry, Entry::Ips { ips, .. } if ips.is_empty()) {
+                return Err(Error::InvalidConfig(format!("hosts: {} has no IP", host)));
+            }
+            if !trie.insert(host, Arc::new(entry)) {
+                return Err(Error::InvalidConfig(format!(
+                    "hosts: invalid host {}",
+                    host
+                )));
+            }
+        }
+
+        Ok(Self {
+            trie,
+            system: use_system_hosts
+                .then(|| Arc::new(RwLock::new(load_system_hosts(&system_hosts_path())))),
+        })
+    }
+
+    /// reloads the system hosts file whenever it is modified, until all the
+    /// clones of these hosts are dropped
+    pub fn watch(&self) {
+        let system = match &self.system {
+            Some(system) => Arc::downgrade(system),
+            None => return,
+        };
+        tokio::spawn(watch_system_hosts(system_hosts_path(), system));
+    }
+
+    fn lookup_system(&self, name: &str) -> Option<Vec<IpAddr>> {
+        let system = self.system.as_ref()?.read().unwrap();
+        system.get(&name.to_ascii_lowercase()).cloned()
+    }
+
+    /// None if the hosts have no entry for `host`
+    pub fn lookup(&self, host: &str) -> Result<Option<HostsAnswer>, Error> {
+        let mut name = host.trim_end_matches('.').to_owned();
+        for i in 0..=MAX_ALIASES {
+            match self.trie.search(&name).and_then(|x| x.get_data()) {
+                Some(Entry::Ips { ips, next }) => {
+                    let start = next.fetch_add(1, Ordering::Relaxed) % ips.len();
+                    let mut ips = ips.clone();
+                    ips.rotate_left(start);
+                    return Ok(Some(HostsAnswer::Ips(ips)));
+                }
+                Some(Entry::Alias(domain)) => name = domain.clone(),
+                None => {
+                    if let Some(ips) = self.lookup_system(&name) {
+                        return Ok(Some(HostsAnswer::Ips(ips)));
+                    }
+                    if i == 0 {
+                        return Ok(None);
+                    }
+                    return Ok(Some(HostsAnswer::Domain(name)));
+                }
+            }
+        }
+        Err(Error::DNSError(format!(
+            "hosts: {} goes through more than {} domains",
+            host, MAX_ALIASES
+        )))
+    }
+}
+
+async fn watch_system_hosts(path: PathBuf, system: Weak<RwLock<SystemEntries>>) {
+    let modified = |path: &Path| {
+        std::fs::metadata(path)
+            .and_then(|x| x.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    };
+    let mut last = modified(&path);
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let system = match system.upgrade() {
+            Some(system) => system,
+            None => return,
+        };
+        let current = modified(&path);
+        if current != last {
+            last = current;
+            debug!("hosts file {} changed, reloading", path.display());
+            let entries = load_system_hosts(&path);
+            *system.write().unwrap() = entries;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::IpAddr,
+        sync::{Arc, RwLock},
+    };
+
+    use crate::config::def::HostsValue;
+
+    use super::{parse_system_hosts, Hosts, HostsAnswer};
+
+    fn ips(x: &[&str]) -> Option<HostsAnswer> {
+        Some(HostsAnswer::Ips(
+            x.iter().map(|x| x.parse::<IpAddr>().unwrap()).collect(),
+        ))
+    }
+
+    #[test]
+    fn test_hosts_lookup() {
+        let mapping: HashMap<String, HostsValue> = serde_yaml::from_str(
+            r#"
+'*.internal.corp': 10.0.0.1
+'cdn.example.com': [1.1.1.1, 1.0.0.1]
+'git.example.com': 'code.internal.corp'
+'docs.example.com': 'docs.example.org'
+'a.loop': 'b.loop'
+'b.loop': 'a.loop'
+"#,
+        )
+        .unwrap();
+        let hosts = Hosts::parse(&mapping, false).unwrap();
+
+        assert_eq!(hosts.lookup("localhost").unwrap(), ips(&["127.0.0.1"]));
+        assert_eq!(hosts.lookup("x.internal.corp").unwrap(), ips(&["10.0.0.1"]));
+        assert_eq!(hosts.lookup("example.com").unwrap(), None);
+
+        assert_eq!(
+            hosts.lookup("cdn.example.com").unwrap(),
+            ips(&["1.1.1.1", "1.0.0.1"])
+        );
+        assert_eq!(
+            hosts.lookup("cdn.example.com").unwrap(),
+            ips(&["1.0.0.1", "1.1.1.1"])
+        );
+
+        assert_eq!(
+            hosts.lookup("git.example.com.").unwrap(),
+            ips(&["10.0.0.1"])
+        );
+        assert_eq!(
+            hosts.lookup("docs.example.com").unwrap(),
+            Some(HostsAnswer::Domain("docs.example.org".to_owned()))
+        );
+        assert!(hosts.lookup("a.loop").is_err());
+    }
+
+    #[test]
+    fn test_hosts_invalid() {
+        for x in ["'a.com': []", "'a.com': [a.org]", "'a.com': 'not a domain'"] {
+            let mapping: HashMap<String, HostsValue> = serde_yaml::from_str(x).unwrap();
+            assert!(Hosts::parse(&mapping, false).is_err(), "{}", x);
+        }
+    }
+
+    #[test]
+    fn test_system_hosts() {
+        let system = parse_system_hosts(
+            r#"
+# comment
+127.0.0.1   localhost
+::1         localhost ip6-localhost
+fe80::1%lo0 link.local
+192.168.1.10 NAS.lan nas # the box under the desk
+192.168.1.11 nas.lan
+not-an-ip   broken
+"#,
+        );
+        assert_eq!(
+            system.get("ip6-localhost"),
+            Some(&vec!["::1".parse().unwrap()])
+        );
+        assert_eq!(
+            system.get("link.local"),
+            Some(&vec!["fe80::1".parse().unwrap()])
+        );
+        assert_eq!(
+            system.get("nas.lan"),
+            Some(&vec![
+                "192.168.1.10".parse().unwrap(),
+                "192.168.1.11".parse().unwrap()
+            ])
+        );
+        assert!(!system.contains_key("broken"));
+
+        let mapping: HashMap<String, HostsValue> =
+            serde_yaml::from_str("'files.example.com': 'NAS.lan'").unwrap();
+        let mut hosts = Hosts::parse(&mapping, false).unwrap();
+        hosts.system = Some(Arc::new(RwLock::new(system)));
+
+        assert_eq!(hosts.lookup("nas").unwrap(), ips(&["192.168.1.10"]));
+        assert_eq!(
+            hosts.lookup("files.example.com").unwrap(),
+            ips(&["192.168.1.10", "192.168.1.11"])
+        );
+        // the config hosts come first
+        assert_eq!(hosts.lookup("localhost").unwrap(), ips(&["127.0.0.1"]));
+        assert_eq!(hosts.lookup("example.com").unwrap(), None);
+    }
+}
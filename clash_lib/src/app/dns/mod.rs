@@ -1,30 +1,44 @@
 use async_trait::async_trait;
 
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::time::Duration;
 
 use hickory_proto::op;
 use std::sync::Arc;
 
+use crate::app::dispatcher::DnsStats;
+use crate::app::outbound::manager::ThreadSafeOutboundManager;
+use crate::app::remote_content_manager::providers::rule_provider::ThreadSafeRuleProvider;
+
 #[cfg(test)]
 use mockall::automock;
 
+mod blocker;
 mod config;
+mod detour;
 mod dhcp;
 mod dns_client;
+mod doh;
+mod domain_map;
 mod dummy_keys;
 mod fakeip;
 mod filters;
 mod helper;
+mod hosts;
+mod monitor;
 pub mod resolver;
+mod rules;
 mod server;
 mod system;
 
 pub use system::SystemResolver;
 
-pub use config::Config;
+pub use blocker::{DnsBlocker, ThreadSafeDnsBlocker};
+pub use config::{Config, PolicySet};
 
 pub use resolver::Resolver;
-pub use server::get_dns_listener;
+pub use server::{answer_query, get_dns_listener, serve_stream};
 
 #[macro_export]
 macro_rules! dns_debug {
@@ -51,6 +65,10 @@ macro_rules! dns_warn {
 pub trait Client: Sync + Send + Debug {
     /// used to identify the client for logging
     fn id(&self) -> String;
+    /// the share of questions it gets with the weighted strategy
+    fn weight(&self) -> u32 {
+        1
+    }
     async fn exchange(&self, msg: &op::Message) -> anyhow::Result<op::Message>;
 }
 
@@ -90,6 +108,49 @@ pub trait ClashResolver: Sync + Send {
     async fn is_fake_ip(&self, ip: std::net::IpAddr) -> bool;
     async fn fake_ip_exists(&self, ip: std::net::IpAddr) -> bool;
 
+    /// the domain `ip` was last answered for, with `enhanced-mode:
+    /// redir-host`
+    fn domain_of(&self, _ip: std::net::IpAddr) -> Option<String> {
+        None
+    }
+
+    /// counts a connection to a fake ip domain for `fake-ip-auto-skip`
+    async fn report_fake_ip_result(&self, _host: &str, _ok: bool) {}
+    /// the domains `fake-ip-auto-skip` learned, None if it's off
+    async fn fake_ip_auto_skipped(&self) -> Option<Vec<String>> {
+        None
+    }
+    /// adds or removes a learned domain, false if `fake-ip-auto-skip` is off
+    async fn set_fake_ip_auto_skipped(&self, _host: &str, _skip: bool) -> bool {
+        false
+    }
+
+    /// asks each nameserver for the root NS records, with the latency or the
+    /// error of each
+    async fn probe_nameservers(&self) -> Vec<(String, Result<Duration, String>)> {
+        vec![]
+    }
+
+    /// hands the rule providers to the `rule-set:` keys of
+    /// `nameserver-policy`, as they are loaded after the resolver
+    fn set_rule_providers(
+        &self,
+        _providers: &HashMap<String, ThreadSafeRuleProvider>,
+    ) -> Result<(), crate::Error> {
+        Ok(())
+    }
+
+    /// saves the cache to cache.db with `store-dns-cache`, on shutdown
+    async fn save_cache(&self) {}
+
+    /// hands the outbounds to the nameservers asked through a proxy, as
+    /// they are built after the resolver
+    fn set_outbound_manager(&self, _outbounds: &ThreadSafeOutboundManager) {}
+
+    /// hands over where the nameserver latencies, the cache hits and the
+    /// query log go, as the statistics manager is built after the resolver
+    fn set_dns_stats(&self, _stats: Arc<DnsStats>) {}
+
     fn ipv6(&self) -> bool;
     fn set_ipv6(&self, enable: bool);
 
@@ -0,0 +1,137 @@
+//! How the nameservers of a resolver do: the ones failing over and over are
+//! left out of the queries for a while rather than waited for on each, and
+//! the latencies and errors go to the DNS stats once those are set.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::app::dispatcher::DnsStats;
+
+use super::ThreadSafeDNSClient;
+
+/// failures in a row that take a nameserver out
+const MAX_FAILURES: u32 = 3;
+/// how long it stays out, then it gets a query again
+const COOLDOWN: Duration = Duration::from_secs(30);
+
+struct Health {
+    failures: u32,
+    last_failure: Instant,
+}
+
+#[derive(Default)]
+pub struct Monitor {
+    /// by client id
+    health: Mutex<HashMap<String, Health>>,
+    stats: OnceLock<Arc<DnsStats>>,
+}
+
+impl Monitor {
+    pub fn set_stats(&self, stats: Arc<DnsStats>) {
+        let _ = self.stats.set(stats);
+    }
+
+    pub fn stats(&self) -> Option<&Arc<DnsStats>> {
+        self.stats.get()
+    }
+
+    /// a query to a nameserver, the latency is of the answer
+    pub fn record(&self, id: &str, latency: Duration, ok: bool) {
+        self.record_health(id, ok);
+        if let Some(stats) = self.stats.get() {
+            stats.record_upstream(id, latency, ok);
+        }
+    }
+
+    /// a health check, which doesn't count as a query
+    pub fn record_health(&self, id: &str, ok: bool) {
+        let mut health = self.health.lock().unwrap();
+        if ok {
+            health.remove(id);
+            return;
+        }
+        let h = health.entry(id.to_owned()).or_insert(Health {
+            failures: 0,
+            last_failure: Instant::now(),
+        });
+        h.failures += 1;
+        h.last_failure = Instant::now();
+    }
+
+    pub fn healthy(&self, id: &str) -> bool {
+        self.health.lock().unwrap().get(id).map_or(true, |h| {
+            h.failures < MAX_FAILURES || h.last_failure.elapsed() >= COOLDOWN
+        })
+    }
+
+    /// the healthy ones of `clients`, or all of them if none is, as some
+    /// answer is better than none
+    pub fn usable<'a, I>(&self, clients: I) -> Vec<&'a ThreadSafeDNSClient>
+    where
+        I: IntoIterator<Item = &'a ThreadSafeDNSClient>,
+    {
+        let clients = clients.into_iter().collect::<Vec<_>>();
+        let healthy = clients
+            .iter()
+            .copied()
+            .filter(|c| self.healthy(&c.id()))
+            .collect::<Vec<_>>();
+        if healthy.is_empty() {
+            clients
+        } else {
+            healthy
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use hickory_proto::op::Message;
+
+    use crate::app::dns::{Client, ThreadSafeDNSClient};
+
+    use super::Monitor;
+
+    #[derive(Debug)]
+    struct Named(&'static str);
+
+    #[async_trait::async_trait]
+    impl Client for Named {
+        fn id(&self) -> String {
+            self.0.to_owned()
+        }
+
+        async fn exchange(&self, _: &Message) -> anyhow::Result<Message> {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_monitor() {
+        let clients: Vec<ThreadSafeDNSClient> = vec![Arc::new(Named("a")), Arc::new(Named("b"))];
+        let ids = |x: Vec<&ThreadSafeDNSClient>| x.iter().map(|c| c.id()).collect::<Vec<_>>();
+        let m = Monitor::default();
+
+        m.record("a", Duration::ZERO, false);
+        m.record("a", Duration::ZERO, false);
+        assert!(m.healthy("a"));
+        m.record_health("a", false);
+        assert!(!m.healthy("a"));
+        assert_eq!(ids(m.usable(&clients)), vec!["b"]);
+
+        for _ in 0..3 {
+            m.record("b", Duration::ZERO, false);
+        }
+        // nothing healthy left, so everything is asked
+        assert_eq!(ids(m.usable(&clients)), vec!["a", "b"]);
+
+        m.record_health("a", true);
+        assert!(m.healthy("a"));
+        assert_eq!(ids(m.usable(&clients)), vec!["a"]);
+    }
+}
@@ -1,46 +1,169 @@
 use async_trait::async_trait;
-use futures::{FutureExt, TryFutureExt};
+use base64::{engine::general_purpose, Engine};
+use futures::FutureExt;
 use rand::prelude::SliceRandom;
+use std::collections::{HashMap, HashSet};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
-use std::time::Duration;
+use std::sync::{OnceLock, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{net, sync::Arc};
 use tokio::sync::RwLock;
-use tracing::{debug, instrument, warn};
+use tracing::{debug, error, instrument};
 
 use hickory_proto::{op, rr};
 
-use crate::app::profile::ThreadSafeCacheFile;
-use crate::common::mmdb::MMDB;
-use crate::config::def::DNSMode;
+use crate::app::dispatcher::{DnsQueryLog, DnsStats};
+use crate::app::outbound::manager::ThreadSafeOutboundManager;
+use crate::app::profile::{CachedAnswer, ThreadSafeCacheFile};
+use crate::app::remote_content_manager::providers::rule_provider::ThreadSafeRuleProvider;
+use crate::common::{
+    geosite::{DomainMatcher, GeoSite},
+    mmdb::MMDB,
+};
+use crate::config::def::{DNSMode, DNSStrategy};
 use crate::dns::helper::make_clients;
 use crate::dns::ThreadSafeDNSClient;
 use crate::dns_debug;
+use crate::session::{Session, SocksAddr};
 use crate::{common::trie, Error};
 
+use super::detour::{self, OutboundSlot};
+use super::domain_map::DomainMap;
 use super::fakeip::{self, FileStore, InMemStore, ThreadSafeFakeDns};
+use super::hosts::{Hosts, HostsAnswer};
+use super::monitor::Monitor;
+use super::rules::DnsRules;
 use super::system::SystemResolver;
 use super::{
-    filters::{DomainFilter, FallbackDomainFilter, FallbackIPFilter, GeoIPFilter, IPNetFilter},
-    Config,
+    filters::{
+        DomainFilter, FallbackDomainFilter, FallbackIPFilter, GeoIPFilter, GeoSiteFilter,
+        IPNetFilter,
+    },
+    Config, PolicySet,
 };
 use super::{ClashResolver, ResolverKind, ThreadSafeDNSResolver};
 
-static TTL: Duration = Duration::from_secs(60);
+/// how long the sequential and weighted strategies wait for a nameserver
+/// before asking the next one
+static ATTEMPT_TIMEOUT: Duration = Duration::from_secs(3);
+/// how long a query waits for the nameservers in all, and a nameserver
+/// racing the others has to answer
+static EXCHANGE_TIMEOUT: Duration = Duration::from_secs(10);
+/// how long a nameserver has to answer `probe_nameservers`
+static PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// the TTL of an expired answer served with `serve-stale`
+const STALE_TTL: u32 = 30;
+/// how long after expiring an answer may still be served with `serve-stale`
+const STALE_MAX_AGE: Duration = Duration::from_secs(86400);
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// an answer in the cache, good for `ttl` from when it was cached
+struct CachedMessage {
+    msg: op::Message,
+    cached_at: Instant,
+    ttl: Duration,
+}
+
+impl CachedMessage {
+    /// the answer with its TTLs counted down by the time it spent in the
+    /// cache, None once it expired
+    fn fresh(&self) -> Option<op::Message> {
+        let elapsed = self.cached_at.elapsed();
+        if elapsed >= self.ttl {
+            return None;
+        }
+        let elapsed = elapsed.as_secs() as u32;
+        Some(self.with_ttls(|ttl| ttl.saturating_sub(elapsed)))
+    }
+
+    /// the expired answer with `STALE_TTL`, as RFC 8767 suggests, if it
+    /// expired no longer than `STALE_MAX_AGE` ago
+    fn stale(&self) -> Option<op::Message> {
+        if self.cached_at.elapsed() >= self.ttl + STALE_MAX_AGE {
+            return None;
+        }
+        Some(self.with_ttls(|ttl| ttl.min(STALE_TTL)))
+    }
+
+    fn with_ttls(&self, f: impl Fn(u32) -> u32) -> op::Message {
+        let set_ttls = |records: Vec<rr::Record>| {
+            records
+                .into_iter()
+                .map(|mut x| {
+                    x.set_ttl(f(x.ttl()));
+                    x
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut msg = self.msg.clone();
+        let answers = set_ttls(msg.take_answers());
+        msg.insert_answers(answers);
+        let name_servers = set_ttls(msg.take_name_servers());
+        msg.insert_name_servers(name_servers);
+        let additionals = set_ttls(msg.take_additionals());
+        msg.insert_additionals(additionals);
+        msg
+    }
+}
+
+/// what a `nameserver-policy` set key matches a domain with
+enum PolicyMatcher {
+    RuleSet(String),
+    GeoSite(Arc<DomainMatcher>),
+}
 
 pub struct Resolver {
     ipv6: AtomicBool,
-    hosts: Option<trie::StringTrie<net::IpAddr>>,
+    hosts: Option<Hosts>,
     main: Vec<ThreadSafeDNSClient>,
 
     fallback: Option<Vec<ThreadSafeDNSClient>>,
     fallback_domain_filters: Option<Vec<Box<dyn FallbackDomainFilter>>>,
     fallback_ip_filters: Option<Vec<Box<dyn FallbackIPFilter>>>,
 
-    lru_cache: Option<Arc<RwLock<lru_time_cache::LruCache<String, op::Message>>>>,
+    lru_cache: Option<Arc<RwLock<lru_time_cache::LruCache<String, CachedMessage>>>>,
+    /// the bounds of how long an answer is cached, whatever its TTL
+    cache_min_ttl: u32,
+    cache_max_ttl: u32,
+    /// answer with expired records while refreshing them in the background
+    serve_stale: bool,
+    /// the queries being refreshed for `serve_stale`
+    refreshing: std::sync::Mutex<HashSet<String>>,
+    /// `dns.rules`, before everything else
+    rules: DnsRules,
     policy: Option<trie::StringTrie<Vec<ThreadSafeDNSClient>>>,
+    /// the `rule-set:` and `geosite:` keys of `nameserver-policy`, in their
+    /// order, tried after the domains
+    set_policy: Vec<(PolicyMatcher, Vec<ThreadSafeDNSClient>)>,
+    /// the rule providers of `set_policy` by name, set once the router
+    /// loaded them
+    rule_set_providers: OnceLock<HashMap<String, ThreadSafeRuleProvider>>,
+    /// for the nameservers asked through a proxy, as the outbounds are
+    /// built after the resolver
+    outbounds: OutboundSlot,
+    strategy: DNSStrategy,
 
     fake_dns: Option<ThreadSafeFakeDns>,
+    /// where the cache is saved on shutdown, with `store-dns-cache`
+    cache_store: Option<ThreadSafeCacheFile>,
+    /// the domains the answers were for, with `enhanced-mode: redir-host`
+    domain_map: Option<DomainMap>,
+    /// the health of the nameservers, and the stats once the statistics
+    /// manager is built. Shared with the queries still running after the
+    /// race they lost
+    monitor: Arc<Monitor>,
+
+    /// to refresh stale answers off the query path, unset for the
+    /// resolvers that aren't behind an Arc
+    me: Weak<Resolver>,
 }
 
 impl Resolver {
@@ -59,17 +182,34 @@ impl Resolver {
                     net: DNSNetMode::UDP,
                     address: "8.8.8.8:53".to_string(),
                     interface: None,
+                    weight: 1,
+                    doh: Default::default(),
+                    proxy: None,
                 }],
                 None,
+                &Default::default(),
             )
             .await,
             fallback: None,
             fallback_domain_filters: None,
             fallback_ip_filters: None,
             lru_cache: None,
+            cache_min_ttl: 0,
+            cache_max_ttl: 0,
+            serve_stale: false,
+            refreshing: Default::default(),
+            rules: Default::default(),
             policy: None,
+            set_policy: vec![],
+            rule_set_providers: OnceLock::new(),
+            outbounds: Default::default(),
+            strategy: DNSStrategy::Race,
 
             fake_dns: None,
+            cache_store: None,
+            domain_map: None,
+            monitor: Default::default(),
+            me: Weak::new(),
         }
     }
 
@@ -77,6 +217,7 @@ impl Resolver {
         cfg: &Config,
         store: ThreadSafeCacheFile,
         mmdb: Arc<MMDB>,
+        geosite: Option<Arc<GeoSite>>,
     ) -> ThreadSafeDNSResolver {
         if !cfg.enable {
             return Arc::new(SystemResolver::new().expect("failed to create system resolver"));
@@ -85,35 +226,87 @@ impl Resolver {
         let default_resolver = Arc::new(Resolver {
             ipv6: AtomicBool::new(false),
             hosts: None,
-            main: make_clients(cfg.default_nameserver.clone(), None).await,
+            main: make_clients(cfg.default_nameserver.clone(), None, &Default::default()).await,
             fallback: None,
             fallback_domain_filters: None,
             fallback_ip_filters: None,
             lru_cache: None,
+            cache_min_ttl: 0,
+            cache_max_ttl: 0,
+            serve_stale: false,
+            refreshing: Default::default(),
+            rules: Default::default(),
             policy: None,
+            set_policy: vec![],
+            rule_set_providers: OnceLock::new(),
+            outbounds: Default::default(),
+            strategy: cfg.strategy,
 
             fake_dns: None,
+            cache_store: None,
+            domain_map: None,
+            monitor: Default::default(),
+            me: Weak::new(),
         });
 
+        let auto_skip = match cfg.enhance_mode {
+            DNSMode::FakeIp if cfg.fake_ip_auto_skip => {
+                Some(fakeip::AutoSkip::new(Some(store.clone())).await)
+            }
+            _ => None,
+        };
+
+        if let Some(hosts) = &cfg.hosts {
+            hosts.watch();
+        }
+
+        let outbounds = OutboundSlot::default();
+        let cache_store = cfg.store_dns_cache.then(|| store.clone());
         let r = Resolver {
             ipv6: AtomicBool::new(cfg.ipv6),
-            main: make_clients(cfg.nameserver.clone(), Some(default_resolver.clone())).await,
+            main: make_clients(
+                cfg.nameserver.clone(),
+                Some(default_resolver.clone()),
+                &outbounds,
+            )
+            .await,
             hosts: cfg.hosts.clone(),
             fallback: if cfg.fallback.len() > 0 {
-                Some(make_clients(cfg.fallback.clone(), Some(default_resolver.clone())).await)
+                Some(
+                    make_clients(
+                        cfg.fallback.clone(),
+                        Some(default_resolver.clone()),
+                        &outbounds,
+                    )
+                    .await,
+                )
             } else {
                 None
             },
-            fallback_domain_filters: if cfg.fallback_filter.domain.len() > 0 {
-                Some(vec![Box::new(DomainFilter::new(
-                    cfg.fallback_filter
-                        .domain
-                        .iter()
-                        .map(|x| x.as_str())
-                        .collect(),
-                )) as Box<dyn FallbackDomainFilter>])
-            } else {
-                None
+            fallback_domain_filters: {
+                let mut filters = vec![];
+                if cfg.fallback_filter.domain.len() > 0 {
+                    filters.push(Box::new(DomainFilter::new(
+                        cfg.fallback_filter
+                            .domain
+                            .iter()
+                            .map(|x| x.as_str())
+                            .collect(),
+                    )) as Box<dyn FallbackDomainFilter>);
+                }
+                // the codes were checked as the geosite was loaded
+                for code in &cfg.fallback_filter.geosite {
+                    let (code, negate) = match code.strip_prefix('!') {
+                        Some(code) => (code, true),
+                        None => (code.as_str(), false),
+                    };
+                    match geosite.as_ref().map(|x| x.matcher(code)) {
+                        Some(Ok(m)) => filters.push(Box::new(GeoSiteFilter::new(m, negate)) as _),
+                        Some(Err(e)) => error!("fallback-filter geosite {}: {}", code, e),
+                        None => error!("fallback-filter geosite {}: no geosite loaded", code),
+                    }
+                }
+                (!filters.is_empty()).then_some(filters)
             },
             fallback_ip_filters: if cfg.fallback_filter.ip_cidr.is_some()
                 || cfg.fallback_filter.geo_ip
@@ -137,15 +330,25 @@ impl Resolver {
                 None
             },
             lru_cache: Some(Arc::new(RwLock::new(
-                lru_time_cache::LruCache::with_expiry_duration_and_capacity(TTL, 4096),
+                lru_time_cache::LruCache::with_capacity(4096),
             ))),
+            cache_min_ttl: cfg.cache_min_ttl,
+            cache_max_ttl: cfg.cache_max_ttl,
+            serve_stale: cfg.serve_stale,
+            refreshing: Default::default(),
+            rules: DnsRules::new(&cfg.rules, default_resolver.clone(), &outbounds).await,
             policy: if cfg.nameserver_policy.len() > 0 {
                 let mut p = trie::StringTrie::new();
                 for (domain, ns) in &cfg.nameserver_policy {
                     p.insert(
                         domain.as_str(),
                         Arc::new(
-                            make_clients(vec![ns.to_owned()], Some(default_resolver.clone())).await,
+                            make_clients(
+                                vec![ns.to_owned()],
+                                Some(default_resolver.clone()),
+                                &outbounds,
+                            )
+                            .await,
                         ),
                     );
                 }
@@ -153,6 +356,38 @@ impl Resolver {
             } else {
                 None
             },
+            set_policy: {
+                let mut rv = vec![];
+                for (set, ns) in &cfg.set_policy {
+                    // the codes were checked as the geosite was loaded
+                    let matcher = match set {
+                        PolicySet::RuleSet(name) => PolicyMatcher::RuleSet(name.to_owned()),
+                        PolicySet::GeoSite(code) => match geosite.as_ref().map(|x| x.matcher(code))
+                        {
+                            Some(Ok(m)) => PolicyMatcher::GeoSite(m),
+                            Some(Err(e)) => {
+                                error!("nameserver-policy geosite:{}: {}", code, e);
+                                continue;
+                            }
+                            None => {
+                                error!("nameserver-policy geosite:{}: no geosite loaded", code);
+                                continue;
+                            }
+                        },
+                    };
+                    let clients = make_clients(
+                        vec![ns.to_owned()],
+                        Some(default_resolver.clone()),
+                        &outbounds,
+                    )
+                    .await;
+                    rv.push((matcher, clients));
+                }
+                rv
+            },
+            rule_set_providers: OnceLock::new(),
+            outbounds,
+            strategy: cfg.strategy,
             fake_dns: match cfg.enhance_mode {
                 DNSMode::FakeIp => Some(Arc::new(RwLock::new(
                     fakeip::FakeDns::new(fakeip::Opts {
@@ -166,6 +401,7 @@ impl Resolver {
                         } else {
                             None
                         },
+                        auto_skip,
                         store: if cfg.store_fake_ip {
                             Box::new(FileStore::new(store))
                         } else {
@@ -174,46 +410,164 @@ impl Resolver {
                     })
                     .unwrap(),
                 ))),
-                DNSMode::RedirHost => {
-                    warn!("dns redir-host is not supported and will not do anything");
-                    None
-                }
                 _ => None,
             },
+            cache_store,
+            domain_map: match cfg.enhance_mode {
+                DNSMode::RedirHost => Some(DomainMap::default()),
+                _ => None,
+            },
+            monitor: Default::default(),
+            me: Weak::new(),
         };
+        r.load_cache().await;
+
+        let r = Arc::new_cyclic(|me| Resolver {
+            me: me.clone(),
+            ..r
+        });
+        if cfg.health_check_interval > 0 {
+            let interval = Duration::from_secs(cfg.health_check_interval);
+            tokio::spawn(Resolver::check_health(Arc::downgrade(&r), interval));
+        }
+        r
+    }
 
-        Arc::new(r)
+    /// probes the nameservers every `interval`, so the failing ones are
+    /// taken out before a query waits for them, and are back once they
+    /// answer again
+    async fn check_health(me: Weak<Resolver>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let Some(me) = me.upgrade() else {
+                return;
+            };
+            for (id, rv) in me.probe_nameservers().await {
+                if let Err(e) = &rv {
+                    dns_debug!("nameserver {} failed the health check: {}", id, e);
+                }
+                me.monitor.record_health(&id, rv.is_ok());
+            }
+        }
     }
 
+    /// asks the nameservers `monitor` finds healthy at once, the first
+    /// answer wins. The queries run detached, so the ones losing the race
+    /// still have their latency, error or timeout recorded
     pub async fn batch_exchange(
-        clients: &Vec<ThreadSafeDNSClient>,
+        clients: &[ThreadSafeDNSClient],
         message: &op::Message,
+        monitor: Option<&Arc<Monitor>>,
     ) -> anyhow::Result<op::Message> {
-        let mut queries = Vec::new();
+        let clients = match monitor {
+            Some(m) => m.usable(clients),
+            None => clients.iter().collect(),
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(clients.len().max(1));
         for c in clients {
-            queries.push(
-                async move {
-                    c.exchange(message)
-                        .inspect_err(|x| {
-                            debug!("DNS client {} resolve error: {}", c.id(), x.to_string())
-                        })
-                        .await
+            let (c, message, monitor, tx) =
+                (c.clone(), message.clone(), monitor.cloned(), tx.clone());
+            tokio::spawn(async move {
+                let start = Instant::now();
+                let rv = match tokio::time::timeout(EXCHANGE_TIMEOUT, c.exchange(&message)).await {
+                    Ok(Ok(r)) => Ok(r),
+                    Ok(Err(e)) => {
+                        debug!("DNS client {} resolve error: {}", c.id(), e);
+                        Err(e)
+                    }
+                    Err(_) => {
+                        debug!("DNS client {} timed out", c.id());
+                        Err(Error::DNSError("DNS query timeout".into()).into())
+                    }
+                };
+                if let Some(m) = monitor {
+                    m.record(&c.id(), start.elapsed(), rv.is_ok());
                 }
-                .boxed(),
-            )
+                let _ = tx.send(rv).await;
+            });
         }
+        drop(tx);
+
+        let query = async {
+            let mut last_err = anyhow!("no nameserver to ask");
+            while let Some(rv) = rx.recv().await {
+                match rv {
+                    Ok(r) => return Ok(r),
+                    Err(e) => last_err = e,
+                }
+            }
+            Err(last_err)
+        };
 
-        let timeout = tokio::time::sleep(Duration::from_secs(10));
+        tokio::time::timeout(EXCHANGE_TIMEOUT, query)
+            .await
+            .map_err(|_| Error::DNSError("DNS query timeout".into()))?
+    }
 
-        tokio::select! {
-            result = futures::future::select_ok(queries) => match result {
-                Ok(r) => Ok(r.0),
-                Err(e) => Err(e.into()),
-            },
-            _ = timeout => Err(Error::DNSError("DNS query timeout".into()).into())
+    /// asks `clients` the way `dns.strategy` says
+    async fn upstream_exchange(
+        &self,
+        clients: &[ThreadSafeDNSClient],
+        message: &op::Message,
+    ) -> anyhow::Result<op::Message> {
+        let monitor = Some(&self.monitor);
+        match self.strategy {
+            DNSStrategy::Race => Resolver::batch_exchange(clients, message, monitor).await,
+            DNSStrategy::Sequential => {
+                Resolver::sequential_exchange(clients.iter().collect(), message, monitor).await
+            }
+            DNSStrategy::Weighted => {
+                let ordered = clients
+                    .choose_multiple_weighted(&mut rand::thread_rng(), clients.len(), |c| {
+                        c.weight() as f64
+                    })
+                    .map(|x| x.collect())
+                    .unwrap_or_else(|_| clients.iter().collect());
+                Resolver::sequential_exchange(ordered, message, monitor).await
+            }
         }
     }
 
+    /// asks `clients` one by one until one answers, so the others never see
+    /// the question. The ones `monitor` finds unhealthy are skipped
+    async fn sequential_exchange(
+        clients: Vec<&ThreadSafeDNSClient>,
+        message: &op::Message,
+        monitor: Option<&Arc<Monitor>>,
+    ) -> anyhow::Result<op::Message> {
+        let clients = match monitor {
+            Some(m) => m.usable(clients),
+            None => clients,
+        };
+        let query = async {
+            let mut last_err = anyhow!("no nameserver to ask");
+            for c in clients {
+                let start = Instant::now();
+                let rv = tokio::time::timeout(ATTEMPT_TIMEOUT, c.exchange(message)).await;
+                if let Some(m) = monitor {
+                    m.record(&c.id(), start.elapsed(), matches!(rv, Ok(Ok(_))));
+                }
+                match rv {
+                    Ok(Ok(r)) => return Ok(r),
+                    Ok(Err(e)) => {
+                        debug!("DNS client {} resolve error: {}", c.id(), e);
+                        last_err = e;
+                    }
+                    Err(_) => {
+                        debug!("DNS client {} timed out", c.id());
+                        last_err = Error::DNSError("DNS query timeout".into()).into();
+                    }
+                }
+            }
+            Err(last_err)
+        };
+
+        tokio::time::timeout(EXCHANGE_TIMEOUT, query)
+            .await
+            .map_err(|_| Error::DNSError("DNS query timeout".into()))?
+    }
+
     /// guaranteed to return at least 1 IP address when Ok
     async fn lookup_ip(
         &self,
@@ -244,31 +598,189 @@ impl Resolver {
     }
 
     async fn exchange(&self, message: op::Message) -> anyhow::Result<op::Message> {
+        let start = Instant::now();
+        let query = message.query().cloned();
+        let rv = match query.as_ref().and_then(|q| self.rules.answer(q)) {
+            Some(answer) => {
+                dns_debug!("dns rule answered {}", query.as_ref().unwrap());
+                Ok((answer, "rule"))
+            }
+            None => self
+                .exchange_cached(message)
+                .await
+                .map(|(msg, cached)| (msg, if cached { "cache" } else { "upstream" })),
+        };
+        if let (Some(domain_map), Ok((msg, _))) = (&self.domain_map, &rv) {
+            domain_map.record(msg);
+        }
+        if let (Some(stats), Some(q)) = (self.monitor.stats(), query) {
+            if stats.watching() {
+                stats.log_query(Resolver::query_log(&q, &rv, start.elapsed()));
+            }
+        }
+        rv.map(|(msg, _)| msg)
+    }
+
+    /// an entry of the query log, `rv` being the answer and where it came
+    /// from
+    fn query_log(
+        q: &op::Query,
+        rv: &anyhow::Result<(op::Message, &str)>,
+        latency: Duration,
+    ) -> DnsQueryLog {
+        let mut entry = DnsQueryLog {
+            time: chrono::Utc::now(),
+            name: q.name().to_ascii(),
+            query_type: q.query_type().to_string(),
+            source: Default::default(),
+            rcode: Default::default(),
+            answers: vec![],
+            latency_ms: latency.as_millis() as u64,
+            error: None,
+        };
+        match rv {
+            Ok((msg, source)) => {
+                entry.source = source.to_string();
+                entry.rcode = msg.response_code().to_string();
+                entry.answers = msg
+                    .answers()
+                    .iter()
+                    .filter_map(|x| x.data().map(|x| x.to_string()))
+                    .collect();
+            }
+            Err(e) => entry.error = Some(e.to_string()),
+        }
+        entry
+    }
+
+    /// the answer and whether it came from the cache
+    async fn exchange_cached(&self, message: op::Message) -> anyhow::Result<(op::Message, bool)> {
         if let Some(q) = message.query() {
             if let Some(lru) = &self.lru_cache {
-                if let Some(cached) = lru.read().await.peek(q.to_string().as_str()) {
-                    return Ok(cached.clone());
+                let hit = lru.read().await.peek(q.to_string().as_str()).and_then(|x| {
+                    x.fresh().map(|msg| (msg, false)).or_else(|| {
+                        self.serve_stale
+                            .then(|| x.stale())
+                            .flatten()
+                            .map(|msg| (msg, true))
+                    })
+                });
+                if let Some(stats) = self.monitor.stats() {
+                    stats.record_cache(hit.is_some());
+                }
+                match hit {
+                    Some((msg, false)) => return Ok((msg, true)),
+                    Some((msg, true)) => {
+                        self.refresh_in_background(message.clone());
+                        return Ok((msg, true));
+                    }
+                    None => {}
                 }
             }
-            self.exchange_no_cache(&message).await
+            self.exchange_no_cache(&message).await.map(|x| (x, false))
         } else {
             Err(anyhow!("invalid query"))
         }
     }
 
+    /// fills the cache with the answers saved on the last shutdown that are
+    /// still fresh
+    async fn load_cache(&self) {
+        let (Some(store), Some(lru)) = (&self.cache_store, &self.lru_cache) else {
+            return;
+        };
+        let answers = store.take_dns_cache().await;
+        let now = unix_now();
+        let mut lru = lru.write().await;
+        for answer in answers {
+            let elapsed = Duration::from_secs(now.saturating_sub(answer.cached_at));
+            let ttl = Duration::from_secs(answer.ttl);
+            if elapsed >= ttl {
+                continue;
+            }
+            let msg = match general_purpose::STANDARD
+                .decode(&answer.message)
+                .ok()
+                .and_then(|x| op::Message::from_vec(&x).ok())
+            {
+                Some(msg) => msg,
+                None => continue,
+            };
+            let (Some(key), Some(cached_at)) = (
+                msg.query().map(|q| q.to_string()),
+                Instant::now().checked_sub(elapsed),
+            ) else {
+                continue;
+            };
+            lru.insert(
+                key,
+                CachedMessage {
+                    msg,
+                    cached_at,
+                    ttl,
+                },
+            );
+        }
+        debug!("loaded {} cached dns answers", lru.len());
+    }
+
+    /// asks the upstreams again for a stale answer without making the
+    /// client wait for it
+    fn refresh_in_background(&self, message: op::Message) {
+        let me = match self.me.upgrade() {
+            Some(me) => me,
+            None => return,
+        };
+        let key = match message.query() {
+            Some(q) => q.to_string(),
+            None => return,
+        };
+        if !self.refreshing.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+        tokio::spawn(async move {
+            if let Err(e) = me.exchange_no_cache(&message).await {
+                debug!("failed to refresh stale answer of {}: {}", key, e);
+            }
+            me.refreshing.lock().unwrap().remove(&key);
+        });
+    }
+
+    /// how long `msg` may be cached: its smallest answer TTL, or for
+    /// NXDOMAIN and NODATA the SOA TTL capped by the SOA minimum as RFC 2308
+    /// says. None for failures and for negative answers without a SOA
+    fn cache_ttl(msg: &op::Message) -> Option<u32> {
+        match msg.response_code() {
+            op::ResponseCode::NoError if !msg.answers().is_empty() => {
+                msg.answers().iter().map(|x| x.ttl()).min()
+            }
+            op::ResponseCode::NoError | op::ResponseCode::NXDomain => {
+                msg.name_servers().iter().find_map(|x| match x.data() {
+                    Some(rr::RData::SOA(soa)) => Some(x.ttl().min(soa.minimum())),
+                    _ => None,
+                })
+            }
+            _ => None,
+        }
+    }
+
     async fn exchange_no_cache(&self, message: &op::Message) -> anyhow::Result<op::Message> {
         let q = message.query().unwrap();
 
         let query = async move {
+            if let Some(matched) = self.rules.upstream(q) {
+                return self.upstream_exchange(matched, message).await;
+            }
+
             if Resolver::is_ip_request(q) {
                 return self.ip_exchange(message).await;
             }
 
             if let Some(matched) = self.match_policy(&message) {
-                return Resolver::batch_exchange(&matched, message).await;
+                return self.upstream_exchange(matched, message).await;
             }
 
-            return Resolver::batch_exchange(&self.main, message).await;
+            return self.upstream_exchange(&self.main, message).await;
         };
 
         let rv = query.await;
@@ -278,29 +790,19 @@ impl Resolver {
                 if !(q.query_type() == rr::RecordType::TXT
                     && q.name().to_ascii().starts_with("_acme-challenge."))
                 {
-                    // TODO: make this TTL wired to LRU cache
-                    #[allow(unused_variables)]
-                    let ttl = if msg.answer_count() != 0 {
-                        msg.answers()
-                            .iter()
-                            .map(|x| x.ttl())
-                            .min()
-                            .unwrap_or_default()
-                    } else if msg.name_server_count() != 0 {
-                        msg.name_servers()
-                            .iter()
-                            .map(|x| x.ttl())
-                            .min()
-                            .unwrap_or_default()
-                    } else {
-                        msg.additionals()
-                            .iter()
-                            .map(|x| x.ttl())
-                            .min()
-                            .unwrap_or_default()
-                    };
-
-                    lru.write().await.insert(q.to_string(), msg.clone());
+                    let ttl = Resolver::cache_ttl(msg)
+                        .map(|x| x.clamp(self.cache_min_ttl, self.cache_max_ttl))
+                        .unwrap_or_default();
+                    if ttl > 0 {
+                        lru.write().await.insert(
+                            q.to_string(),
+                            CachedMessage {
+                                msg: msg.clone(),
+                                cached_at: Instant::now(),
+                                ttl: Duration::from_secs(ttl as u64),
+                            },
+                        );
+                    }
                 }
             }
         }
@@ -309,33 +811,46 @@ impl Resolver {
     }
 
     fn match_policy(&self, m: &op::Message) -> Option<&Vec<ThreadSafeDNSClient>> {
-        if let (Some(_fallback), Some(_fallback_domain_filters), Some(policy)) =
-            (&self.fallback, &self.fallback_domain_filters, &self.policy)
-        {
-            if let Some(domain) = Resolver::domain_name_of_message(m) {
-                return policy.search(&domain).map(|n| n.get_data().unwrap());
-            }
+        let domain = Resolver::domain_name_of_message(m)?;
+        if let Some(n) = self.policy.as_ref().and_then(|x| x.search(&domain)) {
+            return n.get_data();
         }
-        None
+
+        let providers = self.rule_set_providers.get();
+        let sess = Session {
+            destination: SocksAddr::Domain(domain, 0),
+            ..Default::default()
+        };
+        self.set_policy
+            .iter()
+            .find(|(m, _)| match m {
+                PolicyMatcher::RuleSet(name) => providers
+                    .and_then(|x| x.get(name))
+                    .is_some_and(|p| p.search(&sess)),
+                PolicyMatcher::GeoSite(m) => m.matches(&sess.destination.host()),
+            })
+            .map(|(_, clients)| clients)
     }
 
     async fn ip_exchange(&self, message: &op::Message) -> anyhow::Result<op::Message> {
-        if let Some(mut matched) = self.match_policy(message) {
-            return Resolver::batch_exchange(&mut matched, message).await;
+        if let Some(matched) = self.match_policy(message) {
+            return self.upstream_exchange(matched, message).await;
         }
 
         if self.should_only_query_fallback(message) {
             // self.fallback guaranteed in the above check
-            return Resolver::batch_exchange(&self.fallback.as_ref().unwrap(), message).await;
+            return self
+                .upstream_exchange(self.fallback.as_ref().unwrap(), message)
+                .await;
         }
 
-        let main_query = Resolver::batch_exchange(&self.main, message);
+        let main_query = self.upstream_exchange(&self.main, message);
 
         if self.fallback.is_none() {
             return main_query.await;
         }
 
-        let fallback_query = Resolver::batch_exchange(&self.fallback.as_ref().unwrap(), message);
+        let fallback_query = self.upstream_exchange(self.fallback.as_ref().unwrap(), message);
 
         if let Ok(main_result) = main_query.await {
             let ip_list = Resolver::ip_list_of_message(&main_result);
@@ -439,11 +954,17 @@ impl ClashResolver for Resolver {
     ) -> anyhow::Result<Option<net::Ipv4Addr>> {
         if enhanced {
             if let Some(hosts) = &self.hosts {
-                if let Some(v) = hosts.search(host) {
-                    return Ok(v.get_data().map(|v| match v {
-                        net::IpAddr::V4(v4) => *v4,
-                        _ => unreachable!("invalid IP family"),
-                    }));
+                match hosts.lookup(host)? {
+                    Some(HostsAnswer::Ips(ips)) => {
+                        return Ok(ips.into_iter().find_map(|ip| match ip {
+                            net::IpAddr::V4(v4) => Some(v4),
+                            _ => None,
+                        }))
+                    }
+                    Some(HostsAnswer::Domain(domain)) => {
+                        return self.resolve_v4(&domain, false).await
+                    }
+                    None => {}
                 }
             }
         }
@@ -484,11 +1005,17 @@ impl ClashResolver for Resolver {
 
         if enhanced {
             if let Some(hosts) = &self.hosts {
-                if let Some(v) = hosts.search(host) {
-                    return Ok(v.get_data().map(|v| match v {
-                        net::IpAddr::V6(v6) => *v6,
-                        _ => unreachable!("invalid IP family"),
-                    }));
+                match hosts.lookup(host)? {
+                    Some(HostsAnswer::Ips(ips)) => {
+                        return Ok(ips.into_iter().find_map(|ip| match ip {
+                            net::IpAddr::V6(v6) => Some(v6),
+                            _ => None,
+                        }))
+                    }
+                    Some(HostsAnswer::Domain(domain)) => {
+                        return self.resolve_v6(&domain, false).await
+                    }
+                    None => {}
                 }
             }
         }
@@ -519,6 +1046,57 @@ impl ClashResolver for Resolver {
         self.ipv6.store(enable, Relaxed);
     }
 
+    async fn probe_nameservers(&self) -> Vec<(String, Result<Duration, String>)> {
+        let clients = self.main.iter().chain(self.fallback.iter().flatten());
+        futures::future::join_all(clients.map(|c| async move {
+            let mut m = op::Message::new();
+            m.set_recursion_desired(true);
+            m.add_query(op::Query::query(rr::Name::root(), rr::RecordType::NS));
+
+            let start = Instant::now();
+            let rv = match tokio::time::timeout(PROBE_TIMEOUT, c.exchange(&m)).await {
+                Ok(Ok(_)) => Ok(start.elapsed()),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err("timeout".to_owned()),
+            };
+            (c.id(), rv)
+        }))
+        .await
+    }
+
+    fn set_rule_providers(
+        &self,
+        providers: &HashMap<String, ThreadSafeRuleProvider>,
+    ) -> Result<(), Error> {
+        let rv = self
+            .set_policy
+            .iter()
+            .filter_map(|(m, _)| match m {
+                PolicyMatcher::RuleSet(name) => Some(name),
+                PolicyMatcher::GeoSite(_) => None,
+            })
+            .map(|name| {
+                let p = providers.get(name).cloned().ok_or_else(|| {
+                    Error::InvalidConfig(format!(
+                        "nameserver-policy: unknown rule provider {}",
+                        name
+                    ))
+                })?;
+                Ok((name.to_owned(), p))
+            })
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+        self.rule_set_providers.set(rv).ok();
+        Ok(())
+    }
+
+    fn set_outbound_manager(&self, outbounds: &ThreadSafeOutboundManager) {
+        detour::set_outbounds(&self.outbounds, outbounds);
+    }
+
+    fn set_dns_stats(&self, stats: Arc<DnsStats>) {
+        self.monitor.set_stats(stats);
+    }
+
     fn kind(&self) -> ResolverKind {
         ResolverKind::Clash
     }
@@ -554,10 +1132,57 @@ impl ClashResolver for Resolver {
         let mut fake_dns = self.fake_dns.as_ref().unwrap().write().await;
         fake_dns.reverse_lookup(ip).await
     }
+
+    async fn save_cache(&self) {
+        let (Some(store), Some(lru)) = (&self.cache_store, &self.lru_cache) else {
+            return;
+        };
+        let now = unix_now();
+        let answers = lru
+            .read()
+            .await
+            .peek_iter()
+            .filter(|(_, x)| x.cached_at.elapsed() < x.ttl)
+            .filter_map(|(_, x)| {
+                Some(CachedAnswer {
+                    message: general_purpose::STANDARD.encode(x.msg.to_vec().ok()?),
+                    cached_at: now.saturating_sub(x.cached_at.elapsed().as_secs()),
+                    ttl: x.ttl.as_secs(),
+                })
+            })
+            .collect();
+        store.set_dns_cache(answers).await;
+        store.flush().await;
+    }
+
+    fn domain_of(&self, ip: net::IpAddr) -> Option<String> {
+        self.domain_map.as_ref().and_then(|x| x.get(ip))
+    }
+
+    async fn report_fake_ip_result(&self, host: &str, ok: bool) {
+        if let Some(fake_dns) = &self.fake_dns {
+            fake_dns.write().await.report(host, ok).await;
+        }
+    }
+
+    async fn fake_ip_auto_skipped(&self) -> Option<Vec<String>> {
+        match &self.fake_dns {
+            Some(fake_dns) => fake_dns.read().await.auto_skipped(),
+            None => None,
+        }
+    }
+
+    async fn set_fake_ip_auto_skipped(&self, host: &str, skip: bool) -> bool {
+        match &self.fake_dns {
+            Some(fake_dns) => fake_dns.write().await.set_auto_skipped(host, skip).await,
+            None => false,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::app::dns::resolver::CachedMessage;
     use crate::dns::dns_client::{DNSNetMode, DnsClient, Opts};
     use crate::dns::{Resolver, ThreadSafeDNSClient};
     use hickory_client::{client, op};
@@ -565,9 +1190,150 @@ mod tests {
     use hickory_proto::udp::UdpClientStream;
     use hickory_proto::xfer::{DnsHandle, DnsRequest, DnsRequestOptions, FirstAnswer};
     use std::sync::Arc;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
     use tokio::net::UdpSocket;
 
+    #[test]
+    fn test_cached_message_ttl() {
+        let mut m = op::Message::new();
+        m.add_answer(rr::Record::from_rdata(
+            rr::Name::from_ascii("example.com.").unwrap(),
+            300,
+            rr::RData::A(rr::rdata::A::new(93, 184, 216, 34)),
+        ));
+        let ten_secs_ago = Instant::now() - Duration::from_secs(10);
+
+        let cached = CachedMessage {
+            msg: m.clone(),
+            cached_at: ten_secs_ago,
+            ttl: Duration::from_secs(300),
+        };
+        let fresh = cached.fresh().expect("not expired");
+        assert_eq!(fresh.answers()[0].ttl(), 290);
+
+        let cached = CachedMessage {
+            msg: m,
+            cached_at: ten_secs_ago,
+            ttl: Duration::from_secs(5),
+        };
+        assert!(cached.fresh().is_none());
+    }
+
+    #[test]
+    fn test_stale_message() {
+        let mut m = op::Message::new();
+        m.add_answer(rr::Record::from_rdata(
+            rr::Name::from_ascii("example.com.").unwrap(),
+            300,
+            rr::RData::A(rr::rdata::A::new(93, 184, 216, 34)),
+        ));
+
+        let cached = CachedMessage {
+            msg: m.clone(),
+            cached_at: Instant::now() - Duration::from_secs(400),
+            ttl: Duration::from_secs(300),
+        };
+        assert!(cached.fresh().is_none());
+        assert_eq!(
+            cached.stale().expect("within max age").answers()[0].ttl(),
+            30
+        );
+
+        let cached = CachedMessage {
+            msg: m,
+            cached_at: Instant::now() - Duration::from_secs(300) - super::STALE_MAX_AGE,
+            ttl: Duration::from_secs(300),
+        };
+        assert!(cached.stale().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_store_cache() {
+        use crate::app::{dns::ClashResolver, profile::ThreadSafeCacheFile};
+        use tokio::sync::RwLock;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        let store = ThreadSafeCacheFile::new(path.to_str().unwrap(), false);
+
+        let mut m = op::Message::new();
+        m.add_query(op::Query::query(
+            rr::Name::from_ascii("example.com.").unwrap(),
+            rr::RecordType::A,
+        ));
+        m.add_answer(rr::Record::from_rdata(
+            rr::Name::from_ascii("example.com.").unwrap(),
+            300,
+            rr::RData::A(rr::rdata::A::new(93, 184, 216, 34)),
+        ));
+        let key = m.query().unwrap().to_string();
+
+        let mut r = Resolver::new_default().await;
+        r.cache_store = Some(store);
+        r.lru_cache = Some(Arc::new(RwLock::new(
+            lru_time_cache::LruCache::with_capacity(16),
+        )));
+        r.lru_cache.as_ref().unwrap().write().await.insert(
+            key.clone(),
+            CachedMessage {
+                msg: m,
+                cached_at: Instant::now() - Duration::from_secs(10),
+                ttl: Duration::from_secs(300),
+            },
+        );
+        r.save_cache().await;
+
+        let mut r = Resolver::new_default().await;
+        r.cache_store = Some(ThreadSafeCacheFile::new(path.to_str().unwrap(), false));
+        r.lru_cache = Some(Arc::new(RwLock::new(
+            lru_time_cache::LruCache::with_capacity(16),
+        )));
+        r.load_cache().await;
+        let lru = r.lru_cache.as_ref().unwrap().read().await;
+        let fresh = lru.peek(&key).and_then(|x| x.fresh()).expect("loaded");
+        assert!(fresh.answers()[0].ttl() <= 290);
+        assert!(fresh.answers()[0].ttl() > 280);
+    }
+
+    #[test]
+    fn test_negative_cache_ttl() {
+        let name = rr::Name::from_ascii("example.com.").unwrap();
+        let soa = rr::rdata::SOA::new(
+            rr::Name::from_ascii("ns.example.com.").unwrap(),
+            rr::Name::from_ascii("admin.example.com.").unwrap(),
+            1,
+            7200,
+            3600,
+            1209600,
+            60,
+        );
+
+        let mut m = op::Message::new();
+        m.set_response_code(op::ResponseCode::NXDomain);
+        assert_eq!(Resolver::cache_ttl(&m), None);
+
+        m.add_name_server(rr::Record::from_rdata(
+            name.clone(),
+            3600,
+            rr::RData::SOA(soa),
+        ));
+        assert_eq!(Resolver::cache_ttl(&m), Some(60));
+
+        m.set_response_code(op::ResponseCode::NoError);
+        assert_eq!(Resolver::cache_ttl(&m), Some(60));
+
+        m.set_response_code(op::ResponseCode::ServFail);
+        assert_eq!(Resolver::cache_ttl(&m), None);
+
+        m.set_response_code(op::ResponseCode::NoError);
+        m.add_answer(rr::Record::from_rdata(
+            name,
+            300,
+            rr::RData::A(rr::rdata::A::new(93, 184, 216, 34)),
+        ));
+        assert_eq!(Resolver::cache_ttl(&m), Some(300));
+    }
+
     #[tokio::test]
     async fn test_bad_labels_with_custom_resolver() {
         let name = rr::Name::from_str_relaxed("some_domain.understore")
@@ -685,7 +1451,7 @@ mod tests {
         q.set_query_type(rr::RecordType::A);
         m.add_query(q);
 
-        let r = Resolver::batch_exchange(&vec![c.clone()], &m)
+        let r = Resolver::batch_exchange(&vec![c.clone()], &m, None)
             .await
             .expect("should exchange");
 
@@ -701,7 +1467,7 @@ mod tests {
         q.set_query_type(rr::RecordType::AAAA);
         m.add_query(q);
 
-        let r = Resolver::batch_exchange(&vec![c.clone()], &m)
+        let r = Resolver::batch_exchange(&vec![c.clone()], &m, None)
             .await
             .expect("should exchange");
 
@@ -711,4 +1477,64 @@ mod tests {
         assert!(!ips[0].is_unspecified());
         assert!(ips[0].is_ipv6());
     }
+
+    /// answers with its id in the message id, or fails
+    #[derive(Debug)]
+    struct MockClient {
+        id: u16,
+        ok: bool,
+        asked: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::dns::Client for MockClient {
+        fn id(&self) -> String {
+            format!("mock#{}", self.id)
+        }
+
+        async fn exchange(&self, _: &op::Message) -> anyhow::Result<op::Message> {
+            self.asked
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            if self.ok {
+                let mut m = op::Message::new();
+                m.set_id(self.id);
+                Ok(m)
+            } else {
+                Err(anyhow!("mock failure"))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sequential_exchange() {
+        let mock = |id, ok| {
+            Arc::new(MockClient {
+                id,
+                ok,
+                asked: Default::default(),
+            })
+        };
+        let clients = vec![mock(1, false), mock(2, true), mock(3, true)];
+        let dyn_clients: Vec<ThreadSafeDNSClient> =
+            clients.iter().map(|c| c.clone() as _).collect();
+
+        let r =
+            Resolver::sequential_exchange(dyn_clients.iter().collect(), &op::Message::new(), None)
+                .await
+                .expect("should fail over");
+        assert_eq!(r.id(), 2);
+
+        let asked = clients
+            .iter()
+            .map(|c| c.asked.load(std::sync::atomic::Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        // the third nameserver never sees the question
+        assert_eq!(asked, vec![1, 1, 0]);
+
+        assert!(
+            Resolver::sequential_exchange(vec![&dyn_clients[0]], &op::Message::new(), None)
+                .await
+                .is_err()
+        );
+    }
 }
@@ -0,0 +1,210 @@
+//! `dns.rules`, looked at before the cache and `nameserver-policy`: a
+//! matching rule either answers the query itself or names the nameservers
+//! to ask.
+
+use std::{net::IpAddr, sync::Arc};
+
+use hickory_proto::{
+    op::{Message, Query, ResponseCode},
+    rr::{
+        rdata::{A, AAAA},
+        RData, Record, RecordType,
+    },
+};
+
+use crate::{
+    common::trie,
+    dns::{helper::make_clients, ThreadSafeDNSClient},
+};
+
+use super::{
+    config::{DNSRule, DNSRuleAction},
+    detour::OutboundSlot,
+    ThreadSafeDNSResolver,
+};
+
+pub enum Action {
+    StaticIp { ips: Vec<IpAddr>, ttl: u32 },
+    Rcode(ResponseCode),
+    Upstream(Vec<ThreadSafeDNSClient>),
+}
+
+struct Rule {
+    domains: trie::StringTrie<()>,
+    query_types: Vec<RecordType>,
+    action: Action,
+}
+
+#[derive(Default)]
+pub struct DnsRules(Vec<Rule>);
+
+impl DnsRules {
+    pub async fn new(
+        rules: &[DNSRule],
+        resolver: ThreadSafeDNSResolver,
+        outbounds: &OutboundSlot,
+    ) -> Self {
+        let mut rv = vec![];
+        for rule in rules {
+            let mut domains = trie::StringTrie::new();
+            for domain in &rule.domains {
+                domains.insert(domain, Arc::new(()));
+            }
+            let action = match &rule.action {
+                DNSRuleAction::StaticIp { ips, ttl } => Action::StaticIp {
+                    ips: ips.clone(),
+                    ttl: *ttl,
+                },
+                DNSRuleAction::Rcode(rcode) => Action::Rcode(*rcode),
+                DNSRuleAction::Upstream(ns) => Action::Upstream(
+                    make_clients(ns.clone(), Some(resolver.clone()), outbounds).await,
+                ),
+            };
+            rv.push(Rule {
+                domains,
+                query_types: rule.query_types.clone(),
+                action,
+            });
+        }
+        Self(rv)
+    }
+
+    /// the action of the first rule matching `query`
+    pub fn find(&self, query: &Query) -> Option<&Action> {
+        let domain = query.name().to_ascii().to_lowercase();
+        let domain = domain.trim_end_matches('.');
+        if domain.is_empty() {
+            return None;
+        }
+        self.0
+            .iter()
+            .find(|x| {
+                (x.query_types.is_empty() || x.query_types.contains(&query.query_type()))
+                    && x.domains.search(domain).is_some()
+            })
+            .map(|x| &x.action)
+    }
+
+    /// the answer of the rule matching `query`, without its id and
+    /// question, if it is one that answers
+    pub fn answer(&self, query: &Query) -> Option<Message> {
+        let mut m = Message::new();
+        match self.find(query)? {
+            Action::StaticIp { ips, ttl } => {
+                // the other types of a pinned domain are still asked for
+                if !matches!(query.query_type(), RecordType::A | RecordType::AAAA) {
+                    return None;
+                }
+                for ip in ips {
+                    let rdata = match (query.query_type(), ip) {
+                        (RecordType::A, IpAddr::V4(v4)) => RData::A(A(*v4)),
+                        (RecordType::AAAA, IpAddr::V6(v6)) => RData::AAAA(AAAA(*v6)),
+                        _ => continue,
+                    };
+                    m.add_answer(Record::from_rdata(query.name().clone(), *ttl, rdata));
+                }
+            }
+            Action::Rcode(rcode) => {
+                m.set_response_code(*rcode);
+            }
+            Action::Upstream(_) => return None,
+        }
+        Some(m)
+    }
+
+    /// the nameservers of the rule matching `query`, if it is an upstream one
+    pub fn upstream(&self, query: &Query) -> Option<&Vec<ThreadSafeDNSClient>> {
+        match self.find(query)? {
+            Action::Upstream(clients) => Some(clients),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use hickory_proto::{
+        op::{Query, ResponseCode},
+        rr::{Name, RData, RecordType},
+    };
+
+    use crate::app::dns::config::{DNSRule, DNSRuleAction};
+
+    use super::DnsRules;
+
+    #[tokio::test]
+    async fn test_dns_rules() {
+        let rule = |domains: &[&str], query_types: Vec<RecordType>, action| DNSRule {
+            domains: domains.iter().map(|x| x.to_string()).collect(),
+            query_types,
+            action,
+        };
+        let resolver = std::sync::Arc::new(crate::app::dns::Resolver::new_default().await);
+        let rules = DnsRules::new(
+            &[
+                rule(
+                    &["nas.home.arpa"],
+                    vec![],
+                    DNSRuleAction::StaticIp {
+                        ips: vec!["192.168.1.10".parse().unwrap()],
+                        ttl: 300,
+                    },
+                ),
+                rule(
+                    &["+.example.org"],
+                    vec![RecordType::HTTPS],
+                    DNSRuleAction::Rcode(ResponseCode::Refused),
+                ),
+                rule(
+                    &["+.example.org", "*.ads.example"],
+                    vec![],
+                    DNSRuleAction::Rcode(ResponseCode::NXDomain),
+                ),
+            ],
+            resolver,
+            &Default::default(),
+        )
+        .await;
+        let query = |name: &str, t| Query::query(Name::from_ascii(name).unwrap(), t);
+
+        let m = rules
+            .answer(&query("nas.home.arpa.", RecordType::A))
+            .unwrap();
+        assert_eq!(m.response_code(), ResponseCode::NoError);
+        assert_eq!(m.answers()[0].ttl(), 300);
+        assert!(matches!(
+            m.answers()[0].data(),
+            Some(RData::A(a)) if IpAddr::V4(**a) == "192.168.1.10".parse::<IpAddr>().unwrap()
+        ));
+        let m = rules
+            .answer(&query("nas.home.arpa.", RecordType::AAAA))
+            .unwrap();
+        assert!(m.answers().is_empty());
+        assert!(rules
+            .answer(&query("nas.home.arpa.", RecordType::TXT))
+            .is_none());
+
+        let m = rules
+            .answer(&query("www.example.org.", RecordType::HTTPS))
+            .unwrap();
+        assert_eq!(m.response_code(), ResponseCode::Refused);
+        let m = rules.answer(&query("example.org.", RecordType::A)).unwrap();
+        assert_eq!(m.response_code(), ResponseCode::NXDomain);
+        let m = rules
+            .answer(&query("x.ads.example.", RecordType::A))
+            .unwrap();
+        assert_eq!(m.response_code(), ResponseCode::NXDomain);
+
+        assert!(rules
+            .answer(&query("ads.example.", RecordType::A))
+            .is_none());
+        assert!(rules
+            .answer(&query("example.com.", RecordType::A))
+            .is_none());
+        assert!(rules
+            .upstream(&query("example.org.", RecordType::A))
+            .is_none());
+    }
+}
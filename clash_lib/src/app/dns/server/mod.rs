@@ -3,8 +3,8 @@ use std::time::Duration;
 use async_trait::async_trait;
 
 use hickory_proto::{
-    op::{Header, Message, MessageType, OpCode, ResponseCode},
-    rr::RecordType,
+    op::{Header, Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{rdata::AAAA, RData, Record, RecordType},
 };
 use hickory_server::{
     authority::MessageResponseBuilder,
@@ -12,12 +12,15 @@ use hickory_server::{
     ServerFuture,
 };
 use thiserror::Error;
-use tokio::net::{TcpListener, UdpSocket};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+};
 use tracing::{debug, info, warn};
 
-use crate::Runner;
+use crate::{common::nat64, Runner};
 
-use super::{Config, ThreadSafeDNSResolver};
+use super::{Config, ThreadSafeDNSResolver, ThreadSafeDnsBlocker};
 
 struct DnsListener {
     server: ServerFuture<DnsHandler>,
@@ -25,6 +28,7 @@ struct DnsListener {
 
 struct DnsHandler {
     resolver: ThreadSafeDNSResolver,
+    blocker: Option<ThreadSafeDnsBlocker>,
 }
 
 #[derive(Error, Debug)]
@@ -60,13 +64,6 @@ impl DnsHandler {
         let builder = MessageResponseBuilder::from_message_request(request);
         let mut header = Header::response_from_request(request.header());
 
-        if request.query().query_type() == RecordType::AAAA && !self.resolver.ipv6() {
-            header.set_authoritative(true);
-
-            let resp = builder.build_no_records(header);
-            return Ok(response_handle.send_response(resp).await?);
-        }
-
         let mut m = Message::new();
         m.set_op_code(request.op_code());
         m.set_message_type(request.message_type());
@@ -81,41 +78,118 @@ impl DnsHandler {
             m.set_edns(edns.clone());
         }
 
-        match self.resolver.exchange(m).await {
-            Ok(m) => {
-                header.set_recursion_available(m.recursion_available());
-                header.set_response_code(m.response_code());
-                header.set_authoritative(m.authoritative());
-
-                header.set_answer_count(m.answer_count());
-                header.set_name_server_count(m.name_server_count());
-                header.set_additional_count(m.additional_count());
-
-                let mut rv =
-                    builder.build(header, m.answers(), m.name_servers(), &[], m.additionals());
-
-                if let Some(edns) = request.edns() {
-                    if edns.dnssec_ok() {
-                        if let Some(edns) = m.extensions() {
-                            rv.set_edns(edns.clone());
-                        }
-                    }
+        let m = resolve(&self.resolver, self.blocker.as_ref(), m).await?;
+
+        header.set_recursion_available(m.recursion_available());
+        header.set_response_code(m.response_code());
+        header.set_authoritative(m.authoritative());
+
+        header.set_answer_count(m.answer_count());
+        header.set_name_server_count(m.name_server_count());
+        header.set_additional_count(m.additional_count());
+
+        let mut rv = builder.build(header, m.answers(), m.name_servers(), &[], m.additionals());
+
+        if let Some(edns) = request.edns() {
+            if edns.dnssec_ok() {
+                if let Some(edns) = m.extensions() {
+                    rv.set_edns(edns.clone());
                 }
+            }
+        }
 
-                debug!(
-                    "answering dns query {} with answer {:?}",
-                    request.query().name(),
-                    m.answers(),
-                );
+        debug!(
+            "answering dns query {} with answer {:?}",
+            request.query().name(),
+            m.answers(),
+        );
+
+        Ok(response_handle.send_response(rv).await?)
+    }
+}
+
+/// answers `req`, the query as it is sent upstream, for the DNS listener and
+/// the hijacked queries alike: with the blocker's answer, no records for AAAA
+/// while IPv6 is off, or the resolver's, with AAAA records synthesized by
+/// DNS64
+async fn resolve(
+    resolver: &ThreadSafeDNSResolver,
+    blocker: Option<&ThreadSafeDnsBlocker>,
+    req: Message,
+) -> Result<Message, DNSError> {
+    let query = req
+        .query()
+        .cloned()
+        .ok_or(DNSError::QueryFailed("no query".to_owned()))?;
+
+    if let Some(blocker) = blocker.filter(|x| x.blocks(query.name())) {
+        debug!("dns query {} blocked", query.name());
+        let mut m = blocker.answer(&query);
+        m.set_recursion_available(true);
+        return Ok(m);
+    }
+
+    if query.query_type() == RecordType::AAAA && !resolver.ipv6() && nat64::prefix().is_none() {
+        let mut m = Message::new();
+        m.set_authoritative(true);
+        return Ok(m);
+    }
 
-                Ok(response_handle.send_response(rv).await?)
+    let m = resolver.exchange(req).await.map_err(|e| {
+        debug!("dns resolve error: {}", e);
+        DNSError::QueryFailed(e.to_string())
+    })?;
+    if query.query_type() == RecordType::AAAA {
+        Ok(dns64(resolver, &query, m).await)
+    } else {
+        Ok(m)
+    }
+}
+
+/// synthesizes AAAA records from the A records for names without any,
+/// when IPv4 destinations go through NAT64
+async fn dns64(resolver: &ThreadSafeDNSResolver, query: &Query, m: Message) -> Message {
+    if nat64::prefix().is_none()
+        || m.response_code() != ResponseCode::NoError
+        || m.answers()
+            .iter()
+            .any(|x| x.record_type() == RecordType::AAAA)
+    {
+        return m;
+    }
+
+    let mut q = query.clone();
+    q.set_query_type(RecordType::A);
+    let mut req = Message::new();
+    req.set_recursion_desired(true);
+    req.add_query(q);
+    let a = match resolver.exchange(req).await {
+        Ok(a) => a,
+        Err(e) => {
+            debug!("dns64 lookup of {} failed: {}", query.name(), e);
+            return m;
+        }
+    };
+
+    let mut m = m;
+    for record in a.answers() {
+        match record.data() {
+            Some(RData::A(ip)) => {
+                if let std::net::IpAddr::V6(v6) = nat64::translate_ip(ip.0.into()) {
+                    m.add_answer(Record::from_rdata(
+                        record.name().clone(),
+                        record.ttl(),
+                        RData::AAAA(AAAA(v6)),
+                    ));
+                }
             }
-            Err(e) => {
-                debug!("dns resolve error: {}", e);
-                Err(DNSError::QueryFailed(e.to_string()))
+            Some(RData::CNAME(_)) => {
+                m.add_answer(record.clone());
             }
+            _ => {}
         }
     }
+    m
 }
 
 #[async_trait]
@@ -147,12 +221,90 @@ impl RequestHandler for DnsHandler {
 
 static DEFAULT_DNS_SERVER_TIMEOUT: Duration = Duration::from_secs(5);
 
-pub async fn get_dns_listener(cfg: Config, resolver: ThreadSafeDNSResolver) -> Option<Runner> {
+/// answers a query in wire format, for the ones hijacked from the tun device
+/// or tproxy rather than sent to the DNS listener. None if it's no query.
+pub async fn answer_query(
+    resolver: &ThreadSafeDNSResolver,
+    blocker: Option<&ThreadSafeDnsBlocker>,
+    query: &[u8],
+) -> Option<Vec<u8>> {
+    let req = Message::from_vec(query).ok()?;
+    if req.message_type() != MessageType::Query || req.op_code() != OpCode::Query {
+        return None;
+    }
+    let query = req.query()?.clone();
+    debug!(
+        "got hijacked dns request [{}][{}]",
+        query.query_type(),
+        query.name()
+    );
+
+    let mut m = Message::new();
+    m.set_recursion_desired(req.recursion_desired());
+    m.add_query(query.clone());
+    if let Some(edns) = req.extensions() {
+        m.set_edns(edns.clone());
+    }
+    let mut rv = match resolve(resolver, blocker, m).await {
+        Ok(m) => m,
+        Err(_) => {
+            let mut m = Message::new();
+            m.set_response_code(ResponseCode::ServFail);
+            m
+        }
+    };
+
+    rv.set_id(req.id());
+    rv.set_message_type(MessageType::Response);
+    rv.set_op_code(OpCode::Query);
+    rv.set_recursion_desired(req.recursion_desired());
+    rv.set_recursion_available(true);
+    rv.take_queries();
+    rv.add_query(query);
+    rv.to_vec().ok()
+}
+
+/// answers the length prefixed queries of a DNS over TCP connection until
+/// the client closes it or goes idle
+pub async fn serve_stream<S>(
+    resolver: &ThreadSafeDNSResolver,
+    blocker: Option<&ThreadSafeDnsBlocker>,
+    mut stream: S,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let len = match tokio::time::timeout(DEFAULT_DNS_SERVER_TIMEOUT, stream.read_u16()).await {
+            Ok(Ok(len)) => len,
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Ok(()),
+        };
+        let mut query = vec![0u8; len as usize];
+        stream.read_exact(&mut query).await?;
+
+        let answer = match answer_query(resolver, blocker, &query).await {
+            Some(answer) => answer,
+            None => return Ok(()),
+        };
+        let mut buf = Vec::with_capacity(2 + answer.len());
+        buf.extend_from_slice(&(answer.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&answer);
+        stream.write_all(&buf).await?;
+    }
+}
+
+pub async fn get_dns_listener(
+    cfg: Config,
+    resolver: ThreadSafeDNSResolver,
+    blocker: Option<ThreadSafeDnsBlocker>,
+) -> Option<Runner> {
     if !cfg.enable {
         return None;
     }
 
-    let h = DnsHandler { resolver };
+    let h = DnsHandler { resolver, blocker };
     let mut s = ServerFuture::new(h);
 
     if let Some(addr) = cfg.listen.udp {
@@ -163,6 +315,7 @@ pub async fn get_dns_listener(cfg: Config, resolver: ThreadSafeDNSResolver) -> O
                 s.register_socket(x);
                 Ok(())
             })
+            .map_err(|x| warn!("failed to start dns server on udp {}: {}", addr, x))
             .ok()?;
     }
     if let Some(addr) = cfg.listen.tcp {
@@ -173,6 +326,7 @@ pub async fn get_dns_listener(cfg: Config, resolver: ThreadSafeDNSResolver) -> O
                 s.register_listener(x, DEFAULT_DNS_SERVER_TIMEOUT);
                 Ok(())
             })
+            .map_err(|x| warn!("failed to start dns server on tcp {}: {}", addr, x))
             .ok()?;
     }
     if let Some(c) = cfg.listen.doh {
@@ -188,6 +342,7 @@ pub async fn get_dns_listener(cfg: Config, resolver: ThreadSafeDNSResolver) -> O
                 )?;
                 Ok(())
             })
+            .map_err(|x| warn!("failed to start dns server on doh {}: {}", c.0, x))
             .ok()?;
     }
     if let Some(c) = cfg.listen.dot {
@@ -198,6 +353,7 @@ pub async fn get_dns_listener(cfg: Config, resolver: ThreadSafeDNSResolver) -> O
                 s.register_tls_listener(x, DEFAULT_DNS_SERVER_TIMEOUT, c.1.certificate_and_key)?;
                 Ok(())
             })
+            .map_err(|x| warn!("failed to start dns server on dot {}: {}", c.0, x))
             .ok()?;
     }
 
@@ -0,0 +1,268 @@
+//! A self-test run once the listeners are up: are they bound, do the
+//! nameservers answer, did the providers load, is the geo database there and
+//! are the tun routes installed. Logged once and served at `GET /health`.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use super::{
+    dns::{ResolverKind, ThreadSafeDNSResolver},
+    inbound::manager::ThreadSafeInboundManager,
+    outbound::manager::ThreadSafeOutboundManager,
+    router::ThreadSafeRouter,
+};
+
+/// long enough for the listeners and the tun device to come up
+const SETTLE_DELAY: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    /// milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    fn ok(name: impl Into<String>, detail: Option<String>) -> Self {
+        Self {
+            name: name.into(),
+            ok: true,
+            latency: None,
+            detail,
+        }
+    }
+
+    fn failed(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ok: false,
+            latency: None,
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub listeners: Vec<Check>,
+    pub dns: Vec<Check>,
+    pub providers: Vec<Check>,
+    pub geo: Vec<Check>,
+    pub tun: Vec<Check>,
+}
+
+impl HealthReport {
+    fn checks(&self) -> impl Iterator<Item = (&'static str, &Check)> {
+        [
+            ("listener", &self.listeners),
+            ("dns", &self.dns),
+            ("provider", &self.providers),
+            ("geo", &self.geo),
+            ("tun", &self.tun),
+        ]
+        .into_iter()
+        .flat_map(|(kind, checks)| checks.iter().map(move |x| (kind, x)))
+    }
+}
+
+/// None until the startup checks are done
+pub type ThreadSafeHealthReport = Arc<RwLock<Option<HealthReport>>>;
+
+pub struct HealthChecker {
+    pub inbound_manager: ThreadSafeInboundManager,
+    pub dns_resolver: ThreadSafeDNSResolver,
+    pub outbound_manager: ThreadSafeOutboundManager,
+    pub router: ThreadSafeRouter,
+    pub mmdb: PathBuf,
+    /// names of the tun devices clash created
+    pub tun_devices: Vec<String>,
+}
+
+impl HealthChecker {
+    /// runs the checks after the settle delay, logs and stores the report
+    pub async fn run(self, report: ThreadSafeHealthReport) {
+        tokio::time::sleep(SETTLE_DELAY).await;
+
+        let rv = HealthReport {
+            healthy: false,
+            listeners: self.check_listeners().await,
+            dns: self.check_dns().await,
+            providers: self.check_providers().await,
+            geo: vec![check_file("mmdb", &self.mmdb)],
+            tun: self
+                .tun_devices
+                .iter()
+                .map(|x| check_tun_routes(x))
+                .collect(),
+        };
+        let healthy = rv.checks().all(|(_, x)| x.ok);
+        let rv = HealthReport { healthy, ..rv };
+
+        let failed = rv.checks().filter(|(_, x)| !x.ok).count();
+        info!(
+            "health report: {} checks, {} failed",
+            rv.checks().count(),
+            failed
+        );
+        for (kind, check) in rv.checks().filter(|(_, x)| !x.ok) {
+            warn!(
+                "health check failed: {} {}: {}",
+                kind,
+                check.name,
+                check.detail.as_deref().unwrap_or_default()
+            );
+        }
+
+        report.write().await.replace(rv);
+    }
+
+    async fn check_listeners(&self) -> Vec<Check> {
+        let addrs = self.inbound_manager.lock().await.get_listen_addrs();
+        addrs
+            .into_iter()
+            .map(|(name, addr)| match addr {
+                Some(addr) => match is_bound(addr) {
+                    Ok(true) => Check::ok(name, Some(addr.to_string())),
+                    Ok(false) => Check::failed(name, format!("nothing listening on {}", addr)),
+                    Err(e) => Check::failed(name, format!("{}: {}", addr, e)),
+                },
+                None => Check::ok(name, Some("bound to an interface, not checked".to_owned())),
+            })
+            .collect()
+    }
+
+    async fn check_dns(&self) -> Vec<Check> {
+        if matches!(self.dns_resolver.kind(), ResolverKind::System) {
+            return vec![Check::ok("system", Some("not checked".to_owned()))];
+        }
+        self.dns_resolver
+            .probe_nameservers()
+            .await
+            .into_iter()
+            .map(|(name, rv)| match rv {
+                Ok(latency) => Check {
+                    latency: Some(latency.as_millis()),
+                    ..Check::ok(name, None)
+                },
+                Err(e) => Check::failed(name, e),
+            })
+            .collect()
+    }
+
+    async fn check_providers(&self) -> Vec<Check> {
+        let mut checks = vec![];
+        for (name, provider) in self.outbound_manager.get_proxy_providers() {
+            let count = provider.read().await.proxies().await.len();
+            checks.push(if count > 0 {
+                Check::ok(name, Some(format!("{} proxies", count)))
+            } else {
+                Check::failed(name, "no proxies")
+            });
+        }
+        for (name, provider) in self.router.get_rule_providers() {
+            checks.push(match provider.updated_at().await {
+                Some(at) if at.timestamp() == 0 => Check::failed(name, "never loaded"),
+                Some(at) => Check::ok(name, Some(format!("updated at {}", at))),
+                None => Check::ok(name, None),
+            });
+        }
+        checks
+    }
+}
+
+/// whether something listens on `addr`, by failing to bind it. Connecting
+/// would loop through the redir and tproxy listeners
+fn is_bound(addr: SocketAddr) -> std::io::Result<bool> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        None,
+    )?;
+    match socket.bind(&addr.into()) {
+        Ok(_) => Ok(false),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+fn check_file(name: &str, path: &Path) -> Check {
+    match std::fs::metadata(path) {
+        Ok(m) if m.len() > 0 => Check::ok(name, Some(format!("{} bytes", m.len()))),
+        Ok(_) => Check::failed(name, format!("{} is empty", path.display())),
+        Err(e) => Check::failed(name, format!("{}: {}", path.display(), e)),
+    }
+}
+
+/// the lines of a routing table that mention `iface`, which works for
+/// `/proc/net/route`, `/proc/net/ipv6_route` and `netstat -rn` alike
+fn count_routes(table: &str, iface: &str) -> usize {
+    table
+        .lines()
+        .filter(|x| x.split_whitespace().any(|x| x == iface))
+        .count()
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn routing_tables() -> std::io::Result<String> {
+    let mut tables = std::fs::read_to_string("/proc/net/route")?;
+    // no ipv6 is fine
+    tables += &std::fs::read_to_string("/proc/net/ipv6_route").unwrap_or_default();
+    Ok(tables)
+}
+
+#[cfg(target_os = "macos")]
+fn routing_tables() -> std::io::Result<String> {
+    let output = std::process::Command::new("netstat").arg("-rn").output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn routing_tables() -> std::io::Result<String> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "not checked on this platform",
+    ))
+}
+
+fn check_tun_routes(iface: &str) -> Check {
+    match routing_tables() {
+        Ok(tables) => match count_routes(&tables, iface) {
+            0 => Check::failed(iface, "no routes through the device"),
+            n => Check::ok(iface, Some(format!("{} routes", n))),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+            Check::ok(iface, Some(e.to_string()))
+        }
+        Err(e) => Check::failed(iface, e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::count_routes;
+
+    #[test]
+    fn test_count_routes() {
+        let route = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                     eth0\t00000000\t0100A8C0\t0003\t0\t0\t100\t00000000\n\
+                     utun886\t000012C6\t00000000\t0001\t0\t0\t0\t0000FEFF\n";
+        let ipv6_route = "fdfedcba987600000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000400 00000001 00000000 00000001  utun886\n";
+        let tables = format!("{}{}", route, ipv6_route);
+        assert_eq!(count_routes(&tables, "utun886"), 2);
+        assert_eq!(count_routes(&tables, "eth0"), 1);
+        assert_eq!(count_routes(&tables, "utun887"), 0);
+    }
+}
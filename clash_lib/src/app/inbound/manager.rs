@@ -1,12 +1,17 @@
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 
 use crate::app::dispatcher::Dispatcher;
+use crate::app::dns::ThreadSafeDNSResolver;
 use crate::app::inbound::network_listener::{ListenerType, NetworkInboundListener};
-use crate::common::auth::ThreadSafeAuthenticator;
+use crate::common::auth::{self, ThreadSafeAuthenticator};
 use crate::config::internal::config::{BindAddress, Inbound};
+use crate::config::internal::listener::InboundOpts;
+use crate::proxy::tun::get_tun_runner;
+use crate::proxy::utils::{ConnectionLimiter, Interface};
 use crate::{Error, Runner};
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 
 pub struct InboundManager {
@@ -14,6 +19,10 @@ pub struct InboundManager {
     dispatcher: Arc<Dispatcher>,
     bind_address: BindAddress,
     authenticator: ThreadSafeAuthenticator,
+    /// shared by all listeners, so the limit applies to the total number of connections
+    limiter: Option<ConnectionLimiter>,
+    /// `listeners`, kept across port changes
+    listeners: Vec<InboundOpts>,
 }
 
 pub type ThreadSafeInboundManager = Arc<Mutex<InboundManager>>;
@@ -44,6 +53,8 @@ impl InboundManager {
             dispatcher,
             bind_address: inbound.bind_address,
             authenticator,
+            limiter: inbound.max_connections.map(|x| Arc::new(Semaphore::new(x))),
+            listeners: inbound.listeners,
         };
 
         let ports = Ports {
@@ -69,7 +80,44 @@ impl InboundManager {
         }))
     }
 
+    /// the tun devices of `listeners`, which unlike the ports are not
+    /// rebuilt when the ports change
+    pub fn get_tun_runners(&self, resolver: ThreadSafeDNSResolver) -> Result<Vec<Runner>, Error> {
+        let mut runners = Vec::new();
+        for opts in self.listeners.iter() {
+            if let InboundOpts::Tun(tun) = opts {
+                runners.extend(get_tun_runner(
+                    tun.into(),
+                    tun.name.clone(),
+                    self.dispatcher.clone(),
+                    resolver.clone(),
+                )?);
+            }
+        }
+        Ok(runners)
+    }
+
+    /// the address each listener accepts connections on, None for the ones
+    /// bound to an interface by name
+    pub fn get_listen_addrs(&self) -> Vec<(String, Option<SocketAddr>)> {
+        self.network_listeners
+            .values()
+            .map(|x| {
+                let ip = match &x.bind_addr {
+                    BindAddress::Any => Some(IpAddr::from([0, 0, 0, 0])),
+                    BindAddress::One(Interface::IpAddr(ip)) => Some(*ip),
+                    BindAddress::One(Interface::Name(_)) => None,
+                };
+                (x.name.clone(), ip.map(|ip| SocketAddr::new(ip, x.port)))
+            })
+            .collect()
+    }
+
     /// API handlers below
+    pub fn get_listeners(&self) -> &[InboundOpts] {
+        &self.listeners
+    }
+
     pub fn get_bind_address(&self) -> &BindAddress {
         &self.bind_address
     }
@@ -98,6 +146,14 @@ impl InboundManager {
                 ListenerType::Mixed => {
                     ports.mixed_port = Some(x.port);
                 }
+                ListenerType::Redir => {
+                    ports.redir_port = Some(x.port);
+                }
+                ListenerType::Tproxy => {
+                    ports.tproxy_port = Some(x.port);
+                }
+                // the `listeners` entries have no port setting of their own
+                _ => {}
             });
 
         ports
@@ -115,6 +171,8 @@ impl InboundManager {
                     listener_type: ListenerType::HTTP,
                     dispatcher: self.dispatcher.clone(),
                     authenticator: self.authenticator.clone(),
+                    limiter: self.limiter.clone(),
+                    allowlist: None,
                 },
             );
         }
@@ -129,6 +187,8 @@ impl InboundManager {
                     listener_type: ListenerType::SOCKS5,
                     dispatcher: self.dispatcher.clone(),
                     authenticator: self.authenticator.clone(),
+                    limiter: self.limiter.clone(),
+                    allowlist: None,
                 },
             );
         }
@@ -143,6 +203,87 @@ impl InboundManager {
                     listener_type: ListenerType::Mixed,
                     dispatcher: self.dispatcher.clone(),
                     authenticator: self.authenticator.clone(),
+                    limiter: self.limiter.clone(),
+                    allowlist: None,
+                },
+            );
+        }
+
+        if let Some(redir_port) = ports.redir_port {
+            network_listeners.insert(
+                ListenerType::Redir,
+                NetworkInboundListener {
+                    name: "Redir".to_string(),
+                    bind_addr: self.bind_address.clone(),
+                    port: redir_port,
+                    listener_type: ListenerType::Redir,
+                    dispatcher: self.dispatcher.clone(),
+                    authenticator: self.authenticator.clone(),
+                    limiter: self.limiter.clone(),
+                    allowlist: None,
+                },
+            );
+        }
+
+        if let Some(tproxy_port) = ports.tproxy_port {
+            network_listeners.insert(
+                ListenerType::Tproxy,
+                NetworkInboundListener {
+                    name: "TProxy".to_string(),
+                    bind_addr: self.bind_address.clone(),
+                    port: tproxy_port,
+                    listener_type: ListenerType::Tproxy,
+                    dispatcher: self.dispatcher.clone(),
+                    authenticator: self.authenticator.clone(),
+                    limiter: self.limiter.clone(),
+                    allowlist: None,
+                },
+            );
+        }
+
+        for opts in self.listeners.iter() {
+            // tun devices are set up once, see `get_tun_runners`
+            let port = match opts.port() {
+                Some(port) => port,
+                None => continue,
+            };
+            let authenticator: ThreadSafeAuthenticator = match opts {
+                InboundOpts::Http(x) | InboundOpts::Socks(x) | InboundOpts::Mixed(x) => {
+                    match x.users {
+                        Some(ref users) => Arc::new(auth::PlainAuthenticator::new(
+                            users
+                                .iter()
+                                .map(|u| {
+                                    auth::User::new(
+                                        u.username.clone(),
+                                        u.password.expose().to_owned(),
+                                    )
+                                })
+                                .collect(),
+                        )),
+                        None => self.authenticator.clone(),
+                    }
+                }
+                _ => self.authenticator.clone(),
+            };
+            network_listeners.insert(
+                ListenerType::Named(opts.clone()),
+                NetworkInboundListener {
+                    name: opts.name().to_string(),
+                    bind_addr: match opts.listen() {
+                        Some(listen) => listen.parse().unwrap_or_default(),
+                        None => self.bind_address.clone(),
+                    },
+                    port,
+                    listener_type: ListenerType::Named(opts.clone()),
+                    dispatcher: self.dispatcher.clone(),
+                    authenticator,
+                    limiter: self.limiter.clone(),
+                    // checked when the config was loaded
+                    allowlist: opts
+                        .access()
+                        .and_then(|x| x.allowlist().ok().flatten())
+                        .map(Arc::new),
                 },
             );
         }
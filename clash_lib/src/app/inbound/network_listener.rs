@@ -1,9 +1,14 @@
-use crate::common::auth::ThreadSafeAuthenticator;
+use crate::common::auth::{ThreadSafeAllowList, ThreadSafeAuthenticator};
+use crate::config::def::RunMode;
 use crate::config::internal::config::BindAddress;
 
-use crate::proxy::{http, mixed, socks, AnyInboundListener};
+use crate::config::internal::listener::InboundOpts;
+use crate::proxy::{
+    http, mixed, redir, shadowsocks, socks, tproxy, trojan, vmess, AnyInboundListener,
+};
 
-use crate::proxy::utils::Interface;
+use crate::proxy::utils::{ConnectionLimiter, Interface};
+use crate::session::Inbound;
 use crate::{Dispatcher, Error, Runner};
 use futures::FutureExt;
 use network_interface::{Addr, NetworkInterfaceConfig};
@@ -12,11 +17,15 @@ use tracing::{info, warn};
 use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
 
-#[derive(Eq, PartialEq, Hash)]
+#[derive(Eq, PartialEq, Hash, Clone)]
 pub enum ListenerType {
     HTTP,
     SOCKS5,
     Mixed,
+    Redir,
+    Tproxy,
+    /// an entry of `listeners`
+    Named(InboundOpts),
 }
 
 pub struct NetworkInboundListener {
@@ -26,6 +35,8 @@ pub struct NetworkInboundListener {
     pub listener_type: ListenerType,
     pub dispatcher: Arc<Dispatcher>,
     pub authenticator: ThreadSafeAuthenticator,
+    pub limiter: Option<ConnectionLimiter>,
+    pub allowlist: Option<ThreadSafeAllowList>,
 }
 
 impl NetworkInboundListener {
@@ -95,23 +106,117 @@ impl NetworkInboundListener {
         Ok(runners)
     }
 
+    /// `mode` being the one the listener forces
+    fn inbound(&self, mode: Option<RunMode>) -> Inbound {
+        Inbound {
+            name: self.name.clone(),
+            port: Some(self.port),
+            mode,
+        }
+    }
+
     fn build_and_insert_listener(&self, runners: &mut Vec<Runner>, ip: Ipv4Addr) {
         let listener: AnyInboundListener = match self.listener_type {
             ListenerType::HTTP => http::Listener::new(
                 (ip, self.port).into(),
                 self.dispatcher.clone(),
                 self.authenticator.clone(),
+                self.limiter.clone(),
+                self.allowlist.clone(),
+                self.inbound(None),
             ),
             ListenerType::SOCKS5 => socks::Listener::new(
                 (ip, self.port).into(),
                 self.dispatcher.clone(),
                 self.authenticator.clone(),
+                self.limiter.clone(),
+                self.allowlist.clone(),
+                self.inbound(None),
             ),
             ListenerType::Mixed => mixed::Listener::new(
                 (ip, self.port).into(),
                 self.dispatcher.clone(),
                 self.authenticator.clone(),
+                self.limiter.clone(),
+                self.allowlist.clone(),
+                self.inbound(None),
+            ),
+            ListenerType::Redir => redir::Listener::new(
+                (ip, self.port).into(),
+                self.dispatcher.clone(),
+                self.limiter.clone(),
+                self.allowlist.clone(),
+                self.inbound(None),
             ),
+            ListenerType::Tproxy => tproxy::Listener::new(
+                (ip, self.port).into(),
+                self.dispatcher.clone(),
+                self.limiter.clone(),
+                self.allowlist.clone(),
+                self.inbound(None),
+            ),
+            ListenerType::Named(ref opts) => match opts {
+                InboundOpts::Http(_) => http::Listener::new(
+                    (ip, self.port).into(),
+                    self.dispatcher.clone(),
+                    self.authenticator.clone(),
+                    self.limiter.clone(),
+                    self.allowlist.clone(),
+                    self.inbound(opts.mode()),
+                ),
+                InboundOpts::Socks(_) => socks::Listener::new(
+                    (ip, self.port).into(),
+                    self.dispatcher.clone(),
+                    self.authenticator.clone(),
+                    self.limiter.clone(),
+                    self.allowlist.clone(),
+                    self.inbound(opts.mode()),
+                ),
+                InboundOpts::Mixed(_) => mixed::Listener::new(
+                    (ip, self.port).into(),
+                    self.dispatcher.clone(),
+                    self.authenticator.clone(),
+                    self.limiter.clone(),
+                    self.allowlist.clone(),
+                    self.inbound(opts.mode()),
+                ),
+                InboundOpts::Redir(_) => redir::Listener::new(
+                    (ip, self.port).into(),
+                    self.dispatcher.clone(),
+                    self.limiter.clone(),
+                    self.allowlist.clone(),
+                    self.inbound(opts.mode()),
+                ),
+                InboundOpts::Tproxy(_) => tproxy::Listener::new(
+                    (ip, self.port).into(),
+                    self.dispatcher.clone(),
+                    self.limiter.clone(),
+                    self.allowlist.clone(),
+                    self.inbound(opts.mode()),
+                ),
+                InboundOpts::Shadowsocks(opts) => shadowsocks::Listener::new(
+                    (ip, self.port).into(),
+                    opts,
+                    self.dispatcher.clone(),
+                    self.limiter.clone(),
+                    self.allowlist.clone(),
+                ),
+                InboundOpts::Vmess(opts) => vmess::Listener::new(
+                    (ip, self.port).into(),
+                    opts,
+                    self.dispatcher.clone(),
+                    self.limiter.clone(),
+                    self.allowlist.clone(),
+                ),
+                InboundOpts::Trojan(opts) => trojan::Listener::new(
+                    (ip, self.port).into(),
+                    opts,
+                    self.dispatcher.clone(),
+                    self.limiter.clone(),
+                    self.allowlist.clone(),
+                ),
+                InboundOpts::Tun(_) => unreachable!("tun listeners have no port"),
+            },
         };
 
         if listener.handle_tcp() {
@@ -0,0 +1,160 @@
+use std::{
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose,
+};
+use rustls::{PrivateKey, ServerConfig};
+use tracing::info;
+
+use crate::Error;
+
+/// minted certificates kept around
+const CERT_CACHE_SIZE: usize = 256;
+
+/// the approximate current year, minted certificates are valid around it
+/// as clients reject leaf certificates valid for much longer than 2 years
+fn current_year() -> i32 {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    1970 + (secs / 31_556_952) as i32
+}
+
+fn map_rcgen_error(e: rcgen::RcgenError) -> Error {
+    Error::InvalidConfig(format!("mitm certificate error: {}", e))
+}
+
+/// The local CA signing a certificate for every intercepted host.
+pub struct CertificateAuthority {
+    ca: Certificate,
+    ca_der: Vec<u8>,
+    cache: Mutex<lru_time_cache::LruCache<String, Arc<ServerConfig>>>,
+}
+
+impl CertificateAuthority {
+    /// loads the CA from `cert_path` and `key_path`, generating and saving
+    /// a new one if neither exists
+    pub fn load_or_generate(cert_path: &Path, key_path: &Path) -> Result<Self, Error> {
+        let ca = if cert_path.exists() || key_path.exists() {
+            let cert_pem = std::fs::read_to_string(cert_path)?;
+            let key_pem = std::fs::read_to_string(key_path)?;
+            let key = KeyPair::from_pem(&key_pem).map_err(map_rcgen_error)?;
+            let params =
+                CertificateParams::from_ca_cert_pem(&cert_pem, key).map_err(map_rcgen_error)?;
+            Certificate::from_params(params).map_err(map_rcgen_error)?
+        } else {
+            let mut params = CertificateParams::default();
+            let mut name = DistinguishedName::new();
+            name.push(DnType::CommonName, "clash-rs MITM CA");
+            name.push(DnType::OrganizationName, "clash-rs");
+            params.distinguished_name = name;
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.key_usages = vec![
+                KeyUsagePurpose::KeyCertSign,
+                KeyUsagePurpose::CrlSign,
+                KeyUsagePurpose::DigitalSignature,
+            ];
+            let ca = Certificate::from_params(params).map_err(map_rcgen_error)?;
+
+            std::fs::write(cert_path, ca.serialize_pem().map_err(map_rcgen_error)?)?;
+            // only readable by us from the start, not after it's written
+            let mut key_file = std::fs::OpenOptions::new();
+            key_file.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                key_file.mode(0o600);
+            }
+            key_file
+                .open(key_path)?
+                .write_all(ca.serialize_private_key_pem().as_bytes())?;
+            info!(
+                "generated mitm CA at {}, install and trust it on the clients",
+                cert_path.display()
+            );
+            ca
+        };
+
+        // re-signed by itself, same key and subject as the one on disk
+        let ca_der = ca.serialize_der().map_err(map_rcgen_error)?;
+
+        Ok(Self {
+            ca,
+            ca_der,
+            cache: Mutex::new(lru_time_cache::LruCache::with_capacity(CERT_CACHE_SIZE)),
+        })
+    }
+
+    /// the TLS server config presenting a certificate for `host`
+    pub fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>, Error> {
+        if let Some(config) = self.cache.lock().unwrap().get(host) {
+            return Ok(config.clone());
+        }
+
+        let mut params = CertificateParams::new(vec![host.to_owned()]);
+        params.distinguished_name.push(DnType::CommonName, host);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let year = current_year();
+        params.not_before = rcgen::date_time_ymd(year - 1, 12, 1);
+        params.not_after = rcgen::date_time_ymd(year + 1, 12, 1);
+        let cert = Certificate::from_params(params).map_err(map_rcgen_error)?;
+        let cert_der = cert
+            .serialize_der_with_signer(&self.ca)
+            .map_err(map_rcgen_error)?;
+
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![
+                    rustls::Certificate(cert_der),
+                    rustls::Certificate(self.ca_der.clone()),
+                ],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .map_err(|e| Error::InvalidConfig(format!("mitm certificate error: {}", e)))?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let config = Arc::new(config);
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(host.to_owned(), config.clone());
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CertificateAuthority;
+
+    #[test]
+    fn test_generate_and_reload_ca() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("ca.crt");
+        let key = dir.path().join("ca.key");
+
+        let ca = CertificateAuthority::load_or_generate(&cert, &key).unwrap();
+        assert!(cert.exists() && key.exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&key).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let config = ca.server_config("example.com").unwrap();
+        assert!(std::sync::Arc::ptr_eq(
+            &config,
+            &ca.server_config("example.com").unwrap()
+        ));
+
+        let reloaded = CertificateAuthority::load_or_generate(&cert, &key).unwrap();
+        assert!(reloaded.server_config("example.com").is_ok());
+    }
+}
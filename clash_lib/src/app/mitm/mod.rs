@@ -0,0 +1,267 @@
+//! `mitm`: terminates TLS to selected hosts with certificates minted by a
+//! local CA, so that their HTTP requests can be rewritten. The hosts are
+//! listed, or the ones of rule providers.
+//! WebSocket upgrades are not supported through interception.
+
+mod ca;
+mod rewrite;
+
+use std::{collections::HashMap, io, path::Path, sync::Arc};
+
+use http::{header, Request, Response, StatusCode};
+use hyper::{
+    client::conn::{handshake, SendRequest},
+    server::conn::Http,
+    service::service_fn,
+    Body,
+};
+use rustls::{ClientConfig, ServerName};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Mutex,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::{debug, warn};
+
+use crate::{
+    app::remote_content_manager::providers::rule_provider::ThreadSafeRuleProvider,
+    common::{errors::map_io_error, tls::GLOBAL_ROOT_STORE, trie},
+    config::def,
+    session::{Session, SocksAddr},
+    Error,
+};
+
+use self::{
+    ca::CertificateAuthority,
+    rewrite::{Action, Rewrite},
+};
+
+/// buffer between the local connection and the intercepting server
+const BRIDGE_BUFFER_SIZE: usize = 16 * 1024;
+
+pub struct Mitm {
+    ca: CertificateAuthority,
+    hosts: trie::StringTrie<bool>,
+    /// the `rule-set:<provider>` entries of the hosts
+    rule_sets: Vec<ThreadSafeRuleProvider>,
+    rewrites: Arc<Vec<Rewrite>>,
+    client_config: Arc<ClientConfig>,
+}
+
+impl Mitm {
+    pub fn new(
+        cfg: def::Mitm,
+        cwd: &str,
+        providers: &HashMap<String, ThreadSafeRuleProvider>,
+    ) -> Result<Self, Error> {
+        let cwd = Path::new(cwd);
+        let ca = CertificateAuthority::load_or_generate(
+            &cwd.join(&cfg.ca_cert),
+            &cwd.join(&cfg.ca_key),
+        )?;
+
+        let mut hosts = trie::StringTrie::new();
+        let mut rule_sets = vec![];
+        for host in cfg.hosts.iter() {
+            if let Some(name) = host.strip_prefix("rule-set:") {
+                rule_sets.push(providers.get(name).cloned().ok_or_else(|| {
+                    Error::InvalidConfig(format!("mitm: unknown rule provider {}", name))
+                })?);
+            } else if !hosts.insert(host, Arc::new(true)) {
+                return Err(Error::InvalidConfig(format!("invalid mitm host: {}", host)));
+            }
+        }
+
+        let rewrites = cfg
+            .rewrites
+            .iter()
+            .map(|x| x.parse::<Rewrite>())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(GLOBAL_ROOT_STORE.clone())
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Self {
+            ca,
+            hosts,
+            rule_sets,
+            rewrites: Arc::new(rewrites),
+            client_config: Arc::new(client_config),
+        })
+    }
+
+    /// whether the session goes to an intercepted host over HTTP(S)
+    pub fn matches(&self, sess: &Session) -> bool {
+        match &sess.destination {
+            SocksAddr::Domain(host, port) => {
+                (*port == 80 || *port == 443)
+                    && (self.hosts.search(host).is_some()
+                        || self.rule_sets.iter().any(|x| x.search(sess)))
+            }
+            SocksAddr::Ip(_) => false,
+        }
+    }
+
+    /// serves the HTTP(S) requests from `lhs`, forwarding them to `rhs`
+    /// after the rewrites
+    pub async fn intercept<S, R>(&self, sess: &Session, lhs: &mut S, rhs: R) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+        R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (host, tls) = match &sess.destination {
+            SocksAddr::Domain(host, port) => (host.clone(), *port == 443),
+            SocksAddr::Ip(_) => unreachable!("only domain sessions are intercepted"),
+        };
+
+        // hyper needs an owned connection, so bridge the borrowed one
+        let (local, mut bridge) = tokio::io::duplex(BRIDGE_BUFFER_SIZE);
+        let serve = async move {
+            if tls {
+                let server_name = ServerName::try_from(host.as_str()).map_err(map_io_error)?;
+                let rhs = TlsConnector::from(self.client_config.clone())
+                    .connect(server_name, rhs)
+                    .await?;
+                let sender = connect_upstream(rhs).await?;
+
+                let server_config = self.ca.server_config(&host).map_err(map_io_error)?;
+                let local = TlsAcceptor::from(server_config).accept(local).await?;
+                self.serve(local, "https", host, sender).await
+            } else {
+                let sender = connect_upstream(rhs).await?;
+                self.serve(local, "http", host, sender).await
+            }
+        };
+
+        let (copied, served) = tokio::join!(tokio::io::copy_bidirectional(lhs, &mut bridge), serve);
+        served?;
+        copied.map(|_| ())
+    }
+
+    async fn serve<T>(
+        &self,
+        io: T,
+        scheme: &'static str,
+        host: String,
+        upstream: SendRequest<Body>,
+    ) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let upstream = Arc::new(Mutex::new(upstream));
+        let host = Arc::new(host);
+        let rewrites = self.rewrites.clone();
+        let service = service_fn(move |req| {
+            let upstream = upstream.clone();
+            let host = host.clone();
+            let rewrites = rewrites.clone();
+            async move {
+                let res = handle(req, scheme, &host, &rewrites, &upstream).await;
+                Ok::<_, hyper::Error>(res)
+            }
+        });
+
+        Http::new()
+            .http1_only(true)
+            .http1_keep_alive(true)
+            .serve_connection(io, service)
+            .await
+            .map_err(map_io_error)
+    }
+}
+
+async fn connect_upstream<T>(io: T) -> io::Result<SendRequest<Body>>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, conn) = handshake(io).await.map_err(map_io_error)?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            debug!("mitm upstream connection closed with error: {}", e);
+        }
+    });
+    Ok(sender)
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = status;
+    res
+}
+
+async fn handle(
+    mut req: Request<Body>,
+    scheme: &str,
+    host: &str,
+    rewrites: &[Rewrite],
+    upstream: &Mutex<SendRequest<Body>>,
+) -> Response<Body> {
+    let authority = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or(host);
+    let url = format!(
+        "{}://{}{}",
+        scheme,
+        authority,
+        req.uri()
+            .path_and_query()
+            .map(|x| x.as_str())
+            .unwrap_or("/")
+    );
+
+    let matched = rewrites
+        .iter()
+        .filter(|x| x.pattern.is_match(&url))
+        .collect::<Vec<_>>();
+    for r in matched.iter() {
+        match &r.action {
+            Action::Reject => {
+                debug!("mitm rejected {}", url);
+                return empty_response(StatusCode::NOT_FOUND);
+            }
+            Action::Redirect(..) => {
+                if let Some((code, to)) = r.redirect_to(&url) {
+                    debug!("mitm redirected {} to {}", url, to);
+                    return Response::builder()
+                        .status(code)
+                        .header(header::LOCATION, to)
+                        .body(Body::empty())
+                        .unwrap_or_else(|_| empty_response(StatusCode::BAD_GATEWAY));
+                }
+            }
+            Action::RequestHeader(op) => op.apply(req.headers_mut()),
+            Action::ResponseHeader(_) => {}
+        }
+    }
+
+    let res = {
+        let mut upstream = upstream.lock().await;
+        match futures::future::poll_fn(|cx| upstream.poll_ready(cx)).await {
+            Ok(_) => upstream.send_request(req),
+            Err(e) => {
+                warn!("mitm upstream for {} is gone: {}", url, e);
+                return empty_response(StatusCode::BAD_GATEWAY);
+            }
+        }
+    };
+
+    match res.await {
+        Ok(mut res) => {
+            for r in matched.iter() {
+                if let Action::ResponseHeader(op) = &r.action {
+                    op.apply(res.headers_mut());
+                }
+            }
+            res
+        }
+        Err(e) => {
+            warn!("mitm failed to forward {}: {}", url, e);
+            empty_response(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use http::{header::HeaderName, HeaderMap, HeaderValue};
+use regex::Regex;
+
+use crate::Error;
+
+#[derive(Debug)]
+pub enum Action {
+    /// answers 404 without going upstream
+    Reject,
+    /// redirects to the url, `$1` etc. are replaced with the captures
+    Redirect(u16, String),
+    RequestHeader(HeaderOp),
+    ResponseHeader(HeaderOp),
+}
+
+#[derive(Debug)]
+pub enum HeaderOp {
+    Add(HeaderName, HeaderValue),
+    Del(HeaderName),
+    Replace(HeaderName, HeaderValue),
+}
+
+impl HeaderOp {
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        match self {
+            HeaderOp::Add(name, value) => {
+                headers.append(name, value.clone());
+            }
+            HeaderOp::Del(name) => {
+                headers.remove(name);
+            }
+            HeaderOp::Replace(name, value) => {
+                if headers.contains_key(name) {
+                    headers.insert(name, value.clone());
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Rewrite {
+    pub pattern: Regex,
+    pub action: Action,
+}
+
+impl Rewrite {
+    /// the redirect target for `url`, if this is a redirect rule
+    pub fn redirect_to(&self, url: &str) -> Option<(u16, String)> {
+        match &self.action {
+            Action::Redirect(code, to) => {
+                Some((*code, self.pattern.replace(url, to.as_str()).into_owned()))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn parse_header_op(op: &str, args: &[&str]) -> Result<HeaderOp, String> {
+    let name = args
+        .first()
+        .ok_or("missing header name")?
+        .parse::<HeaderName>()
+        .map_err(|e| e.to_string())?;
+    let value = || -> Result<HeaderValue, String> {
+        if args.len() < 2 {
+            return Err("missing header value".to_owned());
+        }
+        args[1..]
+            .join(" ")
+            .parse::<HeaderValue>()
+            .map_err(|e| e.to_string())
+    };
+    match op {
+        "add" => Ok(HeaderOp::Add(name, value()?)),
+        "del" => Ok(HeaderOp::Del(name)),
+        "replace" => Ok(HeaderOp::Replace(name, value()?)),
+        _ => Err(format!("unknown header operation {}", op)),
+    }
+}
+
+impl std::str::FromStr for Rewrite {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            |reason: String| Error::InvalidConfig(format!("invalid rewrite {}: {}", s, reason));
+
+        let parts = s.split_whitespace().collect::<Vec<_>>();
+        if parts.len() < 2 {
+            return Err(invalid("expected `<url regex> <action> [args]`".to_owned()));
+        }
+
+        let pattern = Regex::new(parts[0]).map_err(|e| invalid(e.to_string()))?;
+        let args = &parts[2..];
+        let action = match parts[1] {
+            "reject" => Action::Reject,
+            "302" | "307" => {
+                let to = args
+                    .first()
+                    .ok_or_else(|| invalid("missing redirect url".to_owned()))?;
+                Action::Redirect(parts[1].parse().unwrap(), to.to_string())
+            }
+            action => {
+                if let Some(op) = action.strip_prefix("response-header-") {
+                    Action::ResponseHeader(parse_header_op(op, args).map_err(invalid)?)
+                } else if let Some(op) = action.strip_prefix("header-") {
+                    Action::RequestHeader(parse_header_op(op, args).map_err(invalid)?)
+                } else {
+                    return Err(invalid(format!("unknown action {}", action)));
+                }
+            }
+        };
+
+        Ok(Self { pattern, action })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderMap;
+
+    use super::{Action, Rewrite};
+
+    #[test]
+    fn test_parse_rewrites() {
+        let r = "^https?://ad\\.example\\.com/ reject"
+            .parse::<Rewrite>()
+            .unwrap();
+        assert!(matches!(r.action, Action::Reject));
+        assert!(r.pattern.is_match("https://ad.example.com/banner.js"));
+
+        let r = "^http://example\\.com/(.*) 302 https://example.com/$1"
+            .parse::<Rewrite>()
+            .unwrap();
+        assert_eq!(
+            r.redirect_to("http://example.com/a?b=c"),
+            Some((302, "https://example.com/a?b=c".to_owned()))
+        );
+
+        let r = "^https://example\\.com/ header-add X-Debug on and off"
+            .parse::<Rewrite>()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        match r.action {
+            Action::RequestHeader(op) => op.apply(&mut headers),
+            _ => panic!("expected a request header rewrite"),
+        }
+        assert_eq!(headers.get("x-debug").unwrap(), "on and off");
+
+        let r = "^https://example\\.com/ response-header-del Set-Cookie"
+            .parse::<Rewrite>()
+            .unwrap();
+        assert!(matches!(r.action, Action::ResponseHeader(_)));
+
+        assert!("^https://example\\.com/ header-add X-Debug"
+            .parse::<Rewrite>()
+            .is_err());
+        assert!("^https://example\\.com/ 301 https://a.com"
+            .parse::<Rewrite>()
+            .is_err());
+        assert!("reject".parse::<Rewrite>().is_err());
+    }
+}
@@ -1,9 +1,14 @@
 pub mod api;
+pub mod device;
 pub mod dispatcher;
 pub mod dns;
+pub mod health;
 pub mod inbound;
 pub mod logging;
+#[cfg(feature = "mitm")]
+pub mod mitm;
 pub mod outbound;
 pub mod profile;
 pub mod remote_content_manager;
 pub mod router;
+pub mod updater;
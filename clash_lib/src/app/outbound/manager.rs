@@ -1,27 +1,33 @@
 use anyhow::Result;
 use erased_serde::Serialize;
 use http::Uri;
-use std::collections::HashMap;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::{Mutex, RwLock};
 use tracing::error;
 
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::app::dns::ThreadSafeDNSResolver;
 use crate::app::profile::ThreadSafeCacheFile;
-use crate::app::remote_content_manager::healthcheck::HealthCheck;
+use crate::app::remote_content_manager::healthcheck::{HealthCheck, HealthCheckLimiter};
 use crate::app::remote_content_manager::providers::file_vehicle;
 use crate::app::remote_content_manager::providers::http_vehicle;
+use crate::app::remote_content_manager::unlock::{StreamingService, UnlockResult};
 use crate::app::remote_content_manager::ProxyManager;
 
+use crate::app::remote_content_manager::providers::proxy_provider::CountryProvider;
 use crate::app::remote_content_manager::providers::proxy_provider::PlainProvider;
+use crate::app::remote_content_manager::providers::proxy_provider::ProxyProvider;
 use crate::app::remote_content_manager::providers::proxy_provider::ProxySetProvider;
 use crate::app::remote_content_manager::providers::proxy_provider::ThreadSafeProxyProvider;
+use crate::common::country::{country_of, is_country_code};
 use crate::config::internal::proxy::PROXY_GLOBAL;
-use crate::config::internal::proxy::{OutboundProxyProviderDef, PROXY_DIRECT, PROXY_REJECT};
+use crate::config::internal::proxy::{OutboundProxyProviderDef, RejectMode, PROXY_DIRECT};
+use crate::config::internal::share_link::to_share_link;
 use crate::proxy::fallback;
 use crate::proxy::loadbalance;
 use crate::proxy::selector;
@@ -35,15 +41,81 @@ use crate::{
     Error,
 };
 
-use super::utils::proxy_groups_dag_sort;
+use super::utils::{dialer_loop, proxy_groups_dag_sort};
 
 static RESERVED_PROVIDER_NAME: &str = "default";
 
 pub struct OutboundManager {
-    handlers: HashMap<String, AnyOutboundHandler>,
+    handlers: std::sync::RwLock<HashMap<String, AnyOutboundHandler>>,
     proxy_providers: HashMap<String, ThreadSafeProxyProvider>,
     proxy_manager: ProxyManager,
     selector_control: HashMap<String, ThreadSafeSelectorControl>,
+    runtime: Mutex<RuntimeProxies>,
+    /// of the proxy servers from the config and the API, by name
+    share_links: std::sync::RwLock<HashMap<String, String>>,
+}
+
+/// a group with `include-all`, its provider follows the proxy servers
+struct IncludeAllGroup {
+    name: String,
+    filter: Option<Regex>,
+    /// has `proxies` or `use` besides `include-all`
+    has_other: bool,
+    provider: Arc<RwLock<PlainProvider>>,
+}
+
+impl IncludeAllGroup {
+    fn matches(&self, name: &str) -> bool {
+        self.filter.as_ref().map_or(true, |x| x.is_match(name))
+    }
+}
+
+/// the settings of a group its `include-all` provider is built from
+struct IncludeAllOptions<'a> {
+    name: &'a str,
+    filter: Option<&'a str>,
+    /// has `proxies` or `use` besides `include-all`
+    has_other: bool,
+    interval: u64,
+    lazy: bool,
+    limiter: Option<HealthCheckLimiter>,
+}
+
+/// what's needed to add and remove proxy servers at runtime
+struct RuntimeProxies {
+    /// proxy servers in definition order, runtime added ones last
+    servers: Vec<String>,
+    /// proxy server -> groups listing it in `proxies`
+    referenced_by: HashMap<String, Vec<String>>,
+    /// group -> the proxies and groups it lists in `proxies`, everything
+    /// for GLOBAL
+    listed: HashMap<String, Vec<String>>,
+    include_all: Vec<IncludeAllGroup>,
+    global: Arc<RwLock<PlainProvider>>,
+}
+
+impl RuntimeProxies {
+    /// the outbounds each group may pick, with `added` as the proxy server
+    /// about to be added
+    fn members(&self, added: Option<&str>) -> HashMap<String, Vec<String>> {
+        let mut rv = self.listed.clone();
+        for group in self.include_all.iter() {
+            rv.entry(group.name.clone()).or_default().extend(
+                self.servers
+                    .iter()
+                    .map(String::as_str)
+                    .chain(added)
+                    .filter(|x| group.matches(x))
+                    .map(str::to_owned),
+            );
+        }
+        if let Some(added) = added {
+            rv.entry(PROXY_GLOBAL.to_owned())
+                .or_default()
+                .push(added.to_owned());
+        }
+        rv
+    }
 }
 
 static DEFAULT_LATENCY_TEST_URL: &str = "http://www.gstatic.com/generate_204";
@@ -51,20 +123,32 @@ static DEFAULT_LATENCY_TEST_URL: &str = "http://www.gstatic.com/generate_204";
 pub type ThreadSafeOutboundManager = Arc<OutboundManager>;
 
 impl OutboundManager {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         outbounds: Vec<OutboundProxyProtocol>,
         outbound_groups: Vec<OutboundGroupProtocol>,
         proxy_providers: HashMap<String, OutboundProxyProviderDef>,
+        auto_groups: HashSet<String>,
         proxy_names: Vec<String>,
         dns_resolver: ThreadSafeDNSResolver,
         cache_store: ThreadSafeCacheFile,
+        health_check_limiter: Option<HealthCheckLimiter>,
         cwd: String,
     ) -> Result<Self, Error> {
         let mut handlers = HashMap::new();
         let mut provider_registry = HashMap::new();
         let mut selector_control = HashMap::new();
-        let proxy_manager = ProxyManager::new(dns_resolver.clone());
-
+        let proxy_manager = ProxyManager::new(dns_resolver.clone(), health_check_limiter);
+        let share_links = outbounds
+            .iter()
+            .filter_map(|x| Some((x.name().to_owned(), to_share_link(x).ok()?)))
+            .collect();
+
+        let auto_grouped = proxy_providers
+            .iter()
+            .filter(|(_, p)| p.auto_group_by().is_some())
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
         Self::load_proxy_providers(
             cwd,
             proxy_providers,
@@ -74,7 +158,17 @@ impl OutboundManager {
         )
         .await?;
 
-        Self::load_handlers(
+        Self::load_auto_groups(
+            auto_grouped,
+            &auto_groups,
+            &proxy_names,
+            proxy_manager.clone(),
+            &provider_registry,
+            &mut handlers,
+        )
+        .await?;
+
+        let runtime = Self::load_handlers(
             outbounds,
             outbound_groups,
             proxy_names,
@@ -87,15 +181,17 @@ impl OutboundManager {
         .await?;
 
         Ok(Self {
-            handlers,
+            handlers: std::sync::RwLock::new(handlers),
             proxy_manager,
             selector_control,
             proxy_providers: provider_registry,
+            runtime: Mutex::new(runtime),
+            share_links: std::sync::RwLock::new(share_links),
         })
     }
 
     pub fn get_outbound(&self, name: &str) -> Option<AnyOutboundHandler> {
-        self.handlers.get(name).map(Clone::clone)
+        self.handlers.read().unwrap().get(name).map(Clone::clone)
     }
 
     /// this doesn't populate history/liveness information
@@ -112,8 +208,9 @@ impl OutboundManager {
         let mut r = HashMap::new();
 
         let proxy_manager = self.proxy_manager.clone();
+        let handlers = self.handlers.read().unwrap().clone();
 
-        for (k, v) in self.handlers.iter() {
+        for (k, v) in handlers.iter() {
             let mut m = v.as_map().await;
 
             let alive = proxy_manager.alive(k).await;
@@ -162,12 +259,191 @@ impl OutboundManager {
         proxy_manager.url_test(proxy, url, Some(timeout)).await
     }
 
+    /// a wrapper of proxy_manager.unlock_test
+    pub async fn unlock_test(
+        &self,
+        proxy: AnyOutboundHandler,
+        service: StreamingService,
+        timeout: Duration,
+    ) -> std::io::Result<UnlockResult> {
+        self.proxy_manager
+            .unlock_test(proxy, service, timeout)
+            .await
+    }
+
+    /// the share link of a proxy server, see `to_share_link`, the ones
+    /// from proxy providers have none
+    pub fn share_link(&self, name: &str) -> Option<String> {
+        self.share_links.read().unwrap().get(name).cloned()
+    }
+
     pub fn get_proxy_providers(&self) -> HashMap<String, ThreadSafeProxyProvider> {
         self.proxy_providers.clone()
     }
 
+    /// adds a proxy server, groups with `include-all` and GLOBAL pick it up
+    pub async fn add_proxy(&self, proto: OutboundProxyProtocol) -> Result<(), Error> {
+        let name = proto.name().to_owned();
+        let share_link = to_share_link(&proto).ok();
+        let handler: AnyOutboundHandler = match proto {
+            OutboundProxyProtocol::Ss(s) => s.try_into()?,
+            OutboundProxyProtocol::Socks5(s) => s.try_into()?,
+            OutboundProxyProtocol::Trojan(s) => s.try_into()?,
+            OutboundProxyProtocol::Vmess(s) => s.try_into()?,
+            OutboundProxyProtocol::AnyTls(s) => s.try_into()?,
+            OutboundProxyProtocol::Wireguard(s) => s.try_into()?,
+            OutboundProxyProtocol::NamedDirect(d) => {
+                direct::Handler::new_named(d.name, d.bind_address)
+            }
+            // listed rather than matched by `_`, so a new protocol can't be
+            // left out here unnoticed
+            OutboundProxyProtocol::Direct | OutboundProxyProtocol::Reject(_) => {
+                return Err(Error::InvalidConfig(format!("proxy {} is reserved", name)))
+            }
+        };
+
+        let mut runtime = self.runtime.lock().await;
+        {
+            let mut handlers = self.handlers.write().unwrap();
+            if handlers.contains_key(&name) {
+                return Err(Error::Operation(format!("proxy {} already exists", name)));
+            }
+            Self::bind_dialer_proxies(
+                std::iter::once(&handler),
+                &handlers,
+                &runtime.members(Some(&name)),
+            )?;
+            handlers.insert(name.clone(), handler.clone());
+        }
+        runtime.servers.push(name.clone());
+        if let Some(link) = share_link {
+            self.share_links.write().unwrap().insert(name.clone(), link);
+        }
+
+        let mut global = runtime.global.write().await;
+        let mut proxies = global.proxies().await;
+        proxies.push(handler);
+        global.set_proxies(proxies).await;
+        drop(global);
+
+        self.refresh_include_all(&runtime).await;
+        info!("proxy {} added", name);
+        Ok(())
+    }
+
+    /// removes a proxy server, refusing if a group would lose a
+    /// proxy it lists explicitly or be left with no proxies
+    pub async fn remove_proxy(&self, name: &str) -> Result<(), Error> {
+        let mut runtime = self.runtime.lock().await;
+        if !runtime.servers.iter().any(|x| x == name) {
+            return Err(Error::Operation(format!("{} is not a proxy server", name)));
+        }
+        if let Some(h) = self
+            .handlers
+            .read()
+            .unwrap()
+            .values()
+            .find(|x| x.dialer_proxy().map(|d| d.name()) == Some(name))
+        {
+            return Err(Error::Operation(format!(
+                "proxy {} is the dialer-proxy of {}",
+                name,
+                h.name()
+            )));
+        }
+        if let Some(groups) = runtime.referenced_by.get(name) {
+            return Err(Error::Operation(format!(
+                "proxy {} is used by {}",
+                name,
+                groups.join(", ")
+            )));
+        }
+        for group in runtime.include_all.iter().filter(|x| !x.has_other) {
+            if !runtime
+                .servers
+                .iter()
+                .any(|x| x != name && group.matches(x))
+            {
+                return Err(Error::Operation(format!(
+                    "removing proxy {} leaves group {} empty",
+                    name, group.name
+                )));
+            }
+        }
+
+        runtime.servers.retain(|x| x != name);
+        self.handlers.write().unwrap().remove(name);
+        self.share_links.write().unwrap().remove(name);
+
+        let mut global = runtime.global.write().await;
+        let proxies = global
+            .proxies()
+            .await
+            .into_iter()
+            .filter(|x| x.name() != name)
+            .collect();
+        global.set_proxies(proxies).await;
+        drop(global);
+
+        self.refresh_include_all(&runtime).await;
+        info!("proxy {} removed", name);
+        Ok(())
+    }
+
+    async fn refresh_include_all(&self, runtime: &RuntimeProxies) {
+        for group in runtime.include_all.iter() {
+            let proxies = {
+                let handlers = self.handlers.read().unwrap();
+                runtime
+                    .servers
+                    .iter()
+                    .filter(|x| group.matches(x))
+                    .filter_map(|x| handlers.get(x).cloned())
+                    .collect()
+            };
+            group.provider.write().await.set_proxies(proxies).await;
+        }
+    }
+
     // API handlers end
 
+    /// binds the `dialer-proxy` of `targets` to the outbounds they name,
+    /// refusing one leading back to the proxy, through other dialer-proxies
+    /// or the `members` of the groups on the way, as dialing would never end
+    fn bind_dialer_proxies<'a>(
+        targets: impl Iterator<Item = &'a AnyOutboundHandler>,
+        handlers: &HashMap<String, AnyOutboundHandler>,
+        members: &HashMap<String, Vec<String>>,
+    ) -> Result<(), Error> {
+        let dialers = handlers
+            .iter()
+            .filter_map(|(name, h)| Some((name.as_str(), h.dialer_proxy()?.name())))
+            .collect::<HashMap<_, _>>();
+
+        for h in targets {
+            let Some(dialer) = h.dialer_proxy() else {
+                continue;
+            };
+            if let Some(path) = dialer_loop(h.name(), dialer.name(), &dialers, members) {
+                return Err(Error::InvalidConfig(format!(
+                    "proxy {}: dialer-proxy loop {}",
+                    h.name(),
+                    path.join(" -> ")
+                )));
+            }
+
+            let target = handlers.get(dialer.name()).ok_or_else(|| {
+                Error::InvalidConfig(format!(
+                    "proxy {}: dialer-proxy {} not found",
+                    h.name(),
+                    dialer.name()
+                ))
+            })?;
+            dialer.bind(target);
+        }
+        Ok(())
+    }
+
     async fn load_handlers(
         outbounds: Vec<OutboundProxyProtocol>,
         outbound_groups: Vec<OutboundGroupProtocol>,
@@ -177,7 +453,7 @@ impl OutboundManager {
         handlers: &mut HashMap<String, AnyOutboundHandler>,
         selector_control: &mut HashMap<String, ThreadSafeSelectorControl>,
         cache_store: ThreadSafeCacheFile,
-    ) -> Result<(), Error> {
+    ) -> Result<RuntimeProxies, Error> {
         let mut proxy_providers = vec![];
 
         for outbound in outbounds.iter() {
@@ -186,8 +462,15 @@ impl OutboundManager {
                     handlers.insert(PROXY_DIRECT.to_string(), direct::Handler::new());
                 }
 
-                OutboundProxyProtocol::Reject => {
-                    handlers.insert(PROXY_REJECT.to_string(), reject::Handler::new());
+                OutboundProxyProtocol::Reject(mode) => {
+                    handlers.insert(mode.name().to_string(), reject::Handler::new(*mode));
+                }
+
+                OutboundProxyProtocol::NamedDirect(d) => {
+                    handlers.insert(
+                        d.name.clone(),
+                        direct::Handler::new_named(d.name.clone(), d.bind_address),
+                    );
                 }
 
                 OutboundProxyProtocol::Ss(s) => {
@@ -198,31 +481,116 @@ impl OutboundManager {
                     handlers.insert(v.name.clone(), v.try_into()?);
                 }
 
+                OutboundProxyProtocol::Socks5(v) => {
+                    handlers.insert(v.name.clone(), v.try_into()?);
+                }
+
                 OutboundProxyProtocol::Trojan(v) => {
                     handlers.insert(v.name.clone(), v.try_into()?);
                 }
 
-                p => {
-                    unimplemented!("proto {} not supported yet", p);
+                OutboundProxyProtocol::AnyTls(v) => {
+                    handlers.insert(v.name.clone(), v.try_into()?);
+                }
+
+                OutboundProxyProtocol::Wireguard(v) => {
+                    handlers.insert(v.name.clone(), v.try_into()?);
                 }
             }
         }
 
+        let servers: Vec<String> = proxy_names
+            .iter()
+            .filter(|x| {
+                handlers.contains_key(x.as_str())
+                    && x.as_str() != PROXY_DIRECT
+                    && !RejectMode::is_reject(x)
+            })
+            .cloned()
+            .collect();
+        let mut referenced_by: HashMap<String, Vec<String>> = HashMap::new();
+        let mut include_all = vec![];
+
         let mut outbound_groups = outbound_groups;
         proxy_groups_dag_sort(&mut outbound_groups)?;
 
         for outbound_group in outbound_groups.iter() {
+            for proxy in outbound_group.proxies().into_iter().flatten() {
+                if servers.contains(proxy) {
+                    referenced_by
+                        .entry(proxy.clone())
+                        .or_default()
+                        .push(outbound_group.name().to_owned());
+                }
+            }
+
+            fn make_include_all_provider(
+                opts: IncludeAllOptions,
+                servers: &[String],
+                handlers: &HashMap<String, AnyOutboundHandler>,
+                proxy_manager: ProxyManager,
+                include_all: &mut Vec<IncludeAllGroup>,
+            ) -> Result<ThreadSafeProxyProvider, Error> {
+                let IncludeAllOptions {
+                    name,
+                    filter,
+                    has_other,
+                    interval,
+                    lazy,
+                    limiter,
+                } = opts;
+                let filter = filter.map(Regex::new).transpose().map_err(|x| {
+                    Error::InvalidConfig(format!("proxy group {}: invalid filter: {}", name, x))
+                })?;
+                let proxies = servers
+                    .iter()
+                    .filter(|x| filter.as_ref().map_or(true, |f| f.is_match(x)))
+                    .filter_map(|x| handlers.get(x).cloned())
+                    .collect::<Vec<_>>();
+                if proxies.is_empty() && !has_other {
+                    return Err(Error::InvalidConfig(format!(
+                        "proxy group {} has no proxies",
+                        name
+                    )));
+                }
+
+                let hc = HealthCheck::new(
+                    proxies.clone(),
+                    DEFAULT_LATENCY_TEST_URL.to_owned(),
+                    interval,
+                    lazy,
+                    proxy_manager,
+                    limiter,
+                )
+                .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?;
+
+                let pd = Arc::new(RwLock::new(PlainProvider::new_dynamic(
+                    name.to_owned(),
+                    proxies,
+                    hc,
+                )));
+                include_all.push(IncludeAllGroup {
+                    name: name.to_owned(),
+                    filter,
+                    has_other,
+                    provider: pd.clone(),
+                });
+
+                Ok(pd)
+            }
+
             fn make_provider_from_proxies(
                 name: &str,
                 proxies: &Vec<String>,
                 interval: u64,
                 lazy: bool,
+                limiter: Option<HealthCheckLimiter>,
                 handlers: &HashMap<String, AnyOutboundHandler>,
                 proxy_manager: ProxyManager,
                 proxy_providers: &mut Vec<ThreadSafeProxyProvider>,
                 provider_registry: &mut HashMap<String, ThreadSafeProxyProvider>,
             ) -> Result<ThreadSafeProxyProvider, Error> {
-                if name == PROXY_DIRECT || name == PROXY_REJECT {
+                if name == PROXY_DIRECT || RejectMode::is_reject(name) {
                     return Err(Error::InvalidConfig(format!(
                         "proxy group {} is reserved",
                         name
@@ -244,6 +612,7 @@ impl OutboundManager {
                     interval,
                     lazy,
                     proxy_manager.clone(),
+                    limiter,
                 )
                 .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?;
 
@@ -261,14 +630,14 @@ impl OutboundManager {
 
             match outbound_group {
                 OutboundGroupProtocol::Relay(proto) => {
-                    if proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
+                    let has_other = proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
                         + proto
                             .use_provider
                             .as_ref()
                             .map(|x| x.len())
                             .unwrap_or_default()
-                        == 0
-                    {
+                        > 0;
+                    if !has_other && !proto.include_all.unwrap_or_default() {
                         return Err(Error::InvalidConfig(format!(
                             "proxy group {} has no proxies",
                             proto.name
@@ -282,6 +651,7 @@ impl OutboundManager {
                             proxies,
                             0,
                             true,
+                            None,
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -289,6 +659,23 @@ impl OutboundManager {
                         )?);
                     }
 
+                    if proto.include_all.unwrap_or_default() {
+                        providers.push(make_include_all_provider(
+                            IncludeAllOptions {
+                                name: &proto.name,
+                                filter: proto.filter.as_deref(),
+                                has_other,
+                                interval: 0,
+                                lazy: true,
+                                limiter: None,
+                            },
+                            &servers,
+                            handlers,
+                            proxy_manager.clone(),
+                            &mut include_all,
+                        )?);
+                    }
+
                     if let Some(provider_names) = &proto.use_provider {
                         for provider_name in provider_names {
                             let provider = provider_registry
@@ -310,14 +697,14 @@ impl OutboundManager {
                     handlers.insert(proto.name.clone(), relay);
                 }
                 OutboundGroupProtocol::UrlTest(proto) => {
-                    if proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
+                    let has_other = proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
                         + proto
                             .use_provider
                             .as_ref()
                             .map(|x| x.len())
                             .unwrap_or_default()
-                        == 0
-                    {
+                        > 0;
+                    if !has_other && !proto.include_all.unwrap_or_default() {
                         return Err(Error::InvalidConfig(format!(
                             "proxy group {} has no proxies",
                             proto.name
@@ -331,6 +718,7 @@ impl OutboundManager {
                             proxies,
                             proto.interval,
                             proto.lazy.unwrap_or_default(),
+                            HealthCheckLimiter::from_opts(proto.max_concurrent, proto.spacing),
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -338,6 +726,26 @@ impl OutboundManager {
                         )?);
                     }
 
+                    if proto.include_all.unwrap_or_default() {
+                        providers.push(make_include_all_provider(
+                            IncludeAllOptions {
+                                name: &proto.name,
+                                filter: proto.filter.as_deref(),
+                                has_other,
+                                interval: proto.interval,
+                                lazy: proto.lazy.unwrap_or_default(),
+                                limiter: HealthCheckLimiter::from_opts(
+                                    proto.max_concurrent,
+                                    proto.spacing,
+                                ),
+                            },
+                            &servers,
+                            handlers,
+                            proxy_manager.clone(),
+                            &mut include_all,
+                        )?);
+                    }
+
                     if let Some(provider_names) = &proto.use_provider {
                         for provider_name in provider_names {
                             let provider = provider_registry
@@ -361,14 +769,14 @@ impl OutboundManager {
                     handlers.insert(proto.name.clone(), Arc::new(url_test));
                 }
                 OutboundGroupProtocol::Fallback(proto) => {
-                    if proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
+                    let has_other = proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
                         + proto
                             .use_provider
                             .as_ref()
                             .map(|x| x.len())
                             .unwrap_or_default()
-                        == 0
-                    {
+                        > 0;
+                    if !has_other && !proto.include_all.unwrap_or_default() {
                         return Err(Error::InvalidConfig(format!(
                             "proxy group {} has no proxies",
                             proto.name
@@ -382,6 +790,7 @@ impl OutboundManager {
                             proxies,
                             proto.interval,
                             proto.lazy.unwrap_or_default(),
+                            HealthCheckLimiter::from_opts(proto.max_concurrent, proto.spacing),
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -389,6 +798,26 @@ impl OutboundManager {
                         )?);
                     }
 
+                    if proto.include_all.unwrap_or_default() {
+                        providers.push(make_include_all_provider(
+                            IncludeAllOptions {
+                                name: &proto.name,
+                                filter: proto.filter.as_deref(),
+                                has_other,
+                                interval: proto.interval,
+                                lazy: proto.lazy.unwrap_or_default(),
+                                limiter: HealthCheckLimiter::from_opts(
+                                    proto.max_concurrent,
+                                    proto.spacing,
+                                ),
+                            },
+                            &servers,
+                            handlers,
+                            proxy_manager.clone(),
+                            &mut include_all,
+                        )?);
+                    }
+
                     if let Some(provider_names) = &proto.use_provider {
                         for provider_name in provider_names {
                             let provider = provider_registry
@@ -411,14 +840,14 @@ impl OutboundManager {
                     handlers.insert(proto.name.clone(), Arc::new(fallback));
                 }
                 OutboundGroupProtocol::LoadBalance(proto) => {
-                    if proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
+                    let has_other = proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
                         + proto
                             .use_provider
                             .as_ref()
                             .map(|x| x.len())
                             .unwrap_or_default()
-                        == 0
-                    {
+                        > 0;
+                    if !has_other && !proto.include_all.unwrap_or_default() {
                         return Err(Error::InvalidConfig(format!(
                             "proxy group {} has no proxies",
                             proto.name
@@ -432,6 +861,7 @@ impl OutboundManager {
                             proxies,
                             proto.interval,
                             proto.lazy.unwrap_or_default(),
+                            HealthCheckLimiter::from_opts(proto.max_concurrent, proto.spacing),
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -439,6 +869,26 @@ impl OutboundManager {
                         )?);
                     }
 
+                    if proto.include_all.unwrap_or_default() {
+                        providers.push(make_include_all_provider(
+                            IncludeAllOptions {
+                                name: &proto.name,
+                                filter: proto.filter.as_deref(),
+                                has_other,
+                                interval: proto.interval,
+                                lazy: proto.lazy.unwrap_or_default(),
+                                limiter: HealthCheckLimiter::from_opts(
+                                    proto.max_concurrent,
+                                    proto.spacing,
+                                ),
+                            },
+                            &servers,
+                            handlers,
+                            proxy_manager.clone(),
+                            &mut include_all,
+                        )?);
+                    }
+
                     if let Some(provider_names) = &proto.use_provider {
                         for provider_name in provider_names {
                             let provider = provider_registry
@@ -460,14 +910,14 @@ impl OutboundManager {
                     handlers.insert(proto.name.clone(), Arc::new(load_balance));
                 }
                 OutboundGroupProtocol::Select(proto) => {
-                    if proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
+                    let has_other = proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
                         + proto
                             .use_provider
                             .as_ref()
                             .map(|x| x.len())
                             .unwrap_or_default()
-                        == 0
-                    {
+                        > 0;
+                    if !has_other && !proto.include_all.unwrap_or_default() {
                         return Err(Error::InvalidConfig(format!(
                             "proxy group {} has no proxies",
                             proto.name
@@ -481,6 +931,7 @@ impl OutboundManager {
                             proxies,
                             0,
                             true,
+                            None,
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -488,6 +939,23 @@ impl OutboundManager {
                         )?);
                     }
 
+                    if proto.include_all.unwrap_or_default() {
+                        providers.push(make_include_all_provider(
+                            IncludeAllOptions {
+                                name: &proto.name,
+                                filter: proto.filter.as_deref(),
+                                has_other,
+                                interval: 0,
+                                lazy: true,
+                                limiter: None,
+                            },
+                            &servers,
+                            handlers,
+                            proxy_manager.clone(),
+                            &mut include_all,
+                        )?);
+                    }
+
                     if let Some(provider_names) = &proto.use_provider {
                         for provider_name in provider_names {
                             let provider = provider_registry
@@ -520,8 +988,8 @@ impl OutboundManager {
 
         // insert GLOBAL
         let mut g = vec![];
-        for name in proxy_names {
-            g.push(handlers.get(&name).unwrap().clone());
+        for name in proxy_names.iter() {
+            g.push(handlers.get(name).unwrap().clone());
         }
         let hc = HealthCheck::new(
             g.clone(),
@@ -529,6 +997,7 @@ impl OutboundManager {
             0, // this is a manual HC
             true,
             proxy_manager.clone(),
+            None,
         )
         .unwrap();
         let pd = Arc::new(RwLock::new(
@@ -547,10 +1016,101 @@ impl OutboundManager {
         )
         .await;
 
-        provider_registry.insert(RESERVED_PROVIDER_NAME.to_owned(), pd);
+        provider_registry.insert(RESERVED_PROVIDER_NAME.to_owned(), pd.clone());
         handlers.insert(PROXY_GLOBAL.to_owned(), Arc::new(h.clone()));
         selector_control.insert(PROXY_GLOBAL.to_owned(), Arc::new(Mutex::new(h)));
 
+        let mut listed: HashMap<_, _> = outbound_groups
+            .iter()
+            .map(|x| {
+                (
+                    x.name().to_owned(),
+                    x.proxies().cloned().unwrap_or_default(),
+                )
+            })
+            .collect();
+        listed.insert(PROXY_GLOBAL.to_owned(), proxy_names);
+
+        let runtime = RuntimeProxies {
+            servers,
+            referenced_by,
+            listed,
+            include_all,
+            global: pd,
+        };
+        Self::bind_dialer_proxies(handlers.values(), handlers, &runtime.members(None))?;
+        Ok(runtime)
+    }
+
+    /// a url-test group of the proxies of a provider in each country, for
+    /// the countries it has proxies in once loaded and those of
+    /// `referenced`, the groups the config refers to. The members follow
+    /// the updates of the provider, a group left without any rejects
+    async fn load_auto_groups(
+        auto_grouped: Vec<String>,
+        referenced: &HashSet<String>,
+        proxy_names: &[String],
+        proxy_manager: ProxyManager,
+        provider_registry: &HashMap<String, ThreadSafeProxyProvider>,
+        handlers: &mut HashMap<String, AnyOutboundHandler>,
+    ) -> Result<(), Error> {
+        for provider_name in auto_grouped {
+            let Some(provider) = provider_registry.get(&provider_name).cloned() else {
+                continue;
+            };
+            let mut countries = provider
+                .read()
+                .await
+                .proxies()
+                .await
+                .iter()
+                .filter_map(|x| country_of(x.name()))
+                .collect::<Vec<_>>();
+            countries.extend(
+                referenced
+                    .iter()
+                    .filter_map(|x| x.strip_prefix(provider_name.as_str())?.strip_prefix('-'))
+                    .filter(|x| is_country_code(x))
+                    .map(str::to_owned),
+            );
+            countries.sort();
+            countries.dedup();
+            if countries.is_empty() {
+                warn!(
+                    "proxy provider {} has no proxies of a known country to group",
+                    provider_name
+                );
+            }
+
+            for country in countries {
+                let name = format!("{}-{}", provider_name, country);
+                if proxy_names.contains(&name) {
+                    return Err(Error::InvalidConfig(format!(
+                        "{} grouped by country from provider {} is already defined",
+                        name, provider_name
+                    )));
+                }
+
+                let pd: ThreadSafeProxyProvider = Arc::new(RwLock::new(CountryProvider::new(
+                    name.clone(),
+                    country,
+                    provider.clone(),
+                )));
+                let url_test = urltest::Handler::new(
+                    urltest::HandlerOptions {
+                        name: name.clone(),
+                        ..Default::default()
+                    },
+                    0,
+                    vec![pd],
+                    proxy_manager.clone(),
+                );
+
+                info!("proxy group {} grouped by country", name);
+                handlers.insert(name, Arc::new(url_test));
+            }
+        }
+
         Ok(())
     }
 
@@ -578,6 +1138,10 @@ impl OutboundManager {
                         http.health_check.interval,
                         http.health_check.lazy.unwrap_or_default(),
                         proxy_manager.clone(),
+                        HealthCheckLimiter::from_opts(
+                            http.health_check.max_concurrent,
+                            http.health_check.spacing,
+                        ),
                     )
                     .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?;
                     let provider = ProxySetProvider::new(
@@ -603,6 +1167,10 @@ impl OutboundManager {
                         file.health_check.interval,
                         file.health_check.lazy.unwrap_or_default(),
                         proxy_manager.clone(),
+                        HealthCheckLimiter::from_opts(
+                            file.health_check.max_concurrent,
+                            file.health_check.spacing,
+                        ),
                     )
                     .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?;
 
@@ -168,8 +168,50 @@ pub fn proxy_groups_dag_sort(groups: &mut Vec<OutboundGroupProtocol>) -> Result<
     )));
 }
 
+/// the way from `name` back to itself through its `dialer`, following the
+/// dialer-proxies of the outbounds, `dialers`, and the members of the
+/// groups, if dialing it may come back to it
+pub fn dialer_loop<'a>(
+    name: &'a str,
+    dialer: &'a str,
+    dialers: &HashMap<&'a str, &'a str>,
+    members: &'a HashMap<String, Vec<String>>,
+) -> Option<Vec<&'a str>> {
+    // where each outbound reached was reached from
+    let mut from = HashMap::from([(dialer, name)]);
+    let mut queue = VecDeque::from([dialer]);
+    while let Some(x) = queue.pop_front() {
+        if x == name {
+            let mut path = vec![x];
+            let mut at = x;
+            while at != dialer {
+                at = from[at];
+                path.push(at);
+            }
+            path.push(name);
+            path.reverse();
+            return Some(path);
+        }
+
+        let next = dialers
+            .get(x)
+            .copied()
+            .into_iter()
+            .chain(members.get(x).into_iter().flatten().map(String::as_str));
+        for n in next {
+            if !from.contains_key(n) {
+                from.insert(n, x);
+                queue.push_back(n);
+            }
+        }
+    }
+    None
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use crate::config::internal::proxy::{
         OutboundGroupFallback, OutboundGroupLoadBalance, OutboundGroupProtocol, OutboundGroupRelay,
         OutboundGroupSelect, OutboundGroupUrlTest,
@@ -266,4 +308,24 @@ mod tests {
         let e = super::proxy_groups_dag_sort(&mut groups).unwrap_err();
         assert!(e.to_string().contains("loop detected in proxy groups"));
     }
+
+    #[test]
+    fn test_dialer_loop() {
+        let dialers = HashMap::from([("a", "b"), ("b", "select"), ("c", "d"), ("self", "self")]);
+        let members = HashMap::from([
+            ("select".to_owned(), vec!["d".to_owned(), "a".to_owned()]),
+            ("other".to_owned(), vec!["d".to_owned()]),
+        ]);
+
+        assert_eq!(
+            super::dialer_loop("a", "b", &dialers, &members),
+            Some(vec!["a", "b", "select", "a"])
+        );
+        assert_eq!(
+            super::dialer_loop("self", "self", &dialers, &members),
+            Some(vec!["self", "self"])
+        );
+        assert_eq!(super::dialer_loop("c", "d", &dialers, &members), None);
+        assert_eq!(super::dialer_loop("c", "other", &dialers, &members), None);
+    }
 }
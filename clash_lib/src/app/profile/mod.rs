@@ -3,11 +3,32 @@ use std::{collections::HashMap, sync::Arc};
 use serde::{Deserialize, Serialize};
 use tracing::{error, trace};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Db {
+/// the contents of cache.db, also most of what `/cache/export` returns
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Db {
+    #[serde(default)]
     selected: HashMap<String, String>,
+    #[serde(default)]
     ip_to_host: HashMap<String, String>,
+    #[serde(default)]
     host_to_ip: HashMap<String, String>,
+    /// domains `fake-ip-auto-skip` learned
+    #[serde(default)]
+    fake_ip_skipped: Vec<String>,
+    /// the DNS cache as of the last shutdown, with `store-dns-cache`
+    #[serde(default)]
+    dns_cache: Vec<CachedAnswer>,
+}
+
+/// an answer of the DNS cache
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedAnswer {
+    /// the message in wire format, base64 encoded
+    pub message: String,
+    /// unix seconds
+    pub cached_at: u64,
+    /// seconds from `cached_at`
+    pub ttl: u64,
 }
 
 #[derive(Clone)]
@@ -32,19 +53,7 @@ impl ThreadSafeCacheFile {
                     let db = r.db.clone();
                     drop(r);
 
-                    let s = match serde_yaml::to_string(&db) {
-                        Ok(s) => s,
-                        Err(e) => {
-                            error!("failed to serialize cache file: {}", e);
-                            continue;
-                        }
-                    };
-
-                    if let Err(e) = tokio::fs::write(&path, s).await {
-                        error!("failed to write cache file: {}", e);
-                    } else {
-                        trace!("cache file flushed to {}", path);
-                    }
+                    write(&path, &db).await;
                 }
             });
         }
@@ -52,6 +61,15 @@ impl ThreadSafeCacheFile {
         Self(store)
     }
 
+    /// writes the cache file now rather than with the next periodic flush
+    pub async fn flush(&self) {
+        let r = self.0.read().await;
+        let (path, db) = (r.path.clone(), r.db.clone());
+        drop(r);
+
+        write(&path, &db).await;
+    }
+
     pub async fn set_selected(&self, group: &str, server: &str) {
         let mut g = self.0.write().await;
         if g.store_selected() {
@@ -68,7 +86,6 @@ impl ThreadSafeCacheFile {
         }
     }
 
-    #[allow(dead_code)]
     pub async fn get_selected_map(&self) -> HashMap<String, String> {
         let g = self.0.read().await;
         if g.store_selected() {
@@ -93,9 +110,52 @@ impl ThreadSafeCacheFile {
     pub async fn delete_fake_ip_pair(&self, ip: &str, host: &str) {
         self.0.write().await.delete_fake_ip_pair(ip, host);
     }
+
+    pub async fn get_fake_ip_skipped(&self) -> Vec<String> {
+        self.0.read().await.db.fake_ip_skipped.clone()
+    }
+
+    pub async fn set_fake_ip_skipped(&self, hosts: Vec<String>) {
+        self.0.write().await.db.fake_ip_skipped = hosts;
+    }
+
+    /// the saved DNS cache, which is then dropped from the file
+    pub async fn take_dns_cache(&self) -> Vec<CachedAnswer> {
+        std::mem::take(&mut self.0.write().await.db.dns_cache)
+    }
+
+    pub async fn set_dns_cache(&self, answers: Vec<CachedAnswer>) {
+        self.0.write().await.db.dns_cache = answers;
+    }
+
+    pub async fn export(&self) -> Db {
+        self.0.read().await.db.clone()
+    }
+
+    /// merges a cache exported elsewhere into this one, its entries win
+    pub async fn import(&self, db: Db) {
+        self.0.write().await.import(db);
+    }
+}
+
+async fn write(path: &str, db: &Db) {
+    let s = match serde_yaml::to_string(db) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("failed to serialize cache file: {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = tokio::fs::write(path, s).await {
+        error!("failed to write cache file: {}", e);
+    } else {
+        trace!("cache file flushed to {}", path);
+    }
 }
 
 struct CacheFile {
+    path: String,
     db: Db,
 
     store_selected: bool,
@@ -108,24 +168,20 @@ impl CacheFile {
                 Ok(db) => db,
                 Err(e) => {
                     error!("failed to parse cache file: {}, initilizing a new one", e);
-                    Db {
-                        selected: HashMap::new(),
-                        ip_to_host: HashMap::new(),
-                        host_to_ip: HashMap::new(),
-                    }
+                    Db::default()
                 }
             },
             Err(e) => {
                 error!("failed to read cache file: {}, initializing a new one", e);
-                Db {
-                    selected: HashMap::new(),
-                    ip_to_host: HashMap::new(),
-                    host_to_ip: HashMap::new(),
-                }
+                Db::default()
             }
         };
 
-        Self { db, store_selected }
+        Self {
+            path: path.to_owned(),
+            db,
+            store_selected,
+        }
     }
 
     pub fn store_selected(&self) -> bool {
@@ -162,4 +218,80 @@ impl CacheFile {
         self.db.ip_to_host.remove(ip);
         self.db.host_to_ip.remove(host);
     }
+
+    pub fn import(&mut self, db: Db) {
+        if self.store_selected {
+            self.db.selected.extend(db.selected);
+        }
+
+        // an imported pair replaces any pair holding either of its ends
+        for (ip, host) in db.ip_to_host {
+            if let Some(old_host) = self.db.ip_to_host.get(&ip).cloned() {
+                self.delete_fake_ip_pair(&ip, &old_host);
+            }
+            if let Some(old_ip) = self.db.host_to_ip.get(&host).cloned() {
+                self.delete_fake_ip_pair(&old_ip, &host);
+            }
+            self.set_ip_to_host(&ip, &host);
+            self.set_host_to_ip(&host, &ip);
+        }
+
+        for host in db.fake_ip_skipped {
+            if !self.db.fake_ip_skipped.contains(&host) {
+                self.db.fake_ip_skipped.push(host);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{CacheFile, Db};
+
+    #[test]
+    fn test_import() {
+        let mut cache = CacheFile::new("/nonexistent/cache.db", true);
+        cache.set_selected("proxy", "a");
+        cache.set_selected("auto", "b");
+        cache.set_ip_to_host("198.18.0.1", "old.example.com");
+        cache.set_host_to_ip("old.example.com", "198.18.0.1");
+        cache.set_ip_to_host("198.18.0.2", "example.com");
+        cache.set_host_to_ip("example.com", "198.18.0.2");
+
+        cache.import(Db {
+            selected: HashMap::from([("proxy".to_owned(), "c".to_owned())]),
+            ip_to_host: HashMap::from([("198.18.0.1".to_owned(), "example.com".to_owned())]),
+            host_to_ip: HashMap::from([("example.com".to_owned(), "198.18.0.1".to_owned())]),
+            fake_ip_skipped: vec!["skip.example.com".to_owned()],
+            dns_cache: vec![],
+        });
+
+        let selected = cache.get_selected_map();
+        assert_eq!(selected["proxy"], "c");
+        assert_eq!(selected["auto"], "b");
+        assert_eq!(
+            cache.get_fake_ip("198.18.0.1").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            cache.get_fake_ip("example.com").as_deref(),
+            Some("198.18.0.1")
+        );
+        assert_eq!(cache.get_fake_ip("198.18.0.2"), None);
+        assert_eq!(cache.get_fake_ip("old.example.com"), None);
+        assert_eq!(
+            cache.db.fake_ip_skipped,
+            vec!["skip.example.com".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_import_partial() {
+        let db: Db = serde_yaml::from_str("selected:\n  proxy: a\n").unwrap();
+        assert_eq!(db.selected["proxy"], "a");
+        assert!(db.ip_to_host.is_empty());
+        assert!(db.host_to_ip.is_empty());
+    }
 }
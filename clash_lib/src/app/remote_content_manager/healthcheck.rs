@@ -1,12 +1,67 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use tokio::time::Instant;
+use tokio::{
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
 use tracing::debug;
 
-use crate::{pm_debug, proxy::AnyOutboundHandler};
+use crate::{common::offline, pm_debug, proxy::AnyOutboundHandler};
 
 use super::ProxyManager;
 
+/// Caps the number of latency tests running at the same time,
+/// and optionally spaces out the start of consecutive tests.
+#[derive(Clone)]
+pub struct HealthCheckLimiter {
+    semaphore: Arc<Semaphore>,
+    spacing: Duration,
+    next_start: Arc<Mutex<Instant>>,
+}
+
+impl HealthCheckLimiter {
+    pub fn new(max_concurrent: usize, spacing: Duration) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_concurrent.max(1))),
+            spacing,
+            next_start: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// returns None if neither limit is set
+    pub fn from_opts(max_concurrent: Option<usize>, spacing: Option<u64>) -> Option<Self> {
+        if max_concurrent.is_none() && spacing.is_none() {
+            return None;
+        }
+        Some(Self::new(
+            max_concurrent.unwrap_or(Semaphore::MAX_PERMITS),
+            Duration::from_millis(spacing.unwrap_or_default()),
+        ))
+    }
+
+    /// waits for a free slot, the permit must be held until the test is done
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        let permit = self
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("health check limiter closed");
+
+        if !self.spacing.is_zero() {
+            let start = {
+                let mut next_start = self.next_start.lock().await;
+                let start = (*next_start).max(Instant::now());
+                *next_start = start + self.spacing;
+                start
+            };
+            tokio::time::sleep_until(start).await;
+        }
+
+        permit
+    }
+}
+
 struct HealCheckInner {
     last_check: Instant,
     proxies: Vec<AnyOutboundHandler>,
@@ -18,6 +73,7 @@ pub struct HealthCheck {
     interval: u64,
     lazy: bool,
     proxy_manager: ProxyManager,
+    limiter: Option<HealthCheckLimiter>,
     inner: Arc<tokio::sync::RwLock<HealCheckInner>>,
 }
 
@@ -28,12 +84,14 @@ impl HealthCheck {
         interval: u64,
         lazy: bool,
         proxy_manager: ProxyManager,
+        limiter: Option<HealthCheckLimiter>,
     ) -> anyhow::Result<Self> {
         let health_check = Self {
             url,
             interval,
             lazy,
             proxy_manager,
+            limiter,
             inner: Arc::new(tokio::sync::RwLock::new(HealCheckInner {
                 last_check: tokio::time::Instant::now(),
                 proxies,
@@ -50,14 +108,17 @@ impl HealthCheck {
         let proxies = self.inner.read().await.proxies.clone();
 
         let url = self.url.clone();
+        let limiter = self.limiter.clone();
         tokio::spawn(async move {
-            proxy_manager.check(&proxies, &url, None).await;
+            if !offline::is_offline() {
+                proxy_manager.check(&proxies, &url, None, limiter).await;
+            }
         });
 
         let inner = self.inner.clone();
-        let proxies = self.inner.read().await.proxies.clone();
         let proxy_manager = self.proxy_manager.clone();
         let url = self.url.clone();
+        let limiter = self.limiter.clone();
         let task_handle = tokio::spawn(async move {
             let mut ticker = tokio::time::interval(tokio::time::Duration::from_secs(interval));
             loop {
@@ -65,9 +126,14 @@ impl HealthCheck {
                     _ = ticker.tick() => {
                         pm_debug!("healthcheck ticking: {}, lazy: {}", url, lazy);
                         let now = tokio::time::Instant::now();
-                        let r = inner.read().await;
-                        if !lazy || now.duration_since(r.last_check).as_secs() >= interval {
-                            proxy_manager.check(&proxies, &url, None).await;
+                        // proxies may be replaced by `update`
+                        let (proxies, last_check) = {
+                            let r = inner.read().await;
+                            (r.proxies.clone(), r.last_check)
+                        };
+                        let due = !lazy || now.duration_since(last_check).as_secs() >= interval;
+                        if due && !offline::is_offline() {
+                            proxy_manager.check(&proxies, &url, None, limiter.clone()).await;
                             let mut w = inner.write().await;
                             w.last_check = now;
                         }
@@ -85,7 +151,9 @@ impl HealthCheck {
 
     pub async fn check(&self) {
         let proxies = self.inner.read().await.proxies.clone();
-        self.proxy_manager.check(&proxies, &self.url, None).await;
+        self.proxy_manager
+            .check(&proxies, &self.url, None, self.limiter.clone())
+            .await;
     }
 
     pub async fn update(&self, proxies: Vec<AnyOutboundHandler>) {
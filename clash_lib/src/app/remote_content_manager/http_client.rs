@@ -10,6 +10,7 @@ use tower::Service;
 
 use crate::{
     app::{dispatcher::BoxedChainedStream, dns::ThreadSafeDNSResolver},
+    common::redact,
     proxy::AnyOutboundHandler,
     session::Session,
 };
@@ -30,7 +31,7 @@ impl Service<Uri> for LocalConnector {
     fn call(&mut self, remote: Uri) -> Self::Future {
         let host = remote
             .host()
-            .expect(format!("invalid url: {}", remote.to_string()).as_str())
+            .expect(format!("invalid url: {}", redact::url(&remote.to_string())).as_str())
             .to_owned();
 
         let port = remote.port_u16().unwrap_or(match remote.scheme_str() {
@@ -38,14 +39,15 @@ impl Service<Uri> for LocalConnector {
             Some(s) => match s {
                 s if s == "http" => 80,
                 s if s == "https" => 443,
-                _ => panic!("invalid url: {}", remote),
+                _ => panic!("invalid url: {}", redact::url(&remote.to_string())),
             },
         });
 
         let sess = Session {
             destination: (host, port)
                 .try_into()
-                .expect(format!("invalid url: {}", remote.to_string()).as_str()),
+                .expect(format!("invalid url: {}", redact::url(&remote.to_string())).as_str()),
+            probe: true,
             ..Default::default()
         };
         let handler = self.0.clone();
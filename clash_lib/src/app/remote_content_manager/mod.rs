@@ -26,13 +26,18 @@ use crate::{
     proxy::AnyOutboundHandler,
 };
 
-use self::http_client::LocalConnector;
+use self::{
+    healthcheck::HealthCheckLimiter,
+    http_client::LocalConnector,
+    unlock::{StreamingService, UnlockResult},
+};
 
 use super::dns::ThreadSafeDNSResolver;
 
 pub mod healthcheck;
 mod http_client;
 pub mod providers;
+pub mod unlock;
 
 #[macro_export]
 macro_rules! pm_debug {
@@ -67,14 +72,17 @@ struct ProxyState {
 pub struct ProxyManager {
     proxy_state: Arc<RwLock<HashMap<String, ProxyState>>>,
     dns_resolver: ThreadSafeDNSResolver,
+    /// shared by all health checks
+    limiter: Option<HealthCheckLimiter>,
 
     connector_map: Arc<RwLock<HashMap<String, HttpsConnector<LocalConnector>>>>,
 }
 
 impl ProxyManager {
-    pub fn new(dns_resolver: ThreadSafeDNSResolver) -> Self {
+    pub fn new(dns_resolver: ThreadSafeDNSResolver, limiter: Option<HealthCheckLimiter>) -> Self {
         Self {
             dns_resolver,
+            limiter,
             proxy_state: Arc::new(RwLock::new(HashMap::new())),
             connector_map: Arc::new(RwLock::new(HashMap::new())),
         }
@@ -85,6 +93,7 @@ impl ProxyManager {
         proxies: &Vec<AnyOutboundHandler>,
         url: &str,
         timeout: Option<Duration>,
+        limiter: Option<HealthCheckLimiter>,
     ) {
         let mut futs = vec![];
         for proxy in proxies {
@@ -92,7 +101,17 @@ impl ProxyManager {
             let url = url.to_owned();
             let timeout = timeout.clone();
             let manager = self.clone();
+            let limiter = limiter.clone();
             futs.push(tokio::spawn(async move {
+                // always the provider's permit first, then the global one
+                let _permit = match limiter {
+                    Some(l) => Some(l.acquire().await),
+                    None => None,
+                };
+                let _global_permit = match manager.limiter.as_ref() {
+                    Some(l) => Some(l.acquire().await),
+                    None => None,
+                };
                 manager
                     .url_test(proxy, url.as_str(), timeout)
                     .await
@@ -141,6 +160,17 @@ impl ProxyManager {
             .unwrap_or(max)
     }
 
+    /// whether `service` serves the region `proxy` exits in
+    pub async fn unlock_test(
+        &self,
+        proxy: AnyOutboundHandler,
+        service: StreamingService,
+        timeout: Duration,
+    ) -> std::io::Result<UnlockResult> {
+        pm_debug!("testing {} with {:?}", proxy.name(), service);
+        unlock::unlock_test(proxy, self.dns_resolver.clone(), service, timeout).await
+    }
+
     #[instrument(skip(self, proxy))]
     pub async fn url_test(
         &self,
@@ -271,7 +301,7 @@ mod tests {
             .expect_resolve()
             .returning(|_, _| Ok(Some(std::net::IpAddr::V4(Ipv4Addr::new(172, 217, 167, 67)))));
 
-        let manager = remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(mock_resolver), None);
 
         let mock_handler = direct::Handler::new();
 
@@ -314,7 +344,7 @@ mod tests {
             .expect_resolve()
             .returning(|_, _| Ok(Some(std::net::IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)))));
 
-        let manager = remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(mock_resolver), None);
 
         let mut mock_handler = MockDummyOutboundHandler::new();
         mock_handler
@@ -10,7 +10,7 @@ use futures::future::BoxFuture;
 use tokio::sync::{Mutex, RwLock};
 use tracing::{debug, info, trace, warn};
 
-use crate::common::utils;
+use crate::common::{offline, utils};
 
 use super::{ProviderVehicleType, ThreadSafeProviderVehicle};
 
@@ -202,6 +202,10 @@ where
                 let name = name.clone();
                 let on_update = on_update.clone();
                 let update = || async move {
+                    if offline::is_offline() {
+                        trace!("fetcher {} suspended in offline mode", &name);
+                        return;
+                    }
                     let (elm, same) =
                         match Fetcher::<U, P>::update_inner(inner, vehicle, parser).await {
                             Ok((elm, same)) => (elm, same),
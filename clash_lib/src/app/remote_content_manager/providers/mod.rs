@@ -1,4 +1,5 @@
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use erased_serde::Serialize;
 use serde::Deserialize;
 use std::collections::HashMap;
@@ -20,6 +21,7 @@ pub enum ProviderVehicleType {
     File,
     Http,
     Compatible,
+    Inline,
 }
 
 impl Display for ProviderVehicleType {
@@ -28,6 +30,7 @@ impl Display for ProviderVehicleType {
             ProviderVehicleType::File => write!(f, "File"),
             ProviderVehicleType::Http => write!(f, "HTTP"),
             ProviderVehicleType::Compatible => write!(f, "Compatible"),
+            ProviderVehicleType::Inline => write!(f, "Inline"),
         }
     }
 }
@@ -65,4 +68,10 @@ pub trait Provider {
     async fn update(&self) -> io::Result<()>;
 
     async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>>;
+
+    /// when the content was last fetched or read, the epoch if it never was.
+    /// None for the providers with nothing to fetch
+    async fn updated_at(&self) -> Option<DateTime<Utc>> {
+        None
+    }
 }
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use erased_serde::Serialize;
+
+use crate::{
+    app::remote_content_manager::providers::{Provider, ProviderType, ProviderVehicleType},
+    common::country::country_of,
+    config::internal::proxy::RejectMode,
+    proxy::{reject, AnyOutboundHandler},
+};
+
+use super::{proxy_provider::ProxyProvider, ThreadSafeProxyProvider};
+
+/// the proxies of another provider that are in one country, picked again
+/// on each call so they follow the updates of the provider. The latencies
+/// are tested by the health check of the provider. Without any, e.g. after
+/// an update dropped the country, it is a `REJECT`, so what is routed to
+/// the country never goes out another way
+pub struct CountryProvider {
+    name: String,
+    country: String,
+    source: ThreadSafeProxyProvider,
+    reject: AnyOutboundHandler,
+}
+
+impl CountryProvider {
+    pub fn new(name: String, country: String, source: ThreadSafeProxyProvider) -> Self {
+        Self {
+            name,
+            country,
+            source,
+            reject: reject::Handler::new(RejectMode::Reset),
+        }
+    }
+}
+
+#[async_trait]
+impl Provider for CountryProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn vehicle_type(&self) -> ProviderVehicleType {
+        ProviderVehicleType::Compatible
+    }
+    fn typ(&self) -> ProviderType {
+        ProviderType::Proxy
+    }
+    async fn initialize(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+    async fn update(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
+        let mut m: HashMap<String, Box<dyn Serialize + Send>> = HashMap::new();
+
+        m.insert("name".to_owned(), Box::new(self.name().to_string()));
+        m.insert("type".to_owned(), Box::new(self.typ().to_string()));
+        m.insert(
+            "vehicleType".to_owned(),
+            Box::new(self.vehicle_type().to_string()),
+        );
+
+        m
+    }
+}
+
+#[async_trait]
+impl ProxyProvider for CountryProvider {
+    async fn proxies(&self) -> Vec<AnyOutboundHandler> {
+        let rv: Vec<_> = self
+            .source
+            .read()
+            .await
+            .proxies()
+            .await
+            .into_iter()
+            .filter(|x| country_of(x.name()).as_deref() == Some(self.country.as_str()))
+            .collect();
+        if rv.is_empty() {
+            return vec![self.reject.clone()];
+        }
+        rv
+    }
+
+    async fn touch(&self) {
+        self.source.read().await.touch().await;
+    }
+
+    async fn healthcheck(&self) {
+        self.source.read().await.healthcheck().await;
+    }
+}
@@ -1,7 +1,9 @@
+pub mod country_provider;
 pub mod plain_provider;
 pub mod proxy_provider;
 pub mod proxy_set_provider;
 
+pub use country_provider::CountryProvider;
 pub use plain_provider::PlainProvider;
 pub use proxy_provider::ProxyProvider;
 pub use proxy_provider::ThreadSafeProxyProvider;
@@ -27,12 +27,17 @@ impl PlainProvider {
         proxies: Vec<AnyOutboundHandler>,
         hc: HealthCheck,
     ) -> anyhow::Result<Self> {
-        let hc = Arc::new(hc);
-
         if proxies.is_empty() {
             return Err(Error::InvalidConfig(format!("{}: proxies is empty", name)).into());
         }
 
+        Ok(Self::new_dynamic(name, proxies, hc))
+    }
+
+    /// a provider whose proxies are replaced at runtime, it may be empty
+    pub fn new_dynamic(name: String, proxies: Vec<AnyOutboundHandler>, hc: HealthCheck) -> Self {
+        let hc = Arc::new(hc);
+
         if hc.auto() {
             debug!("kicking off healthcheck: {}", name);
             let hc = hc.clone();
@@ -41,7 +46,12 @@ impl PlainProvider {
             });
         }
 
-        Ok(Self { name, proxies, hc })
+        Self { name, proxies, hc }
+    }
+
+    pub async fn set_proxies(&mut self, proxies: Vec<AnyOutboundHandler>) {
+        self.hc.update(proxies.clone()).await;
+        self.proxies = proxies;
     }
 }
 
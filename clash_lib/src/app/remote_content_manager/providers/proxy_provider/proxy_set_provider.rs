@@ -1,6 +1,7 @@
 use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use erased_serde::Serialize as ESerialize;
 use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
@@ -15,7 +16,7 @@ use crate::{
         providers::{Provider, ProviderType, ProviderVehicleType},
     },
     common::errors::map_io_error,
-    config::internal::proxy::OutboundProxyProtocol,
+    config::internal::{proxy::OutboundProxyProtocol, share_link},
     proxy::{direct, reject, AnyOutboundHandler},
     Error,
 };
@@ -89,21 +90,30 @@ impl ProxySetProvider {
             dyn Fn(&[u8]) -> anyhow::Result<Vec<AnyOutboundHandler>> + Send + Sync + 'static,
         > = Box::new(
             move |input: &[u8]| -> anyhow::Result<Vec<AnyOutboundHandler>> {
-                let scheme: ProviderScheme = serde_yaml::from_slice(input).map_err(|x| {
-                    Error::InvalidConfig(format!("proxy provider parse error {}: {}", n, x))
-                })?;
-                let proxies = scheme.proxies;
+                let proxies = if share_link::is_subscription(input) {
+                    Some(share_link::parse_subscription(input))
+                } else {
+                    let scheme: ProviderScheme = serde_yaml::from_slice(input).map_err(|x| {
+                        Error::InvalidConfig(format!("proxy provider parse error {}: {}", n, x))
+                    })?;
+                    scheme.proxies
+                };
                 if let Some(proxies) = proxies {
                     let proxies = proxies
                         .into_iter()
                         .filter_map(|x| OutboundProxyProtocol::try_from(x).ok())
                         .map(|x| match x {
                             OutboundProxyProtocol::Direct => Ok(direct::Handler::new()),
-                            OutboundProxyProtocol::Reject => Ok(reject::Handler::new()),
+                            OutboundProxyProtocol::Reject(mode) => Ok(reject::Handler::new(mode)),
+                            OutboundProxyProtocol::NamedDirect(d) => {
+                                Ok(direct::Handler::new_named(d.name, d.bind_address))
+                            }
                             OutboundProxyProtocol::Ss(s) => s.try_into(),
-                            OutboundProxyProtocol::Socks5(_) => todo!("socks5 not supported yet"),
+                            OutboundProxyProtocol::Socks5(s) => s.try_into(),
                             OutboundProxyProtocol::Trojan(tr) => tr.try_into(),
                             OutboundProxyProtocol::Vmess(vm) => vm.try_into(),
+                            OutboundProxyProtocol::AnyTls(a) => a.try_into(),
+                            OutboundProxyProtocol::Wireguard(wg) => wg.try_into(),
                         })
                         .collect::<Result<Vec<_>, _>>();
                     Ok(proxies?)
@@ -174,6 +184,10 @@ impl Provider for ProxySetProvider {
 
         m
     }
+
+    async fn updated_at(&self) -> Option<DateTime<Utc>> {
+        Some(self.fetcher.updated_at().await)
+    }
 }
 
 #[async_trait]
@@ -244,13 +258,14 @@ proxies:
 
         let mock_resolver = MockClashResolver::new();
 
-        let latency_manager = ProxyManager::new(Arc::new(mock_resolver));
+        let latency_manager = ProxyManager::new(Arc::new(mock_resolver), None);
         let hc = HealthCheck::new(
             vec![],
             "http://www.google.com".to_owned(),
             0,
             true,
             latency_manager.clone(),
+            None,
         )
         .unwrap();
 
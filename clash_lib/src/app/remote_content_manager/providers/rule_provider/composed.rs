@@ -0,0 +1,393 @@
+//! `type: compose` rule providers, the entries of other rule providers of the
+//! same behavior combined with set operations, from left to right:
+//! ```yaml
+//! ads:
+//!   type: compose
+//!   behavior: domain
+//!   expression: ad-list minus allow-list
+//! ```
+//! `or` adds the entries of the right side, `and` keeps and `minus` drops the
+//! entries of the left side the right side covers. They are computed again
+//! whenever one of the sources is refreshed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Weak},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use erased_serde::Serialize as ESerialize;
+use ipnet::IpNet;
+use tokio::sync::{watch, RwLock};
+use tracing::{debug, warn};
+
+use crate::{
+    app::remote_content_manager::providers::{Provider, ProviderType, ProviderVehicleType},
+    common::{mmdb::MMDB, trie},
+    session::Session,
+    Error,
+};
+
+use super::{
+    rule_provider::{make_rules, Payload, RuleContent},
+    RuleProvider, RuleSetBehavior, ThreadSafeRuleProvider,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOp {
+    Or,
+    And,
+    Minus,
+}
+
+/// `a minus b and c` into `a` and the operations on it
+pub fn parse_expression(expr: &str) -> Result<(String, Vec<(SetOp, String)>), Error> {
+    let mut tokens = expr.split_whitespace();
+    let base = tokens
+        .next()
+        .ok_or_else(|| Error::InvalidConfig("empty rule provider expression".to_owned()))?;
+
+    let mut ops = vec![];
+    while let Some(op) = tokens.next() {
+        let op = match op.to_ascii_lowercase().as_str() {
+            "or" => SetOp::Or,
+            "and" => SetOp::And,
+            "minus" => SetOp::Minus,
+            _ => {
+                return Err(Error::InvalidConfig(format!(
+                    "unknown set operation {} in: {}",
+                    op, expr
+                )))
+            }
+        };
+        let name = tokens.next().ok_or_else(|| {
+            Error::InvalidConfig(format!("missing rule provider at the end of: {}", expr))
+        })?;
+        ops.push((op, name.to_owned()));
+    }
+    Ok((base.to_owned(), ops))
+}
+
+/// the entries of a rule set, to tell which entries of another one they
+/// cover
+struct Cover {
+    exact: HashSet<String>,
+    domains: trie::StringTrie<bool>,
+    /// merged, so that adjacent ranges cover what they span together
+    cidrs: Vec<IpNet>,
+}
+
+impl Cover {
+    fn new(behavior: RuleSetBehavior, entries: &[String]) -> Self {
+        let mut domains = trie::StringTrie::new();
+        let mut cidrs = vec![];
+        for x in entries {
+            match behavior {
+                RuleSetBehavior::Domain => {
+                    domains.insert(x, Arc::new(true));
+                }
+                RuleSetBehavior::IPCIDR => {
+                    if let Ok(net) = x.parse() {
+                        cidrs.push(net);
+                    }
+                }
+                RuleSetBehavior::Classical => {}
+            }
+        }
+        Self {
+            exact: entries.iter().cloned().collect(),
+            domains,
+            cidrs: IpNet::aggregate(&cidrs),
+        }
+    }
+
+    /// a domain pattern is covered when the trie matches it label by label,
+    /// a CIDR when a single merged range contains all of it and a classical
+    /// rule only by itself
+    fn covers(&self, behavior: RuleSetBehavior, entry: &str) -> bool {
+        if self.exact.contains(entry) {
+            return true;
+        }
+        match behavior {
+            RuleSetBehavior::Domain => self.domains.search(entry).is_some(),
+            RuleSetBehavior::IPCIDR => match entry.parse::<IpNet>() {
+                Ok(net) => self.cidrs.iter().any(|x| x.contains(&net)),
+                Err(_) => false,
+            },
+            RuleSetBehavior::Classical => false,
+        }
+    }
+}
+
+fn compose(behavior: RuleSetBehavior, base: &[String], ops: &[(SetOp, Payload)]) -> Vec<String> {
+    let mut rv = base.to_vec();
+    for (op, entries) in ops {
+        match op {
+            SetOp::Or => {
+                let seen = rv.iter().cloned().collect::<HashSet<_>>();
+                rv.extend(entries.iter().filter(|x| !seen.contains(*x)).cloned());
+            }
+            SetOp::And | SetOp::Minus => {
+                let cover = Cover::new(behavior, entries);
+                let keep = *op == SetOp::And;
+                rv.retain(|x| cover.covers(behavior, x) == keep);
+            }
+        }
+    }
+    rv
+}
+
+pub struct ComposedRuleProvider {
+    name: String,
+    behavior: RuleSetBehavior,
+    base: ThreadSafeRuleProvider,
+    ops: Vec<(SetOp, ThreadSafeRuleProvider)>,
+    mmdb: Arc<MMDB>,
+    content: RwLock<RuleContent>,
+    payload: watch::Sender<Payload>,
+    updated_at: RwLock<DateTime<Utc>>,
+}
+
+impl ComposedRuleProvider {
+    pub fn new(
+        name: String,
+        behavior: RuleSetBehavior,
+        base: ThreadSafeRuleProvider,
+        ops: Vec<(SetOp, ThreadSafeRuleProvider)>,
+        mmdb: Arc<MMDB>,
+    ) -> Result<Arc<Self>, Error> {
+        for p in std::iter::once(&base).chain(ops.iter().map(|(_, p)| p)) {
+            if p.behavior() != behavior {
+                return Err(Error::InvalidConfig(format!(
+                    "rule provider {} is {}, but {} is {}",
+                    p.name(),
+                    p.behavior(),
+                    name,
+                    behavior
+                )));
+            }
+        }
+
+        let rv = Arc::new(Self {
+            name,
+            behavior,
+            base,
+            ops,
+            mmdb,
+            content: RwLock::new(RuleContent::empty(behavior)),
+            payload: watch::channel(Payload::default()).0,
+            updated_at: RwLock::new(DateTime::<Utc>::default()),
+        });
+
+        // a weak reference, so that the sources being around doesn't keep a
+        // replaced router's providers alive
+        for source in rv.sources() {
+            let mut rx = source.payload();
+            let me = Arc::downgrade(&rv);
+            tokio::spawn(async move {
+                while rx.changed().await.is_ok() {
+                    match Weak::upgrade(&me) {
+                        Some(me) => me.recompute().await,
+                        None => break,
+                    }
+                }
+            });
+        }
+
+        Ok(rv)
+    }
+
+    fn sources(&self) -> impl Iterator<Item = &ThreadSafeRuleProvider> {
+        std::iter::once(&self.base).chain(self.ops.iter().map(|(_, p)| p))
+    }
+
+    async fn recompute(&self) {
+        let base = self.base.payload().borrow().clone();
+        let ops = self
+            .ops
+            .iter()
+            .map(|(op, p)| (*op, p.payload().borrow().clone()))
+            .collect::<Vec<_>>();
+        let entries = compose(self.behavior, &base, &ops);
+
+        match make_rules(self.behavior, entries.clone(), self.mmdb.clone()) {
+            Ok(content) => {
+                debug!(
+                    "rule provider {} composed of {} entries",
+                    self.name,
+                    entries.len()
+                );
+                *self.content.write().await = content;
+                *self.updated_at.write().await = Utc::now();
+                self.payload.send_replace(Arc::new(entries));
+            }
+            Err(e) => warn!("failed to compose rule provider {}: {}", self.name, e),
+        }
+    }
+}
+
+impl RuleProvider for ComposedRuleProvider {
+    fn search(&self, sess: &Session) -> bool {
+        match self.content.try_read() {
+            Ok(content) => content.search(sess),
+            Err(_) => {
+                debug!("rule provider {} is busy", self.name());
+                false
+            }
+        }
+    }
+
+    fn behavior(&self) -> RuleSetBehavior {
+        self.behavior
+    }
+
+    fn payload(&self) -> watch::Receiver<Payload> {
+        self.payload.subscribe()
+    }
+}
+
+#[async_trait]
+impl Provider for ComposedRuleProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn vehicle_type(&self) -> ProviderVehicleType {
+        ProviderVehicleType::Compatible
+    }
+
+    fn typ(&self) -> ProviderType {
+        ProviderType::Rule
+    }
+
+    /// the sources are initialized on their own, this is computed again as
+    /// each of them is loaded
+    async fn initialize(&self) -> std::io::Result<()> {
+        self.recompute().await;
+        Ok(())
+    }
+
+    async fn update(&self) -> std::io::Result<()> {
+        self.recompute().await;
+        Ok(())
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        let mut m: HashMap<String, Box<dyn ESerialize + Send>> = HashMap::new();
+
+        m.insert("name".to_owned(), Box::new(self.name().to_string()));
+        m.insert("type".to_owned(), Box::new(self.typ().to_string()));
+        m.insert(
+            "vehicleType".to_owned(),
+            Box::new(self.vehicle_type().to_string()),
+        );
+        m.insert(
+            "updatedAt".to_owned(),
+            Box::new(*self.updated_at.read().await),
+        );
+        m.insert("behavior".to_owned(), Box::new(self.behavior().to_string()));
+        m.insert(
+            "ruleCount".to_owned(),
+            Box::new(self.payload.borrow().len()),
+        );
+
+        m
+    }
+
+    async fn updated_at(&self) -> Option<DateTime<Utc>> {
+        Some(*self.updated_at.read().await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{compose, parse_expression, SetOp};
+    use crate::app::remote_content_manager::providers::rule_provider::RuleSetBehavior;
+
+    fn entries(x: &[&str]) -> Arc<Vec<String>> {
+        Arc::new(x.iter().map(|x| x.to_string()).collect())
+    }
+
+    #[test]
+    fn test_parse_expression() {
+        let (base, ops) = parse_expression("ads minus allow AND extra").unwrap();
+        assert_eq!(base, "ads");
+        assert_eq!(
+            ops,
+            vec![
+                (SetOp::Minus, "allow".to_owned()),
+                (SetOp::And, "extra".to_owned())
+            ]
+        );
+        assert!(parse_expression("").is_err());
+        assert!(parse_expression("ads minus").is_err());
+        assert!(parse_expression("ads xor allow").is_err());
+    }
+
+    #[test]
+    fn test_compose_domain() {
+        let ads = entries(&["ads.example.com", "+.tracker.com", "cdn.example.org"]);
+        let allow = entries(&["+.example.org", "ads.example.com"]);
+        let rv = compose(
+            RuleSetBehavior::Domain,
+            &ads,
+            &[(SetOp::Minus, allow.clone())],
+        );
+        assert_eq!(rv, vec!["+.tracker.com".to_owned()]);
+
+        let rv = compose(RuleSetBehavior::Domain, &ads, &[(SetOp::And, allow)]);
+        assert_eq!(
+            rv,
+            vec!["ads.example.com".to_owned(), "cdn.example.org".to_owned()]
+        );
+
+        let rv = compose(
+            RuleSetBehavior::Domain,
+            &entries(&["a.com"]),
+            &[(SetOp::Or, entries(&["a.com", "b.com"]))],
+        );
+        assert_eq!(rv, vec!["a.com".to_owned(), "b.com".to_owned()]);
+    }
+
+    #[test]
+    fn test_compose_ipcidr() {
+        let rv = compose(
+            RuleSetBehavior::IPCIDR,
+            &entries(&["10.1.0.0/16", "10.0.0.0/8", "192.168.0.0/24"]),
+            &[(SetOp::Minus, entries(&["10.0.0.0/12"]))],
+        );
+        assert_eq!(
+            rv,
+            vec!["10.0.0.0/8".to_owned(), "192.168.0.0/24".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_compose_ipcidr_gapped_cover() {
+        // 10.0.0.128/26 is in neither range although both ends of the /24 are
+        let gapped = entries(&["10.0.0.0/25", "10.0.0.192/26"]);
+        let base = entries(&["10.0.0.0/24", "10.0.0.192/27"]);
+        let rv = compose(
+            RuleSetBehavior::IPCIDR,
+            &base,
+            &[(SetOp::Minus, gapped.clone())],
+        );
+        assert_eq!(rv, vec!["10.0.0.0/24".to_owned()]);
+        let rv = compose(RuleSetBehavior::IPCIDR, &base, &[(SetOp::And, gapped)]);
+        assert_eq!(rv, vec!["10.0.0.192/27".to_owned()]);
+
+        // without the gap the ranges cover it together
+        let rv = compose(
+            RuleSetBehavior::IPCIDR,
+            &base,
+            &[(
+                SetOp::And,
+                entries(&["10.0.0.0/25", "10.0.0.128/26", "10.0.0.192/26"]),
+            )],
+        );
+        assert_eq!(rv, base.to_vec());
+    }
+}
@@ -0,0 +1,118 @@
+//! `type: inline` rule providers, their entries listed in the config itself:
+//! ```yaml
+//! lan:
+//!   type: inline
+//!   behavior: ipcidr
+//!   payload:
+//!     - 192.168.0.0/16
+//!     - fd00::/8
+//! ```
+//! They are built along with the router and never change, but can be used
+//! in `RULE-SET` and compose rule providers as any other.
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use erased_serde::Serialize as ESerialize;
+use tokio::sync::watch;
+
+use crate::{
+    app::remote_content_manager::providers::{Provider, ProviderType, ProviderVehicleType},
+    common::mmdb::MMDB,
+    session::Session,
+    Error,
+};
+
+use super::{
+    rule_provider::{make_rules, Payload, RuleContent},
+    RuleProvider, RuleSetBehavior,
+};
+
+pub struct InlineRuleProvider {
+    name: String,
+    behavior: RuleSetBehavior,
+    content: RuleContent,
+    payload: watch::Sender<Payload>,
+    created_at: DateTime<Utc>,
+}
+
+impl InlineRuleProvider {
+    pub fn new(
+        name: String,
+        behavior: RuleSetBehavior,
+        payload: Vec<String>,
+        mmdb: Arc<MMDB>,
+    ) -> Result<Self, Error> {
+        let content = make_rules(behavior, payload.clone(), mmdb)
+            .map_err(|x| Error::InvalidConfig(format!("rule provider {}: {}", name, x)))?;
+        Ok(Self {
+            name,
+            behavior,
+            content,
+            payload: watch::channel(Arc::new(payload)).0,
+            created_at: Utc::now(),
+        })
+    }
+}
+
+impl RuleProvider for InlineRuleProvider {
+    fn search(&self, sess: &Session) -> bool {
+        self.content.search(sess)
+    }
+
+    fn behavior(&self) -> RuleSetBehavior {
+        self.behavior
+    }
+
+    fn payload(&self) -> watch::Receiver<Payload> {
+        self.payload.subscribe()
+    }
+}
+
+#[async_trait]
+impl Provider for InlineRuleProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn vehicle_type(&self) -> ProviderVehicleType {
+        ProviderVehicleType::Inline
+    }
+
+    fn typ(&self) -> ProviderType {
+        ProviderType::Rule
+    }
+
+    /// the entries are compiled in `new`, there is nothing to load
+    async fn initialize(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    async fn update(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        let mut m: HashMap<String, Box<dyn ESerialize + Send>> = HashMap::new();
+
+        m.insert("name".to_owned(), Box::new(self.name().to_string()));
+        m.insert("type".to_owned(), Box::new(self.typ().to_string()));
+        m.insert(
+            "vehicleType".to_owned(),
+            Box::new(self.vehicle_type().to_string()),
+        );
+        m.insert("updatedAt".to_owned(), Box::new(self.created_at));
+        m.insert("behavior".to_owned(), Box::new(self.behavior().to_string()));
+        m.insert(
+            "ruleCount".to_owned(),
+            Box::new(self.payload.borrow().len()),
+        );
+
+        m
+    }
+
+    async fn updated_at(&self) -> Option<DateTime<Utc>> {
+        Some(self.created_at)
+    }
+}
@@ -1,5 +1,11 @@
 mod cidr_trie;
+mod composed;
+mod inline;
+mod mrs;
 mod rule_provider;
 
+pub use composed::{parse_expression, ComposedRuleProvider};
+pub use inline::InlineRuleProvider;
+
 pub use rule_provider::ThreadSafeRuleProvider;
-pub use rule_provider::{RuleProvider, RuleProviderImpl, RuleSetBehavior};
+pub use rule_provider::{RuleProvider, RuleProviderImpl, RuleSetBehavior, RuleSetFormat};
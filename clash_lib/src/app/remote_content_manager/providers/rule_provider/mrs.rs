@@ -0,0 +1,299 @@
+//! mihomo's binary rule sets, `.mrs`: zstd compressed, a header naming the
+//! behavior, then a succinct trie of the reversed domains or the sorted IP
+//! ranges. Decoded back into the entries a yaml rule set would list, so they
+//! compile into the same tries.
+
+use std::{
+    io::{self, Read},
+    net::{IpAddr, Ipv6Addr},
+};
+
+use crate::Error;
+
+use super::RuleSetBehavior;
+
+const MAGIC: [u8; 4] = *b"MRS\x01";
+const BEHAVIOR_DOMAIN: u8 = 0;
+const BEHAVIOR_IPCIDR: u8 = 1;
+
+pub(super) fn decode(behavior: RuleSetBehavior, data: &[u8]) -> Result<Vec<String>, Error> {
+    let invalid = |x: &dyn std::fmt::Display| Error::InvalidConfig(format!("invalid mrs: {}", x));
+
+    let data = zstd::decode_all(data).map_err(|x| invalid(&x))?;
+    let mut r = &data[..];
+
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic).map_err(|x| invalid(&x))?;
+    if magic != MAGIC {
+        return Err(invalid(&"bad magic"));
+    }
+    let expected = match behavior {
+        RuleSetBehavior::Domain => BEHAVIOR_DOMAIN,
+        RuleSetBehavior::IPCIDR => BEHAVIOR_IPCIDR,
+        RuleSetBehavior::Classical => return Err(invalid(&"classical rule sets have no mrs")),
+    };
+    if read_u8(&mut r).map_err(|x| invalid(&x))? != expected {
+        return Err(invalid(&format!("not a {} rule set", behavior)));
+    }
+    let _count = read_len(&mut r).map_err(|x| invalid(&x))?;
+    // reserved
+    let extra = read_len(&mut r).map_err(|x| invalid(&x))?;
+    skip(&mut r, extra).map_err(|x| invalid(&x))?;
+
+    match behavior {
+        RuleSetBehavior::Domain => read_domain_set(&mut r),
+        _ => read_ip_ranges(&mut r),
+    }
+    .map_err(|x| invalid(&x))
+}
+
+fn read_u8(r: &mut &[u8]) -> io::Result<u8> {
+    let mut b = [0u8; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_u64(r: &mut &[u8]) -> io::Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_be_bytes(b))
+}
+
+/// a non negative int64, checked against what is left to read so a corrupt
+/// one can't make us allocate much
+fn read_len(r: &mut &[u8]) -> io::Result<usize> {
+    let len = i64::from_be_bytes(read_u64(r)?.to_be_bytes());
+    usize::try_from(len)
+        .ok()
+        .filter(|x| *x <= r.len())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid length"))
+}
+
+fn skip(r: &mut &[u8], n: usize) -> io::Result<()> {
+    let (_, rest) = r.split_at(n.min(r.len()));
+    *r = rest;
+    Ok(())
+}
+
+fn read_version(r: &mut &[u8]) -> io::Result<()> {
+    match read_u8(r)? {
+        1 => Ok(()),
+        x => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported version {}", x),
+        )),
+    }
+}
+
+fn read_words(r: &mut &[u8]) -> io::Result<Vec<u64>> {
+    let len = read_len(r)?;
+    (0..len).map(|_| read_u64(r)).collect()
+}
+
+fn bit(bm: &[u64], i: usize) -> bool {
+    bm.get(i >> 6).is_some_and(|x| x & (1 << (i & 63)) != 0)
+}
+
+/// the keys of a LOUDS trie: level by level, each node lists its children
+/// as 0 bits of the label bitmap, with their labels, and ends with a 1 bit.
+/// The keys are reversed domains, `+` marking a domain with its subdomains
+fn read_domain_set(r: &mut &[u8]) -> io::Result<Vec<String>> {
+    read_version(r)?;
+    let leaves = read_words(r)?;
+    let label_bitmap = read_words(r)?;
+    let len = read_len(r)?;
+    let labels = r[..len].to_vec();
+    skip(r, len)?;
+
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt domain set");
+    let mut keys: Vec<Vec<u8>> = vec![vec![]];
+    let mut node = 0;
+    let mut label = 0;
+    let mut rv = vec![];
+    for i in 0..label_bitmap.len() * 64 {
+        if node == keys.len() {
+            break;
+        }
+        if bit(&label_bitmap, i) {
+            if bit(&leaves, node) {
+                let mut key = keys[node].clone();
+                key.reverse();
+                rv.push(String::from_utf8(key).map_err(|_| corrupt())?);
+            }
+            node += 1;
+        } else {
+            let mut key = keys[node].clone();
+            key.push(*labels.get(label).ok_or_else(corrupt)?);
+            keys.push(key);
+            label += 1;
+        }
+    }
+    if node != keys.len() {
+        return Err(corrupt());
+    }
+    Ok(rv)
+}
+
+/// inclusive ranges of 16 byte addresses, v4 ones mapped
+fn read_ip_ranges(r: &mut &[u8]) -> io::Result<Vec<String>> {
+    read_version(r)?;
+    let len = read_len(r)?;
+    let mut rv = vec![];
+    for _ in 0..len {
+        let mut from = [0u8; 16];
+        let mut to = [0u8; 16];
+        r.read_exact(&mut from)?;
+        r.read_exact(&mut to)?;
+        let (from, to) = (Ipv6Addr::from(from), Ipv6Addr::from(to));
+        match (from.to_ipv4_mapped(), to.to_ipv4_mapped()) {
+            (Some(from), Some(to)) => rv.extend(range_to_cidrs(
+                u32::from(from) as u128,
+                u32::from(to) as u128,
+                32,
+            )),
+            _ => rv.extend(range_to_cidrs(u128::from(from), u128::from(to), 128)),
+        }
+    }
+    Ok(rv)
+}
+
+/// the fewest CIDRs covering `from..=to`, of `bits` wide addresses
+fn range_to_cidrs(mut from: u128, to: u128, bits: u32) -> Vec<String> {
+    let last = |from: u128, size: u32| from | u128::MAX.checked_shr(128 - size).unwrap_or(0);
+    let mut rv = vec![];
+    while from <= to {
+        // the largest block aligned at `from` that doesn't go past `to`
+        let mut size = from.trailing_zeros().min(bits);
+        while size > 0 && last(from, size) > to {
+            size -= 1;
+        }
+        let ip = match bits {
+            32 => IpAddr::from((from as u32).to_be_bytes()),
+            _ => IpAddr::from(from.to_be_bytes()),
+        };
+        rv.push(format!("{}/{}", ip, bits - size));
+        match last(from, size).checked_add(1) {
+            Some(next) => from = next,
+            None => break,
+        }
+    }
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, range_to_cidrs, MAGIC};
+    use crate::app::remote_content_manager::providers::rule_provider::RuleSetBehavior;
+
+    fn set_bit(bm: &mut Vec<u64>, i: usize) {
+        while i >> 6 >= bm.len() {
+            bm.push(0);
+        }
+        bm[i >> 6] |= 1 << (i & 63);
+    }
+
+    /// the reverse of `read_domain_set`, as mihomo builds it
+    fn domain_set(domains: &[&str]) -> Vec<u8> {
+        let mut keys = domains
+            .iter()
+            .map(|x| x.bytes().rev().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        keys.sort();
+        let (mut leaves, mut label_bitmap, mut labels) = (vec![], vec![], vec![]);
+        let mut queue = vec![(0, keys.len(), 0)];
+        let mut l = 0;
+        let mut i = 0;
+        while i < queue.len() {
+            let (mut s, e, col) = queue[i];
+            if col == keys[s].len() {
+                s += 1;
+                set_bit(&mut leaves, i);
+            }
+            let mut j = s;
+            while j < e {
+                let from = j;
+                while j < e && keys[j][col] == keys[from][col] {
+                    j += 1;
+                }
+                queue.push((from, j, col + 1));
+                labels.push(keys[from][col]);
+                l += 1;
+            }
+            set_bit(&mut label_bitmap, l);
+            l += 1;
+            i += 1;
+        }
+
+        let mut rv = vec![1u8];
+        for words in [&leaves, &label_bitmap] {
+            rv.extend((words.len() as i64).to_be_bytes());
+            words.iter().for_each(|x| rv.extend(x.to_be_bytes()));
+        }
+        rv.extend((labels.len() as i64).to_be_bytes());
+        rv.extend(labels);
+        rv
+    }
+
+    fn mrs(behavior: u8, count: usize, body: &[u8]) -> Vec<u8> {
+        let mut raw = MAGIC.to_vec();
+        raw.push(behavior);
+        raw.extend((count as i64).to_be_bytes());
+        raw.extend(2i64.to_be_bytes());
+        raw.extend([0, 0]);
+        raw.extend(body);
+        zstd::encode_all(&raw[..], 0).unwrap()
+    }
+
+    #[test]
+    fn test_mrs_domain() {
+        let domains = [
+            "+.google.com",
+            "google.com",
+            "a.example.org",
+            "*.b.example.org",
+        ];
+        let data = mrs(0, domains.len(), &domain_set(&domains));
+
+        let mut decoded = decode(RuleSetBehavior::Domain, &data).unwrap();
+        decoded.sort();
+        let mut expected = domains.map(str::to_owned).to_vec();
+        expected.sort();
+        assert_eq!(decoded, expected);
+
+        assert!(decode(RuleSetBehavior::IPCIDR, &data).is_err());
+        assert!(decode(RuleSetBehavior::Classical, &data).is_err());
+        assert!(decode(RuleSetBehavior::Domain, &data[..data.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn test_mrs_ipcidr() {
+        let mut body = vec![1u8];
+        body.extend(2i64.to_be_bytes());
+        let v4 = |x: [u8; 4]| std::net::Ipv4Addr::from(x).to_ipv6_mapped().octets();
+        body.extend(v4([10, 0, 0, 0]));
+        body.extend(v4([10, 255, 255, 255]));
+        body.extend("2001:db8::".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        body.extend(
+            "2001:db8::2"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        let data = mrs(1, 2, &body);
+
+        assert_eq!(
+            decode(RuleSetBehavior::IPCIDR, &data).unwrap(),
+            ["10.0.0.0/8", "2001:db8::/127", "2001:db8::2/128"]
+        );
+    }
+
+    #[test]
+    fn test_range_to_cidrs() {
+        assert_eq!(range_to_cidrs(0, u32::MAX as u128, 32), ["0.0.0.0/0"]);
+        assert_eq!(range_to_cidrs(0, u128::MAX, 128), ["::/0"]);
+        assert_eq!(
+            range_to_cidrs(0x0a000001, 0x0a000004, 32),
+            ["10.0.0.1/32", "10.0.0.2/31", "10.0.0.4/32"]
+        );
+    }
+}
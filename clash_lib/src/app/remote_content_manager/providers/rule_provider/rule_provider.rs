@@ -7,9 +7,11 @@ use std::{
 };
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use erased_serde::Serialize as ESerialize;
 use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
 use tracing::{debug, trace};
 
 use crate::{
@@ -22,7 +24,7 @@ use crate::{
     },
     common::{errors::map_io_error, mmdb::MMDB, trie},
     config::internal::rule::RuleType,
-    session::Session,
+    session::{Session, SocksAddr},
     Error,
 };
 
@@ -33,7 +35,7 @@ struct ProviderScheme {
     pub payload: Vec<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum RuleSetBehavior {
     Domain,
@@ -41,6 +43,38 @@ pub enum RuleSetBehavior {
     Classical,
 }
 
+/// how the file of a rule set is written
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleSetFormat {
+    /// a `payload` list
+    #[default]
+    Yaml,
+    /// an entry per line, `#` or `//` starting a comment line
+    Text,
+    /// mihomo's binary format, domain and ipcidr rule sets only
+    Mrs,
+}
+
+impl RuleSetFormat {
+    fn parse(self, behavior: RuleSetBehavior, input: &[u8]) -> Result<Vec<String>, Error> {
+        match self {
+            RuleSetFormat::Yaml => {
+                let scheme: ProviderScheme = serde_yaml::from_slice(input)
+                    .map_err(|x| Error::InvalidConfig(x.to_string()))?;
+                Ok(scheme.payload)
+            }
+            RuleSetFormat::Text => Ok(String::from_utf8_lossy(input)
+                .lines()
+                .map(str::trim)
+                .filter(|x| !x.is_empty() && !x.starts_with('#') && !x.starts_with("//"))
+                .map(str::to_owned)
+                .collect()),
+            RuleSetFormat::Mrs => super::mrs::decode(behavior, input),
+        }
+    }
+}
+
 impl Display for RuleSetBehavior {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -51,71 +85,126 @@ impl Display for RuleSetBehavior {
     }
 }
 
-enum RuleContent {
+pub(super) enum RuleContent {
     Domain(trie::StringTrie<bool>),
     IPCIDR(CidrTrie),
-    Classical(Vec<Box<dyn RuleMatcher>>),
+    Classical(ClassicalRules),
+}
+
+/// the `DOMAIN`, `DOMAIN-SUFFIX` and `IP-CIDR` rules of a classical rule set
+/// are looked up in tries, the others are tried one by one
+#[derive(Default)]
+pub(super) struct ClassicalRules {
+    domains: trie::StringTrie<bool>,
+    cidrs: Option<CidrTrie>,
+    others: Vec<Box<dyn RuleMatcher>>,
+}
+
+impl ClassicalRules {
+    fn search(&self, sess: &Session) -> bool {
+        let indexed = match &sess.destination {
+            SocksAddr::Domain(domain, _) => self.domains.search(domain).is_some(),
+            SocksAddr::Ip(ip) => self.cidrs.as_ref().is_some_and(|x| x.contains(ip.ip())),
+        };
+        indexed || self.others.iter().any(|x| x.apply(sess))
+    }
+}
+
+impl RuleContent {
+    pub(super) fn empty(behavior: RuleSetBehavior) -> Self {
+        match behavior {
+            RuleSetBehavior::Domain => RuleContent::Domain(trie::StringTrie::new()),
+            RuleSetBehavior::IPCIDR => RuleContent::IPCIDR(CidrTrie::new()),
+            RuleSetBehavior::Classical => RuleContent::Classical(ClassicalRules::default()),
+        }
+    }
+
+    pub(super) fn search(&self, sess: &Session) -> bool {
+        match self {
+            RuleContent::Domain(trie) => trie.search(&sess.destination.host()).is_some(),
+            RuleContent::IPCIDR(trie) => trie.contains(
+                sess.destination
+                    .ip()
+                    .unwrap_or(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))),
+            ),
+            RuleContent::Classical(rules) => rules.search(sess),
+        }
+    }
 }
 
 struct Inner {
     content: RuleContent,
 }
 
+/// the entries of a rule set as they were read, shared with the composed
+/// rule providers built from it
+pub type Payload = Arc<Vec<String>>;
+
 pub trait RuleProvider: Provider {
     fn search(&self, sess: &Session) -> bool;
     fn behavior(&self) -> RuleSetBehavior;
+    /// the entries of the rule set, changing as it is refreshed
+    fn payload(&self) -> watch::Receiver<Payload>;
 }
 
 pub type ThreadSafeRuleProvider = Arc<dyn RuleProvider + Send + Sync>;
 
 pub struct RuleProviderImpl {
     fetcher: Fetcher<
-        Box<dyn Fn(RuleContent) -> BoxFuture<'static, ()> + Send + Sync + 'static>,
-        Box<dyn Fn(&[u8]) -> anyhow::Result<RuleContent> + Send + Sync + 'static>,
+        Box<dyn Fn((RuleContent, Payload)) -> BoxFuture<'static, ()> + Send + Sync + 'static>,
+        Box<dyn Fn(&[u8]) -> anyhow::Result<(RuleContent, Payload)> + Send + Sync + 'static>,
     >,
     inner: std::sync::Arc<tokio::sync::RwLock<Inner>>,
     behavior: RuleSetBehavior,
+    payload: Arc<watch::Sender<Payload>>,
 }
 
 impl RuleProviderImpl {
     pub fn new(
         name: String,
         behovior: RuleSetBehavior,
+        format: RuleSetFormat,
         interval: Duration,
         vehicle: ThreadSafeProviderVehicle,
         mmdb: Arc<MMDB>,
     ) -> Self {
         let inner = Arc::new(tokio::sync::RwLock::new(Inner {
-            content: match behovior {
-                RuleSetBehavior::Domain => RuleContent::Domain(trie::StringTrie::new()),
-                RuleSetBehavior::IPCIDR => RuleContent::IPCIDR(CidrTrie::new()),
-                RuleSetBehavior::Classical => RuleContent::Classical(vec![]),
-            },
+            content: RuleContent::empty(behovior),
         }));
+        let payload = Arc::new(watch::channel(Payload::default()).0);
 
         let inner_clone = inner.clone();
+        let payload_clone = payload.clone();
 
         let n = name.clone();
-        let updater: Box<dyn Fn(RuleContent) -> BoxFuture<'static, ()> + Send + Sync + 'static> =
-            Box::new(move |input: RuleContent| -> BoxFuture<'static, ()> {
+        let updater: Box<
+            dyn Fn((RuleContent, Payload)) -> BoxFuture<'static, ()> + Send + Sync + 'static,
+        > = Box::new(
+            move |(content, entries): (RuleContent, Payload)| -> BoxFuture<'static, ()> {
                 let n = n.clone();
                 let inner: Arc<tokio::sync::RwLock<Inner>> = inner_clone.clone();
+                let payload = payload_clone.clone();
                 Box::pin(async move {
                     let mut inner = inner.write().await;
                     trace!("updated rules for: {}", n);
-                    inner.content = input;
+                    inner.content = content;
+                    payload.send_replace(entries);
                 })
-            });
+            },
+        );
 
         let n = name.clone();
-        let parser: Box<dyn Fn(&[u8]) -> anyhow::Result<RuleContent> + Send + Sync + 'static> =
-            Box::new(move |input: &[u8]| -> anyhow::Result<RuleContent> {
-                let scheme: ProviderScheme = serde_yaml::from_slice(input).map_err(|x| {
-                    Error::InvalidConfig(format!("proxy provider parse error {}: {}", n, x))
+        let parser: Box<
+            dyn Fn(&[u8]) -> anyhow::Result<(RuleContent, Payload)> + Send + Sync + 'static,
+        > = Box::new(
+            move |input: &[u8]| -> anyhow::Result<(RuleContent, Payload)> {
+                let payload = format.parse(behovior, input).map_err(|x| {
+                    Error::InvalidConfig(format!("rule provider parse error {}: {}", n, x))
                 })?;
-                let rules = make_rules(behovior, scheme.payload, mmdb.clone())?;
-                Ok(rules)
-            });
+                let rules = make_rules(behovior, payload.clone(), mmdb.clone())?;
+                Ok((rules, Arc::new(payload)))
+            },
+        );
 
         let fetcher = Fetcher::new(name, interval, vehicle, parser, Some(updater));
 
@@ -123,6 +212,7 @@ impl RuleProviderImpl {
             fetcher,
             inner,
             behavior: behovior,
+            payload,
         }
     }
 }
@@ -133,22 +223,7 @@ impl RuleProvider for RuleProviderImpl {
         let inner = self.inner.try_read();
 
         match inner {
-            Ok(inner) => match &inner.content {
-                RuleContent::Domain(trie) => trie.search(&sess.destination.host()).is_some(),
-                RuleContent::IPCIDR(trie) => trie.contains(
-                    sess.destination
-                        .ip()
-                        .unwrap_or(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))),
-                ),
-                RuleContent::Classical(rules) => {
-                    for rule in rules.iter() {
-                        if rule.apply(sess) {
-                            return true;
-                        }
-                    }
-                    false
-                }
-            },
+            Ok(inner) => inner.content.search(sess),
             Err(_) => {
                 debug!("rule provider {} is busy", self.name());
                 false
@@ -158,6 +233,9 @@ impl RuleProvider for RuleProviderImpl {
     fn behavior(&self) -> RuleSetBehavior {
         self.behavior
     }
+    fn payload(&self) -> watch::Receiver<Payload> {
+        self.payload.subscribe()
+    }
 }
 
 #[async_trait]
@@ -209,9 +287,13 @@ impl Provider for RuleProviderImpl {
 
         m
     }
+
+    async fn updated_at(&self) -> Option<DateTime<Utc>> {
+        Some(self.fetcher.updated_at().await)
+    }
 }
 
-fn make_rules(
+pub(super) fn make_rules(
     behavior: RuleSetBehavior,
     rules: Vec<String>,
     mmdb: Arc<MMDB>,
@@ -241,27 +323,64 @@ fn make_ip_cidr_rules(rules: Vec<String>) -> Result<CidrTrie, Error> {
     Ok(trie)
 }
 
-fn make_classical_rules(
-    rules: Vec<String>,
-    mmdb: Arc<MMDB>,
-) -> Result<Vec<Box<dyn RuleMatcher>>, Error> {
-    let mut rv = vec![];
+fn make_classical_rules(rules: Vec<String>, mmdb: Arc<MMDB>) -> Result<ClassicalRules, Error> {
+    let mut rv = ClassicalRules::default();
     for rule in rules {
-        let parts = rule.split(",").map(str::trim).collect::<Vec<&str>>();
-
         // the rule inside RULE-SET is slightly different from the rule in config
         // the target is always empty as it's holded in the RULE-SET container
-        // let's parse it manually
-        let rule_type = match parts.as_slice() {
-            [proto, payload] => RuleType::new(proto, payload, "", None),
-            [proto, payload, params @ ..] => {
-                RuleType::new(proto, payload, "", Some(params.to_vec()))
-            }
-            _ => Err(Error::InvalidConfig(format!("invalid rule line: {}", rule))),
-        }?;
+        let rule_type = RuleType::new_without_target(&rule)?;
+        if matches!(
+            rule_type,
+            RuleType::GeoSite { .. } | RuleType::Script { .. }
+        ) {
+            return Err(Error::InvalidConfig(format!(
+                "{} is not supported in rule providers: {}",
+                rule_type, rule
+            )));
+        }
 
-        let rule_matcher = map_rule_type(rule_type, mmdb.clone(), None);
-        rv.push(rule_matcher);
+        let indexed = match &rule_type {
+            RuleType::Domain { domain, .. } => rv.domains.insert(domain, Arc::new(true)),
+            RuleType::DomainSuffix { domain_suffix, .. } => rv
+                .domains
+                .insert(&format!("+.{}", domain_suffix), Arc::new(true)),
+            RuleType::IPCIDR { ipnet, .. } => rv
+                .cidrs
+                .get_or_insert_with(CidrTrie::new)
+                .insert(&ipnet.to_string()),
+            _ => false,
+        };
+        if !indexed {
+            rv.others
+                .push(map_rule_type(rule_type, mmdb.clone(), None, None, None));
+        }
     }
     Ok(rv)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{RuleSetBehavior, RuleSetFormat};
+
+    #[test]
+    fn test_rule_set_formats() {
+        let text = b"# ads\n+.doubleclick.net\n\n  // trackers\n tracker.example.com \r\n";
+        assert_eq!(
+            RuleSetFormat::Text
+                .parse(RuleSetBehavior::Domain, text)
+                .unwrap(),
+            ["+.doubleclick.net", "tracker.example.com"]
+        );
+
+        let yaml = b"payload:\n  - '+.doubleclick.net'\n  - tracker.example.com\n";
+        assert_eq!(
+            RuleSetFormat::Yaml
+                .parse(RuleSetBehavior::Domain, yaml)
+                .unwrap(),
+            ["+.doubleclick.net", "tracker.example.com"]
+        );
+        assert!(RuleSetFormat::Mrs
+            .parse(RuleSetBehavior::Domain, yaml)
+            .is_err());
+    }
+}
@@ -0,0 +1,279 @@
+//! Streaming and AI service checks: whether a service serves the exit
+//! region of a proxy, and which region it sees. Each check mimics what the
+//! service's own client does first, the way the usual unlock test scripts
+//! do, so the answers match what users see in the apps.
+
+use std::{io, time::Duration};
+
+use boring::ssl::{SslConnector, SslMethod};
+use http::{
+    header::{ACCEPT_LANGUAGE, LOCATION, USER_AGENT},
+    HeaderMap, Request, StatusCode,
+};
+use hyper::body::HttpBody;
+use hyper_boring::HttpsConnector;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::dns::ThreadSafeDNSResolver,
+    common::errors::{map_io_error, new_io_error},
+    proxy::AnyOutboundHandler,
+};
+
+use super::http_client::LocalConnector;
+
+const BROWSER_UA: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+                          (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+/// the markers looked for are near the start of the pages
+const MAX_BODY: usize = 2 * 1024 * 1024;
+
+/// a title only on Netflix in the regions that license it
+const NETFLIX_LICENSED_TITLE: &str = "https://www.netflix.com/title/70143836";
+/// a Netflix original, available wherever Netflix is
+const NETFLIX_ORIGINAL_TITLE: &str = "https://www.netflix.com/title/81280792";
+const YOUTUBE_PREMIUM: &str = "https://www.youtube.com/premium";
+const CHATGPT_TRACE: &str = "https://chatgpt.com/cdn-cgi/trace";
+const CHATGPT_COMPLIANCE: &str = "https://api.openai.com/compliance/cookie_requirements";
+const CHATGPT_IOS: &str = "https://ios.chat.openai.com/";
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamingService {
+    Netflix,
+    YouTube,
+    ChatGpt,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnlockStatus {
+    Available,
+    /// Netflix serves only its own productions
+    OriginalsOnly,
+    Unavailable,
+    /// the service answered with something the check doesn't know
+    Unknown,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct UnlockResult {
+    pub service: StreamingService,
+    pub status: UnlockStatus,
+    /// the ISO 3166 country code the service sees, if it tells
+    pub region: Option<String>,
+}
+
+type Client = hyper::Client<HttpsConnector<LocalConnector>>;
+
+struct Page {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: String,
+}
+
+pub async fn unlock_test(
+    proxy: AnyOutboundHandler,
+    resolver: ThreadSafeDNSResolver,
+    service: StreamingService,
+    timeout: Duration,
+) -> io::Result<UnlockResult> {
+    let mut ssl = SslConnector::builder(SslMethod::tls()).map_err(map_io_error)?;
+    ssl.set_alpn_protos(b"\x08http/1.1").map_err(map_io_error)?;
+    let connector = HttpsConnector::with_connector(LocalConnector(proxy, resolver), ssl)
+        .map_err(map_io_error)?;
+    let client: Client = hyper::Client::builder().build(connector);
+
+    let test = async {
+        match service {
+            StreamingService::Netflix => netflix(&client).await,
+            StreamingService::YouTube => youtube(&client).await,
+            StreamingService::ChatGpt => chatgpt(&client).await,
+        }
+    };
+    let (status, region) = tokio::time::timeout(timeout, test)
+        .await
+        .map_err(|_| new_io_error("unlock test timed out"))??;
+    Ok(UnlockResult {
+        service,
+        status,
+        region,
+    })
+}
+
+/// fetches `url` without following redirects
+async fn get(client: &Client, url: &str) -> io::Result<Page> {
+    let req = Request::get(url)
+        .header(USER_AGENT, BROWSER_UA)
+        .header(ACCEPT_LANGUAGE, "en")
+        .body(hyper::Body::empty())
+        .map_err(map_io_error)?;
+    let (parts, mut body) = client
+        .request(req)
+        .await
+        .map_err(map_io_error)?
+        .into_parts();
+
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        buf.extend_from_slice(&chunk.map_err(map_io_error)?);
+        if buf.len() >= MAX_BODY {
+            break;
+        }
+    }
+    Ok(Page {
+        status: parts.status,
+        headers: parts.headers,
+        body: String::from_utf8_lossy(&buf).into_owned(),
+    })
+}
+
+/// the 2 letters following `marker` in `body`, upper cased
+fn country_after(body: &str, marker: &str) -> Option<String> {
+    let start = body.find(marker)? + marker.len();
+    let code = body.get(start..start + 2)?;
+    code.chars()
+        .all(|x| x.is_ascii_alphabetic())
+        .then(|| code.to_ascii_uppercase())
+}
+
+/// `https://www.netflix.com/sg-zh/title/81280792` -> `SG`
+fn netflix_region_from_location(location: &str) -> Option<String> {
+    let path = location.split("netflix.com/").nth(1)?;
+    let locale = path.split('/').next()?;
+    let country = locale.split('-').next()?;
+    (country.len() == 2 && country.chars().all(|x| x.is_ascii_alphabetic()))
+        .then(|| country.to_ascii_uppercase())
+}
+
+/// whether Netflix shows a title, and the region it's shown for
+fn netflix_title(page: &Page) -> (bool, Option<String>) {
+    if page.status.is_redirection() {
+        // the regional page of the title
+        let location = page
+            .headers
+            .get(LOCATION)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default();
+        return (
+            location.contains("/title/"),
+            netflix_region_from_location(location),
+        );
+    }
+    (
+        page.status == StatusCode::OK,
+        country_after(&page.body, r#""requestCountry":{"id":""#),
+    )
+}
+
+async fn netflix(client: &Client) -> io::Result<(UnlockStatus, Option<String>)> {
+    let (licensed, original) = tokio::try_join!(
+        get(client, NETFLIX_LICENSED_TITLE),
+        get(client, NETFLIX_ORIGINAL_TITLE)
+    )?;
+    if licensed.status == StatusCode::FORBIDDEN || original.status == StatusCode::FORBIDDEN {
+        return Ok((UnlockStatus::Unavailable, None));
+    }
+
+    let (licensed, licensed_region) = netflix_title(&licensed);
+    let (original, original_region) = netflix_title(&original);
+    let region = licensed_region.or(original_region);
+    Ok(match (licensed, original) {
+        // the US pages have no locale in their paths
+        (true, _) => (
+            UnlockStatus::Available,
+            region.or_else(|| Some("US".to_owned())),
+        ),
+        (false, true) => (UnlockStatus::OriginalsOnly, region),
+        (false, false) => (UnlockStatus::Unavailable, region),
+    })
+}
+
+async fn youtube(client: &Client) -> io::Result<(UnlockStatus, Option<String>)> {
+    let page = get(client, YOUTUBE_PREMIUM).await?;
+    if page.body.contains("www.google.cn") {
+        return Ok((UnlockStatus::Unavailable, Some("CN".to_owned())));
+    }
+
+    let region = country_after(&page.body, r#""INNERTUBE_CONTEXT_GL":""#)
+        .or_else(|| country_after(&page.body, r#""countryCode":""#));
+    let status = if page
+        .body
+        .contains("Premium is not available in your country")
+    {
+        UnlockStatus::Unavailable
+    } else if page.body.to_ascii_lowercase().contains("ad-free") {
+        UnlockStatus::Available
+    } else {
+        UnlockStatus::Unknown
+    };
+    Ok((status, region))
+}
+
+async fn chatgpt(client: &Client) -> io::Result<(UnlockStatus, Option<String>)> {
+    let (trace, compliance, ios) = tokio::try_join!(
+        get(client, CHATGPT_TRACE),
+        get(client, CHATGPT_COMPLIANCE),
+        get(client, CHATGPT_IOS)
+    )?;
+
+    let region = trace
+        .body
+        .lines()
+        .find_map(|x| x.strip_prefix("loc="))
+        .map(|x| x.trim().to_ascii_uppercase());
+    // the web app checks the country, the iOS app the address too
+    let status = if compliance.body.contains("unsupported_country") || ios.body.contains("VPN") {
+        UnlockStatus::Unavailable
+    } else {
+        UnlockStatus::Available
+    };
+    Ok((status, region))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header::LOCATION, HeaderMap, StatusCode};
+
+    use super::{country_after, netflix_region_from_location, netflix_title, Page};
+
+    #[test]
+    fn test_netflix_region() {
+        assert_eq!(
+            netflix_region_from_location("https://www.netflix.com/sg-zh/title/81280792"),
+            Some("SG".to_owned())
+        );
+        assert_eq!(
+            netflix_region_from_location("https://www.netflix.com/title/81280792"),
+            None
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            LOCATION,
+            "https://www.netflix.com/jp/title/70143836".parse().unwrap(),
+        );
+        let page = Page {
+            status: StatusCode::MOVED_PERMANENTLY,
+            headers,
+            body: String::new(),
+        };
+        assert_eq!(netflix_title(&page), (true, Some("JP".to_owned())));
+
+        let page = Page {
+            status: StatusCode::NOT_FOUND,
+            headers: HeaderMap::new(),
+            body: String::new(),
+        };
+        assert_eq!(netflix_title(&page), (false, None));
+    }
+
+    #[test]
+    fn test_country_after() {
+        let body = r#"ytcfg.set({"INNERTUBE_CONTEXT_GL":"de","INNERTUBE_CONTEXT_HL":"en"})"#;
+        assert_eq!(
+            country_after(body, r#""INNERTUBE_CONTEXT_GL":""#),
+            Some("DE".to_owned())
+        );
+        assert_eq!(country_after(r#""GL":"1""#, r#""GL":""#), None);
+    }
+}
@@ -0,0 +1,216 @@
+//! The last routing decisions, so the dozens of connections a browser opens
+//! to the same host go through the rules once. Only kept when no rule looks
+//! past the destination, network and inbound of a session, and cleared as
+//! a rule provider changes. A new config builds a new router, and with it a
+//! new cache.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use lru_time_cache::LruCache;
+
+use crate::{
+    app::remote_content_manager::providers::rule_provider::{
+        RuleSetBehavior, ThreadSafeRuleProvider,
+    },
+    config::internal::rule::RuleType,
+    session::{Network, Session, Type},
+};
+
+const CAPACITY: usize = 4096;
+/// what a domain resolves to, and the domain an IP was answered for, change
+/// over time
+const TTL: Duration = Duration::from_secs(60);
+
+/// the rule a session matched: its `sub-rules` chain, none for `rules`, and
+/// its index there, or none for no rule
+pub type Decision = Option<(Option<String>, usize)>;
+
+/// what the cacheable rules look at
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RouteKey {
+    host: String,
+    /// the domain an IP destination was answered for, with redir-host
+    answered: Option<String>,
+    port: u16,
+    network: Network,
+    subprotocol: Option<String>,
+    typ: Type,
+    inbound: String,
+    in_port: Option<u16>,
+}
+
+impl RouteKey {
+    pub fn new(sess: &Session, answered: Option<String>) -> Self {
+        Self {
+            host: sess.destination.host(),
+            answered,
+            port: sess.destination.port(),
+            network: sess.network,
+            subprotocol: sess.subprotocol.clone(),
+            typ: sess.typ,
+            inbound: sess.inbound.name.clone(),
+            in_port: sess.inbound.port,
+        }
+    }
+}
+
+pub struct RouteCache {
+    decisions: Mutex<LruCache<RouteKey, Decision>>,
+}
+
+impl RouteCache {
+    /// a cache for `rules`, none if one of them looks at more than the key,
+    /// cleared whenever one of `providers` changes
+    pub fn new<'a>(
+        mut rules: impl Iterator<Item = &'a RuleType>,
+        providers: &HashMap<String, ThreadSafeRuleProvider>,
+    ) -> Option<Arc<Self>> {
+        if !rules.all(|r| cacheable(r, providers)) {
+            return None;
+        }
+
+        let rv = Arc::new(Self {
+            decisions: Mutex::new(LruCache::with_expiry_duration_and_capacity(TTL, CAPACITY)),
+        });
+        // a weak reference, so that the providers don't keep a replaced
+        // router's cache alive
+        for p in providers.values() {
+            let mut rx = p.payload();
+            let me = Arc::downgrade(&rv);
+            tokio::spawn(async move {
+                while rx.changed().await.is_ok() {
+                    match me.upgrade() {
+                        Some(me) => me.clear(),
+                        None => break,
+                    }
+                }
+            });
+        }
+        Some(rv)
+    }
+
+    pub fn get(&self, key: &RouteKey) -> Option<Decision> {
+        self.decisions.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: RouteKey, decision: Decision) {
+        self.decisions.lock().unwrap().insert(key, decision);
+    }
+
+    pub fn clear(&self) {
+        self.decisions.lock().unwrap().clear();
+    }
+}
+
+/// whether `r` only looks at what `RouteKey` holds. The entries of
+/// classical rule sets may look at the source or the process
+fn cacheable(r: &RuleType, providers: &HashMap<String, ThreadSafeRuleProvider>) -> bool {
+    match r {
+        RuleType::Domain { .. }
+        | RuleType::DomainSuffix { .. }
+        | RuleType::DomainKeyword { .. }
+        | RuleType::GeoIP { .. }
+        | RuleType::GeoSite { .. }
+        | RuleType::IPCIDR { .. }
+        | RuleType::DSTPort { .. }
+        | RuleType::Network { .. }
+        | RuleType::InType { .. }
+        | RuleType::InPort { .. }
+        | RuleType::InName { .. }
+        | RuleType::Match { .. } => true,
+        RuleType::RuleSet { rule_set, .. } => providers
+            .get(rule_set)
+            .is_some_and(|p| p.behavior() != RuleSetBehavior::Classical),
+        RuleType::SubRule { rule, .. } => cacheable(rule, providers),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        config::internal::rule::RuleType,
+        session::{Network, Session, SocksAddr},
+    };
+
+    use super::{RouteCache, RouteKey};
+
+    #[test]
+    fn test_route_cache() {
+        let rules = |x: &[&str]| {
+            x.iter()
+                .map(|x| x.parse::<RuleType>().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let providers = HashMap::new();
+
+        let cacheable = rules(&[
+            "DOMAIN-SUFFIX,google.com,relay",
+            "SUB-RULE,(NETWORK,udp:443),quic",
+            "IP-CIDR,10.0.0.0/8,DIRECT",
+            "MATCH,relay",
+        ]);
+        let cache = RouteCache::new(cacheable.iter(), &providers).unwrap();
+        for x in [
+            "SRC-IP-CIDR,192.168.1.0/24,DIRECT",
+            "SUB-RULE,(SRC-PORT,7777),chain",
+            "PROCESS-NAME,curl,DIRECT",
+            "RULE-SET,missing,DIRECT",
+        ] {
+            let rules = rules(&[x]);
+            assert!(
+                RouteCache::new(cacheable.iter().chain(&rules), &providers).is_none(),
+                "{}",
+                x
+            );
+        }
+
+        let sess = Session {
+            network: Network::Tcp,
+            destination: SocksAddr::Domain("www.google.com".to_owned(), 443),
+            ..Default::default()
+        };
+        let key = RouteKey::new(&sess, None);
+        assert_eq!(cache.get(&key), None);
+        cache.insert(key.clone(), Some((None, 0)));
+        assert_eq!(cache.get(&key), Some(Some((None, 0))));
+
+        // another source port, the same decision
+        let other = Session {
+            source: "127.0.0.1:50000".parse().unwrap(),
+            ..sess.clone()
+        };
+        assert_eq!(
+            cache.get(&RouteKey::new(&other, None)),
+            Some(Some((None, 0)))
+        );
+        let udp = Session {
+            network: Network::Udp,
+            ..sess.clone()
+        };
+        assert_eq!(cache.get(&RouteKey::new(&udp, None)), None);
+
+        // an IP answered for another domain may match other rules
+        let ip = Session {
+            destination: SocksAddr::Ip("1.2.3.4:443".parse().unwrap()),
+            ..sess.clone()
+        };
+        let key = RouteKey::new(&ip, Some("www.google.com".to_owned()));
+        cache.insert(key.clone(), Some((None, 0)));
+        assert_eq!(cache.get(&key), Some(Some((None, 0))));
+        assert_eq!(cache.get(&RouteKey::new(&ip, None)), None);
+        assert_eq!(
+            cache.get(&RouteKey::new(&ip, Some("www.bing.com".to_owned()))),
+            None
+        );
+
+        cache.clear();
+        assert_eq!(cache.get(&key), None);
+    }
+}
@@ -0,0 +1,254 @@
+//! The `DOMAIN`, `DOMAIN-SUFFIX`, `DOMAIN-KEYWORD`, `IP-CIDR` and
+//! `SRC-IP-CIDR` rules of a list, indexed by what they match so a session
+//! looks up the ones it matches instead of trying each of them. The rules
+//! found, and the ones the index can't tell about, are still tried in the
+//! order they are listed, so the first one matching wins as before.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use aho_corasick::AhoCorasick;
+use ip_network_table_deps_treebitmap::IpLookupTable;
+use ipnet::IpNet;
+
+use crate::{
+    config::internal::rule::RuleType,
+    session::{Session, SocksAddr},
+};
+
+#[derive(Default)]
+pub struct RuleIndex {
+    /// `DOMAIN` rules by their domain
+    domains: HashMap<String, Vec<usize>>,
+    /// `DOMAIN-SUFFIX` rules by their suffix, looked up for the host and
+    /// each of its parent domains
+    suffixes: HashMap<String, Vec<usize>>,
+    /// `DOMAIN-KEYWORD` rules, the position of each keyword
+    keywords: Option<(AhoCorasick, Vec<usize>)>,
+    destinations: CidrIndex,
+    sources: CidrIndex,
+    /// the rules not indexed, always tried
+    others: Vec<usize>,
+    /// the indexed rules resolving a domain when tried, the first one left
+    /// is tried until the domain is looked up
+    resolving: Vec<usize>,
+}
+
+impl RuleIndex {
+    pub fn new(rules: &[RuleType]) -> Self {
+        let mut rv = Self::default();
+        let mut keywords = vec![];
+        let mut destinations = HashMap::<_, Vec<_>>::new();
+        let mut sources = HashMap::<_, Vec<_>>::new();
+
+        for (i, r) in rules.iter().enumerate() {
+            match r {
+                RuleType::Domain { domain, .. } => {
+                    rv.domains.entry(domain.clone()).or_default().push(i)
+                }
+                RuleType::DomainSuffix { domain_suffix, .. } => rv
+                    .suffixes
+                    .entry(domain_suffix.clone())
+                    .or_default()
+                    .push(i),
+                RuleType::DomainKeyword { domain_keyword, .. } => {
+                    keywords.push((domain_keyword.as_str(), i))
+                }
+                RuleType::IPCIDR {
+                    ipnet, no_resolve, ..
+                } => {
+                    destinations.entry(ipnet.trunc()).or_default().push(i);
+                    if !no_resolve {
+                        rv.resolving.push(i);
+                    }
+                }
+                RuleType::SRCIPCIDR { ipnet, .. } => {
+                    sources.entry(ipnet.trunc()).or_default().push(i)
+                }
+                _ => rv.others.push(i),
+            }
+        }
+
+        if !keywords.is_empty() {
+            match AhoCorasick::new(keywords.iter().map(|x| x.0)) {
+                Ok(ac) => rv.keywords = Some((ac, keywords.iter().map(|x| x.1).collect())),
+                // too many to build the automaton, they are tried as others
+                Err(_) => {
+                    rv.others.extend(keywords.iter().map(|x| x.1));
+                    rv.others.sort_unstable();
+                }
+            }
+        }
+        rv.destinations = CidrIndex::new(destinations);
+        rv.sources = CidrIndex::new(sources);
+        rv
+    }
+
+    /// the indexed rules matching `sess`, its destination as the host or
+    /// the IP it is, or `answered`, the domain its IP was answered for
+    pub fn hits(&self, sess: &Session, answered: Option<&Session>) -> BTreeSet<usize> {
+        let mut rv = BTreeSet::new();
+        for s in std::iter::once(sess).chain(answered) {
+            match &s.destination {
+                SocksAddr::Domain(host, _) => self.domain_hits(host, &mut rv),
+                SocksAddr::Ip(addr) => rv.extend(self.destinations.matches(addr.ip())),
+            }
+        }
+        rv.extend(self.sources.matches(sess.source.ip()));
+        rv
+    }
+
+    /// the indexed rules matching a destination IP, once the domain
+    /// resolved to it
+    pub fn ip_hits(&self, ip: IpAddr) -> impl Iterator<Item = usize> + '_ {
+        self.destinations.matches(ip)
+    }
+
+    /// the first rule from `from` worth trying: one not indexed, one of
+    /// `hits`, or, while the domain is to be resolved, one resolving it
+    pub fn next(&self, from: usize, hits: &BTreeSet<usize>, resolve: bool) -> Option<usize> {
+        let at = |x: &[usize]| x.get(x.partition_point(|i| *i < from)).copied();
+        [
+            at(&self.others),
+            hits.range(from..).next().copied(),
+            resolve.then(|| at(&self.resolving)).flatten(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    fn domain_hits(&self, host: &str, rv: &mut BTreeSet<usize>) {
+        if let Some(x) = self.domains.get(host) {
+            rv.extend(x);
+        }
+
+        // a suffix matches the host itself, or after one of its dots
+        let parents = host.match_indices('.').map(|(i, _)| &host[i + 1..]);
+        for suffix in std::iter::once(host).chain(parents) {
+            if let Some(x) = self.suffixes.get(suffix) {
+                rv.extend(x);
+            }
+        }
+
+        if let Some((ac, rules)) = &self.keywords {
+            rv.extend(
+                ac.find_overlapping_iter(host)
+                    .map(|x| rules[x.pattern().as_usize()]),
+            );
+        }
+    }
+}
+
+/// the rules of each network, in prefix trees all the networks an IP is in
+/// are looked up from
+struct CidrIndex {
+    v4: IpLookupTable<Ipv4Addr, Vec<usize>>,
+    v6: IpLookupTable<Ipv6Addr, Vec<usize>>,
+}
+
+impl Default for CidrIndex {
+    fn default() -> Self {
+        Self {
+            v4: IpLookupTable::new(),
+            v6: IpLookupTable::new(),
+        }
+    }
+}
+
+impl CidrIndex {
+    fn new(nets: HashMap<IpNet, Vec<usize>>) -> Self {
+        let mut rv = Self::default();
+        for (net, rules) in nets {
+            match net {
+                IpNet::V4(net) => {
+                    rv.v4.insert(net.addr(), net.prefix_len() as _, rules);
+                }
+                IpNet::V6(net) => {
+                    rv.v6.insert(net.addr(), net.prefix_len() as _, rules);
+                }
+            }
+        }
+        rv
+    }
+
+    fn matches(&self, ip: IpAddr) -> Box<dyn Iterator<Item = usize> + '_> {
+        match ip {
+            IpAddr::V4(ip) => Box::new(self.v4.matches(ip).flat_map(|x| x.2.iter().copied())),
+            IpAddr::V6(ip) => Box::new(self.v6.matches(ip).flat_map(|x| x.2.iter().copied())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use crate::{
+        config::internal::rule::RuleType,
+        session::{Session, SocksAddr},
+    };
+
+    use super::RuleIndex;
+
+    #[test]
+    fn test_rule_index() {
+        let rules = [
+            "DOMAIN,google.com,a",
+            "DOMAIN-SUFFIX,google.com,a",
+            "GEOIP,CN,a",
+            "DOMAIN-KEYWORD,goog,a",
+            "IP-CIDR,10.0.0.0/8,a,no-resolve",
+            "IP-CIDR,10.1.0.0/16,a",
+            "SRC-IP-CIDR,192.168.1.0/24,a,no-resolve",
+            "DOMAIN-SUFFIX,com,a",
+            "IP-CIDR6,fd00::/8,a,no-resolve",
+            "DOMAIN-KEYWORD,ogle,a",
+            "MATCH,a",
+        ]
+        .map(|x| x.parse::<RuleType>().unwrap());
+        let index = RuleIndex::new(&rules);
+
+        let sess = |dst: SocksAddr| Session {
+            source: "192.168.2.1:1000".parse().unwrap(),
+            destination: dst,
+            ..Default::default()
+        };
+        let set = |x: &[usize]| x.iter().copied().collect::<BTreeSet<_>>();
+
+        let www = sess(SocksAddr::Domain("www.google.com".to_owned(), 443));
+        assert_eq!(index.hits(&www, None), set(&[1, 3, 7, 9]));
+        let google = sess(SocksAddr::Domain("google.com".to_owned(), 443));
+        assert_eq!(index.hits(&google, None), set(&[0, 1, 3, 7, 9]));
+        // not a parent domain
+        let other = sess(SocksAddr::Domain("notgoogle.com".to_owned(), 443));
+        assert_eq!(index.hits(&other, None), set(&[3, 7, 9]));
+
+        let ip = sess(SocksAddr::Ip("10.1.2.3:443".parse().unwrap()));
+        assert_eq!(index.hits(&ip, None), set(&[4, 5]));
+        assert_eq!(index.hits(&ip, Some(&google)), set(&[0, 1, 3, 4, 5, 7, 9]));
+        let ip = sess(SocksAddr::Ip("[fd00::1]:443".parse().unwrap()));
+        assert_eq!(index.hits(&ip, None), set(&[8]));
+        assert_eq!(
+            index
+                .ip_hits("10.2.0.1".parse().unwrap())
+                .collect::<Vec<_>>(),
+            [4]
+        );
+
+        let lan = Session {
+            source: "192.168.1.7:1000".parse().unwrap(),
+            ..ip
+        };
+        assert_eq!(index.hits(&lan, None), set(&[6, 8]));
+
+        let hits = set(&[7]);
+        assert_eq!(index.next(0, &hits, true), Some(2));
+        assert_eq!(index.next(3, &hits, true), Some(5));
+        assert_eq!(index.next(3, &hits, false), Some(7));
+        assert_eq!(index.next(8, &hits, true), Some(10));
+        assert_eq!(index.next(11, &hits, true), None);
+    }
+}
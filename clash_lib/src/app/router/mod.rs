@@ -5,32 +5,61 @@ use crate::app::router::rules::ipcidr::IPCIDR;
 use crate::app::router::rules::ruleset::RuleSet;
 use crate::Error;
 
+use crate::common::geosite::GeoSite;
 use crate::common::mmdb::MMDB;
+use crate::common::uid::{owner_process, owner_uid};
 use crate::config::internal::config::RuleProviderDef;
+use crate::config::internal::proxy::{PROXY_REJECT, PROXY_REJECT_DROP, PROXY_REJECT_HTTP};
 use crate::config::internal::rule::RuleType;
 use crate::session::{Session, SocksAddr};
 
 use crate::app::router::rules::final_::Final;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
+use futures::future::BoxFuture;
 use http::Uri;
-use tracing::{error, info};
+use tracing::{debug, error, info};
 
+use self::cache::{RouteCache, RouteKey};
+use self::index::RuleIndex;
 use super::dns::ThreadSafeDNSResolver;
 use super::remote_content_manager::providers::rule_provider::{
-    RuleProviderImpl, ThreadSafeRuleProvider,
+    parse_expression, ComposedRuleProvider, InlineRuleProvider, RuleProviderImpl,
+    ThreadSafeRuleProvider,
 };
 use super::remote_content_manager::providers::{file_vehicle, http_vehicle};
 
+mod cache;
+mod index;
 mod rules;
+mod script;
+mod trace;
 pub use rules::RuleMatcher;
+pub use script::Script;
+pub use trace::{RouteTrace, TraceStep};
 
 pub struct Router {
     rules: Vec<Box<dyn RuleMatcher>>,
-    #[allow(dead_code)]
+    index: RuleIndex,
+    /// the chains of the `SUB-RULE` rules, by name
+    sub_rules: HashMap<String, Vec<Box<dyn RuleMatcher>>>,
+    sub_rule_indexes: HashMap<String, RuleIndex>,
+    /// whether any rule matches a sniffed subprotocol
+    needs_sniffing: bool,
+    /// whether any rule matches the DSCP of tun packets
+    needs_dscp: bool,
+    /// whether any rule matches the user owning the source socket
+    needs_uid: bool,
+    /// whether the script may read the process owning the source socket
+    needs_process: bool,
+    /// the script, asked to pick a proxy ahead of the rules
+    script: Option<Arc<Script>>,
+    /// none when a rule looks at more than the cache is keyed on
+    route_cache: Option<Arc<RouteCache>>,
     rule_provider_registry: HashMap<String, ThreadSafeRuleProvider>,
     dns_resolver: ThreadSafeDNSResolver,
 }
@@ -39,14 +68,32 @@ pub type ThreadSafeRouter = Arc<Router>;
 
 const MATCH: &str = "MATCH";
 
+/// a run through the rules for a session
+struct Matching<'s> {
+    sess: &'s Session,
+    /// `sess`, resolved the first time a rule needs its IP. The rules are
+    /// still applied to `sess` too, for the host next to the IP
+    sess_dup: Session,
+    /// whether the domain was looked up, it is only once
+    resolve_tried: bool,
+    sess_resolved: bool,
+    /// with redir-host, `sess` to the domain its IP was answered for
+    answered: Option<Session>,
+    /// the rules tried, when traced
+    trace: Option<Vec<TraceStep>>,
+}
+
 impl Router {
     pub async fn new(
         rules: Vec<RuleType>,
+        sub_rules: HashMap<String, Vec<RuleType>>,
         rule_providers: HashMap<String, RuleProviderDef>,
         dns_resolver: ThreadSafeDNSResolver,
         mmdb: Arc<MMDB>,
+        geosite: Option<Arc<GeoSite>>,
+        script: Option<Arc<Script>>,
         cwd: String,
-    ) -> Self {
+    ) -> Result<Self, Error> {
         let mut rule_provider_registry = HashMap::new();
 
         Self::load_rule_providers(
@@ -56,52 +103,329 @@ impl Router {
             mmdb.clone(),
             cwd,
         )
-        .await
-        .ok();
+        .await?;
+
+        let mut conditions = rules
+            .iter()
+            .chain(sub_rules.values().flatten())
+            .map(|r| match r {
+                RuleType::SubRule { rule, .. } => rule.as_ref(),
+                _ => r,
+            });
+        let needs_sniffing = conditions
+            .clone()
+            .any(|r| matches!(r, RuleType::Network { network, .. } if network == "ws"));
+        let needs_dscp = conditions
+            .clone()
+            .any(|r| matches!(r, RuleType::Dscp { .. }));
+        let needs_uid = conditions.any(|r| matches!(r, RuleType::Uid { .. }));
+        let needs_process = script.as_ref().is_some_and(|x| x.uses_process());
+
+        let route_cache = RouteCache::new(
+            rules.iter().chain(sub_rules.values().flatten()),
+            &rule_provider_registry,
+        );
+        let index = RuleIndex::new(&rules);
+        let sub_rule_indexes = sub_rules
+            .iter()
+            .map(|(name, rules)| (name.clone(), RuleIndex::new(rules)))
+            .collect();
+
+        let map_rules = |rules: Vec<RuleType>| {
+            rules
+                .into_iter()
+                .map(|r| {
+                    map_rule_type(
+                        r,
+                        mmdb.clone(),
+                        geosite.as_deref(),
+                        script.as_ref(),
+                        Some(&rule_provider_registry),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
 
-        Self {
-            rules: rules
+        Ok(Self {
+            needs_sniffing,
+            needs_dscp,
+            needs_uid,
+            needs_process,
+            route_cache,
+            rules: map_rules(rules),
+            index,
+            sub_rules: sub_rules
                 .into_iter()
-                .map(|r| map_rule_type(r, mmdb.clone(), Some(&rule_provider_registry)))
+                .map(|(name, rules)| (name, map_rules(rules)))
                 .collect(),
+            sub_rule_indexes,
             dns_resolver,
             rule_provider_registry,
-        }
+            script,
+        })
     }
 
     pub async fn match_route<'a>(
         &'a self,
         sess: &'a Session,
-    ) -> (&str, Option<&Box<dyn RuleMatcher>>) {
-        let mut sess_resolved = false;
-        let mut sess_dup = sess.clone();
-
-        for r in self.rules.iter() {
-            if sess.destination.is_domain() && r.should_resolve_ip() && !sess_resolved {
-                if let Ok(ip) = self
-                    .dns_resolver
-                    .resolve(sess.destination.domain().unwrap(), false)
-                    .await
-                {
-                    if let Some(ip) = ip {
-                        sess_dup.destination = SocksAddr::from((ip, sess.destination.port()));
-                        sess_resolved = true;
-                    }
-                }
+    ) -> (Cow<'a, str>, Option<&Box<dyn RuleMatcher>>) {
+        self.lookup_owner(sess).await;
+        if let Some(target) = self.script.as_ref().and_then(|x| x.pick(sess)) {
+            info!("matched {} to target {}[script]", sess, target);
+            return (target.into(), None);
+        }
+
+        let mut m = self.matching(sess, false);
+        let key = self
+            .route_cache
+            .as_ref()
+            .map(|_| RouteKey::new(sess, m.answered.as_ref().map(|x| x.destination.host())));
+        let cached = self.route_cache.as_ref().zip(key.as_ref());
+        if let Some(decision) = cached.and_then(|(cache, key)| cache.get(key)) {
+            let r = decision.and_then(|(chain, i)| self.rule_at(chain.as_deref(), i));
+            if let Some(r) = r {
+                info!(
+                    "matched {} to target {}[{}], cached",
+                    sess,
+                    r.target(),
+                    r.type_name()
+                );
             }
+            return (r.map_or(MATCH, |r| r.target()).into(), r);
+        }
+
+        let matched = self.match_rules(None, &mut m).await;
+        // the IP rules would have matched another time if the domain failed
+        // to resolve
+        let failed = m.resolve_tried && !m.sess_resolved;
+        if let Some((cache, key)) = self.route_cache.as_ref().zip(key).filter(|_| !failed) {
+            cache.insert(key, matched.map(|(chain, i)| (chain.map(str::to_owned), i)));
+        }
 
-            if r.apply(&sess_dup) {
+        match matched.and_then(|(chain, i)| self.rule_at(chain, i)) {
+            Some(r) => {
                 info!(
                     "matched {} to target {}[{}]",
-                    &sess_dup,
+                    &m.sess_dup,
                     r.target(),
                     r.type_name()
                 );
-                return (r.target(), Some(r));
+                (r.target().into(), Some(r))
+            }
+            None => (MATCH.into(), None),
+        }
+    }
+
+    /// `match_route` without routing anything, with every rule tried on the
+    /// way
+    pub async fn trace_route(&self, sess: &Session) -> RouteTrace {
+        self.lookup_owner(sess).await;
+        if let Some(target) = self.script.as_ref().and_then(|x| x.pick(sess)) {
+            return RouteTrace {
+                proxy: target,
+                resolved_ip: None,
+                answered_for: None,
+                steps: vec![],
+            };
+        }
+
+        let mut m = self.matching(sess, true);
+        let matched = self
+            .match_rules(None, &mut m)
+            .await
+            .and_then(|(chain, i)| self.rule_at(chain, i));
+
+        RouteTrace {
+            proxy: matched.map_or(MATCH, |r| r.target()).to_owned(),
+            resolved_ip: m
+                .sess_resolved
+                .then(|| m.sess_dup.destination.ip())
+                .flatten(),
+            answered_for: m.answered.map(|x| x.destination.host()),
+            steps: m.trace.unwrap_or_default(),
+        }
+    }
+
+    /// the owner of the source socket, the user for the UID rules and the
+    /// process for the script, read from procfs off the runtime once per
+    /// session. Ahead of the copies the rules are matched on
+    async fn lookup_owner(&self, sess: &Session) {
+        let uid = self.needs_uid && sess.uid.get().is_none();
+        let process = self.needs_process && sess.process.get().is_none();
+        if !uid && !process {
+            return;
+        }
+        let (network, source) = (sess.network, sess.source);
+        let owner = tokio::task::spawn_blocking(move || {
+            (
+                uid.then(|| owner_uid(network, source)),
+                process.then(|| owner_process(network, source)),
+            )
+        })
+        .await;
+        let (found_uid, found_process) = owner.unwrap_or_default();
+        if let Some(x) = found_uid {
+            let _ = sess.uid.set(x);
+        }
+        if let Some(x) = found_process {
+            let _ = sess.process.set(x);
+        }
+    }
+
+    fn matching<'s>(&self, sess: &'s Session, trace: bool) -> Matching<'s> {
+        // with redir-host, the domain rules match the domain the IP was
+        // answered for
+        let answered = match &sess.destination {
+            SocksAddr::Ip(addr) => self.dns_resolver.domain_of(addr.ip()).map(|domain| {
+                let mut sess = sess.clone();
+                sess.destination = SocksAddr::Domain(domain, addr.port());
+                sess
+            }),
+            SocksAddr::Domain(..) => None,
+        };
+        Matching {
+            sess,
+            sess_dup: sess.clone(),
+            resolve_tried: false,
+            sess_resolved: false,
+            answered,
+            trace: trace.then(Vec::new),
+        }
+    }
+
+    /// the rules of `chain`, or `rules` when none, with their index
+    fn rule_list(&self, chain: Option<&str>) -> Option<(&[Box<dyn RuleMatcher>], &RuleIndex)> {
+        match chain {
+            None => Some((&self.rules, &self.index)),
+            Some(name) => Some((self.sub_rules.get(name)?, self.sub_rule_indexes.get(name)?)),
+        }
+    }
+
+    /// a rule by its chain, none for `rules`, and its index there
+    fn rule_at(&self, chain: Option<&str>, index: usize) -> Option<&Box<dyn RuleMatcher>> {
+        self.rule_list(chain)?.0.get(index)
+    }
+
+    /// the chain and index of the first rule of `chain` matching, going
+    /// into the chain of a matching `SUB-RULE` and on past it when nothing
+    /// there matches. Only the rules the index can't rule out are tried, in
+    /// order. The config makes sure chains don't jump back into each other
+    fn match_rules<'a, 'b, 's>(
+        &'a self,
+        chain: Option<&'a str>,
+        m: &'b mut Matching<'s>,
+    ) -> BoxFuture<'b, Option<(Option<&'a str>, usize)>>
+    where
+        'a: 'b,
+        's: 'b,
+    {
+        Box::pin(async move {
+            let (rules, index) = self.rule_list(chain)?;
+            let mut hits = index.hits(&m.sess_dup, m.answered.as_ref());
+            // a chain entered once an IP rule resolved the domain, its
+            // domain rules still see the host
+            if m.sess_resolved {
+                hits.extend(index.hits(m.sess, None));
             }
+            let mut from = 0;
+            while let Some(i) = index.next(
+                from,
+                &hits,
+                m.sess.destination.is_domain() && !m.resolve_tried,
+            ) {
+                from = i + 1;
+                let r = &rules[i];
+                let resolved = m.sess_resolved;
+                let matched = self.apply(r.as_ref(), m).await;
+                if let Some(trace) = &mut m.trace {
+                    trace.push(TraceStep::new(chain, i, r.as_ref(), matched));
+                }
+                if matched {
+                    let Some(name) = r.sub_rule() else {
+                        return Some((chain, i));
+                    };
+                    if let Some(rv) = self.match_rules(Some(name), m).await {
+                        return Some(rv);
+                    }
+                }
+                // the IP rules past this one see the IP the domain resolved to
+                if !resolved && m.sess_resolved {
+                    if let Some(ip) = m.sess_dup.destination.ip() {
+                        hits.extend(index.ip_hits(ip));
+                    }
+                }
+            }
+            None
+        })
+    }
+
+    /// whether `r` matches the session, resolved first if `r` needs its IP
+    async fn apply(&self, r: &dyn RuleMatcher, m: &mut Matching<'_>) -> bool {
+        if r.should_resolve_ip() {
+            self.resolve(m).await;
         }
 
-        (MATCH, None)
+        r.apply(&m.sess_dup)
+            // the domain rules, and the domain entries of a rule set, still
+            // see the host once it is resolved
+            || (m.sess_resolved && r.apply(m.sess))
+            || m.answered.as_ref().is_some_and(|x| r.apply(x))
+    }
+
+    /// looks the domain of the session up into `sess_dup`, once, so the
+    /// rules that only match hosts never wait on DNS
+    async fn resolve(&self, m: &mut Matching<'_>) {
+        let sess = m.sess;
+        let SocksAddr::Domain(host, port) = &sess.destination else {
+            return;
+        };
+        if m.resolve_tried {
+            return;
+        }
+        m.resolve_tried = true;
+
+        match self.dns_resolver.resolve(host, false).await {
+            Ok(Some(ip)) => {
+                m.sess_dup.destination = SocksAddr::from((ip, *port));
+                m.sess_resolved = true;
+            }
+            Ok(None) => debug!("{} resolved to no IP, IP rules won't match it", host),
+            Err(e) => debug!("failed to resolve {}, IP rules won't match it: {}", host, e),
+        }
+    }
+
+    /// the first rule of `chain` `sess` matches, going through the chains
+    /// of the `SUB-RULE` rules, without resolving it
+    fn first_match(&self, chain: Option<&str>, sess: &Session) -> Option<&dyn RuleMatcher> {
+        let (rules, index) = self.rule_list(chain)?;
+        let hits = index.hits(sess, None);
+        std::iter::successors(index.next(0, &hits, false), |i| {
+            index.next(i + 1, &hits, false)
+        })
+        .map(|i| &rules[i])
+        .filter(|r| r.apply(sess))
+        .find_map(|r| match r.sub_rule() {
+            Some(name) => self.first_match(Some(name), sess),
+            None => Some(r.as_ref()),
+        })
+    }
+
+    /// whether the first rule `sess` matches, without resolving it, rejects
+    /// it
+    pub fn rejects(&self, sess: &Session) -> bool {
+        self.first_match(None, sess).is_some_and(|r| {
+            [PROXY_REJECT, PROXY_REJECT_DROP, PROXY_REJECT_HTTP].contains(&r.target())
+        })
+    }
+
+    /// connections have to be sniffed before they are routed
+    pub fn needs_sniffing(&self) -> bool {
+        self.needs_sniffing
+    }
+
+    /// tun has to keep the DSCP of the packets it reads
+    pub fn needs_dscp(&self) -> bool {
+        self.needs_dscp
     }
 
     async fn load_rule_providers(
@@ -111,13 +435,15 @@ impl Router {
         mmdb: Arc<MMDB>,
         cwd: String,
     ) -> Result<(), Error> {
+        let mut composed = vec![];
         for (name, provider) in rule_providers.into_iter() {
             match provider {
                 RuleProviderDef::Http(http) => {
+                    let url = http.url.parse::<Uri>().map_err(|x| {
+                        Error::InvalidConfig(format!("rule provider {}: {}", name, x))
+                    })?;
                     let vehicle = http_vehicle::Vehicle::new(
-                        http.url
-                            .parse::<Uri>()
-                            .expect(format!("invalid provider url: {}", http.url).as_str()),
+                        url,
                         http.path,
                         Some(cwd.clone()),
                         resolver.clone(),
@@ -126,6 +452,7 @@ impl Router {
                     let provider = RuleProviderImpl::new(
                         name.clone(),
                         http.behavior,
+                        http.format,
                         Duration::from_secs(http.interval),
                         Arc::new(vehicle),
                         mmdb.clone(),
@@ -144,6 +471,7 @@ impl Router {
                     let provider = RuleProviderImpl::new(
                         name.clone(),
                         file.behavior,
+                        file.format,
                         Duration::from_secs(file.interval.unwrap_or_default()),
                         Arc::new(vehicle),
                         mmdb.clone(),
@@ -151,7 +479,55 @@ impl Router {
 
                     rule_provider_registry.insert(name, Arc::new(provider));
                 }
+                RuleProviderDef::Inline(inline) => {
+                    let provider = InlineRuleProvider::new(
+                        name.clone(),
+                        inline.behavior,
+                        inline.payload,
+                        mmdb.clone(),
+                    )?;
+                    rule_provider_registry.insert(name, Arc::new(provider));
+                }
+                RuleProviderDef::Compose(compose) => {
+                    let (base, ops) = parse_expression(&compose.expression).map_err(|x| {
+                        Error::InvalidConfig(format!("rule provider {}: {}", name, x))
+                    })?;
+                    composed.push((name, compose.behavior, base, ops));
+                }
+            }
+        }
+
+        // composed providers may be built on each other, each is added once
+        // all of its sources are
+        while !composed.is_empty() {
+            let (ready, pending): (Vec<_>, Vec<_>) =
+                composed.into_iter().partition(|(_, _, base, ops)| {
+                    std::iter::once(base)
+                        .chain(ops.iter().map(|(_, x)| x))
+                        .all(|x| rule_provider_registry.contains_key(x))
+                });
+            if ready.is_empty() {
+                let names = pending
+                    .iter()
+                    .map(|(name, ..)| name.as_str())
+                    .collect::<Vec<_>>();
+                return Err(Error::InvalidConfig(format!(
+                    "rule providers {} refer to unknown rule providers or to each other",
+                    names.join(", ")
+                )));
+            }
+
+            for (name, behavior, base, ops) in ready {
+                let base = rule_provider_registry[&base].clone();
+                let ops = ops
+                    .into_iter()
+                    .map(|(op, x)| (op, rule_provider_registry[&x].clone()))
+                    .collect();
+                let provider =
+                    ComposedRuleProvider::new(name.clone(), behavior, base, ops, mmdb.clone())?;
+                rule_provider_registry.insert(name, provider);
             }
+            composed = pending;
         }
 
         for p in rule_provider_registry.values() {
@@ -176,11 +552,21 @@ impl Router {
     pub fn get_all_rules(&self) -> &Vec<Box<dyn RuleMatcher>> {
         &self.rules
     }
+
+    pub fn get_sub_rules(&self) -> &HashMap<String, Vec<Box<dyn RuleMatcher>>> {
+        &self.sub_rules
+    }
+
+    pub fn get_rule_providers(&self) -> &HashMap<String, ThreadSafeRuleProvider> {
+        &self.rule_provider_registry
+    }
 }
 
 pub fn map_rule_type(
     rule_type: RuleType,
     mmdb: Arc<MMDB>,
+    geosite: Option<&GeoSite>,
+    script: Option<&Arc<Script>>,
     rule_provider_registry: Option<&HashMap<String, ThreadSafeRuleProvider>>,
 ) -> Box<dyn RuleMatcher> {
     match rule_type {
@@ -224,14 +610,31 @@ pub fn map_rule_type(
 
         RuleType::GeoIP {
             target,
-            country_code,
+            country_codes,
+            negate,
             no_resolve,
         } => Box::new(rules::geoip::GeoIP {
             target,
-            country_code,
+            country_codes,
+            negate,
             no_resolve,
             mmdb: mmdb.clone(),
         }),
+        RuleType::GeoSite {
+            target,
+            country_code,
+        } => {
+            // the codes are checked when the geosite is loaded
+            let matcher = geosite
+                .expect("GEOSITE rules need a geosite")
+                .matcher(&country_code)
+                .expect(format!("geosite {} not found", country_code).as_str());
+            Box::new(rules::geosite::GeoSite {
+                target,
+                code: country_code,
+                matcher,
+            })
+        }
         RuleType::SRCPort { target, port } => Box::new(rules::port::Port {
             port,
             target,
@@ -242,6 +645,17 @@ pub fn map_rule_type(
             target,
             is_src: false,
         }),
+        RuleType::SRCDevice { name, target } => Box::new(rules::device::SrcDevice { name, target }),
+        RuleType::Network {
+            network,
+            ports,
+            target,
+        } => Box::new(rules::network::Network {
+            network,
+            ports,
+            target,
+        }),
+        RuleType::Dscp { dscp, target } => Box::new(rules::network::Dscp { dscp, target }),
         RuleType::ProcessName {
             process_name,
             target,
@@ -258,7 +672,17 @@ pub fn map_rule_type(
             target,
             name_only: false,
         }),
-        RuleType::RuleSet { rule_set, target } => match rule_provider_registry {
+        RuleType::Uid { uids, target } => Box::new(rules::uid::Uid { uids, target }),
+        RuleType::InType { in_type, target } => {
+            Box::new(rules::inbound::InType { in_type, target })
+        }
+        RuleType::InPort { port, target } => Box::new(rules::inbound::InPort { port, target }),
+        RuleType::InName { name, target } => Box::new(rules::inbound::InName { name, target }),
+        RuleType::RuleSet {
+            rule_set,
+            target,
+            no_resolve,
+        } => match rule_provider_registry {
             Some(rule_provider_registry) => Box::new(RuleSet::new(
                 rule_set.clone(),
                 target,
@@ -266,9 +690,177 @@ pub fn map_rule_type(
                     .get(&rule_set)
                     .expect(format!("rule provider {} not found", rule_set).as_str())
                     .clone(),
+                no_resolve,
             )),
             None => unreachable!("you shouldn't next rule-set within another rule-set"),
         },
+        RuleType::SubRule { rule, sub_rule } => Box::new(rules::sub_rule::SubRule {
+            rule: map_rule_type(*rule, mmdb, geosite, script, rule_provider_registry),
+            sub_rule,
+        }),
+        RuleType::Script { shortcut, target } => Box::new(rules::script::Script {
+            shortcut,
+            target,
+            // the shortcuts are checked with the config
+            script: script.expect("SCRIPT rules need a script").clone(),
+        }),
         RuleType::Match { target } => Box::new(Final { target }),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use crate::{
+        app::dns::MockClashResolver,
+        common::{http::new_http_client, mmdb::MMDB},
+        config::internal::rule::RuleType,
+        session::{Inbound, Session, SocksAddr, Type},
+    };
+
+    use super::Router;
+
+    /// a router over `rules`, resolving every domain to 1.2.3.4
+    async fn router(rules: &[&str]) -> Router {
+        router_with_sub_rules(rules, &[]).await
+    }
+
+    /// a router over `rules` and the `SUB-RULE` chains of `sub_rules`
+    async fn router_with_sub_rules(rules: &[&str], sub_rules: &[(&str, &[&str])]) -> Router {
+        let mut resolver = MockClashResolver::new();
+        resolver
+            .expect_resolve()
+            .returning(|_, _| Ok(Some("1.2.3.4".parse().unwrap())));
+        resolver.expect_domain_of().returning(|_| None);
+        let resolver = Arc::new(resolver);
+        let mmdb = MMDB::new(
+            "tests/data/Country.mmdb",
+            None,
+            new_http_client(resolver.clone()).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let parse = |rules: &[&str]| {
+            rules
+                .iter()
+                .map(|x| x.parse::<RuleType>().unwrap())
+                .collect::<Vec<_>>()
+        };
+        Router::new(
+            parse(rules),
+            sub_rules
+                .iter()
+                .map(|(name, rules)| (name.to_string(), parse(rules)))
+                .collect(),
+            HashMap::new(),
+            resolver,
+            Arc::new(mmdb),
+            None,
+            None,
+            ".".to_owned(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_match_route_resolved() {
+        let router = router(&[
+            "IP-CIDR,10.0.0.0/8,a",
+            "DOMAIN-SUFFIX,example.com,b",
+            "IP-CIDR,1.2.3.0/24,c",
+            "MATCH,d",
+        ])
+        .await;
+
+        let sess = |host: &str| Session {
+            destination: SocksAddr::Domain(host.to_owned(), 443),
+            ..Default::default()
+        };
+        // resolved for the first rule, the host still matches the second
+        assert_eq!(router.match_route(&sess("www.example.com")).await.0, "b");
+        assert_eq!(router.match_route(&sess("www.example.org")).await.0, "c");
+    }
+
+    #[tokio::test]
+    async fn test_match_sub_rule_resolved() {
+        let router = router_with_sub_rules(
+            &[
+                "IP-CIDR,10.0.0.0/8,a",
+                "SUB-RULE,(DST-PORT,443),chain",
+                "MATCH,d",
+            ],
+            &[("chain", &["DOMAIN-SUFFIX,example.com,b"])],
+        )
+        .await;
+
+        let sess = |host: &str| Session {
+            destination: SocksAddr::Domain(host.to_owned(), 443),
+            ..Default::default()
+        };
+        // resolved by the first rule before the chain is entered, its
+        // domain rule still matches the host
+        assert_eq!(router.match_route(&sess("www.example.com")).await.0, "b");
+        assert_eq!(router.match_route(&sess("www.example.org")).await.0, "d");
+    }
+
+    #[tokio::test]
+    async fn test_trace_route() {
+        let router = router(&[
+            "IN-NAME,ss-in,a",
+            "IN-PORT,7890,b",
+            "IN-TYPE,SOCKS,c",
+            "UID,1000,d",
+            "IP-CIDR,1.2.3.0/24,e",
+            "MATCH,f",
+        ])
+        .await;
+
+        let sess = Session {
+            destination: SocksAddr::Domain("example.com".to_owned(), 443),
+            inbound: Inbound {
+                name: "mixed-in".to_owned(),
+                port: Some(7890),
+                mode: None,
+            },
+            ..Default::default()
+        };
+        let trace = router.trace_route(&sess).await;
+        assert_eq!(trace.proxy, "b");
+        assert_eq!(
+            trace
+                .steps
+                .iter()
+                .map(|x| (x.index, x.matched))
+                .collect::<Vec<_>>(),
+            [(0, false), (1, true)]
+        );
+        assert_eq!(trace.resolved_ip, None);
+
+        let sess = Session {
+            destination: SocksAddr::Domain("example.com".to_owned(), 443),
+            typ: Type::Socks5,
+            ..Default::default()
+        };
+        assert_eq!(router.trace_route(&sess).await.proxy, "c");
+
+        let sess = Session {
+            destination: SocksAddr::Domain("example.com".to_owned(), 443),
+            ..Default::default()
+        };
+        sess.uid.set(Some(1000)).unwrap();
+        assert_eq!(router.trace_route(&sess).await.proxy, "d");
+
+        let sess = Session {
+            destination: SocksAddr::Domain("example.com".to_owned(), 443),
+            ..Default::default()
+        };
+        sess.uid.set(None).unwrap();
+        let trace = router.trace_route(&sess).await;
+        assert_eq!(trace.proxy, "e");
+        assert_eq!(trace.resolved_ip, Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(trace.steps.len(), 5);
+    }
+}
@@ -0,0 +1,28 @@
+use crate::app::router::rules::RuleMatcher;
+use crate::session::Session;
+
+/// matches the device name resolved from the DHCP leases or static mapping
+pub struct SrcDevice {
+    pub name: String,
+    pub target: String,
+}
+
+impl RuleMatcher for SrcDevice {
+    fn apply(&self, sess: &Session) -> bool {
+        sess.device
+            .as_ref()
+            .is_some_and(|x| x.eq_ignore_ascii_case(&self.name))
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.name.clone()
+    }
+
+    fn type_name(&self) -> &str {
+        "SrcDevice"
+    }
+}
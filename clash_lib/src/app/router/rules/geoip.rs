@@ -1,7 +1,5 @@
 use std::sync::Arc;
 
-use tracing::debug;
-
 use crate::{common::mmdb, session::Session};
 
 use super::RuleMatcher;
@@ -9,7 +7,8 @@ use super::RuleMatcher;
 #[derive(Clone)]
 pub struct GeoIP {
     pub target: String,
-    pub country_code: String,
+    pub country_codes: Vec<String>,
+    pub negate: bool,
     pub no_resolve: bool,
     pub mmdb: Arc<mmdb::MMDB>,
 }
@@ -17,20 +16,17 @@ pub struct GeoIP {
 impl RuleMatcher for GeoIP {
     fn apply(&self, sess: &Session) -> bool {
         match sess.destination {
-            crate::session::SocksAddr::Ip(addr) => match self.mmdb.lookup(addr.ip()) {
-                Ok(country) => {
-                    country
-                        .country
-                        .map(|x| x.iso_code)
-                        .unwrap_or_default()
-                        .unwrap_or_default()
-                        == self.country_code
-                }
-                Err(e) => {
-                    debug!("GeoIP lookup failed: {}", e);
-                    false
-                }
-            },
+            crate::session::SocksAddr::Ip(addr) => {
+                let ip = addr.ip();
+                let found = self.country_codes.iter().any(|code| {
+                    if code.eq_ignore_ascii_case(mmdb::LAN) {
+                        mmdb::is_lan(ip)
+                    } else {
+                        self.mmdb.contains_cached(ip, code, &sess.country)
+                    }
+                });
+                found != self.negate
+            }
             crate::session::SocksAddr::Domain(_, _) => false,
         }
     }
@@ -43,7 +39,11 @@ impl RuleMatcher for GeoIP {
     }
 
     fn payload(&self) -> String {
-        self.country_code.clone()
+        format!(
+            "{}{}",
+            if self.negate { "!" } else { "" },
+            self.country_codes.join("/")
+        )
     }
 
     fn type_name(&self) -> &str {
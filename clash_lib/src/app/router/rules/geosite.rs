@@ -0,0 +1,64 @@
+use std::sync::Arc;
+
+use crate::{
+    common::geosite::DomainMatcher,
+    session::{Session, SocksAddr},
+};
+
+use super::RuleMatcher;
+
+#[derive(Clone)]
+pub struct GeoSite {
+    pub target: String,
+    /// the category, with its attribute if any
+    pub code: String,
+    pub matcher: Arc<DomainMatcher>,
+}
+
+impl RuleMatcher for GeoSite {
+    fn apply(&self, sess: &Session) -> bool {
+        match &sess.destination {
+            SocksAddr::Ip(_) => false,
+            SocksAddr::Domain(domain, _) => self.matcher.matches(domain),
+        }
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.code.clone()
+    }
+
+    fn type_name(&self) -> &str {
+        "GeoSite"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        app::router::rules::RuleMatcher,
+        common::geosite::{tests::test_geosite_dat, GeoSite},
+        session::{Session, SocksAddr},
+    };
+
+    #[test]
+    fn test_geosite_rule() {
+        let geosite = GeoSite::parse(&test_geosite_dat()).unwrap();
+        let rule = super::GeoSite {
+            target: "DIRECT".to_owned(),
+            code: "google@cn".to_owned(),
+            matcher: geosite.matcher("google@cn").unwrap(),
+        };
+        let sess = |destination| Session {
+            destination,
+            ..Default::default()
+        };
+
+        assert!(rule.apply(&sess(SocksAddr::Domain("www.google.cn".to_owned(), 443))));
+        assert!(!rule.apply(&sess(SocksAddr::Domain("www.google.com".to_owned(), 443))));
+        assert!(!rule.apply(&sess(SocksAddr::Ip("1.1.1.1:443".parse().unwrap()))));
+    }
+}
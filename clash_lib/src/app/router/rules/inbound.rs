@@ -0,0 +1,122 @@
+use crate::app::router::rules::RuleMatcher;
+use crate::common::port_set::PortSet;
+use crate::session::{Session, Type};
+
+/// matches the type of the listener a session arrived on
+pub struct InType {
+    pub in_type: Vec<Type>,
+    pub target: String,
+}
+
+impl RuleMatcher for InType {
+    fn apply(&self, sess: &Session) -> bool {
+        self.in_type.contains(&sess.typ)
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.in_type
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    fn type_name(&self) -> &str {
+        "InType"
+    }
+}
+
+/// matches the port of the listener a session arrived on
+pub struct InPort {
+    pub port: PortSet,
+    pub target: String,
+}
+
+impl RuleMatcher for InPort {
+    fn apply(&self, sess: &Session) -> bool {
+        sess.inbound.port.is_some_and(|x| self.port.contains(x))
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.port.to_string()
+    }
+
+    fn type_name(&self) -> &str {
+        "InPort"
+    }
+}
+
+/// matches the name of the listener a session arrived on
+pub struct InName {
+    pub name: String,
+    pub target: String,
+}
+
+impl RuleMatcher for InName {
+    fn apply(&self, sess: &Session) -> bool {
+        sess.inbound.name == self.name
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.name.clone()
+    }
+
+    fn type_name(&self) -> &str {
+        "InName"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::router::rules::RuleMatcher;
+    use crate::session::{Inbound, Session, Type};
+
+    use super::{InName, InPort, InType};
+
+    #[test]
+    fn test_inbound_rules() {
+        let sess = Session {
+            typ: Type::Socks5,
+            inbound: Inbound {
+                name: "lan".to_owned(),
+                port: Some(7891),
+                mode: None,
+            },
+            ..Default::default()
+        };
+
+        let rule = InType {
+            in_type: vec![Type::Socks4, Type::Socks5],
+            target: "DIRECT".to_owned(),
+        };
+        assert!(rule.apply(&sess));
+        assert_eq!(rule.payload(), "SOCKS4/SOCKS5");
+        assert!(!rule.apply(&Session::default()));
+
+        let rule = InPort {
+            port: "7890-7899".parse().unwrap(),
+            target: "DIRECT".to_owned(),
+        };
+        assert!(rule.apply(&sess));
+        assert!(!rule.apply(&Session::default()));
+
+        let rule = InName {
+            name: "lan".to_owned(),
+            target: "DIRECT".to_owned(),
+        };
+        assert!(rule.apply(&sess));
+        assert!(!rule.apply(&Session::default()));
+    }
+}
@@ -23,8 +23,9 @@ impl RuleMatcher for IPCIDR {
         self.target.as_str()
     }
 
+    /// the source is always an IP
     fn should_resolve_ip(&self) -> bool {
-        !self.no_resolve
+        !self.no_resolve && !self.match_src
     }
 
     fn payload(&self) -> String {
@@ -4,15 +4,22 @@ use erased_serde::Serialize;
 
 use crate::session::Session;
 
+pub mod device;
 pub mod domain;
 pub mod domain_keyword;
 pub mod domain_suffix;
 pub mod final_;
 pub mod geoip;
+pub mod geosite;
+pub mod inbound;
 pub mod ipcidr;
+pub mod network;
 pub mod port;
 pub mod process;
 pub mod ruleset;
+pub mod script;
+pub mod sub_rule;
+pub mod uid;
 
 pub trait RuleMatcher: Send + Sync + Unpin {
     /// check if the rule should apply to the session
@@ -31,6 +38,12 @@ pub trait RuleMatcher: Send + Sync + Unpin {
         false
     }
 
+    /// the chain of `sub-rules` to go through when this matches, rather
+    /// than routing to `target`
+    fn sub_rule(&self) -> Option<&str> {
+        None
+    }
+
     fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
         let mut m: HashMap<String, Box<dyn Serialize + Send>> = HashMap::new();
         m.insert("type".to_string(), Box::new(self.type_name().to_owned()));
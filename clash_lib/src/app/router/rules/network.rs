@@ -0,0 +1,66 @@
+use crate::app::router::rules::RuleMatcher;
+use crate::common::port_set::PortSet;
+use crate::session::{self, Session};
+
+/// matches the transport network, or a subprotocol sniffed from it, and
+/// the destination port if any are given
+pub struct Network {
+    pub network: String,
+    pub ports: Option<PortSet>,
+    pub target: String,
+}
+
+impl RuleMatcher for Network {
+    fn apply(&self, sess: &Session) -> bool {
+        let network = match self.network.as_str() {
+            "tcp" => sess.network == session::Network::Tcp,
+            "udp" => sess.network == session::Network::Udp,
+            subprotocol => sess.subprotocol.as_deref() == Some(subprotocol),
+        };
+        network
+            && self
+                .ports
+                .as_ref()
+                .is_none_or(|x| x.contains(sess.destination.port()))
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        match &self.ports {
+            Some(ports) => format!("{}:{}", self.network, ports),
+            None => self.network.clone(),
+        }
+    }
+
+    fn type_name(&self) -> &str {
+        "Network"
+    }
+}
+
+/// matches the DSCP of the packets of tun sessions
+pub struct Dscp {
+    pub dscp: PortSet,
+    pub target: String,
+}
+
+impl RuleMatcher for Dscp {
+    fn apply(&self, sess: &Session) -> bool {
+        sess.dscp()
+            .is_some_and(|x| self.dscp.contains(u16::from(x)))
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.dscp.to_string()
+    }
+
+    fn type_name(&self) -> &str {
+        "Dscp"
+    }
+}
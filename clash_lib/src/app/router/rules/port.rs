@@ -1,9 +1,10 @@
 use crate::app::router::rules::RuleMatcher;
+use crate::common::port_set::PortSet;
 use crate::session::Session;
 
 #[derive(Clone)]
 pub struct Port {
-    pub port: u16,
+    pub port: PortSet,
     pub target: String,
     pub is_src: bool,
 }
@@ -11,9 +12,9 @@ pub struct Port {
 impl RuleMatcher for Port {
     fn apply(&self, sess: &Session) -> bool {
         if self.is_src {
-            sess.source.port() == self.port
+            self.port.contains(sess.source.port())
         } else {
-            sess.destination.port() == self.port
+            self.port.contains(sess.destination.port())
         }
     }
 
@@ -1,4 +1,6 @@
-use crate::app::remote_content_manager::providers::rule_provider::ThreadSafeRuleProvider;
+use crate::app::remote_content_manager::providers::rule_provider::{
+    RuleSetBehavior, ThreadSafeRuleProvider,
+};
 use crate::app::router::rules::RuleMatcher;
 use crate::session::Session;
 
@@ -7,14 +9,21 @@ pub struct RuleSet {
     pub rule_set: String,
     pub target: String,
     pub rule_provider: ThreadSafeRuleProvider,
+    pub no_resolve: bool,
 }
 
 impl RuleSet {
-    pub fn new(rule_set: String, target: String, rule_provider: ThreadSafeRuleProvider) -> Self {
+    pub fn new(
+        rule_set: String,
+        target: String,
+        rule_provider: ThreadSafeRuleProvider,
+        no_resolve: bool,
+    ) -> Self {
         Self {
             rule_set,
             target,
             rule_provider,
+            no_resolve,
         }
     }
 }
@@ -35,4 +44,9 @@ impl RuleMatcher for RuleSet {
     fn type_name(&self) -> &str {
         "RuleSet"
     }
+
+    /// the entries of a domain rule set only match a host
+    fn should_resolve_ip(&self) -> bool {
+        !self.no_resolve && self.rule_provider.behavior() != RuleSetBehavior::Domain
+    }
 }
@@ -0,0 +1,29 @@
+use std::sync::Arc;
+
+use crate::{app::router::script, session::Session};
+
+use super::RuleMatcher;
+
+pub struct Script {
+    pub shortcut: String,
+    pub target: String,
+    pub script: Arc<script::Script>,
+}
+
+impl RuleMatcher for Script {
+    fn apply(&self, sess: &Session) -> bool {
+        self.script.matches(&self.shortcut, sess)
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.shortcut.clone()
+    }
+
+    fn type_name(&self) -> &str {
+        "Script"
+    }
+}
@@ -0,0 +1,36 @@
+use crate::session::Session;
+
+use super::RuleMatcher;
+
+/// the condition of a `SUB-RULE`, the router goes through the chain once
+/// it matches
+pub struct SubRule {
+    pub rule: Box<dyn RuleMatcher>,
+    pub sub_rule: String,
+}
+
+impl RuleMatcher for SubRule {
+    fn apply(&self, sess: &Session) -> bool {
+        self.rule.apply(sess)
+    }
+
+    fn target(&self) -> &str {
+        self.sub_rule.as_str()
+    }
+
+    fn payload(&self) -> String {
+        format!("({},{})", self.rule.type_name(), self.rule.payload())
+    }
+
+    fn type_name(&self) -> &str {
+        "SubRule"
+    }
+
+    fn should_resolve_ip(&self) -> bool {
+        self.rule.should_resolve_ip()
+    }
+
+    fn sub_rule(&self) -> Option<&str> {
+        Some(self.sub_rule.as_str())
+    }
+}
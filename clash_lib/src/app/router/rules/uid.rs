@@ -0,0 +1,61 @@
+use std::ops::RangeInclusive;
+
+use crate::app::router::rules::RuleMatcher;
+use crate::common::uid::owner_uid;
+use crate::session::Session;
+
+pub struct Uid {
+    pub uids: RangeInclusive<u32>,
+    pub target: String,
+}
+
+impl RuleMatcher for Uid {
+    /// the router looks the owner up ahead, off the runtime, unless the
+    /// rule comes from a rule set
+    fn apply(&self, sess: &Session) -> bool {
+        sess.uid
+            .get_or_init(|| owner_uid(sess.network, sess.source))
+            .is_some_and(|x| self.uids.contains(&x))
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        if self.uids.start() == self.uids.end() {
+            self.uids.start().to_string()
+        } else {
+            format!("{}-{}", self.uids.start(), self.uids.end())
+        }
+    }
+
+    fn type_name(&self) -> &str {
+        "Uid"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{app::router::rules::RuleMatcher, session::Session};
+
+    use super::Uid;
+
+    #[test]
+    fn test_uid_looked_up_once() {
+        let rule = |uids| Uid {
+            uids,
+            target: "DIRECT".to_owned(),
+        };
+        // as the router left it, the sockets of the host aren't read again
+        let sess = Session::default();
+        sess.uid.set(Some(1000)).unwrap();
+        assert!(rule(1000..=1000).apply(&sess));
+        assert!(rule(500..=2000).apply(&sess));
+        assert!(!rule(0..=0).apply(&sess));
+
+        let sess = Session::default();
+        sess.uid.set(None).unwrap();
+        assert!(!rule(0..=u32::MAX).apply(&sess));
+    }
+}
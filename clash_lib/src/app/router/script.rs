@@ -0,0 +1,328 @@
+//! The `script` shortcuts of the `SCRIPT` rules, rhai expressions on the
+//! metadata of a session, and its `main` function picking the proxy ahead
+//! of the rules. Rhai has no access to files or the network, and each run
+//! is stopped once it takes longer than the timeout. Runs are synchronous,
+//! on the runtime thread routing the session, so the timeout is capped to
+//! keep a slow script from stalling the other sessions there.
+
+use crate::{config::def, session::Session, Error};
+
+#[cfg(feature = "script")]
+use std::{cell::Cell, collections::HashMap, time::Duration, time::Instant};
+
+#[cfg(feature = "script")]
+use rhai::{Dynamic, Engine, Map, Scope, AST, INT};
+#[cfg(feature = "script")]
+use tracing::{debug, warn};
+
+#[cfg(feature = "script")]
+use crate::session::SocksAddr;
+
+#[cfg(feature = "script")]
+thread_local! {
+    /// when the run on this thread has to stop
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// the longest a run may take, in milliseconds
+#[cfg(feature = "script")]
+const MAX_TIMEOUT: u64 = 100;
+
+pub struct Script {
+    #[cfg(feature = "script")]
+    engine: Engine,
+    #[cfg(feature = "script")]
+    shortcuts: HashMap<String, AST>,
+    /// the functions of `code`, when one of them is `main(metadata)`
+    #[cfg(feature = "script")]
+    main: Option<AST>,
+    /// whether the script mentions `process`, which is only looked up then
+    #[cfg(feature = "script")]
+    uses_process: bool,
+    #[cfg(feature = "script")]
+    timeout: Duration,
+}
+
+#[cfg(not(feature = "script"))]
+impl Script {
+    pub fn new(_: def::Script) -> Result<Self, Error> {
+        Err(Error::InvalidConfig(
+            "script is set but clash was built without the `script` feature".to_owned(),
+        ))
+    }
+
+    pub fn matches(&self, _: &str, _: &Session) -> bool {
+        false
+    }
+
+    pub fn pick(&self, _: &Session) -> Option<String> {
+        None
+    }
+
+    pub fn uses_process(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(feature = "script")]
+impl Script {
+    pub fn new(cfg: def::Script) -> Result<Self, Error> {
+        let mut engine = Engine::new();
+        engine
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(1 << 16)
+            .set_max_array_size(1 << 12)
+            .set_max_map_size(1 << 12)
+            .disable_symbol("eval")
+            .on_print(|x| debug!("script: {}", x))
+            .on_debug(|x, _, pos| debug!("script {}: {}", pos, x))
+            // the clock is read every so many operations
+            .on_progress(|ops| {
+                let late = ops % 1024 == 0
+                    && DEADLINE.with(|x| x.get().is_some_and(|x| Instant::now() > x));
+                late.then_some(Dynamic::UNIT)
+            });
+
+        let invalid = |name: &str, e: &dyn std::fmt::Display| {
+            Error::InvalidConfig(format!("script {}: {}", name, e))
+        };
+        let code = engine
+            .compile(&cfg.code)
+            .map_err(|e| invalid("code", &e))?
+            .clone_functions_only();
+        let main = code
+            .iter_functions()
+            .any(|f| f.name == "main" && f.params.len() == 1)
+            .then(|| code.clone());
+        let uses_process =
+            cfg.code.contains("process") || cfg.shortcuts.values().any(|x| x.contains("process"));
+        let shortcuts = cfg
+            .shortcuts
+            .into_iter()
+            .map(|(name, expr)| {
+                let ast = engine
+                    .compile_expression(&expr)
+                    .map_err(|e| invalid(&name, &e))?;
+                Ok((name, code.merge(&ast)))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        if cfg.timeout > MAX_TIMEOUT {
+            warn!(
+                "script timeout of {}ms capped to {}ms",
+                cfg.timeout, MAX_TIMEOUT
+            );
+        }
+
+        Ok(Self {
+            engine,
+            shortcuts,
+            main,
+            uses_process,
+            timeout: Duration::from_millis(cfg.timeout.min(MAX_TIMEOUT)),
+        })
+    }
+
+    /// whether the script may read the `process` of a session, which has to
+    /// be looked up before it runs
+    pub fn uses_process(&self) -> bool {
+        self.uses_process
+    }
+
+    /// the proxy `main` picks for `sess`, none when there is no `main`, it
+    /// fails or runs out of time, or it returns nothing or an empty string
+    /// to leave `sess` to the rules
+    pub fn pick(&self, sess: &Session) -> Option<String> {
+        let main = self.main.as_ref()?;
+        let rv = self.run(|| {
+            self.engine
+                .call_fn::<Dynamic>(&mut Scope::new(), main, "main", (metadata(sess),))
+        });
+
+        match rv.map(|x| (x.is_unit(), x.into_string())) {
+            Ok((true, _)) => None,
+            Ok((_, Ok(target))) => Some(target).filter(|x| !x.is_empty()),
+            Ok((_, Err(typ))) => {
+                warn!("script main returned a {} for {}", typ, sess);
+                None
+            }
+            Err(e) => {
+                warn!("script main failed for {}: {}", sess, e);
+                None
+            }
+        }
+    }
+
+    /// whether the shortcut evaluates to true for `sess`, false when it
+    /// fails or runs out of time
+    pub fn matches(&self, shortcut: &str, sess: &Session) -> bool {
+        let Some(ast) = self.shortcuts.get(shortcut) else {
+            return false;
+        };
+
+        let mut scope = Scope::new();
+        for (name, value) in metadata(sess) {
+            scope.push_constant_dynamic(name, value);
+        }
+        let rv = self.run(|| self.engine.eval_ast_with_scope::<bool>(&mut scope, ast));
+
+        rv.unwrap_or_else(|e| {
+            warn!("script shortcut {} failed for {}: {}", shortcut, sess, e);
+            false
+        })
+    }
+
+    /// `f` stopped once the timeout passes
+    fn run<T>(&self, f: impl FnOnce() -> T) -> T {
+        DEADLINE.with(|x| x.set(Some(Instant::now() + self.timeout)));
+        let rv = f();
+        DEADLINE.with(|x| x.set(None));
+        rv
+    }
+}
+
+/// what the scripts see of `sess`, the constants of the shortcuts and the
+/// map `main` is called with. `process` is empty until looked up
+#[cfg(feature = "script")]
+fn metadata(sess: &Session) -> Map {
+    [
+        (
+            "network",
+            Dynamic::from(sess.network.to_string().to_lowercase()),
+        ),
+        ("type", Dynamic::from(sess.typ.to_string())),
+        ("src_ip", Dynamic::from(sess.source.ip().to_string())),
+        ("src_port", Dynamic::from(sess.source.port() as INT)),
+        (
+            "dst_ip",
+            Dynamic::from(match &sess.destination {
+                SocksAddr::Ip(addr) => addr.ip().to_string(),
+                SocksAddr::Domain(..) => String::new(),
+            }),
+        ),
+        ("dst_port", Dynamic::from(sess.destination.port() as INT)),
+        ("host", Dynamic::from(sess.destination.host())),
+        ("in_name", Dynamic::from(sess.inbound.name.clone())),
+        (
+            "in_port",
+            Dynamic::from(sess.inbound.port.unwrap_or_default() as INT),
+        ),
+        ("user", Dynamic::from(sess.user.clone().unwrap_or_default())),
+        (
+            "process",
+            Dynamic::from(sess.process.get().cloned().flatten().unwrap_or_default()),
+        ),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.into(), value))
+    .collect()
+}
+
+#[cfg(all(test, feature = "script"))]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Script;
+    use crate::{
+        config::def,
+        session::{Network, Session, SocksAddr},
+    };
+
+    #[test]
+    fn test_script_shortcuts() {
+        let script = Script::new(def::Script {
+            code: "fn is_quic(network, port) { network == \"udp\" && port == 443 }\n\
+                   fn spin() { loop {} }"
+                .to_owned(),
+            shortcuts: [
+                ("quic", "is_quic(network, dst_port)"),
+                ("google", "host.ends_with(\"google.com\")"),
+                ("spin", "spin()"),
+                ("number", "1 + 1"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect(),
+            timeout: 10,
+        })
+        .unwrap();
+
+        let sess = Session {
+            network: Network::Udp,
+            destination: SocksAddr::Domain("www.google.com".to_owned(), 443),
+            ..Default::default()
+        };
+        assert!(script.matches("quic", &sess));
+        assert!(script.matches("google", &sess));
+        assert!(!script.matches("number", &sess));
+        assert!(!script.matches("missing", &sess));
+
+        let tcp = Session {
+            network: Network::Tcp,
+            ..sess.clone()
+        };
+        assert!(!script.matches("quic", &tcp));
+
+        let start = Instant::now();
+        assert!(!script.matches("spin", &sess));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        let invalid = |code: &str, shortcut: &str| {
+            Script::new(def::Script {
+                code: code.to_owned(),
+                shortcuts: [("x".to_owned(), shortcut.to_owned())].into(),
+                timeout: 10,
+            })
+            .is_err()
+        };
+        assert!(invalid("fn f( {", "true"));
+        assert!(invalid("", "let x = 1; x"));
+    }
+
+    #[test]
+    fn test_script_main() {
+        let script = Script::new(def::Script {
+            code: "fn main(metadata) {\n\
+                   if metadata.process == \"curl\" { return \"DIRECT\"; }\n\
+                   if metadata.host.ends_with(\"google.com\") { return \"relay\"; }\n\
+                   if metadata.dst_port == 22 { return 22; }\n\
+                   if metadata.dst_port == 23 { loop {} }\n\
+                   }"
+            .to_owned(),
+            shortcuts: Default::default(),
+            timeout: 1000,
+        })
+        .unwrap();
+        assert!(script.uses_process());
+
+        let sess = |host: &str, port| Session {
+            destination: SocksAddr::Domain(host.to_owned(), port),
+            ..Default::default()
+        };
+        assert_eq!(
+            script.pick(&sess("www.google.com", 443)).as_deref(),
+            Some("relay")
+        );
+        // left to the rules
+        assert_eq!(script.pick(&sess("www.bing.com", 443)), None);
+        assert_eq!(script.pick(&sess("www.bing.com", 22)), None);
+
+        let curl = sess("www.bing.com", 443);
+        curl.process.set(Some("curl".to_owned())).unwrap();
+        assert_eq!(script.pick(&curl).as_deref(), Some("DIRECT"));
+
+        // the timeout is capped
+        let start = Instant::now();
+        assert_eq!(script.pick(&sess("www.bing.com", 23)), None);
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let script = Script::new(def::Script {
+            code: "fn pick(metadata) { \"relay\" }".to_owned(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(!script.uses_process());
+        assert_eq!(script.pick(&sess("www.google.com", 443)), None);
+    }
+}
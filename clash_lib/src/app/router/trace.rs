@@ -0,0 +1,53 @@
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+use super::RuleMatcher;
+
+/// how `Router::trace_route` went through the rules for a session
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteTrace {
+    /// where the session would be routed
+    pub proxy: String,
+    /// the IP the domain resolved to, once a rule needed one
+    #[serde(rename = "resolvedIP")]
+    pub resolved_ip: Option<IpAddr>,
+    /// with redir-host, the domain the destination IP was answered for
+    pub answered_for: Option<String>,
+    /// every rule tried, the last one matched is the rule routed by. None
+    /// when the `main` of the script picked the proxy
+    pub steps: Vec<TraceStep>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceStep {
+    /// the `sub-rules` chain of the rule, none for `rules`
+    pub chain: Option<String>,
+    pub index: usize,
+    #[serde(rename = "type")]
+    pub typ: String,
+    pub payload: String,
+    /// the proxy, or the chain of a `SUB-RULE`
+    pub proxy: String,
+    pub matched: bool,
+}
+
+impl TraceStep {
+    pub(super) fn new(
+        chain: Option<&str>,
+        index: usize,
+        r: &dyn RuleMatcher,
+        matched: bool,
+    ) -> Self {
+        Self {
+            chain: chain.map(str::to_owned),
+            index,
+            typ: r.type_name().to_owned(),
+            payload: r.payload(),
+            proxy: r.target().to_owned(),
+            matched,
+        }
+    }
+}
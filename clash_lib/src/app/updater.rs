@@ -0,0 +1,218 @@
+//! Checks the GitHub releases for a newer build, `GET /upgrade`, and puts
+//! it in place of the running binary, `POST /upgrade`.
+//!
+//! A release is only installed if `<asset>.sig`, the ed25519 signature of
+//! the binary, checks out against the configured public key, see
+//! `scripts/build.sh`.
+
+use std::{path::Path, sync::Arc};
+
+use boring::{pkey::PKey, sign::Verifier};
+use http::{header, Request};
+use hyper::Body;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::{
+    app::dns::SystemResolver,
+    common::http::new_http_client,
+    config::{def, remote},
+    Error,
+};
+
+const CURRENT: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpgradeInfo {
+    pub current: String,
+    pub latest: String,
+    pub has_update: bool,
+    /// the release asset built for this platform
+    pub asset: String,
+}
+
+pub struct Updater {
+    repo: String,
+    public_key: Option<String>,
+    /// one upgrade at a time
+    upgrading: Mutex<()>,
+}
+
+impl Updater {
+    pub fn new(cfg: def::Updater) -> Self {
+        Self {
+            repo: cfg.repo,
+            public_key: cfg.public_key,
+            upgrading: Mutex::new(()),
+        }
+    }
+
+    pub async fn check(&self) -> Result<UpgradeInfo, Error> {
+        // redirects to the tag of the latest release, which saves parsing
+        // the JSON of the API
+        let url = format!("https://github.com/{}/releases/latest", self.repo);
+        let resolver = Arc::new(SystemResolver::new().map_err(|x| Error::DNSError(x.to_string()))?);
+        let client = new_http_client(resolver)?;
+        let req = Request::get(&url)
+            .header(header::USER_AGENT, format!("clash-rs/{}", CURRENT))
+            .body(Body::empty())
+            .map_err(|x| Error::Operation(format!("invalid request: {}", x)))?;
+        let res = client
+            .request(req)
+            .await
+            .map_err(|x| Error::Operation(format!("failed to fetch {}: {}", url, x)))?;
+
+        let latest = res
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.rsplit_once("/tag/"))
+            .map(|(_, tag)| tag.trim_start_matches('v').to_owned())
+            .ok_or_else(|| {
+                Error::Operation(format!("no release found at {}: {}", url, res.status()))
+            })?;
+
+        Ok(UpgradeInfo {
+            current: CURRENT.to_owned(),
+            has_update: is_newer(&latest, CURRENT),
+            latest,
+            asset: asset_name(),
+        })
+    }
+
+    /// the new binary is used from the next start on
+    pub async fn upgrade(&self) -> Result<UpgradeInfo, Error> {
+        let public_key = self.public_key.as_ref().ok_or_else(|| {
+            Error::InvalidConfig(
+                "updater public-key is not set, releases can't be verified".to_owned(),
+            )
+        })?;
+        let _upgrading = self
+            .upgrading
+            .try_lock()
+            .map_err(|_| Error::Operation("an upgrade is already running".to_owned()))?;
+
+        let info = self.check().await?;
+        if !info.has_update {
+            return Err(Error::Operation(format!(
+                "{} is already the latest release",
+                info.current
+            )));
+        }
+
+        let url = format!(
+            "https://github.com/{}/releases/download/v{}/{}",
+            self.repo, info.latest, info.asset
+        );
+        info!("downloading {}", url);
+        let binary = remote::fetch_bytes(&url, &[]).await?;
+        let signature = remote::fetch_bytes(&format!("{}.sig", url), &[]).await?;
+        verify(public_key, &binary, &signature)?;
+
+        let exe = std::env::current_exe()?;
+        replace(&exe, &binary).await?;
+        info!(
+            "upgraded {} from {} to {}, restart to use it",
+            exe.display(),
+            info.current,
+            info.latest
+        );
+        Ok(info)
+    }
+}
+
+/// named as by `scripts/build.sh`
+fn asset_name() -> String {
+    let platform = if cfg!(target_os = "macos") {
+        "apple-darwin"
+    } else if cfg!(target_os = "windows") {
+        "pc-windows-msvc"
+    } else if cfg!(target_env = "musl") {
+        "unknown-linux-musl"
+    } else {
+        "unknown-linux-gnu"
+    };
+    format!("clash-{}-{}", std::env::consts::ARCH, platform)
+}
+
+/// compares the numeric parts of two `x.y.z` versions, a pre-release
+/// suffix is ignored
+fn is_newer(latest: &str, current: &str) -> bool {
+    let parts = |x: &str| {
+        x.split('-')
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|x| x.parse::<u64>().unwrap_or_default())
+            .collect::<Vec<_>>()
+    };
+    parts(latest) > parts(current)
+}
+
+fn verify(public_key: &str, data: &[u8], signature: &[u8]) -> Result<(), Error> {
+    let key = PKey::public_key_from_pem(public_key.as_bytes())
+        .map_err(|x| Error::InvalidConfig(format!("invalid updater public-key: {}", x)))?;
+    let valid = Verifier::new_without_digest(&key)
+        .and_then(|mut v| v.verify_oneshot(signature, data))
+        .map_err(|x| Error::Crypto(format!("failed to verify release: {}", x)))?;
+    if !valid {
+        return Err(Error::Crypto(
+            "release signature doesn't match, not installed".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+async fn replace(exe: &Path, binary: &[u8]) -> std::io::Result<()> {
+    let new = exe.with_extension("new");
+    tokio::fs::write(&new, binary).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(&new, std::fs::Permissions::from_mode(0o755)).await?;
+    }
+
+    // Windows won't overwrite a running binary but lets it be renamed
+    let old = exe.with_extension("old");
+    tokio::fs::rename(exe, &old).await?;
+    if let Err(e) = tokio::fs::rename(&new, exe).await {
+        tokio::fs::rename(&old, exe).await.ok();
+        return Err(e);
+    }
+    // fails on Windows while it runs, it's replaced on the next upgrade
+    tokio::fs::remove_file(&old).await.ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::utils::decode_hex;
+
+    use super::{is_newer, verify};
+
+    /// RFC 8032 7.1 test 1, the signature of an empty message
+    const PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MCowBQYDK2VwAyEA11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=
+-----END PUBLIC KEY-----
+";
+    const SIGNATURE: &str = "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b";
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("0.1.11", "0.1.10"));
+        assert!(is_newer("0.2.0", "0.1.10"));
+        assert!(is_newer("1.0.0", "0.9.9-alpha"));
+        assert!(!is_newer("0.1.10", "0.1.10"));
+        assert!(!is_newer("0.1.9", "0.1.10"));
+    }
+
+    #[test]
+    fn test_verify() {
+        let signature = decode_hex(SIGNATURE).unwrap();
+        assert!(verify(PUBLIC_KEY, b"", &signature).is_ok());
+        assert!(verify(PUBLIC_KEY, b"tampered", &signature).is_err());
+        assert!(verify("not a key", b"", &signature).is_err());
+    }
+}
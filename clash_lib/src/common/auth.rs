@@ -1,4 +1,6 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use ipnet::IpNet;
 
 pub trait Authenticator {
     fn authenticate(&self, username: &str, password: &str) -> bool;
@@ -49,3 +51,54 @@ impl Authenticator for PlainAuthenticator {
         self.usernames.len() > 0
     }
 }
+
+/// the sources an inbound accepts connections from, loopback always is
+pub struct AllowList {
+    allow_lan: bool,
+    /// any source if empty
+    allowed_ips: Vec<IpNet>,
+}
+
+pub type ThreadSafeAllowList = Arc<AllowList>;
+
+impl AllowList {
+    pub fn new(allow_lan: bool, allowed_ips: Vec<IpNet>) -> Self {
+        Self {
+            allow_lan,
+            allowed_ips,
+        }
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        if ip.is_loopback() {
+            return true;
+        }
+        self.allow_lan
+            && (self.allowed_ips.is_empty() || self.allowed_ips.iter().any(|x| x.contains(&ip)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AllowList;
+
+    #[test]
+    fn test_allow_list() {
+        let local_only = AllowList::new(false, vec![]);
+        assert!(local_only.allows("127.0.0.1".parse().unwrap()));
+        assert!(local_only.allows("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!local_only.allows("192.168.1.2".parse().unwrap()));
+
+        let lan = AllowList::new(true, vec!["192.168.1.0/24".parse().unwrap()]);
+        assert!(lan.allows("192.168.1.2".parse().unwrap()));
+        assert!(lan.allows("::ffff:192.168.1.2".parse().unwrap()));
+        assert!(lan.allows("::1".parse().unwrap()));
+        assert!(!lan.allows("10.0.0.2".parse().unwrap()));
+
+        assert!(AllowList::new(true, vec![]).allows("10.0.0.2".parse().unwrap()));
+    }
+}
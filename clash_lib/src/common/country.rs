@@ -0,0 +1,115 @@
+//! Tells the country of a proxy from its name, as subscriptions name their
+//! nodes like `🇭🇰 Hong Kong 01` or `JP-Tokyo-02`.
+
+/// the names nodes go by for the common countries, in lowercase
+const COUNTRIES: &[(&str, &[&str])] = &[
+    ("HK", &["hong kong", "hongkong", "香港"]),
+    ("MO", &["macau", "macao", "澳门", "澳門"]),
+    ("TW", &["taiwan", "台湾", "臺灣"]),
+    ("JP", &["japan", "tokyo", "osaka", "日本", "东京", "大阪"]),
+    ("KR", &["korea", "seoul", "韩国", "首尔"]),
+    ("SG", &["singapore", "新加坡", "狮城"]),
+    (
+        "US",
+        &[
+            "united states",
+            "america",
+            "los angeles",
+            "san jose",
+            "美国",
+            "洛杉矶",
+        ],
+    ),
+    ("CA", &["canada", "加拿大"]),
+    (
+        "GB",
+        &["united kingdom", "britain", "london", "英国", "伦敦"],
+    ),
+    ("DE", &["germany", "frankfurt", "德国"]),
+    ("FR", &["france", "paris", "法国"]),
+    ("NL", &["netherlands", "amsterdam", "荷兰"]),
+    ("RU", &["russia", "moscow", "俄罗斯"]),
+    ("TR", &["turkey", "türkiye", "土耳其"]),
+    ("IN", &["india", "印度"]),
+    ("AU", &["australia", "sydney", "澳大利亚", "澳洲"]),
+];
+
+/// codes that aren't the ISO 3166 one of their country
+const CODE_ALIASES: &[(&str, &str)] = &[("UK", "GB")];
+
+pub fn is_country_code(code: &str) -> bool {
+    code.len() == 2 && code.bytes().all(|x| x.is_ascii_uppercase())
+}
+
+/// the two regional indicators of a flag emoji as letters
+fn flag(name: &str) -> Option<String> {
+    let letters = name
+        .chars()
+        .map(|c| match c {
+            '\u{1F1E6}'..='\u{1F1FF}' => char::from_u32(c as u32 - 0x1F1E6 + 'A' as u32),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    letters.windows(2).find_map(|x| match x {
+        [Some(a), Some(b)] => Some(format!("{}{}", a, b)),
+        _ => None,
+    })
+}
+
+/// the ISO 3166 code of the country a proxy is in, from a flag emoji, a
+/// country or city name, or an uppercase code as a word of its own such as
+/// `HK` in `HK01`
+pub fn country_of(name: &str) -> Option<String> {
+    if let Some(code) = flag(name) {
+        return Some(code);
+    }
+
+    let lower = name.to_lowercase();
+    if let Some((code, _)) = COUNTRIES
+        .iter()
+        .find(|(_, names)| names.iter().any(|x| lower.contains(x)))
+    {
+        return Some(code.to_string());
+    }
+
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .map(|x| x.trim_end_matches(|c: char| c.is_ascii_digit()))
+        .find_map(|word| {
+            CODE_ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == word)
+                .map(|(_, code)| *code)
+                .or_else(|| {
+                    COUNTRIES
+                        .iter()
+                        .find(|(code, _)| *code == word)
+                        .map(|(code, _)| *code)
+                })
+        })
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{country_of, is_country_code};
+
+    #[test]
+    fn test_country_of() {
+        assert_eq!(country_of("🇭🇰 香港 01").as_deref(), Some("HK"));
+        assert_eq!(country_of("🇧🇷 Brazil").as_deref(), Some("BR"));
+        assert_eq!(country_of("Japan Tokyo 02").as_deref(), Some("JP"));
+        assert_eq!(country_of("美国 洛杉矶").as_deref(), Some("US"));
+        assert_eq!(country_of("HK01").as_deref(), Some("HK"));
+        assert_eq!(country_of("SG-02 | IEPL").as_deref(), Some("SG"));
+        assert_eq!(country_of("UK London").as_deref(), Some("GB"));
+        assert_eq!(country_of("UK-01").as_deref(), Some("GB"));
+        // only uppercase codes of their own
+        assert_eq!(country_of("us-west"), None);
+        assert_eq!(country_of("HKG"), None);
+        assert_eq!(country_of("剩余流量：100GB"), None);
+
+        assert!(is_country_code("HK"));
+        assert!(!is_country_code("hk"));
+        assert!(!is_country_code("HKG"));
+    }
+}
@@ -0,0 +1,190 @@
+//! The DSCP of the packets tun reads, for the `DSCP` rule. The stacks only
+//! hand over the payloads, so the mark is read from the IP header before
+//! and kept by the source address of the TCP handshake or UDP packet.
+//! Only marked packets are kept, the others are best effort, 0.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+
+/// how many sources are kept, once there are as many the ones not seen for
+/// `TTL` are dropped, then the oldest eighth
+const CAPACITY: usize = 4096;
+const TTL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub struct DscpTable {
+    /// whether a rule reads the marks, none are kept otherwise
+    enabled: bool,
+    marks: Mutex<HashMap<SocketAddr, (u8, Instant)>>,
+    /// how many marks are kept, so that unmarked packets only take the
+    /// lock to drop an earlier mark of their source
+    len: AtomicUsize,
+}
+
+impl DscpTable {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    /// keeps the mark of `pkt`, an IP packet, if it opens a TCP connection
+    /// or is UDP
+    pub fn record(&self, pkt: &[u8]) {
+        if !self.enabled {
+            return;
+        }
+        let Some((source, dscp)) = parse(pkt) else {
+            return;
+        };
+        if dscp == 0 && self.len.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        let mut marks = self.marks.lock().unwrap();
+        if dscp == 0 {
+            marks.remove(&source);
+        } else {
+            if marks.len() >= CAPACITY && !marks.contains_key(&source) {
+                evict(&mut marks);
+            }
+            marks.insert(source, (dscp, Instant::now()));
+        }
+        self.len.store(marks.len(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self, source: SocketAddr) -> u8 {
+        if self.len.load(Ordering::Relaxed) == 0 {
+            return 0;
+        }
+        self.marks.lock().unwrap().get(&source).map_or(0, |x| x.0)
+    }
+}
+
+fn evict(marks: &mut HashMap<SocketAddr, (u8, Instant)>) {
+    marks.retain(|_, (_, seen)| seen.elapsed() < TTL);
+    if marks.len() < CAPACITY {
+        return;
+    }
+    let mut seen = marks.values().map(|x| x.1).collect::<Vec<_>>();
+    let (_, cutoff, _) = seen.select_nth_unstable(CAPACITY / 8);
+    let cutoff = *cutoff;
+    marks.retain(|_, (_, seen)| *seen > cutoff);
+}
+
+/// the source and DSCP of a TCP SYN or a UDP packet
+fn parse(pkt: &[u8]) -> Option<(SocketAddr, u8)> {
+    let (tos, proto, ip, l4) = match pkt.first()? >> 4 {
+        4 => {
+            let ihl = (pkt[0] & 0x0f) as usize * 4;
+            let src: [u8; 4] = pkt.get(12..16)?.try_into().ok()?;
+            (
+                *pkt.get(1)?,
+                *pkt.get(9)?,
+                IpAddr::V4(Ipv4Addr::from(src)),
+                pkt.get(ihl..)?,
+            )
+        }
+        6 => {
+            let tc = ((pkt.get(..2)?[0] & 0x0f) << 4) | (pkt[1] >> 4);
+            let src: [u8; 16] = pkt.get(8..24)?.try_into().ok()?;
+            (
+                tc,
+                *pkt.get(6)?,
+                IpAddr::V6(Ipv6Addr::from(src)),
+                pkt.get(40..)?,
+            )
+        }
+        _ => return None,
+    };
+
+    match proto {
+        PROTO_TCP if l4.get(13)? & (TCP_SYN | TCP_ACK) == TCP_SYN => {}
+        PROTO_UDP => {}
+        _ => return None,
+    }
+    let port = u16::from_be_bytes(l4.get(..2)?.try_into().ok()?);
+    Some((SocketAddr::new(ip, port), tos >> 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Instant};
+
+    use super::{evict, parse, DscpTable, CAPACITY};
+
+    fn v4(tos: u8, proto: u8, l4: &[u8]) -> Vec<u8> {
+        let mut pkt = vec![0x45, tos, 0, 0, 0, 0, 0, 0, 64, proto, 0, 0];
+        pkt.extend([192, 168, 1, 2, 1, 1, 1, 1]);
+        pkt.extend(l4);
+        pkt
+    }
+
+    #[test]
+    fn test_parse_dscp() {
+        // EF, 46
+        let udp = v4(0xb8, 17, &[0x1f, 0x90, 0x01, 0xbb, 0, 8, 0, 0]);
+        assert_eq!(parse(&udp), Some(("192.168.1.2:8080".parse().unwrap(), 46)));
+
+        let mut tcp = [0u8; 20];
+        tcp[..2].copy_from_slice(&50000u16.to_be_bytes());
+        tcp[13] = 0x02;
+        let syn = v4(0x20, 6, &tcp);
+        assert_eq!(parse(&syn), Some(("192.168.1.2:50000".parse().unwrap(), 8)));
+        tcp[13] = 0x12;
+        assert_eq!(parse(&v4(0x20, 6, &tcp)), None);
+        tcp[13] = 0x10;
+        assert_eq!(parse(&v4(0x20, 6, &tcp)), None);
+        assert_eq!(parse(&v4(0x20, 1, &[8, 0, 0, 0])), None);
+        assert_eq!(parse(&syn[..21]), None);
+
+        // traffic class 0x88, AF41, 34
+        let mut v6 = vec![0x68, 0x80, 0, 0, 0, 8, 17, 64];
+        v6.extend("fd00::2".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        v6.extend([0u8; 16]);
+        v6.extend([0x01, 0xbb, 0x01, 0xbb, 0, 8, 0, 0]);
+        assert_eq!(parse(&v6), Some(("[fd00::2]:443".parse().unwrap(), 34)));
+
+        let table = DscpTable::new(true);
+        table.record(&udp);
+        table.record(&v6);
+        assert_eq!(table.get("192.168.1.2:8080".parse().unwrap()), 46);
+        assert_eq!(table.get("[fd00::2]:443".parse().unwrap()), 34);
+        assert_eq!(table.get("192.168.1.2:8081".parse().unwrap()), 0);
+        // an unmarked packet drops the mark of its source
+        table.record(&v4(0, 17, &[0x1f, 0x90, 0x01, 0xbb, 0, 8, 0, 0]));
+        assert_eq!(table.get("192.168.1.2:8080".parse().unwrap()), 0);
+
+        let disabled = DscpTable::new(false);
+        disabled.record(&udp);
+        assert_eq!(disabled.get("192.168.1.2:8080".parse().unwrap()), 0);
+    }
+
+    #[test]
+    fn test_evict_oldest() {
+        let now = Instant::now();
+        let mut marks = (0..CAPACITY as u16)
+            .map(|i| {
+                let addr = SocketAddr::from(([10, 0, 0, 1], i));
+                (addr, (1, now + std::time::Duration::from_millis(i as u64)))
+            })
+            .collect();
+        evict(&mut marks);
+        assert!(marks.len() < CAPACITY);
+        assert!(!marks.contains_key(&SocketAddr::from(([10, 0, 0, 1], 0))));
+        assert!(marks.contains_key(&SocketAddr::from(([10, 0, 0, 1], CAPACITY as u16 - 1))));
+    }
+}
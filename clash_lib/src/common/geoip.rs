@@ -0,0 +1,241 @@
+//! The IP ranges of a v2ray `geoip.dat`, by country or category, used in
+//! place of the mmdb when `geodata-mode` is on. Only the codes used are
+//! decoded.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    ops::Range,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use prost::Message;
+use tracing::{debug, info, warn};
+
+use crate::{
+    common::{geosite::index_entries, offline},
+    config::remote,
+    Error,
+};
+
+// the messages of v2ray's `app/router/config.proto`, only the fields used
+
+/// only the tests encode it, `GeoIPData::parse` indexes it by hand
+#[cfg(test)]
+#[derive(Clone, PartialEq, Message)]
+struct GeoIPList {
+    #[prost(message, repeated, tag = "1")]
+    entry: Vec<GeoIPEntry>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GeoIPEntry {
+    #[prost(string, tag = "1")]
+    country_code: String,
+    #[prost(message, repeated, tag = "2")]
+    cidr: Vec<Cidr>,
+    #[prost(bool, tag = "3")]
+    reverse_match: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Cidr {
+    /// 4 or 16 bytes
+    #[prost(bytes = "vec", tag = "1")]
+    ip: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    prefix: u32,
+}
+
+pub struct GeoIPData {
+    data: Vec<u8>,
+    /// where each entry is in `data`, by lowercase code
+    index: HashMap<String, Range<usize>>,
+    /// by lowercase code
+    sets: Mutex<HashMap<String, Arc<IpSet>>>,
+}
+
+impl GeoIPData {
+    pub async fn new<P: AsRef<Path>>(path: P, download_url: Option<String>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        debug!("geoip path: {}", path.to_string_lossy());
+
+        if !path.exists() {
+            let url = download_url.ok_or_else(|| {
+                Error::InvalidConfig(format!(
+                    "geoip `{}` not found and geoip_download_url is not set",
+                    path.to_string_lossy()
+                ))
+            })?;
+            if offline::is_offline() {
+                return Err(Error::InvalidConfig(format!(
+                    "geoip `{}` not found and offline mode is on",
+                    path.to_string_lossy()
+                )));
+            }
+            info!("downloading geoip from {}", url);
+            let data = remote::fetch_bytes(&url, &[]).await?;
+            // checked before it is kept
+            Self::parse(&data)?;
+            tokio::fs::write(path, &data).await?;
+        }
+
+        let data = tokio::fs::read(path).await?;
+        Self::parse(&data).map_err(|e| {
+            warn!("invalid geoip `{}`: {}", path.to_string_lossy(), e);
+            e
+        })
+    }
+
+    /// indexes the codes of a `GeoIPList`, their CIDRs are decoded by `set`
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            data: data.to_vec(),
+            index: index_entries(data, "geoip")?,
+            sets: Default::default(),
+        })
+    }
+
+    /// the ranges of `code`, built once per code
+    pub fn set(&self, code: &str) -> Result<Arc<IpSet>, Error> {
+        let code = code.to_lowercase();
+        if let Some(s) = self.sets.lock().unwrap().get(&code) {
+            return Ok(s.clone());
+        }
+
+        let range = self
+            .index
+            .get(&code)
+            .ok_or_else(|| Error::InvalidConfig(format!("geoip {} not found", code)))?;
+        let entry = GeoIPEntry::decode(&self.data[range.clone()])
+            .map_err(|x| Error::InvalidConfig(format!("invalid geoip {}: {}", code, x)))?;
+        let s = Arc::new(IpSet::new(&entry));
+        debug!("geoip {} loaded", code);
+
+        self.sets.lock().unwrap().insert(code, s.clone());
+        Ok(s)
+    }
+}
+
+/// the merged ranges of an entry, searched by bisection
+pub struct IpSet {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+    reverse: bool,
+}
+
+impl IpSet {
+    fn new(entry: &GeoIPEntry) -> Self {
+        let mut v4 = vec![];
+        let mut v6 = vec![];
+        for cidr in &entry.cidr {
+            match cidr.ip.len() {
+                4 if cidr.prefix <= 32 => {
+                    let ip = u32::from_be_bytes(cidr.ip[..].try_into().unwrap());
+                    let host = u32::MAX.checked_shr(cidr.prefix).unwrap_or(0);
+                    v4.push((ip & !host, ip | host));
+                }
+                16 if cidr.prefix <= 128 => {
+                    let ip = u128::from_be_bytes(cidr.ip[..].try_into().unwrap());
+                    let host = u128::MAX.checked_shr(cidr.prefix).unwrap_or(0);
+                    v6.push((ip & !host, ip | host));
+                }
+                _ => debug!("geoip: skipping invalid cidr in {}", entry.country_code),
+            }
+        }
+        Self {
+            v4: merge(v4),
+            v6: merge(v6),
+            reverse: entry.reverse_match,
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let found = match ip {
+            IpAddr::V4(ip) => search(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => search(&self.v4, u32::from(ip)),
+                None => search(&self.v6, u128::from(ip)),
+            },
+        };
+        found != self.reverse
+    }
+}
+
+fn merge<T: Ord + Copy>(mut ranges: Vec<(T, T)>) -> Vec<(T, T)> {
+    ranges.sort_unstable();
+    let mut rv: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match rv.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => rv.push((start, end)),
+        }
+    }
+    rv
+}
+
+fn search<T: Ord + Copy>(ranges: &[(T, T)], x: T) -> bool {
+    let i = ranges.partition_point(|(start, _)| *start <= x);
+    i > 0 && x <= ranges[i - 1].1
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::{Cidr, GeoIPData, GeoIPEntry, GeoIPList};
+
+    #[test]
+    fn test_geoip_dat() {
+        let cidr = |x: &str| {
+            let net = x.parse::<ipnet::IpNet>().unwrap();
+            Cidr {
+                ip: match net.addr() {
+                    std::net::IpAddr::V4(ip) => ip.octets().to_vec(),
+                    std::net::IpAddr::V6(ip) => ip.octets().to_vec(),
+                },
+                prefix: net.prefix_len() as u32,
+            }
+        };
+        let data = GeoIPList {
+            entry: vec![
+                GeoIPEntry {
+                    country_code: "CN".to_owned(),
+                    cidr: vec![
+                        cidr("1.0.1.0/24"),
+                        cidr("1.0.2.0/23"),
+                        cidr("1.0.2.0/24"),
+                        cidr("240e::/20"),
+                    ],
+                    reverse_match: false,
+                },
+                GeoIPEntry {
+                    country_code: "NOT-PRIVATE".to_owned(),
+                    cidr: vec![cidr("10.0.0.0/8"), cidr("0.0.0.0/32")],
+                    reverse_match: true,
+                },
+            ],
+        }
+        .encode_to_vec();
+
+        let geoip = GeoIPData::parse(&data).unwrap();
+        assert!(geoip.set("jp").is_err());
+
+        let cn = geoip.set("cn").unwrap();
+        for x in ["1.0.1.1", "1.0.3.255", "240e:1::1", "::ffff:1.0.2.3"] {
+            assert!(cn.contains(x.parse().unwrap()), "{}", x);
+        }
+        for x in ["1.0.0.255", "1.0.4.0", "2400::1"] {
+            assert!(!cn.contains(x.parse().unwrap()), "{}", x);
+        }
+
+        let not_private = geoip.set("Not-Private").unwrap();
+        assert!(!not_private.contains("10.1.2.3".parse().unwrap()));
+        assert!(!not_private.contains("0.0.0.0".parse().unwrap()));
+        assert!(not_private.contains("8.8.8.8".parse().unwrap()));
+
+        assert!(std::sync::Arc::ptr_eq(&cn, &geoip.set("CN").unwrap()));
+        assert!(GeoIPData::parse(&data[..10]).is_err());
+    }
+}
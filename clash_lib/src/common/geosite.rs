@@ -0,0 +1,309 @@
+//! The domain lists of a v2ray `geosite.dat`, by category, for the
+//! `GEOSITE` rules and the `geosite:` keys of the config. The file is only
+//! loaded, and downloaded if missing, when one of them is used, and only the
+//! categories used are decoded.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use prost::{
+    encoding::{decode_key, decode_varint, WireType},
+    Message,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    common::{offline, trie},
+    config::remote,
+    Error,
+};
+
+// the messages of v2ray's `app/router/config.proto`, only the fields used
+
+/// walked by hand in `GeoSite::parse`, only the tests encode it
+#[cfg(test)]
+#[derive(Clone, PartialEq, Message)]
+struct GeoSiteList {
+    #[prost(message, repeated, tag = "1")]
+    entry: Vec<GeoSiteEntry>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GeoSiteEntry {
+    #[prost(string, tag = "1")]
+    country_code: String,
+    #[prost(message, repeated, tag = "2")]
+    domain: Vec<Domain>,
+}
+
+/// a `GeoSiteEntry` without its domains, or a `GeoIP` without its CIDRs,
+/// for the index
+#[derive(Clone, PartialEq, Message)]
+struct GeoSiteHeader {
+    #[prost(string, tag = "1")]
+    country_code: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Domain {
+    #[prost(int32, tag = "1")]
+    r#type: i32,
+    #[prost(string, tag = "2")]
+    value: String,
+    #[prost(message, repeated, tag = "3")]
+    attribute: Vec<Attribute>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Attribute {
+    #[prost(string, tag = "1")]
+    key: String,
+}
+
+/// `Domain.type`
+const TYPE_KEYWORD: i32 = 0;
+const TYPE_REGEX: i32 = 1;
+const TYPE_DOMAIN: i32 = 2;
+const TYPE_FULL: i32 = 3;
+
+pub struct GeoSite {
+    data: Vec<u8>,
+    /// where each entry is in `data`, by lowercase category
+    index: HashMap<String, Range<usize>>,
+    /// by lowercase code
+    matchers: Mutex<HashMap<String, Arc<DomainMatcher>>>,
+}
+
+impl GeoSite {
+    pub async fn new<P: AsRef<Path>>(path: P, download_url: Option<String>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        debug!("geosite path: {}", path.to_string_lossy());
+
+        if !path.exists() {
+            let url = download_url.ok_or_else(|| {
+                Error::InvalidConfig(format!(
+                    "geosite `{}` not found and geosite_download_url is not set",
+                    path.to_string_lossy()
+                ))
+            })?;
+            if offline::is_offline() {
+                return Err(Error::InvalidConfig(format!(
+                    "geosite `{}` not found and offline mode is on",
+                    path.to_string_lossy()
+                )));
+            }
+            info!("downloading geosite from {}", url);
+            let data = remote::fetch_bytes(&url, &[]).await?;
+            // checked before it is kept
+            Self::parse(&data)?;
+            tokio::fs::write(path, &data).await?;
+        }
+
+        let data = tokio::fs::read(path).await?;
+        Self::parse(&data).map_err(|e| {
+            warn!("invalid geosite `{}`: {}", path.to_string_lossy(), e);
+            e
+        })
+    }
+
+    /// indexes the categories of a `GeoSiteList`, their domains are
+    /// decoded by `matcher`
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            data: data.to_vec(),
+            index: index_entries(data, "geosite")?,
+            matchers: Default::default(),
+        })
+    }
+
+    /// whether there is a category by the name of `code`, see `matcher`
+    pub fn contains(&self, code: &str) -> bool {
+        let category = code.split_once('@').map_or(code, |(x, _)| x);
+        self.index.contains_key(&category.to_lowercase())
+    }
+
+    /// the domains of a category, `cn`, or of those in it that have an
+    /// attribute, `google@cn`, or that don't, `google@!cn`. Built once per
+    /// code
+    pub fn matcher(&self, code: &str) -> Result<Arc<DomainMatcher>, Error> {
+        let code = code.to_lowercase();
+        if let Some(m) = self.matchers.lock().unwrap().get(&code) {
+            return Ok(m.clone());
+        }
+
+        let (category, attribute) = match code.split_once('@') {
+            Some((category, attribute)) => (category, Some(attribute)),
+            None => (code.as_str(), None),
+        };
+        let range = self
+            .index
+            .get(category)
+            .ok_or_else(|| Error::InvalidConfig(format!("geosite {} not found", code)))?;
+        let entry = GeoSiteEntry::decode(&self.data[range.clone()])
+            .map_err(|x| Error::InvalidConfig(format!("invalid geosite {}: {}", category, x)))?;
+
+        let mut m = DomainMatcher::default();
+        for d in &entry.domain {
+            let has = |x: &str| d.attribute.iter().any(|a| a.key.eq_ignore_ascii_case(x));
+            match attribute {
+                Some(x) if x.starts_with('!') && has(&x[1..]) => continue,
+                Some(x) if !x.starts_with('!') && !has(x) => continue,
+                _ => {}
+            }
+            m.add(d)?;
+        }
+        debug!("geosite {} loaded", code);
+
+        let m = Arc::new(m);
+        self.matchers.lock().unwrap().insert(code, m.clone());
+        Ok(m)
+    }
+}
+
+/// where each entry of a `GeoSiteList`, or of a `GeoIPList` which has the
+/// same layout, is in `data`, by lowercase code
+pub(crate) fn index_entries(
+    data: &[u8],
+    what: &str,
+) -> Result<HashMap<String, Range<usize>>, Error> {
+    let invalid = |x: String| Error::InvalidConfig(format!("invalid {}: {}", what, x));
+
+    let mut index = HashMap::new();
+    let mut buf = data;
+    while !buf.is_empty() {
+        let (tag, wire_type) = decode_key(&mut buf).map_err(|x| invalid(x.to_string()))?;
+        if tag != 1 || wire_type != WireType::LengthDelimited {
+            return Err(invalid(format!("unexpected field {}", tag)));
+        }
+        let len = decode_varint(&mut buf).map_err(|x| invalid(x.to_string()))? as usize;
+        if len > buf.len() {
+            return Err(invalid("truncated entry".to_owned()));
+        }
+        let start = data.len() - buf.len();
+        let header = GeoSiteHeader::decode(&buf[..len]).map_err(|x| invalid(x.to_string()))?;
+        index.insert(header.country_code.to_lowercase(), start..start + len);
+        buf = &buf[len..];
+    }
+    Ok(index)
+}
+
+/// matches a domain the way v2ray does against the entries of a category
+#[derive(Default)]
+pub struct DomainMatcher {
+    full: HashSet<String>,
+    /// the domains and their subdomains
+    domain: trie::StringTrie<()>,
+    keyword: Vec<String>,
+    regex: Vec<regex::Regex>,
+}
+
+impl DomainMatcher {
+    fn add(&mut self, d: &Domain) -> Result<(), Error> {
+        let value = d.value.to_lowercase();
+        match d.r#type {
+            TYPE_FULL => {
+                self.full.insert(value);
+            }
+            TYPE_DOMAIN => {
+                // a few lists carry entries the trie can't take, skipped
+                // rather than failing the whole category
+                if !self.domain.insert(&format!("+.{}", value), Arc::new(())) {
+                    debug!("geosite: skipping invalid domain {}", value);
+                }
+            }
+            TYPE_KEYWORD => self.keyword.push(value),
+            TYPE_REGEX => self.regex.push(
+                regex::Regex::new(&d.value)
+                    .map_err(|x| Error::InvalidConfig(format!("geosite: {}: {}", d.value, x)))?,
+            ),
+            x => debug!("geosite: skipping {} of unknown type {}", d.value, x),
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        self.full.contains(&domain)
+            || self.domain.search(&domain).is_some()
+            || self.keyword.iter().any(|x| domain.contains(x.as_str()))
+            || self.regex.iter().any(|x| x.is_match(&domain))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use prost::Message;
+
+    use super::{
+        Attribute, Domain, GeoSite, GeoSiteEntry, GeoSiteList, TYPE_DOMAIN, TYPE_FULL,
+        TYPE_KEYWORD, TYPE_REGEX,
+    };
+
+    /// a `geosite.dat` with the categories `cn` and `google`
+    pub(crate) fn test_geosite_dat() -> Vec<u8> {
+        let domain = |r#type, value: &str, attributes: &[&str]| Domain {
+            r#type,
+            value: value.to_owned(),
+            attribute: attributes
+                .iter()
+                .map(|x| Attribute { key: x.to_string() })
+                .collect(),
+        };
+        GeoSiteList {
+            entry: vec![
+                GeoSiteEntry {
+                    country_code: "CN".to_owned(),
+                    domain: vec![
+                        domain(TYPE_DOMAIN, "baidu.com", &[]),
+                        domain(TYPE_FULL, "www.qq.com", &[]),
+                        domain(TYPE_KEYWORD, "taobao", &[]),
+                        domain(TYPE_REGEX, r"^cdn\d+\.example\.cn$", &[]),
+                    ],
+                },
+                GeoSiteEntry {
+                    country_code: "GOOGLE".to_owned(),
+                    domain: vec![
+                        domain(TYPE_DOMAIN, "google.com", &[]),
+                        domain(TYPE_DOMAIN, "google.cn", &["cn"]),
+                    ],
+                },
+            ],
+        }
+        .encode_to_vec()
+    }
+
+    #[test]
+    fn test_geosite() {
+        let geosite = GeoSite::parse(&test_geosite_dat()).unwrap();
+        assert!(geosite.contains("cn") && geosite.contains("Google@cn"));
+        assert!(!geosite.contains("gfw"));
+        assert!(geosite.matcher("gfw").is_err());
+
+        let cn = geosite.matcher("cn").unwrap();
+        for x in [
+            "baidu.com",
+            "map.baidu.com.",
+            "www.qq.com",
+            "s.taobao.net",
+            "cdn12.example.cn",
+        ] {
+            assert!(cn.matches(x), "{}", x);
+        }
+        for x in ["qq.com", "notbaidu.com", "cdn.example.cn", "google.com"] {
+            assert!(!cn.matches(x), "{}", x);
+        }
+
+        let google_cn = geosite.matcher("google@cn").unwrap();
+        assert!(google_cn.matches("www.google.cn") && !google_cn.matches("google.com"));
+        let google_not_cn = geosite.matcher("google@!cn").unwrap();
+        assert!(!google_not_cn.matches("www.google.cn") && google_not_cn.matches("google.com"));
+
+        // built once
+        assert!(std::sync::Arc::ptr_eq(&cn, &geosite.matcher("CN").unwrap()));
+        assert!(GeoSite::parse(&test_geosite_dat()[..10]).is_err());
+    }
+}
@@ -12,7 +12,10 @@ use tower::Service;
 
 use crate::{
     app::dns::ThreadSafeDNSResolver,
-    proxy::{utils::new_tcp_stream, AnyStream},
+    proxy::{
+        utils::{new_tcp_stream, use_mptcp},
+        AnyStream,
+    },
 };
 
 use super::errors::map_io_error;
@@ -51,6 +54,7 @@ impl Service<Uri> for LocalConnector {
                     },
                 }),
                 None,
+                use_mptcp(None),
                 #[cfg(any(target_os = "linux", target_os = "android"))]
                 None,
             )
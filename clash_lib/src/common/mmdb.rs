@@ -1,4 +1,10 @@
-use std::{fs, io::Write, net::IpAddr, path::Path};
+use std::{
+    fs,
+    io::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::Mutex,
+};
 
 use async_recursion::async_recursion;
 use hyper::body::HttpBody;
@@ -6,15 +12,23 @@ use maxminddb::geoip2;
 use tracing::{debug, info, warn};
 
 use crate::{
-    common::{
-        errors::{map_io_error, new_io_error},
-        http::HttpClient,
-    },
+    common::{errors::new_io_error, geoip::GeoIPData, http::HttpClient, offline},
     Error,
 };
 
+/// the code of the private, loopback and link local ranges, matched without
+/// the database
+pub const LAN: &str = "LAN";
+
+/// the country database, a MaxMind mmdb or, with `geodata-mode`, a v2ray
+/// geoip.dat
 pub struct MMDB {
-    reader: maxminddb::Reader<Vec<u8>>,
+    reader: Reader,
+}
+
+enum Reader {
+    MaxMind(maxminddb::Reader<Vec<u8>>),
+    GeoIP(GeoIPData),
 }
 
 impl MMDB {
@@ -43,7 +57,9 @@ impl MMDB {
         }
 
         match maxminddb::Reader::open_readfile(&path) {
-            Ok(r) => Ok(MMDB { reader: r }),
+            Ok(r) => Ok(MMDB {
+                reader: Reader::MaxMind(r),
+            }),
             Err(e) => match e {
                 maxminddb::MaxMindDBError::InvalidDatabaseError(_)
                 | maxminddb::MaxMindDBError::IoError(_) => {
@@ -63,13 +79,15 @@ impl MMDB {
                                 Error::InvalidConfig(format!("mmdb download failed: {}", x))
                             })?;
                         Ok(MMDB {
-                            reader: maxminddb::Reader::open_readfile(&path).map_err(|x| {
-                                Error::InvalidConfig(format!(
-                                    "cant open mmdb `{}`: {}",
-                                    path.as_ref().to_string_lossy(),
-                                    x.to_string()
-                                ))
-                            })?,
+                            reader: Reader::MaxMind(
+                                maxminddb::Reader::open_readfile(&path).map_err(|x| {
+                                    Error::InvalidConfig(format!(
+                                        "cant open mmdb `{}`: {}",
+                                        path.as_ref().to_string_lossy(),
+                                        x.to_string()
+                                    ))
+                                })?,
+                            ),
                         })
                     } else {
                         return Err(Error::InvalidConfig(format!(
@@ -89,12 +107,24 @@ impl MMDB {
         }
     }
 
+    pub async fn from_geoip_dat<P: AsRef<Path>>(
+        path: P,
+        download_url: Option<String>,
+    ) -> Result<MMDB, Error> {
+        Ok(MMDB {
+            reader: Reader::GeoIP(GeoIPData::new(path, download_url).await?),
+        })
+    }
+
     #[async_recursion(?Send)]
     async fn download<P: AsRef<Path>>(
         url: &str,
         path: P,
         http_client: &HttpClient,
     ) -> anyhow::Result<()> {
+        if offline::is_offline() {
+            return Err(anyhow!("offline mode is on"));
+        }
         let uri = url.parse::<http::Uri>()?;
         let mut out = std::fs::File::create(&path)?;
 
@@ -129,10 +159,125 @@ impl MMDB {
         Ok(())
     }
 
-    pub fn lookup(&self, ip: IpAddr) -> anyhow::Result<geoip2::Country> {
-        self.reader
-            .lookup(ip)
-            .map_err(map_io_error)
-            .map_err(|x| x.into())
+    /// whether `ip` is in `code`, a country of the mmdb or a country or
+    /// category of the geoip.dat
+    pub fn contains(&self, ip: IpAddr, code: &str) -> bool {
+        match &self.reader {
+            Reader::MaxMind(r) => country_of(r, ip).is_some_and(|x| x.eq_ignore_ascii_case(code)),
+            Reader::GeoIP(r) => r.set(code).is_ok_and(|x| x.contains(ip)),
+        }
+    }
+
+    /// `contains`, with the country the mmdb has for `ip` kept in `cache`
+    /// for the next rule. The sets of a geoip.dat are built once already
+    pub fn contains_cached(&self, ip: IpAddr, code: &str, cache: &CountryCache) -> bool {
+        match &self.reader {
+            Reader::MaxMind(r) => {
+                let mut cache = cache.0.lock().unwrap();
+                if cache.as_ref().map(|x| x.0) != Some(ip) {
+                    *cache = Some((ip, country_of(r, ip)));
+                }
+                cache
+                    .as_ref()
+                    .and_then(|x| x.1.as_deref())
+                    .is_some_and(|x| x.eq_ignore_ascii_case(code))
+            }
+            Reader::GeoIP(_) => self.contains(ip, code),
+        }
+    }
+
+    /// fails for the codes the geoip.dat doesn't have, any is fine for the
+    /// mmdb
+    pub fn check_code(&self, code: &str) -> Result<(), Error> {
+        match &self.reader {
+            Reader::MaxMind(_) => Ok(()),
+            Reader::GeoIP(r) => r.set(code).map(|_| ()),
+        }
+    }
+}
+
+fn country_of(r: &maxminddb::Reader<Vec<u8>>, ip: IpAddr) -> Option<String> {
+    match r.lookup::<geoip2::Country>(ip) {
+        Ok(country) => country.country.and_then(|x| x.iso_code).map(str::to_owned),
+        Err(e) => {
+            debug!("GeoIP lookup failed: {}", e);
+            None
+        }
+    }
+}
+
+/// the country of the last IP looked up in the mmdb, kept on the session so
+/// that its GEOIP rules look it up once
+#[derive(Default)]
+pub struct CountryCache(Mutex<Option<(IpAddr, Option<String>)>>);
+
+impl Clone for CountryCache {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+/// whether `ip` is in `LAN`: private, shared, loopback, link local or
+/// unspecified
+pub fn is_lan(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_lan_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_lan_v4(ip),
+            None => is_lan_v6(ip),
+        },
+    }
+}
+
+fn is_lan_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // 100.64.0.0/10
+        || (a == 100 && b & 0xc0 == 64)
+}
+
+fn is_lan_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // fc00::/7 and fe80::/10
+        || first & 0xfe00 == 0xfc00
+        || first & 0xffc0 == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_lan;
+
+    #[test]
+    fn test_is_lan() {
+        for x in [
+            "10.1.2.3",
+            "172.31.0.1",
+            "192.168.1.1",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(is_lan(x.parse().unwrap()), "{}", x);
+        }
+        for x in [
+            "8.8.8.8",
+            "100.128.0.1",
+            "172.32.0.1",
+            "2001:db8::1",
+            "::ffff:1.1.1.1",
+        ] {
+            assert!(!is_lan(x.parse().unwrap()), "{}", x);
+        }
     }
 }
@@ -1,10 +1,20 @@
 pub mod auth;
+pub mod country;
 pub mod crypto;
+pub mod dscp;
 pub mod errors;
+pub mod geoip;
+pub mod geosite;
 pub mod http;
 pub mod io;
 pub mod mmdb;
+pub mod nat64;
+pub mod offline;
+pub mod port_set;
+pub mod redact;
+pub mod system_proxy;
 pub mod timed_future;
 pub mod tls;
 pub mod trie;
+pub mod uid;
 pub mod utils;
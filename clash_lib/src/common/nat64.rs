@@ -0,0 +1,202 @@
+//! NAT64 awareness for IPv6-only networks: IPv4 destinations are dialed at
+//! their address synthesized with the network's NAT64 prefix (RFC 6052),
+//! which is discovered from `ipv4only.arpa` (RFC 7050) in `auto` mode.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::RwLock,
+    time::Duration,
+};
+
+use ipnet::Ipv6Net;
+use tracing::{debug, info, warn};
+
+use crate::Error;
+
+/// the well-known IPv4 addresses `ipv4only.arpa` resolves to
+const IPV4ONLY_ARPA: [Ipv4Addr; 2] = [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+
+/// how often `auto` checks whether the network changed
+const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// the prefix IPv4 destinations are translated with, if any
+static PREFIX: RwLock<Option<Ipv6Net>> = RwLock::new(None);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// discover the prefix when the host has no IPv4 route
+    Auto,
+    Prefix(Ipv6Net),
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(Mode::Auto);
+        }
+        let prefix = s
+            .parse::<Ipv6Net>()
+            .map_err(|_| Error::InvalidConfig(format!("invalid nat64 prefix: {}", s)))?;
+        if ![32, 40, 48, 56, 64, 96].contains(&prefix.prefix_len()) {
+            return Err(Error::InvalidConfig(format!(
+                "nat64 prefix must be /32, /40, /48, /56, /64 or /96: {}",
+                s
+            )));
+        }
+        Ok(Mode::Prefix(prefix.trunc()))
+    }
+}
+
+/// the indexes of the IPv4 octets in an address with a prefix of `len`,
+/// skipping bits 64 to 71 which must be zero
+fn v4_octets(len: u8) -> impl Iterator<Item = usize> {
+    (len as usize / 8..16).filter(|x| *x != 8).take(4)
+}
+
+pub fn synthesize(prefix: &Ipv6Net, v4: Ipv4Addr) -> Ipv6Addr {
+    let mut octets = prefix.network().octets();
+    for (i, octet) in v4_octets(prefix.prefix_len()).zip(v4.octets()) {
+        octets[i] = octet;
+    }
+    Ipv6Addr::from(octets)
+}
+
+pub fn extract(prefix_len: u8, v6: Ipv6Addr) -> Ipv4Addr {
+    let octets = v6.octets();
+    let mut v4 = [0u8; 4];
+    for (octet, i) in v4.iter_mut().zip(v4_octets(prefix_len)) {
+        *octet = octets[i];
+    }
+    Ipv4Addr::from(v4)
+}
+
+pub fn prefix() -> Option<Ipv6Net> {
+    *PREFIX.read().unwrap()
+}
+
+fn set_prefix(prefix: Option<Ipv6Net>) {
+    let mut current = PREFIX.write().unwrap();
+    if *current != prefix {
+        match prefix {
+            Some(prefix) => info!("dialing IPv4 destinations through NAT64 prefix {}", prefix),
+            None if current.is_some() => info!("IPv4 is reachable, NAT64 translation is off"),
+            None => {}
+        }
+        *current = prefix;
+    }
+}
+
+/// `ip` as it should be dialed, synthesized if it's a public IPv4 address
+/// and a NAT64 prefix is in use
+pub fn translate_ip(ip: IpAddr) -> IpAddr {
+    match (ip, prefix()) {
+        (IpAddr::V4(v4), Some(prefix))
+            if !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()) =>
+        {
+            IpAddr::V6(synthesize(&prefix, v4))
+        }
+        _ => ip,
+    }
+}
+
+pub fn translate(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(translate_ip(addr.ip()), addr.port())
+}
+
+/// whether the host has a route to the IPv4 internet. connecting a UDP
+/// socket sends nothing, it only looks the route up
+async fn has_ipv4_route() -> bool {
+    match tokio::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await {
+        Ok(s) => s.connect((Ipv4Addr::new(8, 8, 8, 8), 53)).await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// RFC 7050: the system resolver is DNS64 on NAT64 networks, so the AAAA
+/// records of `ipv4only.arpa` carry the prefix
+async fn discover() -> io::Result<Option<Ipv6Net>> {
+    for addr in tokio::net::lookup_host(("ipv4only.arpa", 0)).await? {
+        if let IpAddr::V6(v6) = addr.ip() {
+            for len in [96, 64, 56, 48, 40, 32] {
+                if IPV4ONLY_ARPA.contains(&extract(len, v6)) {
+                    return Ok(Ipv6Net::new(v6, len).ok().map(|x| x.trunc()));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+async fn detect() -> Option<Ipv6Net> {
+    if has_ipv4_route().await {
+        return None;
+    }
+    match discover().await {
+        Ok(prefix) => {
+            if prefix.is_none() {
+                debug!("no IPv4 route and no NAT64 prefix found");
+            }
+            prefix
+        }
+        Err(e) => {
+            warn!("failed to discover NAT64 prefix: {}", e);
+            None
+        }
+    }
+}
+
+/// sets the prefix up, `auto` keeps watching the network afterwards
+pub async fn init(mode: Mode) {
+    match mode {
+        Mode::Prefix(prefix) => set_prefix(Some(prefix)),
+        Mode::Auto => {
+            set_prefix(detect().await);
+            tokio::spawn(async {
+                loop {
+                    tokio::time::sleep(REFRESH_INTERVAL).await;
+                    set_prefix(detect().await);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::{extract, synthesize, Mode};
+
+    #[test]
+    fn test_rfc6052_examples() {
+        let v4 = Ipv4Addr::new(192, 0, 2, 33);
+        for (prefix, expected) in [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::/96", "2001:db8:122:344::c000:221"),
+            ("64:ff9b::/96", "64:ff9b::c000:221"),
+        ] {
+            let prefix = match prefix.parse::<Mode>().unwrap() {
+                Mode::Prefix(prefix) => prefix,
+                Mode::Auto => unreachable!(),
+            };
+            let v6 = synthesize(&prefix, v4);
+            assert_eq!(v6, expected.parse::<std::net::Ipv6Addr>().unwrap());
+            assert_eq!(extract(prefix.prefix_len(), v6), v4);
+        }
+
+        assert!("2001:db8::/33".parse::<Mode>().is_err());
+        assert_eq!("auto".parse::<Mode>().unwrap(), Mode::Auto);
+    }
+}
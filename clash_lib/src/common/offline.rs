@@ -0,0 +1,21 @@
+//! `offline: true` suspends what clash fetches on its own: provider refreshes,
+//! proxy health checks and geo database downloads. Proxying carries on, and
+//! what the user asks for through the API, e.g. a delay test, still runs.
+//! A provider with no cached copy is still fetched once, as it has nothing
+//! to work with otherwise.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tracing::info;
+
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+pub fn set(enable: bool) {
+    if OFFLINE.swap(enable, Ordering::Relaxed) != enable {
+        info!("offline mode {}", if enable { "on" } else { "off" });
+    }
+}
+
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
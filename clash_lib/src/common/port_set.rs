@@ -0,0 +1,95 @@
+//! The ports of a port rule, e.g. `80/443/8000-8999`, kept as merged ranges
+//! so a lookup is a bisection however many are listed.
+
+use std::{fmt::Display, str::FromStr};
+
+use crate::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortSet {
+    /// sorted and disjoint
+    ranges: Vec<(u16, u16)>,
+}
+
+impl PortSet {
+    pub fn contains(&self, port: u16) -> bool {
+        let i = self.ranges.partition_point(|(start, _)| *start <= port);
+        i > 0 && port <= self.ranges[i - 1].1
+    }
+
+    pub fn max(&self) -> Option<u16> {
+        self.ranges.last().map(|x| x.1)
+    }
+}
+
+impl FromStr for PortSet {
+    type Err = Error;
+
+    /// ports and `start-end` ranges, separated by `/` or `,`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidConfig(format!("invalid port: {}", s));
+        let mut ranges = s
+            .split(['/', ','])
+            .map(|x| {
+                let x = x.trim();
+                let (start, end) = x.split_once('-').unwrap_or((x, x));
+                let start = start.trim().parse::<u16>().map_err(|_| invalid())?;
+                let end = end.trim().parse::<u16>().map_err(|_| invalid())?;
+                if start > end {
+                    return Err(invalid());
+                }
+                Ok((start, end))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        ranges.sort_unstable();
+        let mut merged: Vec<(u16, u16)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Ok(Self { ranges: merged })
+    }
+}
+
+impl Display for PortSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (start, end)) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                f.write_str("/")?;
+            }
+            if start == end {
+                write!(f, "{}", start)?;
+            } else {
+                write!(f, "{}-{}", start, end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PortSet;
+
+    #[test]
+    fn test_port_set() {
+        let ports = "443/8000-8999, 80 ,8443-9000/81"
+            .parse::<PortSet>()
+            .unwrap();
+        assert_eq!(ports.to_string(), "80-81/443/8000-9000");
+        for x in [80, 81, 443, 8000, 8500, 9000] {
+            assert!(ports.contains(x), "{}", x);
+        }
+        for x in [0, 79, 82, 442, 444, 7999, 9001, 65535] {
+            assert!(!ports.contains(x), "{}", x);
+        }
+
+        assert_eq!("0-65535".parse::<PortSet>().unwrap().to_string(), "0-65535");
+        for x in ["", "http", "80/", "2000-1000", "65536", "1-2-3"] {
+            assert!(x.parse::<PortSet>().is_err(), "{}", x);
+        }
+    }
+}
@@ -0,0 +1,389 @@
+//! `system-proxy: true` points the OS proxy settings at the mixed port while
+//! clash runs, and puts back what was there before on shutdown.
+//!
+//! macOS goes through `networksetup`, which writes the SystemConfiguration
+//! preferences of every network service. Windows writes the per-user
+//! WinINET settings in the registry and tells WinINET to reload them.
+//! They are put back on ctrl-c and SIGTERM, a killed process leaves them
+//! pointing at clash.
+
+use std::{io, net::SocketAddr};
+
+use tracing::{info, warn};
+
+/// sites that are never proxied
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+const BYPASS: [&str; 6] = [
+    "localhost",
+    "127.0.0.1",
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "*.local",
+];
+
+/// the proxy settings clash replaced, restored when dropped
+#[cfg_attr(not(any(target_os = "macos", target_os = "windows")), allow(dead_code))]
+pub struct SystemProxy {
+    restored: bool,
+    #[cfg(target_os = "macos")]
+    saved: Vec<macos::ServiceProxies>,
+    #[cfg(target_os = "windows")]
+    saved: windows::InternetSettings,
+}
+
+impl SystemProxy {
+    #[cfg(target_os = "macos")]
+    pub fn enable(addr: SocketAddr) -> io::Result<Self> {
+        let saved = macos::enable(addr)?;
+        info!("system proxy set to {}", addr);
+        Ok(Self {
+            restored: false,
+            saved,
+        })
+    }
+
+    #[cfg(target_os = "windows")]
+    pub fn enable(addr: SocketAddr) -> io::Result<Self> {
+        let saved = windows::enable(addr)?;
+        info!("system proxy set to {}", addr);
+        Ok(Self {
+            restored: false,
+            saved,
+        })
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    pub fn enable(_: SocketAddr) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "system proxy is only supported on macOS and Windows",
+        ))
+    }
+
+    /// puts the replaced settings back right away rather than whenever
+    /// it's dropped
+    pub fn restore(mut self) {
+        self.put_back();
+    }
+
+    fn put_back(&mut self) {
+        if std::mem::replace(&mut self.restored, true) {
+            return;
+        }
+        #[cfg(target_os = "macos")]
+        let rv = macos::restore(&self.saved);
+        #[cfg(target_os = "windows")]
+        let rv = windows::restore(&self.saved);
+        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+        let rv: io::Result<()> = Ok(());
+
+        match rv {
+            Ok(_) => info!("system proxy restored"),
+            Err(e) => warn!("failed to restore system proxy: {}", e),
+        }
+    }
+}
+
+impl Drop for SystemProxy {
+    fn drop(&mut self) {
+        self.put_back();
+    }
+}
+
+/// `networksetup -getwebproxy` and the like:
+/// ```text
+/// Enabled: Yes
+/// Server: 127.0.0.1
+/// Port: 7890
+/// Authenticated Proxy Enabled: 0
+/// ```
+#[cfg(any(target_os = "macos", test))]
+fn parse_networksetup_proxy(output: &str) -> Option<(bool, String, u16)> {
+    let mut enabled = None;
+    let mut server = None;
+    let mut port = None;
+    for line in output.lines() {
+        match line.split_once(':') {
+            Some(("Enabled", v)) => enabled = Some(v.trim() == "Yes"),
+            Some(("Server", v)) => server = Some(v.trim().to_owned()),
+            Some(("Port", v)) => port = v.trim().parse().ok(),
+            _ => {}
+        }
+    }
+    Some((
+        enabled?,
+        server.unwrap_or_default(),
+        port.unwrap_or_default(),
+    ))
+}
+
+/// the value of `name` in `reg query` output:
+/// ```text
+/// HKEY_CURRENT_USER\Software\...\Internet Settings
+///     ProxyEnable    REG_DWORD    0x1
+/// ```
+#[cfg(any(target_os = "windows", test))]
+fn parse_reg_value(output: &str, name: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        if fields.next()? != name {
+            return None;
+        }
+        let _typ = fields.next()?;
+        Some(fields.collect::<Vec<_>>().join(" "))
+    })
+}
+
+#[cfg(target_os = "macos")]
+mod macos {
+    use std::{io, net::SocketAddr, process::Command};
+
+    use super::{parse_networksetup_proxy, BYPASS};
+
+    /// the `networksetup` getter and setter of each proxy kind
+    const KINDS: [(&str, &str); 3] = [
+        ("-getwebproxy", "-setwebproxy"),
+        ("-getsecurewebproxy", "-setsecurewebproxy"),
+        ("-getsocksfirewallproxy", "-setsocksfirewallproxy"),
+    ];
+
+    pub struct ServiceProxies {
+        service: String,
+        /// enabled, server and port, in the order of `KINDS`
+        proxies: Vec<(bool, String, u16)>,
+        bypass: Vec<String>,
+    }
+
+    fn networksetup(args: &[&str]) -> io::Result<String> {
+        let output = Command::new("networksetup").args(args).output()?;
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "networksetup {}: {}",
+                    args.join(" "),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// the enabled network services, the disabled ones start with `*`
+    fn services() -> io::Result<Vec<String>> {
+        Ok(networksetup(&["-listallnetworkservices"])?
+            .lines()
+            .skip(1)
+            .filter(|x| !x.is_empty() && !x.starts_with('*'))
+            .map(str::to_owned)
+            .collect())
+    }
+
+    fn set_bypass(service: &str, bypass: &[String]) -> io::Result<()> {
+        let mut args = vec!["-setproxybypassdomains", service];
+        if bypass.is_empty() {
+            args.push("Empty");
+        } else {
+            args.extend(bypass.iter().map(String::as_str));
+        }
+        networksetup(&args).map(|_| ())
+    }
+
+    pub fn enable(addr: SocketAddr) -> io::Result<Vec<ServiceProxies>> {
+        let host = addr.ip().to_string();
+        let port = addr.port().to_string();
+
+        let mut saved = vec![];
+        for service in services()? {
+            let mut proxies = vec![];
+            for (get, _) in KINDS {
+                let output = networksetup(&[get, &service])?;
+                proxies.push(parse_networksetup_proxy(&output).unwrap_or_default());
+            }
+            let bypass = networksetup(&["-getproxybypassdomains", &service])?
+                .lines()
+                .filter(|x| !x.is_empty() && !x.starts_with("There aren't any"))
+                .map(str::to_owned)
+                .collect();
+            saved.push(ServiceProxies {
+                service: service.clone(),
+                proxies,
+                bypass,
+            });
+
+            let rv = KINDS
+                .iter()
+                .try_for_each(|(_, set)| networksetup(&[set, &service, &host, &port]).map(|_| ()))
+                .and_then(|_| set_bypass(&service, &BYPASS.map(str::to_owned)));
+            // no service is left half done
+            if let Err(e) = rv {
+                restore(&saved).ok();
+                return Err(e);
+            }
+        }
+        Ok(saved)
+    }
+
+    pub fn restore(saved: &[ServiceProxies]) -> io::Result<()> {
+        for s in saved {
+            for ((_, set), (enabled, server, port)) in KINDS.iter().zip(&s.proxies) {
+                let state = set.to_string() + "state";
+                if *enabled {
+                    networksetup(&[set, &s.service, server, &port.to_string()])?;
+                } else {
+                    networksetup(&[&state, &s.service, "off"])?;
+                }
+            }
+            set_bypass(&s.service, &s.bypass)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod windows {
+    use std::{ffi::c_void, io, net::SocketAddr, process::Command, ptr};
+
+    use super::{parse_reg_value, BYPASS};
+
+    const KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings";
+    const INTERNET_OPTION_REFRESH: u32 = 37;
+    const INTERNET_OPTION_SETTINGS_CHANGED: u32 = 39;
+
+    #[link(name = "wininet")]
+    extern "system" {
+        fn InternetSetOptionW(
+            internet: *mut c_void,
+            option: u32,
+            buffer: *mut c_void,
+            len: u32,
+        ) -> i32;
+    }
+
+    pub struct InternetSettings {
+        enable: bool,
+        server: Option<String>,
+        overrides: Option<String>,
+    }
+
+    fn reg(args: &[&str]) -> io::Result<String> {
+        let output = Command::new("reg").args(args).output()?;
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "reg {}: {}",
+                    args.join(" "),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn query(name: &str) -> Option<String> {
+        reg(&["query", KEY, "/v", name])
+            .ok()
+            .and_then(|x| parse_reg_value(&x, name))
+    }
+
+    fn set(name: &str, typ: &str, value: &str) -> io::Result<()> {
+        reg(&["add", KEY, "/v", name, "/t", typ, "/d", value, "/f"]).map(|_| ())
+    }
+
+    fn unset(name: &str) -> io::Result<()> {
+        match reg(&["delete", KEY, "/v", name, "/f"]) {
+            // it wasn't there
+            Err(_) if query(name).is_none() => Ok(()),
+            rv => rv.map(|_| ()),
+        }
+    }
+
+    /// makes the running programs pick the new settings up
+    fn notify() {
+        unsafe {
+            InternetSetOptionW(
+                ptr::null_mut(),
+                INTERNET_OPTION_SETTINGS_CHANGED,
+                ptr::null_mut(),
+                0,
+            );
+            InternetSetOptionW(ptr::null_mut(), INTERNET_OPTION_REFRESH, ptr::null_mut(), 0);
+        }
+    }
+
+    pub fn enable(addr: SocketAddr) -> io::Result<InternetSettings> {
+        let saved = InternetSettings {
+            enable: query("ProxyEnable").is_some_and(|x| x == "0x1"),
+            server: query("ProxyServer"),
+            overrides: query("ProxyOverride"),
+        };
+
+        // WinINET can't take the CIDRs
+        let overrides = BYPASS
+            .iter()
+            .filter(|x| !x.contains('/'))
+            .copied()
+            .chain(["10.*", "172.16.*", "192.168.*", "<local>"])
+            .collect::<Vec<_>>()
+            .join(";");
+        set("ProxyServer", "REG_SZ", &addr.to_string())?;
+        set("ProxyOverride", "REG_SZ", &overrides)?;
+        set("ProxyEnable", "REG_DWORD", "1")?;
+        notify();
+        Ok(saved)
+    }
+
+    pub fn restore(saved: &InternetSettings) -> io::Result<()> {
+        match &saved.server {
+            Some(server) => set("ProxyServer", "REG_SZ", server)?,
+            None => unset("ProxyServer")?,
+        }
+        match &saved.overrides {
+            Some(overrides) => set("ProxyOverride", "REG_SZ", overrides)?,
+            None => unset("ProxyOverride")?,
+        }
+        set(
+            "ProxyEnable",
+            "REG_DWORD",
+            if saved.enable { "1" } else { "0" },
+        )?;
+        notify();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_networksetup_proxy, parse_reg_value};
+
+    #[test]
+    fn test_parse_networksetup_proxy() {
+        let output = "Enabled: Yes\nServer: 10.0.0.1\nPort: 3128\nAuthenticated Proxy Enabled: 0\n";
+        assert_eq!(
+            parse_networksetup_proxy(output),
+            Some((true, "10.0.0.1".to_owned(), 3128))
+        );
+        let output = "Enabled: No\nServer: \nPort: 0\nAuthenticated Proxy Enabled: 0\n";
+        assert_eq!(
+            parse_networksetup_proxy(output),
+            Some((false, String::new(), 0))
+        );
+        assert_eq!(parse_networksetup_proxy("** Error: unknown service"), None);
+    }
+
+    #[test]
+    fn test_parse_reg_value() {
+        let output = "\r\nHKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Internet Settings\r\n    ProxyEnable    REG_DWORD    0x1\r\n    ProxyOverride    REG_SZ    localhost;<local>\r\n";
+        assert_eq!(
+            parse_reg_value(output, "ProxyEnable"),
+            Some("0x1".to_owned())
+        );
+        assert_eq!(
+            parse_reg_value(output, "ProxyOverride"),
+            Some("localhost;<local>".to_owned())
+        );
+        assert_eq!(parse_reg_value(output, "ProxyServer"), None);
+    }
+}
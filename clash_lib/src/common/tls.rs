@@ -5,8 +5,10 @@ use rustls::{
 };
 use tracing::warn;
 
-use rustls::{Certificate, ServerName};
-use std::{sync::Arc, time::SystemTime};
+use rustls::{Certificate, PrivateKey, ServerName};
+use std::{fs::File, io, io::BufReader, sync::Arc, time::SystemTime};
+
+use super::errors::new_io_error;
 
 pub static GLOBAL_ROOT_STORE: Lazy<Arc<RootCertStore>> = Lazy::new(|| global_root_store());
 
@@ -23,6 +25,28 @@ fn global_root_store() -> Arc<RootCertStore> {
     Arc::new(root_store)
 }
 
+/// reads the PEM certificate chain in `cert` and the first PKCS#8, RSA or
+/// EC private key in `key`
+pub fn load_certificate_and_key(
+    cert: &str,
+    key: &str,
+) -> io::Result<(Vec<Certificate>, PrivateKey)> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| new_io_error(format!("no private key found in {}", key).as_str()))?;
+    Ok((certs, key))
+}
+
 /// Warning: NO validation on certs.
 pub struct DummyTlsVerifier;
 
@@ -49,6 +49,12 @@ impl<T: Sync + Send + Clone> Node<T> {
     }
 }
 
+impl<T: Sync + Send + Clone> Default for StringTrie<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: Sync + Send + Clone> StringTrie<T> {
     pub fn new() -> Self {
         StringTrie {
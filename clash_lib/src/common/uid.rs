@@ -0,0 +1,166 @@
+//! Which user, and which process, owns a local socket, for the `UID` rule
+//! and the scripts. Looked up in procfs, so only on Linux and Android;
+//! elsewhere no socket has an owner.
+
+#![cfg_attr(not(any(target_os = "linux", target_os = "android")), allow(dead_code))]
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::session::Network;
+
+/// the uid of the socket bound to `local`, the source of a session from
+/// this host
+pub fn owner_uid(network: Network, local: SocketAddr) -> Option<u32> {
+    find_socket(network, local).map(|x| x.0)
+}
+
+/// the name of the process having the socket bound to `local` open, found
+/// by the inode of the socket among the descriptors of every process
+pub fn owner_process(network: Network, local: SocketAddr) -> Option<String> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let (_, inode) = find_socket(network, local)?;
+        let link = format!("socket:[{}]", inode);
+        std::fs::read_dir("/proc").ok()?.flatten().find_map(|pid| {
+            let owns = std::fs::read_dir(pid.path().join("fd"))
+                .ok()?
+                .flatten()
+                .any(|fd| {
+                    std::fs::read_link(fd.path()).is_ok_and(|x| x.as_os_str() == link.as_str())
+                });
+            owns.then(|| std::fs::read_to_string(pid.path().join("comm")).ok())
+                .flatten()
+                .map(|x| x.trim_end().to_owned())
+        })
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = (network, local);
+        None
+    }
+}
+
+/// the uid and inode of the socket bound to `local`
+fn find_socket(network: Network, local: SocketAddr) -> Option<(u32, u64)> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let local = normalize(local);
+        let tables: &[&str] = match network {
+            Network::Tcp => &["/proc/net/tcp", "/proc/net/tcp6"],
+            Network::Udp => &["/proc/net/udp", "/proc/net/udp6"],
+        };
+        let tables = tables
+            .iter()
+            .filter_map(|x| std::fs::read_to_string(x).ok())
+            .collect::<Vec<_>>();
+        // an unconnected UDP socket is only bound to its port
+        tables
+            .iter()
+            .find_map(|x| find_entry(x, local, false))
+            .or_else(|| {
+                (network == Network::Udp)
+                    .then(|| tables.iter().find_map(|x| find_entry(x, local, true)))
+                    .flatten()
+            })
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = (network, local);
+        None
+    }
+}
+
+/// the uid and inode of the entry of a `/proc/net/{tcp,udp}{,6}` table
+/// whose local address is `local`, or, if `wildcard`, is unspecified with
+/// the port of `local`
+fn find_entry(table: &str, local: SocketAddr, wildcard: bool) -> Option<(u32, u64)> {
+    // sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode
+    table.lines().skip(1).find_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let addr = parse_addr(fields.get(1)?)?;
+        let matches = if wildcard {
+            addr.port() == local.port() && addr.ip().is_unspecified()
+        } else {
+            addr == local
+        };
+        matches
+            .then(|| Some((fields.get(7)?.parse().ok()?, fields.get(9)?.parse().ok()?)))
+            .flatten()
+    })
+}
+
+/// `0100007F:0050`, the address words in host order and the port in network
+/// order, as the kernel prints them
+fn parse_addr(s: &str) -> Option<SocketAddr> {
+    let (ip, port) = s.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut words = (0..ip.len()).step_by(8).map(|i| {
+        ip.get(i..i + 8)
+            .and_then(|x| u32::from_str_radix(x, 16).ok())
+    });
+    let ip = match ip.len() {
+        8 => IpAddr::V4(Ipv4Addr::from(words.next()??.to_ne_bytes())),
+        32 => {
+            let mut octets = [0u8; 16];
+            for chunk in octets.chunks_mut(4) {
+                chunk.copy_from_slice(&words.next()??.to_ne_bytes());
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(normalize(SocketAddr::new(ip, port)))
+}
+
+/// v4 sockets of a dual stack one show up in the v6 tables
+fn normalize(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_entry, parse_addr};
+
+    /// the words are in host order, these are as a little endian host
+    /// prints them
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn test_find_entry() {
+        assert_eq!(
+            parse_addr("0100007F:1F90"),
+            Some("127.0.0.1:8080".parse().unwrap())
+        );
+        assert_eq!(
+            parse_addr("0000000000000000FFFF00000100007F:0035"),
+            Some("127.0.0.1:53".parse().unwrap())
+        );
+        assert_eq!(
+            parse_addr("B80D0120000000000000000001000000:01BB"),
+            Some("[2001:db8::1]:443".parse().unwrap())
+        );
+        assert_eq!(parse_addr("0100007F"), None);
+        assert_eq!(parse_addr("0100007:1F90"), None);
+
+        let tcp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1 1 0 100 0 0 10 0
+   1: 0100007F:C350 0100007F:1F90 01 00000000:00000000 00:00000000 00000000  1000        0 2 1 0 20 4 30 10 -1
+   2: 00000000:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000   101        0 3 2 0 0 0 0 0";
+        let local = "127.0.0.1:50000".parse().unwrap();
+        assert_eq!(find_entry(tcp, local, false), Some((1000, 2)));
+        assert_eq!(
+            find_entry(tcp, "127.0.0.1:50001".parse().unwrap(), false),
+            None
+        );
+        assert_eq!(find_entry(tcp, "10.0.0.2:53".parse().unwrap(), false), None);
+        assert_eq!(
+            find_entry(tcp, "10.0.0.2:53".parse().unwrap(), true),
+            Some((101, 3))
+        );
+    }
+}
@@ -1,12 +1,13 @@
 use crate::Error;
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::{collections::HashMap, fmt::Display};
+use std::{collections::HashMap, fmt::Display, net::IpAddr};
 
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
 
-#[derive(Serialize, Deserialize, Default, Copy, Clone)]
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum RunMode {
     #[serde(alias = "Global")]
@@ -81,6 +82,7 @@ impl Display for LogLevel {
 ///   enhanced-mode: fake-ip
 ///   fake-ip-range: 198.18.0.2/16 # Fake IP addresses pool CIDR
 ///   # use-hosts: true # lookup hosts and return IP record
+///   # use-system-hosts: true # and /etc/hosts, reloaded when it changes
 
 ///   # Hostnames in this list will not be resolved with fake IPs
 ///   # i.e. questions to these domain names will always be answered with their
@@ -90,14 +92,23 @@ impl Display for LogLevel {
 ///   #   - localhost.ptlogin2.qq.com
 
 ///   # Supports UDP, TCP, DoT, DoH. You can specify the port to connect to.
-///   # All DNS questions are sent directly to the nameserver, without proxies
-///   # involved. Clash answers the DNS question with the first result gathered.
+///   # DNS questions are sent directly to the nameserver, unless it names a
+///   # proxy or group to go through. Clash answers the DNS question with the
+///   # first result gathered.
 ///   nameserver:
 ///     - 114.114.114.114 # default value
 ///     - 1.1.1.1 # default value
 ///     - tls://1.1.1.1:853 # DNS over TLS
-///     - https://1.1.1.1/dns-query # DNS over HTTPS
-/// #    - dhcp://en0 # dns from dhcp
+///     - https://1.1.1.1/dns-query?weight=3 # DNS over HTTPS
+///     - https://dns.example/resolve#h2&header=X-Token:abc # DoH with its own path, HTTP version and headers
+///     - h3://dns.google/dns-query # DoH over HTTP/3, falls back to HTTP/2 when QUIC is blocked
+///     - https://1.1.1.1/dns-query#ProxyGroupA # through a proxy or group, also `#proxy=ProxyGroupA`
+/// #    - dhcp://en0 # dns from dhcp, re-probed as the lease or network changes
+/// #    - dhcp://en0,wlan0 # from whichever of these are up
+///   # race (default): every nameserver at once, the first answer wins
+///   # sequential: one after another in order, the next one on failure
+///   # weighted: one picked at random by `weight` (default 1), the others on failure
+///   # strategy: race
 
 /// allow-lan: true
 /// mode: rule
@@ -111,6 +122,7 @@ impl Display for LogLevel {
 /// profile:
 ///   store-selected: true
 ///   store-fake-ip: false
+///   store-dns-cache: false
 
 /// proxy-groups:
 ///   - name: "relay"
@@ -169,6 +181,14 @@ impl Display for LogLevel {
 ///     proxies:
 ///       - DIRECT
 
+///   - name: "all-vmess"
+///     type: url-test
+///     # every proxy server, including ones added via the API later on
+///     include-all: true
+///     filter: "vmess"
+///     url: "http://www.gstatic.com/generate_204"
+///     interval: 300
+
 /// proxies:
 ///   - name: plain-vmess
 ///     type: vmess
@@ -178,7 +198,25 @@ impl Display for LogLevel {
 ///     alterId: 0
 ///     cipher: auto
 ///     udp: true
+///     # full cone UDP with v2ray-core v5+ servers, requires alterId: 0
+///     packet-encoding: xudp
 ///     skip-cert-verify: true
+///   - name: vmess-via-ws
+///     type: vmess
+///     server: 10.0.0.14
+///     port: 16823
+///     uuid: b831381d-6324-4d53-ad4f-8cda48b30811
+///     alterId: 0
+///     cipher: auto
+///     # connect to 10.0.0.14 through another outbound or group
+///     dialer-proxy: ws-vmess
+///     # at most 3 sessions at once, groups spill over to their next member
+///     max-connections: 3
+///     # the local IP to dial from, on hosts with several
+///     bind-address: 192.0.2.10
+///   - name: DIRECT-2
+///     type: direct
+///     bind-address: 192.0.2.11
 ///   - name: ws-vmess
 ///     type: vmess
 ///     server: 10.0.0.13
@@ -219,6 +257,19 @@ impl Display for LogLevel {
 ///     h2-opts:
 ///       path: /ray
 
+///   - name: quic-vmess
+///     type: vmess
+///     server: 10.0.0.13
+///     port: 8445
+///     uuid: b831381d-6324-4d53-ad4f-8cda48b30811
+///     alterId: 0
+///     cipher: auto
+///     tls: true
+///     network: quic
+///     quic-opts:
+///       congestion-controller: bbr # cubic, new_reno or bbr
+///       zero-rtt: true
+
 ///   - name: vmess-altid
 ///     type: vmess
 ///     server: tw-1.ac.laowanxiang.com
@@ -239,6 +290,37 @@ impl Display for LogLevel {
 ///     cipher: aes-256-gcm
 ///     password: "password"
 ///     udp: true
+///   - name: "ss-obfs"
+///     type: ss
+///     server: 10.0.0.13
+///     port: 8388
+///     cipher: aes-256-gcm
+///     password: "password"
+///     plugin: obfs
+///     plugin-opts:
+///       mode: tls # or http
+///       host: bing.com
+///   - name: "ss-v2ray-plugin"
+///     type: ss
+///     server: 10.0.0.13
+///     port: 443
+///     cipher: aes-256-gcm
+///     password: "password"
+///     plugin: v2ray-plugin
+///     plugin-opts:
+///       mode: websocket
+///       tls: true
+///       host: example.com
+///       path: /ws
+///       mux: true
+///   - name: "ss-xray-plugin"
+///     type: ss
+///     server: 10.0.0.13
+///     port: 443
+///     cipher: aes-256-gcm
+///     password: "password"
+///     plugin: /usr/bin/xray-plugin # the absolute path of any SIP003 plugin, or a known one by name
+///     plugin-opts: "tls;host=example.com"
 ///   - name: "trojan"
 ///     type: trojan
 ///     server: 10.0.0.13
@@ -250,6 +332,54 @@ impl Display for LogLevel {
 ///       - h2
 ///       - http/1.1
 ///     skip-cert-verify: true
+///     # ClientHello split into 10-30 byte records, 5-15ms apart
+///     tls-fragment: 10-30,5-15
+///     smux:
+///       enabled: true
+///       protocol: h2mux # or yamux
+///       max-connections: 4
+///       max-streams: 8
+///     udp-over-tcp: true # carry UDP over TCP streams with UoT v2
+///   - name: "trojan-h2"
+///     type: trojan
+///     server: 10.0.0.13
+///     port: 443
+///     password: password1
+///     network: h2
+///     h2-opts:
+///       host:
+///         - cdn.example.com
+///       path: /trojan
+///   - name: "anytls"
+///     type: anytls
+///     server: 10.0.0.14
+///     port: 443
+///     password: password1
+///     # sni: example.com
+///     skip-cert-verify: true
+///     udp: true # carried as UDP over TCP
+///     idle-session-timeout: 30 # seconds an idle session is kept for reuse
+///   - name: "wg"
+///     type: wireguard
+///     server: 10.0.0.15
+///     port: 51820
+///     private-key: eCtXsJZ27+4PbhDkHnB923tkUn2Gj59wZw5wFA75MnU=
+///     public-key: Cr8hWlKvtDt7nrvf+f0brNQQzabAqrjfBvas9pmowjo=
+///     # pre-shared-key: 31aIhAPwktDGpH4JDhA8GNvjFXEf/a6+UaQRyOAiyfM=
+///     ip: 172.16.0.2 # the addresses of this end inside the tunnel
+///     # ipv6: fd01:5ca1:ab1e::2
+///     mtu: 1420
+///     udp: true
+///     # persistent-keepalive: 25
+///     # remote-dns-resolve: true # resolve domains with `dns` through the tunnel
+///     # dns: [1.1.1.1]
+///     # peers: # in place of server/port/public-key, the peer with the most
+///     #        # specific allowed-ips containing the destination is used
+///     #   - server: 10.0.0.16
+///     #     port: 51820
+///     #     public-key: Cr8hWlKvtDt7nrvf+f0brNQQzabAqrjfBvas9pmowjo=
+///     #     allowed-ips: [10.1.0.0/16]
+///     # dialer-proxy: ss1 # send the tunnel packets through another proxy
 
 /// proxy-providers:
 ///   file-provider:
@@ -260,6 +390,18 @@ impl Display for LogLevel {
 ///       enable: true
 ///       url: http://www.gstatic.com/generate_204
 ///       interval: 300
+///       max-concurrent: 8 # optional, at most 8 tests at the same time for this provider
+///       spacing: 50 # optional, milliseconds between the start of two tests
+///   subscription:
+///     type: http
+///     # a clash config, or share links (ss://, vmess://, trojan://, ...),
+///     # one per line, optionally base64 encoded
+///     url: https://example.com/sub
+///     path: ./sub.yaml
+///     interval: 3600
+///     # a url-test group per country of the proxies, by their flag or
+///     # country name, e.g. subscription-HK, which rules may name
+///     auto-group-by: country
 
 /// rule-providers:
 ///   file-provider:
@@ -267,18 +409,54 @@ impl Display for LogLevel {
 ///     path: ./rule-set.yaml
 ///     interval: 300
 ///     behavior: domain
+///   allow-list:
+///     type: file
+///     path: ./allow.yaml
+///     behavior: domain
+///   cn-ip:
+///     type: http
+///     url: https://example.com/cn.mrs
+///     path: ./cn.mrs
+///     interval: 86400
+///     behavior: ipcidr # domain, ipcidr or classical
+///     format: mrs # yaml (default), text with an entry per line, or mrs
+///   lan:
+///     type: inline # the entries right here
+///     behavior: classical
+///     payload:
+///       - IP-CIDR,192.168.0.0/16
+///       - DOMAIN-SUFFIX,lan
+///   filtered:
+///     type: compose # or, and, minus, left to right
+///     behavior: domain
+///     expression: file-provider minus allow-list
 
 /// rules:
 ///   - DOMAIN,ipinfo.io,relay
 ///   - RULE-SET,file-provider,trojan
 ///   - GEOIP,CN,relay
-///   - DOMAIN-SUFFIX,facebook.com,REJECT
+///   - DOMAIN-SUFFIX,facebook.com,REJECT # close right away
+///   - DOMAIN-SUFFIX,doubleclick.net,REJECT-HTTP # 403 page for http, alert for tls
+///   - DOMAIN-KEYWORD,tracker,REJECT-DROP # hold silently for 30s
 ///   - DOMAIN-KEYWORD,google,select
 ///   - DOMAIN,google.com,select
 ///   - SRC-IP-CIDR,192.168.1.1/24,DIRECT
 ///   - GEOIP,CN,DIRECT
+///   - GEOIP,LAN,DIRECT # private, loopback and link local addresses
+///   - GEOIP,!CN/HK,relay # any of the codes, or none of them with `!`
+///   - GEOSITE,category-ads-all,REJECT # see `geosite`
+///   - GEOSITE,google@cn,DIRECT # only the domains with the `cn` attribute
 ///   - DST-PORT,53,trojan
+///   - DST-PORT,80/443/8000-8999,proxy # or listed with commas
 ///   - SRC-PORT,7777,DIRECT
+///   - SRC-DEVICE,phone,relay # see `devices`
+///   - NETWORK,ws,select # plain HTTP WebSocket upgrades, sniffed
+///   - NETWORK,udp:443,REJECT # QUIC, so browsers fall back to TCP
+///   - DSCP,46,relay # marked packets through tun, 0-63 or ranges
+///   - UID,10000-19999,relay # Linux and Android only, from this host
+///   - IN-TYPE,SOCKS/HTTP,relay # HTTPS for CONNECT, SOCKS for SOCKS4 and SOCKS5
+///   - IN-PORT,7890,DIRECT
+///   - IN-NAME,ss-in,relay # `HTTP`, `SOCKS5`, `Mixed`, `Redir`, `TProxy`, `Tun` or a `listeners` name
 ///   - MATCH, DIRECT
 /// ...
 /// ```
@@ -303,6 +481,16 @@ pub struct Config {
 
     /// HTTP and SOCKS5 proxy authentication
     pub authentication: Vec<String>,
+    /// Traffic quotas of the users of the inbounds, upload and download
+    /// together in bytes. Their new sessions are rejected once it is used up,
+    /// until `DELETE /users/{name}/stats`. The traffic is only counted in
+    /// memory, so a restart starts everyone over
+    /// # Example
+    /// ```yaml
+    /// user-quotas:
+    ///   kid: 10737418240 # 10 GiB
+    /// ```
+    pub user_quotas: HashMap<String, u64>,
     /// Allow connections to the local-end server from other LAN IP addresses
     #[deprecated = "dont use. see `bind_address`"]
     pub allow_lan: bool,
@@ -312,6 +500,85 @@ pub struct Config {
     /// - setting this to non local IP will enable `allow_lan` automatically
     /// - and if you don't want `allow_lan` to be enabled, you should set this to `localhost` or `127.1`
     pub bind_address: String,
+    /// Max number of concurrent inbound TCP connections across all listeners.
+    /// New connections wait in the listen backlog when the limit is reached.
+    /// Unlimited if not set
+    /// # Example
+    /// ```yaml
+    /// max-connections: 4096
+    /// ```
+    pub max_connections: Option<usize>,
+    /// Points the macOS or Windows proxy settings at `mixed-port` while
+    /// running, and restores them on exit
+    /// # Example
+    /// ```yaml
+    /// system-proxy: true
+    /// ```
+    pub system_proxy: bool,
+    /// Extra inbounds, each with its own port and settings
+    /// # Example
+    /// ```yaml
+    /// listeners:
+    ///   - name: socks-in
+    ///     type: socks # or http, mixed
+    ///     listen: 127.0.0.1
+    ///     port: 7891
+    ///     users: # replaces `authentication`, an empty list allows anyone
+    ///       - username: alice
+    ///         password: secret
+    ///     # who may connect, any source if neither is set. loopback always can
+    ///     allow-lan: true
+    ///     lan-allowed-ips:
+    ///       - 192.168.1.0/24
+    ///       - fd00::1
+    ///   - name: socks-direct
+    ///     type: socks
+    ///     port: 7894
+    ///     # rule, global or direct for every listener, overrides `mode`
+    ///     mode: direct
+    ///   - name: tproxy-in
+    ///     type: tproxy # or redir
+    ///     port: 7893
+    ///   - name: tun-in
+    ///     type: tun
+    ///     device-id: dev://utun1989
+    ///     network: 198.19.0.0/16 # optional, as in the `tun` section
+    ///     dns-hijack: [any:53] # optional, in place of `tun.dns-hijack`
+    ///   - name: ss-in
+    ///     type: shadowsocks
+    ///     listen: 0.0.0.0 # defaults to `bind-address`
+    ///     port: 8388
+    ///     cipher: 2022-blake3-aes-128-gcm # or aes-128-gcm, aes-256-gcm, chacha20-ietf-poly1305, 2022-blake3-aes-256-gcm, 2022-blake3-chacha20-poly1305
+    ///     password: "base64 key for 2022 ciphers"
+    ///     udp: true
+    ///   - name: vmess-in
+    ///     type: vmess
+    ///     port: 10086
+    ///     users:
+    ///       - username: alice # optional
+    ///         uuid: b831381d-6324-4d53-ad4f-8cda48b30811
+    ///     ws-path: /ws # serves over websocket, or `grpc-service-name` for gRPC
+    ///     certificate: ./server.crt # serves over TLS when both are set
+    ///     private-key: ./server.key
+    ///   - name: trojan-in
+    ///     type: trojan
+    ///     port: 443
+    ///     users:
+    ///       - password: example
+    ///     grpc-service-name: GunService
+    ///     certificate: ./server.crt
+    ///     private-key: ./server.key
+    /// ```
+    pub listeners: Vec<HashMap<String, Value>>,
+    /// Limits on latency tests across all proxies and providers.
+    /// Providers and groups can set their own `max-concurrent` and `spacing` on top of these
+    /// # Example
+    /// ```yaml
+    /// health-check:
+    ///   max-concurrent: 32
+    ///   spacing: 20 # milliseconds between the start of two tests
+    /// ```
+    pub health_check: HealthCheckLimit,
     /// Clash router working mode
     /// Either `rule`, `global` or `direct`
     pub mode: RunMode,
@@ -329,23 +596,117 @@ pub struct Config {
     /// Proxy group settings
     pub proxy_group: Vec<HashMap<String, Value>>,
     #[serde(rename = "rules")]
-    /// Rule settings
+    /// Rule settings. The domain of a session is looked up once, when the
+    /// first rule needing an IP is tried: `IP-CIDR`, `IP-CIDR6`, `GEOIP`,
+    /// and `RULE-SET` of `ipcidr` or `classical` rule providers, unless they
+    /// end with `no-resolve`
+    /// # Example
+    /// ```yaml
+    /// rules:
+    ///   - DOMAIN-SUFFIX,google.com,relay
+    ///   - IP-CIDR,10.0.0.0/8,DIRECT,no-resolve
+    ///   - RULE-SET,cn-ips,DIRECT
+    /// ```
     pub rule: Vec<String>,
+    /// Named rule chains, gone through for the sessions the condition of a
+    /// `SUB-RULE` rule matches. Routing carries on with the rule after the
+    /// `SUB-RULE` if none in the chain matches. Chains may jump into other
+    /// chains, as long as none leads back to itself
+    /// # Example
+    /// ```yaml
+    /// sub-rules:
+    ///   streaming:
+    ///     - DOMAIN-SUFFIX,netflix.com,relay
+    ///     - SUB-RULE,(DST-PORT,443),us-only
+    ///   us-only:
+    ///     - GEOIP,US,relay
+    /// rules:
+    ///   - SUB-RULE,(NETWORK,tcp),streaming
+    /// ```
+    pub sub_rules: HashMap<String, Vec<String>>,
+    /// Rhai expressions on the session, matched by `SCRIPT` rules, and
+    /// functions for them. A `main(metadata)` function among them picks the
+    /// proxy ahead of the rules, returning its name, or nothing to leave
+    /// the session to the rules. Needs the `script` cargo feature. Each run
+    /// is stopped after `timeout` milliseconds, at most 100 as scripts run
+    /// on the thread routing the session, and has no access to files or
+    /// the network
+    /// # Example
+    /// ```yaml
+    /// script:
+    ///   timeout: 10
+    ///   code: |
+    ///     fn is_quic(network, port) { network == "udp" && port == 443 }
+    ///     fn main(metadata) {
+    ///       if metadata.process == "curl" { return "DIRECT"; }
+    ///     }
+    ///   shortcuts:
+    ///     quic: is_quic(network, dst_port)
+    ///     lan-ssh: dst_port == 22 && src_ip.starts_with("192.168.")
+    /// rules:
+    ///   - SCRIPT,quic,REJECT
+    /// ```
+    /// The shortcuts see the constants `network`, `type`, `src_ip`,
+    /// `src_port`, `dst_ip` (empty for a domain), `dst_port`, `host`,
+    /// `in_name`, `in_port`, `user` and `process` (empty where unknown),
+    /// `main` the same as fields of `metadata`, the other functions only
+    /// their arguments
+    pub script: Option<Script>,
     /// Hosts
-    pub hosts: HashMap<String, String>,
+    pub hosts: HashMap<String, HostsValue>,
     /// Country database path relative to the $CWD
     pub mmdb: String,
     /// Country database download url
     pub mmdb_download_url: Option<String>,
+    /// Use a v2ray `geoip.dat`, `geoip`, as the country database instead of
+    /// `mmdb`. Its categories, `GEOIP,private,DIRECT`, work as countries
+    pub geodata_mode: bool,
+    /// The `geoip.dat` path relative to the $CWD
+    pub geoip: String,
+    /// The `geoip.dat` download url
+    pub geoip_download_url: Option<String>,
+    /// Domain category database path relative to the $CWD, a v2ray
+    /// `geosite.dat`, only loaded if a `geosite:` option or a `GEOSITE` rule
+    /// is used
+    pub geosite: String,
+    /// Domain category database download url
+    pub geosite_download_url: Option<String>,
 
     /// these options has default vals,
     /// and needs extra processing
     #[deprecated = "this is essentially just dns.ipv6 in original clash"]
     pub ipv6: Option<bool>,
+    /// Dial IPv4 destinations through NAT64 on IPv6-only networks.
+    /// Either `auto`, to discover the prefix when there is no IPv4 route,
+    /// or a fixed prefix
+    /// # Example
+    /// ```yaml
+    /// nat64: auto # or 64:ff9b::/96
+    /// ```
+    pub nat64: Option<String>,
+    /// Dial with MPTCP on Linux, so a multi-homed host can spread a
+    /// connection over its links. Falls back to TCP where the kernel or the
+    /// server doesn't support it. Proxies can set their own `mptcp`
+    /// # Example
+    /// ```yaml
+    /// mptcp: true
+    /// ```
+    pub mptcp: bool,
+    /// Suspends provider refreshes, proxy health checks and geo database
+    /// downloads, e.g. on a metered link. Proxying keeps working. Can be
+    /// switched at runtime with `PATCH /configs`
+    /// # Example
+    /// ```yaml
+    /// offline: true
+    /// ```
+    pub offline: bool,
     /// external controller address
     pub external_controller: Option<String>,
     /// dashboard folder path relative to the $CWD
     pub external_ui: Option<String>,
+    /// folder relative to the $CWD that `POST /connections/{id}/capture`
+    /// writes its pcap files to, the endpoint is off when unset
+    pub capture_dir: Option<String>,
     /// external controller secret
     pub secret: Option<String>,
     #[serde(rename = "interface-name")]
@@ -365,6 +726,69 @@ pub struct Config {
     pub rule_provider: Option<HashMap<String, HashMap<String, Value>>>,
     /// experimental settings, if any
     pub experimental: Option<Experimental>,
+    /// Names LAN devices by their source IP for the `SRC-DEVICE` rule,
+    /// from a DHCP lease file and/or a static mapping
+    /// # Example
+    /// ```yaml
+    /// devices:
+    ///   lease-file: /var/lib/misc/dnsmasq.leases
+    ///   lease-format: dnsmasq # or kea
+    ///   interval: 60 # seconds between reloads of the lease file
+    ///   static:
+    ///     192.168.1.10: phone
+    ///     aa:bb:cc:dd:ee:ff: laptop # a MAC in the lease file
+    /// ```
+    pub devices: Option<Devices>,
+    /// Intercepts HTTP(S) to the listed hosts and applies rewrite rules.
+    /// Needs the `mitm` cargo feature, and clients trusting the CA.
+    /// # Example
+    /// ```yaml
+    /// mitm:
+    ///   enable: true
+    ///   ca-cert: mitm-ca.crt # generated on first run if missing
+    ///   ca-key: mitm-ca.key
+    ///   hosts:
+    ///     - "+.example.com"
+    ///   rewrites:
+    ///     - ^https?://ad\.example\.com/ reject
+    ///     - ^http://example\.com/(.*) 302 https://example.com/$1
+    ///     - ^https://api\.example\.com/ header-add X-Debug 1
+    ///     - ^https://api\.example\.com/ header-del Cookie
+    ///     - ^https://api\.example\.com/ response-header-replace Cache-Control no-cache
+    /// ```
+    pub mitm: Option<Mitm>,
+    /// Reads the domain a connection is for from its first bytes, the TLS or
+    /// QUIC SNI or the HTTP Host, and routes and connects to that instead of
+    /// the IP it was sent to. Only sessions to an IP are sniffed, fake ips
+    /// and the ones redirected to clash by tun, redir or tproxy.
+    /// # Example
+    /// ```yaml
+    /// sniffer:
+    ///   enable: true
+    ///   sniff: # all three on their usual ports if missing
+    ///     tls:
+    ///       ports: [443, 8443]
+    ///     http:
+    ///       ports: [80, "8080-8880"]
+    ///     quic:
+    ///       ports: [443]
+    ///   skip-domain:
+    ///     - "+.push.apple.com"
+    /// ```
+    pub sniffer: Option<Sniffer>,
+    /// Checks the GitHub releases for a newer build with `GET /upgrade` and
+    /// installs it with `POST /upgrade`, from the next start on. A release
+    /// is only installed if its `.sig` checks out against `public-key`.
+    /// # Example
+    /// ```yaml
+    /// updater:
+    ///   repo: Watfaq/clash-rs
+    ///   public-key: |
+    ///     -----BEGIN PUBLIC KEY-----
+    ///     MCowBQYDK2VwAyEA...
+    ///     -----END PUBLIC KEY-----
+    /// ```
+    pub updater: Option<Updater>,
 
     /// tun settings
     /// # Example
@@ -372,6 +796,10 @@ pub struct Config {
     /// tun:
     ///   enable: true
     ///   device-id: "dev://utun1989"
+    ///   stack: gvisor # or system, the default
+    ///   dns-hijack: # answered by the internal resolver, tproxy too
+    ///     - any:53
+    ///     - tcp://any:53
     /// ```
     pub tun: Option<HashMap<String, Value>>,
 }
@@ -410,13 +838,22 @@ impl Default for Config {
             tproxy_port: Default::default(),
             mixed_port: Default::default(),
             authentication: Default::default(),
+            user_quotas: Default::default(),
             allow_lan: Default::default(),
             bind_address: String::from("*"),
+            max_connections: None,
+            system_proxy: false,
+            listeners: Default::default(),
+            health_check: Default::default(),
             mode: Default::default(),
             log_level: Default::default(),
             ipv6: Default::default(),
+            nat64: Default::default(),
+            mptcp: false,
+            offline: false,
             external_controller: Default::default(),
             external_ui: Default::default(),
+            capture_dir: Default::default(),
             secret: Default::default(),
             interface: Default::default(),
             routing_mask: Default::default(),
@@ -425,15 +862,32 @@ impl Default for Config {
             hosts: Default::default(),
             dns: Default::default(),
             experimental: Default::default(),
+            devices: Default::default(),
+            mitm: Default::default(),
+            sniffer: Default::default(),
+            updater: Default::default(),
             profile: Default::default(),
             proxy: Default::default(),
             proxy_group: Default::default(),
             rule: Default::default(),
+            sub_rules: Default::default(),
+            script: Default::default(),
             mmdb: "Country.mmdb".to_string(),
             mmdb_download_url: Some(
                 "https://github.com/Loyalsoldier/geoip/releases/download/202307271745/Country.mmdb"
                     .to_owned(),
             ),
+            geodata_mode: false,
+            geoip: "geoip.dat".to_string(),
+            geoip_download_url: Some(
+                "https://github.com/Loyalsoldier/v2ray-rules-dat/releases/latest/download/geoip.dat"
+                    .to_owned(),
+            ),
+            geosite: "geosite.dat".to_string(),
+            geosite_download_url: Some(
+                "https://github.com/Loyalsoldier/v2ray-rules-dat/releases/latest/download/geosite.dat"
+                    .to_owned(),
+            ),
             tun: Default::default(),
         }
     }
@@ -458,6 +912,11 @@ pub enum DNSListen {
 ///     tcp: 127.0.0.1:5353
 ///     doh: 127.0.0.1:5354
 ///     dot: 127.0.0.1:5355
+///     # the PEM pair DoH and DoT are served with, a dummy one clients
+///     # won't trust if unset
+///     cert: dns.crt
+///     key: dns.key
+///     hostname: dns.example.com # the host DoH requests must be for, any if unset
 /// ```
 
 #[derive(Serialize, Deserialize)]
@@ -468,26 +927,110 @@ pub struct DNS {
     pub enable: bool,
     /// When false, response to AAAA questions will be empty
     pub ipv6: bool,
-    /// Whether to `Config::hosts` as when resolving hostnames
-    pub user_hosts: bool,
+    /// Whether to use `Config::hosts` when resolving hostnames
+    #[serde(alias = "user-hosts")]
+    pub use_hosts: bool,
+    /// Whether to also use the hosts file of the system, `/etc/hosts` or
+    /// `%SystemRoot%\System32\drivers\etc\hosts`, after `Config::hosts`.
+    /// Off by default, so that upgrading doesn't change what resolves
+    pub use_system_hosts: bool,
     /// DNS servers
-    pub nameserver: Vec<String>,
+    pub nameserver: Vec<NameServerDef>,
     /// Fallback DNS servers
-    pub fallback: Vec<String>,
+    pub fallback: Vec<NameServerDef>,
     /// Fallback DNS filter
     pub fallback_filter: FallbackFilter,
     /// DNS server listening address. If not present, the DNS server will be disabled.
     pub listen: Option<DNSListen>,
-    /// Whether to use fake IP addresses
+    /// `fake-ip` answers with fake IP addresses, `redir-host` with the real
+    /// ones and remembers the domain of each, so the domain rules still
+    /// match the connections made to them
     pub enhanced_mode: DNSMode,
     /// Fake IP addresses pool CIDR
     pub fake_ip_range: String,
     /// Fake IP addresses filter
     pub fake_ip_filter: Vec<String>,
+    /// Stop answering fake IPs for domains whose connections keep failing,
+    /// the client closing them without sending anything while the proxy
+    /// works. The learned domains are kept in cache.db
+    pub fake_ip_auto_skip: bool,
     /// Default nameservers, used to resolve DoH hostnames
     pub default_nameserver: Vec<String>,
-    /// Lookup domains via specific nameservers
-    pub nameserver_policy: HashMap<String, String>,
+    /// Lookup domains via specific nameservers. A key is a domain, a
+    /// wildcard, `rule-set:<provider>`, `geosite:<code>` or a comma
+    /// separated list of them. The domains are looked up first, then the
+    /// rule sets and geosite codes in the order they are listed
+    /// # Example
+    /// ```yaml
+    /// nameserver-policy:
+    ///   '+.corp.example,*.lan': 10.0.0.1
+    ///   'rule-set:cn-sites': https://223.5.5.5/dns-query
+    ///   'geosite:cn': 223.5.5.5
+    /// ```
+    pub nameserver_policy: IndexMap<String, String>,
+    /// How a question is spread over the nameservers of a group
+    pub strategy: DNSStrategy,
+    /// Ask the plain `https://` nameservers over HTTP/3, falling back to
+    /// HTTP/2 when it fails
+    pub prefer_h3: bool,
+    /// Probe the nameservers every this many seconds, 0 to only learn of
+    /// the failing ones from the queries. Either way a nameserver failing 3
+    /// times in a row isn't asked for 30 seconds, unless all are failing
+    /// # Example
+    /// ```yaml
+    /// health-check-interval: 60
+    /// ```
+    pub health_check_interval: u64,
+    /// Answers are cached for their TTL, raised to `cache-min-ttl` and
+    /// capped at `cache-max-ttl` seconds. 0 and 86400 if not set
+    /// # Example
+    /// ```yaml
+    /// cache-min-ttl: 30
+    /// cache-max-ttl: 3600
+    /// ```
+    pub cache_min_ttl: u32,
+    pub cache_max_ttl: u32,
+    /// Answer with records up to a day past their TTL, with a TTL of 30,
+    /// while they are refreshed in the background
+    /// # Example
+    /// ```yaml
+    /// serve-stale: true
+    /// ```
+    pub serve_stale: bool,
+    /// Rule providers of the domains the DNS server answers itself with
+    /// `block-mode` rather than asking the nameservers
+    /// # Example
+    /// ```yaml
+    /// blocklist:
+    ///   - ads
+    /// block-rejected: true # also the domains the rules send to REJECT
+    /// block-mode: null-ip # nxdomain, null-ip (0.0.0.0 and ::) or empty
+    /// ```
+    pub blocklist: Vec<String>,
+    pub block_rejected: bool,
+    pub block_mode: DNSBlockMode,
+    /// Answer or route queries by domain before anything else, in order,
+    /// the first matching rule wins
+    /// # Example
+    /// ```yaml
+    /// rules:
+    ///   - domain: nas.home.arpa
+    ///     action: static-ip
+    ///     ip: [192.168.1.10, fd00::10]
+    ///     ttl: 300
+    ///   - domain: +.doubleclick.net,+.adservice.google.com
+    ///     action: nxdomain
+    ///   - domain: +.example.org
+    ///     query-type: [HTTPS, SVCB]
+    ///     action: rcode
+    ///     rcode: refused
+    ///   - domain: +.corp.example
+    ///     action: upstream
+    ///     nameserver:
+    ///       - 10.0.0.1
+    ///       - tls://10.0.0.2:853
+    /// ```
+    pub rules: Vec<DNSRule>,
 }
 
 impl Default for DNS {
@@ -495,7 +1038,8 @@ impl Default for DNS {
         Self {
             enable: Default::default(),
             ipv6: Default::default(),
-            user_hosts: true,
+            use_hosts: true,
+            use_system_hosts: false,
             nameserver: Default::default(),
             fallback: Default::default(),
             fallback_filter: Default::default(),
@@ -503,8 +1047,19 @@ impl Default for DNS {
             enhanced_mode: Default::default(),
             fake_ip_range: String::from("198.18.0.1/16"),
             fake_ip_filter: Default::default(),
+            fake_ip_auto_skip: Default::default(),
             default_nameserver: vec![String::from("114.114.114.114"), String::from("8.8.8.8")],
             nameserver_policy: Default::default(),
+            strategy: Default::default(),
+            prefer_h3: false,
+            health_check_interval: 0,
+            cache_min_ttl: 0,
+            cache_max_ttl: 86400,
+            serve_stale: false,
+            blocklist: Default::default(),
+            block_rejected: false,
+            block_mode: Default::default(),
+            rules: Default::default(),
         }
     }
 }
@@ -518,6 +1073,102 @@ pub enum DNSMode {
     RedirHost,
 }
 
+/// an IP or a domain, or IPs answered in turn
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum HostsValue {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// a nameserver URL, or an object for the DoH ones needing more settings
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum NameServerDef {
+    Url(String),
+    Detailed(DetailedNameServer),
+}
+
+/// ```yaml
+/// - address: https://dns.example/resolve
+///   headers:
+///     X-Token: abc
+///   http-version: "2" # 1.1 or 2, negotiated if not set
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DetailedNameServer {
+    /// the nameserver URL, which may carry all of the options below
+    pub address: String,
+    pub path: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub http_version: Option<String>,
+    pub interface: Option<String>,
+    pub weight: Option<u32>,
+    /// the proxy or group to ask the nameserver through
+    pub proxy: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DNSStrategy {
+    /// asks every nameserver at once and takes the first answer
+    #[default]
+    Race,
+    /// asks the nameservers one by one in the configured order
+    Sequential,
+    /// asks a nameserver picked at random by weight, then the others in
+    /// the same way
+    Weighted,
+}
+
+/// how the DNS server answers the queries for blocked domains
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DNSBlockMode {
+    #[default]
+    Nxdomain,
+    /// 0.0.0.0 for A and :: for AAAA, no records for the others
+    NullIp,
+    /// NOERROR without any records
+    Empty,
+}
+
+/// a `dns.rules` entry
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DNSRule {
+    /// a comma separated list of domains and wildcards, as the keys of
+    /// `nameserver-policy`
+    pub domain: String,
+    /// the query types the rule applies to, all if empty
+    #[serde(default)]
+    pub query_type: Vec<String>,
+    pub action: DNSRuleAction,
+    /// the addresses of `static-ip`
+    #[serde(default)]
+    pub ip: Vec<IpAddr>,
+    /// the TTL of the `static-ip` answers, 60 if not set
+    pub ttl: Option<u32>,
+    /// the response code of `rcode`, e.g. `refused` or `servfail`
+    pub rcode: Option<String>,
+    /// the nameservers `upstream` asks
+    #[serde(default)]
+    pub nameserver: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DNSRuleAction {
+    /// answers A and AAAA queries with `ip`, the others go on as usual
+    StaticIp,
+    Nxdomain,
+    Rcode,
+    #[serde(alias = "upstream-group")]
+    Upstream,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(default)]
 pub struct FallbackFilter {
@@ -528,6 +1179,9 @@ pub struct FallbackFilter {
     #[serde(rename = "ipcidr")]
     pub ip_cidr: Vec<String>,
     pub domain: Vec<String>,
+    /// geosite categories whose domains are only asked to `fallback`,
+    /// `!cn` for the domains that aren't in `cn`
+    pub geosite: Vec<String>,
 }
 
 impl Default for FallbackFilter {
@@ -537,6 +1191,7 @@ impl Default for FallbackFilter {
             geo_ip_code: String::from("CN"),
             ip_cidr: Default::default(),
             domain: Default::default(),
+            geosite: Default::default(),
         }
     }
 }
@@ -544,6 +1199,174 @@ impl Default for FallbackFilter {
 #[derive(Serialize, Deserialize, Default)]
 pub struct Experimental {}
 
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceLeaseFormat {
+    /// `/var/lib/misc/dnsmasq.leases`
+    #[default]
+    Dnsmasq,
+    /// Kea DHCPv4 memfile CSV
+    Kea,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Devices {
+    /// path of the lease file relative to the $CWD
+    pub lease_file: Option<String>,
+    #[serde(default)]
+    pub lease_format: DeviceLeaseFormat,
+    #[serde(default = "default_device_lease_interval")]
+    pub interval: u64,
+    /// device names keyed by IP or MAC
+    #[serde(default, rename = "static")]
+    pub static_mapping: HashMap<String, String>,
+}
+
+fn default_device_lease_interval() -> u64 {
+    60
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Mitm {
+    pub enable: bool,
+    /// path of the CA certificate relative to the $CWD, in PEM
+    pub ca_cert: String,
+    /// path of the CA private key relative to the $CWD, in PEM
+    pub ca_key: String,
+    /// domains to intercept, wildcards as in `fake-ip-filter`, or
+    /// `rule-set:<provider>` for the domains of a rule provider
+    pub hosts: Vec<String>,
+    /// `<url regex> <action> [args]`
+    pub rewrites: Vec<String>,
+}
+
+impl Default for Mitm {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            ca_cert: "mitm-ca.crt".to_owned(),
+            ca_key: "mitm-ca.key".to_owned(),
+            hosts: Default::default(),
+            rewrites: Default::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Script {
+    /// functions the shortcuts can call
+    pub code: String,
+    /// expressions by name, true for the sessions they match
+    pub shortcuts: HashMap<String, String>,
+    /// milliseconds a shortcut or `main` may run for, at most 100
+    pub timeout: u64,
+}
+
+impl Default for Script {
+    fn default() -> Self {
+        Self {
+            code: Default::default(),
+            shortcuts: Default::default(),
+            timeout: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Sniffer {
+    #[serde(default)]
+    pub enable: bool,
+    #[serde(default = "default_sniff_protocols")]
+    pub sniff: SniffProtocols,
+    /// domains never taken from sniffing, wildcards as in `fake-ip-filter`
+    #[serde(default)]
+    pub skip_domain: Vec<String>,
+}
+
+impl Default for Sniffer {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            sniff: default_sniff_protocols(),
+            skip_domain: Default::default(),
+        }
+    }
+}
+
+/// the ports sniffed for each protocol, a protocol left out isn't sniffed
+#[derive(Serialize, Deserialize)]
+pub struct SniffProtocols {
+    #[serde(default, alias = "TLS")]
+    pub tls: Option<SniffPorts>,
+    #[serde(default, alias = "HTTP")]
+    pub http: Option<SniffPorts>,
+    #[serde(default, alias = "QUIC")]
+    pub quic: Option<SniffPorts>,
+}
+
+fn default_sniff_protocols() -> SniffProtocols {
+    let ports = |port| {
+        Some(SniffPorts {
+            ports: vec![SniffPort::Port(port)],
+        })
+    };
+    SniffProtocols {
+        tls: ports(443),
+        http: ports(80),
+        quic: ports(443),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SniffPorts {
+    pub ports: Vec<SniffPort>,
+}
+
+/// a port, or an inclusive range of them as `"8080-8880"`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum SniffPort {
+    Port(u16),
+    Range(String),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Updater {
+    /// the GitHub `owner/name` the releases are taken from
+    pub repo: String,
+    /// ed25519 key in PEM the release signatures are checked against,
+    /// nothing is installed without it
+    pub public_key: Option<String>,
+}
+
+impl Default for Updater {
+    fn default() -> Self {
+        Self {
+            repo: "Watfaq/clash-rs".to_owned(),
+            public_key: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthCheckLimit {
+    /// max number of latency tests running at the same time, no limit if
+    /// not set
+    pub max_concurrent: Option<usize>,
+    /// milliseconds between the start of two latency tests
+    pub spacing: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
@@ -552,6 +1375,9 @@ pub struct Profile {
     pub store_selected: bool,
     /// persistence fakeip
     pub store_fake_ip: bool,
+    /// keep the DNS cache across restarts, the answers are served again for
+    /// what is left of their TTLs
+    pub store_dns_cache: bool,
 }
 
 impl Default for Profile {
@@ -559,6 +1385,7 @@ impl Default for Profile {
         Self {
             store_selected: true,
             store_fake_ip: false,
+            store_dns_cache: false,
         }
     }
 }
@@ -587,7 +1414,7 @@ port: 7890
 # Port of SOCKS5 proxy server on the local end
 socks-port: 7891
 
-# Transparent proxy server port for Linux and macOS (Redirect TCP and TProxy UDP)
+# Transparent proxy server port for Linux (iptables/nftables REDIRECT, TCP only)
 # redir-port: 7892
 
 # Transparent proxy server port for Linux (TProxy TCP and TProxy UDP)
@@ -656,10 +1483,13 @@ routing-mark: 6666
 # Non-wildcard domain names have a higher priority than wildcard domain names
 # e.g. foo.example.com > *.example.com > .example.com
 # P.S. +.foo.com equals to .foo.com and foo.com
+# A host may map to several IPs, taken in turn, or to another domain
 hosts:
   # '*.clash.dev': 127.0.0.1
   # '.dev': 127.0.0.1
   # 'alpha.clash.dev': '::1'
+  # 'cdn.clash.dev': [1.1.1.1, 1.0.0.1]
+  # 'git.clash.dev': 'code.clash.dev'
 
 profile:
   # Store the `select` results in $HOME/.config/clash/.cache
@@ -670,6 +1500,9 @@ profile:
   # persistence fakeip
   store-fake-ip: true
 
+  # save the DNS cache on shutdown and load the answers still fresh on startup
+  store-dns-cache: true
+
 # DNS server settings
 # This section is optional. When not present, the DNS server will be disabled.
 dns:
@@ -682,9 +1515,10 @@ dns:
   default-nameserver:
     - 114.114.114.114
     - 8.8.8.8
-  enhanced-mode: fake-ip # or redir-host (not recommended)
+  enhanced-mode: fake-ip # or redir-host, the real IPs mapped back to their domains for the rules
   fake-ip-range: 198.18.0.1/16 # Fake IP addresses pool CIDR
   # use-hosts: true # lookup hosts and return IP record
+  # use-system-hosts: true # and /etc/hosts, reloaded when it changes
   
   # Hostnames in this list will not be resolved with fake IPs
   # i.e. questions to these domain names will always be answered with their
@@ -692,6 +1526,11 @@ dns:
   # fake-ip-filter:
   #   - '*.lan'
   #   - localhost.ptlogin2.qq.com
+
+  # Learn the domains whose connections keep failing through fake IPs,
+  # e.g. apps validating the DNS answers themselves, and answer them with
+  # real IPs from then on. See the /dns/fakeip/skip API.
+  # fake-ip-auto-skip: true
   
   # Supports UDP, TCP, DoT, DoH. You can specify the port to connect to.
   # All DNS questions are sent directly to the nameserver, without proxies
@@ -701,7 +1540,7 @@ dns:
     - 8.8.8.8 # default value
     - tls://dns.rubyfish.cn:853 # DNS over TLS
     - https://1.1.1.1/dns-query # DNS over HTTPS
-    - dhcp://en0 # dns from dhcp
+    - dhcp://en0 # dns from dhcp, re-probed as the lease or network changes
     # - '8.8.8.8#en0'
 
   # When `fallback` is present, the DNS server will send concurrent requests
@@ -732,11 +1571,16 @@ dns:
   #     - '+.google.com'
   #     - '+.facebook.com'
   #     - '+.youtube.com'
+  #   geosite:
+  #     - gfw
+  #     - '!cn' # or whatever isn't in geosite:cn
   
   # Lookup domains via specific nameservers
   # nameserver-policy:
   #   'www.baidu.com': '114.114.114.114'
   #   '+.internal.crop.com': '10.0.0.1'
+  #   '+.corp.example,*.lan': '10.0.0.1'
+  #   'rule-set:cn-sites': '223.5.5.5'
 
 proxies:
   # Shadowsocks
@@ -858,8 +1702,9 @@ proxies:
     # username: username
     # password: password
     # tls: true
+    # sni: example.com
     # skip-cert-verify: true
-    # udp: true
+    # udp: true # relayed with UDP ASSOCIATE
 
   # http
   - name: "http"
@@ -1,25 +1,29 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use std::fmt::Display;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 
 use serde::de::value::MapDeserializer;
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
 
-use crate::app::remote_content_manager::providers::rule_provider::RuleSetBehavior;
-use crate::common::auth;
+use crate::app::remote_content_manager::providers::rule_provider::{
+    RuleSetBehavior, RuleSetFormat,
+};
+use crate::common::{auth, country, nat64};
 use crate::config::def::{self};
-use crate::config::internal::proxy::{OutboundProxy, PROXY_DIRECT, PROXY_REJECT};
+use crate::config::internal::proxy::{OutboundProxy, RejectMode, PROXY_DIRECT};
 use crate::config::internal::rule::RuleType;
 use crate::proxy::utils::Interface;
+use crate::session::Network;
 use crate::{
     app::dns,
     config::def::{LogLevel, RunMode},
     Error,
 };
 
+use super::listener::InboundOpts;
 use super::proxy::{map_serde_error, OutboundProxyProtocol, OutboundProxyProviderDef};
 
 pub struct Config {
@@ -27,10 +31,16 @@ pub struct Config {
     pub dns: dns::Config,
     pub tun: TunConfig,
     pub experimental: Option<def::Experimental>,
+    pub devices: Option<def::Devices>,
+    pub mitm: Option<def::Mitm>,
+    pub sniffer: Option<def::Sniffer>,
     pub profile: Profile,
     pub rules: Vec<RuleType>,
+    pub sub_rules: HashMap<String, Vec<RuleType>>,
+    pub script: Option<def::Script>,
     pub rule_providers: HashMap<String, RuleProviderDef>,
     pub users: Vec<auth::User>,
+    pub user_quotas: HashMap<String, u64>,
     /// a list maintaining the order from the config file
     pub proxy_names: Vec<String>,
     pub proxies: HashMap<String, OutboundProxy>,
@@ -39,25 +49,233 @@ pub struct Config {
 }
 
 impl Config {
+    /// a group `auto-group-by` may make, which is only known once the
+    /// provider is loaded
+    fn is_auto_group(&self, name: &str) -> bool {
+        self.proxy_providers
+            .iter()
+            .filter(|(_, p)| p.auto_group_by().is_some())
+            .any(|(provider, _)| {
+                name.strip_prefix(provider.as_str())
+                    .and_then(|x| x.strip_prefix('-'))
+                    .is_some_and(country::is_country_code)
+            })
+    }
+
+    /// the groups `auto-group-by` makes the groups and the rules refer to,
+    /// made even while their provider has no proxy in the country
+    pub fn referenced_auto_groups(&self) -> HashSet<String> {
+        let members = self
+            .proxy_groups
+            .values()
+            .filter_map(|x| match x {
+                OutboundProxy::ProxyGroup(g) => g.proxies(),
+                _ => None,
+            })
+            .flatten()
+            .map(String::as_str);
+        let targets = self
+            .rules
+            .iter()
+            .chain(self.sub_rules.values().flatten())
+            .filter(|r| !matches!(r, RuleType::SubRule { .. }))
+            .map(|r| r.target());
+        members
+            .chain(targets)
+            .filter(|x| {
+                !self.proxies.contains_key(*x)
+                    && !self.proxy_groups.contains_key(*x)
+                    && self.is_auto_group(x)
+            })
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// every group member and provider exists, and no group contains
+    /// itself, directly or through other groups
+    fn validate_proxy_groups(&self) -> Result<(), Error> {
+        // in the order of `proxy-groups`, for the errors to point at
+        let groups = self
+            .proxy_names
+            .iter()
+            .filter_map(|name| match self.proxy_groups.get(name) {
+                Some(OutboundProxy::ProxyGroup(g)) => Some(g),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for (i, group) in groups.iter().enumerate() {
+            if self.proxies.contains_key(group.name()) {
+                return Err(Error::InvalidConfig(format!(
+                    "proxy-groups[{}] `{}`: the name is taken by a proxy",
+                    i,
+                    group.name()
+                )));
+            }
+            for (j, member) in group.proxies().into_iter().flatten().enumerate() {
+                if !self.proxies.contains_key(member)
+                    && !self.proxy_groups.contains_key(member)
+                    && !self.is_auto_group(member)
+                {
+                    return Err(Error::InvalidConfig(format!(
+                        "proxy-groups[{}] `{}`: proxies[{}] `{}` is neither a proxy nor a group",
+                        i,
+                        group.name(),
+                        j,
+                        member
+                    )));
+                }
+            }
+            for (j, provider) in group.use_provider().into_iter().flatten().enumerate() {
+                if !self.proxy_providers.contains_key(provider) {
+                    return Err(Error::InvalidConfig(format!(
+                        "proxy-groups[{}] `{}`: use[{}] `{}` is not a proxy provider",
+                        i,
+                        group.name(),
+                        j,
+                        provider
+                    )));
+                }
+            }
+        }
+
+        // depth first, meeting a group again while its members are still
+        // being visited closes a cycle
+        fn visit<'a>(
+            name: &'a str,
+            groups: &'a HashMap<String, OutboundProxy>,
+            path: &mut Vec<&'a str>,
+            done: &mut HashSet<&'a str>,
+        ) -> Result<(), Error> {
+            if done.contains(name) {
+                return Ok(());
+            }
+            if let Some(start) = path.iter().position(|x| *x == name) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(name);
+                return Err(Error::InvalidConfig(format!(
+                    "proxy groups contain each other: {}",
+                    cycle.join(" -> ")
+                )));
+            }
+            let group = match groups.get(name) {
+                Some(OutboundProxy::ProxyGroup(g)) => g,
+                _ => return Ok(()),
+            };
+            path.push(name);
+            for member in group.proxies().into_iter().flatten() {
+                visit(member, groups, path, done)?;
+            }
+            path.pop();
+            done.insert(name);
+            Ok(())
+        }
+
+        let mut done = HashSet::new();
+        for group in groups {
+            visit(group.name(), &self.proxy_groups, &mut vec![], &mut done)?;
+        }
+        Ok(())
+    }
+
     fn validate(self) -> Result<Self, crate::Error> {
-        for r in self.rules.iter() {
-            if !self.proxies.contains_key(r.target()) && !self.proxy_groups.contains_key(r.target())
+        self.validate_proxy_groups()?;
+        let check_target = |r: &RuleType| {
+            if !self.proxies.contains_key(r.target())
+                && !self.proxy_groups.contains_key(r.target())
+                && !self.is_auto_group(r.target())
             {
                 return Err(Error::InvalidConfig(format!(
                     "proxy `{}` referenced in a rule was not found",
                     r.target()
                 )));
             }
+            Ok(())
+        };
+        let check_shortcut = |r: &RuleType| match r {
+            RuleType::Script { shortcut, .. }
+                if !self
+                    .script
+                    .as_ref()
+                    .is_some_and(|x| x.shortcuts.contains_key(shortcut)) =>
+            {
+                Err(Error::InvalidConfig(format!(
+                    "script shortcut `{}` referenced in a rule was not found",
+                    shortcut
+                )))
+            }
+            _ => Ok(()),
+        };
+        let check_rule = |r: &RuleType| match r {
+            RuleType::SubRule { rule, sub_rule } => {
+                if !self.sub_rules.contains_key(sub_rule) {
+                    return Err(Error::InvalidConfig(format!(
+                        "sub-rule `{}` referenced in a rule was not found",
+                        sub_rule
+                    )));
+                }
+                check_shortcut(rule)
+            }
+            _ => check_shortcut(r).and_then(|_| check_target(r)),
+        };
+        for r in self.rules.iter() {
+            check_rule(r)?;
+        }
+        for (name, rules) in self.sub_rules.iter() {
+            for r in rules {
+                check_rule(r)
+                    .map_err(|x| Error::InvalidConfig(format!("sub-rules {}: {}", name, x)))?;
+            }
         }
+        self.validate_sub_rules()?;
         Ok(self)
     }
+
+    /// sub-rules may jump into other sub-rules, but never back into one
+    /// being matched
+    fn validate_sub_rules(&self) -> Result<(), Error> {
+        fn visit<'a>(
+            name: &'a str,
+            sub_rules: &'a HashMap<String, Vec<RuleType>>,
+            path: &mut Vec<&'a str>,
+            done: &mut HashSet<&'a str>,
+        ) -> Result<(), Error> {
+            if done.contains(name) {
+                return Ok(());
+            }
+            if let Some(start) = path.iter().position(|x| *x == name) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(name);
+                return Err(Error::InvalidConfig(format!(
+                    "sub-rules jump into each other: {}",
+                    cycle.join(" -> ")
+                )));
+            }
+            path.push(name);
+            for r in sub_rules.get(name).into_iter().flatten() {
+                if let RuleType::SubRule { sub_rule, .. } = r {
+                    visit(sub_rule, sub_rules, path, done)?;
+                }
+            }
+            path.pop();
+            done.insert(name);
+            Ok(())
+        }
+
+        let mut done = HashSet::new();
+        for name in self.sub_rules.keys() {
+            visit(name, &self.sub_rules, &mut vec![], &mut done)?;
+        }
+        Ok(())
+    }
 }
 
 impl TryFrom<def::Config> for Config {
     type Error = crate::Error;
 
     fn try_from(c: def::Config) -> Result<Self, Self::Error> {
-        let mut proxy_names = vec![String::from(PROXY_DIRECT), String::from(PROXY_REJECT)];
+        let mut proxy_names = vec![String::from(PROXY_DIRECT)];
+        proxy_names.extend(RejectMode::ALL.iter().map(|x| x.name().to_owned()));
         #[allow(deprecated)]
         Self {
             general: General {
@@ -69,11 +287,24 @@ impl TryFrom<def::Config> for Config {
                     mixed_port: c.mixed_port,
                     authentication: c.authentication.clone(),
                     bind_address: c.bind_address.parse()?,
+                    max_connections: c.max_connections,
+                    listeners: c
+                        .listeners
+                        .iter()
+                        .cloned()
+                        .map(InboundOpts::try_from)
+                        .collect::<Result<Vec<_>, _>>()?,
+                },
+                health_check: HealthCheckLimit {
+                    max_concurrent: c.health_check.max_concurrent,
+                    spacing: c.health_check.spacing,
                 },
                 controller: Controller {
                     external_controller: c.external_controller.clone(),
                     external_ui: c.external_ui.clone(),
+                    capture_dir: c.capture_dir.clone(),
                     secret: c.secret.clone(),
+                    updater: c.updater.clone(),
                 },
                 mode: c.mode,
                 log_level: c.log_level,
@@ -88,9 +319,22 @@ impl TryFrom<def::Config> for Config {
                 routing_mask: c.routing_mask,
                 mmdb: c.mmdb.to_owned(),
                 mmdb_download_url: c.mmdb_download_url.to_owned(),
+                geodata_mode: c.geodata_mode,
+                geoip: c.geoip.to_owned(),
+                geoip_download_url: c.geoip_download_url.to_owned(),
+                geosite: c.geosite.to_owned(),
+                geosite_download_url: c.geosite_download_url.to_owned(),
+                nat64: c.nat64.as_deref().map(str::parse).transpose()?,
+                mptcp: c.mptcp,
+                offline: c.offline,
+                system_proxy: c.system_proxy,
             },
             dns: (&c).try_into()?,
             experimental: c.experimental,
+            devices: c.devices,
+            mitm: c.mitm,
+            sniffer: c.sniffer,
+            script: c.script,
             tun: match c.tun {
                 Some(mapping) => TunConfig::deserialize(MapDeserializer::new(mapping.into_iter()))
                     .map_err(|e| Error::InvalidConfig(format!("invalid tun config: {}", e)))?,
@@ -107,6 +351,18 @@ impl TryFrom<def::Config> for Config {
                         .map_err(|x| Error::InvalidConfig(x.to_string()))
                 })
                 .collect::<Result<Vec<_>, _>>()?,
+            sub_rules: c
+                .sub_rules
+                .into_iter()
+                .map(|(name, rules)| {
+                    let rules = rules
+                        .into_iter()
+                        .map(|x| x.parse::<RuleType>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|x| Error::InvalidConfig(format!("sub-rules {}: {}", name, x)))?;
+                    Ok((name, rules))
+                })
+                .collect::<Result<HashMap<_, _>, Error>>()?,
             rule_providers: c
                 .rule_provider
                 .map(|m| {
@@ -124,6 +380,7 @@ impl TryFrom<def::Config> for Config {
                         .expect("proxy provider parse error")
                 })
                 .unwrap_or_default(),
+            user_quotas: c.user_quotas,
             users: c
                 .authentication
                 .into_iter()
@@ -135,16 +392,19 @@ impl TryFrom<def::Config> for Config {
                 })
                 .collect(),
             proxies: c.proxy.into_iter().try_fold(
-                HashMap::from([
-                    (
+                RejectMode::ALL
+                    .iter()
+                    .map(|x| {
+                        (
+                            x.name().to_owned(),
+                            OutboundProxy::ProxyServer(OutboundProxyProtocol::Reject(*x)),
+                        )
+                    })
+                    .chain(std::iter::once((
                         String::from(PROXY_DIRECT),
                         OutboundProxy::ProxyServer(OutboundProxyProtocol::Direct),
-                    ),
-                    (
-                        String::from(PROXY_REJECT),
-                        OutboundProxy::ProxyServer(OutboundProxyProtocol::Reject),
-                    ),
-                ]),
+                    )))
+                    .collect::<HashMap<_, _>>(),
                 |mut rv, x| {
                     let proxy = OutboundProxy::ProxyServer(OutboundProxyProtocol::try_from(x)?);
                     let name = proxy.name();
@@ -175,6 +435,12 @@ impl TryFrom<def::Config> for Config {
                             }
                         },
                     )?);
+                    if rv.contains_key(group.name().as_str()) {
+                        return Err(Error::InvalidConfig(format!(
+                            "duplicated proxy group name: {}",
+                            group.name()
+                        )));
+                    }
                     proxy_names.push(group.name().into());
                     rv.insert(group.name().to_string(), group);
                     Ok::<HashMap<String, OutboundProxy>, Error>(rv)
@@ -197,8 +463,8 @@ impl TryFrom<def::Config> for Config {
                             rv.insert(name, provider);
                             Ok::<HashMap<std::string::String, OutboundProxyProviderDef>, Error>(rv)
                         })
-                        .expect("proxy provider parse error")
                 })
+                .transpose()?
                 .unwrap_or_default(),
         }
         .validate()
@@ -207,9 +473,9 @@ impl TryFrom<def::Config> for Config {
 
 #[cfg(test)]
 mod tests {
-    use crate::def;
+    use crate::{def, session::Network};
 
-    use super::Config;
+    use super::{Config, DnsHijack, RuleProviderDef};
 
     #[test]
     fn from_def_config() {
@@ -221,10 +487,240 @@ mod tests {
         let cc: Config = c.try_into().expect("should into");
         assert_eq!(cc.general.inbound.port, Some(9090));
     }
+
+    #[test]
+    fn validate_proxy_groups() {
+        let load = |groups: &[&str]| {
+            let cfg = format!(
+                r#"
+proxies:
+  - {{ name: ss, type: ss, server: 10.0.0.1, port: 8388, cipher: aes-128-gcm, password: x }}
+proxy-groups:
+  - {}
+"#,
+                groups.join("\n  - ")
+            );
+            Config::try_from(cfg.parse::<def::Config>().unwrap())
+                .err()
+                .map(|x| x.to_string())
+        };
+
+        assert_eq!(
+            load(&[
+                "{ name: a, type: select, proxies: [b, ss] }",
+                "{ name: b, type: relay, proxies: [ss, DIRECT] }",
+            ]),
+            None
+        );
+
+        let e = load(&[
+            "{ name: a, type: select, proxies: [b] }",
+            "{ name: b, type: relay, proxies: [c] }",
+            "{ name: c, type: select, proxies: [ss, a] }",
+        ])
+        .unwrap();
+        assert!(e.contains("a -> b -> c -> a"), "{}", e);
+
+        let e = load(&["{ name: a, type: select, proxies: [ss, missing] }"]).unwrap();
+        assert!(
+            e.contains("proxy-groups[0] `a`: proxies[1] `missing`"),
+            "{}",
+            e
+        );
+
+        let e = load(&["{ name: a, type: select, use: [nowhere] }"]).unwrap();
+        assert!(e.contains("use[0] `nowhere`"), "{}", e);
+
+        let e = load(&["{ name: ss, type: select, proxies: [DIRECT] }"]).unwrap();
+        assert!(e.contains("taken by a proxy"), "{}", e);
+
+        let e = load(&[
+            "{ name: a, type: select, proxies: [DIRECT] }",
+            "{ name: a, type: select, proxies: [ss] }",
+        ])
+        .unwrap();
+        assert!(e.contains("duplicated proxy group name: a"), "{}", e);
+    }
+
+    #[test]
+    fn reject_zero_max_connections() {
+        let cfg = r#"
+proxies:
+  - { name: ss, type: ss, server: 10.0.0.1, port: 8388, cipher: aes-128-gcm, password: x, max-connections: 0 }
+"#;
+        let e = Config::try_from(cfg.parse::<def::Config>().unwrap())
+            .err()
+            .expect("should reject")
+            .to_string();
+        assert!(
+            e.contains("max-connections must be greater than 0"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn referenced_auto_groups() {
+        let cfg = r#"
+proxy-providers:
+  subs:
+    type: file
+    path: ./subs.yaml
+    health-check: { enable: true, url: http://www.gstatic.com/generate_204, interval: 300 }
+    auto-group-by: country
+proxy-groups:
+  - { name: asia, type: select, proxies: [subs-JP, subs-HK, DIRECT] }
+rules:
+  - DOMAIN-SUFFIX,jp,subs-JP
+  - DOMAIN-SUFFIX,us,subs-US
+  - MATCH,asia
+"#;
+        let c = Config::try_from(cfg.parse::<def::Config>().unwrap()).unwrap();
+        let mut groups = c.referenced_auto_groups().into_iter().collect::<Vec<_>>();
+        groups.sort();
+        assert_eq!(groups, ["subs-HK", "subs-JP", "subs-US"]);
+
+        let e = Config::try_from(
+            cfg.replace("subs-US", "subs-usa")
+                .parse::<def::Config>()
+                .unwrap(),
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(e.contains("subs-usa"), "{}", e);
+    }
+
+    #[test]
+    fn validate_sub_rules() {
+        let load = |sub_rules: &str, rules: &str| {
+            let cfg = format!("sub-rules:\n{}\nrules:\n{}\n", sub_rules, rules);
+            Config::try_from(cfg.parse::<def::Config>().unwrap())
+                .err()
+                .map(|x| x.to_string())
+        };
+
+        let chain = "  lan:\n    - IP-CIDR,10.0.0.0/8,DIRECT";
+        assert_eq!(load(chain, "  - SUB-RULE,(NETWORK,tcp),lan"), None);
+
+        let e = load(chain, "  - SUB-RULE,(NETWORK,tcp),wan").unwrap();
+        assert!(e.contains("sub-rule `wan`"), "{}", e);
+
+        let e = load(
+            "  lan:\n    - MATCH,nowhere",
+            "  - SUB-RULE,(NETWORK,tcp),lan",
+        )
+        .unwrap();
+        assert!(
+            e.contains("sub-rules lan: ") && e.contains("nowhere"),
+            "{}",
+            e
+        );
+
+        let nested = "  lan:\n    - SUB-RULE,(DST-PORT,53),dns\n    - MATCH,DIRECT\n  dns:\n    - NETWORK,udp,DIRECT";
+        assert_eq!(load(nested, "  - SUB-RULE,(NETWORK,tcp),lan"), None);
+
+        let e = load(
+            "  lan:\n    - SUB-RULE,(NETWORK,tcp),lan",
+            "  - MATCH,DIRECT",
+        )
+        .unwrap();
+        assert!(e.contains("jump into each other: lan -> lan"), "{}", e);
+
+        let e = load(
+            "  a:\n    - SUB-RULE,(NETWORK,tcp),b\n  b:\n    - SUB-RULE,(NETWORK,udp),a",
+            "  - MATCH,DIRECT",
+        )
+        .unwrap();
+        assert!(
+            e.contains("a -> b -> a") || e.contains("b -> a -> b"),
+            "{}",
+            e
+        );
+
+        let e = load(
+            "  lan:\n    - SUB-RULE,(NETWORK,tcp),wan",
+            "  - MATCH,DIRECT",
+        )
+        .unwrap();
+        assert!(
+            e.contains("sub-rules lan: ") && e.contains("sub-rule `wan`"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn validate_script_rules() {
+        let load = |rules: &str| {
+            let cfg = format!(
+                "script:\n  shortcuts:\n    quic: network == \"udp\"\nrules:\n{}\n",
+                rules
+            );
+            Config::try_from(cfg.parse::<def::Config>().unwrap())
+                .err()
+                .map(|x| x.to_string())
+        };
+
+        assert_eq!(load("  - SCRIPT,quic,REJECT"), None);
+        let e = load("  - SCRIPT,tcp,REJECT").unwrap();
+        assert!(e.contains("script shortcut `tcp`"), "{}", e);
+        let e = load("  - SCRIPT,quic,nowhere").unwrap();
+        assert!(e.contains("nowhere"), "{}", e);
+    }
+
+    #[test]
+    fn parse_inline_rule_provider() {
+        let cfg = r#"
+rule-providers:
+  lan:
+    type: inline
+    behavior: domain
+    payload:
+      - "+.lan"
+      - router.local
+"#;
+        let c = Config::try_from(cfg.parse::<def::Config>().unwrap()).unwrap();
+        match &c.rule_providers["lan"] {
+            RuleProviderDef::Inline(p) => assert_eq!(p.payload, ["+.lan", "router.local"]),
+            _ => panic!("not inline"),
+        }
+    }
+
+    #[test]
+    fn parse_dns_hijack() {
+        let any = "any:53".parse::<DnsHijack>().unwrap();
+        assert_eq!((any.network, any.ip, any.port), (None, None, 53));
+        assert!(any.matches(Network::Udp, &"8.8.8.8:53".parse().unwrap()));
+        assert!(!any.matches(Network::Udp, &"8.8.8.8:5353".parse().unwrap()));
+
+        let tcp = "tcp://198.18.0.2".parse::<DnsHijack>().unwrap();
+        assert!(tcp.matches(Network::Tcp, &"198.18.0.2:53".parse().unwrap()));
+        assert!(!tcp.matches(Network::Udp, &"198.18.0.2:53".parse().unwrap()));
+        assert!(!tcp.matches(Network::Tcp, &"198.18.0.3:53".parse().unwrap()));
+
+        let v6 = "udp://[2001:4860:4860::8888]:53"
+            .parse::<DnsHijack>()
+            .unwrap();
+        assert_eq!(v6.ip, Some("2001:4860:4860::8888".parse().unwrap()));
+        assert_eq!("0.0.0.0:53".parse::<DnsHijack>().unwrap().ip, None);
+
+        for x in [
+            "any:53",
+            "tcp://198.18.0.2:53",
+            "udp://[2001:4860:4860::8888]:53",
+        ] {
+            assert_eq!(x.parse::<DnsHijack>().unwrap().to_string(), x);
+        }
+
+        assert!("quic://any:53".parse::<DnsHijack>().is_err());
+        assert!("example.com:53".parse::<DnsHijack>().is_err());
+    }
 }
 
 pub struct General {
     pub inbound: Inbound,
+    pub health_check: HealthCheckLimit,
     pub(crate) controller: Controller,
     pub mode: RunMode,
     pub log_level: LogLevel,
@@ -233,6 +729,20 @@ pub struct General {
     pub routing_mask: Option<u32>,
     pub mmdb: String,
     pub mmdb_download_url: Option<String>,
+    pub geodata_mode: bool,
+    pub geoip: String,
+    pub geoip_download_url: Option<String>,
+    pub geosite: String,
+    pub geosite_download_url: Option<String>,
+    pub nat64: Option<nat64::Mode>,
+    pub mptcp: bool,
+    pub offline: bool,
+    pub system_proxy: bool,
+}
+
+pub struct HealthCheckLimit {
+    pub max_concurrent: Option<usize>,
+    pub spacing: Option<u64>,
 }
 
 pub struct Profile {
@@ -254,6 +764,108 @@ pub struct TunConfig {
     /// default: 198.18.0.0/16
     pub network: Option<String>,
     pub gateway: Option<IpAddr>,
+    /// routes every connection with this mode instead of the global `mode`
+    pub mode: Option<RunMode>,
+    #[serde(default)]
+    pub stack: TunStack,
+    /// DNS queries sent to these are answered by clash instead of going
+    /// out, e.g. `any:53` or `tcp://8.8.8.8:53`. Applies to tproxy too.
+    #[serde(default)]
+    pub dns_hijack: Vec<DnsHijack>,
+}
+
+/// an entry of `tun.dns-hijack`, `[tcp://|udp://]<ip|any>[:port]`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct DnsHijack {
+    /// both if None
+    pub network: Option<Network>,
+    /// any if None
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+}
+
+impl DnsHijack {
+    pub fn matches(&self, network: Network, dst: &SocketAddr) -> bool {
+        self.network.map_or(true, |x| x == network)
+            && self.ip.map_or(true, |x| x == dst.ip())
+            && self.port == dst.port()
+    }
+}
+
+impl FromStr for DnsHijack {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidConfig(format!("invalid dns-hijack: {}", s));
+        let (network, rest) = match s.split_once("://") {
+            Some(("tcp", rest)) => (Some(Network::Tcp), rest),
+            Some(("udp", rest)) => (Some(Network::Udp), rest),
+            Some(_) => return Err(invalid()),
+            None => (None, s),
+        };
+
+        let (ip, port) = if let Ok(addr) = rest.parse::<SocketAddr>() {
+            (Some(addr.ip()), addr.port())
+        } else if let Ok(ip) = rest.parse::<IpAddr>() {
+            (Some(ip), 53)
+        } else {
+            let (host, port) = match rest.rsplit_once(':') {
+                Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+                None => (rest, 53),
+            };
+            if host != "any" {
+                return Err(invalid());
+            }
+            (None, port)
+        };
+
+        Ok(Self {
+            network,
+            ip: ip.filter(|x| !x.is_unspecified()),
+            port,
+        })
+    }
+}
+
+impl TryFrom<String> for DnsHijack {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for DnsHijack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.network {
+            Some(Network::Tcp) => f.write_str("tcp://")?,
+            Some(Network::Udp) => f.write_str("udp://")?,
+            None => {}
+        }
+        match self.ip {
+            Some(ip) => write!(f, "{}", SocketAddr::new(ip, self.port)),
+            None => write!(f, "any:{}", self.port),
+        }
+    }
+}
+
+impl From<DnsHijack> for String {
+    fn from(value: DnsHijack) -> Self {
+        value.to_string()
+    }
+}
+
+/// the userspace TCP/IP stack terminating the connections of the tun device
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum TunStack {
+    /// lwIP
+    #[default]
+    System,
+    /// a smoltcp stack in the spirit of gVisor's netstack, with larger
+    /// buffers and more throughput under heavy load
+    Gvisor,
 }
 
 #[derive(Clone, Default)]
@@ -301,13 +913,18 @@ pub struct Inbound {
     pub mixed_port: Option<u16>,
     pub authentication: Vec<String>,
     pub bind_address: BindAddress,
+    pub max_connections: Option<usize>,
+    /// `listeners`, on top of the ports above
+    pub listeners: Vec<InboundOpts>,
 }
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct Controller {
     pub external_controller: Option<String>,
     pub external_ui: Option<String>,
+    pub capture_dir: Option<String>,
     pub secret: Option<String>,
+    pub updater: Option<def::Updater>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -316,6 +933,8 @@ pub struct Controller {
 pub enum RuleProviderDef {
     Http(HttpRuleProvider),
     File(FileRuleProvider),
+    Inline(InlineRuleProvider),
+    Compose(ComposeRuleProvider),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -323,6 +942,8 @@ pub struct HttpRuleProvider {
     pub url: String,
     pub interval: u64,
     pub behavior: RuleSetBehavior,
+    #[serde(default)]
+    pub format: RuleSetFormat,
     pub path: String,
 }
 
@@ -331,6 +952,23 @@ pub struct FileRuleProvider {
     pub path: String,
     pub interval: Option<u64>,
     pub behavior: RuleSetBehavior,
+    #[serde(default)]
+    pub format: RuleSetFormat,
+}
+
+/// the entries listed right in the config
+#[derive(Serialize, Deserialize)]
+pub struct InlineRuleProvider {
+    pub behavior: RuleSetBehavior,
+    pub payload: Vec<String>,
+}
+
+/// other rule providers combined with `or`, `and` and `minus`, e.g.
+/// `ad-list minus allow-list`
+#[derive(Serialize, Deserialize)]
+pub struct ComposeRuleProvider {
+    pub behavior: RuleSetBehavior,
+    pub expression: String,
 }
 
 impl TryFrom<HashMap<String, Value>> for RuleProviderDef {
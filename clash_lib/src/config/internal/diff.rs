@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use super::{
+    config::{BindAddress, Config},
+    listener::InboundOpts,
+    proxy::OutboundProxy,
+};
+
+/// where a listener of a [`ConfigSummary`] comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListenerKind {
+    /// `port`, `socks-port` and the like
+    Port,
+    /// one of `listeners`
+    Named,
+    /// the `tun` section
+    Tun,
+}
+
+/// the parts of a config a reload preview compares
+#[derive(Default, Clone)]
+pub struct ConfigSummary {
+    /// `<kind> <bind address>:<port>` -> where it's configured
+    pub listeners: BTreeMap<String, ListenerKind>,
+    /// proxy server name -> its definition
+    pub proxies: BTreeMap<String, String>,
+    /// proxy group name -> its definition
+    pub groups: BTreeMap<String, String>,
+    pub rules: usize,
+}
+
+/// the inbound listeners as they show in a [`ConfigSummary`]
+pub fn listeners(
+    bind_address: &BindAddress,
+    ports: &[(&str, Option<u16>)],
+) -> BTreeMap<String, ListenerKind> {
+    ports
+        .iter()
+        .filter_map(|(kind, port)| {
+            port.map(|p| {
+                (
+                    format!("{} {}:{}", kind, bind_address, p),
+                    ListenerKind::Port,
+                )
+            })
+        })
+        .collect()
+}
+
+/// the `listeners` entries as they show in a [`ConfigSummary`]
+pub fn named_listeners(
+    bind_address: &BindAddress,
+    opts: &[InboundOpts],
+) -> BTreeMap<String, ListenerKind> {
+    let bind_address = bind_address.to_string();
+    opts.iter()
+        .map(|l| match l {
+            InboundOpts::Tun(tun) => format!("{} tun {}", l.name(), tun.device_id),
+            _ => format!(
+                "{} {}:{}",
+                l.name(),
+                l.listen().unwrap_or(bind_address.as_str()),
+                l.port().unwrap_or_default()
+            ),
+        })
+        .map(|x| (x, ListenerKind::Named))
+        .collect()
+}
+
+impl From<&Config> for ConfigSummary {
+    fn from(c: &Config) -> Self {
+        let inbound = &c.general.inbound;
+        let mut listeners = listeners(
+            &inbound.bind_address,
+            &[
+                ("http", inbound.port),
+                ("socks", inbound.socks_port),
+                ("redir", inbound.redir_port),
+                ("tproxy", inbound.tproxy_port),
+                ("mixed", inbound.mixed_port),
+            ],
+        );
+        listeners.extend(named_listeners(&inbound.bind_address, &inbound.listeners));
+        if c.tun.enable {
+            listeners.insert(format!("tun {}", c.tun.device_id), ListenerKind::Tun);
+        }
+
+        let definition = |p: &OutboundProxy| match p {
+            OutboundProxy::ProxyServer(s) => serde_yaml::to_string(s).unwrap_or_default(),
+            OutboundProxy::ProxyGroup(g) => serde_yaml::to_string(g).unwrap_or_default(),
+        };
+
+        Self {
+            listeners,
+            proxies: c
+                .proxies
+                .iter()
+                .map(|(k, v)| (k.clone(), definition(v)))
+                .collect(),
+            groups: c
+                .proxy_groups
+                .iter()
+                .map(|(k, v)| (k.clone(), definition(v)))
+                .collect(),
+            rules: c.rules.len(),
+        }
+    }
+}
+
+#[derive(Serialize, Default, Debug, PartialEq)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changed: Vec<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RulesDelta {
+    pub before: usize,
+    pub after: usize,
+    pub delta: i64,
+}
+
+/// what reloading `to` in place of `from` would change
+#[derive(Serialize, Debug)]
+pub struct ConfigDiff {
+    pub listeners: Changes,
+    pub proxies: Changes,
+    pub groups: Changes,
+    pub rules: RulesDelta,
+}
+
+fn key_changes<V>(from: &BTreeMap<String, V>, to: &BTreeMap<String, V>) -> Changes {
+    Changes {
+        added: to
+            .keys()
+            .filter(|k| !from.contains_key(*k))
+            .cloned()
+            .collect(),
+        removed: from
+            .keys()
+            .filter(|k| !to.contains_key(*k))
+            .cloned()
+            .collect(),
+        changed: vec![],
+    }
+}
+
+fn map_changes(from: &BTreeMap<String, String>, to: &BTreeMap<String, String>) -> Changes {
+    Changes {
+        added: to
+            .keys()
+            .filter(|k| !from.contains_key(*k))
+            .cloned()
+            .collect(),
+        removed: from
+            .keys()
+            .filter(|k| !to.contains_key(*k))
+            .cloned()
+            .collect(),
+        changed: to
+            .iter()
+            .filter(|(k, v)| from.get(*k).is_some_and(|x| x != *v))
+            .map(|(k, _)| k.clone())
+            .collect(),
+    }
+}
+
+impl ConfigSummary {
+    pub fn diff(&self, to: &ConfigSummary) -> ConfigDiff {
+        ConfigDiff {
+            listeners: key_changes(&self.listeners, &to.listeners),
+            proxies: map_changes(&self.proxies, &to.proxies),
+            groups: map_changes(&self.groups, &to.groups),
+            rules: RulesDelta {
+                before: self.rules,
+                after: to.rules,
+                delta: to.rules as i64 - self.rules as i64,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::internal::config::Config, def};
+
+    use super::{Changes, ConfigSummary, ListenerKind};
+
+    fn summary(cfg: &str) -> ConfigSummary {
+        let c: Config = cfg.parse::<def::Config>().unwrap().try_into().unwrap();
+        (&c).into()
+    }
+
+    #[test]
+    fn test_config_diff() {
+        let from = summary(
+            r#"
+mixed-port: 7890
+proxies:
+  - {name: a, type: socks5, server: 10.0.0.1, port: 1080}
+  - {name: b, type: socks5, server: 10.0.0.2, port: 1080}
+proxy-groups:
+  - {name: g, type: select, proxies: [a, b]}
+rules:
+  - MATCH,g
+"#,
+        );
+        let to = summary(
+            r#"
+socks-port: 7891
+proxies:
+  - {name: a, type: socks5, server: 10.0.0.1, port: 1080}
+  - {name: c, type: socks5, server: 10.0.0.3, port: 1080}
+proxy-groups:
+  - {name: g, type: select, proxies: [a, c]}
+rules:
+  - DOMAIN,example.com,a
+  - MATCH,g
+"#,
+        );
+
+        assert_eq!(
+            from.listeners.get("mixed *:7890"),
+            Some(&ListenerKind::Port)
+        );
+
+        let diff = from.diff(&to);
+        assert_eq!(
+            diff.listeners,
+            Changes {
+                added: vec!["socks *:7891".to_owned()],
+                removed: vec!["mixed *:7890".to_owned()],
+                changed: vec![],
+            }
+        );
+        assert_eq!(diff.proxies.added, vec!["c"]);
+        assert_eq!(diff.proxies.removed, vec!["b"]);
+        assert!(diff.proxies.changed.is_empty());
+        assert_eq!(diff.groups.changed, vec!["g"]);
+        assert_eq!(diff.rules.delta, 1);
+    }
+}
@@ -0,0 +1,437 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use ipnet::IpNet;
+use serde::{de::value::MapDeserializer, Deserialize};
+use serde_yaml::Value;
+
+use crate::{
+    common::{auth::AllowList, redact::Secret, utils::default_bool_true},
+    config::def::RunMode,
+    Error,
+};
+
+use super::{
+    config::{DnsHijack, TunConfig, TunStack},
+    proxy::map_serde_error,
+};
+
+/// an entry of `listeners`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(tag = "type")]
+pub enum InboundOpts {
+    #[serde(rename = "http")]
+    Http(InboundProxy),
+    #[serde(rename = "socks", alias = "socks5")]
+    Socks(InboundProxy),
+    #[serde(rename = "mixed")]
+    Mixed(InboundProxy),
+    #[serde(rename = "redir")]
+    Redir(InboundTransparent),
+    #[serde(rename = "tproxy")]
+    Tproxy(InboundTransparent),
+    #[serde(rename = "tun")]
+    Tun(InboundTun),
+    #[serde(rename = "shadowsocks", alias = "ss")]
+    Shadowsocks(InboundShadowsocks),
+    #[serde(rename = "vmess")]
+    Vmess(InboundVmess),
+    #[serde(rename = "trojan")]
+    Trojan(InboundTrojan),
+}
+
+impl InboundOpts {
+    pub fn name(&self) -> &str {
+        match self {
+            InboundOpts::Http(http) => &http.name,
+            InboundOpts::Socks(socks) => &socks.name,
+            InboundOpts::Mixed(mixed) => &mixed.name,
+            InboundOpts::Redir(redir) => &redir.name,
+            InboundOpts::Tproxy(tproxy) => &tproxy.name,
+            InboundOpts::Tun(tun) => &tun.name,
+            InboundOpts::Shadowsocks(ss) => &ss.name,
+            InboundOpts::Vmess(vmess) => &vmess.name,
+            InboundOpts::Trojan(trojan) => &trojan.name,
+        }
+    }
+
+    /// the address to listen on, `bind-address` if unset
+    pub fn listen(&self) -> Option<&str> {
+        match self {
+            InboundOpts::Http(http) => http.listen.as_deref(),
+            InboundOpts::Socks(socks) => socks.listen.as_deref(),
+            InboundOpts::Mixed(mixed) => mixed.listen.as_deref(),
+            InboundOpts::Redir(redir) => redir.listen.as_deref(),
+            InboundOpts::Tproxy(tproxy) => tproxy.listen.as_deref(),
+            InboundOpts::Tun(_) => None,
+            InboundOpts::Shadowsocks(ss) => ss.listen.as_deref(),
+            InboundOpts::Vmess(vmess) => vmess.listen.as_deref(),
+            InboundOpts::Trojan(trojan) => trojan.listen.as_deref(),
+        }
+    }
+
+    /// `None` for tun, which takes no connections of its own
+    pub fn access(&self) -> Option<&InboundAccess> {
+        match self {
+            InboundOpts::Http(http) => Some(&http.access),
+            InboundOpts::Socks(socks) => Some(&socks.access),
+            InboundOpts::Mixed(mixed) => Some(&mixed.access),
+            InboundOpts::Redir(redir) => Some(&redir.access),
+            InboundOpts::Tproxy(tproxy) => Some(&tproxy.access),
+            InboundOpts::Tun(_) => None,
+            InboundOpts::Shadowsocks(ss) => Some(&ss.access),
+            InboundOpts::Vmess(vmess) => Some(&vmess.access),
+            InboundOpts::Trojan(trojan) => Some(&trojan.access),
+        }
+    }
+
+    /// `None` for tun, which listens on a device instead
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            InboundOpts::Http(http) => Some(http.port),
+            InboundOpts::Socks(socks) => Some(socks.port),
+            InboundOpts::Mixed(mixed) => Some(mixed.port),
+            InboundOpts::Redir(redir) => Some(redir.port),
+            InboundOpts::Tproxy(tproxy) => Some(tproxy.port),
+            InboundOpts::Tun(_) => None,
+            InboundOpts::Shadowsocks(ss) => Some(ss.port),
+            InboundOpts::Vmess(vmess) => Some(vmess.port),
+            InboundOpts::Trojan(trojan) => Some(trojan.port),
+        }
+    }
+
+    /// the dispatch mode of the connections taken by this listener,
+    /// the global `mode` if unset
+    pub fn mode(&self) -> Option<RunMode> {
+        match self {
+            InboundOpts::Http(http) => http.mode,
+            InboundOpts::Socks(socks) => socks.mode,
+            InboundOpts::Mixed(mixed) => mixed.mode,
+            InboundOpts::Redir(redir) => redir.mode,
+            InboundOpts::Tproxy(tproxy) => tproxy.mode,
+            InboundOpts::Tun(tun) => tun.mode,
+            InboundOpts::Shadowsocks(ss) => ss.mode,
+            InboundOpts::Vmess(vmess) => vmess.mode,
+            InboundOpts::Trojan(trojan) => trojan.mode,
+        }
+    }
+}
+
+impl TryFrom<HashMap<String, Value>> for InboundOpts {
+    type Error = crate::Error;
+
+    fn try_from(mapping: HashMap<String, Value>) -> Result<Self, Self::Error> {
+        let opts = InboundOpts::deserialize(MapDeserializer::new(mapping.into_iter()))
+            .map_err(map_serde_error)?;
+        if let Some(access) = opts.access() {
+            access.allowlist()?;
+        }
+        Ok(opts)
+    }
+}
+
+/// who may connect to a listener, anyone if neither is set
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct InboundAccess {
+    /// accept connections from other hosts, only from loopback if false
+    pub allow_lan: Option<bool>,
+    /// the CIDRs or IPs `allow-lan` is limited to
+    pub lan_allowed_ips: Option<Vec<String>>,
+}
+
+impl InboundAccess {
+    pub fn allowlist(&self) -> Result<Option<AllowList>, Error> {
+        if self.allow_lan.is_none() && self.lan_allowed_ips.is_none() {
+            return Ok(None);
+        }
+        let allowed_ips = self
+            .lan_allowed_ips
+            .iter()
+            .flatten()
+            .map(|x| {
+                x.parse::<IpNet>()
+                    .or_else(|_| x.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| Error::InvalidConfig(format!("invalid lan-allowed-ips: {}", x)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(AllowList::new(
+            self.allow_lan.unwrap_or(true),
+            allowed_ips,
+        )))
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InboundUser {
+    pub username: String,
+    pub password: Secret,
+}
+
+/// the http, socks and mixed listeners
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub struct InboundProxy {
+    pub name: String,
+    pub listen: Option<String>,
+    pub port: u16,
+    /// replaces `authentication` for this listener, empty to allow anyone
+    pub users: Option<Vec<InboundUser>>,
+    /// `rule`, `global` or `direct` regardless of the global `mode`
+    pub mode: Option<RunMode>,
+    #[serde(flatten)]
+    pub access: InboundAccess,
+}
+
+/// the redir and tproxy listeners
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub struct InboundTransparent {
+    pub name: String,
+    pub listen: Option<String>,
+    pub port: u16,
+    pub mode: Option<RunMode>,
+    #[serde(flatten)]
+    pub access: InboundAccess,
+}
+
+/// a tun device on top of the `tun` section
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub struct InboundTun {
+    pub name: String,
+    #[serde(alias = "device-url")]
+    pub device_id: String,
+    pub network: Option<String>,
+    pub gateway: Option<IpAddr>,
+    pub mode: Option<RunMode>,
+    #[serde(default)]
+    pub stack: TunStack,
+    /// the DNS queries of this device answered by clash, in place of
+    /// `tun.dns-hijack`
+    #[serde(default)]
+    pub dns_hijack: Vec<DnsHijack>,
+}
+
+impl From<&InboundTun> for TunConfig {
+    fn from(tun: &InboundTun) -> Self {
+        Self {
+            enable: true,
+            device_id: tun.device_id.clone(),
+            network: tun.network.clone(),
+            gateway: tun.gateway,
+            mode: tun.mode,
+            stack: tun.stack,
+            dns_hijack: tun.dns_hijack.clone(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub struct InboundShadowsocks {
+    pub name: String,
+    pub listen: Option<String>,
+    pub port: u16,
+    pub cipher: String,
+    pub password: Secret,
+    #[serde(default = "default_bool_true")]
+    pub udp: bool,
+    pub mode: Option<RunMode>,
+    #[serde(flatten)]
+    pub access: InboundAccess,
+}
+
+/// how the vmess and trojan listeners carry their streams
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct InboundTransport {
+    /// serves over websocket on this path
+    pub ws_path: Option<String>,
+    /// serves over gRPC (gun) with this service name
+    pub grpc_service_name: Option<String>,
+    /// PEM certificate chain, TLS is served when set with `private-key`
+    pub certificate: Option<String>,
+    pub private_key: Option<Secret>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub struct InboundVmessUser {
+    pub username: Option<String>,
+    pub uuid: Secret,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub struct InboundVmess {
+    pub name: String,
+    pub listen: Option<String>,
+    pub port: u16,
+    pub users: Vec<InboundVmessUser>,
+    #[serde(default = "default_bool_true")]
+    pub udp: bool,
+    pub mode: Option<RunMode>,
+    #[serde(flatten)]
+    pub transport: InboundTransport,
+    #[serde(flatten)]
+    pub access: InboundAccess,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub struct InboundTrojanUser {
+    pub username: Option<String>,
+    pub password: Secret,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub struct InboundTrojan {
+    pub name: String,
+    pub listen: Option<String>,
+    pub port: u16,
+    pub users: Vec<InboundTrojanUser>,
+    #[serde(default = "default_bool_true")]
+    pub udp: bool,
+    pub mode: Option<RunMode>,
+    #[serde(flatten)]
+    pub transport: InboundTransport,
+    #[serde(flatten)]
+    pub access: InboundAccess,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_yaml::Value;
+
+    use crate::config::def::RunMode;
+
+    use super::{InboundOpts, TunStack};
+
+    #[test]
+    fn test_parse_listener() {
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            r#"
+name: ss-in
+type: ss
+port: 8388
+cipher: 2022-blake3-aes-128-gcm
+password: AAAAAAAAAAAAAAAAAAAAAA==
+"#,
+        )
+        .unwrap();
+
+        let opts = InboundOpts::try_from(mapping).unwrap();
+        assert_eq!(opts.name(), "ss-in");
+        assert_eq!(opts.port(), Some(8388));
+        assert_eq!(opts.listen(), None);
+        match opts {
+            InboundOpts::Shadowsocks(ss) => assert!(ss.udp),
+            _ => panic!("expected a shadowsocks listener"),
+        }
+    }
+
+    #[test]
+    fn test_parse_transport() {
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            r#"
+name: trojan-in
+type: trojan
+port: 443
+users:
+  - password: example
+grpc-service-name: GunService
+certificate: ./server.crt
+private-key: ./server.key
+"#,
+        )
+        .unwrap();
+
+        match InboundOpts::try_from(mapping).unwrap() {
+            InboundOpts::Trojan(trojan) => {
+                assert_eq!(trojan.users[0].password, "example");
+                assert_eq!(
+                    trojan.transport.grpc_service_name.as_deref(),
+                    Some("GunService")
+                );
+                assert_eq!(trojan.transport.ws_path, None);
+                assert_eq!(
+                    trojan.transport.private_key.as_deref(),
+                    Some("./server.key")
+                );
+            }
+            _ => panic!("expected a trojan listener"),
+        }
+    }
+
+    #[test]
+    fn test_parse_proxy_listeners() {
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            r#"
+name: socks-in
+type: socks
+listen: 127.0.0.1
+port: 7891
+mode: direct
+users:
+  - username: alice
+    password: secret
+lan-allowed-ips:
+  - 192.168.1.0/24
+  - 10.0.0.1
+"#,
+        )
+        .unwrap();
+
+        let opts = InboundOpts::try_from(mapping).unwrap();
+        assert_eq!(opts.listen(), Some("127.0.0.1"));
+        assert_eq!(opts.mode(), Some(RunMode::Direct));
+        let allowlist = opts.access().unwrap().allowlist().unwrap().unwrap();
+        assert!(allowlist.allows("10.0.0.1".parse().unwrap()));
+        assert!(!allowlist.allows("10.0.0.2".parse().unwrap()));
+        match opts {
+            InboundOpts::Socks(socks) => {
+                let users = socks.users.unwrap();
+                assert_eq!(users[0].username, "alice");
+                assert_eq!(users[0].password, "secret");
+            }
+            _ => panic!("expected a socks listener"),
+        }
+
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            r#"
+name: tun-in
+type: tun
+device-url: dev://utun1989
+stack: gvisor
+dns-hijack: [any:53]
+"#,
+        )
+        .unwrap();
+
+        let opts = InboundOpts::try_from(mapping).unwrap();
+        assert_eq!(opts.port(), None);
+        assert_eq!(opts.mode(), None);
+        match opts {
+            InboundOpts::Tun(tun) => {
+                assert_eq!(tun.device_id, "dev://utun1989");
+                assert_eq!(tun.stack, TunStack::Gvisor);
+                assert_eq!(tun.dns_hijack, ["any:53".parse().unwrap()]);
+            }
+            _ => panic!("expected a tun listener"),
+        }
+
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            r#"
+name: http-in
+type: http
+port: 7890
+lan-allowed-ips: [192.168.1.0/33]
+"#,
+        )
+        .unwrap();
+        assert!(InboundOpts::try_from(mapping).is_err());
+    }
+}
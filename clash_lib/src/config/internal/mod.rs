@@ -1,5 +1,8 @@
 pub mod config;
+pub mod diff;
+pub mod listener;
 pub mod proxy;
 pub mod rule;
+pub mod share_link;
 
 pub use config::Config as InternalConfig;
@@ -1,3 +1,4 @@
+use crate::common::redact::Secret;
 use crate::common::utils::default_bool_true;
 use crate::config::utils;
 use crate::Error;
@@ -6,11 +7,43 @@ use serde::Deserialize;
 use serde_yaml::Value;
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 pub const PROXY_DIRECT: &str = "DIRECT";
 pub const PROXY_REJECT: &str = "REJECT";
+pub const PROXY_REJECT_DROP: &str = "REJECT-DROP";
+pub const PROXY_REJECT_HTTP: &str = "REJECT-HTTP";
 pub const PROXY_GLOBAL: &str = "GLOBAL";
 
+/// How a built-in reject outbound answers a blocked connection,
+/// rules pick one by targeting its name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RejectMode {
+    /// `REJECT`: close right away, aborting the inbound connection
+    #[default]
+    Reset,
+    /// `REJECT-DROP`: hold the connection without answering for a while
+    Drop,
+    /// `REJECT-HTTP`: answer plain HTTP with a 403 page and TLS with an alert
+    Http,
+}
+
+impl RejectMode {
+    pub const ALL: [RejectMode; 3] = [RejectMode::Reset, RejectMode::Drop, RejectMode::Http];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RejectMode::Reset => PROXY_REJECT,
+            RejectMode::Drop => PROXY_REJECT_DROP,
+            RejectMode::Http => PROXY_REJECT_HTTP,
+        }
+    }
+
+    pub fn is_reject(name: &str) -> bool {
+        Self::ALL.iter().any(|x| x.name() == name)
+    }
+}
+
 pub enum OutboundProxy {
     ProxyServer(OutboundProxyProtocol),
     ProxyGroup(OutboundGroupProtocol),
@@ -44,7 +77,10 @@ pub enum OutboundProxyProtocol {
     #[serde(skip)]
     Direct,
     #[serde(skip)]
-    Reject,
+    Reject(RejectMode),
+    /// DIRECT under a name of its own, from a chosen local IP
+    #[serde(rename = "direct")]
+    NamedDirect(OutboundDirect),
     #[serde(rename = "ss")]
     Ss(OutboundShadowsocks),
     #[serde(rename = "socks5")]
@@ -53,17 +89,38 @@ pub enum OutboundProxyProtocol {
     Trojan(OutboundTrojan),
     #[serde(rename = "vmess")]
     Vmess(OutboundVmess),
+    #[serde(rename = "anytls")]
+    AnyTls(OutboundAnyTls),
+    #[serde(rename = "wireguard")]
+    Wireguard(OutboundWireguard),
 }
 
 impl OutboundProxyProtocol {
-    fn name(&self) -> &str {
+    pub(crate) fn name(&self) -> &str {
         match &self {
             OutboundProxyProtocol::Direct => PROXY_DIRECT,
-            OutboundProxyProtocol::Reject => PROXY_REJECT,
+            OutboundProxyProtocol::Reject(mode) => mode.name(),
+            OutboundProxyProtocol::NamedDirect(direct) => &direct.name,
             OutboundProxyProtocol::Ss(ss) => &ss.name,
             OutboundProxyProtocol::Socks5(socks5) => &socks5.name,
             OutboundProxyProtocol::Trojan(trojan) => &trojan.name,
             OutboundProxyProtocol::Vmess(vmess) => &vmess.name,
+            OutboundProxyProtocol::AnyTls(anytls) => &anytls.name,
+            OutboundProxyProtocol::Wireguard(wg) => &wg.name,
+        }
+    }
+
+    fn max_connections(&self) -> Option<usize> {
+        match &self {
+            OutboundProxyProtocol::Ss(ss) => ss.max_connections,
+            OutboundProxyProtocol::Socks5(socks5) => socks5.max_connections,
+            OutboundProxyProtocol::Trojan(trojan) => trojan.max_connections,
+            OutboundProxyProtocol::Vmess(vmess) => vmess.max_connections,
+            OutboundProxyProtocol::AnyTls(anytls) => anytls.max_connections,
+            OutboundProxyProtocol::Wireguard(wg) => wg.max_connections,
+            OutboundProxyProtocol::Direct
+            | OutboundProxyProtocol::Reject(_)
+            | OutboundProxyProtocol::NamedDirect(_) => None,
         }
     }
 }
@@ -72,8 +129,16 @@ impl TryFrom<HashMap<String, Value>> for OutboundProxyProtocol {
     type Error = crate::Error;
 
     fn try_from(mapping: HashMap<String, Value>) -> Result<Self, Self::Error> {
-        OutboundProxyProtocol::deserialize(MapDeserializer::new(mapping.into_iter()))
-            .map_err(map_serde_error)
+        let proxy = OutboundProxyProtocol::deserialize(MapDeserializer::new(mapping.into_iter()))
+            .map_err(map_serde_error)?;
+        // a cap of 0 would turn every session away
+        if proxy.max_connections() == Some(0) {
+            return Err(Error::InvalidConfig(format!(
+                "proxy {}: max-connections must be greater than 0",
+                proxy.name()
+            )));
+        }
+        Ok(proxy)
     }
 }
 
@@ -83,39 +148,82 @@ impl Display for OutboundProxyProtocol {
             OutboundProxyProtocol::Ss(_) => write!(f, "Shadowsocks"),
             OutboundProxyProtocol::Socks5(_) => write!(f, "Socks5"),
             OutboundProxyProtocol::Direct => write!(f, "{}", PROXY_DIRECT),
-            OutboundProxyProtocol::Reject => write!(f, "{}", PROXY_REJECT),
+            OutboundProxyProtocol::Reject(mode) => write!(f, "{}", mode.name()),
+            OutboundProxyProtocol::NamedDirect(_) => write!(f, "Direct"),
             OutboundProxyProtocol::Trojan(_) => write!(f, "{}", "Trojan"),
             OutboundProxyProtocol::Vmess(_) => write!(f, "{}", "Vmess"),
+            OutboundProxyProtocol::AnyTls(_) => write!(f, "{}", "AnyTLS"),
+            OutboundProxyProtocol::Wireguard(_) => write!(f, "{}", "WireGuard"),
         }
     }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutboundDirect {
+    pub name: String,
+    /// the local IP to connect from, for hosts with several
+    pub bind_address: Option<IpAddr>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
 pub struct OutboundShadowsocks {
     pub name: String,
     pub server: String,
     pub port: u16,
     pub cipher: String,
-    pub password: String,
+    pub password: Secret,
     #[serde(default = "default_bool_true")]
     pub udp: bool,
     pub plugin: Option<String>,
-    pub plugin_opts: Option<HashMap<String, serde_yaml::Value>>,
+    pub plugin_opts: Option<PluginOpts>,
+    pub smux: Option<SmuxOpt>,
+    pub udp_over_tcp: Option<bool>,
+    /// name of an outbound to reach the server through
+    pub dialer_proxy: Option<String>,
+    /// dial the server with MPTCP, the global `mptcp` if not set
+    pub mptcp: Option<bool>,
+    /// the local IP to dial the server from, for hosts with several
+    pub bind_address: Option<IpAddr>,
+    /// the most sessions through this proxy at once
+    pub max_connections: Option<usize>,
+}
+
+/// `plugin-opts` is either a map, or for SIP003 plugins, the raw
+/// `SS_PLUGIN_OPTIONS` string e.g. `tls;host=example.com`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum PluginOpts {
+    Map(HashMap<String, serde_yaml::Value>),
+    Env(String),
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
 pub struct OutboundSocks5 {
     pub name: String,
     pub server: String,
     pub port: u16,
     pub username: Option<String>,
-    pub password: Option<String>,
-    pub tls: bool,
-    pub skip_cert_verity: bool,
-    pub udp: bool,
+    pub password: Option<Secret>,
+    pub tls: Option<bool>,
+    pub sni: Option<String>,
+    pub skip_cert_verify: Option<bool>,
+    /// see [`TlsFragment`](crate::proxy::transport::TlsFragment)
+    pub tls_fragment: Option<String>,
+    pub udp: Option<bool>,
+    pub dialer_proxy: Option<String>,
+    /// dial the server with MPTCP, the global `mptcp` if not set
+    pub mptcp: Option<bool>,
+    /// the local IP to dial the server from, for hosts with several
+    pub bind_address: Option<IpAddr>,
+    /// the most sessions through this proxy at once
+    pub max_connections: Option<usize>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
 pub struct WsOpt {
     pub path: Option<String>,
     pub headers: Option<HashMap<String, String>>,
@@ -129,6 +237,24 @@ pub struct H2Opt {
     pub path: Option<String>,
 }
 
+/// multiplexes the streams of an outbound over a few connections
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct SmuxOpt {
+    pub enabled: bool,
+    /// yamux or h2mux
+    pub protocol: Option<String>,
+    pub max_connections: Option<usize>,
+    pub max_streams: Option<usize>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct QuicOpt {
+    pub congestion_controller: Option<String>,
+    pub zero_rtt: Option<bool>,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
 #[serde(rename_all = "kebab-case")]
 pub struct GrpcOpt {
@@ -141,14 +267,93 @@ pub struct OutboundTrojan {
     pub name: String,
     pub server: String,
     pub port: u16,
-    pub password: String,
+    pub password: Secret,
     pub alpn: Option<Vec<String>>,
     pub sni: Option<String>,
     pub skip_cert_verify: Option<bool>,
+    /// see [`TlsFragment`](crate::proxy::transport::TlsFragment)
+    pub tls_fragment: Option<String>,
     pub udp: Option<bool>,
     pub network: Option<String>,
     pub grpc_opts: Option<GrpcOpt>,
     pub ws_opts: Option<WsOpt>,
+    pub h2_opts: Option<H2Opt>,
+    pub smux: Option<SmuxOpt>,
+    pub udp_over_tcp: Option<bool>,
+    pub dialer_proxy: Option<String>,
+    /// dial the server with MPTCP, the global `mptcp` if not set
+    pub mptcp: Option<bool>,
+    /// the local IP to dial the server from, for hosts with several
+    pub bind_address: Option<IpAddr>,
+    /// the most sessions through this proxy at once
+    pub max_connections: Option<usize>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutboundAnyTls {
+    pub name: String,
+    pub server: String,
+    pub port: u16,
+    pub password: Secret,
+    pub alpn: Option<Vec<String>>,
+    pub sni: Option<String>,
+    pub skip_cert_verify: Option<bool>,
+    /// see [`TlsFragment`](crate::proxy::transport::TlsFragment)
+    pub tls_fragment: Option<String>,
+    pub udp: Option<bool>,
+    /// seconds an idle session is kept for reuse
+    pub idle_session_timeout: Option<u64>,
+    pub dialer_proxy: Option<String>,
+    /// dial the server with MPTCP, the global `mptcp` if not set
+    pub mptcp: Option<bool>,
+    /// the local IP to dial the server from, for hosts with several
+    pub bind_address: Option<IpAddr>,
+    /// the most sessions through this proxy at once
+    pub max_connections: Option<usize>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutboundWireguard {
+    pub name: String,
+    /// the peer, unless `peers` lists them
+    pub server: Option<String>,
+    pub port: Option<u16>,
+    pub public_key: Option<String>,
+    #[serde(alias = "pre-shared-key")]
+    pub preshared_key: Option<Secret>,
+    pub peers: Option<Vec<WireguardPeer>>,
+    pub private_key: Secret,
+    /// the addresses of this end inside the tunnel
+    pub ip: Ipv4Addr,
+    pub ipv6: Option<Ipv6Addr>,
+    pub mtu: Option<u16>,
+    pub udp: Option<bool>,
+    /// seconds between keepalives, for peers behind NAT
+    pub persistent_keepalive: Option<u16>,
+    /// resolve domains with `dns` through the tunnel rather than locally
+    pub remote_dns_resolve: Option<bool>,
+    pub dns: Option<Vec<IpAddr>>,
+    /// sends the tunnel packets through another proxy, which must relay UDP
+    pub dialer_proxy: Option<String>,
+    /// the local IP to send the tunnel packets from, for hosts with several
+    pub bind_address: Option<IpAddr>,
+    /// the most sessions through this proxy at once
+    pub max_connections: Option<usize>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct WireguardPeer {
+    pub server: String,
+    pub port: u16,
+    pub public_key: String,
+    #[serde(alias = "pre-shared-key")]
+    pub preshared_key: Option<Secret>,
+    /// the destinations sent to this peer, in CIDR notation, all of them if
+    /// not set
+    pub allowed_ips: Option<Vec<String>>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
@@ -157,18 +362,32 @@ pub struct OutboundVmess {
     pub name: String,
     pub server: String,
     pub port: u16,
-    pub uuid: String,
+    pub uuid: Secret,
     #[serde(alias = "alterId")]
     pub alter_id: u16,
     pub cipher: Option<String>,
     pub udp: Option<bool>,
     pub tls: Option<bool>,
     pub skip_cert_verify: Option<bool>,
+    /// see [`TlsFragment`](crate::proxy::transport::TlsFragment)
+    pub tls_fragment: Option<String>,
     #[serde(alias = "servername")]
     pub server_name: Option<String>,
     pub network: Option<String>,
     pub ws_opts: Option<WsOpt>,
     pub h2_opts: Option<H2Opt>,
+    pub quic_opts: Option<QuicOpt>,
+    pub smux: Option<SmuxOpt>,
+    pub udp_over_tcp: Option<bool>,
+    /// only `xudp` is supported
+    pub packet_encoding: Option<String>,
+    pub dialer_proxy: Option<String>,
+    /// dial the server with MPTCP, the global `mptcp` if not set
+    pub mptcp: Option<bool>,
+    /// the local IP to dial the server from, for hosts with several
+    pub bind_address: Option<IpAddr>,
+    /// the most sessions through this proxy at once
+    pub max_connections: Option<usize>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
@@ -206,6 +425,16 @@ impl OutboundGroupProtocol {
             OutboundGroupProtocol::Select(g) => g.proxies.as_ref(),
         }
     }
+
+    pub fn use_provider(&self) -> Option<&Vec<String>> {
+        match &self {
+            OutboundGroupProtocol::Relay(g) => g.use_provider.as_ref(),
+            OutboundGroupProtocol::UrlTest(g) => g.use_provider.as_ref(),
+            OutboundGroupProtocol::Fallback(g) => g.use_provider.as_ref(),
+            OutboundGroupProtocol::LoadBalance(g) => g.use_provider.as_ref(),
+            OutboundGroupProtocol::Select(g) => g.use_provider.as_ref(),
+        }
+    }
 }
 
 impl TryFrom<HashMap<String, Value>> for OutboundGroupProtocol {
@@ -235,6 +464,11 @@ pub struct OutboundGroupRelay {
     pub proxies: Option<Vec<String>>,
     #[serde(rename = "use")]
     pub use_provider: Option<Vec<String>>,
+    /// also use every proxy server, including ones added at runtime
+    #[serde(rename = "include-all")]
+    pub include_all: Option<bool>,
+    /// regex on proxy names, applies to `include-all`
+    pub filter: Option<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
@@ -244,11 +478,19 @@ pub struct OutboundGroupUrlTest {
     pub proxies: Option<Vec<String>>,
     #[serde(rename = "use")]
     pub use_provider: Option<Vec<String>>,
+    /// also use every proxy server, including ones added at runtime
+    #[serde(rename = "include-all")]
+    pub include_all: Option<bool>,
+    /// regex on proxy names, applies to `include-all`
+    pub filter: Option<String>,
 
     pub url: String,
     #[serde(deserialize_with = "utils::deserialize_u64")]
     pub interval: u64,
     pub lazy: Option<bool>,
+    #[serde(rename = "max-concurrent")]
+    pub max_concurrent: Option<usize>,
+    pub spacing: Option<u64>,
     pub tolerance: Option<u16>,
 }
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
@@ -258,11 +500,19 @@ pub struct OutboundGroupFallback {
     pub proxies: Option<Vec<String>>,
     #[serde(rename = "use")]
     pub use_provider: Option<Vec<String>>,
+    /// also use every proxy server, including ones added at runtime
+    #[serde(rename = "include-all")]
+    pub include_all: Option<bool>,
+    /// regex on proxy names, applies to `include-all`
+    pub filter: Option<String>,
 
     pub url: String,
     #[serde(deserialize_with = "utils::deserialize_u64")]
     pub interval: u64,
     pub lazy: Option<bool>,
+    #[serde(rename = "max-concurrent")]
+    pub max_concurrent: Option<usize>,
+    pub spacing: Option<u64>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
@@ -272,11 +522,19 @@ pub struct OutboundGroupLoadBalance {
     pub proxies: Option<Vec<String>>,
     #[serde(rename = "use")]
     pub use_provider: Option<Vec<String>>,
+    /// also use every proxy server, including ones added at runtime
+    #[serde(rename = "include-all")]
+    pub include_all: Option<bool>,
+    /// regex on proxy names, applies to `include-all`
+    pub filter: Option<String>,
 
     pub url: String,
     #[serde(deserialize_with = "utils::deserialize_u64")]
     pub interval: u64,
     pub lazy: Option<bool>,
+    #[serde(rename = "max-concurrent")]
+    pub max_concurrent: Option<usize>,
+    pub spacing: Option<u64>,
     pub strategy: Option<LoadBalanceStrategy>,
 }
 
@@ -296,6 +554,11 @@ pub struct OutboundGroupSelect {
     pub proxies: Option<Vec<String>>,
     #[serde(rename = "use")]
     pub use_provider: Option<Vec<String>>,
+    /// also use every proxy server, including ones added at runtime
+    #[serde(rename = "include-all")]
+    pub include_all: Option<bool>,
+    /// regex on proxy names, applies to `include-all`
+    pub filter: Option<String>,
     pub udp: Option<bool>,
 }
 
@@ -316,6 +579,7 @@ pub struct OutboundHttpProvider {
     pub interval: u64,
     pub path: String,
     pub health_check: HealthCheck,
+    pub auto_group_by: Option<AutoGroupBy>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -326,14 +590,37 @@ pub struct OutboundFileProvider {
     pub path: String,
     pub interval: Option<u64>,
     pub health_check: HealthCheck,
+    pub auto_group_by: Option<AutoGroupBy>,
+}
+
+/// makes a url-test group `<provider>-<code>` for each country the proxies
+/// of a provider are in, e.g. `subscription-HK`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AutoGroupBy {
+    Country,
+}
+
+impl OutboundProxyProviderDef {
+    pub fn auto_group_by(&self) -> Option<AutoGroupBy> {
+        match self {
+            Self::Http(http) => http.auto_group_by,
+            Self::File(file) => file.auto_group_by,
+        }
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
 pub struct HealthCheck {
     pub enable: bool,
     pub url: String,
     pub interval: u64,
     pub lazy: Option<bool>,
+    /// max number of latency tests running at the same time for this provider
+    pub max_concurrent: Option<usize>,
+    /// milliseconds between the start of two latency tests
+    pub spacing: Option<u64>,
 }
 
 impl TryFrom<HashMap<String, Value>> for OutboundProxyProviderDef {
@@ -1,4 +1,4 @@
-use crate::Error;
+use crate::{common::port_set::PortSet, Error};
 use std::{fmt::Display, str::FromStr};
 
 pub enum RuleType {
@@ -14,11 +14,19 @@ pub enum RuleType {
         domain_keyword: String,
         target: String,
     },
+    /// any of the codes, `LAN` for the private ranges, or none of them
+    /// with `!`
     GeoIP {
         target: String,
-        country_code: String,
+        country_codes: Vec<String>,
+        negate: bool,
         no_resolve: bool,
     },
+    /// a category of the geosite, with an optional `@attribute`
+    GeoSite {
+        target: String,
+        country_code: String,
+    },
     IPCIDR {
         ipnet: ipnet::IpNet,
         target: String,
@@ -31,11 +39,27 @@ pub enum RuleType {
     },
     SRCPort {
         target: String,
-        port: u16,
+        port: PortSet,
     },
     DSTPort {
         target: String,
-        port: u16,
+        port: PortSet,
+    },
+    SRCDevice {
+        name: String,
+        target: String,
+    },
+    /// `tcp`, `udp` or a sniffed subprotocol, and the destination ports
+    /// after a `:`, e.g. `udp:443`
+    Network {
+        network: String,
+        ports: Option<PortSet>,
+        target: String,
+    },
+    /// the DSCP of the packets tun reads, 0 to 63
+    Dscp {
+        dscp: PortSet,
+        target: String,
     },
     ProcessName {
         process_name: String,
@@ -45,9 +69,43 @@ pub enum RuleType {
         process_path: String,
         target: String,
     },
+    /// the owner of the source socket, for sessions from this host
+    Uid {
+        uids: std::ops::RangeInclusive<u32>,
+        target: String,
+    },
+    /// the type of the listener the session arrived on
+    InType {
+        in_type: Vec<crate::session::Type>,
+        target: String,
+    },
+    /// the port of the listener the session arrived on
+    InPort {
+        port: PortSet,
+        target: String,
+    },
+    /// the name of the listener the session arrived on, see `Inbound`
+    InName {
+        name: String,
+        target: String,
+    },
+    /// `ipcidr` and `classical` rule sets resolve a domain to match it,
+    /// unless `no-resolve`
     RuleSet {
         rule_set: String,
         target: String,
+        no_resolve: bool,
+    },
+    /// a shortcut of `script`
+    Script {
+        shortcut: String,
+        target: String,
+    },
+    /// carries on with the chain `sub_rule` of `sub-rules` for the sessions
+    /// `rule` matches, and with the next rule if none in the chain does
+    SubRule {
+        rule: Box<RuleType>,
+        sub_rule: String,
     },
     Match {
         target: String,
@@ -61,13 +119,23 @@ impl RuleType {
             RuleType::DomainSuffix { target, .. } => target,
             RuleType::DomainKeyword { target, .. } => target,
             RuleType::GeoIP { target, .. } => target,
+            RuleType::GeoSite { target, .. } => target,
             RuleType::IPCIDR { target, .. } => target,
             RuleType::SRCIPCIDR { target, .. } => target,
             RuleType::SRCPort { target, .. } => target,
             RuleType::DSTPort { target, .. } => target,
+            RuleType::SRCDevice { target, .. } => target,
+            RuleType::Network { target, .. } => target,
+            RuleType::Dscp { target, .. } => target,
             RuleType::ProcessName { target, .. } => target,
             RuleType::ProcessPath { target, .. } => target,
+            RuleType::Uid { target, .. } => target,
+            RuleType::InType { target, .. } => target,
+            RuleType::InPort { target, .. } => target,
+            RuleType::InName { target, .. } => target,
             RuleType::RuleSet { target, .. } => target,
+            RuleType::Script { target, .. } => target,
+            RuleType::SubRule { sub_rule, .. } => sub_rule,
             RuleType::Match { target } => target,
         }
     }
@@ -80,13 +148,23 @@ impl Display for RuleType {
             RuleType::DomainSuffix { .. } => write!(f, "DOMAIN-SUFFIX"),
             RuleType::DomainKeyword { .. } => write!(f, "DOMAIN-KEYWORD"),
             RuleType::GeoIP { .. } => write!(f, "GEOIP"),
+            RuleType::GeoSite { .. } => write!(f, "GEOSITE"),
             RuleType::IPCIDR { .. } => write!(f, "IP-CIDR"),
             RuleType::SRCIPCIDR { .. } => write!(f, "SRC-IP-CIDR"),
             RuleType::SRCPort { .. } => write!(f, "SRC-PORT"),
             RuleType::DSTPort { .. } => write!(f, "DST-PORT"),
+            RuleType::SRCDevice { .. } => write!(f, "SRC-DEVICE"),
+            RuleType::Network { .. } => write!(f, "NETWORK"),
+            RuleType::Dscp { .. } => write!(f, "DSCP"),
             RuleType::ProcessName { .. } => write!(f, "PROCESS-NAME"),
             RuleType::ProcessPath { .. } => write!(f, "PROCESS-PATH"),
+            RuleType::Uid { .. } => write!(f, "UID"),
+            RuleType::InType { .. } => write!(f, "IN-TYPE"),
+            RuleType::InPort { .. } => write!(f, "IN-PORT"),
+            RuleType::InName { .. } => write!(f, "IN-NAME"),
             RuleType::RuleSet { .. } => write!(f, "RULE-SET"),
+            RuleType::Script { .. } => write!(f, "SCRIPT"),
+            RuleType::SubRule { .. } => write!(f, "SUB-RULE"),
             RuleType::Match { .. } => write!(f, "MATCH"),
         }
     }
@@ -112,14 +190,35 @@ impl RuleType {
                 domain_keyword: payload.to_string(),
                 target: target.to_string(),
             }),
-            "GEOIP" => Ok(RuleType::GeoIP {
+            "GEOIP" => {
+                let (negate, codes) = match payload.strip_prefix('!') {
+                    Some(codes) => (true, codes),
+                    None => (false, payload),
+                };
+                let country_codes = codes
+                    .split('/')
+                    .map(|x| x.trim().to_owned())
+                    .collect::<Vec<_>>();
+                if country_codes.iter().any(|x| x.is_empty()) {
+                    return Err(Error::InvalidConfig(format!(
+                        "invalid GEOIP codes: {}",
+                        payload
+                    )));
+                }
+                Ok(RuleType::GeoIP {
+                    target: target.to_string(),
+                    country_codes,
+                    negate,
+                    no_resolve: if let Some(params) = params {
+                        params.contains(&"no-resolve")
+                    } else {
+                        false
+                    },
+                })
+            }
+            "GEOSITE" => Ok(RuleType::GeoSite {
                 target: target.to_string(),
                 country_code: payload.to_string(),
-                no_resolve: if let Some(params) = params {
-                    params.contains(&"no-resolve")
-                } else {
-                    false
-                },
             }),
             "IP-CIDR" | "IP-CIDR6" => Ok(RuleType::IPCIDR {
                 ipnet: payload.parse()?,
@@ -141,16 +240,46 @@ impl RuleType {
             }),
             "SRC-PORT" => Ok(RuleType::SRCPort {
                 target: target.to_string(),
-                port: payload
-                    .parse()
-                    .expect(format!("invalid port: {}", payload).as_str()),
+                port: payload.parse()?,
             }),
             "DST-PORT" => Ok(RuleType::DSTPort {
                 target: target.to_string(),
-                port: payload
-                    .parse()
-                    .expect(format!("invalid port: {}", payload).as_str()),
+                port: payload.parse()?,
+            }),
+            "SRC-DEVICE" => Ok(RuleType::SRCDevice {
+                name: payload.to_string(),
+                target: target.to_string(),
             }),
+            "NETWORK" => {
+                let (network, ports) = match payload.split_once(':') {
+                    Some((network, ports)) => (network, Some(ports.parse()?)),
+                    None => (payload, None),
+                };
+                match network.trim().to_lowercase().as_str() {
+                    network @ ("tcp" | "udp" | "ws") => Ok(RuleType::Network {
+                        network: network.to_owned(),
+                        ports,
+                        target: target.to_string(),
+                    }),
+                    _ => Err(Error::InvalidConfig(format!(
+                        "invalid network: {}, must be one of tcp, udp or ws",
+                        payload
+                    ))),
+                }
+            }
+            "DSCP" => {
+                let dscp = payload.parse::<PortSet>()?;
+                if dscp.max().is_some_and(|x| x > 63) {
+                    return Err(Error::InvalidConfig(format!(
+                        "invalid dscp: {}, must be within 0-63",
+                        payload
+                    )));
+                }
+                Ok(RuleType::Dscp {
+                    dscp,
+                    target: target.to_string(),
+                })
+            }
             "PROCESS-NAME" => Ok(RuleType::ProcessName {
                 process_name: payload.to_string(),
                 target: target.to_string(),
@@ -159,9 +288,58 @@ impl RuleType {
                 process_path: payload.to_string(),
                 target: target.to_string(),
             }),
+            "UID" => {
+                let invalid = || Error::InvalidConfig(format!("invalid uid: {}", payload));
+                let uids = match payload.split_once('-') {
+                    Some((start, end)) => {
+                        start.trim().parse().map_err(|_| invalid())?
+                            ..=end.trim().parse().map_err(|_| invalid())?
+                    }
+                    None => {
+                        let uid = payload.parse().map_err(|_| invalid())?;
+                        uid..=uid
+                    }
+                };
+                if uids.is_empty() {
+                    return Err(invalid());
+                }
+                Ok(RuleType::Uid {
+                    uids,
+                    target: target.to_string(),
+                })
+            }
+            // `SOCKS` being both versions, e.g. `IN-TYPE,SOCKS/HTTP,proxy`
+            "IN-TYPE" => Ok(RuleType::InType {
+                in_type: payload
+                    .split('/')
+                    .map(|x| match x.trim().to_uppercase().as_str() {
+                        "SOCKS" => Ok(vec![
+                            crate::session::Type::Socks4,
+                            crate::session::Type::Socks5,
+                        ]),
+                        x => x.parse().map(|x| vec![x]),
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| Error::InvalidConfig(e.to_string()))?
+                    .concat(),
+                target: target.to_string(),
+            }),
+            "IN-PORT" => Ok(RuleType::InPort {
+                port: payload.parse()?,
+                target: target.to_string(),
+            }),
+            "IN-NAME" => Ok(RuleType::InName {
+                name: payload.to_string(),
+                target: target.to_string(),
+            }),
             "RULE-SET" => Ok(RuleType::RuleSet {
                 rule_set: payload.to_string(),
                 target: target.to_string(),
+                no_resolve: params.is_some_and(|x| x.contains(&"no-resolve")),
+            }),
+            "SCRIPT" => Ok(RuleType::Script {
+                shortcut: payload.to_string(),
+                target: target.to_string(),
             }),
             "MATCH" => Ok(RuleType::Match {
                 target: target.to_string(),
@@ -172,15 +350,67 @@ impl RuleType {
             ))),
         }
     }
+
+    /// `(<condition>),<sub-rule>`, the condition being a rule without a
+    /// target
+    fn parse_sub_rule(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidConfig(format!("invalid rule line: SUB-RULE,{}", s));
+        let (condition, sub_rule) = s
+            .strip_prefix('(')
+            .and_then(|x| x.rsplit_once(')'))
+            .ok_or_else(invalid)?;
+        let sub_rule = sub_rule
+            .trim()
+            .strip_prefix(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .ok_or_else(invalid)?;
+
+        let rule = match condition.split_once(',') {
+            Some(_) => RuleType::new_without_target(condition)?,
+            None => RuleType::new(condition.trim(), "", "", None)?,
+        };
+        Ok(RuleType::SubRule {
+            rule: Box::new(rule),
+            sub_rule: sub_rule.to_owned(),
+        })
+    }
+
+    /// `<type>,<payload>[,<params>]`, a rule held by something else that
+    /// decides where the sessions it matches go
+    pub fn new_without_target(line: &str) -> Result<Self, Error> {
+        let parts = line.split(',').map(str::trim).collect::<Vec<&str>>();
+        match parts.as_slice() {
+            [proto @ ("SRC-PORT" | "DST-PORT" | "IN-PORT"), ports @ ..] => {
+                RuleType::new(proto, &ports.join(","), "", None)
+            }
+            [proto, payload] => RuleType::new(proto, payload, "", None),
+            [proto, payload, params @ ..] => {
+                RuleType::new(proto, payload, "", Some(params.to_vec()))
+            }
+            _ => Err(Error::InvalidConfig(format!("invalid rule line: {}", line))),
+        }
+    }
 }
 
 impl TryFrom<String> for RuleType {
     type Error = crate::Error;
 
     fn try_from(line: String) -> Result<Self, Self::Error> {
+        // the condition has commas of its own
+        if let Some(rest) = line.trim().strip_prefix("SUB-RULE,") {
+            return RuleType::parse_sub_rule(rest.trim());
+        }
+
         let parts = line.split(",").map(str::trim).collect::<Vec<&str>>();
 
         match parts.as_slice() {
+            // the ports may be listed with commas, e.g. `DST-PORT,80,443,proxy`
+            [proto @ ("SRC-PORT" | "DST-PORT" | "IN-PORT"), ports @ .., target]
+                if ports.len() > 1 =>
+            {
+                RuleType::new(proto, &ports.join(","), target, None)
+            }
             [proto, target] => RuleType::new(proto, "", target, None),
             [proto, payload, target] => RuleType::new(proto, payload, target, None),
             [proto, payload, target, params @ ..] => {
@@ -198,3 +428,171 @@ impl FromStr for RuleType {
         s.to_string().try_into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::RuleType;
+
+    #[test]
+    fn test_parse_sub_rule() {
+        let rule = "SUB-RULE,(DOMAIN-SUFFIX,example.com),chain"
+            .parse::<RuleType>()
+            .unwrap();
+        match rule {
+            RuleType::SubRule { rule, sub_rule } => {
+                assert_eq!(sub_rule, "chain");
+                assert!(matches!(
+                    *rule,
+                    RuleType::DomainSuffix { ref domain_suffix, ref target }
+                        if domain_suffix == "example.com" && target.is_empty()
+                ));
+            }
+            _ => panic!("not a SUB-RULE"),
+        }
+
+        let rule = "SUB-RULE, (IP-CIDR,10.0.0.0/8,no-resolve) , lan"
+            .parse::<RuleType>()
+            .unwrap();
+        assert!(matches!(
+            rule,
+            RuleType::SubRule { ref rule, ref sub_rule }
+                if sub_rule == "lan"
+                    && matches!(**rule, RuleType::IPCIDR { no_resolve: true, .. })
+        ));
+
+        for x in [
+            "SUB-RULE,DOMAIN,example.com,chain",
+            "SUB-RULE,(DOMAIN,example.com)",
+            "SUB-RULE,(DOMAIN,example.com),",
+            "SUB-RULE,(NOPE,x),chain",
+            "SUB-RULE,(DST-PORT,80,http),chain",
+        ] {
+            assert!(x.parse::<RuleType>().is_err(), "{}", x);
+        }
+    }
+
+    #[test]
+    fn test_parse_in_type() {
+        use crate::session::Type;
+
+        let rule = "IN-TYPE,socks/HTTPS,DIRECT".parse::<RuleType>().unwrap();
+        assert!(matches!(
+            rule,
+            RuleType::InType { ref in_type, .. }
+                if in_type == &[Type::Socks4, Type::Socks5, Type::HttpConnect]
+        ));
+        assert!("IN-TYPE,SOCKS6,DIRECT".parse::<RuleType>().is_err());
+        assert!("IN-PORT,http,DIRECT".parse::<RuleType>().is_err());
+    }
+
+    #[test]
+    fn test_parse_ports() {
+        let ports = |x: &str| match x.parse::<RuleType>() {
+            Ok(RuleType::DSTPort { port, target }) => {
+                assert_eq!(target, "proxy");
+                Some(port.to_string())
+            }
+            _ => None,
+        };
+        assert_eq!(ports("DST-PORT,443,proxy").as_deref(), Some("443"));
+        assert_eq!(
+            ports("DST-PORT,80,443/8000-8999,proxy").as_deref(),
+            Some("80/443/8000-8999")
+        );
+        assert_eq!(ports("DST-PORT,80,https,proxy"), None);
+        assert!(matches!(
+            "SRC-PORT, 1000-2000 ,DIRECT".parse::<RuleType>(),
+            Ok(RuleType::SRCPort { ref port, .. }) if port.contains(1500)
+        ));
+        assert!(matches!(
+            "SUB-RULE,(DST-PORT,80,443),web".parse::<RuleType>(),
+            Ok(RuleType::SubRule { ref rule, .. })
+                if matches!(**rule, RuleType::DSTPort { ref port, .. } if port.contains(443))
+        ));
+    }
+
+    #[test]
+    fn test_parse_uid() {
+        let uids = |x: &str| match x.parse::<RuleType>() {
+            Ok(RuleType::Uid { uids, target }) => {
+                assert_eq!(target, "DIRECT");
+                Some(uids)
+            }
+            _ => None,
+        };
+        assert_eq!(uids("UID,1000,DIRECT"), Some(1000..=1000));
+        assert_eq!(uids("UID,10000-19999,DIRECT"), Some(10000..=19999));
+        assert_eq!(uids("UID,2-1,DIRECT"), None);
+        assert_eq!(uids("UID,-1,DIRECT"), None);
+        assert_eq!(uids("UID,root,DIRECT"), None);
+    }
+
+    #[test]
+    fn test_parse_network() {
+        assert!(matches!(
+            "NETWORK,UDP:443/8443,REJECT".parse::<RuleType>(),
+            Ok(RuleType::Network { ref network, ports: Some(ref ports), .. })
+                if network == "udp" && ports.contains(8443) && !ports.contains(80)
+        ));
+        assert!(matches!(
+            "NETWORK,tcp,DIRECT".parse::<RuleType>(),
+            Ok(RuleType::Network { ports: None, .. })
+        ));
+        assert!("NETWORK,icmp,DIRECT".parse::<RuleType>().is_err());
+        assert!("NETWORK,udp:quic,DIRECT".parse::<RuleType>().is_err());
+
+        assert!(matches!(
+            "DSCP,46/40-43,relay".parse::<RuleType>(),
+            Ok(RuleType::Dscp { ref dscp, .. }) if dscp.contains(46) && dscp.contains(41)
+        ));
+        assert!("DSCP,64,relay".parse::<RuleType>().is_err());
+        assert!("DSCP,ef,relay".parse::<RuleType>().is_err());
+    }
+
+    #[test]
+    fn test_parse_geoip() {
+        let geoip = |x: &str| match x.parse::<RuleType>() {
+            Ok(RuleType::GeoIP {
+                country_codes,
+                negate,
+                no_resolve,
+                ..
+            }) => Some((country_codes, negate, no_resolve)),
+            _ => None,
+        };
+        assert_eq!(
+            geoip("GEOIP,CN,DIRECT"),
+            Some((vec!["CN".to_owned()], false, false))
+        );
+        assert_eq!(
+            geoip("GEOIP,!CN/HK,relay,no-resolve"),
+            Some((vec!["CN".to_owned(), "HK".to_owned()], true, true))
+        );
+        assert_eq!(
+            geoip("GEOIP,LAN,DIRECT"),
+            Some((vec!["LAN".to_owned()], false, false))
+        );
+        assert_eq!(geoip("GEOIP,CN/,DIRECT"), None);
+        assert_eq!(geoip("GEOIP,!,DIRECT"), None);
+    }
+
+    #[test]
+    fn test_parse_rule_set() {
+        let no_resolve = |x: &str| match x.parse::<RuleType>() {
+            Ok(RuleType::RuleSet {
+                rule_set,
+                no_resolve,
+                ..
+            }) => Some((rule_set, no_resolve)),
+            _ => None,
+        };
+        assert_eq!(
+            no_resolve("RULE-SET,cn,DIRECT"),
+            Some(("cn".to_owned(), false))
+        );
+        assert_eq!(
+            no_resolve("RULE-SET,cn,DIRECT,no-resolve"),
+            Some(("cn".to_owned(), true))
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::Arc;
+
+use http::{header, Request, Uri};
+use hyper::{body, Body};
+use tokio::io::AsyncReadExt;
+use tracing::info;
+
+use crate::{
+    app::dns::SystemResolver,
+    common::{http::new_http_client, redact},
+    Error,
+};
+
+const MAX_REDIRECTS: usize = 5;
+
+/// fetches a profile over HTTP(S), following redirects.
+/// `headers` are sent along with every request, a default `User-Agent`
+/// is added unless one is given. Once redirected to another origin, only
+/// the `User-Agent` of `headers` is, as the others may carry credentials
+/// for the first. The URLs are redacted in the errors, as
+/// subscription URLs carry their token.
+pub async fn fetch(url: &str, headers: &[(String, String)]) -> Result<String, Error> {
+    let body = fetch_bytes(url, headers).await?;
+    String::from_utf8(body).map_err(|x| {
+        Error::InvalidConfig(format!("config {} is not utf-8: {}", redact::url(url), x))
+    })
+}
+
+/// as [`fetch`], for content that isn't text
+pub async fn fetch_bytes(url: &str, headers: &[(String, String)]) -> Result<Vec<u8>, Error> {
+    let resolver = Arc::new(SystemResolver::new().map_err(|x| Error::DNSError(x.to_string()))?);
+    let client = new_http_client(resolver)?;
+
+    let mut uri = url
+        .parse::<Uri>()
+        .map_err(|x| Error::InvalidConfig(format!("invalid url {}: {}", redact::url(url), x)))?;
+
+    let mut cross_origin = false;
+    for _ in 0..=MAX_REDIRECTS {
+        let shown = redact::url(&uri.to_string());
+        let mut req = Request::get(uri.clone());
+        if !headers
+            .iter()
+            .any(|(k, _)| k.eq_ignore_ascii_case(header::USER_AGENT.as_str()))
+        {
+            req = req.header(
+                header::USER_AGENT,
+                format!("clash-rs/{}", env!("CARGO_PKG_VERSION")),
+            );
+        }
+        for (k, v) in headers {
+            if cross_origin && !k.eq_ignore_ascii_case(header::USER_AGENT.as_str()) {
+                continue;
+            }
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let req = req
+            .body(Body::empty())
+            .map_err(|x| Error::InvalidConfig(format!("invalid request: {}", x)))?;
+
+        let res = client
+            .request(req)
+            .await
+            .map_err(|x| Error::InvalidConfig(format!("failed to fetch {}: {}", shown, x)))?;
+
+        if res.status().is_redirection() {
+            let location = res
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|x| x.to_str().ok())
+                .ok_or_else(|| {
+                    Error::InvalidConfig(format!("{} redirected without location", shown))
+                })?;
+            let location = resolve_location(&uri, location)?;
+            cross_origin |= !same_origin(&uri, &location);
+            uri = location;
+            info!("redirected to {}", redact::url(&uri.to_string()));
+            continue;
+        }
+
+        if !res.status().is_success() {
+            return Err(Error::InvalidConfig(format!(
+                "failed to fetch {}: {}",
+                shown,
+                res.status()
+            )));
+        }
+
+        let body = body::to_bytes(res.into_body())
+            .await
+            .map_err(|x| Error::InvalidConfig(format!("failed to read {}: {}", shown, x)))?;
+        return Ok(body.to_vec());
+    }
+
+    Err(Error::InvalidConfig(format!(
+        "too many redirects fetching {}",
+        redact::url(url)
+    )))
+}
+
+/// reads the whole profile from stdin
+pub async fn read_stdin() -> Result<String, Error> {
+    let mut content = String::new();
+    tokio::io::stdin().read_to_string(&mut content).await?;
+    Ok(content)
+}
+
+fn same_origin(a: &Uri, b: &Uri) -> bool {
+    a.scheme() == b.scheme() && a.authority() == b.authority()
+}
+
+/// `Location` may be relative to the url that was requested
+fn resolve_location(base: &Uri, location: &str) -> Result<Uri, Error> {
+    let invalid = |x: http::Error| {
+        Error::InvalidConfig(format!("invalid redirect {}: {}", redact::url(location), x))
+    };
+
+    let target = location.parse::<Uri>().map_err(|x| invalid(x.into()))?;
+    if target.scheme().is_some() {
+        return Ok(target);
+    }
+
+    let mut parts = base.clone().into_parts();
+    parts.path_and_query = target.path_and_query().cloned();
+    Uri::from_parts(parts).map_err(|x| invalid(x.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Uri;
+
+    use super::{resolve_location, same_origin};
+
+    #[test]
+    fn test_resolve_location() {
+        let base = "https://example.com/a/config.yaml?token=1"
+            .parse::<Uri>()
+            .unwrap();
+        assert_eq!(
+            resolve_location(&base, "https://cdn.example.org/c.yaml").unwrap(),
+            "https://cdn.example.org/c.yaml"
+        );
+        assert_eq!(
+            resolve_location(&base, "/b/config.yaml").unwrap(),
+            "https://example.com/b/config.yaml"
+        );
+
+        let redirected = |x: &str| same_origin(&base, &resolve_location(&base, x).unwrap());
+        assert!(redirected("/b/config.yaml"));
+        assert!(redirected("https://example.com/c.yaml"));
+        assert!(!redirected("https://cdn.example.org/c.yaml"));
+        assert!(!redirected("http://example.com/c.yaml"));
+        assert!(!redirected("https://example.com:8443/c.yaml"));
+    }
+}
@@ -0,0 +1,220 @@
+//! The built-in cases, one per outbound protocol and transport, each against
+//! the reference implementation most deployments run.
+
+use crate::Error;
+
+use super::{docker::ServerSpec, Case};
+
+const PASSWORD: &str = "clash-conformance";
+const UUID: &str = "b831381d-6324-4d53-ad4f-8cda48b30811";
+
+const SHADOWSOCKS_RUST_IMAGE: &str = "ghcr.io/shadowsocks/ssserver-rust:latest";
+const XRAY_IMAGE: &str = "teddysun/xray:latest";
+const SING_BOX_IMAGE: &str = "ghcr.io/sagernet/sing-box:latest";
+
+const XRAY_DIR: &str = "/etc/xray";
+const SING_BOX_DIR: &str = "/etc/sing-box";
+
+pub fn builtin() -> Vec<Case> {
+    vec![
+        Case {
+            name: "shadowsocks",
+            udp: true,
+            server: shadowsocks_server,
+            outbound: |port| {
+                format!(
+                    "{{type: ss, name: ss, server: 127.0.0.1, port: {}, cipher: aes-256-gcm, \
+                     password: {}, udp: true}}",
+                    port, PASSWORD
+                )
+            },
+        },
+        Case {
+            name: "vmess",
+            udp: true,
+            server: |port| {
+                xray_server(
+                    port,
+                    &format!(
+                        r#""protocol": "vmess", "settings": {{"clients": [{{"id": "{}"}}]}}"#,
+                        UUID
+                    ),
+                )
+            },
+            outbound: |port| {
+                format!(
+                    "{{type: vmess, name: vmess, server: 127.0.0.1, port: {}, uuid: {}, \
+                     alterId: 0, cipher: auto, udp: true}}",
+                    port, UUID
+                )
+            },
+        },
+        Case {
+            name: "vmess-ws",
+            udp: true,
+            server: |port| {
+                xray_server(
+                    port,
+                    &format!(
+                        r#""protocol": "vmess", "settings": {{"clients": [{{"id": "{}"}}]}},
+                        "streamSettings": {{"network": "ws", "wsSettings": {{"path": "/ws"}}}}"#,
+                        UUID
+                    ),
+                )
+            },
+            outbound: |port| {
+                format!(
+                    "{{type: vmess, name: vmess-ws, server: 127.0.0.1, port: {}, uuid: {}, \
+                     alterId: 0, cipher: auto, udp: true, network: ws, ws-opts: {{path: /ws}}}}",
+                    port, UUID
+                )
+            },
+        },
+        Case {
+            name: "socks5",
+            udp: true,
+            server: |port| {
+                xray_server(
+                    port,
+                    &format!(
+                        r#""protocol": "socks", "settings": {{"auth": "password",
+                        "accounts": [{{"user": "clash", "pass": "{}"}}],
+                        "udp": true, "ip": "127.0.0.1"}}"#,
+                        PASSWORD
+                    ),
+                )
+            },
+            outbound: |port| {
+                format!(
+                    "{{type: socks5, name: socks5, server: 127.0.0.1, port: {}, \
+                     username: clash, password: {}, udp: true}}",
+                    port, PASSWORD
+                )
+            },
+        },
+        Case {
+            name: "trojan",
+            udp: true,
+            server: |port| {
+                let mut spec = xray_server(
+                    port,
+                    &format!(
+                        r#""protocol": "trojan", "settings": {{"clients": [{{"password": "{}"}}]}},
+                        "streamSettings": {{"security": "tls", "tlsSettings": {{"certificates":
+                        [{{"certificateFile": "{1}/cert.pem", "keyFile": "{1}/key.pem"}}]}}}}"#,
+                        PASSWORD, XRAY_DIR
+                    ),
+                )?;
+                spec.files.extend(self_signed()?);
+                Ok(spec)
+            },
+            outbound: |port| {
+                format!(
+                    "{{type: trojan, name: trojan, server: 127.0.0.1, port: {}, password: {}, \
+                     sni: localhost, skip-cert-verify: true, udp: true}}",
+                    port, PASSWORD
+                )
+            },
+        },
+        Case {
+            // UDP is carried over TCP, which sing-box relays the same way
+            name: "anytls",
+            udp: false,
+            server: anytls_server,
+            outbound: |port| {
+                format!(
+                    "{{type: anytls, name: anytls, server: 127.0.0.1, port: {}, password: {}, \
+                     sni: localhost, skip-cert-verify: true}}",
+                    port, PASSWORD
+                )
+            },
+        },
+    ]
+}
+
+/// a certificate for `localhost`, as `cert.pem` and `key.pem`
+fn self_signed() -> Result<Vec<(String, String)>, Error> {
+    let map_err = |e: rcgen::RcgenError| Error::Operation(format!("certificate error: {}", e));
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).map_err(map_err)?;
+    Ok(vec![
+        (
+            "cert.pem".to_owned(),
+            cert.serialize_pem().map_err(map_err)?,
+        ),
+        ("key.pem".to_owned(), cert.serialize_private_key_pem()),
+    ])
+}
+
+fn shadowsocks_server(port: u16) -> Result<ServerSpec, Error> {
+    Ok(ServerSpec {
+        image: SHADOWSOCKS_RUST_IMAGE.to_owned(),
+        entrypoint: Some("ssserver".to_owned()),
+        args: vec![
+            "-s".to_owned(),
+            format!("0.0.0.0:{}", port),
+            "-m".to_owned(),
+            "aes-256-gcm".to_owned(),
+            "-k".to_owned(),
+            PASSWORD.to_owned(),
+            // TCP and UDP
+            "-u".to_owned(),
+        ],
+        files: vec![],
+        config_dir: String::new(),
+        port,
+    })
+}
+
+/// an xray server with a single inbound, `inbound` being the JSON fields
+/// after `listen` and `port`
+fn xray_server(port: u16, inbound: &str) -> Result<ServerSpec, Error> {
+    let config = format!(
+        r#"{{
+  "log": {{"loglevel": "warning"}},
+  "inbounds": [{{"listen": "0.0.0.0", "port": {}, {}}}],
+  "outbounds": [{{"protocol": "freedom"}}]
+}}"#,
+        port, inbound
+    );
+    Ok(ServerSpec {
+        image: XRAY_IMAGE.to_owned(),
+        entrypoint: Some("/usr/bin/xray".to_owned()),
+        args: vec![
+            "run".to_owned(),
+            "-c".to_owned(),
+            format!("{}/config.json", XRAY_DIR),
+        ],
+        files: vec![("config.json".to_owned(), config)],
+        config_dir: XRAY_DIR.to_owned(),
+        port,
+    })
+}
+
+fn anytls_server(port: u16) -> Result<ServerSpec, Error> {
+    let config = format!(
+        r#"{{
+  "log": {{"level": "warn"}},
+  "inbounds": [{{
+    "type": "anytls", "listen": "0.0.0.0", "listen_port": {},
+    "users": [{{"password": "{}"}}],
+    "tls": {{"enabled": true, "certificate_path": "{2}/cert.pem", "key_path": "{2}/key.pem"}}
+  }}],
+  "outbounds": [{{"type": "direct"}}]
+}}"#,
+        port, PASSWORD, SING_BOX_DIR
+    );
+    let mut files = vec![("config.json".to_owned(), config)];
+    files.extend(self_signed()?);
+    Ok(ServerSpec {
+        image: SING_BOX_IMAGE.to_owned(),
+        entrypoint: Some("sing-box".to_owned()),
+        args: vec![
+            "run".to_owned(),
+            "-c".to_owned(),
+            format!("{}/config.json", SING_BOX_DIR),
+        ],
+        files,
+        config_dir: SING_BOX_DIR.to_owned(),
+        port,
+    })
+}
@@ -0,0 +1,117 @@
+//! A thin wrapper over the `docker` CLI, enough to keep a reference server
+//! running for the length of a case.
+
+use std::{io, path::PathBuf};
+
+use tokio::process::Command;
+use tracing::debug;
+
+use crate::common::errors::new_io_error;
+
+/// how containers reach the echo servers on the host
+pub const HOST_GATEWAY: &str = "host.docker.internal";
+
+/// A reference server, listening on one port for both TCP and UDP.
+pub struct ServerSpec {
+    pub image: String,
+    pub entrypoint: Option<String>,
+    pub args: Vec<String>,
+    /// `(file name, contents)`, written to a scratch directory that is
+    /// mounted read-only at `config_dir`
+    pub files: Vec<(String, String)>,
+    pub config_dir: String,
+    pub port: u16,
+}
+
+async fn docker(args: &[String]) -> io::Result<String> {
+    let output = Command::new("docker").args(args).output().await?;
+    if !output.status.success() {
+        return Err(new_io_error(&format!(
+            "docker {} failed: {}",
+            args.first().map(String::as_str).unwrap_or_default(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// A detached container, removed on drop.
+pub struct Container {
+    id: String,
+    dir: Option<PathBuf>,
+}
+
+impl Container {
+    /// pulls the image if needed and starts the server, which may still be
+    /// booting when this returns
+    pub async fn start(spec: ServerSpec) -> io::Result<Self> {
+        let mut args = vec![
+            "run".to_owned(),
+            "-d".to_owned(),
+            "--rm".to_owned(),
+            format!("--add-host={}:host-gateway", HOST_GATEWAY),
+            format!("--publish=127.0.0.1:{0}:{0}/tcp", spec.port),
+            format!("--publish=127.0.0.1:{0}:{0}/udp", spec.port),
+        ];
+
+        let dir = if spec.files.is_empty() {
+            None
+        } else {
+            let dir = std::env::temp_dir().join(format!("clash-conformance-{}", spec.port));
+            std::fs::create_dir_all(&dir)?;
+            for (name, contents) in spec.files.iter() {
+                std::fs::write(dir.join(name), contents)?;
+            }
+            args.push(format!(
+                "--volume={}:{}:ro",
+                dir.to_string_lossy(),
+                spec.config_dir
+            ));
+            Some(dir)
+        };
+
+        if let Some(entrypoint) = spec.entrypoint {
+            args.push(format!("--entrypoint={}", entrypoint));
+        }
+        args.push(spec.image);
+        args.extend(spec.args);
+
+        let id = docker(&args).await?;
+        debug!("started conformance container {}", id);
+        Ok(Self { id, dir })
+    }
+
+    /// the tail of the server log, to tell why a case failed
+    pub async fn logs(&self) -> String {
+        let args = ["logs", "--tail=20", &self.id].map(str::to_owned);
+        match Command::new("docker").args(args).output().await {
+            Ok(output) => format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            Err(e) => format!("failed to read container logs: {}", e),
+        }
+    }
+
+    /// removes the container, waiting for it to go
+    pub async fn stop(mut self) {
+        let id = std::mem::take(&mut self.id);
+        if let Err(e) = docker(&["rm".to_owned(), "-f".to_owned(), id]).await {
+            debug!("failed to remove conformance container: {}", e);
+        }
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        // not stopped, as the case was cancelled. The removal is left to run
+        // on its own rather than blocking the runtime until it's done
+        if !self.id.is_empty() {
+            let _ = Command::new("docker").args(["rm", "-f", &self.id]).spawn();
+        }
+        if let Some(dir) = self.dir.take() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
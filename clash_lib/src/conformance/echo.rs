@@ -0,0 +1,64 @@
+//! TCP and UDP echo servers on the host, the targets relayed to through the
+//! outbound under test.
+
+use std::io;
+
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    task::JoinHandle,
+};
+
+/// Echoes TCP and UDP on the same port of every interface, so containers
+/// can reach it through the host gateway.
+pub struct EchoServer {
+    pub port: u16,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for EchoServer {
+    fn drop(&mut self) {
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+    }
+}
+
+impl EchoServer {
+    pub async fn start() -> io::Result<Self> {
+        let (tcp, udp) = bind_pair().await?;
+        let port = tcp.local_addr()?.port();
+
+        let tcp = tokio::spawn(async move {
+            while let Ok((mut s, _)) = tcp.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = s.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        let udp = tokio::spawn(async move {
+            let mut buf = vec![0u8; 65535];
+            while let Ok((n, src)) = udp.recv_from(&mut buf).await {
+                let _ = udp.send_to(&buf[..n], src).await;
+            }
+        });
+
+        Ok(Self {
+            port,
+            tasks: vec![tcp, udp],
+        })
+    }
+}
+
+/// a TCP listener and a UDP socket on the same free port
+async fn bind_pair() -> io::Result<(TcpListener, UdpSocket)> {
+    let mut last_err = None;
+    for _ in 0..10 {
+        let tcp = TcpListener::bind("0.0.0.0:0").await?;
+        match UdpSocket::bind(tcp.local_addr()?).await {
+            Ok(udp) => return Ok((tcp, udp)),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::ErrorKind::AddrInUse.into()))
+}
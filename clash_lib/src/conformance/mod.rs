@@ -0,0 +1,287 @@
+//! Outbound protocol conformance: each case starts a reference server in
+//! docker, points the matching outbound handler at it and relays TCP, and
+//! UDP where the protocol has it, to an echo server on the host.
+//!
+//! Built with the `conformance` feature and needs a docker daemon that can
+//! pull the images. `clash --conformance` runs the built-in cases against
+//! the build at hand, as does `cargo test --features conformance --
+//! --ignored`.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{SinkExt, StreamExt};
+use serde_yaml::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    app::dns::{SystemResolver, ThreadSafeDNSResolver},
+    common::errors::{map_io_error, new_io_error},
+    config::internal::proxy::OutboundProxyProtocol,
+    proxy::{datagram::UdpPacket, AnyOutboundHandler},
+    session::{Network, Session, SocksAddr},
+    Error,
+};
+
+mod cases;
+mod docker;
+mod echo;
+
+pub use cases::builtin;
+pub use docker::ServerSpec;
+
+use docker::{Container, HOST_GATEWAY};
+use echo::EchoServer;
+
+/// how long a server gets to boot before the first check counts
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+const UDP_ATTEMPTS: usize = 3;
+const TCP_PAYLOAD_LEN: usize = 256 * 1024;
+const UDP_PAYLOAD_LEN: usize = 1200;
+
+/// A reference server and the outbound that should interoperate with it.
+pub struct Case {
+    pub name: &'static str,
+    /// whether UDP is checked too
+    pub udp: bool,
+    /// the server, listening on the given port
+    pub server: fn(u16) -> Result<ServerSpec, Error>,
+    /// the outbound as in `proxies`, pointed at `127.0.0.1` and the given
+    /// port
+    pub outbound: fn(u16) -> String,
+}
+
+pub struct Report {
+    pub name: &'static str,
+    pub tcp: Result<(), String>,
+    /// `None` if the case doesn't check UDP
+    pub udp: Option<Result<(), String>>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.tcp.is_ok() && self.udp.as_ref().map_or(true, Result::is_ok)
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = |r: &Result<(), String>| match r {
+            Ok(_) => "ok".to_owned(),
+            Err(e) => format!("FAILED: {}", e),
+        };
+        writeln!(f, "{}", self.name)?;
+        writeln!(f, "  tcp: {}", status(&self.tcp))?;
+        match self.udp.as_ref() {
+            Some(udp) => write!(f, "  udp: {}", status(udp)),
+            None => write!(f, "  udp: skipped"),
+        }
+    }
+}
+
+/// runs `cases` one after another on a runtime of its own, for callers
+/// outside of tokio
+pub fn run_all(cases: &[Case]) -> Result<Vec<Report>, Error> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    Ok(rt.block_on(async {
+        let mut reports = Vec::with_capacity(cases.len());
+        for case in cases {
+            reports.push(run(case).await);
+        }
+        reports
+    }))
+}
+
+pub async fn run(case: &Case) -> Report {
+    let setup = async {
+        let echo = EchoServer::start().await.map_err(|e| e.to_string())?;
+        let port = free_port().map_err(|e| e.to_string())?;
+        let spec = (case.server)(port).map_err(|e| e.to_string())?;
+        let handler = build_handler(&(case.outbound)(port)).map_err(|e| e.to_string())?;
+        let container = Container::start(spec).await.map_err(|e| e.to_string())?;
+        let resolver: ThreadSafeDNSResolver =
+            Arc::new(SystemResolver::new().map_err(|e| e.to_string())?);
+        Ok::<_, String>((echo, container, handler, resolver))
+    };
+    let (echo, container, handler, resolver) = match setup.await {
+        Ok(x) => x,
+        Err(e) => {
+            return Report {
+                name: case.name,
+                tcp: Err(e),
+                udp: case.udp.then(|| Err("not run".to_owned())),
+            }
+        }
+    };
+
+    // the first check also waits for the server to come up
+    let tcp = until_ready(|| check_tcp(&handler, resolver.clone(), echo.port)).await;
+    let udp = if case.udp && tcp.is_ok() {
+        Some(check_udp(&handler, resolver.clone(), echo.port).await)
+    } else {
+        case.udp.then(|| Err(new_io_error("not run")))
+    };
+
+    let logs = if tcp.is_err() || udp.as_ref().is_some_and(Result::is_err) {
+        container.logs().await
+    } else {
+        String::new()
+    };
+    let with_logs = |r: io::Result<()>| r.map_err(|e| format!("{}\n{}", e, logs.trim_end()));
+    container.stop().await;
+
+    Report {
+        name: case.name,
+        tcp: with_logs(tcp),
+        udp: udp.map(with_logs),
+    }
+}
+
+fn build_handler(outbound: &str) -> Result<AnyOutboundHandler, Error> {
+    let map: HashMap<String, Value> = serde_yaml::from_str(outbound)
+        .map_err(|e| Error::InvalidConfig(format!("invalid outbound {}: {}", outbound, e)))?;
+    Ok(match OutboundProxyProtocol::try_from(map)? {
+        OutboundProxyProtocol::Ss(s) => s.try_into()?,
+        OutboundProxyProtocol::Socks5(s) => s.try_into()?,
+        OutboundProxyProtocol::Trojan(s) => s.try_into()?,
+        OutboundProxyProtocol::Vmess(s) => s.try_into()?,
+        OutboundProxyProtocol::AnyTls(s) => s.try_into()?,
+        p => {
+            return Err(Error::InvalidConfig(format!(
+                "{} has no conformance check",
+                p
+            )))
+        }
+    })
+}
+
+/// a port nothing listens on right now, for the server to publish
+fn free_port() -> io::Result<u16> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+async fn until_ready<F, Fut>(check: F) -> io::Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = io::Result<()>>,
+{
+    let deadline = Instant::now() + READY_TIMEOUT;
+    loop {
+        match check().await {
+            Err(_) if Instant::now() < deadline => tokio::time::sleep(Duration::from_secs(1)).await,
+            rv => return rv,
+        }
+    }
+}
+
+fn random_payload(len: usize) -> Vec<u8> {
+    (0..len).map(|_| rand::random()).collect()
+}
+
+fn echo_session(network: Network, port: u16) -> Session {
+    Session {
+        network,
+        destination: SocksAddr::Domain(HOST_GATEWAY.to_owned(), port),
+        ..Default::default()
+    }
+}
+
+async fn check_tcp(
+    handler: &AnyOutboundHandler,
+    resolver: ThreadSafeDNSResolver,
+    port: u16,
+) -> io::Result<()> {
+    let sess = echo_session(Network::Tcp, port);
+    let payload = random_payload(TCP_PAYLOAD_LEN);
+
+    let relay = async {
+        let s = handler.connect_stream(&sess, resolver).await?;
+        let (mut r, mut w) = tokio::io::split(s);
+        let write = async {
+            w.write_all(&payload).await?;
+            w.flush().await
+        };
+        let read = async {
+            let mut buf = vec![0u8; payload.len()];
+            r.read_exact(&mut buf).await.map(|_| buf)
+        };
+        let (_, echoed) = tokio::try_join!(write, read)?;
+        Ok::<_, io::Error>(echoed)
+    };
+
+    let echoed = tokio::time::timeout(CHECK_TIMEOUT, relay)
+        .await
+        .map_err(map_io_error)??;
+    if echoed != payload {
+        return Err(new_io_error("TCP echo mismatched"));
+    }
+    Ok(())
+}
+
+async fn check_udp(
+    handler: &AnyOutboundHandler,
+    resolver: ThreadSafeDNSResolver,
+    port: u16,
+) -> io::Result<()> {
+    let sess = echo_session(Network::Udp, port);
+    let payload = random_payload(UDP_PAYLOAD_LEN);
+
+    let mut datagram =
+        tokio::time::timeout(CHECK_TIMEOUT, handler.connect_datagram(&sess, resolver))
+            .await
+            .map_err(map_io_error)??;
+
+    // packets may get lost, resend a few times before giving up
+    for _ in 0..UDP_ATTEMPTS {
+        datagram
+            .send(UdpPacket::new(
+                payload.clone(),
+                sess.source.into(),
+                sess.destination.clone(),
+            ))
+            .await?;
+        match tokio::time::timeout(CHECK_TIMEOUT / UDP_ATTEMPTS as u32, datagram.next()).await {
+            Ok(Some(pkt)) if pkt.data == payload => return Ok(()),
+            Ok(Some(_)) => return Err(new_io_error("UDP echo mismatched")),
+            Ok(None) => return Err(new_io_error("UDP relay closed")),
+            Err(_) => continue,
+        }
+    }
+    Err(new_io_error("no UDP echo"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{builtin, run};
+
+    #[tokio::test]
+    #[ignore = "needs a docker daemon"]
+    async fn test_builtin_cases() {
+        let mut failed = vec![];
+        for case in builtin().iter() {
+            let report = run(case).await;
+            println!("{}", report);
+            if !report.passed() {
+                failed.push(report.name);
+            }
+        }
+        assert!(failed.is_empty(), "failed: {:?}", failed);
+    }
+
+    #[test]
+    fn test_build_handlers() {
+        for case in builtin().iter() {
+            super::build_handler(&(case.outbound)(443)).unwrap();
+        }
+    }
+}
@@ -1,24 +1,36 @@
 #[macro_use]
 extern crate anyhow;
 
+use crate::app::device::DeviceTable;
 use crate::app::dispatcher::Dispatcher;
 use crate::app::dns;
+use crate::app::health::{HealthChecker, ThreadSafeHealthReport};
 use crate::app::inbound::manager::InboundManager;
 use crate::app::outbound::manager::OutboundManager;
-use crate::app::router::Router;
+use crate::app::remote_content_manager::healthcheck::HealthCheckLimiter;
+use crate::app::router::{Router, Script};
 use crate::config::def;
+use crate::config::internal::config::BindAddress;
+use crate::config::internal::diff::ConfigSummary;
+use crate::config::internal::listener::InboundOpts;
 use crate::config::internal::proxy::OutboundProxy;
+use crate::config::internal::rule::RuleType;
 use crate::config::internal::InternalConfig;
+use app::dispatcher::Sniffer;
 use app::dispatcher::StatisticsManager;
 use app::dns::SystemResolver;
 use app::profile;
 use common::auth;
+use common::geosite::GeoSite;
 use common::http::new_http_client;
 use common::mmdb;
+use common::system_proxy::SystemProxy;
 use config::def::LogLevel;
 use proxy::tun::get_tun_runner;
+use proxy::utils::Interface;
 use state::InitCell;
 use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use tokio::task::JoinHandle;
 use tracing::error;
@@ -34,6 +46,10 @@ mod config;
 mod proxy;
 mod session;
 
+#[cfg(feature = "conformance")]
+pub mod conformance;
+
+pub use app::dispatcher::{ConnectionInfo, ConnectionObserver};
 pub use config::def::Config as ClashConfigDef;
 pub use config::def::DNS as ClashDNSConfigDef;
 pub use config::DNSListen as ClashDNSListen;
@@ -64,6 +80,10 @@ pub struct Options {
     pub cwd: Option<String>,
     pub rt: Option<TokioRuntime>,
     pub log_file: Option<String>,
+    /// extra headers sent when fetching a `Config::Url`
+    pub config_headers: Vec<(String, String)>,
+    /// told about each connection and its traffic as it goes
+    pub observers: Vec<Arc<dyn ConnectionObserver>>,
 }
 
 pub enum TokioRuntime {
@@ -76,6 +96,9 @@ pub enum Config {
     Internal(InternalConfig),
     File(String),
     Str(String),
+    /// fetched over HTTP(S) with `Options::config_headers`
+    Url(String),
+    Stdin,
 }
 
 pub struct GlobalState {
@@ -83,6 +106,8 @@ pub struct GlobalState {
     inbound_listener_handle: Option<JoinHandle<Result<(), Error>>>,
     #[allow(dead_code)]
     dns_listener_handle: Option<JoinHandle<Result<(), Error>>>,
+    /// what a reload preview compares against
+    running_config: ConfigSummary,
 }
 
 pub struct RuntimeController {
@@ -129,6 +154,14 @@ async fn start_async(opts: Options) -> Result<(), Error> {
         Config::Internal(c) => c,
         Config::File(file) => TryInto::<def::Config>::try_into(PathBuf::from(file))?.try_into()?,
         Config::Str(s) => s.parse::<def::Config>()?.try_into()?,
+        Config::Url(url) => config::remote::fetch(&url, &opts.config_headers)
+            .await?
+            .parse::<def::Config>()?
+            .try_into()?,
+        Config::Stdin => config::remote::read_stdin()
+            .await?
+            .parse::<def::Config>()?
+            .try_into()?,
     };
 
     let cwd = opts.cwd.unwrap_or_else(|| ".".to_string());
@@ -155,24 +188,131 @@ async fn start_async(opts: Options) -> Result<(), Error> {
     let mut tasks = Vec::<Runner>::new();
     let mut runners = Vec::new();
 
+    let running_config = ConfigSummary::from(&config);
+    let auto_groups = config.referenced_auto_groups();
+
+    if let Some(mode) = config.general.nat64 {
+        common::nat64::init(mode).await;
+    }
+    proxy::utils::set_mptcp(config.general.mptcp);
+    common::offline::set(config.general.offline);
+
     let system_resolver =
         Arc::new(SystemResolver::new().map_err(|x| Error::DNSError(x.to_string()))?);
     let client = new_http_client(system_resolver).map_err(|x| Error::DNSError(x.to_string()))?;
-    let mmdb = Arc::new(
+    let mmdb = Arc::new(if config.general.geodata_mode {
+        mmdb::MMDB::from_geoip_dat(
+            cwd.join(&config.general.geoip),
+            config.general.geoip_download_url,
+        )
+        .await?
+    } else {
         mmdb::MMDB::new(
             cwd.join(&config.general.mmdb),
             config.general.mmdb_download_url,
             client,
         )
-        .await?,
-    );
+        .await?
+    });
+    // the codes missing from a geoip.dat are errors rather than rules that
+    // never match
+    for code in config
+        .rules
+        .iter()
+        .chain(config.sub_rules.values().flatten())
+        .flat_map(|x| match x {
+            RuleType::GeoIP { country_codes, .. } => country_codes.as_slice(),
+            RuleType::SubRule { rule, .. } => match rule.as_ref() {
+                RuleType::GeoIP { country_codes, .. } => country_codes.as_slice(),
+                _ => &[],
+            },
+            _ => &[],
+        })
+        .filter(|x| !x.eq_ignore_ascii_case(mmdb::LAN))
+    {
+        mmdb.check_code(code)
+            .map_err(|x| Error::InvalidConfig(format!("rule GEOIP,{}: {}", code, x)))?;
+    }
+    if config.dns.enable && config.dns.fallback_filter.geo_ip {
+        mmdb.check_code(&config.dns.fallback_filter.geo_ip_code)
+            .map_err(|x| Error::InvalidConfig(format!("fallback-filter: {}", x)))?;
+    }
 
     let cache_store = profile::ThreadSafeCacheFile::new(
         cwd.join("cache.db").as_path().to_str().unwrap(),
         config.profile.store_selected,
     );
 
-    let dns_resolver = dns::Resolver::new(&config.dns, cache_store.clone(), mmdb.clone()).await;
+    let (fallback_geosite, policy_geosite) = if config.dns.enable {
+        (
+            config.dns.fallback_filter.geosite.as_slice(),
+            config
+                .dns
+                .set_policy
+                .iter()
+                .filter_map(|(set, _)| match set {
+                    dns::PolicySet::GeoSite(code) => Some(code),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+        )
+    } else {
+        (&[][..], vec![])
+    };
+    let geosite_rules = config
+        .rules
+        .iter()
+        .chain(config.sub_rules.values().flatten())
+        .filter_map(|x| match x {
+            RuleType::GeoSite { country_code, .. } => Some(country_code),
+            RuleType::SubRule { rule, .. } => match rule.as_ref() {
+                RuleType::GeoSite { country_code, .. } => Some(country_code),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let geosite = if !fallback_geosite.is_empty()
+        || !geosite_rules.is_empty()
+        || !policy_geosite.is_empty()
+    {
+        let geosite = GeoSite::new(
+            cwd.join(&config.general.geosite),
+            config.general.geosite_download_url,
+        )
+        .await?;
+        for code in fallback_geosite {
+            if !geosite.contains(code.trim_start_matches('!')) {
+                return Err(Error::InvalidConfig(format!(
+                    "fallback-filter: geosite {} not found",
+                    code
+                )));
+            }
+        }
+        // only the categories of the rules are decoded, here rather than
+        // by the router so that a missing one is an error
+        for code in geosite_rules {
+            geosite
+                .matcher(code)
+                .map_err(|x| Error::InvalidConfig(format!("rule GEOSITE,{}: {}", code, x)))?;
+        }
+        for code in policy_geosite {
+            geosite.matcher(code).map_err(|x| {
+                Error::InvalidConfig(format!("nameserver-policy geosite:{}: {}", code, x))
+            })?;
+        }
+        Some(Arc::new(geosite))
+    } else {
+        None
+    };
+
+    let dns_resolver = dns::Resolver::new(
+        &config.dns,
+        cache_store.clone(),
+        mmdb.clone(),
+        geosite.clone(),
+    )
+    .await;
 
     let outbound_manager = Arc::new(
         OutboundManager::new(
@@ -193,34 +333,105 @@ async fn start_async(opts: Options) -> Result<(), Error> {
                 })
                 .collect(),
             config.proxy_providers,
+            auto_groups,
             config.proxy_names,
             dns_resolver.clone(),
             cache_store.clone(),
+            HealthCheckLimiter::from_opts(
+                config.general.health_check.max_concurrent,
+                config.general.health_check.spacing,
+            ),
             cwd.to_string_lossy().to_string(),
         )
         .await?,
     );
+    dns_resolver.set_outbound_manager(&outbound_manager);
 
     let router = Arc::new(
         Router::new(
             config.rules,
+            config.sub_rules,
             config.rule_providers,
             dns_resolver.clone(),
             mmdb,
+            geosite,
+            config.script.map(Script::new).transpose()?.map(Arc::new),
             cwd.to_string_lossy().to_string(),
         )
-        .await,
+        .await?,
     );
 
-    let statistics_manager = StatisticsManager::new();
+    dns_resolver.set_rule_providers(router.get_rule_providers())?;
+    let dns_blocker = dns::DnsBlocker::new(&config.dns, &router)?;
 
-    let dispatcher = Arc::new(Dispatcher::new(
+    let devices = config.devices.map(|cfg| {
+        let devices = Arc::new(DeviceTable::new(cfg, cwd.to_string_lossy().as_ref()));
+        devices.kick_off();
+        devices
+    });
+
+    let statistics_manager = StatisticsManager::new(opts.observers);
+    statistics_manager.set_user_quotas(config.user_quotas);
+    dns_resolver.set_dns_stats(statistics_manager.dns().clone());
+
+    let dispatcher = Dispatcher::new(
         outbound_manager.clone(),
         router.clone(),
         dns_resolver.clone(),
         config.general.mode,
+        devices,
         statistics_manager.clone(),
-    ));
+    )
+    .with_sniffer(match config.sniffer {
+        Some(cfg) if cfg.enable => Some(Arc::new(Sniffer::new(cfg)?)),
+        _ => None,
+    })
+    .with_dns_hijack(
+        config.tun.dns_hijack.clone(),
+        config
+            .general
+            .inbound
+            .listeners
+            .iter()
+            .filter_map(|x| match x {
+                InboundOpts::Tun(tun) => Some((tun.name.clone(), tun.dns_hijack.clone())),
+                _ => None,
+            })
+            .collect(),
+    )
+    .with_dns_blocker(dns_blocker.clone());
+    #[cfg(feature = "mitm")]
+    let dispatcher = dispatcher.with_mitm(match config.mitm {
+        Some(cfg) if cfg.enable => Some(Arc::new(app::mitm::Mitm::new(
+            cfg,
+            cwd.to_string_lossy().as_ref(),
+            router.get_rule_providers(),
+        )?)),
+        _ => None,
+    });
+    #[cfg(not(feature = "mitm"))]
+    {
+        if config.mitm.is_some_and(|x| x.enable) {
+            tracing::warn!("mitm is enabled but clash was built without the `mitm` feature");
+        }
+    }
+    let dispatcher = Arc::new(dispatcher);
+
+    // the mixed port where local clients reach it
+    let system_proxy_addr = match config.general.inbound.mixed_port {
+        Some(port) if config.general.system_proxy => {
+            let ip = match &config.general.inbound.bind_address {
+                BindAddress::One(Interface::IpAddr(ip)) if !ip.is_unspecified() => *ip,
+                _ => IpAddr::from([127, 0, 0, 1]),
+            };
+            Some(SocketAddr::new(ip, port))
+        }
+        None if config.general.system_proxy => {
+            tracing::warn!("system-proxy needs mixed-port, leaving the system proxy alone");
+            None
+        }
+        _ => None,
+    };
 
     let authenticator = Arc::new(auth::PlainAuthenticator::new(config.users));
 
@@ -232,20 +443,69 @@ async fn start_async(opts: Options) -> Result<(), Error> {
 
     let inbound_runner = inbound_manager.lock().await.get_runner()?;
     let inbound_listener_handle = tokio::spawn(inbound_runner);
+    // restored when clash exits
+    let system_proxy = system_proxy_addr.and_then(|addr| {
+        SystemProxy::enable(addr)
+            .map_err(|e| tracing::warn!("failed to set system proxy: {}", e))
+            .ok()
+    });
+    runners.extend(
+        inbound_manager
+            .lock()
+            .await
+            .get_tun_runners(dns_resolver.clone())?,
+    );
 
-    let tun_runner = get_tun_runner(config.tun, dispatcher.clone(), dns_resolver.clone())?;
+    // the devices opened by fd are set up by whoever passed them in
+    let tun_devices = inbound_manager
+        .lock()
+        .await
+        .get_listeners()
+        .iter()
+        .filter_map(|x| match x {
+            InboundOpts::Tun(tun) => Some(tun.device_id.clone()),
+            _ => None,
+        })
+        .chain(config.tun.enable.then(|| config.tun.device_id.clone()))
+        .filter_map(|x| x.strip_prefix("dev://").map(str::to_owned))
+        .collect();
+
+    let tun_runner = get_tun_runner(
+        config.tun,
+        "Tun".to_owned(),
+        dispatcher.clone(),
+        dns_resolver.clone(),
+    )?;
     if let Some(tun_runner) = tun_runner {
         runners.push(tun_runner);
     }
 
-    let dns_listener_handle = dns::get_dns_listener(config.dns, dns_resolver.clone())
+    let dns_listener_handle = dns::get_dns_listener(config.dns, dns_resolver.clone(), dns_blocker)
         .await
         .map(|l| tokio::spawn(l));
 
+    let health_report = ThreadSafeHealthReport::default();
+    tokio::spawn(
+        HealthChecker {
+            inbound_manager: inbound_manager.clone(),
+            dns_resolver: dns_resolver.clone(),
+            outbound_manager: outbound_manager.clone(),
+            router: router.clone(),
+            mmdb: cwd.join(if config.general.geodata_mode {
+                &config.general.geoip
+            } else {
+                &config.general.mmdb
+            }),
+            tun_devices,
+        }
+        .run(health_report.clone()),
+    );
+
     let global_state = Arc::new(Mutex::new(GlobalState {
         log_level: config.general.log_level,
         inbound_listener_handle: Some(inbound_listener_handle),
         dns_listener_handle,
+        running_config,
     }));
 
     let api_runner = app::api::get_api_runner(
@@ -254,11 +514,12 @@ async fn start_async(opts: Options) -> Result<(), Error> {
         inbound_manager,
         dispatcher,
         global_state,
-        dns_resolver,
+        dns_resolver.clone(),
         outbound_manager,
         statistics_manager,
         cache_store,
         router,
+        health_report,
         cwd.to_string_lossy().to_string(),
     );
     if let Some(r) = api_runner {
@@ -276,16 +537,53 @@ async fn start_async(opts: Options) -> Result<(), Error> {
     }));
 
     tasks.push(Box::pin(async move {
-        let _ = tokio::signal::ctrl_c().await;
+        if let Err(e) = shutdown_signal().await {
+            error!("failed to listen for shutdown signals: {}", e);
+            std::future::pending::<()>().await;
+        }
+        info!("receive shutdown signal");
         Ok(())
     }));
 
-    futures::future::select_all(tasks).await.0.map_err(|x| {
+    let rv = futures::future::select_all(tasks).await.0;
+    // before anything slower, windows only gives a closed console a few
+    // seconds
+    if let Some(system_proxy) = system_proxy {
+        system_proxy.restore();
+    }
+    dns_resolver.save_cache().await;
+    rv.map_err(|x| {
         error!("runtime error: {}, shutting down", x);
         x
     })
 }
 
+/// ctrl-c, or SIGTERM from a service manager or `kill`
+#[cfg(unix)]
+async fn shutdown_signal() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        rv = tokio::signal::ctrl_c() => rv,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+/// ctrl-c, the console being closed or the system shutting down
+#[cfg(windows)]
+async fn shutdown_signal() -> io::Result<()> {
+    use tokio::signal::windows::{ctrl_close, ctrl_shutdown};
+
+    let mut close = ctrl_close()?;
+    let mut shutdown = ctrl_shutdown()?;
+    tokio::select! {
+        rv = tokio::signal::ctrl_c() => rv,
+        _ = close.recv() => Ok(()),
+        _ = shutdown.recv() => Ok(()),
+    }
+}
+
 #[cfg(test)]
 #[ctor::ctor]
 fn setup_tests() {
@@ -313,6 +611,8 @@ mod tests {
                 cwd: None,
                 rt: None,
                 log_file: None,
+                config_headers: vec![],
+                observers: vec![],
             })
             .unwrap()
         });
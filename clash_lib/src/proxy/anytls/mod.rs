@@ -0,0 +1,254 @@
+//! AnyTLS, a TLS based protocol padding its first packets to hide the
+//! TLS in TLS pattern. Streams reuse idle sessions to save handshakes,
+//! those of connections this outbound dialed itself, as a session over a
+//! stream from another outbound is tied to where that stream goes.
+//! Compatible with sing-box/mihomo AnyTLS servers.
+
+use std::{
+    io,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::TryFutureExt;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::{
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedStream, ChainedStreamWrapper,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    session::{Session, SocksAddr},
+};
+
+use self::{
+    padding::PaddingScheme,
+    session::{Session as AnyTlsSession, SharedPaddingScheme},
+};
+
+use super::{
+    transport::{self, TLSOptions, TlsFragment},
+    utils::DialerProxy,
+    AnyOutboundHandler, AnyStream, CommonOption, OutboundHandler, OutboundType,
+};
+
+mod padding;
+mod session;
+
+pub struct Opts {
+    pub name: String,
+    pub common_opts: CommonOption,
+    pub server: String,
+    pub port: u16,
+    pub password: String,
+    pub sni: String,
+    pub alpn: Option<Vec<String>>,
+    pub skip_cert_verify: bool,
+    pub tls_fragment: Option<TlsFragment>,
+    pub udp: bool,
+    /// how long an idle session is kept for reuse
+    pub idle_session_timeout: Duration,
+}
+
+/// sessions without a stream, the most recently used last
+type IdleSessions = Arc<Mutex<Vec<(Instant, Arc<AnyTlsSession>)>>>;
+
+pub struct Handler {
+    opts: Opts,
+    padding: SharedPaddingScheme,
+    idle: IdleSessions,
+}
+
+impl Handler {
+    pub fn new(opts: Opts) -> AnyOutboundHandler {
+        Arc::new(Self {
+            opts,
+            padding: Arc::new(RwLock::new(Arc::new(PaddingScheme::default()))),
+            idle: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    fn take_idle_session(&self) -> Option<Arc<AnyTlsSession>> {
+        let mut idle = self.idle.lock().unwrap();
+        let timeout = self.opts.idle_session_timeout;
+        idle.retain(|(since, s)| since.elapsed() < timeout && !s.is_closed());
+        idle.pop().map(|(_, s)| s)
+    }
+
+    async fn new_session(&self, s: AnyStream) -> io::Result<Arc<AnyTlsSession>> {
+        let s = transport::tls::wrap_stream(
+            s,
+            TLSOptions {
+                skip_cert_verify: self.opts.skip_cert_verify,
+                sni: self.opts.sni.clone(),
+                alpn: self.opts.alpn.clone(),
+                fragment: self.opts.tls_fragment,
+            },
+        )
+        .await?;
+        AnyTlsSession::new(s, &self.opts.password, self.padding.clone()).await
+    }
+
+    /// opens a stream on `session`, kept for reuse once the stream is done
+    /// if `reuse`
+    async fn open_stream(
+        &self,
+        session: Arc<AnyTlsSession>,
+        sess: &Session,
+        reuse: bool,
+    ) -> io::Result<AnyStream> {
+        if !reuse {
+            return session.open_stream(&sess.destination, |_| {}).await;
+        }
+
+        let idle = Arc::downgrade(&self.idle);
+        let timeout = self.opts.idle_session_timeout;
+        session
+            .open_stream(&sess.destination, move |s| {
+                if let Some(idle) = idle.upgrade() {
+                    keep_idle(&idle, s, timeout);
+                }
+            })
+            .await
+    }
+}
+
+/// keeps `session` for reuse, until it has been idle for `timeout` and is
+/// dropped, closing its connection
+fn keep_idle(idle: &IdleSessions, session: Arc<AnyTlsSession>, timeout: Duration) {
+    idle.lock().unwrap().push((Instant::now(), session));
+
+    let idle = Arc::downgrade(idle);
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        if let Some(idle) = idle.upgrade() {
+            idle.lock()
+                .unwrap()
+                .retain(|(since, s)| since.elapsed() < timeout && !s.is_closed());
+        }
+    });
+}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        &self.opts.name
+    }
+
+    fn proto(&self) -> OutboundType {
+        OutboundType::AnyTls
+    }
+
+    fn dialer_proxy(&self) -> Option<&DialerProxy> {
+        self.opts.common_opts.dialer_proxy()
+    }
+
+    async fn remote_addr(&self) -> Option<SocksAddr> {
+        Some(SocksAddr::Domain(self.opts.server.clone(), self.opts.port))
+    }
+
+    async fn support_udp(&self) -> bool {
+        self.opts.udp
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        let session = match self.take_idle_session() {
+            Some(s) => {
+                debug!("{} reusing an idle session for {}", self.name(), sess);
+                s
+            }
+            None => {
+                let s = self
+                    .opts
+                    .common_opts
+                    .connect_stream(resolver, self.opts.server.as_str(), self.opts.port)
+                    .map_err(|x| {
+                        io::Error::new(
+                            io::ErrorKind::Other,
+                            format!(
+                                "dial outbound {}:{}: {}",
+                                self.opts.server, self.opts.port, x
+                            ),
+                        )
+                    })
+                    .await?;
+                self.new_session(s).await?
+            }
+        };
+
+        let s = self.open_stream(session, sess, true).await?;
+        let chained = ChainedStreamWrapper::new(s);
+        chained.append_to_chain(self.name()).await;
+        Ok(Box::new(chained))
+    }
+
+    async fn proxy_stream(
+        &self,
+        s: AnyStream,
+        sess: &Session,
+        _: ThreadSafeDNSResolver,
+    ) -> io::Result<AnyStream> {
+        let session = self.new_session(s).await?;
+        self.open_stream(session, sess, false).await
+    }
+
+    async fn connect_datagram(
+        &self,
+        _sess: &Session,
+        _resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        // UDP is carried as UDP-over-TCP v2, see the converter
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "anytls UDP must be carried over TCP",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex, RwLock},
+        time::Duration,
+    };
+
+    use tokio::io::AsyncReadExt;
+
+    use crate::proxy::AnyStream;
+
+    use super::{keep_idle, padding::PaddingScheme, session::Session};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_session_expires() {
+        let (client, mut server) = tokio::io::duplex(1 << 16);
+        let session = Session::new(
+            Box::new(client) as AnyStream,
+            "password",
+            Arc::new(RwLock::new(Arc::new(PaddingScheme::default()))),
+        )
+        .await
+        .unwrap();
+
+        let idle = Arc::new(Mutex::new(Vec::new()));
+        keep_idle(&idle, session, Duration::from_secs(30));
+        assert_eq!(idle.lock().unwrap().len(), 1);
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(idle.lock().unwrap().len(), 1);
+        tokio::time::sleep(Duration::from_secs(21)).await;
+        assert!(idle.lock().unwrap().is_empty());
+
+        // dropped, and with it the connection
+        let mut rest = vec![];
+        server.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest.len(), 32 + 2 + 30);
+    }
+}
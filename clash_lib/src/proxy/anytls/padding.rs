@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use crate::common::utils;
+
+/// the scheme a session starts with until the server sends another one
+pub const DEFAULT_PADDING_SCHEME: &str = "stop=8
+0=30-30
+1=100-400
+2=400-500,c,500-1000,c,500-1000,c,500-1000,c,500-1000
+3=9-9,500-1000
+4=500-1000
+5=500-1000
+6=500-1000
+7=500-1000";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Size {
+    Range(usize, usize),
+    /// stop padding the packet if there's no payload left
+    Check,
+}
+
+/// how the first `stop` packets of a session are split and padded.
+/// Packet `n` is written as records of the sizes listed for `n`.
+#[derive(Debug)]
+pub struct PaddingScheme {
+    raw: String,
+    stop: u32,
+    packets: HashMap<u32, Vec<Size>>,
+}
+
+impl PaddingScheme {
+    pub fn parse(raw: &str) -> Option<Self> {
+        let mut stop = None;
+        let mut packets = HashMap::new();
+
+        for line in raw.lines().map(str::trim).filter(|x| !x.is_empty()) {
+            let (k, v) = line.split_once('=')?;
+            if k == "stop" {
+                stop = Some(v.parse().ok()?);
+                continue;
+            }
+
+            let sizes = v
+                .split(',')
+                .map(|x| match x.trim() {
+                    "c" => Some(Size::Check),
+                    x => {
+                        let (min, max) = x.split_once('-')?;
+                        let (min, max) = (min.parse().ok()?, max.parse().ok()?);
+                        Some(Size::Range(
+                            std::cmp::min(min, max),
+                            std::cmp::max(min, max),
+                        ))
+                    }
+                })
+                .collect::<Option<Vec<_>>>()?;
+            packets.insert(k.parse().ok()?, sizes);
+        }
+
+        Some(Self {
+            raw: raw.to_owned(),
+            stop: stop?,
+            packets,
+        })
+    }
+
+    /// sent to the server, which pushes its scheme if it differs
+    pub fn md5(&self) -> String {
+        utils::encode_hex(&utils::md5(self.raw.as_bytes()))
+    }
+
+    pub fn stop(&self) -> u32 {
+        self.stop
+    }
+
+    /// the record sizes of packet `pkt`, `None` being a check mark
+    pub fn record_sizes(&self, pkt: u32) -> Vec<Option<usize>> {
+        self.packets
+            .get(&pkt)
+            .map(|sizes| {
+                sizes
+                    .iter()
+                    .map(|x| match x {
+                        Size::Range(min, max) if min == max => Some(*min),
+                        Size::Range(min, max) => Some(utils::rand_range(*min..=*max)),
+                        Size::Check => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Default for PaddingScheme {
+    fn default() -> Self {
+        Self::parse(DEFAULT_PADDING_SCHEME).expect("default padding scheme")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PaddingScheme;
+
+    #[test]
+    fn test_parse_padding_scheme() {
+        let scheme = PaddingScheme::default();
+        assert_eq!(scheme.stop(), 8);
+        assert_eq!(scheme.record_sizes(0), vec![Some(30)]);
+
+        let sizes = scheme.record_sizes(2);
+        assert_eq!(sizes.len(), 9);
+        assert!(sizes[1].is_none());
+        assert!(sizes[2].is_some_and(|x| (500..=1000).contains(&x)));
+        assert!(scheme.record_sizes(8).is_empty());
+
+        assert!(PaddingScheme::parse("0=1-2").is_none());
+        assert!(PaddingScheme::parse("stop=2\n0=a-b").is_none());
+    }
+}
@@ -0,0 +1,460 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, RwLock, Weak,
+    },
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    common::{errors::new_io_error, utils},
+    proxy::AnyStream,
+    session::SocksAddr,
+};
+
+use super::padding::PaddingScheme;
+
+const CMD_WASTE: u8 = 0;
+const CMD_SYN: u8 = 1;
+const CMD_PSH: u8 = 2;
+const CMD_FIN: u8 = 3;
+const CMD_SETTINGS: u8 = 4;
+const CMD_ALERT: u8 = 5;
+const CMD_UPDATE_PADDING_SCHEME: u8 = 6;
+const CMD_SYNACK: u8 = 7;
+const CMD_HEART_REQUEST: u8 = 8;
+const CMD_HEART_RESPONSE: u8 = 9;
+const CMD_SERVER_SETTINGS: u8 = 10;
+
+/// cmd(1) + stream id(4) + data length(2)
+const HEADER_SIZE: usize = 7;
+
+/// the most data a single frame carries
+const MAX_FRAME_DATA: usize = 16 * 1024;
+
+/// the padding scheme in use, shared by all sessions of an outbound
+/// as the server may replace it
+pub type SharedPaddingScheme = Arc<RwLock<Arc<PaddingScheme>>>;
+
+fn put_frame(buf: &mut BytesMut, cmd: u8, sid: u32, data: &[u8]) {
+    buf.reserve(HEADER_SIZE + data.len());
+    buf.put_u8(cmd);
+    buf.put_u32(sid);
+    buf.put_u16(data.len() as u16);
+    buf.put_slice(data);
+}
+
+struct SessionWriter {
+    inner: WriteHalf<AnyStream>,
+    padding: Arc<PaddingScheme>,
+    /// packets written so far, the auth request being packet 0
+    pkt: u32,
+    sent_settings: bool,
+}
+
+impl SessionWriter {
+    /// writes `b` as one packet, split and padded as the scheme says. Each
+    /// record is as long on the wire as its size, headers included
+    async fn write_packet(&mut self, mut b: BytesMut) -> io::Result<()> {
+        if self.pkt >= self.padding.stop() {
+            return self.inner.write_all(&b).await;
+        }
+        self.pkt += 1;
+        if self.pkt >= self.padding.stop() {
+            return self.inner.write_all(&b).await;
+        }
+
+        for size in self.padding.record_sizes(self.pkt) {
+            let remaining = b.len();
+            let size = match size {
+                Some(size) => size,
+                None if remaining == 0 => break,
+                None => continue,
+            };
+
+            if remaining > size {
+                // all payload
+                let record = b.split_to(size);
+                self.inner.write_all(&record).await?;
+            } else if remaining > 0 {
+                // the last of the payload, padded up to the size
+                if size > remaining + HEADER_SIZE {
+                    let padding = vec![0u8; size - remaining - HEADER_SIZE];
+                    put_frame(&mut b, CMD_WASTE, 0, &padding);
+                }
+                self.inner.write_all(&b).await?;
+                b.clear();
+            } else {
+                // all padding
+                let mut record = BytesMut::new();
+                put_frame(
+                    &mut record,
+                    CMD_WASTE,
+                    0,
+                    &vec![0u8; size.saturating_sub(HEADER_SIZE)],
+                );
+                self.inner.write_all(&record).await?;
+            }
+        }
+
+        if !b.is_empty() {
+            self.inner.write_all(&b).await?;
+        }
+        Ok(())
+    }
+}
+
+/// an authenticated connection to the server carrying streams
+pub struct Session {
+    writer: Mutex<SessionWriter>,
+    streams: std::sync::Mutex<HashMap<u32, mpsc::Sender<Bytes>>>,
+    next_stream_id: AtomicU32,
+    closed: AtomicBool,
+    reader: std::sync::Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(reader) = self.reader.lock().unwrap().take() {
+            reader.abort();
+        }
+    }
+}
+
+impl Session {
+    /// authenticates on a connected TLS stream
+    pub async fn new(
+        mut stream: AnyStream,
+        password: &str,
+        padding: SharedPaddingScheme,
+    ) -> io::Result<Arc<Self>> {
+        let scheme = padding.read().unwrap().clone();
+
+        let padding0 = scheme
+            .record_sizes(0)
+            .first()
+            .copied()
+            .flatten()
+            .unwrap_or_default();
+        let mut auth = BytesMut::with_capacity(32 + 2 + padding0);
+        auth.put_slice(&utils::sha256(password.as_bytes()));
+        auth.put_u16(padding0 as u16);
+        auth.put_bytes(0, padding0);
+        stream.write_all(&auth).await?;
+
+        let (r, w) = tokio::io::split(stream);
+        let session = Arc::new(Self {
+            writer: Mutex::new(SessionWriter {
+                inner: w,
+                padding: scheme,
+                pkt: 0,
+                sent_settings: false,
+            }),
+            streams: std::sync::Mutex::new(HashMap::new()),
+            next_stream_id: AtomicU32::new(1),
+            closed: AtomicBool::new(false),
+            reader: std::sync::Mutex::new(None),
+        });
+
+        let reader = tokio::spawn(Self::read_loop(Arc::downgrade(&session), r, padding));
+        *session.reader.lock().unwrap() = Some(reader);
+
+        Ok(session)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        // dropping the senders ends the streams
+        self.streams.lock().unwrap().clear();
+    }
+
+    async fn write_packet(&self, b: BytesMut) -> io::Result<()> {
+        let rv = self.writer.lock().await.write_packet(b).await;
+        if rv.is_err() {
+            self.close();
+        }
+        rv
+    }
+
+    async fn read_loop(
+        session: Weak<Session>,
+        mut r: ReadHalf<AnyStream>,
+        padding: SharedPaddingScheme,
+    ) {
+        let rv: io::Result<()> = async {
+            let mut header = [0u8; HEADER_SIZE];
+            loop {
+                r.read_exact(&mut header).await?;
+                let cmd = header[0];
+                let sid = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+                let len = u16::from_be_bytes([header[5], header[6]]) as usize;
+                let mut data = vec![0u8; len];
+                r.read_exact(&mut data).await?;
+
+                let session = match session.upgrade() {
+                    Some(s) => s,
+                    None => return Ok(()),
+                };
+
+                match cmd {
+                    CMD_PSH => {
+                        let tx = session.streams.lock().unwrap().get(&sid).cloned();
+                        if let Some(tx) = tx {
+                            if tx.send(data.into()).await.is_err() {
+                                session.streams.lock().unwrap().remove(&sid);
+                            }
+                        }
+                    }
+                    CMD_FIN => {
+                        session.streams.lock().unwrap().remove(&sid);
+                    }
+                    CMD_SYNACK if !data.is_empty() => {
+                        warn!(
+                            "anytls stream {} refused: {}",
+                            sid,
+                            String::from_utf8_lossy(&data)
+                        );
+                        session.streams.lock().unwrap().remove(&sid);
+                    }
+                    CMD_UPDATE_PADDING_SCHEME => {
+                        match PaddingScheme::parse(&String::from_utf8_lossy(&data)) {
+                            Some(scheme) => {
+                                debug!("anytls padding scheme updated by server");
+                                *padding.write().unwrap() = Arc::new(scheme);
+                            }
+                            None => warn!("invalid anytls padding scheme from server"),
+                        }
+                    }
+                    CMD_HEART_REQUEST => {
+                        let mut b = BytesMut::new();
+                        put_frame(&mut b, CMD_HEART_RESPONSE, sid, &[]);
+                        session.write_packet(b).await?;
+                    }
+                    CMD_ALERT => {
+                        return Err(new_io_error(
+                            format!("anytls alert: {}", String::from_utf8_lossy(&data)).as_str(),
+                        ));
+                    }
+                    CMD_WASTE | CMD_SYNACK | CMD_SETTINGS | CMD_SERVER_SETTINGS
+                    | CMD_HEART_RESPONSE => {}
+                    cmd => debug!("unknown anytls command {}", cmd),
+                }
+            }
+        }
+        .await;
+
+        if let Err(e) = rv {
+            debug!("anytls session closed: {}", e);
+        }
+        if let Some(session) = session.upgrade() {
+            session.close();
+        }
+    }
+
+    /// opens a stream to `dst`. `on_finish` runs once the stream is closed
+    /// both ways, with the session still usable.
+    pub async fn open_stream<F>(
+        self: &Arc<Self>,
+        dst: &SocksAddr,
+        on_finish: F,
+    ) -> io::Result<AnyStream>
+    where
+        F: FnOnce(Arc<Session>) + Send + 'static,
+    {
+        let sid = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, mut rx) = mpsc::channel::<Bytes>(32);
+        self.streams.lock().unwrap().insert(sid, tx);
+
+        let mut b = BytesMut::new();
+        {
+            let mut writer = self.writer.lock().await;
+            if !writer.sent_settings {
+                let settings = format!(
+                    "v=2\nclient=clash-rs/{}\npadding-md5={}",
+                    env!("CARGO_PKG_VERSION"),
+                    writer.padding.md5()
+                );
+                put_frame(&mut b, CMD_SETTINGS, 0, settings.as_bytes());
+                writer.sent_settings = true;
+            }
+        }
+        put_frame(&mut b, CMD_SYN, sid, &[]);
+        let mut addr = BytesMut::new();
+        dst.write_buf(&mut addr);
+        put_frame(&mut b, CMD_PSH, sid, &addr);
+        self.write_packet(b).await?;
+
+        let (local, remote) = tokio::io::duplex(MAX_FRAME_DATA * 4);
+        let (mut rr, mut rw) = tokio::io::split(remote);
+        let session = self.clone();
+
+        tokio::spawn(async move {
+            let upload = async {
+                let mut buf = vec![0u8; MAX_FRAME_DATA];
+                loop {
+                    let n = match rr.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => n,
+                    };
+                    let mut b = BytesMut::new();
+                    put_frame(&mut b, CMD_PSH, sid, &buf[..n]);
+                    session.write_packet(b).await?;
+                }
+                let mut b = BytesMut::new();
+                put_frame(&mut b, CMD_FIN, sid, &[]);
+                session.write_packet(b).await
+            };
+            let download = async {
+                while let Some(data) = rx.recv().await {
+                    if rw.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+                let _ = rw.shutdown().await;
+                // not interested in the rest
+                drop(rx);
+            };
+
+            let (up, _) = tokio::join!(upload, download);
+            session.streams.lock().unwrap().remove(&sid);
+            if up.is_ok() && !session.is_closed() {
+                on_finish(session);
+            }
+        });
+
+        Ok(Box::new(local))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::oneshot,
+    };
+
+    use crate::{
+        common::utils,
+        proxy::{anytls::padding::PaddingScheme, AnyStream},
+        session::SocksAddr,
+    };
+
+    use super::{Session, CMD_FIN, CMD_PSH, CMD_SETTINGS, CMD_SYN, CMD_WASTE, HEADER_SIZE};
+
+    /// the frames `b` holds, each as its command, stream id and data
+    fn frames(mut b: &[u8]) -> Vec<(u8, u32, Vec<u8>)> {
+        let mut rv = vec![];
+        while !b.is_empty() {
+            let sid = u32::from_be_bytes([b[1], b[2], b[3], b[4]]);
+            let len = u16::from_be_bytes([b[5], b[6]]) as usize;
+            rv.push((b[0], sid, b[HEADER_SIZE..HEADER_SIZE + len].to_vec()));
+            b = &b[HEADER_SIZE + len..];
+        }
+        rv
+    }
+
+    async fn read_frame(s: &mut (impl AsyncReadExt + Unpin)) -> (u8, u32, Vec<u8>) {
+        let mut header = [0u8; HEADER_SIZE];
+        s.read_exact(&mut header).await.unwrap();
+        let mut data = vec![0u8; u16::from_be_bytes([header[5], header[6]]) as usize];
+        s.read_exact(&mut data).await.unwrap();
+        let sid = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        (header[0], sid, data)
+    }
+
+    #[tokio::test]
+    async fn test_session_framing() {
+        let (client, mut server) = tokio::io::duplex(1 << 16);
+        let scheme = PaddingScheme::parse("stop=2\n0=10-10\n1=200-200,50-50").unwrap();
+        let session = Session::new(
+            Box::new(client) as AnyStream,
+            "password",
+            Arc::new(RwLock::new(Arc::new(scheme))),
+        )
+        .await
+        .unwrap();
+
+        // the password hash, then as much padding as packet 0 says
+        let mut auth = [0u8; 32 + 2 + 10];
+        server.read_exact(&mut auth).await.unwrap();
+        assert_eq!(&auth[..32], utils::sha256(b"password").as_slice());
+        assert_eq!(&auth[32..34], &10u16.to_be_bytes());
+
+        let (done_tx, done_rx) = oneshot::channel();
+        let dst = SocksAddr::Ip("1.2.3.4:443".parse().unwrap());
+        let mut stream = session
+            .open_stream(&dst, move |_| {
+                let _ = done_tx.send(());
+            })
+            .await
+            .unwrap();
+
+        // packet 1, padded up to the first record and followed by a record
+        // of padding alone
+        let mut pkt = [0u8; 250];
+        server.read_exact(&mut pkt).await.unwrap();
+        let pkt = frames(&pkt);
+        let cmds = pkt.iter().map(|x| (x.0, x.1)).collect::<Vec<_>>();
+        assert_eq!(
+            cmds,
+            [
+                (CMD_SETTINGS, 0),
+                (CMD_SYN, 1),
+                (CMD_PSH, 1),
+                (CMD_WASTE, 0),
+                (CMD_WASTE, 0)
+            ]
+        );
+        assert!(String::from_utf8_lossy(&pkt[0].2).starts_with("v=2\n"));
+        let mut addr = bytes::BytesMut::new();
+        dst.write_buf(&mut addr);
+        assert_eq!(pkt[2].2, addr);
+        assert_eq!(pkt[4].2.len(), 50 - HEADER_SIZE);
+
+        // past `stop`, nothing is padded
+        stream.write_all(b"ping").await.unwrap();
+        assert_eq!(
+            read_frame(&mut server).await,
+            (CMD_PSH, 1, b"ping".to_vec())
+        );
+
+        let mut b = bytes::BytesMut::new();
+        super::put_frame(&mut b, CMD_WASTE, 0, &[0u8; 5]);
+        super::put_frame(&mut b, CMD_PSH, 1, b"pong");
+        super::put_frame(&mut b, CMD_FIN, 1, &[]);
+        server.write_all(&b).await.unwrap();
+        let mut got = vec![];
+        stream.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"pong");
+
+        // closed both ways, the session is handed back
+        stream.shutdown().await.unwrap();
+        assert_eq!(read_frame(&mut server).await, (CMD_FIN, 1, vec![]));
+        done_rx.await.unwrap();
+        assert!(!session.is_closed());
+
+        drop(server);
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while !session.is_closed() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+//! `max-connections`: caps the concurrent sessions through an outbound,
+//! for providers limiting the connections per account or device.
+
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use erased_serde::Serialize as ESerialize;
+use futures::{Sink, Stream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tracing::{info, warn};
+
+use crate::{
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram, ChainedStream, ProxyChain,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    session::{Session, SocksAddr},
+};
+
+use super::{
+    datagram::UdpPacket, utils::DialerProxy, AnyOutboundHandler, AnyStream, OutboundHandler,
+    OutboundType,
+};
+
+/// Wraps an outbound so that at most `max` sessions go through it at once.
+/// A session holds a permit until it's torn down. Probes go through
+/// regardless, so that a busy proxy isn't deemed dead.
+pub struct Handler {
+    inner: AnyOutboundHandler,
+    max: usize,
+    permits: Arc<Semaphore>,
+    /// set once the cap is hit, to report it once until a session is let
+    /// through again
+    saturated: AtomicBool,
+}
+
+impl Handler {
+    pub fn new(inner: AnyOutboundHandler, max: usize) -> AnyOutboundHandler {
+        Arc::new(Self {
+            inner,
+            max,
+            permits: Arc::new(Semaphore::new(max)),
+            saturated: AtomicBool::new(false),
+        })
+    }
+
+    fn acquire(&self) -> io::Result<OwnedSemaphorePermit> {
+        match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => {
+                if self.saturated.swap(false, Ordering::Relaxed) {
+                    info!("{} is below its max-connections again", self.name());
+                }
+                Ok(permit)
+            }
+            Err(_) => {
+                if !self.saturated.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "{} reached its max-connections {}, new sessions spill over",
+                        self.name(),
+                        self.max
+                    );
+                }
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{} reached max-connections {}", self.name(), self.max),
+                ))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn proto(&self) -> OutboundType {
+        self.inner.proto()
+    }
+
+    fn dialer_proxy(&self) -> Option<&DialerProxy> {
+        self.inner.dialer_proxy()
+    }
+
+    fn saturated(&self) -> bool {
+        self.permits.available_permits() == 0
+    }
+
+    async fn remote_addr(&self) -> Option<SocksAddr> {
+        self.inner.remote_addr().await
+    }
+
+    async fn support_udp(&self) -> bool {
+        self.inner.support_udp().await
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        if sess.probe {
+            return self.inner.connect_stream(sess, resolver).await;
+        }
+        let permit = self.acquire()?;
+        let s = self.inner.connect_stream(sess, resolver).await?;
+        Ok(Box::new(Capped {
+            inner: s,
+            _permit: permit,
+        }))
+    }
+
+    async fn proxy_stream(
+        &self,
+        s: AnyStream,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<AnyStream> {
+        if sess.probe {
+            return self.inner.proxy_stream(s, sess, resolver).await;
+        }
+        let permit = self.acquire()?;
+        let s = self.inner.proxy_stream(s, sess, resolver).await?;
+        Ok(Box::new(Capped {
+            inner: s,
+            _permit: permit,
+        }))
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        if sess.probe {
+            return self.inner.connect_datagram(sess, resolver).await;
+        }
+        let permit = self.acquire()?;
+        let d = self.inner.connect_datagram(sess, resolver).await?;
+        Ok(Box::new(Capped {
+            inner: d,
+            _permit: permit,
+        }))
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        let mut m = self.inner.as_map().await;
+        m.insert("max-connections".to_string(), Box::new(self.max) as _);
+        m.insert(
+            "connections".to_string(),
+            Box::new(self.max - self.permits.available_permits()) as _,
+        );
+        m
+    }
+}
+
+/// a session through a capped outbound, its permit is released on teardown
+#[derive(Debug)]
+struct Capped<T> {
+    inner: T,
+    _permit: OwnedSemaphorePermit,
+}
+
+#[async_trait]
+impl ChainedStream for Capped<BoxedChainedStream> {
+    fn chain(&self) -> &ProxyChain {
+        self.inner.chain()
+    }
+
+    async fn append_to_chain(&self, name: &str) {
+        self.inner.append_to_chain(name).await
+    }
+}
+
+#[async_trait]
+impl ChainedDatagram for Capped<BoxedChainedDatagram> {
+    fn chain(&self) -> &ProxyChain {
+        self.inner.chain()
+    }
+
+    async fn append_to_chain(&self, name: &str) {
+        self.inner.append_to_chain(name).await
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Capped<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Capped<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<T: Stream<Item = UdpPacket> + Unpin> Stream for Capped<T> {
+    type Item = UdpPacket;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl<T: Sink<UdpPacket, Error = io::Error> + Unpin> Sink<UdpPacket> for Capped<T> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        app::{
+            dispatcher::ChainedStreamWrapper,
+            dns::{MockClashResolver, ThreadSafeDNSResolver},
+        },
+        proxy::mocks::MockDummyOutboundHandler,
+        session::Session,
+    };
+
+    use super::Handler;
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let mut mock = MockDummyOutboundHandler::new();
+        mock.expect_name().return_const("node".to_owned());
+        mock.expect_connect_stream().returning(|_, _| {
+            let (s, _) = tokio::io::duplex(16);
+            Ok(Box::new(ChainedStreamWrapper::new(s)))
+        });
+
+        let h = Handler::new(Arc::new(mock), 1);
+        let resolver: ThreadSafeDNSResolver = Arc::new(MockClashResolver::new());
+        let sess = Session::default();
+
+        let first = h.connect_stream(&sess, resolver.clone()).await.unwrap();
+        assert!(h.saturated());
+        assert!(h.connect_stream(&sess, resolver.clone()).await.is_err());
+
+        // health checks still get through
+        let probe = Session {
+            probe: true,
+            ..Default::default()
+        };
+        assert!(h.connect_stream(&probe, resolver.clone()).await.is_ok());
+        assert!(h.saturated());
+
+        drop(first);
+        assert!(!h.saturated());
+        assert!(h.connect_stream(&sess, resolver).await.is_ok());
+    }
+}
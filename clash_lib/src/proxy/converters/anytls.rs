@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use tracing::warn;
+
+use super::maybe_cap;
+use crate::{
+    config::internal::proxy::OutboundAnyTls,
+    proxy::{
+        anytls::{Handler, Opts},
+        uot, AnyOutboundHandler, CommonOption,
+    },
+};
+
+impl TryFrom<OutboundAnyTls> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(value: OutboundAnyTls) -> Result<Self, Self::Error> {
+        (&value).try_into()
+    }
+}
+
+impl TryFrom<&OutboundAnyTls> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(s: &OutboundAnyTls) -> Result<Self, Self::Error> {
+        let skip_cert_verify = s.skip_cert_verify.unwrap_or_default();
+        if skip_cert_verify {
+            warn!("skipping TLS cert verification for {}", s.server);
+        }
+
+        let udp = s.udp.unwrap_or(true);
+        let h = Handler::new(Opts {
+            name: s.name.to_owned(),
+            common_opts: CommonOption::new(s.dialer_proxy.clone())
+                .with_mptcp(s.mptcp)
+                .with_bind_address(s.bind_address),
+            server: s.server.to_owned(),
+            port: s.port,
+            password: s.password.expose().to_owned(),
+            sni: s
+                .sni
+                .as_ref()
+                .map(|x| x.to_owned())
+                .unwrap_or(s.server.to_owned()),
+            alpn: s.alpn.as_ref().map(|x| x.to_owned()),
+            skip_cert_verify,
+            tls_fragment: s.tls_fragment.as_deref().map(str::parse).transpose()?,
+            udp,
+            idle_session_timeout: Duration::from_secs(s.idle_session_timeout.unwrap_or(30)),
+        });
+        // AnyTLS servers only take UDP as UDP-over-TCP v2
+        let h = if udp { uot::Handler::new(h) } else { h };
+        Ok(maybe_cap(h, s.max_connections))
+    }
+}
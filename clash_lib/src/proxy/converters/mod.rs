@@ -1,3 +1,60 @@
+pub mod anytls;
 pub mod shadowsocks;
+pub mod socks5;
 pub mod trojan;
 pub mod vmess;
+pub mod wireguard;
+
+use crate::{
+    config::internal::proxy::SmuxOpt,
+    proxy::{
+        capped,
+        mux::{self, MuxOption, MuxProtocol},
+        uot, AnyOutboundHandler,
+    },
+    Error,
+};
+
+/// wraps `h` with the multiplexer if `smux` is enabled
+pub(crate) fn maybe_mux(
+    h: AnyOutboundHandler,
+    smux: Option<&SmuxOpt>,
+) -> Result<AnyOutboundHandler, Error> {
+    match smux {
+        Some(opt) if opt.enabled => Ok(mux::Handler::new(
+            h,
+            MuxOption {
+                protocol: opt
+                    .protocol
+                    .as_deref()
+                    .map(MuxProtocol::try_from)
+                    .transpose()
+                    .map_err(Error::InvalidConfig)?
+                    .unwrap_or(MuxProtocol::H2Mux),
+                max_connections: opt.max_connections.unwrap_or(4).max(1),
+                max_streams: opt.max_streams.unwrap_or(8).max(1),
+            },
+        )),
+        _ => Ok(h),
+    }
+}
+
+/// wraps `h` so its UDP sessions go over TCP if `udp-over-tcp` is set
+pub(crate) fn maybe_uot(h: AnyOutboundHandler, udp_over_tcp: Option<bool>) -> AnyOutboundHandler {
+    if udp_over_tcp.unwrap_or_default() {
+        uot::Handler::new(h)
+    } else {
+        h
+    }
+}
+
+/// caps the sessions through `h` if `max-connections` is set
+pub(crate) fn maybe_cap(
+    h: AnyOutboundHandler,
+    max_connections: Option<usize>,
+) -> AnyOutboundHandler {
+    match max_connections {
+        Some(max) => capped::Handler::new(h, max),
+        None => h,
+    }
+}
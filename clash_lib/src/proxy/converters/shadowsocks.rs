@@ -1,12 +1,63 @@
+use std::collections::HashMap;
+
+use super::{maybe_cap, maybe_mux, maybe_uot};
 use crate::{
-    config::internal::proxy::OutboundShadowsocks,
+    config::internal::proxy::{OutboundShadowsocks, PluginOpts},
     proxy::{
-        shadowsocks::{Handler, HandlerOptions, OBFSOption},
+        shadowsocks::{
+            check_plugin, encode_plugin_opts, Handler, HandlerOptions, OBFSOption, Sip003Option,
+        },
         AnyOutboundHandler, CommonOption,
     },
     Error,
 };
 
+fn plugin_opts_map(
+    s: &OutboundShadowsocks,
+    plugin: &str,
+) -> Result<HashMap<String, serde_yaml::Value>, Error> {
+    match &s.plugin_opts {
+        Some(PluginOpts::Map(x)) => Ok(x.clone()),
+        Some(PluginOpts::Env(_)) => Err(Error::InvalidConfig(format!(
+            "plugin_opts must be a map for plugin {}",
+            plugin
+        ))),
+        None => Err(Error::InvalidConfig(format!(
+            "plugin_opts is required for plugin {}",
+            plugin
+        ))),
+    }
+}
+
+/// a SIP003 plugin is a process of its own dialing the server, which none of
+/// the options of how to dial it apply to
+fn sip003_option(s: &OutboundShadowsocks, plugin: &str) -> Result<Sip003Option, Error> {
+    check_plugin(plugin)?;
+    if s.dialer_proxy.is_some() || s.bind_address.is_some() {
+        return Err(Error::InvalidConfig(format!(
+            "plugin {} dials the server itself, without dialer-proxy or bind-address",
+            plugin
+        )));
+    }
+
+    Ok(Sip003Option {
+        plugin: plugin.to_owned(),
+        plugin_opts: s.plugin_opts.as_ref().map(|x| match x {
+            PluginOpts::Env(x) => x.to_owned(),
+            PluginOpts::Map(x) => encode_plugin_opts(x.iter().map(|(k, v)| {
+                (
+                    k.as_str(),
+                    match v {
+                        serde_yaml::Value::Bool(true) => None,
+                        serde_yaml::Value::String(v) => Some(v.to_owned()),
+                        v => serde_yaml::to_string(v).ok().map(|x| x.trim().to_owned()),
+                    },
+                )
+            })),
+        }),
+    })
+}
+
 impl TryFrom<OutboundShadowsocks> for AnyOutboundHandler {
     type Error = crate::Error;
 
@@ -21,42 +72,28 @@ impl TryFrom<&OutboundShadowsocks> for AnyOutboundHandler {
     fn try_from(s: &OutboundShadowsocks) -> Result<Self, Self::Error> {
         let h = Handler::new(HandlerOptions {
             name: s.name.to_owned(),
-            common_opts: CommonOption::default(),
+            common_opts: CommonOption::new(s.dialer_proxy.clone())
+                .with_mptcp(s.mptcp)
+                .with_bind_address(s.bind_address),
             server: s.server.to_owned(),
             port: s.port,
-            password: s.password.to_owned(),
+            password: s.password.expose().to_owned(),
             cipher: s.cipher.to_owned(),
             plugin_opts: match &s.plugin {
                 Some(plugin) => match plugin.as_str() {
-                    "obfs" => s
-                        .plugin_opts
-                        .clone()
-                        .ok_or(Error::InvalidConfig(
-                            "plugin_opts is required for plugin obfs".to_owned(),
-                        ))?
-                        .try_into()
-                        .map(|x| OBFSOption::Simple(x))
-                        .ok(),
-                    "v2ray-plugin" => s
-                        .plugin_opts
-                        .clone()
-                        .ok_or(Error::InvalidConfig(
-                            "plugin_opts is required for plugin obfs".to_owned(),
-                        ))?
-                        .try_into()
-                        .map(|x| OBFSOption::V2Ray(x))
-                        .ok(),
-                    _ => {
-                        return Err(Error::InvalidConfig(format!(
-                            "unsupported plugin: {}",
-                            plugin
-                        )));
+                    "obfs" => Some(OBFSOption::Simple(plugin_opts_map(s, plugin)?.try_into()?)),
+                    "v2ray-plugin" => {
+                        Some(OBFSOption::V2Ray(plugin_opts_map(s, plugin)?.try_into()?))
                     }
+                    _ => Some(OBFSOption::Sip003(sip003_option(s, plugin)?)),
                 },
                 None => None,
             },
             udp: s.udp,
         });
-        Ok(h)
+        Ok(maybe_cap(
+            maybe_uot(maybe_mux(h, s.smux.as_ref())?, s.udp_over_tcp),
+            s.max_connections,
+        ))
     }
 }
@@ -0,0 +1,46 @@
+use tracing::warn;
+
+use super::maybe_cap;
+use crate::{
+    config::internal::proxy::OutboundSocks5,
+    proxy::{
+        socks::{Handler, HandlerOptions},
+        AnyOutboundHandler, CommonOption,
+    },
+};
+
+impl TryFrom<OutboundSocks5> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(value: OutboundSocks5) -> Result<Self, Self::Error> {
+        (&value).try_into()
+    }
+}
+
+impl TryFrom<&OutboundSocks5> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(s: &OutboundSocks5) -> Result<Self, Self::Error> {
+        let skip_cert_verify = s.skip_cert_verify.unwrap_or_default();
+        if skip_cert_verify {
+            warn!("skipping TLS cert verification for {}", s.server);
+        }
+
+        let h = Handler::new(HandlerOptions {
+            name: s.name.to_owned(),
+            common_opts: CommonOption::new(s.dialer_proxy.clone())
+                .with_mptcp(s.mptcp)
+                .with_bind_address(s.bind_address),
+            server: s.server.to_owned(),
+            port: s.port,
+            user: s.username.clone(),
+            password: s.password.as_ref().map(|x| x.expose().to_owned()),
+            udp: s.udp.unwrap_or_default(),
+            tls: s.tls.unwrap_or_default(),
+            sni: s.sni.clone().unwrap_or(s.server.to_owned()),
+            skip_cert_verify,
+            tls_fragment: s.tls_fragment.as_deref().map(str::parse).transpose()?,
+        });
+        Ok(maybe_cap(h, s.max_connections))
+    }
+}
@@ -1,9 +1,10 @@
 use tracing::warn;
 
+use super::{maybe_cap, maybe_mux, maybe_uot};
 use crate::{
     config::internal::proxy::OutboundTrojan,
     proxy::{
-        options::{GrpcOption, WsOption},
+        options::{GrpcOption, Http2Option, WsOption},
         trojan::{Handler, Opts, Transport},
         AnyOutboundHandler, CommonOption,
     },
@@ -29,10 +30,12 @@ impl TryFrom<&OutboundTrojan> for AnyOutboundHandler {
 
         let h = Handler::new(Opts {
             name: s.name.to_owned(),
-            common_opts: CommonOption::default(),
+            common_opts: CommonOption::new(s.dialer_proxy.clone())
+                .with_mptcp(s.mptcp)
+                .with_bind_address(s.bind_address),
             server: s.server.to_owned(),
             port: s.port,
-            password: s.password.clone(),
+            password: s.password.expose().to_owned(),
             udp: s.udp.unwrap_or_default(),
             sni: s
                 .sni
@@ -41,6 +44,7 @@ impl TryFrom<&OutboundTrojan> for AnyOutboundHandler {
                 .unwrap_or(s.server.to_owned()),
             alpn: s.alpn.as_ref().map(|x| x.to_owned()),
             skip_cert_verify,
+            tls_fragment: s.tls_fragment.as_deref().map(str::parse).transpose()?,
             transport: s
                 .network
                 .as_ref()
@@ -82,10 +86,25 @@ impl TryFrom<&OutboundTrojan> for AnyOutboundHandler {
                         .ok_or(Error::InvalidConfig(
                             "grpc_opts is required for grpc".to_owned(),
                         )),
+                    "h2" => s
+                        .h2_opts
+                        .as_ref()
+                        .map(|x| {
+                            Transport::H2(Http2Option {
+                                host: x.host.as_ref().map(|x| x.to_owned()).unwrap_or_default(),
+                                path: x.path.as_ref().map(|x| x.to_owned()).unwrap_or_default(),
+                            })
+                        })
+                        .ok_or(Error::InvalidConfig(
+                            "h2_opts is required for h2".to_owned(),
+                        )),
                     _ => return Err(Error::InvalidConfig(format!("unsupported network: {}", x))),
                 })
                 .transpose()?,
         });
-        Ok(h)
+        Ok(maybe_cap(
+            maybe_uot(maybe_mux(h, s.smux.as_ref())?, s.udp_over_tcp),
+            s.max_connections,
+        ))
     }
 }
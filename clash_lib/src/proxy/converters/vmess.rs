@@ -1,10 +1,11 @@
 use tracing::warn;
 
+use super::{maybe_cap, maybe_mux, maybe_uot};
 use crate::{
     config::internal::proxy::OutboundVmess,
     proxy::{
-        options::{Http2Option, WsOption},
-        transport::TLSOptions,
+        options::{Http2Option, QuicOption, WsOption},
+        transport::{CongestionController, TLSOptions},
         vmess::{Handler, HandlerOptions, VmessTransport},
         AnyOutboundHandler, CommonOption,
     },
@@ -28,15 +29,35 @@ impl TryFrom<&OutboundVmess> for AnyOutboundHandler {
             warn!("skipping TLS cert verification for {}", s.server);
         }
 
+        let xudp = match s.packet_encoding.as_deref() {
+            None | Some("") => false,
+            Some("xudp") => true,
+            Some(x) => {
+                return Err(Error::InvalidConfig(format!(
+                    "unsupported packet-encoding {} for {}, only xudp is supported",
+                    x, s.name
+                )))
+            }
+        };
+        if xudp && s.alter_id != 0 {
+            return Err(Error::InvalidConfig(format!(
+                "xudp requires AEAD, alterId of {} must be 0",
+                s.name
+            )));
+        }
+
         let h = Handler::new(HandlerOptions {
             name: s.name.to_owned(),
-            common_opts: CommonOption::default(),
+            common_opts: CommonOption::new(s.dialer_proxy.clone())
+                .with_mptcp(s.mptcp)
+                .with_bind_address(s.bind_address),
             server: s.server.to_owned(),
             port: s.port,
-            uuid: s.uuid.clone(),
+            uuid: s.uuid.expose().to_owned(),
             alter_id: s.alter_id,
             security: s.cipher.as_ref().map(Clone::clone).unwrap_or_default(),
             udp: s.udp.unwrap_or(true),
+            xudp,
             transport: s
                 .network
                 .clone()
@@ -75,6 +96,18 @@ impl TryFrom<&OutboundVmess> for AnyOutboundHandler {
                         .ok_or(Error::InvalidConfig(
                             "h2_opts is required for h2".to_owned(),
                         )),
+                    "quic" => {
+                        let opts = s.quic_opts.as_ref();
+                        Ok(VmessTransport::Quic(QuicOption {
+                            congestion_controller: opts
+                                .and_then(|x| x.congestion_controller.as_ref())
+                                .map(|x| CongestionController::try_from(x.as_str()))
+                                .transpose()
+                                .map_err(Error::InvalidConfig)?
+                                .unwrap_or_default(),
+                            zero_rtt: opts.and_then(|x| x.zero_rtt).unwrap_or_default(),
+                        }))
+                    }
                     _ => {
                         return Err(Error::InvalidConfig(format!("unsupported network: {}", x)));
                     }
@@ -107,13 +140,18 @@ impl TryFrom<&OutboundVmess> for AnyOutboundHandler {
                             "ws" => Ok(vec!["http/1.1".to_owned()]),
                             "http" => Ok(vec![]),
                             "h2" => Ok(vec!["h2".to_owned()]),
+                            "quic" => Ok(vec!["h3".to_owned()]),
                             _ => Err(Error::InvalidConfig(format!("unsupported network: {}", x))),
                         })
                         .transpose()?,
+                    fragment: s.tls_fragment.as_deref().map(str::parse).transpose()?,
                 }),
                 false => None,
             },
         });
-        Ok(h)
+        Ok(maybe_cap(
+            maybe_uot(maybe_mux(h, s.smux.as_ref())?, s.udp_over_tcp),
+            s.max_connections,
+        ))
     }
 }
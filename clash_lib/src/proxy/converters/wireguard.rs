@@ -0,0 +1,228 @@
+use base64::{engine::general_purpose, Engine};
+use boringtun::x25519::{PublicKey, StaticSecret};
+
+use super::maybe_cap;
+use crate::{
+    config::internal::proxy::{OutboundWireguard, WireguardPeer},
+    proxy::{
+        wg::{Handler, Opts, PeerOpts},
+        AnyOutboundHandler, CommonOption,
+    },
+    Error,
+};
+
+impl TryFrom<OutboundWireguard> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(value: OutboundWireguard) -> Result<Self, Self::Error> {
+        (&value).try_into()
+    }
+}
+
+impl TryFrom<&OutboundWireguard> for AnyOutboundHandler {
+    type Error = crate::Error;
+
+    fn try_from(s: &OutboundWireguard) -> Result<Self, Self::Error> {
+        let peers = match &s.peers {
+            Some(peers) if !peers.is_empty() => peers
+                .iter()
+                .map(|p| peer_opts(&s.name, p))
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => {
+                let (Some(server), Some(port), Some(public_key)) =
+                    (&s.server, s.port, &s.public_key)
+                else {
+                    return Err(Error::InvalidConfig(format!(
+                        "wireguard {}: server, port and public-key are required without peers",
+                        s.name
+                    )));
+                };
+                vec![peer_opts(
+                    &s.name,
+                    &WireguardPeer {
+                        server: server.to_owned(),
+                        port,
+                        public_key: public_key.to_owned(),
+                        preshared_key: s.preshared_key.clone(),
+                        allowed_ips: None,
+                    },
+                )?]
+            }
+        };
+
+        let dns = if s.remote_dns_resolve.unwrap_or_default() {
+            match &s.dns {
+                Some(dns) if !dns.is_empty() => dns.clone(),
+                _ => {
+                    return Err(Error::InvalidConfig(format!(
+                        "wireguard {}: remote-dns-resolve needs dns servers",
+                        s.name
+                    )))
+                }
+            }
+        } else {
+            vec![]
+        };
+
+        let h = Handler::new(Opts {
+            name: s.name.to_owned(),
+            common_opts: CommonOption::new(s.dialer_proxy.clone())
+                .with_bind_address(s.bind_address),
+            ip: s.ip,
+            ipv6: s.ipv6,
+            private_key: StaticSecret::from(decode_key(
+                &s.name,
+                "private-key",
+                s.private_key.expose(),
+            )?),
+            peers,
+            persistent_keepalive: s.persistent_keepalive,
+            dns,
+            mtu: s.mtu.unwrap_or(1420),
+            udp: s.udp.unwrap_or(true),
+        });
+        Ok(maybe_cap(h, s.max_connections))
+    }
+}
+
+fn peer_opts(name: &str, p: &WireguardPeer) -> Result<PeerOpts, Error> {
+    Ok(PeerOpts {
+        server: p.server.to_owned(),
+        port: p.port,
+        public_key: PublicKey::from(decode_key(name, "public-key", &p.public_key)?),
+        preshared_key: p
+            .preshared_key
+            .as_ref()
+            .map(|x| decode_key(name, "pre-shared-key", x.expose()))
+            .transpose()?,
+        allowed_ips: p
+            .allowed_ips
+            .iter()
+            .flatten()
+            .map(|x| {
+                x.parse().map_err(|e| {
+                    Error::InvalidConfig(format!(
+                        "wireguard {}: invalid allowed ip {}: {}",
+                        name, x, e
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+    })
+}
+
+/// a base64 encoded 32 bytes key
+fn decode_key(name: &str, field: &str, key: &str) -> Result<[u8; 32], Error> {
+    general_purpose::STANDARD
+        .decode(key.trim())
+        .ok()
+        .and_then(|x| <[u8; 32]>::try_from(x).ok())
+        .ok_or_else(|| {
+            Error::InvalidConfig(format!(
+                "wireguard {}: {} isn't a base64 encoded 32 bytes key",
+                name, field
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::internal::proxy::{OutboundWireguard, WireguardPeer},
+        proxy::AnyOutboundHandler,
+        Error,
+    };
+
+    use super::{decode_key, peer_opts};
+
+    const KEY: &str = "Cr8hWlKvtDt7nrvf+f0brNQQzabAqrjfBvas9pmowjo=";
+
+    fn config(extra: &str) -> OutboundWireguard {
+        serde_yaml::from_str(&format!(
+            "{{name: wg, private-key: eCtXsJZ27+4PbhDkHnB923tkUn2Gj59wZw5wFA75MnU=, ip: \
+             172.16.0.2{}}}",
+            extra
+        ))
+        .unwrap()
+    }
+
+    fn is_invalid_config<T>(r: Result<T, Error>, field: &str) -> bool {
+        matches!(r, Err(Error::InvalidConfig(e)) if e.contains(field))
+    }
+
+    #[test]
+    fn test_decode_key() {
+        assert_eq!(decode_key("wg", "public-key", KEY).unwrap().len(), 32);
+        assert!(is_invalid_config(
+            decode_key("wg", "public-key", "not base64!"),
+            "public-key"
+        ));
+        // 16 bytes
+        assert!(is_invalid_config(
+            decode_key("wg", "private-key", "AAAAAAAAAAAAAAAAAAAAAA=="),
+            "private-key"
+        ));
+    }
+
+    #[test]
+    fn test_peer_opts() {
+        let mut peer = WireguardPeer {
+            server: "10.0.0.1".to_owned(),
+            port: 51820,
+            public_key: KEY.to_owned(),
+            preshared_key: None,
+            allowed_ips: Some(vec!["10.1.0.0/16".to_owned(), "fd00::/8".to_owned()]),
+        };
+        let opts = peer_opts("wg", &peer).unwrap();
+        assert_eq!(opts.allowed_ips.len(), 2);
+        assert!(opts.preshared_key.is_none());
+
+        peer.allowed_ips = Some(vec!["10.1.0.0/33".to_owned()]);
+        assert!(is_invalid_config(peer_opts("wg", &peer), "10.1.0.0/33"));
+
+        peer.allowed_ips = None;
+        peer.preshared_key = Some("short".to_owned().into());
+        assert!(is_invalid_config(peer_opts("wg", &peer), "pre-shared-key"));
+
+        peer.preshared_key = None;
+        peer.public_key = "AAAA".to_owned();
+        assert!(is_invalid_config(peer_opts("wg", &peer), "public-key"));
+    }
+
+    #[test]
+    fn test_wireguard_peers() {
+        // neither a server nor peers
+        assert!(is_invalid_config(
+            AnyOutboundHandler::try_from(config("")),
+            "server, port and public-key"
+        ));
+        assert!(is_invalid_config(
+            AnyOutboundHandler::try_from(config(", server: 10.0.0.1, port: 51820")),
+            "server, port and public-key"
+        ));
+        assert!(is_invalid_config(
+            AnyOutboundHandler::try_from(config(", peers: []")),
+            "server, port and public-key"
+        ));
+
+        assert!(AnyOutboundHandler::try_from(config(&format!(
+            ", server: 10.0.0.1, port: 51820, public-key: {}",
+            KEY
+        )))
+        .is_ok());
+        assert!(AnyOutboundHandler::try_from(config(&format!(
+            ", peers: [{{server: 10.0.0.1, port: 51820, public-key: {}, allowed-ips: \
+             [10.1.0.0/16]}}]",
+            KEY
+        )))
+        .is_ok());
+
+        assert!(is_invalid_config(
+            AnyOutboundHandler::try_from(config(&format!(
+                ", server: 10.0.0.1, port: 51820, public-key: {}, remote-dns-resolve: true",
+                KEY
+            ))),
+            "remote-dns-resolve"
+        ));
+    }
+}
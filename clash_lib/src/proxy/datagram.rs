@@ -1,4 +1,5 @@
 use crate::app::dns::ThreadSafeDNSResolver;
+use crate::common::nat64;
 use crate::proxy::socks::Socks5UDPCodec;
 use crate::proxy::{AnyOutboundDatagram, InboundDatagram};
 use crate::session::SocksAddr;
@@ -6,12 +7,13 @@ use bytes::Bytes;
 use futures::{ready, Sink, SinkExt, Stream, StreamExt};
 use std::fmt::{Debug, Display, Formatter};
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::io::ReadBuf;
 use tokio::net::UdpSocket;
 use tokio_util::udp::UdpFramed;
+use tracing::trace;
 
 #[derive(Clone)]
 pub struct UdpPacket {
@@ -53,6 +55,8 @@ impl UdpPacket {
 
 pub struct InboundUdp<I> {
     inner: I,
+    /// packets from other hosts are dropped when set
+    client: Option<IpAddr>,
 }
 
 impl<I> InboundUdp<I>
@@ -60,8 +64,8 @@ where
     I: Stream + Unpin,
     I: Sink<((Bytes, SocksAddr), SocketAddr)>,
 {
-    pub fn new(inner: I) -> Self {
-        Self { inner }
+    pub fn new(inner: I, client: Option<IpAddr>) -> Self {
+        Self { inner, client }
     }
 }
 
@@ -77,19 +81,22 @@ impl Stream for InboundUdp<UdpFramed<Socks5UDPCodec>> {
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let pin = self.get_mut();
 
-        match pin.inner.poll_next_unpin(cx) {
-            Poll::Ready(item) => match item {
-                None => Poll::Ready(None),
-                Some(item) => match item {
-                    Ok(((dst, pkt), src)) => Poll::Ready(Some(UdpPacket {
+        loop {
+            match ready!(pin.inner.poll_next_unpin(cx)) {
+                None => return Poll::Ready(None),
+                Some(Ok(((dst, pkt), src))) => {
+                    if pin.client.is_some_and(|x| x != src.ip()) {
+                        trace!("dropping UDP packet from {}, not the client", src);
+                        continue;
+                    }
+                    return Poll::Ready(Some(UdpPacket {
                         data: pkt.to_vec(),
                         src_addr: SocksAddr::Ip(src),
                         dst_addr: dst,
-                    })),
-                    Err(_) => Poll::Ready(None),
-                },
-            },
-            Poll::Pending => Poll::Pending,
+                    }));
+                }
+                Some(Err(_)) => return Poll::Ready(None),
+            }
         }
     }
 }
@@ -199,6 +206,7 @@ impl Sink<UdpPacket> for OutboundDatagramImpl {
                 }
                 SocksAddr::Ip(addr) => *addr,
             };
+            let dst = nat64::translate(dst);
 
             let n = ready!(inner.poll_send_to(cx, data.as_slice(), dst))?;
             let wrote_all = n == data.len();
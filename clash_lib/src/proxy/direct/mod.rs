@@ -5,29 +5,41 @@ use crate::app::dispatcher::{
 use crate::app::dns::ThreadSafeDNSResolver;
 use crate::config::internal::proxy::PROXY_DIRECT;
 use crate::proxy::datagram::OutboundDatagramImpl;
-use crate::proxy::utils::{new_tcp_stream, new_udp_socket};
+use crate::proxy::utils::{new_tcp_stream, new_udp_socket, use_mptcp, Interface};
 use crate::proxy::{AnyOutboundHandler, AnyStream, OutboundHandler};
 use crate::session::{Session, SocksAddr};
 
 use async_trait::async_trait;
 use serde::Serialize;
-use std::sync::Arc;
+use std::{net::IpAddr, sync::Arc};
 
 use super::OutboundType;
 
 #[derive(Serialize)]
-pub struct Handler;
+pub struct Handler {
+    name: String,
+    #[serde(skip)]
+    iface: Option<Interface>,
+}
 
 impl Handler {
     pub fn new() -> AnyOutboundHandler {
-        Arc::new(Self)
+        Self::new_named(PROXY_DIRECT.to_owned(), None)
+    }
+
+    /// a `type: direct` proxy, connecting from `bind_address` if set
+    pub fn new_named(name: String, bind_address: Option<IpAddr>) -> AnyOutboundHandler {
+        Arc::new(Self {
+            name,
+            iface: bind_address.map(Interface::IpAddr),
+        })
     }
 }
 
 #[async_trait]
 impl OutboundHandler for Handler {
     fn name(&self) -> &str {
-        PROXY_DIRECT
+        &self.name
     }
 
     fn proto(&self) -> OutboundType {
@@ -51,7 +63,8 @@ impl OutboundHandler for Handler {
             resolver,
             sess.destination.host().as_str(),
             sess.destination.port(),
-            None,
+            self.iface.as_ref(),
+            use_mptcp(None),
             #[cfg(any(target_os = "linux", target_os = "android"))]
             None,
         )
@@ -78,7 +91,7 @@ impl OutboundHandler for Handler {
     ) -> std::io::Result<BoxedChainedDatagram> {
         let d = new_udp_socket(
             None,
-            sess.iface.as_ref(),
+            self.iface.as_ref().or(sess.iface.as_ref()),
             #[cfg(any(target_os = "linux", target_os = "android"))]
             None,
         )
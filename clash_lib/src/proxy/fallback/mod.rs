@@ -53,6 +53,14 @@ impl Handler {
     async fn find_alive_proxy(&self, touch: bool) -> AnyOutboundHandler {
         let proxies = self.get_proxies(touch).await;
         for proxy in proxies.iter() {
+            if proxy.saturated() {
+                debug!(
+                    "{} skips {} at its max-connections",
+                    self.name(),
+                    proxy.name()
+                );
+                continue;
+            }
             if self.proxy_manager.alive(proxy.name()).await {
                 debug!("{} fastest {} is alive", self.name(), proxy.name());
                 return proxy.clone();
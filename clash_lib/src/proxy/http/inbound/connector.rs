@@ -1,5 +1,5 @@
 use crate::proxy::{AnyStream, ProxyError};
-use crate::session::{Network, Session, Type};
+use crate::session::{Inbound, Network, Session, Type};
 use crate::Dispatcher;
 use futures::FutureExt;
 
@@ -17,11 +17,24 @@ use super::proxy::maybe_socks_addr;
 pub struct Connector {
     src: SocketAddr,
     dispatcher: Arc<Dispatcher>,
+    /// the user the connections are opened for
+    user: Option<String>,
+    inbound: Inbound,
 }
 
 impl Connector {
-    pub fn new(src: SocketAddr, dispatcher: Arc<Dispatcher>) -> Self {
-        Self { src, dispatcher }
+    pub fn new(
+        src: SocketAddr,
+        dispatcher: Arc<Dispatcher>,
+        user: Option<String>,
+        inbound: Inbound,
+    ) -> Self {
+        Self {
+            src,
+            dispatcher,
+            user,
+            inbound,
+        }
     }
 }
 
@@ -40,6 +53,8 @@ impl tower::Service<Uri> for Connector {
     fn call(&mut self, url: Uri) -> Self::Future {
         let src = self.src.clone();
         let dispatcher = self.dispatcher.clone();
+        let inbound = self.inbound.clone();
+        let user = self.user.clone();
 
         let destination = maybe_socks_addr(&url);
 
@@ -51,6 +66,8 @@ impl tower::Service<Uri> for Connector {
                 typ: Type::Http,
                 source: src,
                 destination: destination.ok_or(ProxyError::InvalidUrl(url.to_string()))?,
+                inbound,
+                user,
                 ..Default::default()
             };
 
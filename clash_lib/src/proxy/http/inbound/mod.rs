@@ -2,9 +2,10 @@ mod auth;
 mod connector;
 mod proxy;
 
-use crate::common::auth::ThreadSafeAuthenticator;
-use crate::proxy::utils::apply_tcp_options;
+use crate::common::auth::{ThreadSafeAllowList, ThreadSafeAuthenticator};
+use crate::proxy::utils::{Acceptor, ConnectionLimiter};
 use crate::proxy::{AnyInboundListener, InboundListener};
+use crate::session::Inbound;
 use crate::Dispatcher;
 use async_trait::async_trait;
 
@@ -21,6 +22,9 @@ pub struct Listener {
     addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    limiter: Option<ConnectionLimiter>,
+    allowlist: Option<ThreadSafeAllowList>,
+    inbound: Inbound,
 }
 
 impl Drop for Listener {
@@ -34,11 +38,17 @@ impl Listener {
         addr: SocketAddr,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        limiter: Option<ConnectionLimiter>,
+        allowlist: Option<ThreadSafeAllowList>,
+        inbound: Inbound,
     ) -> AnyInboundListener {
         Arc::new(Self {
             addr,
             dispatcher,
             authenticator,
+            limiter,
+            allowlist,
+            inbound,
         }) as _
     }
 }
@@ -55,17 +65,18 @@ impl InboundListener for Listener {
 
     async fn listen_tcp(&self) -> std::io::Result<()> {
         let listener = TcpListener::bind(self.addr).await?;
+        let mut acceptor = Acceptor::new(listener, self.limiter.clone(), self.allowlist.clone());
 
         loop {
-            let (socket, src_addr) = listener.accept().await?;
-
-            let socket = apply_tcp_options(socket)?;
+            let (socket, src_addr, permit) = acceptor.accept().await;
 
             let dispatcher = self.dispatcher.clone();
             let author = self.authenticator.clone();
+            let inbound = self.inbound.clone();
 
             tokio::spawn(async move {
-                proxy::handle(Box::new(socket), src_addr, dispatcher, author).await
+                let _permit = permit;
+                proxy::handle(Box::new(socket), src_addr, dispatcher, author, inbound).await
             });
         }
     }
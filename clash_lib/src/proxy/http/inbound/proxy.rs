@@ -1,10 +1,16 @@
 use std::{
+    collections::HashMap,
     net::{IpAddr, SocketAddr},
     sync::Arc,
+    time::Duration,
 };
 
 use futures::{future::BoxFuture, TryFutureExt};
-use http::{uri::Scheme, Method, Request, Response, Uri};
+use http::{
+    header::{HeaderName, CONNECTION},
+    uri::Scheme,
+    HeaderMap, Method, Request, Response, Uri,
+};
 
 use hyper::{server::conn::Http, Body, Client};
 
@@ -15,11 +21,42 @@ use crate::{
     app::dispatcher::Dispatcher,
     common::auth::ThreadSafeAuthenticator,
     proxy::{AnyStream, ProxyError},
-    session::{Network, Session, SocksAddr, Type},
+    session::{Inbound, Network, Session, SocksAddr, Type},
 };
 
 use super::{auth::authenticate_req, connector::Connector};
 
+/// headers that only apply to one connection, RFC 7230 section 6.1.
+/// `Transfer-Encoding` is left to hyper, which frames the body itself
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// drops the headers of the client's connection to us, or of ours to the
+/// server, before a message is forwarded
+fn strip_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .filter_map(|x| HeaderName::from_bytes(x.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
 pub fn maybe_socks_addr(r: &Uri) -> Option<SocksAddr> {
     let port = r
         .port_u16()
@@ -42,19 +79,10 @@ async fn proxy(
     req: Request<Body>,
     src: SocketAddr,
     dispatcher: Arc<Dispatcher>,
-    authenticator: ThreadSafeAuthenticator,
+    client: Client<Connector>,
+    user: Option<String>,
+    inbound: Inbound,
 ) -> Result<Response<Body>, ProxyError> {
-    if authenticator.enabled() {
-        if let Some(res) = authenticate_req(&req, authenticator) {
-            return Ok(res);
-        }
-    }
-
-    let client = Client::builder()
-        .http1_title_case_headers(true)
-        .http1_preserve_header_case(true)
-        .build(Connector::new(src.clone(), dispatcher.clone()));
-
     // TODO: handle other upgrades: https://github.com/hyperium/hyper/blob/master/examples/upgrades.rs
     if req.method() == Method::CONNECT {
         if let Some(addr) = maybe_socks_addr(req.uri()) {
@@ -66,11 +94,13 @@ async fn proxy(
                             typ: Type::HttpConnect,
                             source: src,
                             destination: addr,
+                            inbound,
+                            user,
 
                             ..Default::default()
                         };
 
-                        dispatcher.dispatch_stream(sess, upgraded).await
+                        dispatcher.dispatch_stream(sess, upgraded).await;
                     }
                     Err(e) => warn!("HTTP handshake failure, {}", e),
                 }
@@ -84,12 +114,26 @@ async fn proxy(
                 .unwrap())
         }
     } else {
+        // plain proxy requests carry the absolute URI, e.g. `GET http://example.com/`,
+        // the client sends it upstream in origin form
+        if req.uri().scheme() != Some(&Scheme::HTTP) || req.uri().host().is_none() {
+            return Ok(Response::builder()
+                .status(http::StatusCode::BAD_REQUEST)
+                .body(format!("not an http:// proxy request: {}", req.uri()).into())
+                .unwrap());
+        }
+
+        let mut req = req;
+        strip_hop_by_hop_headers(req.headers_mut());
         match client
             .request(req)
             .map_err(|x| ProxyError::General(x.to_string()))
             .await
         {
-            Ok(res) => Ok(res),
+            Ok(mut res) => {
+                strip_hop_by_hop_headers(res.headers_mut());
+                Ok(res)
+            }
             Err(e) => {
                 warn!("http proxy error: {}", e);
                 Ok(Response::builder()
@@ -105,6 +149,11 @@ struct ProxyService {
     src: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    /// one for each user of the inbound connection, shared by their
+    /// requests so that upstream connections to the same host are kept
+    /// alive and reused, but only for the user who opened them
+    clients: HashMap<Option<String>, Client<Connector>>,
+    inbound: Inbound,
 }
 
 impl Service<Request<Body>> for ProxyService {
@@ -122,11 +171,39 @@ impl Service<Request<Body>> for ProxyService {
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let user = if self.authenticator.enabled() {
+            match authenticate_req(&req, self.authenticator.clone()) {
+                Ok(user) => Some(user),
+                Err(res) => return Box::pin(futures::future::ok(res)),
+            }
+        } else {
+            None
+        };
+
+        let client = self
+            .clients
+            .entry(user.clone())
+            .or_insert_with(|| {
+                Client::builder()
+                    .http1_title_case_headers(true)
+                    .http1_preserve_header_case(true)
+                    .pool_idle_timeout(Duration::from_secs(90))
+                    .build(Connector::new(
+                        self.src,
+                        self.dispatcher.clone(),
+                        user.clone(),
+                        self.inbound.clone(),
+                    ))
+            })
+            .clone();
+
         Box::pin(proxy(
             req,
             self.src,
             self.dispatcher.clone(),
-            self.authenticator.clone(),
+            client,
+            user,
+            self.inbound.clone(),
         ))
     }
 }
@@ -137,23 +214,48 @@ pub async fn handle(
     src: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    inbound: Inbound,
 ) {
-    tokio::task::spawn(async move {
-        if let Err(http_err) = Http::new()
-            .http1_only(true)
-            .http1_keep_alive(true)
-            .serve_connection(
-                stream,
-                ProxyService {
-                    src,
-                    dispatcher,
-                    authenticator,
-                },
-            )
-            .with_upgrades()
-            .await
-        {
-            warn!("Error while serving HTTP connection: {}", http_err);
-        }
-    });
+    if let Err(http_err) = Http::new()
+        .http1_only(true)
+        .http1_keep_alive(true)
+        .serve_connection(
+            stream,
+            ProxyService {
+                src,
+                dispatcher,
+                authenticator,
+                clients: HashMap::new(),
+                inbound,
+            },
+        )
+        .with_upgrades()
+        .await
+    {
+        warn!("Error while serving HTTP connection: {}", http_err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderMap;
+
+    use super::strip_hop_by_hop_headers;
+
+    #[test]
+    fn test_strip_hop_by_hop_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", "keep-alive, X-Session".parse().unwrap());
+        headers.insert("x-session", "1".parse().unwrap());
+        headers.insert("proxy-authorization", "Basic dXNlcjpwYXNz".parse().unwrap());
+        headers.insert("proxy-connection", "keep-alive".parse().unwrap());
+        headers.insert("host", "example.com".parse().unwrap());
+        headers.insert("content-length", "0".parse().unwrap());
+
+        strip_hop_by_hop_headers(&mut headers);
+
+        assert_eq!(headers.len(), 2);
+        assert!(headers.contains_key("host"));
+        assert!(headers.contains_key("content-length"));
+    }
 }
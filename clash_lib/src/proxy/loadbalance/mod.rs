@@ -19,8 +19,8 @@ use crate::{
 use self::helpers::{strategy_consistent_hashring, strategy_rr, StrategyFn};
 
 use super::{
-    utils::provider_helper::get_proxies_from_providers, AnyOutboundHandler, AnyStream,
-    CommonOption, OutboundHandler, OutboundType,
+    utils::provider_helper::{get_proxies_from_providers, spill_over},
+    AnyOutboundHandler, AnyStream, CommonOption, OutboundHandler, OutboundType,
 };
 
 #[derive(Default, Clone)]
@@ -93,7 +93,8 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
         let proxies = self.get_proxies(false).await;
-        let proxy = (self.inner.lock().await.strategy_fn)(proxies, &sess).await?;
+        let proxy = (self.inner.lock().await.strategy_fn)(proxies.clone(), &sess).await?;
+        let proxy = spill_over(proxy, &proxies);
         debug!("{} use proxy {}", self.name(), proxy.name());
         match proxy.connect_stream(sess, resolver).await {
             Ok(s) => {
@@ -112,7 +113,8 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<AnyStream> {
         let proxies = self.get_proxies(false).await;
-        let proxy = (self.inner.lock().await.strategy_fn)(proxies, &sess).await?;
+        let proxy = (self.inner.lock().await.strategy_fn)(proxies.clone(), &sess).await?;
+        let proxy = spill_over(proxy, &proxies);
         debug!("{} use proxy {}", self.name(), proxy.name());
         proxy.proxy_stream(s, sess, resolver).await
     }
@@ -124,7 +126,8 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
         let proxies = self.get_proxies(false).await;
-        let proxy = (self.inner.lock().await.strategy_fn)(proxies, &sess).await?;
+        let proxy = (self.inner.lock().await.strategy_fn)(proxies.clone(), &sess).await?;
+        let proxy = spill_over(proxy, &proxies);
         debug!("{} use proxy {}", self.name(), proxy.name());
         proxy.connect_datagram(sess, resolver).await
     }
@@ -1,6 +1,6 @@
-use crate::common::auth::ThreadSafeAuthenticator;
+use crate::common::auth::{ThreadSafeAllowList, ThreadSafeAuthenticator};
 use crate::proxy::{AnyInboundListener, InboundListener};
-use crate::session::{Network, Session};
+use crate::session::{Inbound, Network, Session, Type};
 use crate::Dispatcher;
 use async_trait::async_trait;
 use std::net::SocketAddr;
@@ -9,13 +9,16 @@ use std::sync::Arc;
 use tokio::net::TcpListener;
 use tracing::warn;
 
-use super::utils::apply_tcp_options;
+use super::utils::{Acceptor, ConnectionLimiter};
 use super::{http, socks};
 
 pub struct Listener {
     addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    limiter: Option<ConnectionLimiter>,
+    allowlist: Option<ThreadSafeAllowList>,
+    inbound: Inbound,
 }
 
 impl Drop for Listener {
@@ -29,11 +32,17 @@ impl Listener {
         addr: SocketAddr,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        limiter: Option<ConnectionLimiter>,
+        allowlist: Option<ThreadSafeAllowList>,
+        inbound: Inbound,
     ) -> AnyInboundListener {
         Arc::new(Self {
             addr,
             dispatcher,
             authenticator,
+            limiter,
+            allowlist,
+            inbound,
         }) as _
     }
 }
@@ -50,40 +59,59 @@ impl InboundListener for Listener {
 
     async fn listen_tcp(&self) -> std::io::Result<()> {
         let listener = TcpListener::bind(self.addr).await?;
+        let mut acceptor = Acceptor::new(listener, self.limiter.clone(), self.allowlist.clone());
 
         loop {
-            let (socket, _) = listener.accept().await?;
-            let mut socket = apply_tcp_options(socket)?;
-
-            let mut p = [0; 1];
-            let n = socket.peek(&mut p).await?;
-            if n != 1 {
-                warn!("failed to peek socket on mixed listener {}", self.addr);
-                continue;
-            }
+            let (mut socket, src, permit) = acceptor.accept().await;
 
             let dispatcher = self.dispatcher.clone();
             let authenticator = self.authenticator.clone();
-
-            match p[0] {
-                socks::SOCKS5_VERSION => {
-                    let mut sess = Session {
-                        network: Network::Tcp,
-                        source: socket.peer_addr()?,
-
-                        ..Default::default()
-                    };
-
-                    tokio::spawn(async move {
-                        socks::handle_tcp(&mut sess, &mut socket, dispatcher, authenticator).await
-                    });
+            let addr = self.addr;
+            let inbound = self.inbound.clone();
+
+            tokio::spawn(async move {
+                let _permit = permit;
+
+                let mut p = [0; 1];
+                match socket.peek(&mut p).await {
+                    Ok(1) => {}
+                    _ => {
+                        warn!("failed to peek socket on mixed listener {}", addr);
+                        return;
+                    }
                 }
 
-                _ => {
-                    let src = socket.peer_addr()?;
-                    http::handle_http(Box::new(socket), src, dispatcher, authenticator).await;
+                match p[0] {
+                    socks::SOCKS4_VERSION | socks::SOCKS5_VERSION => {
+                        let mut sess = Session {
+                            network: Network::Tcp,
+                            typ: Type::Socks5,
+                            source: src,
+                            inbound,
+
+                            ..Default::default()
+                        };
+
+                        if let Err(e) =
+                            socks::handle_tcp(&mut sess, &mut socket, dispatcher, authenticator)
+                                .await
+                        {
+                            warn!("failed to handle socks connection from {}: {}", src, e);
+                        }
+                    }
+
+                    _ => {
+                        http::handle_http(
+                            Box::new(socket),
+                            src,
+                            dispatcher,
+                            authenticator,
+                            inbound,
+                        )
+                        .await;
+                    }
                 }
-            }
+            });
         }
     }
 
@@ -1,7 +1,7 @@
 use crate::app::dispatcher::{BoxedChainedDatagram, BoxedChainedStream};
 use crate::app::dns::ThreadSafeDNSResolver;
 use crate::proxy::datagram::UdpPacket;
-use crate::proxy::utils::Interface;
+use crate::proxy::utils::{new_tcp_stream, use_mptcp, DialerProxy, Interface};
 use crate::session::{Session, SocksAddr};
 use async_trait::async_trait;
 use erased_serde::Serialize as ESerialize;
@@ -11,6 +11,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::io;
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use tokio::io::AsyncRead;
@@ -21,18 +22,24 @@ pub mod reject;
 
 pub mod http;
 pub mod mixed;
+pub mod mux;
 
+pub mod anytls;
+pub mod capped;
 pub(crate) mod datagram;
 mod options;
 
+pub mod redir;
 #[cfg(feature = "shadowsocks")]
 pub mod shadowsocks;
 pub mod socks;
+pub mod tproxy;
 pub mod trojan;
 pub mod tun;
+pub mod uot;
 pub mod utils;
 pub mod vmess;
-//pub mod wg;
+pub mod wg;
 
 pub mod converters;
 
@@ -93,9 +100,61 @@ pub type AnyOutboundDatagram =
 
 #[derive(Default, Debug, Clone)]
 pub struct CommonOption {
-    #[allow(dead_code)]
     so_mark: Option<u32>,
     iface: Option<Interface>,
+    dialer_proxy: Option<DialerProxy>,
+    /// the global `mptcp` if not set
+    mptcp: Option<bool>,
+}
+
+impl CommonOption {
+    pub fn new(dialer_proxy: Option<String>) -> Self {
+        Self {
+            dialer_proxy: dialer_proxy.map(DialerProxy::new),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_mptcp(mut self, mptcp: Option<bool>) -> Self {
+        self.mptcp = mptcp;
+        self
+    }
+
+    /// dials from `bind_address`, taking the place of the interface
+    pub fn with_bind_address(mut self, bind_address: Option<IpAddr>) -> Self {
+        if let Some(ip) = bind_address {
+            self.iface = Some(Interface::IpAddr(ip));
+        }
+        self
+    }
+
+    pub fn dialer_proxy(&self) -> Option<&DialerProxy> {
+        self.dialer_proxy.as_ref()
+    }
+
+    /// dials the proxy server, through the `dialer-proxy` if there is one
+    pub async fn connect_stream(
+        &self,
+        resolver: ThreadSafeDNSResolver,
+        address: &str,
+        port: u16,
+    ) -> io::Result<AnyStream> {
+        match &self.dialer_proxy {
+            Some(dialer) => dialer.connect_stream(resolver, address, port).await,
+            None => {
+                new_tcp_stream(
+                    resolver,
+                    address,
+                    port,
+                    self.iface.as_ref(),
+                    use_mptcp(self.mptcp),
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    self.so_mark,
+                )
+                .await
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -116,6 +175,9 @@ pub enum OutboundType {
     Vmess,
     Trojan,
     WireGuard,
+    Socks5,
+    #[serde(rename = "AnyTLS")]
+    AnyTls,
 
     #[serde(rename = "URLTest")]
     UrlTest,
@@ -165,6 +227,17 @@ pub trait OutboundHandler: Sync + Send + Unpin {
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram>;
 
+    /// the outbound its connections are made through, see `dialer-proxy`
+    fn dialer_proxy(&self) -> Option<&DialerProxy> {
+        None
+    }
+
+    /// whether the outbound is at its `max-connections`,
+    /// groups spill over to their next member meanwhile
+    fn saturated(&self) -> bool {
+        false
+    }
+
     /// for API
     /// the map only contains basic information
     /// to populate history/liveness information, use the proxy_manager
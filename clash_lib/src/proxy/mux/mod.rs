@@ -0,0 +1,282 @@
+//! sing-mux compatible multiplexing for TCP based outbounds.
+
+mod session;
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use erased_serde::Serialize as ESerialize;
+use tracing::debug;
+
+use crate::{
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedStream, ChainedStreamWrapper,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    session::{Session, SocksAddr},
+};
+
+use self::session::{MuxSession, StreamSlot};
+
+use super::{utils::DialerProxy, AnyOutboundHandler, AnyStream, OutboundHandler, OutboundType};
+
+/// the destination sing-mux servers recognize as a mux session request
+const MUX_DESTINATION: &str = "sp.mux.sing-box.arpa";
+const MUX_DESTINATION_PORT: u16 = 444;
+/// how long dialing the server and starting a session may take
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MuxProtocol {
+    // smux = 0 is not supported
+    Yamux = 1,
+    H2Mux = 2,
+}
+
+impl TryFrom<&str> for MuxProtocol {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "yamux" => Ok(Self::Yamux),
+            "h2mux" => Ok(Self::H2Mux),
+            _ => Err(format!("unsupported mux protocol: {}", value)),
+        }
+    }
+}
+
+pub struct MuxOption {
+    pub protocol: MuxProtocol,
+    /// max number of underlying connections
+    pub max_connections: usize,
+    /// max number of streams on one connection before opening another one
+    pub max_streams: usize,
+}
+
+/// Wraps an outbound so that its TCP streams are multiplexed over
+/// a few long-lived connections to the server.
+pub struct Handler {
+    inner: AnyOutboundHandler,
+    opts: MuxOption,
+    sessions: Mutex<Sessions>,
+}
+
+#[derive(Default)]
+struct Sessions {
+    open: Vec<Arc<MuxSession>>,
+    /// the sessions being dialed, counted against max-connections
+    dialing: usize,
+}
+
+/// a session being dialed, until the dial is done or given up
+struct Dialing<'a>(&'a Mutex<Sessions>);
+
+impl Drop for Dialing<'_> {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().dialing -= 1;
+    }
+}
+
+impl Handler {
+    pub fn new(inner: AnyOutboundHandler, opts: MuxOption) -> AnyOutboundHandler {
+        Arc::new(Self {
+            inner,
+            opts,
+            sessions: Mutex::new(Sessions::default()),
+        })
+    }
+
+    /// the session to open a stream on, with the stream already counted
+    /// in it so that concurrent callers see it. The lock isn't held while
+    /// a new session is dialed, the streams the open sessions take don't
+    /// wait on a slow server
+    async fn get_session(
+        &self,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<(Arc<MuxSession>, StreamSlot)> {
+        let _dialing = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.open.retain(|s| !s.is_closed());
+
+            let least_used = sessions
+                .open
+                .iter()
+                .min_by_key(|s| s.num_streams())
+                .cloned();
+            if let Some(s) = least_used {
+                if s.num_streams() < self.opts.max_streams
+                    || sessions.open.len() + sessions.dialing >= self.opts.max_connections
+                {
+                    let slot = s.reserve();
+                    return Ok((s, slot));
+                }
+            }
+            sessions.dialing += 1;
+            Dialing(&self.sessions)
+        };
+
+        let session = tokio::time::timeout(HANDSHAKE_TIMEOUT, self.dial(resolver))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "mux handshake timed out"))??;
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.open.push(session.clone());
+        debug!(
+            "new {:?} session for {}, {} sessions in total",
+            self.opts.protocol,
+            self.name(),
+            sessions.open.len()
+        );
+        let slot = session.reserve();
+        Ok((session, slot))
+    }
+
+    async fn dial(&self, resolver: ThreadSafeDNSResolver) -> io::Result<Arc<MuxSession>> {
+        let sess = Session {
+            destination: SocksAddr::Domain(MUX_DESTINATION.to_owned(), MUX_DESTINATION_PORT),
+            ..Default::default()
+        };
+        let stream = self.inner.connect_stream(&sess, resolver).await?;
+        Ok(Arc::new(
+            MuxSession::new(Box::new(stream), self.opts.protocol).await?,
+        ))
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn proto(&self) -> OutboundType {
+        self.inner.proto()
+    }
+
+    fn dialer_proxy(&self) -> Option<&DialerProxy> {
+        self.inner.dialer_proxy()
+    }
+
+    async fn remote_addr(&self) -> Option<SocksAddr> {
+        self.inner.remote_addr().await
+    }
+
+    async fn support_udp(&self) -> bool {
+        self.inner.support_udp().await
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        let (session, slot) = self.get_session(resolver).await?;
+        let s = session.open_stream(slot, &sess.destination, false).await?;
+
+        let chained = ChainedStreamWrapper::new(s);
+        chained.append_to_chain(self.name()).await;
+        Ok(Box::new(chained))
+    }
+
+    /// streams chained through a relay are not multiplexed
+    async fn proxy_stream(
+        &self,
+        s: AnyStream,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<AnyStream> {
+        self.inner.proxy_stream(s, sess, resolver).await
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        self.inner.connect_datagram(sess, resolver).await
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        self.inner.as_map().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{
+        app::{
+            dispatcher::ChainedStreamWrapper,
+            dns::{MockClashResolver, ThreadSafeDNSResolver},
+        },
+        proxy::mocks::MockDummyOutboundHandler,
+    };
+
+    use super::{Handler, MuxOption, MuxProtocol};
+
+    /// a handler over servers of which only the first one reads the session
+    /// request, the handshakes with the others hang
+    fn handler() -> Handler {
+        let servers = Mutex::new(vec![]);
+        let mut mock = MockDummyOutboundHandler::new();
+        mock.expect_name().return_const("node".to_owned());
+        mock.expect_connect_stream().returning(move |_, _| {
+            let mut servers = servers.lock().unwrap();
+            let size = if servers.is_empty() { 1024 } else { 1 };
+            let (client, server) = tokio::io::duplex(size);
+            servers.push(server);
+            Ok(Box::new(ChainedStreamWrapper::new(client)))
+        });
+
+        Handler {
+            inner: Arc::new(mock),
+            opts: MuxOption {
+                protocol: MuxProtocol::Yamux,
+                max_connections: 2,
+                max_streams: 1,
+            },
+            sessions: Default::default(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dial_outside_lock() {
+        let h = Arc::new(handler());
+        let resolver: ThreadSafeDNSResolver = Arc::new(MockClashResolver::new());
+
+        let (first, slot) = h.get_session(resolver.clone()).await.unwrap();
+        // the first session is full, a second one is dialed
+        let dialing = tokio::spawn({
+            let h = h.clone();
+            let resolver = resolver.clone();
+            async move { h.get_session(resolver).await.map(|_| ()) }
+        });
+        while h.sessions.lock().unwrap().dialing == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // the first session takes streams again while the dial hangs
+        drop(slot);
+        let (session, _slot) = h.get_session(resolver).await.unwrap();
+        assert!(Arc::ptr_eq(&session, &first));
+
+        assert_eq!(
+            dialing.await.unwrap().unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+        let sessions = h.sessions.lock().unwrap();
+        assert_eq!(sessions.dialing, 0);
+        assert_eq!(sessions.open.len(), 1);
+    }
+}
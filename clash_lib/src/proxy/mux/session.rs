@@ -0,0 +1,325 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::future::poll_fn;
+use h2::client::SendRequest;
+use http::{Method, Request, StatusCode};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{mpsc, oneshot},
+};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tracing::debug;
+
+use crate::{
+    common::errors::{map_io_error, new_io_error},
+    proxy::{transport::Http2Stream, AnyStream},
+    session::SocksAddr,
+};
+
+use super::MuxProtocol;
+
+const FLAG_UDP: u16 = 1;
+
+type YamuxStreamRequest = oneshot::Sender<io::Result<yamux::Stream>>;
+
+enum Inner {
+    Yamux(mpsc::UnboundedSender<YamuxStreamRequest>),
+    H2(SendRequest<Bytes>),
+}
+
+/// One multiplexed connection to the server, speaking the sing-mux protocol.
+pub struct MuxSession {
+    inner: Inner,
+    streams: Arc<AtomicUsize>,
+}
+
+impl MuxSession {
+    /// writes the session request on `stream` and starts the multiplexer on it
+    pub async fn new(mut stream: AnyStream, protocol: MuxProtocol) -> io::Result<Self> {
+        // version 0, no padding
+        stream.write_all(&[0, protocol as u8]).await?;
+
+        let streams = Arc::new(AtomicUsize::new(0));
+        let inner = match protocol {
+            MuxProtocol::Yamux => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                tokio::spawn(drive_yamux(stream, rx, streams.clone()));
+                Inner::Yamux(tx)
+            }
+            MuxProtocol::H2Mux => {
+                let (client, conn) = h2::client::handshake(stream).await.map_err(map_io_error)?;
+                tokio::spawn(async move {
+                    if let Err(e) = conn.await {
+                        debug!("h2mux connection closed: {}", e);
+                    }
+                });
+                Inner::H2(client)
+            }
+        };
+
+        Ok(Self { inner, streams })
+    }
+
+    pub fn num_streams(&self) -> usize {
+        self.streams.load(Ordering::Relaxed)
+    }
+
+    /// counts a stream about to be opened, so that the sessions are picked
+    /// by the streams they will have rather than the ones already open
+    pub fn reserve(&self) -> StreamSlot {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        StreamSlot(self.streams.clone())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        match &self.inner {
+            Inner::Yamux(tx) => tx.is_closed(),
+            Inner::H2(client) => {
+                let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+                matches!(client.clone().poll_ready(&mut cx), Poll::Ready(Err(_)))
+            }
+        }
+    }
+
+    /// opens a new stream to `destination` on this session, in the `slot`
+    /// reserved for it
+    pub async fn open_stream(
+        &self,
+        slot: StreamSlot,
+        destination: &SocksAddr,
+        udp: bool,
+    ) -> io::Result<AnyStream> {
+        let mut stream: AnyStream = match &self.inner {
+            Inner::Yamux(tx) => {
+                let (stream_tx, stream_rx) = oneshot::channel();
+                tx.send(stream_tx)
+                    .map_err(|_| new_io_error("mux session closed"))?;
+                let s = stream_rx
+                    .await
+                    .map_err(|_| new_io_error("mux session closed"))??;
+                Box::new(FuturesAsyncReadCompatExt::compat(s))
+            }
+            Inner::H2(client) => {
+                let mut client = client.clone().ready().await.map_err(map_io_error)?;
+                let req = Request::builder()
+                    .method(Method::CONNECT)
+                    .uri("https://localhost")
+                    .body(())
+                    .map_err(map_io_error)?;
+                let (resp, send) = client.send_request(req, false).map_err(map_io_error)?;
+                let resp = resp.await.map_err(map_io_error)?;
+                if resp.status() != StatusCode::OK {
+                    return Err(new_io_error(
+                        format!("h2mux stream rejected: {}", resp.status()).as_str(),
+                    ));
+                }
+                Box::new(Http2Stream::new(resp.into_body(), send))
+            }
+        };
+
+        stream.write_all(&stream_request(destination, udp)).await?;
+
+        Ok(Box::new(MuxStream {
+            inner: stream,
+            response_read: false,
+            _slot: slot,
+        }))
+    }
+}
+
+/*
++-------+----------+-----------+-------------+
+| FLAGS |   ATYP   |   ADDR    |    PORT     |
++-------+----------+-----------+-------------+
+|   2   |    1     | Variable  |      2      |
++-------+----------+-----------+-------------+
+the destination is a SOCKS5 address, as sing-mux reads it
+*/
+fn stream_request(destination: &SocksAddr, udp: bool) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(2 + destination.size());
+    buf.put_u16(if udp { FLAG_UDP } else { 0 });
+    destination.write_buf(&mut buf);
+    buf
+}
+
+/// a stream of a session, counted until it is dropped
+pub struct StreamSlot(Arc<AtomicUsize>);
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// drives the yamux connection, serving stream open requests
+/// until the session is dropped and all its streams are closed
+async fn drive_yamux(
+    stream: AnyStream,
+    mut rx: mpsc::UnboundedReceiver<YamuxStreamRequest>,
+    streams: Arc<AtomicUsize>,
+) {
+    let mut conn = yamux::Connection::new(
+        TokioAsyncReadCompatExt::compat(stream),
+        yamux::Config::default(),
+        yamux::Mode::Client,
+    );
+    let mut pending: VecDeque<YamuxStreamRequest> = VecDeque::new();
+    let mut closing = false;
+
+    let rv = poll_fn(|cx| {
+        while !closing {
+            match rx.poll_recv(cx) {
+                Poll::Ready(Some(req)) => pending.push_back(req),
+                Poll::Ready(None) => closing = true,
+                Poll::Pending => break,
+            }
+        }
+
+        while !pending.is_empty() {
+            match conn.poll_new_outbound(cx) {
+                Poll::Ready(Ok(s)) => {
+                    let _ = pending.pop_front().unwrap().send(Ok(s));
+                }
+                Poll::Ready(Err(e)) => {
+                    for req in pending.drain(..) {
+                        let _ = req.send(Err(map_io_error(&e)));
+                    }
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        if closing && streams.load(Ordering::Relaxed) == 0 {
+            return conn.poll_close(cx);
+        }
+
+        loop {
+            match conn.poll_next_inbound(cx) {
+                // the server is not supposed to open streams
+                Poll::Ready(Some(Ok(_))) => continue,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    })
+    .await;
+
+    if let Err(e) = rv {
+        debug!("yamux connection closed: {}", e);
+    }
+}
+
+/// A stream on a mux session. The server status is read before any data.
+struct MuxStream {
+    inner: AnyStream,
+    response_read: bool,
+    _slot: StreamSlot,
+}
+
+impl Debug for MuxStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MuxStream")
+            .field("inner", &self.inner)
+            .field("response_read", &self.response_read)
+            .finish()
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.response_read {
+            let mut status = [0u8; 1];
+            let mut status_buf = ReadBuf::new(&mut status);
+            futures::ready!(Pin::new(&mut self.inner).poll_read(cx, &mut status_buf))?;
+            if status_buf.filled().is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            if status[0] != 0 {
+                return Poll::Ready(Err(new_io_error("mux stream rejected by server")));
+            }
+            self.response_read = true;
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use crate::session::SocksAddr;
+
+    use super::{stream_request, Inner, MuxSession};
+
+    #[test]
+    fn test_stream_request() {
+        let v4 = SocksAddr::Ip("1.2.3.4:443".parse().unwrap());
+        assert_eq!(
+            &stream_request(&v4, false)[..],
+            [0, 0, 1, 1, 2, 3, 4, 1, 187]
+        );
+
+        let v6 = SocksAddr::Ip("[::1]:53".parse().unwrap());
+        let mut expected = vec![0, 1, 4];
+        expected.extend([0; 15]);
+        expected.extend([1, 0, 53]);
+        assert_eq!(&stream_request(&v6, true)[..], expected);
+
+        let domain = SocksAddr::Domain("example.com".to_owned(), 80);
+        let mut expected = vec![0, 0, 3, 11];
+        expected.extend(b"example.com");
+        expected.extend([0, 80]);
+        assert_eq!(&stream_request(&domain, false)[..], expected);
+    }
+
+    #[test]
+    fn test_stream_slot() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let session = MuxSession {
+            inner: Inner::Yamux(tx),
+            streams: Default::default(),
+        };
+        let a = session.reserve();
+        let b = session.reserve();
+        assert_eq!(session.num_streams(), 2);
+        drop(a);
+        assert_eq!(session.num_streams(), 1);
+        drop(b);
+        assert_eq!(session.num_streams(), 0);
+    }
+}
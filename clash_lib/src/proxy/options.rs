@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use super::transport::CongestionController;
+
 pub struct HttpOption {
     pub method: String,
     pub path: Vec<String>,
@@ -21,3 +23,8 @@ pub struct WsOption {
     pub max_early_data: usize,
     pub early_data_header_name: String,
 }
+
+pub struct QuicOption {
+    pub congestion_controller: CongestionController,
+    pub zero_rtt: bool,
+}
@@ -0,0 +1,133 @@
+//! `redir-port`: accepts TCP connections redirected by iptables/nftables
+//! `REDIRECT` rules and proxies them to their original destination,
+//! for hosts that can't use TUN.
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+use crate::{
+    common::auth::ThreadSafeAllowList,
+    proxy::{
+        utils::{Acceptor, ConnectionLimiter},
+        AnyInboundListener, InboundListener,
+    },
+    session::{Inbound, Network, Session, Type},
+    Dispatcher,
+};
+
+#[derive(Clone)]
+pub struct Listener {
+    addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+    limiter: Option<ConnectionLimiter>,
+    allowlist: Option<ThreadSafeAllowList>,
+    inbound: Inbound,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        warn!("Redir inbound listener on {} stopped", self.addr);
+    }
+}
+
+impl Listener {
+    pub fn new(
+        addr: SocketAddr,
+        dispatcher: Arc<Dispatcher>,
+        limiter: Option<ConnectionLimiter>,
+        allowlist: Option<ThreadSafeAllowList>,
+        inbound: Inbound,
+    ) -> AnyInboundListener {
+        Arc::new(Self {
+            addr,
+            dispatcher,
+            limiter,
+            allowlist,
+            inbound,
+        }) as _
+    }
+}
+
+/// the destination a connection had before it was redirected,
+/// read with `SO_ORIGINAL_DST`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn original_dst(s: &TcpStream) -> io::Result<SocketAddr> {
+    let sock = socket2::SockRef::from(s);
+    let dst = if s.local_addr()?.is_ipv4() {
+        sock.original_dst()?
+    } else {
+        sock.original_dst_ipv6()?
+    };
+    dst.as_socket()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "invalid original destination"))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn original_dst(_: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[async_trait]
+impl InboundListener for Listener {
+    fn handle_tcp(&self) -> bool {
+        true
+    }
+
+    fn handle_udp(&self) -> bool {
+        false
+    }
+
+    async fn listen_tcp(&self) -> io::Result<()> {
+        if cfg!(not(any(target_os = "linux", target_os = "android"))) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "redir is only supported on Linux",
+            ));
+        }
+
+        let listener = TcpListener::bind(self.addr).await?;
+        let mut acceptor = Acceptor::new(listener, self.limiter.clone(), self.allowlist.clone());
+
+        loop {
+            let (mut socket, src_addr, permit) = acceptor.accept().await;
+
+            let dst = match original_dst(&socket) {
+                Ok(dst) => dst,
+                Err(e) => {
+                    warn!("failed to get original destination of {}: {}", src_addr, e);
+                    continue;
+                }
+            };
+            // not redirected, a connection to the redir port itself
+            if socket.local_addr().is_ok_and(|x| x == dst) {
+                debug!("dropping unredirected connection from {}", src_addr);
+                continue;
+            }
+
+            let sess = Session {
+                network: Network::Tcp,
+                typ: Type::Redir,
+                source: src_addr,
+                destination: dst.into(),
+                inbound: self.inbound.clone(),
+
+                ..Default::default()
+            };
+
+            let dispatcher = self.dispatcher.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                if dispatcher.dispatch_stream(sess, &mut socket).await {
+                    let _ = socket.set_linger(Some(Duration::ZERO));
+                }
+            });
+        }
+    }
+
+    async fn listen_udp(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "unsupported"))
+    }
+}
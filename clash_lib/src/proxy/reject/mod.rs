@@ -1,28 +1,38 @@
-use crate::app::dispatcher::{BoxedChainedDatagram, BoxedChainedStream};
+use crate::app::dispatcher::{
+    BoxedChainedDatagram, BoxedChainedStream, ChainedStream, ChainedStreamWrapper,
+};
 use crate::app::dns::ThreadSafeDNSResolver;
-use crate::config::internal::proxy::PROXY_REJECT;
+use crate::config::internal::proxy::RejectMode;
 use crate::proxy::{AnyOutboundHandler, AnyStream, OutboundHandler};
 use crate::session::{Session, SocksAddr};
 use async_trait::async_trait;
-use serde::Serialize;
 use std::io;
 use std::sync::Arc;
 
+use self::stream::RejectStream;
+
 use super::OutboundType;
 
-#[derive(Serialize)]
-pub struct Handler;
+mod stream;
+
+pub struct Handler {
+    mode: RejectMode,
+}
 
 impl Handler {
-    pub fn new() -> AnyOutboundHandler {
-        Arc::new(Self)
+    pub fn new(mode: RejectMode) -> AnyOutboundHandler {
+        Arc::new(Self { mode })
+    }
+
+    fn reject_error(&self) -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, self.mode.name())
     }
 }
 
 #[async_trait]
 impl OutboundHandler for Handler {
     fn name(&self) -> &str {
-        PROXY_REJECT
+        self.mode.name()
     }
 
     fn proto(&self) -> OutboundType {
@@ -42,7 +52,14 @@ impl OutboundHandler for Handler {
         #[allow(unused_variables)] sess: &Session,
         #[allow(unused_variables)] _resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        Err(io::Error::new(io::ErrorKind::Other, "REJECT"))
+        let s = match self.mode {
+            RejectMode::Reset => return Err(self.reject_error()),
+            RejectMode::Drop => RejectStream::hold(),
+            RejectMode::Http => RejectStream::http(),
+        };
+        let chained = ChainedStreamWrapper::new(s);
+        chained.append_to_chain(self.name()).await;
+        Ok(Box::new(chained))
     }
 
     async fn proxy_stream(
@@ -51,7 +68,7 @@ impl OutboundHandler for Handler {
         #[allow(unused_variables)] sess: &Session,
         #[allow(unused_variables)] _resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<AnyStream> {
-        Err(io::Error::new(io::ErrorKind::Other, "REJECT"))
+        Err(self.reject_error())
     }
 
     async fn connect_datagram(
@@ -59,6 +76,6 @@ impl OutboundHandler for Handler {
         #[allow(unused_variables)] sess: &Session,
         #[allow(unused_variables)] _resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
-        Err(io::Error::new(io::ErrorKind::Other, "REJECT"))
+        Err(self.reject_error())
     }
 }
@@ -0,0 +1,179 @@
+use std::{
+    fmt::Debug,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+/// how long REJECT-DROP holds a connection before closing it
+const DROP_HOLD: Duration = Duration::from_secs(30);
+
+const HTTP_METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"HEAD ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+const HTTP_FORBIDDEN: &[u8] = b"HTTP/1.1 403 Forbidden\r\n\
+Content-Type: text/plain\r\n\
+Content-Length: 24\r\n\
+Connection: close\r\n\
+\r\n\
+blocked by clash-rs rule";
+
+/// fatal `access_denied` alert record
+const TLS_ACCESS_DENIED: &[u8] = &[0x15, 0x03, 0x01, 0x00, 0x02, 0x02, 0x31];
+
+enum State {
+    /// REJECT-DROP: discard everything until the hold expires
+    Hold(Pin<Box<Sleep>>),
+    /// REJECT-HTTP: waiting for the first bytes from the client
+    Sniff(Option<Waker>),
+    /// sending the canned response, then EOF
+    Respond(&'static [u8]),
+}
+
+/// The stream behind the REJECT-DROP and REJECT-HTTP outbounds,
+/// it never dials anything.
+pub struct RejectStream {
+    state: State,
+}
+
+impl Debug for RejectStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RejectStream").finish()
+    }
+}
+
+impl RejectStream {
+    pub fn hold() -> Self {
+        Self {
+            state: State::Hold(Box::pin(tokio::time::sleep(DROP_HOLD))),
+        }
+    }
+
+    pub fn http() -> Self {
+        Self {
+            state: State::Sniff(None),
+        }
+    }
+}
+
+/// a 403 page for plain HTTP, an alert for TLS and nothing for anything else
+fn response_for(buf: &[u8]) -> &'static [u8] {
+    if buf.first() == Some(&0x16) {
+        TLS_ACCESS_DENIED
+    } else if HTTP_METHODS.iter().any(|m| buf.starts_with(m)) {
+        HTTP_FORBIDDEN
+    } else {
+        &[]
+    }
+}
+
+impl AsyncRead for RejectStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.state {
+            State::Hold(sleep) => {
+                futures::ready!(sleep.as_mut().poll(cx));
+                self.state = State::Respond(&[]);
+                Poll::Ready(Ok(()))
+            }
+            State::Sniff(waker) => {
+                *waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            State::Respond(resp) => {
+                let n = resp.len().min(buf.remaining());
+                buf.put_slice(&resp[..n]);
+                *resp = &resp[n..];
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+impl AsyncWrite for RejectStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let State::Sniff(waker) = &mut self.state {
+            if let Some(waker) = waker.take() {
+                waker.wake();
+            }
+            self.state = State::Respond(response_for(buf));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // the client is done, stop holding or sniffing
+        match &mut self.state {
+            State::Hold(_) => self.state = State::Respond(&[]),
+            State::Sniff(waker) => {
+                if let Some(waker) = waker.take() {
+                    waker.wake();
+                }
+                self.state = State::Respond(&[]);
+            }
+            State::Respond(_) => {}
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{RejectStream, HTTP_FORBIDDEN, TLS_ACCESS_DENIED};
+
+    #[tokio::test]
+    async fn test_reject_http() {
+        let mut s = RejectStream::http();
+        s.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        s.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, HTTP_FORBIDDEN);
+
+        let mut s = RejectStream::http();
+        s.write_all(&[0x16, 0x03, 0x01, 0x00, 0x10]).await.unwrap();
+        let mut buf = vec![];
+        s.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, TLS_ACCESS_DENIED);
+    }
+
+    #[tokio::test]
+    async fn test_reject_drop() {
+        let mut s = RejectStream::hold();
+        s.write_all(b"anything").await.unwrap();
+        s.shutdown().await.unwrap();
+        let mut buf = vec![];
+        s.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+    }
+}
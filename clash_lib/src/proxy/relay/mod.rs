@@ -13,7 +13,7 @@ use crate::{
         remote_content_manager::providers::proxy_provider::ThreadSafeProxyProvider,
     },
     common::errors::new_io_error,
-    proxy::utils::new_tcp_stream,
+    proxy::utils::{new_tcp_stream, use_mptcp},
     session::{Session, SocksAddr},
 };
 
@@ -96,6 +96,7 @@ impl OutboundHandler for Handler {
                     remote_addr.host().as_str(),
                     remote_addr.port(),
                     None,
+                    use_mptcp(None),
                     #[cfg(any(target_os = "linux", target_os = "android"))]
                     None,
                 )
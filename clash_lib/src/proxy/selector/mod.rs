@@ -54,9 +54,11 @@ impl Handler {
         providers: Vec<ThreadSafeProxyProvider>,
         seleted: Option<String>,
     ) -> Self {
-        let provider = providers.first().unwrap();
-        let proxies = provider.read().await.proxies().await;
-        let current = proxies.first().unwrap().name().to_owned();
+        let proxies = get_proxies_from_providers(&providers, false).await;
+        let current = proxies
+            .first()
+            .map(|x| x.name().to_owned())
+            .unwrap_or_default();
 
         Self {
             opts,
@@ -69,13 +71,22 @@ impl Handler {
 
     async fn selected_proxy(&self, touch: bool) -> AnyOutboundHandler {
         let proxies = get_proxies_from_providers(&self.providers, touch).await;
-        for proxy in proxies {
-            if proxy.name() == self.inner.read().await.current {
+        let current = self.inner.read().await.current.clone();
+        for proxy in proxies.iter() {
+            if proxy.name() == current {
                 p_debug!("{} selected {}", self.name(), proxy.name());
-                return proxy;
+                return proxy.clone();
             }
         }
-        unreachable!("selected proxy not found")
+        // the selected proxy may have been removed at runtime
+        let proxy = proxies.first().expect("selector has no proxies").clone();
+        debug!(
+            "{} selected {} not found, falling back to {}",
+            self.name(),
+            current,
+            proxy.name()
+        );
+        proxy
     }
 }
 
@@ -1,20 +1,33 @@
 use std::{
+    fmt::Debug,
     io,
     pin::Pin,
     task::{Context, Poll},
 };
 
-use futures::{ready, Sink, Stream};
-use shadowsocks::ProxySocket;
+use bytes::BytesMut;
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use shadowsocks::{
+    context::SharedContext,
+    crypto::CipherKind,
+    relay::udprelay::{
+        crypto_io::{decrypt_server_payload, encrypt_client_payload},
+        options::UdpSocketControlData,
+    },
+    ProxySocket, ServerConfig,
+};
 use tokio::io::ReadBuf;
 use tracing::{debug, instrument, trace};
 
 use crate::{
-    app::dns::ThreadSafeDNSResolver,
+    app::{dispatcher::BoxedChainedDatagram, dns::ThreadSafeDNSResolver},
+    common::nat64,
     proxy::{datagram::UdpPacket, AnyOutboundDatagram},
     session::SocksAddr,
 };
 
+use super::inbound::to_socks_addr;
+
 #[must_use = "sinks do nothing unless polled"]
 pub struct OutboundDatagramShadowsocks {
     inner: ProxySocket,
@@ -101,6 +114,7 @@ impl Sink<UdpPacket> for OutboundDatagramShadowsocks {
             }
             SocksAddr::Ip(addr) => addr,
         };
+        let dst = nat64::translate(dst);
 
         let pkt_container = pkt;
 
@@ -170,3 +184,99 @@ impl Stream for OutboundDatagramShadowsocks {
         }
     }
 }
+
+/// the packets to a shadowsocks server reached through a `dialer-proxy`,
+/// encrypted here and carried to the server by the datagram of the dialer
+pub struct OutboundDatagramShadowsocksDetour {
+    inner: BoxedChainedDatagram,
+    context: SharedContext,
+    method: CipherKind,
+    key: Box<[u8]>,
+    server: SocksAddr,
+}
+
+impl OutboundDatagramShadowsocksDetour {
+    pub fn new(
+        inner: BoxedChainedDatagram,
+        context: SharedContext,
+        cfg: &ServerConfig,
+        server: SocksAddr,
+    ) -> Self {
+        Self {
+            inner,
+            context,
+            method: cfg.method(),
+            key: cfg.key().into(),
+            server,
+        }
+    }
+}
+
+impl Debug for OutboundDatagramShadowsocksDetour {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutboundDatagramShadowsocksDetour")
+            .field("server", &self.server)
+            .finish()
+    }
+}
+
+impl Sink<UdpPacket> for OutboundDatagramShadowsocksDetour {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+        let addr: shadowsocks::relay::Address = (item.dst_addr.host(), item.dst_addr.port()).into();
+        let mut buf = BytesMut::new();
+        encrypt_client_payload(
+            &self.context,
+            self.method,
+            &self.key,
+            &addr,
+            &UdpSocketControlData::default(),
+            &[],
+            &item.data,
+            &mut buf,
+        );
+        let server = self.server.clone();
+        self.inner.start_send_unpin(UdpPacket {
+            data: buf.to_vec(),
+            src_addr: item.src_addr,
+            dst_addr: server,
+        })
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+impl Stream for OutboundDatagramShadowsocksDetour {
+    type Item = UdpPacket;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let mut pkt = match ready!(self.inner.poll_next_unpin(cx)) {
+                Some(pkt) => pkt,
+                None => return Poll::Ready(None),
+            };
+            match decrypt_server_payload(&self.context, self.method, &self.key, &mut pkt.data) {
+                Ok((n, src, _)) => {
+                    pkt.data.truncate(n);
+                    return Poll::Ready(Some(UdpPacket {
+                        data: pkt.data,
+                        src_addr: to_socks_addr(src),
+                        dst_addr: SocksAddr::any_ipv4(),
+                    }));
+                }
+                Err(e) => debug!("failed to read shadowsocks udp packet: {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use base64::Engine;
+use lru_time_cache::LruCache;
+use shadowsocks::{
+    config::ServerType,
+    context::{Context, SharedContext},
+    crypto::{CipherCategory, CipherKind},
+    net::AcceptOpts,
+    relay::{
+        tcprelay::ProxyServerStream,
+        udprelay::{options::UdpSocketControlData, proxy_socket::UdpSocketType},
+        Address,
+    },
+    ProxyListener, ProxySocket, ServerConfig,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UdpSocket},
+};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    common::auth::ThreadSafeAllowList,
+    config::internal::listener::InboundShadowsocks,
+    proxy::{
+        datagram::UdpPacket, tun::datagram::TunDatagram, utils::ConnectionLimiter,
+        AnyInboundListener, InboundListener,
+    },
+    session::{Inbound, Network, Session, SocksAddr, Type},
+    Dispatcher,
+};
+
+/// how long a UDP client is remembered without sending anything
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(120);
+/// how long a client has to send its request header once connected
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// how long accepting waits after failing, so that running out of file
+/// descriptors doesn't spin
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serves the shadowsocks protocol, the server side of a `ss` outbound.
+pub struct Listener {
+    addr: SocketAddr,
+    name: String,
+    cipher: String,
+    password: String,
+    udp: bool,
+    dispatcher: Arc<Dispatcher>,
+    limiter: Option<ConnectionLimiter>,
+    allowlist: Option<ThreadSafeAllowList>,
+    inbound: Inbound,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        warn!(
+            "Shadowsocks inbound listener {} on {} stopped",
+            self.name, self.addr
+        );
+    }
+}
+
+impl Listener {
+    pub fn new(
+        addr: SocketAddr,
+        opts: &InboundShadowsocks,
+        dispatcher: Arc<Dispatcher>,
+        limiter: Option<ConnectionLimiter>,
+        allowlist: Option<ThreadSafeAllowList>,
+    ) -> AnyInboundListener {
+        Arc::new(Self {
+            addr,
+            name: opts.name.clone(),
+            cipher: opts.cipher.clone(),
+            password: opts.password.expose().to_owned(),
+            udp: opts.udp,
+            dispatcher,
+            limiter,
+            allowlist,
+            inbound: Inbound {
+                name: opts.name.clone(),
+                port: Some(addr.port()),
+                mode: opts.mode,
+            },
+        }) as _
+    }
+
+    fn server_config(&self) -> io::Result<(SharedContext, ServerConfig)> {
+        let invalid = |msg: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("shadowsocks listener {}: {}", self.name, msg),
+            )
+        };
+
+        let method = match self.cipher.as_str() {
+            "aes-128-gcm" => CipherKind::AES_128_GCM,
+            "aes-256-gcm" => CipherKind::AES_256_GCM,
+            "chacha20-ietf-poly1305" => CipherKind::CHACHA20_POLY1305,
+            "2022-blake3-aes-128-gcm" => CipherKind::AEAD2022_BLAKE3_AES_128_GCM,
+            "2022-blake3-aes-256-gcm" => CipherKind::AEAD2022_BLAKE3_AES_256_GCM,
+            "2022-blake3-chacha20-poly1305" => CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305,
+            cipher => return Err(invalid(format!("unsupported cipher {}", cipher))),
+        };
+        // checked here as the key would otherwise panic in `ServerConfig::new`
+        if matches!(method.category(), CipherCategory::Aead2022) {
+            let key = base64::engine::general_purpose::STANDARD
+                .decode(&self.password)
+                .map_err(|_| invalid("the password must be a base64 key".to_owned()))?;
+            if key.len() != method.key_len() {
+                return Err(invalid(format!(
+                    "the key must be {} bytes for {}",
+                    method.key_len(),
+                    self.cipher
+                )));
+            }
+        }
+
+        Ok((
+            Context::new_shared(ServerType::Server),
+            ServerConfig::new(self.addr, self.password.clone(), method),
+        ))
+    }
+}
+
+/// reads the request header of `stream`, which verifies the client's key
+/// and rejects replayed salts
+async fn handshake<S>(stream: &mut ProxyServerStream<S>) -> io::Result<Address>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.handshake())
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))?
+}
+
+pub(super) fn to_socks_addr(addr: Address) -> SocksAddr {
+    match addr {
+        Address::SocketAddress(addr) => SocksAddr::Ip(addr),
+        Address::DomainNameAddress(host, port) => SocksAddr::Domain(host, port),
+    }
+}
+
+#[async_trait]
+impl InboundListener for Listener {
+    fn handle_tcp(&self) -> bool {
+        true
+    }
+
+    fn handle_udp(&self) -> bool {
+        self.udp
+    }
+
+    async fn listen_tcp(&self) -> io::Result<()> {
+        let (context, cfg) = self.server_config()?;
+        // server streams are only made by the listener of the crate, so the
+        // limit and the allowlist are checked here rather than by an
+        // `Acceptor`
+        let mut opts = AcceptOpts::default();
+        opts.tcp.keepalive = Some(Duration::from_secs(10));
+        let listener = ProxyListener::from_listener(
+            context,
+            shadowsocks::net::TcpListener::from_listener(TcpListener::bind(self.addr).await?, opts),
+            &cfg,
+        );
+
+        loop {
+            let permit = match self.limiter.as_ref() {
+                Some(limiter) => Some(
+                    limiter
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("connection limiter closed"),
+                ),
+                None => None,
+            };
+            let (mut stream, src_addr) = match listener.accept().await {
+                Ok(x) => x,
+                Err(e) => {
+                    debug!("failed to accept shadowsocks connection: {}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            if self
+                .allowlist
+                .as_ref()
+                .is_some_and(|x| !x.allows(src_addr.ip()))
+            {
+                debug!("refused connection from {}", src_addr);
+                continue;
+            }
+
+            let dispatcher = self.dispatcher.clone();
+            let inbound = self.inbound.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                let target = match handshake(&mut stream).await {
+                    Ok(target) => target,
+                    Err(e) => {
+                        debug!("shadowsocks handshake with {} failed: {}", src_addr, e);
+                        return;
+                    }
+                };
+
+                let sess = Session {
+                    network: Network::Tcp,
+                    typ: Type::Shadowsocks,
+                    source: src_addr,
+                    destination: to_socks_addr(target),
+                    inbound,
+
+                    ..Default::default()
+                };
+
+                dispatcher.dispatch_stream(sess, stream).await;
+            });
+        }
+    }
+
+    async fn listen_udp(&self) -> io::Result<()> {
+        let (context, cfg) = self.server_config()?;
+        let socket = UdpSocket::bind(self.addr).await?;
+        let socket = Arc::new(ProxySocket::from_socket(
+            UdpSocketType::Server,
+            context,
+            &cfg,
+            socket,
+        ));
+
+        // the session state of each client, 2022 ciphers need it for replies
+        let sessions: Arc<Mutex<LruCache<SocketAddr, UdpSocketControlData>>> = Arc::new(
+            Mutex::new(LruCache::with_expiry_duration(UDP_SESSION_TIMEOUT)),
+        );
+
+        let (l_tx, mut l_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
+        let (d_tx, d_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
+        let sess = Session {
+            network: Network::Udp,
+            typ: Type::Shadowsocks,
+            inbound: self.inbound.clone(),
+            ..Default::default()
+        };
+        let closer = self
+            .dispatcher
+            .dispatch_datagram(sess, Box::new(TunDatagram::new(l_tx, d_rx, self.addr)));
+
+        // dispatcher -> clients
+        let sender = socket.clone();
+        let replies = sessions.clone();
+        tokio::spawn(async move {
+            while let Some(pkt) = l_rx.recv().await {
+                let client = pkt.dst_addr.must_into_socket_addr();
+                let ctrl = match replies.lock().unwrap().get_mut(&client) {
+                    Some(ctrl) => {
+                        ctrl.packet_id = ctrl.packet_id.wrapping_add(1);
+                        ctrl.clone()
+                    }
+                    None => {
+                        trace!("dropping reply to expired shadowsocks client {}", client);
+                        continue;
+                    }
+                };
+                let addr: Address = (pkt.src_addr.host(), pkt.src_addr.port()).into();
+                if let Err(e) = sender
+                    .send_to_with_ctrl(client, &addr, &ctrl, &pkt.data)
+                    .await
+                {
+                    warn!("failed to send udp packet to {}: {}", client, e);
+                }
+            }
+        });
+
+        // clients -> dispatcher
+        let mut buf = vec![0u8; 65535];
+        let rv = loop {
+            let (n, src_addr, target, _, ctrl) = match socket.recv_from_with_ctrl(&mut buf).await {
+                Ok(r) => r,
+                Err(e) => {
+                    // a bad packet, not a broken socket
+                    debug!("invalid shadowsocks udp packet: {}", e);
+                    continue;
+                }
+            };
+            if self
+                .allowlist
+                .as_ref()
+                .is_some_and(|x| !x.allows(src_addr.ip()))
+            {
+                trace!("dropping udp packet from refused source {}", src_addr);
+                continue;
+            }
+
+            {
+                let mut sessions = sessions.lock().unwrap();
+                let state = sessions.entry(src_addr).or_insert_with(|| {
+                    let mut state = UdpSocketControlData::default();
+                    state.server_session_id = rand::random();
+                    state
+                });
+                if let Some(ctrl) = ctrl {
+                    state.client_session_id = ctrl.client_session_id;
+                }
+            }
+
+            let pkt = UdpPacket {
+                data: buf[..n].to_vec(),
+                src_addr: src_addr.into(),
+                dst_addr: to_socks_addr(target),
+            };
+            if d_tx.send(pkt).await.is_err() {
+                break Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "udp dispatcher stopped",
+                ));
+            }
+        };
+
+        closer.send(0).ok();
+        rv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use shadowsocks::{
+        config::{ServerConfig, ServerType},
+        context::Context,
+        crypto::CipherKind,
+        relay::{tcprelay::ProxyClientStream, Address},
+        ProxyListener,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::handshake;
+
+    async fn listen(method: CipherKind, password: &str) -> (ProxyListener, ServerConfig) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = ServerConfig::new(listener.local_addr().unwrap(), password, method);
+        let listener = ProxyListener::from_listener(
+            Context::new_shared(ServerType::Server),
+            shadowsocks::net::TcpListener::from_listener(listener, Default::default()),
+            &cfg,
+        );
+        (listener, cfg)
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        for (method, password) in [
+            (CipherKind::AES_256_GCM, "password"),
+            (
+                CipherKind::AEAD2022_BLAKE3_AES_256_GCM,
+                "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=",
+            ),
+        ] {
+            let (listener, cfg) = listen(method, password).await;
+
+            let client = tokio::spawn(async move {
+                let s = TcpStream::connect(cfg.addr().to_string()).await.unwrap();
+                let mut s = ProxyClientStream::from_stream(
+                    Context::new_shared(ServerType::Local),
+                    s,
+                    &cfg,
+                    ("example.com".to_owned(), 443),
+                );
+                s.write_all(b"ping").await.unwrap();
+                let mut buf = [0; 4];
+                s.read_exact(&mut buf).await.unwrap();
+                buf
+            });
+
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert_eq!(
+                handshake(&mut stream).await.unwrap(),
+                Address::DomainNameAddress("example.com".to_owned(), 443)
+            );
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            stream.write_all(b"pong").await.unwrap();
+            assert_eq!(&client.await.unwrap(), b"pong", "{}", method);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let (listener, cfg) = listen(CipherKind::AES_128_GCM, "password").await;
+        let _client = TcpStream::connect(cfg.addr().to_string()).await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(
+            handshake(&mut stream).await.unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+}
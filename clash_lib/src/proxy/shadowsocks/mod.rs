@@ -1,5 +1,7 @@
 mod datagram;
+mod inbound;
 mod obfs;
+mod sip003;
 mod stream;
 mod v2ray;
 
@@ -23,11 +25,22 @@ use crate::{
     Error,
 };
 use std::{collections::HashMap, io, sync::Arc};
+use tokio::net::TcpStream;
 
-use self::{datagram::OutboundDatagramShadowsocks, stream::ShadowSocksStream};
+use self::{
+    datagram::{OutboundDatagramShadowsocks, OutboundDatagramShadowsocksDetour},
+    obfs::{HTTPObfs, TLSObfs},
+    sip003::Sip003Plugin,
+    stream::ShadowSocksStream,
+    v2ray::mux::MuxConn,
+};
+
+pub use inbound::Listener;
+pub use sip003::{check_plugin, encode_plugin_opts};
 
 use super::{
-    utils::{new_tcp_stream, new_udp_socket},
+    transport::{self, TLSOptions},
+    utils::{new_udp_socket, DialerProxy},
     AnyOutboundHandler, AnyStream, OutboundType,
 };
 
@@ -95,11 +108,8 @@ impl TryFrom<HashMap<String, serde_yaml::Value>> for V2RayOBFSOption {
             return Err(Error::InvalidConfig(format!("invalid obfs mode: {}", mode)));
         }
 
-        let path = value
-            .get("path")
-            .and_then(|x| x.as_str())
-            .ok_or(Error::InvalidConfig("obfs path is required".to_owned()))?;
-        let mux = value.get("mux").and_then(|x| x.as_bool()).unwrap_or(false);
+        let path = value.get("path").and_then(|x| x.as_str()).unwrap_or("/");
+        let mux = value.get("mux").and_then(|x| x.as_bool()).unwrap_or(true);
         let tls = value.get("tls").and_then(|x| x.as_bool()).unwrap_or(false);
         let skip_cert_verify = value
             .get("skip-cert-verify")
@@ -129,9 +139,16 @@ impl TryFrom<HashMap<String, serde_yaml::Value>> for V2RayOBFSOption {
     }
 }
 
+/// an external plugin speaking SIP003
+pub struct Sip003Option {
+    pub plugin: String,
+    pub plugin_opts: Option<String>,
+}
+
 pub enum OBFSOption {
     Simple(SimpleOBFSOption),
     V2Ray(V2RayOBFSOption),
+    Sip003(Sip003Option),
 }
 
 pub struct HandlerOptions {
@@ -147,11 +164,91 @@ pub struct HandlerOptions {
 
 pub struct Handler {
     opts: HandlerOptions,
+    sip003_plugin: Option<Sip003Plugin>,
 }
 
 impl Handler {
     pub fn new(opts: HandlerOptions) -> AnyOutboundHandler {
-        Arc::new(Self { opts })
+        let sip003_plugin = match opts.plugin_opts {
+            Some(OBFSOption::Sip003(ref opt)) => Some(Sip003Plugin::new(
+                opt.plugin.clone(),
+                opt.plugin_opts.clone(),
+                opts.server.clone(),
+                opts.port,
+            )),
+            _ => None,
+        };
+        Arc::new(Self {
+            opts,
+            sip003_plugin,
+        })
+    }
+
+    async fn shadowsocks_stream(&self, s: AnyStream, sess: &Session) -> io::Result<AnyStream> {
+        let ctx = Context::new_shared(ServerType::Local);
+        let cfg = ServerConfig::new(
+            (self.opts.server.to_owned(), self.opts.port),
+            self.opts.password.to_owned(),
+            match self.opts.cipher.as_str() {
+                "aes-128-gcm" => CipherKind::AES_128_GCM,
+                "aes-256-gcm" => CipherKind::AES_256_GCM,
+                "chacha20-ietf-poly1305" => CipherKind::CHACHA20_POLY1305,
+                _ => return Err(io::Error::new(io::ErrorKind::Other, "unsupported cipher")),
+            },
+        );
+
+        let stream = ProxyClientStream::from_stream(
+            ctx,
+            s,
+            &cfg,
+            (sess.destination.host(), sess.destination.port()),
+        );
+
+        Ok(Box::new(ShadowSocksStream(stream)))
+    }
+
+    /// v2ray-plugin websocket mode, optionally over TLS and Mux.Cool
+    async fn v2ray_plugin_stream(
+        &self,
+        s: AnyStream,
+        opt: &V2RayOBFSOption,
+    ) -> io::Result<AnyStream> {
+        let s = if opt.tls {
+            transport::tls::wrap_stream(
+                s,
+                TLSOptions {
+                    skip_cert_verify: opt.skip_cert_verify,
+                    sni: opt.host.clone(),
+                    alpn: Some(vec!["http/1.1".to_owned()]),
+                    fragment: None,
+                },
+            )
+            .await?
+        } else {
+            s
+        };
+
+        let mut headers = opt.headers.clone();
+        if !headers.keys().any(|k| k.eq_ignore_ascii_case("host")) {
+            headers.insert("Host".to_owned(), opt.host.clone());
+        }
+        let s = transport::WebsocketStreamBuilder::new(
+            opt.host.clone(),
+            self.opts.port,
+            opt.path.clone(),
+            headers,
+            None,
+            0,
+            String::new(),
+        )
+        .proxy_stream(s)
+        .await?;
+
+        if opt.mux {
+            Ok(Box::new(MuxConn::new(s)))
+        } else {
+            Ok(s)
+        }
     }
 }
 
@@ -165,6 +262,10 @@ impl OutboundHandler for Handler {
         OutboundType::Shadowsocks
     }
 
+    fn dialer_proxy(&self) -> Option<&DialerProxy> {
+        self.opts.common_opts.dialer_proxy()
+    }
+
     async fn remote_addr(&self) -> Option<SocksAddr> {
         Some(SocksAddr::Domain(self.opts.server.clone(), self.opts.port))
     }
@@ -178,24 +279,35 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        let stream = new_tcp_stream(
-            resolver.clone(),
-            self.opts.server.as_str(),
-            self.opts.port,
-            self.opts.common_opts.iface.as_ref(),
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            None,
-        )
-        .map_err(|x| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "dial outbound {}:{}: {}",
-                    self.opts.server, self.opts.port, x
-                ),
-            )
-        })
-        .await?;
+        if let Some(plugin) = &self.sip003_plugin {
+            // the plugin process dials the server for us
+            let addr = plugin.local_addr().await?;
+            let stream = TcpStream::connect(addr).await.map_err(|x| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("dial sip003 plugin {}: {}", addr, x),
+                )
+            })?;
+            let s = self.shadowsocks_stream(Box::new(stream), sess).await?;
+            let chained = ChainedStreamWrapper::new(s);
+            chained.append_to_chain(self.name()).await;
+            return Ok(Box::new(chained));
+        }
+
+        let stream = self
+            .opts
+            .common_opts
+            .connect_stream(resolver.clone(), self.opts.server.as_str(), self.opts.port)
+            .map_err(|x| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "dial outbound {}:{}: {}",
+                        self.opts.server, self.opts.port, x
+                    ),
+                )
+            })
+            .await?;
 
         let s = self.proxy_stream(stream, sess, resolver).await?;
         let chained = ChainedStreamWrapper::new(s);
@@ -211,38 +323,32 @@ impl OutboundHandler for Handler {
     ) -> std::io::Result<AnyStream> {
         if let Some(plugin) = &self.opts.plugin_opts {
             match plugin {
-                OBFSOption::Simple(_) => {
+                OBFSOption::Simple(opt) => {
+                    let s: AnyStream = match opt.mode {
+                        SimpleOBFSMode::Http => {
+                            Box::new(HTTPObfs::new(s, opt.host.clone(), self.opts.port))
+                        }
+                        SimpleOBFSMode::Tls => Box::new(TLSObfs::new(s, opt.host.clone())),
+                    };
+                    return self.shadowsocks_stream(s, sess).await;
+                }
+                OBFSOption::V2Ray(opt) => {
+                    let s = self.v2ray_plugin_stream(s, opt).await?;
+                    return self.shadowsocks_stream(s, sess).await;
+                }
+                OBFSOption::Sip003(opt) => {
                     return Err(io::Error::new(
                         io::ErrorKind::Other,
-                        "simple-obfs is deprecated, please use v2ray-plugin instead",
+                        format!(
+                            "sip003 plugin {} can't be used over another stream",
+                            opt.plugin
+                        ),
                     ))
                 }
-                OBFSOption::V2Ray(_opt) => {
-                    todo!("v2ray-plugin is not implemented yet")
-                }
             }
         }
 
-        let ctx = Context::new_shared(ServerType::Local);
-        let cfg = ServerConfig::new(
-            (self.opts.server.to_owned(), self.opts.port),
-            self.opts.password.to_owned(),
-            match self.opts.cipher.as_str() {
-                "aes-128-gcm" => CipherKind::AES_128_GCM,
-                "aes-256-gcm" => CipherKind::AES_256_GCM,
-                "chacha20-ietf-poly1305" => CipherKind::CHACHA20_POLY1305,
-                _ => return Err(io::Error::new(io::ErrorKind::Other, "unsupported cipher")),
-            },
-        );
-
-        let stream = ProxyClientStream::from_stream(
-            ctx,
-            s,
-            &cfg,
-            (sess.destination.host(), sess.destination.port()),
-        );
-
-        Ok(Box::new(ShadowSocksStream(stream)))
+        self.shadowsocks_stream(s, sess).await
     }
 
     async fn connect_datagram(
@@ -261,6 +367,16 @@ impl OutboundHandler for Handler {
                 _ => return Err(io::Error::new(io::ErrorKind::Other, "unsupported cipher")),
             },
         );
+
+        if let Some(dialer) = self.opts.common_opts.dialer_proxy() {
+            let server: SocksAddr = (self.opts.server.to_owned(), self.opts.port).try_into()?;
+            let inner = dialer.connect_datagram(resolver, server.clone()).await?;
+            let d = OutboundDatagramShadowsocksDetour::new(inner, ctx, &cfg, server);
+            let d = ChainedDatagramWrapper::new(d);
+            d.append_to_chain(self.name()).await;
+            return Ok(Box::new(d));
+        }
+
         let socket = new_udp_socket(
             None,
             self.opts.common_opts.iface.as_ref(),
@@ -0,0 +1,185 @@
+use std::{
+    fmt::Debug,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use base64::Engine;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{common::errors::new_io_error, proxy::AnyStream};
+
+use super::poll_write_pending;
+
+/// the response header should never be this large
+const MAX_RESPONSE_HEADER_SIZE: usize = 8 * 1024;
+
+/// simple-obfs `obfs=http`: the first write is sent as the body of a
+/// websocket upgrade request, and the response header is skipped.
+pub struct HTTPObfs {
+    inner: AnyStream,
+    host: String,
+    port: u16,
+
+    first_request: bool,
+    first_response: bool,
+    write_buf: BytesMut,
+    read_buf: BytesMut,
+}
+
+impl Debug for HTTPObfs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HTTPObfs")
+            .field("inner", &self.inner)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+impl HTTPObfs {
+    pub fn new(inner: AnyStream, host: String, port: u16) -> Self {
+        Self {
+            inner,
+            host,
+            port,
+            first_request: true,
+            first_response: true,
+            write_buf: BytesMut::new(),
+            read_buf: BytesMut::new(),
+        }
+    }
+
+    fn make_request(&self, body: &[u8]) -> BytesMut {
+        let host = if self.port == 80 {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        };
+        let key = base64::engine::general_purpose::STANDARD.encode(rand::random::<[u8; 16]>());
+
+        let mut buf = BytesMut::with_capacity(256 + body.len());
+        buf.put_slice(
+            format!(
+                "GET / HTTP/1.1\r\n\
+                 Host: {}\r\n\
+                 User-Agent: curl/7.{}.{}\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Key: {}\r\n\
+                 Content-Length: {}\r\n\r\n",
+                host,
+                rand::random::<u8>() % 54,
+                rand::random::<u8>() % 2,
+                key,
+                body.len()
+            )
+            .as_bytes(),
+        );
+        buf.put_slice(body);
+        buf
+    }
+}
+
+impl AsyncWrite for HTTPObfs {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        futures::ready!(poll_write_pending(&mut this.inner, &mut this.write_buf, cx))?;
+
+        if this.first_request {
+            this.write_buf = this.make_request(buf);
+            this.first_request = false;
+            // the request is drained on the next write or flush
+            let _ = poll_write_pending(&mut this.inner, &mut this.write_buf, cx)?;
+            return Poll::Ready(Ok(buf.len()));
+        }
+
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        futures::ready!(poll_write_pending(&mut this.inner, &mut this.write_buf, cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        futures::ready!(poll_write_pending(&mut this.inner, &mut this.write_buf, cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncRead for HTTPObfs {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        while this.first_response {
+            if let Some(idx) = this.read_buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                this.read_buf.advance(idx + 4);
+                this.first_response = false;
+                break;
+            }
+            if this.read_buf.len() > MAX_RESPONSE_HEADER_SIZE {
+                return Poll::Ready(Err(new_io_error("obfs http response header too large")));
+            }
+
+            let mut tmp = [0u8; 1024];
+            let mut tmp_buf = ReadBuf::new(&mut tmp);
+            futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut tmp_buf))?;
+            if tmp_buf.filled().is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.read_buf.put_slice(tmp_buf.filled());
+        }
+
+        if !this.read_buf.is_empty() {
+            let n = this.read_buf.len().min(buf.remaining());
+            buf.put_slice(&this.read_buf[..n]);
+            this.read_buf.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::HTTPObfs;
+
+    #[tokio::test]
+    async fn test_http_obfs() {
+        let (client, mut server) = duplex(4096);
+        let mut obfs = HTTPObfs::new(Box::new(client), "example.com".to_owned(), 8080);
+
+        obfs.write_all(b"hello").await.unwrap();
+        obfs.flush().await.unwrap();
+
+        let mut buf = vec![0u8; 1024];
+        let n = server.read(&mut buf).await.unwrap();
+        let req = String::from_utf8_lossy(&buf[..n]);
+        assert!(req.starts_with("GET / HTTP/1.1\r\nHost: example.com:8080\r\n"));
+        assert!(req.ends_with("\r\n\r\nhello"));
+
+        server
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\n\r\nworld")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 5];
+        obfs.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+}
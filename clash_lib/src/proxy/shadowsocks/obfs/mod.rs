@@ -1,4 +1,33 @@
-mod simple;
+//! simple-obfs compatible stream obfuscation, as `plugin: obfs` in clash.
 
-#[deprecated(since = "0.1.0", note = "Please never use.")]
-pub use simple::HTTPObfs as SimpleObfs;
+mod http;
+mod tls;
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BytesMut};
+use tokio::io::AsyncWrite;
+
+use crate::proxy::AnyStream;
+
+pub use self::{http::HTTPObfs, tls::TLSObfs};
+
+/// writes out everything in `buf` before the stream accepts more data
+fn poll_write_pending(
+    inner: &mut AnyStream,
+    buf: &mut BytesMut,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>> {
+    while !buf.is_empty() {
+        let n = futures::ready!(Pin::new(&mut *inner).poll_write(cx, buf))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        buf.advance(n);
+    }
+    Poll::Ready(Ok(()))
+}
@@ -0,0 +1,265 @@
+use std::{
+    fmt::Debug,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::proxy::AnyStream;
+
+use super::poll_write_pending;
+
+const CHUNK_SIZE: usize = 1 << 14;
+
+/// record header of the first server response, plus the ServerHello,
+/// ChangeCipherSpec and the header of the record carrying data
+const FIRST_RESPONSE_DISCARD: usize = 105;
+/// content type + version of an application data record
+const RECORD_DISCARD: usize = 3;
+
+#[rustfmt::skip]
+const CIPHER_SUITES: [u8; 56] = [
+    0xc0, 0x2c, 0xc0, 0x30, 0x00, 0x9f, 0xcc, 0xa9, 0xcc, 0xa8, 0xcc, 0xaa, 0xc0, 0x2b, 0xc0, 0x2f,
+    0x00, 0x9e, 0xc0, 0x24, 0xc0, 0x28, 0x00, 0x6b, 0xc0, 0x23, 0xc0, 0x27, 0x00, 0x67, 0xc0, 0x0a,
+    0xc0, 0x14, 0x00, 0x39, 0xc0, 0x09, 0xc0, 0x13, 0x00, 0x33, 0x00, 0x9d, 0x00, 0x9c, 0x00, 0x3d,
+    0x00, 0x3c, 0x00, 0x35, 0x00, 0x2f, 0x00, 0xff,
+];
+
+#[rustfmt::skip]
+const SIGNATURE_ALGORITHMS: [u8; 36] = [
+    0x00, 0x0d, 0x00, 0x20, 0x00, 0x1e, 0x06, 0x01, 0x06, 0x02, 0x06, 0x03, 0x05,
+    0x01, 0x05, 0x02, 0x05, 0x03, 0x04, 0x01, 0x04, 0x02, 0x04, 0x03, 0x03, 0x01,
+    0x03, 0x02, 0x03, 0x03, 0x02, 0x01, 0x02, 0x02, 0x02, 0x03,
+];
+
+enum ReadState {
+    /// skipping the record header up to the length field
+    Discard(usize),
+    /// reading the 2 bytes record length
+    Length([u8; 2], usize),
+    /// reading the record payload
+    Payload(usize),
+}
+
+/// simple-obfs `obfs=tls`: the first write is sent as the session ticket
+/// of a fake ClientHello, the rest as TLS 1.2 application data records.
+pub struct TLSObfs {
+    inner: AnyStream,
+    server: String,
+
+    first_request: bool,
+    write_buf: BytesMut,
+    read_state: ReadState,
+}
+
+impl Debug for TLSObfs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TLSObfs")
+            .field("inner", &self.inner)
+            .field("server", &self.server)
+            .finish()
+    }
+}
+
+impl TLSObfs {
+    pub fn new(inner: AnyStream, server: String) -> Self {
+        Self {
+            inner,
+            server,
+            first_request: true,
+            write_buf: BytesMut::new(),
+            read_state: ReadState::Discard(FIRST_RESPONSE_DISCARD),
+        }
+    }
+}
+
+fn make_client_hello(data: &[u8], server: &str) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(217 + data.len() + server.len());
+
+    // handshake, TLS 1.0 version, length
+    buf.put_u8(22);
+    buf.put_slice(&[0x03, 0x01]);
+    buf.put_u16((212 + data.len() + server.len()) as u16);
+
+    // client hello, length, TLS 1.2 version
+    buf.put_u8(1);
+    buf.put_u8(0);
+    buf.put_u16((208 + data.len() + server.len()) as u16);
+    buf.put_slice(&[0x03, 0x03]);
+
+    // random with timestamp, session id
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    buf.put_u32(now as u32);
+    buf.put_slice(&rand::random::<[u8; 28]>());
+    buf.put_u8(32);
+    buf.put_slice(&rand::random::<[u8; 32]>());
+
+    buf.put_u16(CIPHER_SUITES.len() as u16);
+    buf.put_slice(&CIPHER_SUITES);
+
+    // compression
+    buf.put_slice(&[0x01, 0x00]);
+
+    // extensions length
+    buf.put_u16((79 + data.len() + server.len()) as u16);
+
+    // session ticket
+    buf.put_slice(&[0x00, 0x23]);
+    buf.put_u16(data.len() as u16);
+    buf.put_slice(data);
+
+    // server name
+    buf.put_slice(&[0x00, 0x00]);
+    buf.put_u16((server.len() + 5) as u16);
+    buf.put_u16((server.len() + 3) as u16);
+    buf.put_u8(0);
+    buf.put_u16(server.len() as u16);
+    buf.put_slice(server.as_bytes());
+
+    // ec point formats
+    buf.put_slice(&[0x00, 0x0b, 0x00, 0x04, 0x03, 0x01, 0x00, 0x02]);
+
+    // supported groups
+    buf.put_slice(&[
+        0x00, 0x0a, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x19, 0x00, 0x18,
+    ]);
+
+    buf.put_slice(&SIGNATURE_ALGORITHMS);
+
+    // encrypt then mac
+    buf.put_slice(&[0x00, 0x16, 0x00, 0x00]);
+
+    // extended master secret
+    buf.put_slice(&[0x00, 0x17, 0x00, 0x00]);
+
+    buf
+}
+
+impl AsyncWrite for TLSObfs {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        futures::ready!(poll_write_pending(&mut this.inner, &mut this.write_buf, cx))?;
+
+        let n = buf.len().min(CHUNK_SIZE);
+        if this.first_request {
+            this.write_buf = make_client_hello(&buf[..n], &this.server);
+            this.first_request = false;
+        } else {
+            this.write_buf.reserve(5 + n);
+            this.write_buf.put_slice(&[0x17, 0x03, 0x03]);
+            this.write_buf.put_u16(n as u16);
+            this.write_buf.put_slice(&buf[..n]);
+        }
+
+        // the record is drained on the next write or flush
+        let _ = poll_write_pending(&mut this.inner, &mut this.write_buf, cx)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        futures::ready!(poll_write_pending(&mut this.inner, &mut this.write_buf, cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        futures::ready!(poll_write_pending(&mut this.inner, &mut this.write_buf, cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncRead for TLSObfs {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        loop {
+            match this.read_state {
+                ReadState::Discard(0) => this.read_state = ReadState::Length([0; 2], 0),
+                ReadState::Discard(remaining) => {
+                    let mut tmp = [0u8; FIRST_RESPONSE_DISCARD];
+                    let mut tmp_buf = ReadBuf::new(&mut tmp[..remaining]);
+                    futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut tmp_buf))?;
+                    if tmp_buf.filled().is_empty() {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    this.read_state = ReadState::Discard(remaining - tmp_buf.filled().len());
+                }
+                ReadState::Length(len, 2) => {
+                    this.read_state = ReadState::Payload(u16::from_be_bytes(len) as usize)
+                }
+                ReadState::Length(mut len, filled) => {
+                    let mut tmp_buf = ReadBuf::new(&mut len[filled..]);
+                    futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut tmp_buf))?;
+                    let n = tmp_buf.filled().len();
+                    if n == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    this.read_state = ReadState::Length(len, filled + n);
+                }
+                ReadState::Payload(0) => this.read_state = ReadState::Discard(RECORD_DISCARD),
+                ReadState::Payload(remaining) => {
+                    if buf.remaining() == 0 {
+                        return Poll::Ready(Ok(()));
+                    }
+                    let max = remaining.min(buf.remaining());
+                    let mut tmp_buf = ReadBuf::new(buf.initialize_unfilled_to(max));
+                    futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut tmp_buf))?;
+                    let n = tmp_buf.filled().len();
+                    if n == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    buf.advance(n);
+                    this.read_state = ReadState::Payload(remaining - n);
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::{make_client_hello, TLSObfs, FIRST_RESPONSE_DISCARD};
+
+    #[test]
+    fn test_client_hello_length() {
+        let hello = make_client_hello(b"data", "example.com");
+        let record_len = u16::from_be_bytes([hello[3], hello[4]]) as usize;
+        assert_eq!(hello.len(), 5 + record_len);
+    }
+
+    #[tokio::test]
+    async fn test_tls_obfs_read() {
+        let (client, mut server) = duplex(4096);
+        let mut obfs = TLSObfs::new(Box::new(client), "example.com".to_owned());
+
+        let mut resp = vec![0u8; FIRST_RESPONSE_DISCARD];
+        resp.extend_from_slice(&[0x00, 0x05]);
+        resp.extend_from_slice(b"hello");
+        resp.extend_from_slice(&[0x17, 0x03, 0x03, 0x00, 0x05]);
+        resp.extend_from_slice(b"world");
+        server.write_all(&resp).await.unwrap();
+
+        let mut buf = [0u8; 10];
+        obfs.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"helloworld");
+    }
+}
@@ -0,0 +1,143 @@
+use std::{io, net::SocketAddr, path::Path};
+
+use shadowsocks::{
+    config::{Mode, ServerAddr},
+    plugin::{Plugin, PluginConfig, PluginMode},
+};
+use tokio::{sync::Mutex, task::JoinHandle};
+use tracing::{debug, warn};
+
+use crate::Error;
+
+/// the SIP003 plugins that may be given by name and are looked up in
+/// `PATH`, any other one has to be given by its absolute path
+const KNOWN_PLUGINS: [&str; 7] = [
+    "obfs-local",
+    "simple-obfs",
+    "xray-plugin",
+    "kcptun",
+    "gost-plugin",
+    "ck-client",
+    "qtun-client",
+];
+
+/// whether `plugin` may be started, so that a config from a subscription
+/// can't run any binary it names
+pub fn check_plugin(plugin: &str) -> Result<(), Error> {
+    if KNOWN_PLUGINS.contains(&plugin) || Path::new(plugin).is_absolute() {
+        Ok(())
+    } else {
+        Err(Error::InvalidConfig(format!(
+            "unknown plugin {}, give the absolute path of a sip003 plugin",
+            plugin
+        )))
+    }
+}
+
+/// An external SIP003 plugin, e.g. `/usr/bin/xray-plugin`.
+///
+/// The plugin process is started on first use and listens on a local port
+/// that forwards to the remote server. If the process exits it is restarted
+/// on the next dial, and it's killed when the handler is dropped.
+pub struct Sip003Plugin {
+    cfg: PluginConfig,
+    remote: ServerAddr,
+    process: Mutex<Option<(SocketAddr, JoinHandle<()>)>>,
+}
+
+impl Sip003Plugin {
+    pub fn new(plugin: String, plugin_opts: Option<String>, server: String, port: u16) -> Self {
+        Self {
+            cfg: PluginConfig {
+                plugin,
+                plugin_opts,
+                plugin_args: vec![],
+                plugin_mode: Mode::TcpOnly,
+            },
+            remote: ServerAddr::DomainName(server, port),
+            process: Mutex::new(None),
+        }
+    }
+
+    /// returns the local address of the running plugin,
+    /// starting the plugin process if it's not running
+    pub async fn local_addr(&self) -> io::Result<SocketAddr> {
+        let mut process = self.process.lock().await;
+        if let Some((addr, handle)) = process.as_ref() {
+            if !handle.is_finished() {
+                return Ok(*addr);
+            }
+        }
+
+        let plugin = Plugin::start(&self.cfg, &self.remote, PluginMode::Client)?;
+        let addr = plugin.local_addr();
+        debug!("sip003 plugin {} listening on {}", self.cfg.plugin, addr);
+
+        let name = self.cfg.plugin.clone();
+        let handle = tokio::spawn(async move {
+            match plugin.join().await {
+                Ok(status) => warn!("sip003 plugin {} exited with {}", name, status),
+                Err(e) => warn!("sip003 plugin {} failed: {}", name, e),
+            }
+        });
+
+        process.replace((addr, handle));
+        Ok(addr)
+    }
+}
+
+impl Drop for Sip003Plugin {
+    fn drop(&mut self) {
+        // aborting the task drops the plugin, which kills the process
+        if let Some((_, handle)) = self.process.get_mut().take() {
+            handle.abort();
+        }
+    }
+}
+
+/// encodes `k=v` pairs into the SIP003 `SS_PLUGIN_OPTIONS` format
+pub fn encode_plugin_opts<'a>(opts: impl Iterator<Item = (&'a str, Option<String>)>) -> String {
+    fn escape(s: &str) -> String {
+        let mut rv = String::with_capacity(s.len());
+        for c in s.chars() {
+            if matches!(c, '\\' | '=' | ';') {
+                rv.push('\\');
+            }
+            rv.push(c);
+        }
+        rv
+    }
+
+    opts.map(|(k, v)| match v {
+        Some(v) => format!("{}={}", escape(k), escape(&v)),
+        None => escape(k),
+    })
+    .collect::<Vec<_>>()
+    .join(";")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_plugin, encode_plugin_opts};
+
+    #[test]
+    fn test_check_plugin() {
+        assert!(check_plugin("xray-plugin").is_ok());
+        assert!(check_plugin("/usr/local/bin/my-plugin").is_ok());
+        assert!(check_plugin("my-plugin").is_err());
+        assert!(check_plugin("../bin/xray-plugin").is_err());
+    }
+
+    #[test]
+    fn test_encode_plugin_opts() {
+        let opts = vec![
+            ("tls", None),
+            ("host", Some("example.com".to_owned())),
+            ("path", Some("/a;b=c".to_owned())),
+        ];
+        assert_eq!(
+            encode_plugin_opts(opts.into_iter()),
+            r"tls;host=example.com;path=/a\;b\=c"
+        );
+    }
+}
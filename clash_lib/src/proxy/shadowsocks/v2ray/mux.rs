@@ -1 +1,278 @@
+use std::{
+    fmt::Debug,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{common::errors::new_io_error, proxy::AnyStream};
+
+const SESSION_STATUS_NEW: u8 = 0x01;
+const SESSION_STATUS_KEEP: u8 = 0x02;
+const SESSION_STATUS_END: u8 = 0x03;
+const SESSION_STATUS_KEEP_ALIVE: u8 = 0x04;
+
+const OPTION_NONE: u8 = 0x00;
+const OPTION_DATA: u8 = 0x01;
+
+const NETWORK_TCP: u8 = 0x01;
+const ADDR_IPV4: u8 = 0x01;
+
+const MAX_METADATA_LEN: usize = 512;
+
+enum ReadState {
+    MetadataLen,
+    Metadata(usize),
+    DataLen { keep_alive: bool },
+    Data { remaining: usize, discard: bool },
+    Eof,
+}
+
+/// A single Mux.Cool sub connection, as v2ray-plugin uses with `mux: true`.
+/// v2ray-plugin forwards everything to the shadowsocks server regardless of
+/// the destination, so the sub connection targets 127.0.0.1:0 like clash does.
+pub struct MuxConn {
+    inner: AnyStream,
+    id: [u8; 2],
+
+    write_buf: BytesMut,
+    end_frame_sent: bool,
+
+    read_buf: BytesMut,
+    read_state: ReadState,
+}
+
+impl Debug for MuxConn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MuxConn")
+            .field("inner", &self.inner)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl MuxConn {
+    pub fn new(inner: AnyStream) -> Self {
+        let id = [0u8, 0u8];
+        let mut write_buf = BytesMut::new();
+        // metadata length, ID, status, option, network, port, address
+        write_buf.put_u16(2 + 1 + 1 + 1 + 2 + 1 + 4);
+        write_buf.put_slice(&id);
+        write_buf.put_u8(SESSION_STATUS_NEW);
+        write_buf.put_u8(OPTION_NONE);
+        write_buf.put_u8(NETWORK_TCP);
+        write_buf.put_u16(0);
+        write_buf.put_u8(ADDR_IPV4);
+        write_buf.put_slice(&[127, 0, 0, 1]);
+
+        Self {
+            inner,
+            id,
+            write_buf,
+            end_frame_sent: false,
+            read_buf: BytesMut::new(),
+            read_state: ReadState::MetadataLen,
+        }
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = futures::ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// fills `read_buf` up to `n` bytes, returns false on EOF
+    fn poll_fill(&mut self, cx: &mut Context<'_>, n: usize) -> Poll<io::Result<bool>> {
+        while self.read_buf.len() < n {
+            let mut tmp = [0u8; MAX_METADATA_LEN];
+            let want = (n - self.read_buf.len()).min(tmp.len());
+            let mut tmp_buf = ReadBuf::new(&mut tmp[..want]);
+            futures::ready!(Pin::new(&mut self.inner).poll_read(cx, &mut tmp_buf))?;
+            if tmp_buf.filled().is_empty() {
+                return Poll::Ready(Ok(false));
+            }
+            self.read_buf.put_slice(tmp_buf.filled());
+        }
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl AsyncWrite for MuxConn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        futures::ready!(this.poll_write_pending(cx))?;
+
+        let n = buf.len().min(u16::MAX as usize);
+        this.write_buf.reserve(2 + 4 + 2 + n);
+        this.write_buf.put_u16(4);
+        this.write_buf.put_slice(&this.id);
+        this.write_buf.put_u8(SESSION_STATUS_KEEP);
+        this.write_buf.put_u8(OPTION_DATA);
+        this.write_buf.put_u16(n as u16);
+        this.write_buf.put_slice(&buf[..n]);
+
+        // the frame is drained on the next write or flush
+        let _ = this.poll_write_pending(cx)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        futures::ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        futures::ready!(this.poll_write_pending(cx))?;
+        if !this.end_frame_sent {
+            this.write_buf.put_u16(4);
+            this.write_buf.put_slice(&this.id);
+            this.write_buf.put_u8(SESSION_STATUS_END);
+            this.write_buf.put_u8(OPTION_NONE);
+            this.end_frame_sent = true;
+            futures::ready!(this.poll_write_pending(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncRead for MuxConn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        loop {
+            match this.read_state {
+                ReadState::MetadataLen => {
+                    if !futures::ready!(this.poll_fill(cx, 2))? {
+                        this.read_state = ReadState::Eof;
+                        continue;
+                    }
+                    let len = this.read_buf.get_u16() as usize;
+                    if len < 4 || len > MAX_METADATA_LEN {
+                        return Poll::Ready(Err(new_io_error("invalid mux metadata length")));
+                    }
+                    this.read_state = ReadState::Metadata(len);
+                }
+                ReadState::Metadata(len) => {
+                    if !futures::ready!(this.poll_fill(cx, len))? {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    let metadata = this.read_buf.split_to(len);
+                    let (status, option) = (metadata[2], metadata[3]);
+                    this.read_state = match status {
+                        SESSION_STATUS_END => ReadState::Eof,
+                        _ if option & OPTION_DATA != 0 => ReadState::DataLen {
+                            keep_alive: status == SESSION_STATUS_KEEP_ALIVE,
+                        },
+                        _ => ReadState::MetadataLen,
+                    };
+                }
+                ReadState::DataLen { keep_alive } => {
+                    if !futures::ready!(this.poll_fill(cx, 2))? {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    let remaining = this.read_buf.get_u16() as usize;
+                    this.read_state = ReadState::Data {
+                        remaining,
+                        discard: keep_alive,
+                    };
+                }
+                ReadState::Data { remaining: 0, .. } => this.read_state = ReadState::MetadataLen,
+                ReadState::Data { remaining, discard } => {
+                    if discard {
+                        if !futures::ready!(this.poll_fill(cx, remaining.min(MAX_METADATA_LEN)))? {
+                            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                        }
+                        let n = remaining.min(this.read_buf.len());
+                        this.read_buf.advance(n);
+                        this.read_state = ReadState::Data {
+                            remaining: remaining - n,
+                            discard,
+                        };
+                        continue;
+                    }
+
+                    if buf.remaining() == 0 {
+                        return Poll::Ready(Ok(()));
+                    }
+                    let n = if !this.read_buf.is_empty() {
+                        let n = remaining.min(this.read_buf.len()).min(buf.remaining());
+                        buf.put_slice(&this.read_buf[..n]);
+                        this.read_buf.advance(n);
+                        n
+                    } else {
+                        let max = remaining.min(buf.remaining());
+                        let mut tmp_buf = ReadBuf::new(buf.initialize_unfilled_to(max));
+                        futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut tmp_buf))?;
+                        let n = tmp_buf.filled().len();
+                        if n == 0 {
+                            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                        }
+                        buf.advance(n);
+                        n
+                    };
+                    this.read_state = ReadState::Data {
+                        remaining: remaining - n,
+                        discard,
+                    };
+                    return Poll::Ready(Ok(()));
+                }
+                ReadState::Eof => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::MuxConn;
+
+    #[tokio::test]
+    async fn test_mux_conn() {
+        let (client, mut server) = duplex(4096);
+        let mut conn = MuxConn::new(Box::new(client));
+
+        conn.write_all(b"hello").await.unwrap();
+        conn.flush().await.unwrap();
+
+        let mut buf = vec![0u8; 14 + 13];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..2], &[0, 12]);
+        assert_eq!(&buf[14..20], &[0, 4, 0, 0, 2, 1]);
+        assert_eq!(&buf[22..], b"hello");
+
+        // a keep alive frame with data, then a data frame, then the end
+        server
+            .write_all(&[0, 4, 0, 0, 4, 1, 0, 2, 0xff, 0xff])
+            .await
+            .unwrap();
+        server
+            .write_all(&[0, 4, 0, 0, 2, 1, 0, 5, b'w', b'o', b'r', b'l', b'd'])
+            .await
+            .unwrap();
+        server.write_all(&[0, 4, 0, 0, 3, 0]).await.unwrap();
+
+        let mut buf = vec![];
+        conn.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"world");
+    }
+}
@@ -2,6 +2,7 @@ use crate::session::SocksAddr;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::io;
 use tokio_util::codec::{Decoder, Encoder};
+use tracing::trace;
 
 /*
 +----+------+------+----------+----------+----------+
@@ -41,22 +42,68 @@ impl Decoder for Socks5UDPCodec {
     type Item = (SocksAddr, BytesMut);
     type Error = std::io::Error;
 
+    /// `src` is a whole datagram. The ones that are fragmented or malformed
+    /// are dropped, a `None` with the buffer cleared moves on to the next
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         if src.len() < 3 {
+            src.clear();
             return Ok(None);
         }
 
+        // reassembly is optional in RFC 1928, datagrams with a FRAG other
+        // than 0 must be dropped without it
         if src[2] != 0 {
-            return Err(std::io::Error::new(
-                io::ErrorKind::Other,
-                "unsupported FRAG",
-            ));
+            trace!("dropping fragmented SOCKS5 datagram");
+            src.clear();
+            return Ok(None);
         }
 
         src.advance(3);
-        let addr = SocksAddr::peek_read(src)?;
+        let addr = match SocksAddr::peek_read(src) {
+            Ok(addr) => addr,
+            Err(e) => {
+                trace!("dropping malformed SOCKS5 datagram: {}", e);
+                src.clear();
+                return Ok(None);
+            }
+        };
         src.advance(addr.size());
         let packet = std::mem::take(src);
         Ok(Some((addr, packet)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+    use tokio_util::codec::Decoder;
+
+    use crate::session::SocksAddr;
+
+    use super::Socks5UDPCodec;
+
+    #[test]
+    fn test_decode() {
+        let dst = SocksAddr::Ip("1.2.3.4:53".parse().unwrap());
+
+        let mut buf = BytesMut::new();
+        buf.put_slice(&[0, 0, 0]);
+        dst.write_buf(&mut buf);
+        buf.put_slice(b"query");
+        let (addr, data) = Socks5UDPCodec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(addr, dst);
+        assert_eq!(&data[..], b"query");
+
+        // fragments are dropped, not fatal
+        let mut buf = BytesMut::new();
+        buf.put_slice(&[0, 0, 1]);
+        dst.write_buf(&mut buf);
+        buf.put_slice(b"part");
+        assert!(Socks5UDPCodec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&[0, 0, 0, 9][..]);
+        assert!(Socks5UDPCodec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+    }
+}
@@ -1,10 +1,11 @@
 mod datagram;
+mod socks4;
 mod stream;
 
-use crate::common::auth::ThreadSafeAuthenticator;
-use crate::proxy::utils::apply_tcp_options;
+use crate::common::auth::{ThreadSafeAllowList, ThreadSafeAuthenticator};
+use crate::proxy::utils::{Acceptor, ConnectionLimiter};
 use crate::proxy::{AnyInboundListener, InboundListener};
-use crate::session::{Network, Session, Type};
+use crate::session::{Inbound, Network, Session, Type};
 use crate::Dispatcher;
 use async_trait::async_trait;
 use std::net::SocketAddr;
@@ -15,6 +16,7 @@ use tracing::warn;
 
 pub use datagram::Socks5UDPCodec;
 
+pub const SOCKS4_VERSION: u8 = 0x04;
 pub const SOCKS5_VERSION: u8 = 0x05;
 
 pub(crate) mod auth_methods {
@@ -45,6 +47,9 @@ pub struct Listener {
     addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    limiter: Option<ConnectionLimiter>,
+    allowlist: Option<ThreadSafeAllowList>,
+    inbound: Inbound,
 }
 
 impl Drop for Listener {
@@ -58,11 +63,17 @@ impl Listener {
         addr: SocketAddr,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        limiter: Option<ConnectionLimiter>,
+        allowlist: Option<ThreadSafeAllowList>,
+        inbound: Inbound,
     ) -> AnyInboundListener {
         Arc::new(Self {
             addr,
             dispatcher,
             authenticator,
+            limiter,
+            allowlist,
+            inbound,
         }) as _
     }
 }
@@ -79,16 +90,16 @@ impl InboundListener for Listener {
 
     async fn listen_tcp(&self) -> std::io::Result<()> {
         let listener = TcpListener::bind(self.addr).await?;
+        let mut acceptor = Acceptor::new(listener, self.limiter.clone(), self.allowlist.clone());
 
         loop {
-            let (socket, _) = listener.accept().await?;
-
-            let mut socket = apply_tcp_options(socket)?;
+            let (mut socket, src_addr, permit) = acceptor.accept().await;
 
             let mut sess = Session {
                 network: Network::Tcp,
                 typ: Type::Socks5,
-                source: socket.peer_addr()?,
+                source: src_addr,
+                inbound: self.inbound.clone(),
 
                 ..Default::default()
             };
@@ -97,6 +108,7 @@ impl InboundListener for Listener {
             let authenticator = self.authenticator.clone();
 
             tokio::spawn(async move {
+                let _permit = permit;
                 handle_tcp(&mut sess, &mut socket, dispatcher, authenticator).await
             });
         }
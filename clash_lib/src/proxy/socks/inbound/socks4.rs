@@ -0,0 +1,151 @@
+use std::{io, net::Ipv4Addr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::trace;
+
+use crate::{
+    common::{auth::ThreadSafeAuthenticator, errors::new_io_error},
+    session::{Session, SocksAddr, Type},
+    Dispatcher,
+};
+
+use super::{socks_command, SOCKS4_VERSION};
+
+const REQUEST_GRANTED: u8 = 90;
+const REQUEST_REJECTED: u8 = 91;
+
+/// user ids and SOCKS4a domains are at most this long
+const MAX_FIELD_LEN: usize = 255;
+
+#[derive(Debug, PartialEq)]
+struct Request {
+    command: u8,
+    user: String,
+    dst: SocksAddr,
+}
+
+async fn read_nul_terminated<S: AsyncRead + Unpin>(s: &mut S) -> io::Result<String> {
+    let mut field = Vec::new();
+    loop {
+        match s.read_u8().await? {
+            0 => break,
+            b if field.len() < MAX_FIELD_LEN => field.push(b),
+            _ => return Err(new_io_error("SOCKS4 field too long")),
+        }
+    }
+    String::from_utf8(field).map_err(|_| new_io_error("invalid SOCKS4 field"))
+}
+
+/*
++----+----+----+----+----+----+----+----+----+----+....+----+
+| VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
++----+----+----+----+----+----+----+----+----+----+....+----+
+| 1  | 1  |    2    |         4         | variable     | 1  |
++----+----+----+----+----+----+----+----+----+----+....+----+
+SOCKS4a sets DSTIP to 0.0.0.x and appends the NUL terminated domain
+ */
+async fn read_request<S: AsyncRead + Unpin>(s: &mut S) -> io::Result<Request> {
+    let mut buf = [0u8; 8];
+    s.read_exact(&mut buf).await?;
+    if buf[0] != SOCKS4_VERSION {
+        return Err(new_io_error("unsupported SOCKS version"));
+    }
+
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+    let user = read_nul_terminated(s).await?;
+    let dst = match ip.octets() {
+        [0, 0, 0, x] if x != 0 => SocksAddr::Domain(read_nul_terminated(s).await?, port),
+        _ => SocksAddr::Ip((ip, port).into()),
+    };
+
+    Ok(Request {
+        command: buf[1],
+        user,
+        dst,
+    })
+}
+
+async fn reply(s: &mut TcpStream, code: u8) -> io::Result<()> {
+    // the bound address is ignored by clients
+    s.write_all(&[0, code, 0, 0, 0, 0, 0, 0]).await
+}
+
+/// SOCKS4 carries no password, so it's refused when authentication is on
+pub async fn handle_tcp_v4(
+    sess: &mut Session,
+    s: &mut TcpStream,
+    dispatcher: Arc<Dispatcher>,
+    authenticator: ThreadSafeAuthenticator,
+) -> io::Result<()> {
+    let req = read_request(s).await?;
+
+    if authenticator.enabled() {
+        reply(s, REQUEST_REJECTED).await?;
+        return Err(new_io_error("auth required, which SOCKS4 can't do"));
+    }
+    if req.command != socks_command::CONNECT {
+        reply(s, REQUEST_REJECTED).await?;
+        return Err(new_io_error("unsupported SOCKS4 command"));
+    }
+
+    trace!(
+        "Got a SOCKS4 CONNECT request from {} ({}) to {}",
+        s.peer_addr()?,
+        req.user,
+        req.dst
+    );
+    reply(s, REQUEST_GRANTED).await?;
+
+    sess.typ = Type::Socks4;
+    sess.destination = req.dst;
+    if dispatcher.dispatch_stream(sess.to_owned(), &mut *s).await {
+        // sends an RST rather than a FIN as the socket is dropped
+        s.set_linger(Some(Duration::ZERO))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use crate::session::SocksAddr;
+
+    use super::read_request;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        // SOCKS4 to 1.2.3.4:80 as "bob"
+        client
+            .write_all(&[4, 1, 0, 80, 1, 2, 3, 4, b'b', b'o', b'b', 0])
+            .await
+            .unwrap();
+        let req = read_request(&mut server).await.unwrap();
+        assert_eq!(req.command, 1);
+        assert_eq!(req.user, "bob");
+        assert_eq!(req.dst, SocksAddr::Ip("1.2.3.4:80".parse().unwrap()));
+
+        // SOCKS4a to example.com:443 without user id
+        client
+            .write_all(&[4, 1, 1, 187, 0, 0, 0, 1, 0])
+            .await
+            .unwrap();
+        client.write_all(b"example.com\0").await.unwrap();
+        let req = read_request(&mut server).await.unwrap();
+        assert_eq!(req.user, "");
+        assert_eq!(req.dst, SocksAddr::Domain("example.com".to_owned(), 443));
+
+        client
+            .write_all(&[5, 1, 0, 80, 1, 2, 3, 4, 0])
+            .await
+            .unwrap();
+        assert!(read_request(&mut server).await.is_err());
+    }
+}
@@ -2,7 +2,10 @@ use crate::common::auth::ThreadSafeAuthenticator;
 use crate::common::errors::new_io_error;
 use crate::proxy::datagram::InboundUdp;
 use crate::proxy::socks::inbound::datagram::Socks5UDPCodec;
-use crate::proxy::socks::inbound::{auth_methods, response_code, socks_command, SOCKS5_VERSION};
+use crate::proxy::socks::inbound::socks4::handle_tcp_v4;
+use crate::proxy::socks::inbound::{
+    auth_methods, response_code, socks_command, SOCKS4_VERSION, SOCKS5_VERSION,
+};
 use crate::proxy::utils::new_udp_socket;
 use crate::session::{Network, Session, SocksAddr, Type};
 use crate::Dispatcher;
@@ -10,6 +13,7 @@ use bytes::{BufMut, BytesMut};
 
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{io, str};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
@@ -23,6 +27,12 @@ pub async fn handle_tcp<'a>(
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
 ) -> io::Result<()> {
+    // SOCKS4 clients are served on the same port
+    let mut version = [0u8; 1];
+    if s.peek(&mut version).await? == 1 && version[0] == SOCKS4_VERSION {
+        return handle_tcp_v4(sess, s, dispatcher, authenticator).await;
+    }
+
     // handshake
     let mut buf = BytesMut::new();
     {
@@ -89,6 +99,7 @@ pub async fn handle_tcp<'a>(
                 true => {
                     response = [0x1, response_code::SUCCEEDED];
                     s.write_all(&response).await?;
+                    sess.user = Some(user);
                 }
                 false => {
                     response = [0x1, response_code::FAILURE];
@@ -132,7 +143,10 @@ pub async fn handle_tcp<'a>(
             s.write_all(&buf[..]).await?;
             sess.destination = dst;
 
-            dispatcher.dispatch_stream(sess.to_owned(), s).await;
+            if dispatcher.dispatch_stream(sess.to_owned(), &mut *s).await {
+                // sends an RST rather than a FIN as the socket is dropped
+                s.set_linger(Some(Duration::ZERO))?;
+            }
 
             Ok(())
         }
@@ -168,14 +182,18 @@ pub async fn handle_tcp<'a>(
                 typ: Type::Socks5,
                 packet_mark: None,
                 iface: None,
+                inbound: sess.inbound.clone(),
+                user: sess.user.clone(),
                 ..Default::default()
             };
 
             let dispatcher_cloned = dispatcher.clone();
+            // the relay only serves the host that asked for it
+            let client = Some(s.peer_addr()?.ip());
 
             tokio::spawn(async move {
-                let handle =
-                    dispatcher_cloned.dispatch_datagram(sess, Box::new(InboundUdp::new(framed)));
+                let handle = dispatcher_cloned
+                    .dispatch_datagram(sess, Box::new(InboundUdp::new(framed, client)));
                 close_listener.await.ok();
                 handle.send(0).ok();
             });
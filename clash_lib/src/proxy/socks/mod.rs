@@ -1,6 +1,10 @@
 mod inbound;
+mod outbound;
 
 pub use inbound::handle_tcp;
 pub use inbound::Listener;
 pub use inbound::Socks5UDPCodec;
+pub use inbound::SOCKS4_VERSION;
 pub use inbound::SOCKS5_VERSION;
+pub use outbound::Handler;
+pub use outbound::HandlerOptions;
@@ -0,0 +1,176 @@
+use std::{
+    fmt::Debug,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::BytesMut;
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use tokio_util::{
+    codec::{Decoder, Encoder},
+    udp::UdpFramed,
+};
+use tracing::debug;
+
+use crate::{
+    app::dispatcher::BoxedChainedDatagram,
+    proxy::{datagram::UdpPacket, socks::Socks5UDPCodec, AnyStream},
+    session::SocksAddr,
+};
+
+/// UDP relayed by a SOCKS5 server, the association lives as long as
+/// the TCP connection it was negotiated on, so it's kept here.
+#[must_use = "sinks do nothing unless polled"]
+pub struct OutboundDatagramSocks5 {
+    inner: UdpFramed<Socks5UDPCodec>,
+    relay: SocketAddr,
+    _control: AnyStream,
+}
+
+impl OutboundDatagramSocks5 {
+    pub fn new(inner: UdpFramed<Socks5UDPCodec>, relay: SocketAddr, control: AnyStream) -> Self {
+        Self {
+            inner,
+            relay,
+            _control: control,
+        }
+    }
+}
+
+impl Debug for OutboundDatagramSocks5 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutboundDatagramSocks5")
+            .field("relay", &self.relay)
+            .finish()
+    }
+}
+
+impl Sink<UdpPacket> for OutboundDatagramSocks5 {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+        let relay = self.relay;
+        self.inner
+            .start_send_unpin(((item.data.into(), item.dst_addr), relay))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+impl Stream for OutboundDatagramSocks5 {
+    type Item = UdpPacket;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(self.inner.poll_next_unpin(cx)) {
+                Some(Ok(((src, data), from))) => {
+                    if from != self.relay {
+                        debug!("dropping socks5 udp packet from unknown peer {}", from);
+                        continue;
+                    }
+                    return Poll::Ready(Some(UdpPacket {
+                        data: data.to_vec(),
+                        src_addr: src,
+                        dst_addr: SocksAddr::any_ipv4(),
+                    }));
+                }
+                Some(Err(e)) => {
+                    debug!("failed to read socks5 udp packet: {}", e);
+                    return Poll::Ready(None);
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// UDP relayed by a SOCKS5 server that's reached through a `dialer-proxy`,
+/// the SOCKS5 UDP header is added to packets sent over the dialer's datagram.
+#[must_use = "sinks do nothing unless polled"]
+pub struct OutboundDatagramSocks5Detour {
+    inner: BoxedChainedDatagram,
+    relay: SocksAddr,
+    _control: AnyStream,
+}
+
+impl OutboundDatagramSocks5Detour {
+    pub fn new(inner: BoxedChainedDatagram, relay: SocksAddr, control: AnyStream) -> Self {
+        Self {
+            inner,
+            relay,
+            _control: control,
+        }
+    }
+}
+
+impl Debug for OutboundDatagramSocks5Detour {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutboundDatagramSocks5Detour")
+            .field("relay", &self.relay)
+            .finish()
+    }
+}
+
+impl Sink<UdpPacket> for OutboundDatagramSocks5Detour {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+        let mut buf = BytesMut::new();
+        Socks5UDPCodec.encode((item.data.into(), item.dst_addr), &mut buf)?;
+        let relay = self.relay.clone();
+        self.inner.start_send_unpin(UdpPacket {
+            data: buf.to_vec(),
+            src_addr: item.src_addr,
+            dst_addr: relay,
+        })
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+impl Stream for OutboundDatagramSocks5Detour {
+    type Item = UdpPacket;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let pkt = match ready!(self.inner.poll_next_unpin(cx)) {
+                Some(pkt) => pkt,
+                None => return Poll::Ready(None),
+            };
+            let mut buf = BytesMut::from(pkt.data.as_slice());
+            match Socks5UDPCodec.decode(&mut buf) {
+                Ok(Some((src, data))) => {
+                    return Poll::Ready(Some(UdpPacket {
+                        data: data.to_vec(),
+                        src_addr: src,
+                        dst_addr: SocksAddr::any_ipv4(),
+                    }))
+                }
+                Ok(None) => debug!("dropping truncated socks5 udp packet"),
+                Err(e) => debug!("failed to read socks5 udp packet: {}", e),
+            }
+        }
+    }
+}
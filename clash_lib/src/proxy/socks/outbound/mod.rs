@@ -0,0 +1,347 @@
+mod datagram;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use erased_serde::Serialize as ESerialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::udp::UdpFramed;
+
+use crate::{
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram, ChainedDatagramWrapper,
+            ChainedStream, ChainedStreamWrapper,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    common::errors::new_io_error,
+    proxy::{
+        transport::{self, TLSOptions, TlsFragment},
+        utils::{new_udp_socket, DialerProxy},
+        AnyOutboundHandler, AnyStream, CommonOption, OutboundHandler, OutboundType,
+    },
+    session::{Session, SocksAddr},
+};
+
+use self::datagram::{OutboundDatagramSocks5, OutboundDatagramSocks5Detour};
+
+use super::{
+    inbound::{auth_methods, response_code, socks_command},
+    Socks5UDPCodec, SOCKS5_VERSION,
+};
+
+pub struct HandlerOptions {
+    pub name: String,
+    pub common_opts: CommonOption,
+    pub server: String,
+    pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub udp: bool,
+    pub tls: bool,
+    pub sni: String,
+    pub skip_cert_verify: bool,
+    pub tls_fragment: Option<TlsFragment>,
+}
+
+pub struct Handler {
+    opts: HandlerOptions,
+}
+
+impl Handler {
+    pub fn new(opts: HandlerOptions) -> AnyOutboundHandler {
+        Arc::new(Self { opts })
+    }
+
+    async fn dial(&self, resolver: ThreadSafeDNSResolver) -> io::Result<AnyStream> {
+        self.opts
+            .common_opts
+            .connect_stream(resolver, self.opts.server.as_str(), self.opts.port)
+            .await
+            .map_err(|x| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "dial outbound {}:{}: {}",
+                        self.opts.server, self.opts.port, x
+                    ),
+                )
+            })
+    }
+
+    async fn tls_stream(&self, s: AnyStream) -> io::Result<AnyStream> {
+        if self.opts.tls {
+            transport::tls::wrap_stream(
+                s,
+                TLSOptions {
+                    skip_cert_verify: self.opts.skip_cert_verify,
+                    sni: self.opts.sni.clone(),
+                    alpn: None,
+                    fragment: self.opts.tls_fragment,
+                },
+            )
+            .await
+        } else {
+            Ok(s)
+        }
+    }
+
+    /// negotiates auth and sends `cmd`, returns the bound address
+    async fn handshake(
+        &self,
+        s: &mut AnyStream,
+        cmd: u8,
+        addr: &SocksAddr,
+    ) -> io::Result<SocksAddr> {
+        let mut buf = BytesMut::new();
+        buf.put_u8(SOCKS5_VERSION);
+        // some servers only take a password, sent with an empty username
+        if self.opts.user.is_some() || self.opts.password.is_some() {
+            buf.put_slice(&[2, auth_methods::NO_AUTH, auth_methods::USER_PASS]);
+        } else {
+            buf.put_slice(&[1, auth_methods::NO_AUTH]);
+        }
+        s.write_all(&buf).await?;
+
+        let mut reply = [0u8; 2];
+        s.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS5_VERSION {
+            return Err(new_io_error("unsupported SOCKS version"));
+        }
+
+        match reply[1] {
+            auth_methods::NO_AUTH => {}
+            auth_methods::USER_PASS => {
+                let user = self.opts.user.as_deref().unwrap_or_default();
+                let pass = self.opts.password.as_deref().unwrap_or_default();
+                if user.len() > 255 || pass.len() > 255 {
+                    return Err(new_io_error("SOCKS username or password too long"));
+                }
+
+                buf.clear();
+                buf.put_u8(0x01);
+                buf.put_u8(user.len() as u8);
+                buf.put_slice(user.as_bytes());
+                buf.put_u8(pass.len() as u8);
+                buf.put_slice(pass.as_bytes());
+                s.write_all(&buf).await?;
+
+                s.read_exact(&mut reply).await?;
+                if reply[1] != response_code::SUCCEEDED {
+                    return Err(new_io_error("SOCKS auth failure"));
+                }
+            }
+            _ => return Err(new_io_error("no acceptable SOCKS auth method")),
+        }
+
+        buf.clear();
+        buf.put_slice(&[SOCKS5_VERSION, cmd, 0x00]);
+        addr.write_buf(&mut buf);
+        s.write_all(&buf).await?;
+
+        let mut reply = [0u8; 3];
+        s.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS5_VERSION {
+            return Err(new_io_error("unsupported SOCKS version"));
+        }
+        if reply[1] != response_code::SUCCEEDED {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("SOCKS request failed with code {}", reply[1]),
+            ));
+        }
+
+        SocksAddr::read_from(s).await
+    }
+
+    /// the UDP relay address, servers may answer with an unspecified
+    /// address meaning the one we reached them on
+    async fn relay_addr(
+        &self,
+        bnd: SocksAddr,
+        resolver: &ThreadSafeDNSResolver,
+    ) -> io::Result<SocketAddr> {
+        let (host, port) = match bnd {
+            SocksAddr::Ip(addr) if !addr.ip().is_unspecified() => return Ok(addr),
+            SocksAddr::Ip(addr) => (self.opts.server.clone(), addr.port()),
+            SocksAddr::Domain(domain, port) => (domain, port),
+        };
+
+        let ip = resolver
+            .resolve(host.as_str(), false)
+            .await
+            .map_err(|x| new_io_error(x.to_string().as_str()))?
+            .ok_or_else(|| new_io_error(format!("can't resolve dns: {}", host).as_str()))?;
+        Ok(SocketAddr::new(ip, port))
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        self.opts.name.as_str()
+    }
+
+    fn proto(&self) -> OutboundType {
+        OutboundType::Socks5
+    }
+
+    fn dialer_proxy(&self) -> Option<&DialerProxy> {
+        self.opts.common_opts.dialer_proxy()
+    }
+
+    async fn remote_addr(&self) -> Option<SocksAddr> {
+        Some(SocksAddr::Domain(self.opts.server.clone(), self.opts.port))
+    }
+
+    async fn support_udp(&self) -> bool {
+        self.opts.udp
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        let stream = self.dial(resolver.clone()).await?;
+        let s = self.proxy_stream(stream, sess, resolver).await?;
+        let chained = ChainedStreamWrapper::new(s);
+        chained.append_to_chain(self.name()).await;
+        Ok(Box::new(chained))
+    }
+
+    async fn proxy_stream(
+        &self,
+        s: AnyStream,
+        sess: &Session,
+        _resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<AnyStream> {
+        let mut s = self.tls_stream(s).await?;
+        self.handshake(&mut s, socks_command::CONNECT, &sess.destination)
+            .await?;
+        Ok(s)
+    }
+
+    async fn connect_datagram(
+        &self,
+        _sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        let mut control = self.tls_stream(self.dial(resolver.clone()).await?).await?;
+        let bnd = self
+            .handshake(
+                &mut control,
+                socks_command::UDP_ASSOCIATE,
+                &SocksAddr::any_ipv4(),
+            )
+            .await?;
+
+        if let Some(dialer) = self.opts.common_opts.dialer_proxy() {
+            let relay = match bnd {
+                SocksAddr::Ip(addr) if addr.ip().is_unspecified() => {
+                    (self.opts.server.clone(), addr.port()).try_into()?
+                }
+                bnd => bnd,
+            };
+            let inner = dialer.connect_datagram(resolver, relay.clone()).await?;
+            let d = OutboundDatagramSocks5Detour::new(inner, relay, control);
+            let chained = ChainedDatagramWrapper::new(d);
+            chained.append_to_chain(self.name()).await;
+            return Ok(Box::new(chained));
+        }
+
+        let relay = self.relay_addr(bnd, &resolver).await?;
+
+        let local = match relay.ip() {
+            IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        };
+        let socket = new_udp_socket(
+            Some(&local),
+            self.opts.common_opts.iface.as_ref(),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await?;
+
+        let d = OutboundDatagramSocks5::new(UdpFramed::new(socket, Socks5UDPCodec), relay, control);
+        let chained = ChainedDatagramWrapper::new(d);
+        chained.append_to_chain(self.name()).await;
+        Ok(Box::new(chained))
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        let mut m = HashMap::new();
+        m.insert("type".to_string(), Box::new(self.proto()) as _);
+        m.insert("udp".to_string(), Box::new(self.opts.udp) as _);
+        m.insert("tls".to_string(), Box::new(self.opts.tls) as _);
+        m
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        proxy::{socks::inbound::socks_command, AnyStream, CommonOption},
+        session::SocksAddr,
+    };
+
+    use super::{Handler, HandlerOptions};
+
+    #[tokio::test]
+    async fn test_handshake_with_auth() {
+        let handler = Handler {
+            opts: HandlerOptions {
+                name: "socks".to_owned(),
+                common_opts: CommonOption::default(),
+                server: "127.0.0.1".to_owned(),
+                port: 1080,
+                user: Some("user".to_owned()),
+                password: Some("pass".to_owned()),
+                udp: true,
+                tls: false,
+                sni: "127.0.0.1".to_owned(),
+                skip_cert_verify: false,
+                tls_fragment: None,
+            },
+        };
+
+        let (client, mut server) = duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut buf = [0u8; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 2, 0, 2]);
+            server.write_all(&[5, 2]).await.unwrap();
+
+            let mut buf = [0u8; 1 + 1 + 4 + 1 + 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"\x01\x04user\x04pass");
+            server.write_all(&[1, 0]).await.unwrap();
+
+            let mut buf = [0u8; 3 + 7];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf[1], socks_command::UDP_ASSOCIATE);
+            server
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0x04, 0x38])
+                .await
+                .unwrap();
+        });
+
+        let mut s: AnyStream = Box::new(client);
+        let bnd = handler
+            .handshake(&mut s, socks_command::UDP_ASSOCIATE, &SocksAddr::any_ipv4())
+            .await
+            .unwrap();
+        server.await.unwrap();
+        assert_eq!(bnd, SocksAddr::Ip("0.0.0.0:1080".parse().unwrap()));
+    }
+}
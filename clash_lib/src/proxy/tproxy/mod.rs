@@ -0,0 +1,141 @@
+//! `tproxy-port`: accepts TCP connections and UDP packets diverted by
+//! iptables/nftables `TPROXY` rules. The socket is transparent, so the local
+//! address of an accepted connection is its original destination.
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use tokio::net::TcpListener;
+use tracing::warn;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod udp;
+
+use crate::{
+    common::auth::ThreadSafeAllowList,
+    proxy::{
+        utils::{Acceptor, ConnectionLimiter},
+        AnyInboundListener, InboundListener,
+    },
+    session::{Inbound, Network, Session, Type},
+    Dispatcher,
+};
+
+pub struct Listener {
+    addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+    limiter: Option<ConnectionLimiter>,
+    allowlist: Option<ThreadSafeAllowList>,
+    inbound: Inbound,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        warn!("TProxy inbound listener on {} stopped", self.addr);
+    }
+}
+
+impl Listener {
+    pub fn new(
+        addr: SocketAddr,
+        dispatcher: Arc<Dispatcher>,
+        limiter: Option<ConnectionLimiter>,
+        allowlist: Option<ThreadSafeAllowList>,
+        inbound: Inbound,
+    ) -> AnyInboundListener {
+        Arc::new(Self {
+            addr,
+            dispatcher,
+            limiter,
+            allowlist,
+            inbound,
+        }) as _
+    }
+}
+
+/// a listener that accepts connections to any address, needs CAP_NET_ADMIN
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn transparent_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_ip_transparent(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn transparent_listener(_: SocketAddr) -> io::Result<TcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "tproxy is only supported on Linux",
+    ))
+}
+
+#[async_trait]
+impl InboundListener for Listener {
+    fn handle_tcp(&self) -> bool {
+        true
+    }
+
+    fn handle_udp(&self) -> bool {
+        true
+    }
+
+    async fn listen_tcp(&self) -> io::Result<()> {
+        let listener = transparent_listener(self.addr)?;
+        let mut acceptor = Acceptor::new(listener, self.limiter.clone(), self.allowlist.clone());
+
+        loop {
+            let (mut socket, src_addr, permit) = acceptor.accept().await;
+
+            let dst = match socket.local_addr() {
+                Ok(dst) => dst,
+                Err(e) => {
+                    warn!("failed to get original destination of {}: {}", src_addr, e);
+                    continue;
+                }
+            };
+
+            let sess = Session {
+                network: Network::Tcp,
+                typ: Type::Tproxy,
+                source: src_addr,
+                destination: dst.into(),
+                inbound: self.inbound.clone(),
+
+                ..Default::default()
+            };
+
+            let dispatcher = self.dispatcher.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                if dispatcher.dispatch_stream(sess, &mut socket).await {
+                    let _ = socket.set_linger(Some(Duration::ZERO));
+                }
+            });
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    async fn listen_udp(&self) -> io::Result<()> {
+        udp::listen(
+            self.addr,
+            self.dispatcher.clone(),
+            self.allowlist.clone(),
+            self.inbound.clone(),
+        )
+        .await
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    async fn listen_udp(&self) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "tproxy is only supported on Linux",
+        ))
+    }
+}
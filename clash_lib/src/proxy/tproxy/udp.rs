@@ -0,0 +1,365 @@
+//! The UDP side of `tproxy-port`. A few `SO_REUSEPORT` sockets share the
+//! port so the kernel spreads the flows over them, each read by its own task
+//! in batches with `recvmmsg`. The original destination of a packet comes
+//! from its `IP_ORIGDSTADDR` control message, and the replies are sent from
+//! transparent sockets bound to that address.
+
+use std::{
+    io, mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    os::fd::{AsRawFd, RawFd},
+    ptr,
+    sync::Arc,
+    time::Duration,
+};
+
+use lru_time_cache::LruCache;
+use socket2::{Domain, Socket, Type as SocketType};
+use tokio::{io::unix::AsyncFd, net::UdpSocket, sync::mpsc};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    common::auth::ThreadSafeAllowList,
+    proxy::{datagram::UdpPacket, tun::datagram::TunDatagram},
+    session::{Inbound, Network, Session, SocksAddr, Type},
+    Dispatcher,
+};
+
+/// the most sockets sharing the port
+const MAX_WORKERS: usize = 8;
+/// packets read by one `recvmmsg`
+const BATCH_SIZE: usize = 16;
+const BUF_SIZE: usize = 65535;
+/// fits a control message carrying a `sockaddr_in6`
+const CONTROL_SIZE: usize = 64;
+/// packets queued between a worker and the dispatcher
+const CHANNEL_SIZE: usize = 256;
+/// how long the socket replying from an address is kept, as long as the
+/// dispatcher keeps a UDP flow
+const REPLY_SOCKET_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub async fn listen(
+    addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+    allowlist: Option<ThreadSafeAllowList>,
+    inbound: Inbound,
+) -> io::Result<()> {
+    let workers = std::thread::available_parallelism()
+        .map(|x| x.get())
+        .unwrap_or(1)
+        .min(MAX_WORKERS);
+
+    let mut tasks = vec![];
+    for _ in 0..workers {
+        let socket = AsyncFd::new(listener_socket(addr)?)?;
+        tasks.push(tokio::spawn(worker(
+            socket,
+            addr,
+            dispatcher.clone(),
+            allowlist.clone(),
+            inbound.clone(),
+        )));
+    }
+    debug!("tproxy udp on {} read by {} workers", addr, workers);
+
+    // the listener is up as long as all of its workers are
+    let (rv, _, rest) = futures::future::select_all(tasks).await;
+    for t in rest {
+        t.abort();
+    }
+    rv.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+}
+
+async fn worker(
+    socket: AsyncFd<std::net::UdpSocket>,
+    addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+    allowlist: Option<ThreadSafeAllowList>,
+    inbound: Inbound,
+) -> io::Result<()> {
+    let (l_tx, mut l_rx) = mpsc::channel::<UdpPacket>(CHANNEL_SIZE);
+    let (d_tx, d_rx) = mpsc::channel::<UdpPacket>(CHANNEL_SIZE);
+    let sess = Session {
+        network: Network::Udp,
+        typ: Type::Tproxy,
+        inbound,
+        ..Default::default()
+    };
+    let closer = dispatcher.dispatch_datagram(sess, Box::new(TunDatagram::new(l_tx, d_rx, addr)));
+
+    // dispatcher -> clients
+    tokio::spawn(async move {
+        let mut senders: LruCache<SocketAddr, Arc<UdpSocket>> =
+            LruCache::with_expiry_duration(REPLY_SOCKET_TIMEOUT);
+        while let Some(pkt) = l_rx.recv().await {
+            let from = match pkt.src_addr {
+                SocksAddr::Ip(from) => from,
+                SocksAddr::Domain(..) => {
+                    trace!("dropping tproxy reply from {}", pkt.src_addr);
+                    continue;
+                }
+            };
+            let sender = match senders.get(&from) {
+                Some(sender) => sender.clone(),
+                None => match reply_socket(from) {
+                    Ok(sender) => {
+                        let sender = Arc::new(sender);
+                        senders.insert(from, sender.clone());
+                        sender
+                    }
+                    Err(e) => {
+                        warn!("failed to reply from {}: {}", from, e);
+                        continue;
+                    }
+                },
+            };
+            let to = pkt.dst_addr.must_into_socket_addr();
+            if let Err(e) = sender.send_to(&pkt.data, to).await {
+                debug!("failed to send udp packet from {} to {}: {}", from, to, e);
+            }
+        }
+    });
+
+    // clients -> dispatcher
+    let mut batch = Batch::new();
+    let rv = 'read: loop {
+        let mut guard = match socket.readable().await {
+            Ok(guard) => guard,
+            Err(e) => break Err(e),
+        };
+        let received = match guard.try_io(|x| batch.recv(x.as_raw_fd())) {
+            Ok(Ok(received)) => received,
+            Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Ok(Err(e)) => break Err(e),
+            Err(_would_block) => continue,
+        };
+
+        for (i, (len, src, dst)) in received.into_iter().enumerate() {
+            let (src, dst) = match (src, dst) {
+                (Some(src), Some(dst)) => (src, dst),
+                _ => {
+                    trace!("dropping tproxy udp packet without its addresses");
+                    continue;
+                }
+            };
+            if allowlist.as_ref().is_some_and(|x| !x.allows(src.ip())) {
+                trace!("dropping udp packet from refused source {}", src);
+                continue;
+            }
+
+            let pkt = UdpPacket {
+                data: batch.bufs[i][..len].to_vec(),
+                src_addr: src.into(),
+                dst_addr: dst.into(),
+            };
+            if d_tx.send(pkt).await.is_err() {
+                break 'read Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "udp dispatcher stopped",
+                ));
+            }
+        }
+    };
+
+    closer.send(0).ok();
+    rv
+}
+
+/// the buffers of one `recvmmsg`
+struct Batch {
+    bufs: Vec<Vec<u8>>,
+    names: Vec<libc::sockaddr_storage>,
+    /// u64 keeps the control messages aligned
+    controls: Vec<[u64; CONTROL_SIZE / 8]>,
+}
+
+impl Batch {
+    fn new() -> Self {
+        Self {
+            bufs: vec![vec![0u8; BUF_SIZE]; BATCH_SIZE],
+            names: vec![unsafe { mem::zeroed() }; BATCH_SIZE],
+            controls: vec![[0u64; CONTROL_SIZE / 8]; BATCH_SIZE],
+        }
+    }
+
+    /// the length, source and original destination of each packet read,
+    /// their data is in `bufs`
+    fn recv(
+        &mut self,
+        fd: RawFd,
+    ) -> io::Result<Vec<(usize, Option<SocketAddr>, Option<SocketAddr>)>> {
+        let mut iovs = self
+            .bufs
+            .iter_mut()
+            .map(|x| libc::iovec {
+                iov_base: x.as_mut_ptr() as *mut libc::c_void,
+                iov_len: x.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut hdrs = (0..BATCH_SIZE)
+            .map(|i| {
+                let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+                hdr.msg_hdr.msg_name = &mut self.names[i] as *mut _ as *mut libc::c_void;
+                hdr.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+                hdr.msg_hdr.msg_iov = &mut iovs[i];
+                hdr.msg_hdr.msg_iovlen = 1;
+                hdr.msg_hdr.msg_control = self.controls[i].as_mut_ptr() as *mut libc::c_void;
+                hdr.msg_hdr.msg_controllen = CONTROL_SIZE as _;
+                hdr
+            })
+            .collect::<Vec<_>>();
+
+        let n = unsafe {
+            libc::recvmmsg(
+                fd,
+                hdrs.as_mut_ptr(),
+                BATCH_SIZE as _,
+                libc::MSG_DONTWAIT as _,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(hdrs[..n as usize]
+            .iter()
+            .zip(&self.names)
+            .map(|(hdr, name)| {
+                let src = socket_addr_of(name);
+                let dst = unsafe { original_dst(&hdr.msg_hdr) };
+                (hdr.msg_len as usize, src, dst)
+            })
+            .collect())
+    }
+}
+
+fn v4_addr(sa: &libc::sockaddr_in) -> SocketAddr {
+    SocketAddr::new(
+        Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr)).into(),
+        u16::from_be(sa.sin_port),
+    )
+}
+
+fn v6_addr(sa: &libc::sockaddr_in6) -> SocketAddr {
+    SocketAddrV6::new(
+        Ipv6Addr::from(sa.sin6_addr.s6_addr),
+        u16::from_be(sa.sin6_port),
+        sa.sin6_flowinfo,
+        sa.sin6_scope_id,
+    )
+    .into()
+}
+
+fn socket_addr_of(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    let storage = storage as *const libc::sockaddr_storage;
+    match unsafe { (*storage).ss_family } as libc::c_int {
+        libc::AF_INET => Some(v4_addr(unsafe { &*(storage as *const libc::sockaddr_in) })),
+        libc::AF_INET6 => Some(v6_addr(unsafe { &*(storage as *const libc::sockaddr_in6) })),
+        _ => None,
+    }
+}
+
+/// the address from the `IP_ORIGDSTADDR` or `IPV6_ORIGDSTADDR` control
+/// message of a packet
+unsafe fn original_dst(hdr: &libc::msghdr) -> Option<SocketAddr> {
+    let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+    while !cmsg.is_null() {
+        let data = libc::CMSG_DATA(cmsg);
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            (libc::SOL_IP, libc::IP_ORIGDSTADDR) => {
+                return Some(v4_addr(&ptr::read_unaligned(
+                    data as *const libc::sockaddr_in,
+                )));
+            }
+            (libc::SOL_IPV6, libc::IPV6_ORIGDSTADDR) => {
+                return Some(v6_addr(&ptr::read_unaligned(
+                    data as *const libc::sockaddr_in6,
+                )));
+            }
+            _ => {}
+        }
+        cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+    }
+    None
+}
+
+fn set_option(socket: &Socket, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    let rv = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of_val(&on) as libc::socklen_t,
+        )
+    };
+    if rv != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// a socket that may use an address that isn't local, needs CAP_NET_ADMIN
+fn transparent_socket(addr: SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), SocketType::DGRAM, None)?;
+    match addr {
+        SocketAddr::V4(_) => set_option(&socket, libc::SOL_IP, libc::IP_TRANSPARENT)?,
+        SocketAddr::V6(_) => set_option(&socket, libc::SOL_IPV6, libc::IPV6_TRANSPARENT)?,
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+fn listener_socket(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+    let socket = transparent_socket(addr)?;
+    socket.set_reuse_port(true)?;
+    match addr {
+        SocketAddr::V4(_) => set_option(&socket, libc::SOL_IP, libc::IP_RECVORIGDSTADDR)?,
+        SocketAddr::V6(_) => set_option(&socket, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR)?,
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// a socket sending from `from`, the address the client sent to
+fn reply_socket(from: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = transparent_socket(from)?;
+    socket.bind(&from.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{mem, net::SocketAddr};
+
+    use super::socket_addr_of;
+
+    #[test]
+    fn test_socket_addr_of() {
+        let addr: SocketAddr = "192.168.1.10:5353".parse().unwrap();
+        let sa: socket2::SockAddr = addr.into();
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                sa.as_ptr() as *const u8,
+                &mut storage as *mut _ as *mut u8,
+                sa.len() as usize,
+            );
+        }
+        assert_eq!(socket_addr_of(&storage), Some(addr));
+
+        let addr: SocketAddr = "[fd00::1]:53".parse().unwrap();
+        let sa: socket2::SockAddr = addr.into();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                sa.as_ptr() as *const u8,
+                &mut storage as *mut _ as *mut u8,
+                sa.len() as usize,
+            );
+        }
+        assert_eq!(socket_addr_of(&storage), Some(addr));
+    }
+}
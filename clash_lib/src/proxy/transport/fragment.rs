@@ -0,0 +1,283 @@
+//! ClientHello fragmentation: the first TLS record is cut into smaller
+//! records sent a little apart, so filters that look for the SNI in a single
+//! packet don't find it. Servers reassemble the handshake transparently.
+
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    future::Future,
+    io,
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use rand::Rng;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+use crate::Error;
+
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+/// the largest record payload allowed by RFC 8446
+const MAX_RECORD_LEN: usize = 1 << 14;
+
+/// `tls-fragment: size,interval` of the socks5, trojan, anytls and vmess
+/// proxies, splitting the ClientHello into smaller records to get past SNI
+/// filters. Each is either `N` or `MIN-MAX` and picked at random per record,
+/// e.g. `10-30,5-15` (bytes, milliseconds)
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct TlsFragment {
+    /// bounds of the payload length of each record
+    pub size: (usize, usize),
+    /// bounds of the pause between records, in milliseconds
+    pub interval: (u64, u64),
+}
+
+fn parse_range<T: FromStr + PartialOrd + Copy>(s: &str) -> Option<(T, T)> {
+    let (min, max) = match s.split_once('-') {
+        Some((min, max)) => (min.trim().parse().ok()?, max.trim().parse().ok()?),
+        None => {
+            let x = s.trim().parse().ok()?;
+            (x, x)
+        }
+    };
+    (min <= max).then_some((min, max))
+}
+
+impl FromStr for TlsFragment {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidConfig(format!("invalid tls-fragment: {}", s));
+
+        let (size, interval) = s.split_once(',').ok_or_else(invalid)?;
+        let size: (usize, usize) = parse_range(size).ok_or_else(invalid)?;
+        let interval = parse_range(interval).ok_or_else(invalid)?;
+        if size.0 == 0 || size.1 > MAX_RECORD_LEN {
+            return Err(invalid());
+        }
+        Ok(Self { size, interval })
+    }
+}
+
+impl TlsFragment {
+    fn pick_size(&self) -> usize {
+        rand::thread_rng().gen_range(self.size.0..=self.size.1)
+    }
+
+    fn pick_interval(&self) -> Duration {
+        Duration::from_millis(rand::thread_rng().gen_range(self.interval.0..=self.interval.1))
+    }
+
+    /// splits the handshake record at the start of `buf`, returning the
+    /// records to send instead and how much of `buf` they replace. `None`
+    /// if `buf` doesn't start with a whole handshake record
+    fn split(&self, buf: &[u8]) -> Option<(Vec<Bytes>, usize)> {
+        if buf.len() < RECORD_HEADER_LEN || buf[0] != CONTENT_TYPE_HANDSHAKE {
+            return None;
+        }
+        let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+        let end = RECORD_HEADER_LEN + len;
+        if buf.len() < end {
+            return None;
+        }
+
+        let mut records = Vec::new();
+        let mut payload = &buf[RECORD_HEADER_LEN..end];
+        while !payload.is_empty() {
+            let n = self.pick_size().min(payload.len());
+            let mut record = BytesMut::with_capacity(RECORD_HEADER_LEN + n);
+            record.put_slice(&buf[..3]);
+            record.put_u16(n as u16);
+            record.put_slice(&payload[..n]);
+            records.push(record.freeze());
+            payload = &payload[n..];
+        }
+        Some((records, end))
+    }
+}
+
+/// Sits under the TLS client and fragments the ClientHello it writes,
+/// everything after that passes through untouched.
+pub struct FragmentStream<S> {
+    inner: S,
+    fragment: TlsFragment,
+    /// the ClientHello records not sent yet
+    pending: VecDeque<Bytes>,
+    /// the pause before the next record
+    delay: Option<Pin<Box<Sleep>>>,
+    /// a record was just sent and must leave before the pause
+    flush: bool,
+    /// only the first record is fragmented
+    done: bool,
+}
+
+impl<S> Debug for FragmentStream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FragmentStream")
+            .field("fragment", &self.fragment)
+            .finish()
+    }
+}
+
+impl<S> FragmentStream<S> {
+    pub fn new(inner: S, fragment: TlsFragment) -> Self {
+        Self {
+            inner,
+            fragment,
+            pending: VecDeque::new(),
+            delay: None,
+            flush: false,
+            done: false,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> FragmentStream<S> {
+    /// writes out the pending records, pausing between them
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if self.flush {
+                ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+                self.flush = false;
+                if !self.pending.is_empty() {
+                    self.delay = Some(Box::pin(tokio::time::sleep(self.fragment.pick_interval())));
+                }
+            }
+            if let Some(delay) = self.delay.as_mut() {
+                ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+
+            let record = match self.pending.front_mut() {
+                Some(record) => record,
+                None => return Poll::Ready(Ok(())),
+            };
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, record))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            record.advance(n);
+            if record.is_empty() {
+                self.pending.pop_front();
+                self.flush = true;
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for FragmentStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // the TLS client may wait for the server before writing again, so
+        // reading keeps the ClientHello going too
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FragmentStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+
+        if !this.done {
+            this.done = true;
+            if let Some((records, n)) = this.fragment.split(buf) {
+                this.pending = records.into();
+                // the rest is sent by later writes, flushes or reads
+                if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+                    return Poll::Ready(Err(e));
+                }
+                return Poll::Ready(Ok(n));
+            }
+        }
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{FragmentStream, TlsFragment};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "10-30,5-15".parse::<TlsFragment>().unwrap(),
+            TlsFragment {
+                size: (10, 30),
+                interval: (5, 15)
+            }
+        );
+        assert_eq!(
+            "1,0".parse::<TlsFragment>().unwrap(),
+            TlsFragment {
+                size: (1, 1),
+                interval: (0, 0)
+            }
+        );
+        assert!("0,10".parse::<TlsFragment>().is_err());
+        assert!("30-10,10".parse::<TlsFragment>().is_err());
+        assert!("10".parse::<TlsFragment>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fragment_client_hello() {
+        let fragment: TlsFragment = "3-7,0-1".parse().unwrap();
+        let payload = (0..100u8).collect::<Vec<_>>();
+        let mut hello = vec![0x16, 0x03, 0x01, 0x00, 100];
+        hello.extend_from_slice(&payload);
+
+        let (client, mut server) = tokio::io::duplex(4096);
+        let mut client = FragmentStream::new(client, fragment);
+        client.write_all(&hello).await.unwrap();
+        client.write_all(b"after").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut sent = Vec::new();
+        server.read_to_end(&mut sent).await.unwrap();
+
+        let mut reassembled = Vec::new();
+        let mut rest = &sent[..];
+        while rest[0] == 0x16 {
+            assert_eq!(&rest[1..3], &[0x03, 0x01]);
+            let n = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+            assert!(n <= 7);
+            reassembled.extend_from_slice(&rest[5..5 + n]);
+            rest = &rest[5 + n..];
+        }
+        assert_eq!(reassembled, payload);
+        assert_eq!(rest, b"after");
+    }
+}
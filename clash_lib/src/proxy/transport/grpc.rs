@@ -60,7 +60,8 @@ impl GrpcStreamBuilder {
 }
 
 pub struct GrpcStream {
-    resp_fut: h2::client::ResponseFuture,
+    /// the response carrying the receiving half, the server side has it already
+    resp_fut: Option<h2::client::ResponseFuture>,
     recv: Option<RecvStream>,
     send: SendStream<Bytes>,
     buffer: BytesMut,
@@ -82,7 +83,7 @@ impl Debug for GrpcStream {
 impl GrpcStream {
     pub fn new(resp_fut: h2::client::ResponseFuture, send: SendStream<Bytes>) -> Self {
         Self {
-            resp_fut,
+            resp_fut: Some(resp_fut),
             recv: None,
             send,
             buffer: BytesMut::with_capacity(1024 * 4),
@@ -90,6 +91,17 @@ impl GrpcStream {
         }
     }
 
+    /// a stream accepted by a gRPC server, both directions speak the same framing
+    pub fn from_server(recv: RecvStream, send: SendStream<Bytes>) -> Self {
+        Self {
+            resp_fut: None,
+            recv: Some(recv),
+            send,
+            buffer: BytesMut::with_capacity(1024 * 4),
+            payload_len: 0,
+        }
+    }
+
     fn reserve_send_capacity(&mut self, data: &[u8]) {
         let mut buf = [0u8; 10];
         let mut buf = &mut buf[..];
@@ -118,8 +130,12 @@ impl AsyncRead for GrpcStream {
         dst: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
         if self.recv.is_none() {
+            let resp_fut = self
+                .resp_fut
+                .as_mut()
+                .expect("client streams are created with a response");
             self.recv = Some(
-                ready!(Pin::new(&mut self.resp_fut).poll(cx))
+                ready!(Pin::new(resp_fut).poll(cx))
                     .map_err(map_io_error)?
                     .into_body(),
             );
@@ -1,12 +1,20 @@
-use std::{collections::HashMap, fmt::Debug};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 
 use bytes::{Bytes, BytesMut};
-use futures::ready;
-use h2::{RecvStream, SendStream};
+use futures::{future::poll_fn, ready, FutureExt};
+use h2::{client::SendRequest, RecvStream, SendStream};
 use http::Request;
 use rand::random;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::error;
+use tracing::{debug, error};
 
 use crate::{common::errors::map_io_error, proxy::AnyStream};
 
@@ -42,19 +50,150 @@ impl Http2Config {
         Ok(request.body(()).expect("build req"))
     }
 
-    pub async fn proxy_stream(&self, stream: AnyStream) -> std::io::Result<AnyStream> {
-        let (mut client, h2) = h2::client::handshake(stream).await.map_err(map_io_error)?;
-        let req = self.req()?;
-        let (resp, send_stream) = client.send_request(req, false).map_err(map_io_error)?;
+    /// performs the h2 handshake over `stream` and drives the connection
+    /// in the background, returning a handle that can open new streams
+    pub async fn handshake(&self, stream: AnyStream) -> std::io::Result<Http2Conn> {
+        let (client, h2) = h2::client::handshake(stream).await.map_err(map_io_error)?;
+        let mut h2 = Box::pin(h2);
+        let max_streams = Arc::new(AtomicUsize::new(h2.max_concurrent_send_streams()));
+        let max = max_streams.clone();
         tokio::spawn(async move {
-            if let Err(e) = h2.await {
+            let rv = poll_fn(|cx| {
+                let rv = h2.as_mut().poll(cx);
+                // the server's SETTINGS are applied while polling
+                max.store(h2.max_concurrent_send_streams(), Ordering::Relaxed);
+                rv
+            })
+            .await;
+            if let Err(e) = rv {
                 error!("h2 error: {}", e);
             }
         });
+        Ok(Http2Conn {
+            client,
+            max_streams,
+            streams: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// opens a new stream on an established h2 connection, `slot` is held
+    /// by the stream for as long as it's open
+    pub async fn open_stream(
+        &self,
+        client: SendRequest<Bytes>,
+        slot: StreamSlot,
+    ) -> std::io::Result<AnyStream> {
+        let mut client = client.ready().await.map_err(map_io_error)?;
+        let req = self.req()?;
+        let (resp, send_stream) = client.send_request(req, false).map_err(map_io_error)?;
 
         let recv_stream = resp.await.map_err(map_io_error)?.into_body();
 
-        Ok(Box::new(Http2Stream::new(recv_stream, send_stream)))
+        let mut stream = Http2Stream::new(recv_stream, send_stream);
+        stream._slot = Some(slot);
+        Ok(Box::new(stream))
+    }
+
+    pub async fn proxy_stream(&self, stream: AnyStream) -> std::io::Result<AnyStream> {
+        let conn = self.handshake(stream).await?;
+        let slot = conn.claim();
+        self.open_stream(conn.client, slot).await
+    }
+}
+
+/// an h2 connection along with what tells whether it takes another stream
+/// right away
+#[derive(Clone)]
+pub struct Http2Conn {
+    client: SendRequest<Bytes>,
+    /// the server's SETTINGS_MAX_CONCURRENT_STREAMS
+    max_streams: Arc<AtomicUsize>,
+    /// the streams open on the connection
+    streams: Arc<AtomicUsize>,
+}
+
+impl Http2Conn {
+    fn claim(&self) -> StreamSlot {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        StreamSlot(self.streams.clone())
+    }
+
+    /// checks without waiting whether a stream can be opened now, errors
+    /// once the connection is gone
+    fn available(&mut self) -> Result<bool, h2::Error> {
+        if self.streams.load(Ordering::Relaxed) >= self.max_streams.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        match poll_fn(|cx| self.client.poll_ready(cx)).now_or_never() {
+            Some(Ok(())) => Ok(true),
+            Some(Err(e)) => Err(e),
+            None => Ok(false),
+        }
+    }
+}
+
+/// counts a stream on its connection until dropped
+pub struct StreamSlot(Arc<AtomicUsize>);
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Keeps the h2 connection of an outbound alive so that new streams
+/// are multiplexed over it instead of dialing a new TCP/TLS session each time.
+#[derive(Clone, Default)]
+pub struct Http2ConnPool {
+    conn: Arc<Mutex<Option<Http2Conn>>>,
+}
+
+impl Http2ConnPool {
+    /// claims a stream on the pooled connection if it takes one right away,
+    /// a dead connection is dropped from the pool
+    fn get(&self) -> Option<(SendRequest<Bytes>, StreamSlot)> {
+        let mut pooled = self.conn.lock().unwrap();
+        let conn = pooled.as_mut()?;
+        match conn.available() {
+            Ok(true) => Some((conn.client.clone(), conn.claim())),
+            Ok(false) => {
+                debug!("pooled h2 connection is at its max concurrent streams");
+                None
+            }
+            Err(e) => {
+                debug!("pooled h2 connection is gone: {}", e);
+                *pooled = None;
+                None
+            }
+        }
+    }
+
+    /// pools `conn` unless a concurrent dial pooled one that still takes
+    /// streams, `conn` then closes with its last stream
+    fn put(&self, conn: &Http2Conn) {
+        let mut pooled = self.conn.lock().unwrap();
+        if !matches!(pooled.as_mut().map(Http2Conn::available), Some(Ok(true))) {
+            *pooled = Some(conn.clone());
+        }
+    }
+
+    /// returns a stream on the pooled connection, or dials a new connection
+    /// with `dial` and keeps it for later streams
+    pub async fn open_stream<F>(&self, cfg: &Http2Config, dial: F) -> std::io::Result<AnyStream>
+    where
+        F: std::future::Future<Output = std::io::Result<AnyStream>>,
+    {
+        if let Some((client, slot)) = self.get() {
+            match cfg.open_stream(client, slot).await {
+                Ok(s) => return Ok(s),
+                Err(e) => debug!("failed to open stream on pooled h2 connection: {}", e),
+            }
+        }
+
+        let conn = cfg.handshake(dial.await?).await?;
+        let slot = conn.claim();
+        self.put(&conn);
+        cfg.open_stream(conn.client, slot).await
     }
 }
 
@@ -62,6 +201,8 @@ pub struct Http2Stream {
     recv: RecvStream,
     send: SendStream<Bytes>,
     buffer: BytesMut,
+    /// set on the streams of a pooled connection
+    _slot: Option<StreamSlot>,
 }
 
 impl Debug for Http2Stream {
@@ -80,6 +221,7 @@ impl Http2Stream {
             recv,
             send,
             buffer: BytesMut::with_capacity(1024 * 4),
+            _slot: None,
         }
     }
 }
@@ -164,3 +306,97 @@ impl AsyncWrite for Http2Stream {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use bytes::Bytes;
+    use tokio::{io::DuplexStream, sync::Barrier};
+
+    use crate::proxy::AnyStream;
+
+    use super::{Http2Config, Http2ConnPool};
+
+    fn config() -> Http2Config {
+        Http2Config {
+            hosts: vec!["example.com".to_owned()],
+            headers: HashMap::new(),
+            method: http::Method::PUT,
+            path: "/".parse().unwrap(),
+        }
+    }
+
+    /// answers every stream and keeps it open
+    async fn serve(io: DuplexStream, max_streams: u32) {
+        let mut conn = h2::server::Builder::new()
+            .max_concurrent_streams(max_streams)
+            .handshake::<_, Bytes>(io)
+            .await
+            .unwrap();
+        let mut streams = vec![];
+        while let Some(Ok((_, mut respond))) = conn.accept().await {
+            streams.push(
+                respond
+                    .send_response(http::Response::new(()), false)
+                    .unwrap(),
+            );
+        }
+    }
+
+    async fn dial(dials: &AtomicUsize, max_streams: u32) -> std::io::Result<AnyStream> {
+        dials.fetch_add(1, Ordering::Relaxed);
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve(server, max_streams));
+        Ok(Box::new(client))
+    }
+
+    #[tokio::test]
+    async fn test_pool_dials_when_busy() {
+        let cfg = config();
+        let pool = Http2ConnPool::default();
+        let dials = AtomicUsize::new(0);
+
+        let s1 = pool.open_stream(&cfg, dial(&dials, 1)).await.unwrap();
+        // the pooled connection is at its max concurrent streams, waiting on
+        // it would block until s1 is closed
+        let s2 = pool.open_stream(&cfg, dial(&dials, 1)).await.unwrap();
+        assert_eq!(dials.load(Ordering::Relaxed), 2);
+
+        drop(s1);
+        drop(s2);
+        let _s3 = pool.open_stream(&cfg, dial(&dials, 1)).await.unwrap();
+        assert_eq!(dials.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pool_concurrent_dials() {
+        let cfg = config();
+        let pool = Http2ConnPool::default();
+        let dials = AtomicUsize::new(0);
+
+        // both miss the empty pool before either connection is pooled
+        let barrier = Barrier::new(2);
+        let dial_both = || async {
+            barrier.wait().await;
+            dial(&dials, 100).await
+        };
+        let (s1, s2) = tokio::join!(
+            pool.open_stream(&cfg, dial_both()),
+            pool.open_stream(&cfg, dial_both()),
+        );
+        assert_eq!(dials.load(Ordering::Relaxed), 2);
+
+        // only the first connection is pooled, the other one isn't replacing
+        // it and closes with its stream
+        let s3 = pool.open_stream(&cfg, dial(&dials, 100)).await.unwrap();
+        assert_eq!(dials.load(Ordering::Relaxed), 2);
+        let pooled = pool.conn.lock().unwrap().clone().unwrap();
+        assert_eq!(pooled.streams.load(Ordering::Relaxed), 2);
+
+        drop((s1.unwrap(), s2.unwrap(), s3));
+    }
+}
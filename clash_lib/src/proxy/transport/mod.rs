@@ -1,7 +1,10 @@
+mod fragment;
 mod grpc;
 mod h2;
 #[path = "tls.rs"]
 mod internal_tls;
+mod quic;
+mod server;
 mod websocket;
 
 pub use websocket::WebsocketConn;
@@ -12,6 +15,15 @@ pub use grpc::GrpcStream;
 pub use grpc::GrpcStreamBuilder;
 
 pub use self::h2::Http2Config;
+pub use self::h2::Http2ConnPool;
+pub use self::h2::Http2Stream;
+
+pub use self::quic::CongestionController;
+pub use self::quic::QuicTransport;
+
+pub use self::server::ServerTransport;
+
+pub use self::fragment::TlsFragment;
 
 pub mod tls {
     pub use super::internal_tls::wrap_stream;
@@ -0,0 +1,422 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use network_interface::{Addr, NetworkInterface, NetworkInterfaceConfig};
+use once_cell::sync::Lazy;
+use quinn::{
+    congestion::{BbrConfig, CubicConfig, NewRenoConfig},
+    Connection, Endpoint, EndpointConfig, RecvStream, SendStream, TokioRuntime, TransportConfig,
+};
+use rustls::ClientConfig;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Mutex,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    app::dns::ThreadSafeDNSResolver,
+    common::{
+        errors::{map_io_error, new_io_error},
+        tls::{DummyTlsVerifier, GLOBAL_ROOT_STORE},
+    },
+    proxy::{
+        utils::{new_udp_socket, Interface},
+        AnyStream,
+    },
+};
+
+use super::TLSOptions;
+
+/// All QUIC based outbounds share the endpoints in this pool, so one UDP
+/// socket is used per (address family, interface, mark) instead of one per proxy.
+pub static GLOBAL_QUIC_ENDPOINTS: Lazy<QuicEndpointPool> = Lazy::new(QuicEndpointPool::default);
+
+/// how often the local addresses are checked for a change to rebind the
+/// endpoints on
+const ADDRS_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct EndpointKey {
+    ipv6: bool,
+    iface: Option<Interface>,
+    packet_mark: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct QuicEndpointPool {
+    endpoints: Mutex<HashMap<EndpointKey, Endpoint>>,
+    /// whether the local addresses are being watched
+    watching: AtomicBool,
+}
+
+impl QuicEndpointPool {
+    /// returns the shared client endpoint able to reach `remote`,
+    /// creating it on first use
+    pub async fn get(
+        &self,
+        remote: &SocketAddr,
+        iface: Option<&Interface>,
+        packet_mark: Option<u32>,
+    ) -> io::Result<Endpoint> {
+        let key = EndpointKey {
+            ipv6: remote.is_ipv6(),
+            iface: iface.cloned(),
+            packet_mark,
+        };
+
+        let mut endpoints = self.endpoints.lock().await;
+        if let Some(endpoint) = endpoints.get(&key) {
+            return Ok(endpoint.clone());
+        }
+
+        let socket = Self::new_socket(&key).await?;
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            None,
+            socket,
+            Arc::new(TokioRuntime),
+        )?;
+        debug!(
+            "new quic endpoint {:?} bound to {:?}",
+            key,
+            endpoint.local_addr()
+        );
+
+        endpoints.insert(key, endpoint.clone());
+        if !self.watching.swap(true, Ordering::Relaxed) {
+            tokio::spawn(watch_local_addrs());
+        }
+        Ok(endpoint)
+    }
+
+    /// binds every endpoint to a fresh socket, e.g. after the local address has changed.
+    /// connections on the endpoints are migrated to the new path by quinn.
+    pub async fn rebind_all(&self) {
+        let endpoints = self.endpoints.lock().await;
+        for (key, endpoint) in endpoints.iter() {
+            let rv = match Self::new_socket(key).await {
+                Ok(socket) => endpoint.rebind(socket),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = rv {
+                warn!("failed to rebind quic endpoint {:?}: {}", key, e);
+            }
+        }
+    }
+
+    async fn new_socket(key: &EndpointKey) -> io::Result<std::net::UdpSocket> {
+        let src = if key.ipv6 {
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+        } else {
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+        };
+
+        let socket = new_udp_socket(
+            Some(&src),
+            key.iface.as_ref(),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            key.packet_mark,
+        )
+        .await?;
+
+        socket.into_std()
+    }
+}
+
+/// the addresses of the local interfaces, sorted, none if they can't be
+/// listed
+fn local_addrs() -> Option<Vec<IpAddr>> {
+    let ifaces = match NetworkInterface::show() {
+        Ok(ifaces) => ifaces,
+        Err(e) => {
+            debug!("failed to list interfaces: {}", e);
+            return None;
+        }
+    };
+    let mut rv: Vec<IpAddr> = ifaces
+        .iter()
+        .flat_map(|x| &x.addr)
+        .map(|x| match x {
+            Addr::V4(v4) => v4.ip.into(),
+            Addr::V6(v6) => v6.ip.into(),
+        })
+        .collect();
+    rv.sort_unstable();
+    Some(rv)
+}
+
+/// rebinds the shared endpoints whenever the local addresses change, e.g.
+/// on switching from wifi to cellular, so their connections migrate to the
+/// new network instead of timing out on the old one
+async fn watch_local_addrs() {
+    let mut last = local_addrs();
+    loop {
+        tokio::time::sleep(ADDRS_CHECK_INTERVAL).await;
+        let addrs = local_addrs();
+        if addrs.is_some() && addrs != last {
+            debug!("local addresses changed, rebinding quic endpoints");
+            GLOBAL_QUIC_ENDPOINTS.rebind_all().await;
+            last = addrs;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CongestionController {
+    #[default]
+    Cubic,
+    NewReno,
+    Bbr,
+}
+
+impl TryFrom<&str> for CongestionController {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "cubic" => Ok(Self::Cubic),
+            "new_reno" | "newreno" => Ok(Self::NewReno),
+            "bbr" => Ok(Self::Bbr),
+            _ => Err(format!("unsupported congestion controller: {}", value)),
+        }
+    }
+}
+
+/// A raw QUIC transport. Streams of an outbound are opened on one QUIC
+/// connection, which is dialed from the shared endpoint pool.
+pub struct QuicTransport {
+    server: String,
+    port: u16,
+    sni: String,
+    zero_rtt: bool,
+    /// set once the server turns down 0-RTT, the connections after are
+    /// dialed with a full handshake
+    zero_rtt_rejected: Arc<AtomicBool>,
+    congestion_controller: CongestionController,
+    client_config: quinn::ClientConfig,
+    conn: Mutex<Option<Connection>>,
+}
+
+/// How the current connection of a QUIC outbound is doing, in the API, to
+/// tell a congested or lossy path from a peer that won't take more data.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QuicStats {
+    pub congestion_controller: String,
+    pub rtt_ms: u64,
+    /// the congestion window in bytes
+    pub cwnd: u64,
+    pub congestion_events: u64,
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    pub lost_bytes: u64,
+    pub sent_bytes: u64,
+    pub received_bytes: u64,
+    /// how often sending had to wait for the server to raise the flow
+    /// control limits of the connection or of a stream
+    pub send_blocked: u64,
+    /// how often the server had to wait for us to raise them
+    pub receive_blocked: u64,
+}
+
+impl QuicStats {
+    fn new(congestion_controller: CongestionController, conn: &Connection) -> Self {
+        let stats = conn.stats();
+        Self {
+            congestion_controller: format!("{:?}", congestion_controller),
+            rtt_ms: stats.path.rtt.as_millis() as u64,
+            cwnd: stats.path.cwnd,
+            congestion_events: stats.path.congestion_events,
+            sent_packets: stats.path.sent_packets,
+            lost_packets: stats.path.lost_packets,
+            lost_bytes: stats.path.lost_bytes,
+            sent_bytes: stats.udp_tx.bytes,
+            received_bytes: stats.udp_rx.bytes,
+            send_blocked: stats.frame_tx.data_blocked + stats.frame_tx.stream_data_blocked,
+            receive_blocked: stats.frame_rx.data_blocked + stats.frame_rx.stream_data_blocked,
+        }
+    }
+}
+
+impl QuicTransport {
+    pub fn new(
+        server: String,
+        port: u16,
+        tls: TLSOptions,
+        congestion_controller: CongestionController,
+        zero_rtt: bool,
+    ) -> Self {
+        let mut tls_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(GLOBAL_ROOT_STORE.clone())
+            .with_no_client_auth();
+        tls_config.alpn_protocols = tls
+            .alpn
+            .unwrap_or_default()
+            .into_iter()
+            .map(|x| x.as_bytes().to_vec())
+            .collect();
+        // session tickets are kept in the config's resumption store,
+        // so the config must live as long as the handler for 0-RTT to work
+        tls_config.enable_early_data = zero_rtt;
+        if tls.skip_cert_verify {
+            tls_config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(DummyTlsVerifier {}));
+        }
+
+        let mut transport = TransportConfig::default();
+        transport.keep_alive_interval(Some(Duration::from_secs(10)));
+        match congestion_controller {
+            CongestionController::Cubic => {
+                transport.congestion_controller_factory(Arc::new(CubicConfig::default()))
+            }
+            CongestionController::NewReno => {
+                transport.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+            }
+            CongestionController::Bbr => {
+                transport.congestion_controller_factory(Arc::new(BbrConfig::default()))
+            }
+        };
+
+        let mut client_config = quinn::ClientConfig::new(Arc::new(tls_config));
+        client_config.transport_config(Arc::new(transport));
+
+        Self {
+            server,
+            port,
+            sni: tls.sni,
+            zero_rtt,
+            zero_rtt_rejected: Default::default(),
+            congestion_controller,
+            client_config,
+            conn: Mutex::new(None),
+        }
+    }
+
+    async fn connect(
+        &self,
+        resolver: ThreadSafeDNSResolver,
+        iface: Option<&Interface>,
+        packet_mark: Option<u32>,
+    ) -> io::Result<Connection> {
+        let ip = resolver
+            .resolve(&self.server, false)
+            .await
+            .map_err(map_io_error)?
+            .ok_or(new_io_error(
+                format!("can't resolve dns: {}", self.server).as_str(),
+            ))?;
+        let addr = SocketAddr::new(ip, self.port);
+
+        let endpoint = GLOBAL_QUIC_ENDPOINTS.get(&addr, iface, packet_mark).await?;
+        let connecting = endpoint
+            .connect_with(self.client_config.clone(), addr, &self.sni)
+            .map_err(map_io_error)?;
+
+        if self.zero_rtt && !self.zero_rtt_rejected.load(Ordering::Relaxed) {
+            match connecting.into_0rtt() {
+                Ok((conn, accepted)) => {
+                    // the data sent early on a rejected connection is lost,
+                    // failing the streams it was sent on
+                    let rejected = self.zero_rtt_rejected.clone();
+                    let server = self.server.clone();
+                    tokio::spawn(async move {
+                        if !accepted.await {
+                            warn!("quic server {} rejected 0-RTT, not trying it again", server);
+                            rejected.store(true, Ordering::Relaxed);
+                        }
+                    });
+                    return Ok(conn);
+                }
+                Err(connecting) => return connecting.await.map_err(map_io_error),
+            }
+        }
+        connecting.await.map_err(map_io_error)
+    }
+
+    /// the stats of the current connection, None if there is none open
+    pub async fn stats(&self) -> Option<QuicStats> {
+        let conn = self.conn.lock().await;
+        conn.as_ref()
+            .filter(|c| c.close_reason().is_none())
+            .map(|c| QuicStats::new(self.congestion_controller, c))
+    }
+
+    /// opens a bidirectional stream on the connection of this transport,
+    /// dialing a new connection if there is none or the old one is closed
+    pub async fn open_stream(
+        &self,
+        resolver: ThreadSafeDNSResolver,
+        iface: Option<&Interface>,
+        packet_mark: Option<u32>,
+    ) -> io::Result<AnyStream> {
+        // the lock isn't held while opening or dialing, a reconnect mustn't
+        // hold back the streams of the other sessions
+        let pooled = self.conn.lock().await.clone();
+        if let Some(c) = pooled.filter(|c| c.close_reason().is_none()) {
+            match c.open_bi().await {
+                Ok((send, recv)) => return Ok(Box::new(QuicStream { send, recv })),
+                Err(e) => debug!("failed to open stream on quic connection: {}", e),
+            }
+        }
+
+        let c = self.connect(resolver, iface, packet_mark).await?;
+        let (send, recv) = c.open_bi().await.map_err(map_io_error)?;
+        {
+            // keep a connection another session dialed meanwhile, this one
+            // then closes with its stream
+            let mut conn = self.conn.lock().await;
+            if conn.as_ref().map_or(true, |x| x.close_reason().is_some()) {
+                conn.replace(c);
+            }
+        }
+        Ok(Box::new(QuicStream { send, recv }))
+    }
+}
+
+#[derive(Debug)]
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        AsyncRead::poll_read(Pin::new(&mut self.recv), cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.send), cx)
+    }
+}
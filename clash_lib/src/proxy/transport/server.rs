@@ -0,0 +1,196 @@
+//! The server side of the transports, for the listeners of protocols that
+//! clash-rs otherwise dials: TLS, websocket and gRPC (gun).
+
+use std::{future::Future, io, sync::Arc, time::Duration};
+
+use http::{Response, StatusCode};
+use rustls::ServerConfig;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::handshake::server::{
+    ErrorResponse, Request, Response as WsResponse,
+};
+use tracing::debug;
+
+use crate::{
+    common::{
+        errors::{map_io_error, new_io_error},
+        tls,
+    },
+    config::internal::listener::InboundTransport,
+    proxy::AnyStream,
+};
+
+use super::{GrpcStream, WebsocketConn};
+
+/// how long the TLS, websocket or h2 handshake of a client may take, the
+/// idle ones would otherwise hold a connection of the listener forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn handshake<T>(f: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, f)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))?
+}
+
+enum Transport {
+    Tcp,
+    Ws(String),
+    Grpc(String),
+}
+
+pub struct ServerTransport {
+    tls: Option<TlsAcceptor>,
+    transport: Transport,
+}
+
+fn load_tls(cert: &str, key: &str, alpn: &[&str]) -> io::Result<TlsAcceptor> {
+    let (certs, key) = tls::load_certificate_and_key(cert, key)?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(map_io_error)?;
+    config.alpn_protocols = alpn.iter().map(|x| x.as_bytes().to_vec()).collect();
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// the outbound sends the service name as the whole path, other clients
+/// call the `Tun` method of the service
+fn grpc_path_matches(path: &str, service_name: &str) -> bool {
+    let service_name = service_name.trim_start_matches('/');
+    path.trim_start_matches('/') == service_name || path == format!("/{}/Tun", service_name)
+}
+
+impl ServerTransport {
+    pub fn new(opts: &InboundTransport) -> io::Result<Self> {
+        let (transport, alpn) = match (&opts.ws_path, &opts.grpc_service_name) {
+            (Some(_), Some(_)) => {
+                return Err(new_io_error(
+                    "only one of ws-path and grpc-service-name can be set",
+                ))
+            }
+            (Some(path), None) => (Transport::Ws(path.clone()), vec!["http/1.1"]),
+            (None, Some(name)) => (Transport::Grpc(name.clone()), vec!["h2"]),
+            (None, None) => (Transport::Tcp, vec![]),
+        };
+
+        let tls = match (&opts.certificate, &opts.private_key) {
+            (Some(cert), Some(key)) => Some(load_tls(cert, key, &alpn)?),
+            (None, None) => None,
+            _ => {
+                return Err(new_io_error(
+                    "certificate and private-key must be set together",
+                ))
+            }
+        };
+
+        Ok(Self { tls, transport })
+    }
+
+    /// unwraps the streams tunneled in `stream` and runs `handler` on each:
+    /// a connection carries one stream, or any number with gRPC
+    pub async fn serve<F, Fut>(&self, stream: TcpStream, handler: F) -> io::Result<()>
+    where
+        F: Fn(AnyStream) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let stream: AnyStream = match self.tls {
+            Some(ref acceptor) => Box::new(handshake(acceptor.accept(stream)).await?),
+            None => Box::new(stream),
+        };
+
+        match self.transport {
+            Transport::Tcp => {
+                handler(stream).await;
+                Ok(())
+            }
+            Transport::Ws(ref path) => {
+                let check_path = |req: &Request, res: WsResponse| {
+                    if req.uri().path() == path {
+                        Ok(res)
+                    } else {
+                        let mut res = ErrorResponse::new(None);
+                        *res.status_mut() = StatusCode::NOT_FOUND;
+                        Err(res)
+                    }
+                };
+                let ws = handshake(async {
+                    tokio_tungstenite::accept_hdr_async(stream, check_path)
+                        .await
+                        .map_err(map_io_error)
+                })
+                .await?;
+                handler(Box::new(WebsocketConn::from_websocket(ws))).await;
+                Ok(())
+            }
+            Transport::Grpc(ref service_name) => {
+                let mut conn =
+                    handshake(async { h2::server::handshake(stream).await.map_err(map_io_error) })
+                        .await?;
+                // accepting also drives the connection, so this runs until
+                // the client is gone
+                while let Some(accepted) = conn.accept().await {
+                    let (req, mut respond) = accepted.map_err(map_io_error)?;
+                    if !grpc_path_matches(req.uri().path(), service_name) {
+                        debug!("gRPC request to unknown path {}", req.uri().path());
+                        let res = Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(())
+                            .unwrap();
+                        respond.send_response(res, true).ok();
+                        continue;
+                    }
+
+                    let res = Response::builder()
+                        .status(StatusCode::OK)
+                        .header("content-type", "application/grpc")
+                        .body(())
+                        .unwrap();
+                    let send = respond.send_response(res, false).map_err(map_io_error)?;
+                    tokio::spawn(handler(Box::new(GrpcStream::from_server(
+                        req.into_body(),
+                        send,
+                    ))));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::config::internal::listener::InboundTransport;
+
+    use super::{grpc_path_matches, ServerTransport};
+
+    #[test]
+    fn test_grpc_path() {
+        assert!(grpc_path_matches("/GunService/Tun", "GunService"));
+        assert!(grpc_path_matches("/GunService", "GunService"));
+        assert!(!grpc_path_matches("/Other/Tun", "GunService"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let transport = ServerTransport::new(&InboundTransport {
+            ws_path: Some("/ws".to_owned()),
+            ..Default::default()
+        })
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let served = transport.serve(stream, |_| async {}).await;
+        assert_eq!(served.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
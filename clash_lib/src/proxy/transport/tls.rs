@@ -9,11 +9,15 @@ use crate::{
     proxy::AnyStream,
 };
 
+use super::fragment::{FragmentStream, TlsFragment};
+
 #[derive(Serialize, Clone)]
 pub struct TLSOptions {
     pub skip_cert_verify: bool,
     pub sni: String,
     pub alpn: Option<Vec<String>>,
+    /// split the ClientHello into several records
+    pub fragment: Option<TlsFragment>,
 }
 
 pub async fn wrap_stream(stream: AnyStream, opt: TLSOptions) -> io::Result<AnyStream> {
@@ -38,6 +42,11 @@ pub async fn wrap_stream(stream: AnyStream, opt: TLSOptions) -> io::Result<AnySt
     let dns_name = ServerName::try_from(opt.sni.as_str())
         .expect(format!("invalid server name: {}", opt.sni).as_str());
 
+    let stream = match opt.fragment {
+        Some(fragment) => Box::new(FragmentStream::new(stream, fragment)) as AnyStream,
+        None => stream,
+    };
+
     connector
         .connect(dns_name, stream)
         .await
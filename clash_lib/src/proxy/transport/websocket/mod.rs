@@ -21,6 +21,33 @@ macro_rules! ws_debug {
     };
 }
 
+/// the header xray and the v2ray browser dialer carry early data in
+const DEFAULT_EARLY_DATA_HEADER_NAME: &str = "Sec-WebSocket-Protocol";
+
+/// strips the xray style `ed=<max early data>` query parameter from `path`
+fn split_early_data_query(path: String) -> (String, Option<usize>) {
+    let Some((p, query)) = path.split_once('?') else {
+        return (path, None);
+    };
+
+    let mut max_early_data = None;
+    let mut rest = vec![];
+    for kv in query.split('&') {
+        match kv.split_once('=') {
+            Some(("ed", v)) if v.parse::<usize>().is_ok() => {
+                max_early_data = v.parse().ok();
+            }
+            _ => rest.push(kv),
+        }
+    }
+
+    if rest.is_empty() {
+        (p.to_owned(), max_early_data)
+    } else {
+        (format!("{}?{}", p, rest.join("&")), max_early_data)
+    }
+}
+
 pub struct WebsocketStreamBuilder {
     server: String,
     port: u16,
@@ -41,6 +68,19 @@ impl WebsocketStreamBuilder {
         max_early_data: usize,
         early_data_header_name: String,
     ) -> Self {
+        let (path, ed) = split_early_data_query(path);
+        let (max_early_data, early_data_header_name) = match ed {
+            Some(ed) if max_early_data == 0 => (
+                ed,
+                if early_data_header_name.is_empty() {
+                    DEFAULT_EARLY_DATA_HEADER_NAME.to_owned()
+                } else {
+                    early_data_header_name
+                },
+            ),
+            _ => (max_early_data, early_data_header_name),
+        };
+
         Self {
             server,
             port,
@@ -63,7 +103,7 @@ impl WebsocketStreamBuilder {
         for (k, v) in self.headers.iter() {
             request = request.header(k.as_str(), v.as_str());
         }
-        if self.max_early_data > 0 {
+        if self.max_early_data > 0 && !self.early_data_header_name.is_empty() {
             // we will replace this field later
             request = request.header(self.early_data_header_name.as_str(), "xxoo");
         }
@@ -96,3 +136,28 @@ impl WebsocketStreamBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::split_early_data_query;
+
+    #[test]
+    fn test_split_early_data_query() {
+        assert_eq!(
+            split_early_data_query("/ws?ed=2048".to_owned()),
+            ("/ws".to_owned(), Some(2048))
+        );
+        assert_eq!(
+            split_early_data_query("/ws?a=b&ed=1024&c".to_owned()),
+            ("/ws?a=b&c".to_owned(), Some(1024))
+        );
+        assert_eq!(
+            split_early_data_query("/ws?ed=foo".to_owned()),
+            ("/ws?ed=foo".to_owned(), None)
+        );
+        assert_eq!(
+            split_early_data_query("/ws".to_owned()),
+            ("/ws".to_owned(), None)
+        );
+    }
+}
@@ -8,7 +8,7 @@ use std::{
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use futures::ready;
 use futures::Future;
-use http::{HeaderValue, Request, StatusCode};
+use http::{HeaderValue, Request, StatusCode, Uri};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_tungstenite::{client_async_with_config, tungstenite::protocol::WebSocketConfig};
 
@@ -136,15 +136,29 @@ impl AsyncWrite for WebsocketEarlyDataConn {
                     return Poll::Ready(Ok(self.as_mut().early_data_len));
                 } else {
                     let mut req = self.as_mut().req.take().expect("req must be present");
-                    if let Some(v) = req
+                    self.as_mut().early_data_len =
+                        cmp::min(self.as_mut().early_data_len, buf.len());
+                    let early_data = URL_SAFE_NO_PAD.encode(&buf[..self.as_mut().early_data_len]);
+
+                    if self.as_mut().early_data_header_name.is_empty() {
+                        // v2ray style: early data is appended to the path
+                        let uri = req.uri().clone();
+                        let mut parts = uri.into_parts();
+                        let path_and_query = match parts.path_and_query.as_ref() {
+                            Some(pq) => match pq.query() {
+                                Some(q) => format!("{}{}?{}", pq.path(), early_data, q),
+                                None => format!("{}{}", pq.path(), early_data),
+                            },
+                            None => format!("/{}", early_data),
+                        };
+                        parts.path_and_query =
+                            Some(path_and_query.try_into().map_err(map_io_error)?);
+                        *req.uri_mut() = Uri::from_parts(parts).map_err(map_io_error)?;
+                    } else if let Some(v) = req
                         .headers_mut()
                         .get_mut(&self.as_mut().early_data_header_name)
                     {
-                        self.as_mut().early_data_len =
-                            cmp::min(self.as_mut().early_data_len, buf.len());
-                        let header_value =
-                            URL_SAFE_NO_PAD.encode(&buf[..self.as_mut().early_data_len]);
-                        *v = HeaderValue::from_str(&header_value).expect("bad header value");
+                        *v = HeaderValue::from_str(&early_data).expect("bad header value");
                     }
 
                     let stream = self.as_mut().stream.take().expect("msg: bad state");
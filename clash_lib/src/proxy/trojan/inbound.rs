@@ -0,0 +1,440 @@
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use sha2::{Digest, Sha224};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    common::{auth::ThreadSafeAllowList, utils},
+    config::internal::listener::InboundTrojan,
+    proxy::{
+        datagram::UdpPacket,
+        transport::ServerTransport,
+        tun::datagram::TunDatagram,
+        utils::{Acceptor, ConnectionLimiter},
+        AnyInboundListener, AnyStream, InboundListener,
+    },
+    session::{Inbound, Network, Session, SocksAddr, Type},
+    Dispatcher,
+};
+
+const COMMAND_TCP: u8 = 0x01;
+const COMMAND_UDP: u8 = 0x03;
+/// how long a client may take to send its request, an idle one would
+/// otherwise hold a connection of the listener forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves the trojan protocol, the server side of a `trojan` outbound.
+/// UDP is relayed in the streams, there's no UDP listener.
+pub struct Listener {
+    addr: SocketAddr,
+    opts: InboundTrojan,
+    dispatcher: Arc<Dispatcher>,
+    limiter: Option<ConnectionLimiter>,
+    allowlist: Option<ThreadSafeAllowList>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        warn!(
+            "Trojan inbound listener {} on {} stopped",
+            self.opts.name, self.addr
+        );
+    }
+}
+
+impl Listener {
+    pub fn new(
+        addr: SocketAddr,
+        opts: &InboundTrojan,
+        dispatcher: Arc<Dispatcher>,
+        limiter: Option<ConnectionLimiter>,
+        allowlist: Option<ThreadSafeAllowList>,
+    ) -> AnyInboundListener {
+        Arc::new(Self {
+            addr,
+            opts: opts.clone(),
+            dispatcher,
+            limiter,
+            allowlist,
+        }) as _
+    }
+
+    /// the hex sha224 of each password, which is what clients send, to the
+    /// user's name
+    fn users(&self) -> HashMap<String, String> {
+        self.opts
+            .users
+            .iter()
+            .enumerate()
+            .map(|(i, user)| {
+                let hash = utils::encode_hex(&Sha224::digest(user.password.as_bytes())[..]);
+                let name = user
+                    .username
+                    .clone()
+                    .unwrap_or_else(|| format!("user {}", i));
+                (hash, name)
+            })
+            .collect()
+    }
+}
+
+async fn read_crlf<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<()> {
+    let mut crlf = [0u8; 2];
+    stream.read_exact(&mut crlf).await?;
+    if &crlf != b"\r\n" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "trojan request is missing CRLF",
+        ));
+    }
+    Ok(())
+}
+
+/// reads the request header, returning the user, the network and the
+/// destination
+async fn accept<'a, S: AsyncRead + Unpin>(
+    stream: &mut S,
+    users: &'a HashMap<String, String>,
+    udp: bool,
+) -> io::Result<(&'a str, Network, SocksAddr)> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, read_request(stream, users, udp))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))?
+}
+
+async fn read_request<'a, S: AsyncRead + Unpin>(
+    stream: &mut S,
+    users: &'a HashMap<String, String>,
+    udp: bool,
+) -> io::Result<(&'a str, Network, SocksAddr)> {
+    let mut hash = [0u8; 56];
+    stream.read_exact(&mut hash).await?;
+    let user = std::str::from_utf8(&hash)
+        .ok()
+        .and_then(|x| users.get(x))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "unknown trojan user"))?;
+    read_crlf(stream).await?;
+
+    let network = match stream.read_u8().await? {
+        COMMAND_TCP => Network::Tcp,
+        COMMAND_UDP if udp => Network::Udp,
+        cmd => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported trojan command {}", cmd),
+            ))
+        }
+    };
+    let destination = SocksAddr::read_from(stream).await?;
+    read_crlf(stream).await?;
+    Ok((user, network, destination))
+}
+
+async fn handle(
+    mut stream: AnyStream,
+    src_addr: SocketAddr,
+    users: Arc<HashMap<String, String>>,
+    udp: bool,
+    dispatcher: Arc<Dispatcher>,
+    inbound: Inbound,
+) -> io::Result<()> {
+    let (user, network, destination) = accept(&mut stream, &users, udp).await?;
+
+    debug!(
+        "trojan {} from {} connects to {}",
+        user, src_addr, destination
+    );
+    let sess = Session {
+        network,
+        typ: Type::Trojan,
+        source: src_addr,
+        destination,
+        inbound,
+        user: Some(user.to_owned()),
+
+        ..Default::default()
+    };
+    match network {
+        Network::Tcp => {
+            dispatcher.dispatch_stream(sess, stream).await;
+        }
+        Network::Udp => relay_udp(stream, sess, dispatcher).await,
+    }
+    Ok(())
+}
+
+async fn relay_udp<S>(stream: S, sess: Session, dispatcher: Arc<Dispatcher>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (l_tx, l_rx) = mpsc::channel::<UdpPacket>(32);
+    let (d_tx, d_rx) = mpsc::channel::<UdpPacket>(32);
+    let src_addr = sess.source;
+    let closer =
+        dispatcher.dispatch_datagram(sess, Box::new(TunDatagram::new(l_tx, d_rx, src_addr)));
+
+    relay_packets(stream, src_addr, l_rx, d_tx).await;
+    closer.send(0).ok();
+}
+
+/// packets are framed as the address, length and CRLF ahead of the payload,
+/// both ways. The `replies` are written to the stream and the packets read
+/// from it are sent to `requests`, until either side is closed
+async fn relay_packets<S>(
+    stream: S,
+    src_addr: SocketAddr,
+    mut replies: mpsc::Receiver<UdpPacket>,
+    requests: mpsc::Sender<UdpPacket>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut r, mut w) = tokio::io::split(stream);
+
+    let write = async move {
+        while let Some(pkt) = replies.recv().await {
+            let mut buf = BytesMut::with_capacity(pkt.src_addr.size() + 4 + pkt.data.len());
+            pkt.src_addr.write_buf(&mut buf);
+            buf.put_u16(pkt.data.len() as u16);
+            buf.put_slice(b"\r\n");
+            buf.put_slice(&pkt.data);
+            if w.write_all(&buf).await.is_err() {
+                break;
+            }
+        }
+    };
+    let read = async move {
+        loop {
+            let read = async {
+                let dst_addr = SocksAddr::read_from(&mut r).await?;
+                let len = r.read_u16().await? as usize;
+                read_crlf(&mut r).await?;
+                let mut data = vec![0u8; len];
+                r.read_exact(&mut data).await?;
+                io::Result::Ok(UdpPacket {
+                    data,
+                    src_addr: src_addr.into(),
+                    dst_addr,
+                })
+            };
+            match read.await {
+                Ok(pkt) => {
+                    if requests.send(pkt).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    debug!("trojan udp from {} ended: {}", src_addr, e);
+                    break;
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = write => {}
+        _ = read => {}
+    }
+}
+
+#[async_trait]
+impl InboundListener for Listener {
+    fn handle_tcp(&self) -> bool {
+        true
+    }
+
+    fn handle_udp(&self) -> bool {
+        false
+    }
+
+    async fn listen_tcp(&self) -> io::Result<()> {
+        let users = Arc::new(self.users());
+        let transport = Arc::new(ServerTransport::new(&self.opts.transport)?);
+        let listener = TcpListener::bind(self.addr).await?;
+        let mut acceptor = Acceptor::new(listener, self.limiter.clone(), self.allowlist.clone());
+        let inbound = Inbound {
+            name: self.opts.name.clone(),
+            port: Some(self.addr.port()),
+            mode: self.opts.mode,
+        };
+
+        loop {
+            let (socket, src_addr, permit) = acceptor.accept().await;
+
+            let users = users.clone();
+            let transport = transport.clone();
+            let dispatcher = self.dispatcher.clone();
+            let udp = self.opts.udp;
+            let inbound = inbound.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                let served = transport
+                    .serve(socket, |stream| {
+                        let users = users.clone();
+                        let dispatcher = dispatcher.clone();
+                        let inbound = inbound.clone();
+                        async move {
+                            if let Err(e) =
+                                handle(stream, src_addr, users, udp, dispatcher, inbound).await
+                            {
+                                debug!("trojan request from {} rejected: {}", src_addr, e);
+                            }
+                        }
+                    })
+                    .await;
+                if let Err(e) = served {
+                    debug!("trojan connection from {} failed: {}", src_addr, e);
+                }
+            });
+        }
+    }
+
+    async fn listen_udp(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "unsupported"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io};
+
+    use bytes::{BufMut, BytesMut};
+    use sha2::{Digest, Sha224};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::mpsc,
+    };
+
+    use crate::{
+        common::utils,
+        proxy::datagram::UdpPacket,
+        session::{Network, SocksAddr},
+    };
+
+    use super::{accept, relay_packets, COMMAND_TCP, COMMAND_UDP};
+
+    fn users() -> HashMap<String, String> {
+        HashMap::from([(hash("password"), "alice".to_owned())])
+    }
+
+    fn hash(password: &str) -> String {
+        utils::encode_hex(&Sha224::digest(password.as_bytes())[..])
+    }
+
+    fn request(password: &str, command: u8, dst: &SocksAddr) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_slice(hash(password).as_bytes());
+        buf.put_slice(b"\r\n");
+        buf.put_u8(command);
+        dst.write_buf(&mut buf);
+        buf.put_slice(b"\r\n");
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_accept_user() {
+        let users = users();
+        let dst = SocksAddr::Domain("example.com".to_owned(), 443);
+
+        let mut req = &request("password", COMMAND_TCP, &dst)[..];
+        let (user, network, destination) = accept(&mut req, &users, false).await.unwrap();
+        assert_eq!(user, "alice");
+        assert_eq!(network, Network::Tcp);
+        assert_eq!(destination, dst);
+
+        let mut req = &request("wrong", COMMAND_TCP, &dst)[..];
+        let err = accept(&mut req, &users, false).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_reject_missing_crlf() {
+        let users = users();
+        let dst = SocksAddr::Domain("example.com".to_owned(), 443);
+
+        // after the hash
+        let mut req = request("password", COMMAND_TCP, &dst);
+        req[56] = b'\n';
+        let err = accept(&mut &req[..], &users, false).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // after the destination
+        let mut req = request("password", COMMAND_TCP, &dst);
+        req.truncate(req.len() - 2);
+        req.put_slice(b"xx");
+        let err = accept(&mut &req[..], &users, false).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_udp_command() {
+        let users = users();
+        let dst = SocksAddr::Domain("example.com".to_owned(), 53);
+        let req = request("password", COMMAND_UDP, &dst);
+
+        let err = accept(&mut &req[..], &users, false).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        let (_, network, _) = accept(&mut &req[..], &users, true).await.unwrap();
+        assert_eq!(network, Network::Udp);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let users = users();
+        let (_client, mut server) = tokio::io::duplex(1024);
+        let err = accept(&mut server, &users, false).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_udp_framing() {
+        let src_addr = "127.0.0.1:10000".parse().unwrap();
+        let dst = SocksAddr::Domain("example.com".to_owned(), 53);
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (reply_tx, reply_rx) = mpsc::channel(1);
+        let (request_tx, mut request_rx) = mpsc::channel(1);
+        let relay = tokio::spawn(relay_packets(server, src_addr, reply_rx, request_tx));
+
+        let mut buf = BytesMut::new();
+        dst.write_buf(&mut buf);
+        buf.put_u16(5);
+        buf.put_slice(b"\r\nhello");
+        client.write_all(&buf).await.unwrap();
+
+        let pkt = request_rx.recv().await.unwrap();
+        assert_eq!(pkt.dst_addr, dst);
+        assert_eq!(pkt.src_addr, SocksAddr::from(src_addr));
+        assert_eq!(pkt.data, b"hello");
+
+        let from = SocksAddr::Ip("1.2.3.4:53".parse().unwrap());
+        reply_tx
+            .send(UdpPacket {
+                data: b"world".to_vec(),
+                src_addr: from.clone(),
+                dst_addr: src_addr.into(),
+            })
+            .await
+            .unwrap();
+        let mut expected = BytesMut::new();
+        from.write_buf(&mut expected);
+        expected.put_u16(5);
+        expected.put_slice(b"\r\nworld");
+        let mut reply = vec![0u8; expected.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, &expected[..]);
+
+        // a bad frame ends the relay
+        client
+            .write_all(b"\x01\x01\x02\x03\x04\x00\x35\x00\x05xx")
+            .await
+            .unwrap();
+        relay.await.unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use std::{io, sync::Arc};
+use std::{collections::HashMap, io, sync::Arc};
 
 use async_trait::async_trait;
 use bytes::BufMut;
@@ -13,6 +13,7 @@ use crate::app::dispatcher::ChainedDatagram;
 use crate::app::dispatcher::ChainedDatagramWrapper;
 use crate::app::dispatcher::ChainedStream;
 use crate::app::dispatcher::ChainedStreamWrapper;
+use crate::common::errors::new_io_error;
 use crate::common::utils;
 use crate::{
     app::{dispatcher::BoxedChainedStream, dns::ThreadSafeDNSResolver},
@@ -22,21 +23,25 @@ use crate::{
 use self::datagram::OutboundDatagramTrojan;
 
 use super::transport;
-use super::transport::TLSOptions;
+use super::transport::{Http2Config, Http2ConnPool, TLSOptions, TlsFragment};
 use super::{
-    options::{GrpcOption, WsOption},
-    utils::new_tcp_stream,
+    options::{GrpcOption, Http2Option, WsOption},
+    utils::DialerProxy,
     AnyOutboundHandler, AnyStream, CommonOption, OutboundHandler, OutboundType,
 };
 
 mod datagram;
+mod inbound;
 mod stream;
 
+pub use inbound::Listener;
+
 static DEFAULT_ALPN: [&str; 2] = ["h2", "http/1.1"];
 
 pub enum Transport {
     Ws(WsOption),
     Grpc(GrpcOption),
+    H2(Http2Option),
 }
 
 pub struct Opts {
@@ -49,40 +54,133 @@ pub struct Opts {
     pub sni: String,
     pub alpn: Option<Vec<String>>,
     pub skip_cert_verify: bool,
+    pub tls_fragment: Option<TlsFragment>,
     pub transport: Option<Transport>,
 }
 
 pub struct Handler {
     opts: Opts,
+    h2_pool: Http2ConnPool,
 }
 
 impl Handler {
     pub fn new(opts: Opts) -> AnyOutboundHandler {
-        Arc::new(Self { opts })
+        Arc::new(Self {
+            opts,
+            h2_pool: Http2ConnPool::default(),
+        })
+    }
+
+    async fn dial(&self, resolver: ThreadSafeDNSResolver) -> io::Result<AnyStream> {
+        self.opts
+            .common_opts
+            .connect_stream(resolver, self.opts.server.as_str(), self.opts.port)
+            .map_err(|x| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "dial outbound {}:{}: {}",
+                        self.opts.server, self.opts.port, x
+                    ),
+                )
+            })
+            .await
+    }
+
+    fn tls_options(&self) -> TLSOptions {
+        // a configured alpn is kept whatever the transport
+        let alpn = self.opts.alpn.clone().or_else(|| {
+            Some(match self.opts.transport {
+                Some(Transport::H2(_)) => vec!["h2".to_owned()],
+                Some(Transport::Ws(_)) => vec!["http/1.1".to_owned()],
+                _ => DEFAULT_ALPN.iter().map(|x| x.to_string()).collect(),
+            })
+        });
+        TLSOptions {
+            skip_cert_verify: self.opts.skip_cert_verify,
+            sni: self.opts.sni.clone(),
+            alpn,
+            fragment: self.opts.tls_fragment,
+        }
+    }
+
+    fn h2_config(&self, opt: &Http2Option) -> io::Result<Http2Config> {
+        let hosts = if opt.host.is_empty() {
+            vec![self.opts.sni.clone()]
+        } else {
+            opt.host.clone()
+        };
+        Ok(Http2Config {
+            hosts,
+            method: http::Method::PUT,
+            headers: HashMap::new(),
+            path: opt
+                .path
+                .to_owned()
+                .try_into()
+                .map_err(|_| new_io_error(format!("invalid H2 path: {}", opt.path).as_str()))?,
+        })
+    }
+
+    /// dials the server and applies TLS and the transport layer.
+    /// h2 streams are multiplexed over a pooled connection of this outbound.
+    async fn connect_transport(&self, resolver: ThreadSafeDNSResolver) -> io::Result<AnyStream> {
+        match self.opts.transport {
+            Some(Transport::H2(ref opt)) => {
+                let h2_builder = self.h2_config(opt)?;
+                self.h2_pool
+                    .open_stream(&h2_builder, async {
+                        let stream = self.dial(resolver).await?;
+                        transport::tls::wrap_stream(stream, self.tls_options()).await
+                    })
+                    .await
+            }
+            _ => {
+                let stream = self.dial(resolver).await?;
+                self.transport_stream(stream).await
+            }
+        }
+    }
+
+    async fn transport_stream(&self, s: AnyStream) -> io::Result<AnyStream> {
+        let s = transport::tls::wrap_stream(s, self.tls_options()).await?;
+
+        match self.opts.transport {
+            Some(Transport::Ws(ref opt)) => {
+                let ws_builder = transport::WebsocketStreamBuilder::new(
+                    self.opts.server.clone(),
+                    self.opts.port,
+                    opt.path.clone(),
+                    opt.headers.clone(),
+                    None,
+                    opt.max_early_data,
+                    opt.early_data_header_name.clone(),
+                );
+                ws_builder.proxy_stream(s).await
+            }
+            Some(Transport::Grpc(ref opt)) => {
+                let grpc_builder = transport::GrpcStreamBuilder::new(
+                    self.opts.server.clone(),
+                    opt.service_name
+                        .to_owned()
+                        .try_into()
+                        .map_err(|_| new_io_error("invalid gRPC service path"))?,
+                );
+                grpc_builder.proxy_stream(s).await
+            }
+            Some(Transport::H2(ref opt)) => self.h2_config(opt)?.proxy_stream(s).await,
+            None => Ok(s),
+        }
     }
 
     /// TCP: 0x01,
     /// UDP: 0x03,
-    async fn inner_proxy_stream(
+    async fn trojan_stream(
         &self,
-        s: AnyStream,
+        mut s: AnyStream,
         sess: &Session,
         tcp: bool,
     ) -> io::Result<AnyStream> {
-        let tls_opt = TLSOptions {
-            skip_cert_verify: self.opts.skip_cert_verify,
-            sni: self.opts.sni.clone(),
-            alpn: self.opts.alpn.clone().or(Some(
-                DEFAULT_ALPN
-                    .to_vec()
-                    .into_iter()
-                    .map(|x| x.to_owned())
-                    .collect::<Vec<String>>(),
-            )),
-        };
-
-        let mut s = transport::tls::wrap_stream(s, tls_opt.to_owned()).await?;
-
         let mut buf = BytesMut::new();
         let password = Sha224::digest(self.opts.password.as_bytes());
         let password = utils::encode_hex(&password[..]);
@@ -95,6 +193,16 @@ impl Handler {
 
         Ok(s)
     }
+
+    async fn inner_proxy_stream(
+        &self,
+        s: AnyStream,
+        sess: &Session,
+        tcp: bool,
+    ) -> io::Result<AnyStream> {
+        let s = self.transport_stream(s).await?;
+        self.trojan_stream(s, sess, tcp).await
+    }
 }
 
 #[async_trait]
@@ -107,6 +215,10 @@ impl OutboundHandler for Handler {
         OutboundType::Trojan
     }
 
+    fn dialer_proxy(&self) -> Option<&DialerProxy> {
+        self.opts.common_opts.dialer_proxy()
+    }
+
     async fn remote_addr(&self) -> Option<SocksAddr> {
         Some(SocksAddr::Domain(self.opts.server.clone(), self.opts.port))
     }
@@ -120,26 +232,8 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        let stream = new_tcp_stream(
-            resolver.clone(),
-            self.opts.server.as_str(),
-            self.opts.port,
-            self.opts.common_opts.iface.as_ref(),
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            None,
-        )
-        .map_err(|x| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "dial outbound {}:{}: {}",
-                    self.opts.server, self.opts.port, x
-                ),
-            )
-        })
-        .await?;
-
-        let stream = self.proxy_stream(stream, sess, resolver).await?;
+        let stream = self.connect_transport(resolver).await?;
+        let stream = self.trojan_stream(stream, sess, true).await?;
 
         let chained = ChainedStreamWrapper::new(stream);
         chained.append_to_chain(self.name()).await;
@@ -160,26 +254,8 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
-        let stream = new_tcp_stream(
-            resolver.clone(),
-            self.opts.server.as_str(),
-            self.opts.port,
-            self.opts.common_opts.iface.as_ref(),
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            None,
-        )
-        .map_err(|x| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "dial outbound {}:{}: {}",
-                    self.opts.server, self.opts.port, x
-                ),
-            )
-        })
-        .await?;
-
-        let stream = self.inner_proxy_stream(stream, sess, false).await?;
+        let stream = self.connect_transport(resolver).await?;
+        let stream = self.trojan_stream(stream, sess, false).await?;
 
         let d = OutboundDatagramTrojan::new(stream, sess.destination.clone());
 
@@ -188,3 +264,69 @@ impl OutboundHandler for Handler {
         Ok(Box::new(chained))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::{
+        options::{Http2Option, WsOption},
+        transport::Http2ConnPool,
+        CommonOption,
+    };
+
+    use super::{Handler, Opts, Transport};
+
+    fn handler(alpn: Option<Vec<String>>, transport: Option<Transport>) -> Handler {
+        Handler {
+            opts: Opts {
+                name: "trojan".to_owned(),
+                common_opts: CommonOption::default(),
+                server: "example.com".to_owned(),
+                port: 443,
+                password: "password".to_owned(),
+                udp: true,
+                sni: "example.com".to_owned(),
+                alpn,
+                skip_cert_verify: false,
+                tls_fragment: None,
+                transport,
+            },
+            h2_pool: Http2ConnPool::default(),
+        }
+    }
+
+    #[test]
+    fn test_tls_options_alpn() {
+        let h2 = || {
+            Some(Transport::H2(Http2Option {
+                host: vec![],
+                path: "/".to_owned(),
+            }))
+        };
+        let ws = || {
+            Some(Transport::Ws(WsOption {
+                path: "/".to_owned(),
+                headers: Default::default(),
+                max_early_data: 0,
+                early_data_header_name: "".to_owned(),
+            }))
+        };
+
+        assert_eq!(
+            handler(None, None).tls_options().alpn,
+            Some(vec!["h2".to_owned(), "http/1.1".to_owned()])
+        );
+        assert_eq!(
+            handler(None, h2()).tls_options().alpn,
+            Some(vec!["h2".to_owned()])
+        );
+        assert_eq!(
+            handler(None, ws()).tls_options().alpn,
+            Some(vec!["http/1.1".to_owned()])
+        );
+
+        let alpn = Some(vec!["x".to_owned()]);
+        assert_eq!(handler(alpn.clone(), None).tls_options().alpn, alpn);
+        assert_eq!(handler(alpn.clone(), h2()).tls_options().alpn, alpn);
+        assert_eq!(handler(alpn.clone(), ws()).tls_options().alpn, alpn);
+    }
+}
@@ -0,0 +1,170 @@
+//! Answers pings arriving on the tun device. The userspace stacks drop ICMP
+//! for addresses other than their own, so without this `ping` through the
+//! tun times out even when everything else works. The reply is made
+//! locally: it tells the tun path is up, not that the remote host is.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const PROTO_ICMP: u8 = 1;
+const PROTO_ICMPV6: u8 = 58;
+
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+const IPV4_MIN_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const ICMP_HEADER_LEN: usize = 8;
+const REPLY_TTL: u8 = 64;
+
+/// the echo reply to `pkt`, if it's an ICMP or ICMPv6 echo request
+pub fn echo_reply(pkt: &[u8]) -> Option<Vec<u8>> {
+    match pkt.first()? >> 4 {
+        4 => echo_reply_v4(pkt),
+        6 => echo_reply_v6(pkt),
+        _ => None,
+    }
+}
+
+/// the ones' complement sum of `data` in 16 bit words, before folding
+fn sum(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|x| u16::from_be_bytes([x[0], *x.get(1).unwrap_or(&0)]) as u32)
+        .sum()
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn echo_reply_v4(pkt: &[u8]) -> Option<Vec<u8>> {
+    let header_len = ((pkt[0] & 0x0f) as usize) * 4;
+    let total_len = u16::from_be_bytes([*pkt.get(2)?, *pkt.get(3)?]) as usize;
+    if header_len < IPV4_MIN_HEADER_LEN
+        || total_len > pkt.len()
+        || total_len < header_len + ICMP_HEADER_LEN
+        || pkt[9] != PROTO_ICMP
+    {
+        return None;
+    }
+    // fragments are left to the stack
+    let flags_and_offset = u16::from_be_bytes([pkt[6], pkt[7]]);
+    if flags_and_offset & 0x3fff != 0 {
+        return None;
+    }
+    let dst = Ipv4Addr::new(pkt[16], pkt[17], pkt[18], pkt[19]);
+    if dst.is_multicast() || dst.is_broadcast() {
+        return None;
+    }
+    if pkt[header_len] != ICMP_ECHO_REQUEST || pkt[header_len + 1] != 0 {
+        return None;
+    }
+
+    let mut reply = pkt[..total_len].to_vec();
+    let (src, dst) = reply[12..20].split_at_mut(4);
+    src.swap_with_slice(dst);
+    reply[8] = REPLY_TTL;
+    reply[10..12].copy_from_slice(&[0, 0]);
+    let checksum = fold(sum(&reply[..header_len]));
+    reply[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    let icmp = &mut reply[header_len..];
+    icmp[0] = ICMP_ECHO_REPLY;
+    icmp[2..4].copy_from_slice(&[0, 0]);
+    let checksum = fold(sum(icmp));
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    Some(reply)
+}
+
+/// only echo requests right after the fixed header, which is all `ping`
+/// sends
+fn echo_reply_v6(pkt: &[u8]) -> Option<Vec<u8>> {
+    if pkt.len() < IPV6_HEADER_LEN + ICMP_HEADER_LEN || pkt[6] != PROTO_ICMPV6 {
+        return None;
+    }
+    let payload_len = u16::from_be_bytes([pkt[4], pkt[5]]) as usize;
+    if payload_len < ICMP_HEADER_LEN || IPV6_HEADER_LEN + payload_len > pkt.len() {
+        return None;
+    }
+    let mut dst = [0u8; 16];
+    dst.copy_from_slice(&pkt[24..40]);
+    if Ipv6Addr::from(dst).is_multicast() {
+        return None;
+    }
+    if pkt[IPV6_HEADER_LEN] != ICMPV6_ECHO_REQUEST || pkt[IPV6_HEADER_LEN + 1] != 0 {
+        return None;
+    }
+
+    let mut reply = pkt[..IPV6_HEADER_LEN + payload_len].to_vec();
+    let (src, dst) = reply[8..40].split_at_mut(16);
+    src.swap_with_slice(dst);
+    reply[7] = REPLY_TTL;
+
+    // the pseudo header: addresses, upper layer length and next header
+    let pseudo = sum(&reply[8..40]) + payload_len as u32 + PROTO_ICMPV6 as u32;
+    let icmp = &mut reply[IPV6_HEADER_LEN..];
+    icmp[0] = ICMPV6_ECHO_REPLY;
+    icmp[2..4].copy_from_slice(&[0, 0]);
+    let checksum = fold(pseudo + sum(icmp));
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{echo_reply, fold, sum};
+
+    /// `ping -c 1 1.1.1.1` from 198.18.0.1, with 4 bytes of data
+    fn ping_v4() -> Vec<u8> {
+        let mut pkt = vec![
+            0x45, 0, 0, 32, 0x12, 0x34, 0x40, 0, 64, 1, 0, 0, 198, 18, 0, 1, 1, 1, 1, 1,
+        ];
+        pkt.extend_from_slice(&[8, 0, 0, 0, 0, 1, 0, 1, b'p', b'i', b'n', b'g']);
+        let checksum = fold(sum(&pkt[..20]));
+        pkt[10..12].copy_from_slice(&checksum.to_be_bytes());
+        let checksum = fold(sum(&pkt[20..]));
+        pkt[22..24].copy_from_slice(&checksum.to_be_bytes());
+        pkt
+    }
+
+    #[test]
+    fn test_echo_reply_v4() {
+        let reply = echo_reply(&ping_v4()).expect("should answer");
+        assert_eq!(&reply[12..16], &[1, 1, 1, 1]);
+        assert_eq!(&reply[16..20], &[198, 18, 0, 1]);
+        assert_eq!(reply[20], 0);
+        assert_eq!(&reply[24..], b"\x00\x01\x00\x01ping");
+        // a valid checksum sums up to 0
+        assert_eq!(fold(sum(&reply[..20])), 0);
+        assert_eq!(fold(sum(&reply[20..])), 0);
+
+        assert!(echo_reply(&reply).is_none());
+        let mut udp = ping_v4();
+        udp[9] = 17;
+        assert!(echo_reply(&udp).is_none());
+    }
+
+    #[test]
+    fn test_echo_reply_v6() {
+        let src: std::net::Ipv6Addr = "fdfe:dcba:9876::1".parse().unwrap();
+        let dst: std::net::Ipv6Addr = "2606:4700:4700::1111".parse().unwrap();
+        let mut pkt = vec![0x60, 0, 0, 0, 0, 12, 58, 64];
+        pkt.extend_from_slice(&src.octets());
+        pkt.extend_from_slice(&dst.octets());
+        pkt.extend_from_slice(&[128, 0, 0, 0, 0, 1, 0, 1, b'p', b'i', b'n', b'g']);
+
+        let reply = echo_reply(&pkt).expect("should answer");
+        assert_eq!(&reply[8..24], &dst.octets());
+        assert_eq!(&reply[24..40], &src.octets());
+        assert_eq!(reply[40], 129);
+
+        let pseudo = sum(&reply[8..40]) + 12 + 58;
+        assert_eq!(fold(pseudo + sum(&reply[40..])), 0);
+    }
+}
@@ -1,48 +1,61 @@
-use super::{datagram::TunDatagram, netstack};
-use std::{net::SocketAddr, sync::Arc};
+use super::{datagram::TunDatagram, icmp, netstack};
+use std::{fmt::Display, io, net::SocketAddr, sync::Arc};
 
-use futures::{SinkExt, StreamExt};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::{error, info, trace, warn};
 use tun::{Device, TunPacket};
 use url::Url;
 
 use crate::{
     app::{dispatcher::Dispatcher, dns::ThreadSafeDNSResolver},
-    common::errors::map_io_error,
-    config::internal::config::TunConfig,
+    common::{dscp::DscpTable, errors::map_io_error},
+    config::internal::config::{TunConfig, TunStack},
     proxy::datagram::UdpPacket,
-    session::{Network, Session, SocksAddr, Type},
+    session::{Inbound, Network, Session, SocksAddr, Type},
     Error, Runner,
 };
 
-async fn handle_inbound_stream(
-    stream: netstack::TcpStream,
+/// a UDP packet between the stack and the dispatcher: payload, source and
+/// destination
+type StackUdpPacket = (Vec<u8>, SocketAddr, SocketAddr);
+
+async fn handle_inbound_stream<S>(
+    stream: S,
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
-) {
+    inbound: Inbound,
+    dscp_marks: Arc<DscpTable>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
     let sess = Session {
         network: Network::Tcp,
         typ: Type::Tun,
         source: local_addr,
         destination: remote_addr.into(),
+        inbound,
+        dscp_marks: Some(dscp_marks),
         ..Default::default()
     };
 
     dispatcher.dispatch_stream(sess, stream).await;
 }
 
-async fn handle_inbound_datagram(
-    socket: Box<netstack::UdpSocket>,
+async fn handle_inbound_datagram<R, W>(
+    mut lr: R,
+    mut ls: W,
+    local_addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     resolver: ThreadSafeDNSResolver,
-) {
-    let local_addr = socket.local_addr();
-    // tun i/o
-
-    let (ls, mut lr) = socket.split();
-    let ls = Arc::new(ls);
-
+    inbound: Inbound,
+    dscp_marks: Arc<DscpTable>,
+) where
+    R: Stream<Item = StackUdpPacket> + Unpin + Send + 'static,
+    W: Sink<StackUdpPacket> + Unpin + Send + 'static,
+    W::Error: Display,
+{
     // dispatcher <-> tun communications
     let (l_tx, mut l_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
 
@@ -56,6 +69,8 @@ async fn handle_inbound_datagram(
     let sess = Session {
         network: Network::Udp,
         typ: Type::Tun,
+        inbound,
+        dscp_marks: Some(dscp_marks),
         ..Default::default()
     };
 
@@ -82,11 +97,10 @@ async fn handle_inbound_datagram(
                     }
                 }
             };
-            if let Err(e) = ls.send_to(
-                &pkt.data[..],
-                &src_addr,
-                &pkt.dst_addr.must_into_socket_addr(),
-            ) {
+            if let Err(e) = ls
+                .send((pkt.data, src_addr, pkt.dst_addr.must_into_socket_addr()))
+                .await
+            {
                 warn!("failed to send udp packet to netstack: {}", e);
             }
         }
@@ -94,7 +108,7 @@ async fn handle_inbound_datagram(
 
     // tun -> dispatcher
     tokio::spawn(async move {
-        while let Ok((data, src_addr, dst_addr)) = lr.recv_from().await {
+        while let Some((data, src_addr, dst_addr)) = lr.next().await {
             let pkt = UdpPacket {
                 data,
                 src_addr: src_addr.into(),
@@ -115,8 +129,11 @@ async fn handle_inbound_datagram(
     });
 }
 
+/// `name` is that of the inbound of the sessions, `Tun` or the name of a
+/// `listeners` entry
 pub fn get_runner(
     cfg: TunConfig,
+    name: String,
     dispatcher: Arc<Dispatcher>,
     resolver: ThreadSafeDNSResolver,
 ) -> Result<Option<Runner>, Error> {
@@ -126,6 +143,11 @@ pub fn get_runner(
     }
 
     let device_id = cfg.device_id;
+    let inbound = Inbound {
+        name,
+        port: None,
+        mode: cfg.mode,
+    };
 
     let u =
         Url::parse(&device_id).map_err(|x| Error::InvalidConfig(format!("tun device {}", x)))?;
@@ -161,20 +183,120 @@ pub fn get_runner(
     let tun_name = tun.get_ref().name().map_err(map_io_error)?;
     info!("tun started at {}", tun_name);
 
-    let (stack, mut tcp_listener, udp_socket) =
-        netstack::NetStack::with_buffer_size(512, 256).map_err(map_io_error)?;
-
-    Ok(Some(Box::pin(async move {
-        let framed = tun.into_framed();
+    match cfg.stack {
+        TunStack::System => {
+            let (stack, tcp_listener, udp_socket) =
+                netstack::NetStack::with_buffer_size(512, 256).map_err(map_io_error)?;
+
+            let local_addr = udp_socket.local_addr();
+            let (ls, lr) = udp_socket.split();
+            let udp_rx = Box::pin(futures::stream::unfold(lr, |mut lr| async move {
+                lr.recv_from().await.ok().map(|pkt| (pkt, lr))
+            }));
+            let udp_tx = Box::pin(futures::sink::unfold(
+                ls,
+                |ls, (data, src, dst): StackUdpPacket| async move {
+                    ls.send_to(&data[..], &src, &dst).map(|_| ls)
+                },
+            ));
+
+            Ok(Some(run_stack(
+                tun.into_framed(),
+                stack,
+                tcp_listener,
+                udp_rx,
+                udp_tx,
+                local_addr,
+                None,
+                dispatcher,
+                resolver,
+                inbound,
+            )))
+        }
+        TunStack::Gvisor => {
+            let (stack, runner, udp_socket, tcp_listener) =
+                netstack_smoltcp::StackBuilder::default()
+                    .stack_buffer_size(512)
+                    .tcp_buffer_size(4096)
+                    .enable_udp(true)
+                    .enable_tcp(true)
+                    .build()?;
+            let tcp_listener = tcp_listener
+                .ok_or_else(|| Error::Operation("gvisor stack without tcp".to_string()))?;
+            let (udp_rx, udp_tx) = udp_socket
+                .ok_or_else(|| Error::Operation("gvisor stack without udp".to_string()))?
+                .split();
+            // the stack reports no address of its own for UDP
+            let local_addr = SocketAddr::from(([0, 0, 0, 0], 0));
+
+            let runner = runner.map(|runner| -> Runner {
+                Box::pin(async move {
+                    runner.await.map_err(Error::Io)?;
+                    Err(Error::Operation(
+                        "tun stack stopped unexpectedly".to_string(),
+                    ))
+                })
+            });
+
+            Ok(Some(run_stack(
+                tun.into_framed(),
+                stack,
+                tcp_listener,
+                udp_rx,
+                udp_tx,
+                local_addr,
+                runner,
+                dispatcher,
+                resolver,
+                inbound,
+            )))
+        }
+    }
+}
 
-        let (mut tun_sink, mut tun_stream) = framed.split();
-        let (mut stack_sink, mut stack_stream) = stack.split();
+/// relays packets between the tun device and a userspace stack, and hands
+/// what the stack assembles to the dispatcher. `stack_runner` drives stacks
+/// that need their own task
+#[allow(clippy::too_many_arguments)]
+fn run_stack<T, S, L, C, R, W>(
+    tun: T,
+    stack: S,
+    mut tcp_listener: L,
+    udp_rx: R,
+    udp_tx: W,
+    udp_addr: SocketAddr,
+    stack_runner: Option<Runner>,
+    dispatcher: Arc<Dispatcher>,
+    resolver: ThreadSafeDNSResolver,
+    inbound: Inbound,
+) -> Runner
+where
+    T: Stream<Item = io::Result<TunPacket>> + Sink<TunPacket, Error = io::Error> + Send + 'static,
+    S: Stream<Item = io::Result<Vec<u8>>> + Sink<Vec<u8>> + Send + 'static,
+    S::Error: Display,
+    L: Stream<Item = (C, SocketAddr, SocketAddr)> + Unpin + Send + 'static,
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    R: Stream<Item = StackUdpPacket> + Unpin + Send + 'static,
+    W: Sink<StackUdpPacket> + Unpin + Send + 'static,
+    W::Error: Display,
+{
+    Box::pin(async move {
+        let (mut tun_sink, mut tun_stream) = tun.split();
+        let (mut stack_sink, stack_stream) = stack.split();
+        // echo replies written back to the tun next to what the stack sends
+        let (mut icmp_tx, icmp_rx) = futures::channel::mpsc::channel::<Vec<u8>>(32);
+        let mut outgoing = futures::stream::select(stack_stream, icmp_rx.map(Ok));
+
+        let dscp_marks = Arc::new(DscpTable::new(dispatcher.needs_dscp()));
 
         let mut futs: Vec<Runner> = vec![];
+        if let Some(runner) = stack_runner {
+            futs.push(runner);
+        }
 
         // dispatcher -> stack -> tun
         futs.push(Box::pin(async move {
-            while let Some(pkt) = stack_stream.next().await {
+            while let Some(pkt) = outgoing.next().await {
                 match pkt {
                     Ok(pkt) => {
                         if let Err(e) = tun_sink.send(TunPacket::new(pkt)).await {
@@ -193,11 +315,20 @@ pub fn get_runner(
         }));
 
         // tun -> stack -> dispatcher
+        let marks = dscp_marks.clone();
         futs.push(Box::pin(async move {
             while let Some(pkt) = tun_stream.next().await {
                 match pkt {
                     Ok(pkt) => {
-                        if let Err(e) = stack_sink.send(pkt.into_bytes().into()).await {
+                        let pkt = pkt.into_bytes();
+                        if let Some(reply) = icmp::echo_reply(&pkt) {
+                            if icmp_tx.try_send(reply).is_err() {
+                                trace!("tun is busy, dropping an ICMP echo reply");
+                            }
+                            continue;
+                        }
+                        marks.record(&pkt);
+                        if let Err(e) = stack_sink.send(pkt.into()).await {
                             error!("failed to send pkt to stack: {}", e);
                             break;
                         }
@@ -213,6 +344,8 @@ pub fn get_runner(
         }));
 
         let dsp = dispatcher.clone();
+        let tcp_inbound = inbound.clone();
+        let marks = dscp_marks.clone();
         futs.push(Box::pin(async move {
             while let Some((stream, local_addr, remote_addr)) = tcp_listener.next().await {
                 tokio::spawn(handle_inbound_stream(
@@ -220,6 +353,8 @@ pub fn get_runner(
                     local_addr,
                     remote_addr,
                     dsp.clone(),
+                    tcp_inbound.clone(),
+                    marks.clone(),
                 ));
             }
 
@@ -227,7 +362,10 @@ pub fn get_runner(
         }));
 
         futs.push(Box::pin(async move {
-            handle_inbound_datagram(udp_socket, dispatcher, resolver).await;
+            handle_inbound_datagram(
+                udp_rx, udp_tx, udp_addr, dispatcher, resolver, inbound, dscp_marks,
+            )
+            .await;
             Err(Error::Operation("tun stopped unexpectedly 3".to_string()))
         }));
 
@@ -235,5 +373,5 @@ pub fn get_runner(
             error!("tun error: {}. stopped", x);
             x
         })
-    })))
+    })
 }
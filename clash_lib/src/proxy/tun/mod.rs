@@ -1,4 +1,5 @@
 pub mod inbound;
 pub use netstack_lwip as netstack;
-mod datagram;
+pub(crate) mod datagram;
+mod icmp;
 pub use inbound::get_runner as get_tun_runner;
@@ -0,0 +1,345 @@
+//! UDP-over-TCP v2, carries UDP sessions over stream-only outbounds.
+//! The wire format is compatible with sing-box.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, BytesMut};
+use erased_serde::Serialize as ESerialize;
+use futures::{Sink, SinkExt, Stream};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::debug;
+
+use crate::{
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram, ChainedDatagramWrapper,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    session::{Network, Session, SocksAddr},
+};
+
+use super::{
+    datagram::UdpPacket, utils::DialerProxy, AnyOutboundHandler, AnyStream, OutboundHandler,
+    OutboundType,
+};
+
+/// the destination UoT v2 servers recognize as a UoT request
+const MAGIC_ADDRESS: &str = "sp.v2.udp-over-tcp.arpa";
+
+const ATYP_IPV4: u8 = 0x00;
+const ATYP_IPV6: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x02;
+
+/// a domain has its length in one byte, a longer one can't be sent
+fn check_addr(addr: &SocksAddr) -> io::Result<()> {
+    match addr {
+        SocksAddr::Domain(domain, _) if domain.len() > u8::MAX as usize => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("domain too long for uot: {} bytes", domain.len()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// the addresses of the packets use their own address type values, unlike
+/// SOCKS5. The one of the request is a SOCKS5 address, as sing-box reads it
+fn write_addr<B: BufMut>(addr: &SocksAddr, buf: &mut B) -> io::Result<()> {
+    check_addr(addr)?;
+    match addr {
+        SocksAddr::Ip(SocketAddr::V4(addr)) => {
+            buf.put_u8(ATYP_IPV4);
+            buf.put_slice(&addr.ip().octets());
+            buf.put_u16(addr.port());
+        }
+        SocksAddr::Ip(SocketAddr::V6(addr)) => {
+            buf.put_u8(ATYP_IPV6);
+            buf.put_slice(&addr.ip().octets());
+            buf.put_u16(addr.port());
+        }
+        SocksAddr::Domain(domain, port) => {
+            buf.put_u8(ATYP_DOMAIN);
+            buf.put_u8(domain.len() as u8);
+            buf.put_slice(domain.as_bytes());
+            buf.put_u16(*port);
+        }
+    }
+    Ok(())
+}
+
+/// returns the address and its encoded length, or None if `buf` is incomplete
+fn peek_addr(buf: &[u8]) -> io::Result<Option<(SocksAddr, usize)>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    match buf[0] {
+        ATYP_IPV4 => {
+            if buf.len() < 1 + 4 + 2 {
+                return Ok(None);
+            }
+            let ip = Ipv4Addr::new(buf[1], buf[2], buf[3], buf[4]);
+            let port = u16::from_be_bytes([buf[5], buf[6]]);
+            Ok(Some(((ip, port).into(), 7)))
+        }
+        ATYP_IPV6 => {
+            if buf.len() < 1 + 16 + 2 {
+                return Ok(None);
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buf[1..17]);
+            let port = u16::from_be_bytes([buf[17], buf[18]]);
+            Ok(Some(((Ipv6Addr::from(octets), port).into(), 19)))
+        }
+        ATYP_DOMAIN => {
+            if buf.len() < 2 {
+                return Ok(None);
+            }
+            let len = buf[1] as usize;
+            if buf.len() < 2 + len + 2 {
+                return Ok(None);
+            }
+            let domain = String::from_utf8(buf[2..2 + len].to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let port = u16::from_be_bytes([buf[2 + len], buf[3 + len]]);
+            Ok(Some((SocksAddr::Domain(domain, port), 4 + len)))
+        }
+        t => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid uot address type: {}", t),
+        )),
+    }
+}
+
+/*
++---------+----------+--------+----------+
+|  ATYP   |  ADDR    | LENGTH |   DATA   |
++---------+----------+--------+----------+
+|  1      | Variable |   2    | Variable |
++---------+----------+--------+----------+
+*/
+struct UotCodec;
+
+impl Encoder<UdpPacket> for UotCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: UdpPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if item.data.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "udp packet too large",
+            ));
+        }
+        dst.reserve(item.dst_addr.size() + 2 + item.data.len());
+        write_addr(&item.dst_addr, dst)?;
+        dst.put_u16(item.data.len() as u16);
+        dst.put_slice(&item.data);
+        Ok(())
+    }
+}
+
+impl Decoder for UotCodec {
+    type Item = UdpPacket;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let (addr, addr_len) = match peek_addr(src)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        if src.len() < addr_len + 2 {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes([src[addr_len], src[addr_len + 1]]) as usize;
+        if src.len() < addr_len + 2 + len {
+            src.reserve(addr_len + 2 + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(addr_len + 2);
+        let data = src.split_to(len).to_vec();
+        Ok(Some(UdpPacket {
+            data,
+            src_addr: addr,
+            dst_addr: SocksAddr::any_ipv4(),
+        }))
+    }
+}
+
+struct OutboundDatagramUot {
+    inner: Framed<AnyStream, UotCodec>,
+}
+
+impl Sink<UdpPacket> for OutboundDatagramUot {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+        self.inner.start_send_unpin(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+impl Stream for OutboundDatagramUot {
+    type Item = UdpPacket;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match futures::ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+            Some(Ok(pkt)) => Poll::Ready(Some(pkt)),
+            Some(Err(e)) => {
+                debug!("failed to read uot packet: {}", e);
+                Poll::Ready(None)
+            }
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// Wraps an outbound so that its UDP sessions are carried over its TCP streams.
+pub struct Handler {
+    inner: AnyOutboundHandler,
+}
+
+impl Handler {
+    pub fn new(inner: AnyOutboundHandler) -> AnyOutboundHandler {
+        Arc::new(Self { inner })
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn proto(&self) -> OutboundType {
+        self.inner.proto()
+    }
+
+    fn dialer_proxy(&self) -> Option<&DialerProxy> {
+        self.inner.dialer_proxy()
+    }
+
+    async fn remote_addr(&self) -> Option<SocksAddr> {
+        self.inner.remote_addr().await
+    }
+
+    async fn support_udp(&self) -> bool {
+        true
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        self.inner.connect_stream(sess, resolver).await
+    }
+
+    async fn proxy_stream(
+        &self,
+        s: AnyStream,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<AnyStream> {
+        self.inner.proxy_stream(s, sess, resolver).await
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        check_addr(&sess.destination)?;
+        let uot_sess = Session {
+            network: Network::Tcp,
+            destination: SocksAddr::Domain(MAGIC_ADDRESS.to_owned(), 0),
+            ..sess.clone()
+        };
+        let mut stream = self.inner.connect_stream(&uot_sess, resolver).await?;
+
+        // not connected: every packet carries its own destination
+        let mut req = BytesMut::new();
+        req.put_u8(0);
+        sess.destination.write_buf(&mut req);
+        tokio::io::AsyncWriteExt::write_all(&mut stream, &req).await?;
+
+        let d = OutboundDatagramUot {
+            inner: Framed::new(Box::new(stream) as AnyStream, UotCodec),
+        };
+        let chained = ChainedDatagramWrapper::new(d);
+        chained.append_to_chain(self.name()).await;
+        Ok(Box::new(chained))
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        self.inner.as_map().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::{proxy::datagram::UdpPacket, session::SocksAddr};
+
+    use super::UotCodec;
+
+    #[test]
+    fn test_uot_codec_roundtrip() {
+        let mut buf = BytesMut::new();
+        UotCodec
+            .encode(
+                UdpPacket {
+                    data: b"hello".to_vec(),
+                    src_addr: SocksAddr::any_ipv4(),
+                    dst_addr: SocksAddr::Domain("example.com".to_owned(), 53),
+                },
+                &mut buf,
+            )
+            .unwrap();
+
+        let mut partial = buf.split_to(5);
+        assert!(UotCodec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+
+        let pkt = UotCodec.decode(&mut partial).unwrap().unwrap();
+        assert_eq!(pkt.data, b"hello");
+        assert_eq!(
+            pkt.src_addr,
+            SocksAddr::Domain("example.com".to_owned(), 53)
+        );
+        assert!(partial.is_empty());
+
+        let mut buf = BytesMut::new();
+        assert!(UotCodec
+            .encode(
+                UdpPacket {
+                    data: b"hello".to_vec(),
+                    src_addr: SocksAddr::any_ipv4(),
+                    dst_addr: SocksAddr::Domain("a".repeat(256), 53),
+                },
+                &mut buf,
+            )
+            .is_err());
+        assert!(buf.is_empty());
+    }
+}
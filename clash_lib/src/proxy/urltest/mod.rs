@@ -17,8 +17,8 @@ use crate::{
 };
 
 use super::{
-    utils::provider_helper::get_proxies_from_providers, AnyOutboundHandler, AnyStream,
-    CommonOption, OutboundHandler, OutboundType,
+    utils::provider_helper::{get_proxies_from_providers, spill_over},
+    AnyOutboundHandler, AnyStream, CommonOption, OutboundHandler, OutboundType,
 };
 
 #[derive(Default)]
@@ -119,6 +119,13 @@ impl Handler {
             .unwrap_or(proxies.first().unwrap())
             .clone();
     }
+
+    /// the fastest proxy, or while it's at its `max-connections`, the first
+    /// member after it that isn't
+    async fn select(&self, touch: bool) -> AnyOutboundHandler {
+        let fastest = self.fastest(touch).await;
+        spill_over(fastest, &self.get_proxies(false).await)
+    }
 }
 
 #[async_trait::async_trait]
@@ -150,7 +157,7 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
         let s = self
-            .fastest(false)
+            .select(false)
             .await
             .connect_stream(sess, resolver)
             .await?;
@@ -165,7 +172,7 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<AnyStream> {
-        self.fastest(true)
+        self.select(true)
             .await
             .proxy_stream(s, sess, resolver)
             .await
@@ -178,7 +185,7 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
         let d = self
-            .fastest(false)
+            .select(false)
             .await
             .connect_datagram(sess, resolver)
             .await?;
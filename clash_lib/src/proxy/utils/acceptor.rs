@@ -0,0 +1,134 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures::FutureExt;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tracing::{debug, warn};
+
+use crate::common::auth::ThreadSafeAllowList;
+
+use super::apply_tcp_options;
+
+/// limits the number of concurrent inbound connections
+pub type ConnectionLimiter = Arc<Semaphore>;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Accepts inbound TCP connections without giving up on transient errors.
+///
+/// When the process runs out of file descriptors, a reserved fd is released
+/// to accept and close the pending connection, so the client gets a reset
+/// instead of waiting in the backlog while the accept loop spins.
+/// Connections from sources the allowlist refuses are closed right away.
+pub struct Acceptor {
+    listener: TcpListener,
+    limiter: Option<ConnectionLimiter>,
+    allowlist: Option<ThreadSafeAllowList>,
+    #[cfg(unix)]
+    reserved_fd: Option<std::fs::File>,
+}
+
+impl Acceptor {
+    pub fn new(
+        listener: TcpListener,
+        limiter: Option<ConnectionLimiter>,
+        allowlist: Option<ThreadSafeAllowList>,
+    ) -> Self {
+        Self {
+            listener,
+            limiter,
+            allowlist,
+            #[cfg(unix)]
+            reserved_fd: std::fs::File::open("/dev/null").ok(),
+        }
+    }
+
+    /// waits for the next connection. the permit, if any, must be held
+    /// for as long as the connection is alive.
+    pub async fn accept(&mut self) -> (TcpStream, SocketAddr, Option<OwnedSemaphorePermit>) {
+        let permit = match self.limiter.as_ref() {
+            Some(limiter) => Some(
+                limiter
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("connection limiter closed"),
+            ),
+            None => None,
+        };
+
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match self.listener.accept().await {
+                Ok((_, addr))
+                    if self
+                        .allowlist
+                        .as_ref()
+                        .is_some_and(|x| !x.allows(addr.ip())) =>
+                {
+                    debug!("refused connection from {}", addr);
+                    continue;
+                }
+                Ok((s, addr)) => match apply_tcp_options(s) {
+                    Ok(s) => return (s, addr, permit),
+                    Err(e) => warn!("failed to set options on connection from {}: {}", addr, e),
+                },
+                Err(e) if is_connection_error(&e) => continue,
+                Err(e) => {
+                    if is_fd_exhausted(&e) {
+                        warn!(
+                            "too many open files, dropping pending connection on {:?}",
+                            self.listener.local_addr()
+                        );
+                        self.shed_pending_connection();
+                    } else {
+                        warn!("failed to accept connection: {}", e);
+                    }
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn shed_pending_connection(&mut self) {
+        drop(self.reserved_fd.take());
+        if let Some(Ok((s, _))) = self.listener.accept().now_or_never() {
+            drop(s);
+        }
+        self.reserved_fd = std::fs::File::open("/dev/null").ok();
+    }
+
+    #[cfg(not(unix))]
+    fn shed_pending_connection(&mut self) {
+        if let Some(Ok((s, _))) = self.listener.accept().now_or_never() {
+            drop(s);
+        }
+    }
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+    )
+}
+
+fn is_fd_exhausted(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = e;
+        false
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    fmt::Debug,
+    io,
+    sync::{Arc, Weak},
+};
+
+use once_cell::sync::OnceCell;
+
+use crate::{
+    app::{dispatcher::BoxedChainedDatagram, dns::ThreadSafeDNSResolver},
+    common::errors::new_io_error,
+    proxy::{AnyOutboundHandler, AnyStream, OutboundHandler},
+    session::{Network, Session, SocksAddr},
+};
+
+/// `dialer-proxy`: another outbound the connections to the proxy server
+/// are made through. It's configured by name and bound once all the
+/// outbounds are loaded, as it may refer to one defined later. Held
+/// weakly, the outbound manager keeps the outbounds alive.
+#[derive(Clone)]
+pub struct DialerProxy {
+    name: String,
+    handler: Arc<OnceCell<Weak<dyn OutboundHandler>>>,
+}
+
+impl Debug for DialerProxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DialerProxy")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl DialerProxy {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            handler: Arc::new(OnceCell::new()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// binds the outbound named by `name`, later binds are ignored
+    pub fn bind(&self, handler: &AnyOutboundHandler) {
+        let _ = self.handler.set(Arc::downgrade(handler));
+    }
+
+    fn handler(&self) -> io::Result<AnyOutboundHandler> {
+        self.handler.get().and_then(Weak::upgrade).ok_or_else(|| {
+            new_io_error(format!("dialer-proxy {} is not available", self.name).as_str())
+        })
+    }
+
+    pub async fn connect_stream(
+        &self,
+        resolver: ThreadSafeDNSResolver,
+        address: &str,
+        port: u16,
+    ) -> io::Result<AnyStream> {
+        let sess = Session {
+            destination: (address.to_owned(), port).try_into()?,
+            ..Default::default()
+        };
+        let s = self.handler()?.connect_stream(&sess, resolver).await?;
+        Ok(Box::new(s))
+    }
+
+    pub async fn connect_datagram(
+        &self,
+        resolver: ThreadSafeDNSResolver,
+        destination: SocksAddr,
+    ) -> io::Result<BoxedChainedDatagram> {
+        let sess = Session {
+            network: Network::Udp,
+            destination,
+            ..Default::default()
+        };
+        self.handler()?.connect_datagram(&sess, resolver).await
+    }
+}
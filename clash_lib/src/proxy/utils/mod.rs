@@ -1,12 +1,16 @@
 use std::net::{IpAddr, SocketAddr};
 
+mod acceptor;
+mod dialer;
 pub mod provider_helper;
 mod socket_helpers;
 
+pub use acceptor::{Acceptor, ConnectionLimiter};
+pub use dialer::DialerProxy;
 use serde::{Deserialize, Serialize};
 pub use socket_helpers::*;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
 pub enum Interface {
     IpAddr(IpAddr),
     Name(String),
@@ -25,3 +25,28 @@ pub async fn get_proxies_from_providers(
     }
     proxies
 }
+
+/// `preferred`, or while it's at its `max-connections`, the first member
+/// after it that isn't
+pub fn spill_over(
+    preferred: AnyOutboundHandler,
+    proxies: &[AnyOutboundHandler],
+) -> AnyOutboundHandler {
+    if !preferred.saturated() {
+        return preferred;
+    }
+
+    let start = proxies
+        .iter()
+        .position(|x| x.name() == preferred.name())
+        .map(|x| x + 1)
+        .unwrap_or_default();
+    proxies
+        .iter()
+        .cycle()
+        .skip(start)
+        .take(proxies.len())
+        .find(|x| !x.saturated())
+        .cloned()
+        .unwrap_or(preferred)
+}
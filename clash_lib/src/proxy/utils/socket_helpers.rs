@@ -1,20 +1,25 @@
 use std::{
     io,
     net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicBool, Ordering},
     time::Duration,
 };
 
+use futures::FutureExt;
 use socket2::TcpKeepalive;
 use tokio::{
     net::{TcpSocket, TcpStream, UdpSocket},
+    sync::Notify,
     time::timeout,
 };
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use tracing::debug;
 #[cfg(target_os = "windows")]
 use tracing::warn;
 
 use super::Interface;
-use crate::{app::dns::ThreadSafeDNSResolver, proxy::AnyStream};
+use crate::{app::dns::ThreadSafeDNSResolver, common::nat64, proxy::AnyStream};
 
 pub fn apply_tcp_options(s: TcpStream) -> std::io::Result<TcpStream> {
     #[cfg(not(target_os = "windows"))]
@@ -67,25 +72,124 @@ fn must_bind_socket_on_interface(socket: &socket2::Socket, iface: &Interface) ->
     }
 }
 
+/// the global `mptcp`, which the proxies without their own setting follow
+static MPTCP: AtomicBool = AtomicBool::new(false);
+
+/// not in libc for every target
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IPPROTO_MPTCP: i32 = 262;
+
+pub fn set_mptcp(enable: bool) {
+    MPTCP.store(enable, Ordering::Relaxed);
+}
+
+/// whether to dial with MPTCP, a proxy's own setting over the global one
+pub fn use_mptcp(own: Option<bool>) -> bool {
+    own.unwrap_or_else(|| MPTCP.load(Ordering::Relaxed))
+}
+
+/// an MPTCP socket if asked for and the kernel has it, TCP otherwise. The
+/// kernel also falls back to TCP when the server doesn't speak MPTCP
+#[cfg_attr(
+    not(any(target_os = "linux", target_os = "android")),
+    allow(unused_variables)
+)]
+fn new_stream_socket(domain: socket2::Domain, mptcp: bool) -> io::Result<socket2::Socket> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if mptcp {
+        match socket2::Socket::new(domain, socket2::Type::STREAM, Some(IPPROTO_MPTCP.into())) {
+            Ok(socket) => return Ok(socket),
+            Err(e) => debug!("MPTCP is not available, dialing with TCP: {}", e),
+        }
+    }
+    socket2::Socket::new(domain, socket2::Type::STREAM, None)
+}
+
+/// RFC 8305 "Connection Attempt Delay", the head start IPv6 gets before
+/// IPv4 is dialed alongside
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// dials `address` the happy eyeballs way (RFC 8305) when IPv6 is enabled:
+/// A and AAAA are resolved at once, IPv6 is dialed as soon as it's in and
+/// IPv4 joins in once IPv6 fails or has had its head start. The first
+/// connection up is used.
 pub async fn new_tcp_stream<'a>(
     resolver: ThreadSafeDNSResolver,
     address: &'a str,
     port: u16,
     iface: Option<&'a Interface>,
+    mptcp: bool,
     #[cfg(any(target_os = "linux", target_os = "android"))] packet_mark: Option<u32>,
 ) -> io::Result<AnyStream> {
-    let dial_addr = resolver
-        .resolve(address, false)
-        .await
-        .map_err(|v| io::Error::new(io::ErrorKind::Other, format!("dns failure: {}", v)))?
-        .ok_or(io::Error::new(
+    let dns_error =
+        |v: anyhow::Error| io::Error::new(io::ErrorKind::Other, format!("dns failure: {}", v));
+    let no_address = || {
+        io::Error::new(
             io::ErrorKind::Other,
             format!("can't resolve dns: {}", address),
-        ))?;
+        )
+    };
+    let connect = |ip: IpAddr| {
+        connect_tcp(
+            nat64::translate_ip(ip),
+            port,
+            iface,
+            mptcp,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            packet_mark,
+        )
+    };
+
+    if address.parse::<IpAddr>().is_ok() || !resolver.ipv6() {
+        let dial_addr = resolver
+            .resolve(address, false)
+            .await
+            .map_err(dns_error)?
+            .ok_or_else(no_address)?;
+        let stream = timeout(Duration::from_secs(10), connect(dial_addr)).await??;
+        return Ok(Box::new(stream));
+    }
+
+    let v6_failed = Notify::new();
+    let v6 = async {
+        let rv = match resolver.resolve_v6(address, false).await {
+            Ok(Some(ip)) => connect(ip.into()).await,
+            Ok(None) => Err(no_address()),
+            Err(e) => Err(dns_error(e)),
+        };
+        if rv.is_err() {
+            v6_failed.notify_one();
+        }
+        rv
+    };
+    let v4 = async {
+        let ip = resolver
+            .resolve_v4(address, false)
+            .await
+            .map_err(dns_error)?
+            .ok_or_else(no_address)?;
+        let _ = timeout(CONNECTION_ATTEMPT_DELAY, v6_failed.notified()).await;
+        connect(ip.into()).await
+    };
 
+    let (stream, _) = timeout(
+        Duration::from_secs(10),
+        futures::future::select_ok([v6.boxed(), v4.boxed()]),
+    )
+    .await??;
+    Ok(Box::new(stream))
+}
+
+async fn connect_tcp(
+    dial_addr: IpAddr,
+    port: u16,
+    iface: Option<&Interface>,
+    mptcp: bool,
+    #[cfg(any(target_os = "linux", target_os = "android"))] packet_mark: Option<u32>,
+) -> io::Result<TcpStream> {
     let socket = match dial_addr {
-        IpAddr::V4(_) => socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)?,
-        IpAddr::V6(_) => socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::STREAM, None)?,
+        IpAddr::V4(_) => new_stream_socket(socket2::Domain::IPV4, mptcp)?,
+        IpAddr::V6(_) => new_stream_socket(socket2::Domain::IPV6, mptcp)?,
     };
 
     if let Some(iface) = iface {
@@ -101,13 +205,9 @@ pub async fn new_tcp_stream<'a>(
     socket.set_nodelay(true)?;
     socket.set_nonblocking(true)?;
 
-    let stream = timeout(
-        Duration::from_secs(10),
-        TcpSocket::from_std_stream(socket.into()).connect((dial_addr, port).into()),
-    )
-    .await??;
-
-    Ok(Box::new(stream))
+    TcpSocket::from_std_stream(socket.into())
+        .connect((dial_addr, port).into())
+        .await
 }
 
 pub async fn new_udp_socket(
@@ -123,6 +223,12 @@ pub async fn new_udp_socket(
                 socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, None)?
             }
         }
+        // IPv4 destinations are sent to through NAT64
+        None if nat64::prefix().is_some()
+            || matches!(iface, Some(Interface::IpAddr(IpAddr::V6(_)))) =>
+        {
+            socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, None)?
+        }
         None => socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None)?,
     };
 
@@ -147,9 +253,49 @@ pub async fn new_udp_socket(
 
 #[cfg(test)]
 mod tests {
-    use std::{net::IpAddr, time::Duration};
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use tokio::{
+        net::{TcpListener, TcpSocket},
+        time::timeout,
+    };
+
+    use crate::app::dns::MockClashResolver;
+
+    use super::new_tcp_stream;
 
-    use tokio::{net::TcpSocket, time::timeout};
+    #[tokio::test]
+    async fn test_happy_eyeballs_fallback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut resolver = MockClashResolver::new();
+        resolver.expect_ipv6().return_const(true);
+        // nothing listens there, IPv4 is dialed right as IPv6 fails
+        resolver
+            .expect_resolve_v6()
+            .returning(|_, _| Ok(Some(Ipv6Addr::LOCALHOST)));
+        resolver
+            .expect_resolve_v4()
+            .returning(|_, _| Ok(Some(Ipv4Addr::LOCALHOST)));
+
+        new_tcp_stream(
+            Arc::new(resolver),
+            "example.com",
+            port,
+            None,
+            false,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await
+        .unwrap();
+        listener.accept().await.unwrap();
+    }
 
     #[tokio::test]
     #[ignore = "not a real test"]
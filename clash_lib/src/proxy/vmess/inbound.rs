@@ -0,0 +1,243 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    common::{auth::ThreadSafeAllowList, redact},
+    config::internal::listener::InboundVmess,
+    proxy::{
+        datagram::UdpPacket,
+        transport::ServerTransport,
+        tun::datagram::TunDatagram,
+        utils::{Acceptor, ConnectionLimiter},
+        AnyInboundListener, AnyStream, InboundListener,
+    },
+    session::{Inbound, Network, Session, Type},
+    Dispatcher,
+};
+
+use super::vmess_impl::{self, Users, VmessStream, COMMAND_UDP};
+
+/// Serves the vmess protocol, the server side of a `vmess` outbound.
+/// UDP is relayed in the streams, there's no UDP listener.
+pub struct Listener {
+    addr: SocketAddr,
+    opts: InboundVmess,
+    dispatcher: Arc<Dispatcher>,
+    limiter: Option<ConnectionLimiter>,
+    allowlist: Option<ThreadSafeAllowList>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        warn!(
+            "VMess inbound listener {} on {} stopped",
+            self.opts.name, self.addr
+        );
+    }
+}
+
+impl Listener {
+    pub fn new(
+        addr: SocketAddr,
+        opts: &InboundVmess,
+        dispatcher: Arc<Dispatcher>,
+        limiter: Option<ConnectionLimiter>,
+        allowlist: Option<ThreadSafeAllowList>,
+    ) -> AnyInboundListener {
+        Arc::new(Self {
+            addr,
+            opts: opts.clone(),
+            dispatcher,
+            limiter,
+            allowlist,
+        }) as _
+    }
+
+    fn users(&self) -> io::Result<Users> {
+        let users = self
+            .opts
+            .users
+            .iter()
+            .map(|user| {
+                let uuid = uuid::Uuid::parse_str(&user.uuid).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "vmess listener {}: invalid uuid {:?}",
+                            self.opts.name, user.uuid
+                        ),
+                    )
+                })?;
+                Ok((
+                    user.username
+                        .clone()
+                        .unwrap_or_else(|| redact::secret(&user.uuid)),
+                    uuid,
+                ))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Users::new(users))
+    }
+}
+
+async fn handle(
+    mut stream: AnyStream,
+    src_addr: SocketAddr,
+    users: Arc<Users>,
+    udp: bool,
+    dispatcher: Arc<Dispatcher>,
+    inbound: Inbound,
+) {
+    let (user, id, header) = match vmess_impl::accept(&mut stream, &users).await {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            debug!("vmess handshake with {} failed: {}", src_addr, e);
+            return;
+        }
+    };
+
+    let network = if header.command == COMMAND_UDP {
+        Network::Udp
+    } else {
+        Network::Tcp
+    };
+    if network == Network::Udp && !udp {
+        debug!("vmess user {} asked for UDP which is disabled", user);
+        return;
+    }
+    let sess = Session {
+        network,
+        typ: Type::Vmess,
+        source: src_addr,
+        destination: header.dst.clone(),
+        inbound,
+        user: Some(user.clone()),
+
+        ..Default::default()
+    };
+    debug!(
+        "vmess user {} from {} connects to {}",
+        user, src_addr, sess.destination
+    );
+
+    let stream = match VmessStream::accepted(stream, &id, header) {
+        Ok(stream) => stream,
+        Err(e) => {
+            debug!("vmess request from {} rejected: {}", src_addr, e);
+            return;
+        }
+    };
+    match network {
+        Network::Tcp => {
+            dispatcher.dispatch_stream(sess, stream).await;
+        }
+        Network::Udp => relay_udp(stream, sess, dispatcher).await,
+    }
+}
+
+/// each chunk of the stream is a packet to the session's destination
+async fn relay_udp<S>(stream: S, sess: Session, dispatcher: Arc<Dispatcher>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut r, mut w) = tokio::io::split(stream);
+    let (l_tx, mut l_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
+    let (d_tx, d_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
+    let src_addr = sess.source;
+    let dst_addr = sess.destination.clone();
+    let closer =
+        dispatcher.dispatch_datagram(sess, Box::new(TunDatagram::new(l_tx, d_rx, src_addr)));
+
+    let replies = async move {
+        while let Some(pkt) = l_rx.recv().await {
+            if w.write_all(&pkt.data).await.is_err() {
+                break;
+            }
+        }
+    };
+    let requests = async move {
+        // a read returns at most one chunk
+        let mut buf = vec![0u8; 65535];
+        loop {
+            let n = match r.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            let pkt = UdpPacket {
+                data: buf[..n].to_vec(),
+                src_addr: src_addr.into(),
+                dst_addr: dst_addr.clone(),
+            };
+            if d_tx.send(pkt).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = replies => {}
+        _ = requests => {}
+    }
+    closer.send(0).ok();
+}
+
+#[async_trait]
+impl InboundListener for Listener {
+    fn handle_tcp(&self) -> bool {
+        true
+    }
+
+    fn handle_udp(&self) -> bool {
+        false
+    }
+
+    async fn listen_tcp(&self) -> io::Result<()> {
+        let users = Arc::new(self.users()?);
+        let transport = Arc::new(ServerTransport::new(&self.opts.transport)?);
+        let listener = TcpListener::bind(self.addr).await?;
+        let mut acceptor = Acceptor::new(listener, self.limiter.clone(), self.allowlist.clone());
+        let inbound = Inbound {
+            name: self.opts.name.clone(),
+            port: Some(self.addr.port()),
+            mode: self.opts.mode,
+        };
+
+        loop {
+            let (socket, src_addr, permit) = acceptor.accept().await;
+
+            let users = users.clone();
+            let transport = transport.clone();
+            let dispatcher = self.dispatcher.clone();
+            let udp = self.opts.udp;
+            let inbound = inbound.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                let served = transport
+                    .serve(socket, |stream| {
+                        handle(
+                            stream,
+                            src_addr,
+                            users.clone(),
+                            udp,
+                            dispatcher.clone(),
+                            inbound.clone(),
+                        )
+                    })
+                    .await;
+                if let Err(e) = served {
+                    debug!("vmess connection from {} failed: {}", src_addr, e);
+                }
+            });
+        }
+    }
+
+    async fn listen_udp(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "unsupported"))
+    }
+}
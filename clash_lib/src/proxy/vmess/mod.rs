@@ -1,10 +1,14 @@
-use std::{collections::HashMap, io, net::IpAddr, sync::Arc};
+use std::{collections::HashMap, io, sync::Arc};
 
 use async_trait::async_trait;
+use erased_serde::Serialize as ESerialize;
 use futures::TryFutureExt;
 
+mod inbound;
 mod vmess_impl;
 
+pub use inbound::Listener;
+
 use crate::{
     app::{
         dispatcher::{
@@ -13,16 +17,18 @@ use crate::{
         },
         dns::ThreadSafeDNSResolver,
     },
-    common::errors::{map_io_error, new_io_error},
+    common::errors::new_io_error,
     session::{Session, SocksAddr},
 };
 
-use self::vmess_impl::OutboundDatagramVmess;
+use self::vmess_impl::{
+    global_id, OutboundDatagramVmess, OutboundDatagramXudp, COMMAND_MUX, COMMAND_TCP, COMMAND_UDP,
+};
 
 use super::{
-    options::{GrpcOption, Http2Option, HttpOption, WsOption},
-    transport::{self, Http2Config},
-    utils::new_tcp_stream,
+    options::{GrpcOption, Http2Option, HttpOption, QuicOption, WsOption},
+    transport::{self, Http2Config, Http2ConnPool, QuicTransport},
+    utils::DialerProxy,
     AnyOutboundHandler, AnyStream, CommonOption, OutboundHandler, OutboundType,
 };
 
@@ -40,6 +46,7 @@ pub enum VmessTransport {
     Grpc(GrpcOption),
     #[allow(dead_code)]
     Http(HttpOption),
+    Quic(QuicOption),
 }
 
 pub struct HandlerOptions {
@@ -51,25 +58,130 @@ pub struct HandlerOptions {
     pub alter_id: u16,
     pub security: String,
     pub udp: bool,
+    /// carry UDP as XUDP, with a global ID per session
+    pub xudp: bool,
     pub transport: Option<VmessTransport>,
     pub tls: Option<transport::TLSOptions>,
 }
 
 pub struct Handler {
     opts: HandlerOptions,
+    h2_pool: Http2ConnPool,
+    quic: Option<QuicTransport>,
 }
 
 impl Handler {
     pub fn new(opts: HandlerOptions) -> AnyOutboundHandler {
-        Arc::new(Self { opts })
+        let quic = match opts.transport {
+            Some(VmessTransport::Quic(ref opt)) => Some(QuicTransport::new(
+                opts.server.clone(),
+                opts.port,
+                opts.tls.clone().unwrap_or(transport::TLSOptions {
+                    skip_cert_verify: false,
+                    sni: opts.server.clone(),
+                    alpn: Some(vec!["h3".to_owned()]),
+                    fragment: None,
+                }),
+                opt.congestion_controller,
+                opt.zero_rtt,
+            )),
+            _ => None,
+        };
+
+        Arc::new(Self {
+            opts,
+            h2_pool: Http2ConnPool::default(),
+            quic,
+        })
+    }
+
+    async fn dial(&self, resolver: ThreadSafeDNSResolver) -> io::Result<AnyStream> {
+        self.opts
+            .common_opts
+            .connect_stream(resolver, self.opts.server.as_str(), self.opts.port)
+            .map_err(|x| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "dial outbound {}:{}: {}",
+                        self.opts.server, self.opts.port, x
+                    ),
+                )
+            })
+            .await
+    }
+
+    fn h2_config(&self, opt: &Http2Option) -> io::Result<Http2Config> {
+        let hosts = if opt.host.is_empty() {
+            vec![self.opts.server.clone()]
+        } else {
+            opt.host.clone()
+        };
+        Ok(Http2Config {
+            hosts,
+            method: http::Method::PUT,
+            headers: HashMap::new(),
+            path: opt
+                .path
+                .to_owned()
+                .try_into()
+                .map_err(|_| new_io_error(format!("invalid H2 path: {}", opt.path).as_str()))?,
+        })
+    }
+
+    async fn h2_tls_stream(&self, stream: AnyStream) -> io::Result<AnyStream> {
+        let mut tls_opt = self
+            .opts
+            .tls
+            .as_ref()
+            .ok_or(new_io_error("H2 conn must have tls opt"))?
+            .clone();
+        tls_opt.alpn = Some(vec!["h2".to_string()]);
+        transport::tls::wrap_stream(stream, tls_opt).await
+    }
+
+    /// dials the server and applies the transport layer.
+    /// h2 streams are multiplexed over a pooled connection of this outbound.
+    async fn connect_transport(&self, resolver: ThreadSafeDNSResolver) -> io::Result<AnyStream> {
+        match self.opts.transport {
+            Some(VmessTransport::H2(ref opt)) => {
+                let h2_builder = self.h2_config(opt)?;
+                self.h2_pool
+                    .open_stream(&h2_builder, async {
+                        let stream = self.dial(resolver).await?;
+                        self.h2_tls_stream(stream).await
+                    })
+                    .await
+            }
+            Some(VmessTransport::Quic(_)) => {
+                self.quic
+                    .as_ref()
+                    .ok_or(new_io_error("quic transport not initialized"))?
+                    .open_stream(
+                        resolver,
+                        self.opts.common_opts.iface.as_ref(),
+                        self.opts.common_opts.so_mark,
+                    )
+                    .await
+            }
+            _ => {
+                let stream = self.dial(resolver).await?;
+                self.transport_stream(stream).await
+            }
+        }
     }
 
     async fn inner_proxy_stream<'a>(
         &'a self,
         s: AnyStream,
         sess: &'a Session,
-        udp: bool,
+        command: u8,
     ) -> io::Result<AnyStream> {
+        let underlying = self.transport_stream(s).await?;
+        self.vmess_stream(underlying, sess, command).await
+    }
+
+    async fn transport_stream(&self, s: AnyStream) -> io::Result<AnyStream> {
         let mut stream = s;
 
         let underlying = match self.opts.transport {
@@ -91,23 +203,8 @@ impl Handler {
                 ws_builder.proxy_stream(stream).await?
             }
             Some(VmessTransport::H2(ref opt)) => {
-                let mut tls_opt = self
-                    .opts
-                    .tls
-                    .as_ref()
-                    .expect("H2 conn must have tls opt")
-                    .clone();
-                tls_opt.alpn = Some(vec!["h2".to_string()]);
-                stream = transport::tls::wrap_stream(stream, tls_opt.to_owned()).await?;
-
-                let h2_builder = Http2Config {
-                    hosts: vec![self.opts.server.clone()],
-                    method: http::Method::GET,
-                    headers: HashMap::new(),
-                    path: opt.path.to_owned().try_into().expect("invalid H2 path"),
-                };
-
-                h2_builder.proxy_stream(stream).await?
+                let stream = self.h2_tls_stream(stream).await?;
+                self.h2_config(opt)?.proxy_stream(stream).await?
             }
             Some(VmessTransport::Grpc(ref opt)) => {
                 let tls_opt = self.opts.tls.as_ref().expect("gRPC conn must have tls opt");
@@ -124,6 +221,11 @@ impl Handler {
             Some(VmessTransport::Http(_)) => {
                 unimplemented!("HTTP transport is not implemented yet")
             }
+            Some(VmessTransport::Quic(_)) => {
+                return Err(new_io_error(
+                    "QUIC transport can't be used over another stream",
+                ));
+            }
             None => {
                 if let Some(tls_opt) = self.opts.tls.as_ref() {
                     stream = transport::tls::wrap_stream(stream, tls_opt.to_owned()).await?;
@@ -132,11 +234,20 @@ impl Handler {
             }
         };
 
+        Ok(underlying)
+    }
+
+    async fn vmess_stream(
+        &self,
+        underlying: AnyStream,
+        sess: &Session,
+        command: u8,
+    ) -> io::Result<AnyStream> {
         let vmess_builder = vmess_impl::Builder::new(&vmess_impl::VmessOption {
             uuid: self.opts.uuid.to_owned(),
             alter_id: self.opts.alter_id,
             security: self.opts.security.to_owned(),
-            udp,
+            command,
             dst: sess.destination.clone(),
         })?;
 
@@ -155,6 +266,10 @@ impl OutboundHandler for Handler {
         OutboundType::Vmess
     }
 
+    fn dialer_proxy(&self) -> Option<&DialerProxy> {
+        self.opts.common_opts.dialer_proxy()
+    }
+
     /// The proxy remote address
     async fn remote_addr(&self) -> Option<SocksAddr> {
         Some(SocksAddr::Domain(self.opts.server.clone(), self.opts.port))
@@ -170,26 +285,8 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        let stream = new_tcp_stream(
-            resolver,
-            self.opts.server.as_str(),
-            self.opts.port,
-            self.opts.common_opts.iface.as_ref(),
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            None,
-        )
-        .map_err(|x| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "dial outbound {}:{}: {}",
-                    self.opts.server, self.opts.port, x
-                ),
-            )
-        })
-        .await?;
-
-        let s = self.inner_proxy_stream(stream, sess, false).await?;
+        let stream = self.connect_transport(resolver).await?;
+        let s = self.vmess_stream(stream, sess, COMMAND_TCP).await?;
         let chained = ChainedStreamWrapper::new(s);
         chained.append_to_chain(self.name()).await;
         Ok(Box::new(chained))
@@ -202,7 +299,7 @@ impl OutboundHandler for Handler {
         sess: &Session,
         _: ThreadSafeDNSResolver,
     ) -> io::Result<AnyStream> {
-        self.inner_proxy_stream(s, sess, false).await
+        self.inner_proxy_stream(s, sess, COMMAND_TCP).await
     }
 
     async fn connect_datagram(
@@ -210,45 +307,39 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
-        let stream = new_tcp_stream(
-            resolver.clone(),
-            self.opts.server.as_str(),
-            self.opts.port,
-            self.opts.common_opts.iface.as_ref(),
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            None,
-        )
-        .map_err(|x| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "dial outbound {}:{}: {}",
-                    self.opts.server, self.opts.port, x
-                ),
-            )
-        })
-        .await?;
-
-        let remote_addr = resolver
-            .resolve_v4(sess.destination.host().as_str(), false)
-            .map_err(map_io_error)
-            .await?
-            .ok_or(new_io_error(
-                format!("failed to resolve {}", sess.destination.host()).as_str(),
-            ))?;
-
-        let stream = self.inner_proxy_stream(stream, sess, true).await?;
-
-        let d = OutboundDatagramVmess::new(
-            stream,
-            SocksAddr::Ip(std::net::SocketAddr::new(
-                IpAddr::V4(remote_addr),
-                sess.destination.port(),
-            )),
-        );
+        if self.opts.xudp {
+            // the server resolves the destinations, and keeps the same
+            // socket for sessions with the same global ID
+            let stream = self.connect_transport(resolver).await?;
+            let stream = self.vmess_stream(stream, sess, COMMAND_MUX).await?;
+
+            let d = OutboundDatagramXudp::new(stream, global_id(&sess.source));
+
+            let chained = ChainedDatagramWrapper::new(d);
+            chained.append_to_chain(self.name()).await;
+            return Ok(Box::new(chained));
+        }
+
+        // the destination is sent as is, a domain is resolved by the server
+        let stream = self.connect_transport(resolver).await?;
+        let stream = self.vmess_stream(stream, sess, COMMAND_UDP).await?;
+
+        let d = OutboundDatagramVmess::new(stream, sess.destination.clone());
 
         let chained = ChainedDatagramWrapper::new(d);
         chained.append_to_chain(self.name()).await;
         Ok(Box::new(chained))
     }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        let mut m = HashMap::new();
+        m.insert("type".to_string(), Box::new(self.proto()) as _);
+        if let Some(stats) = match &self.quic {
+            Some(quic) => quic.stats().await,
+            None => None,
+        } {
+            m.insert("quic".to_string(), Box::new(stats) as _);
+        }
+        m
+    }
 }
@@ -13,7 +13,8 @@ pub struct VmessOption {
     pub uuid: String,
     pub alter_id: u16,
     pub security: String,
-    pub udp: bool,
+    /// one of the `COMMAND_*`s
+    pub command: u8,
     pub dst: SocksAddr,
 }
 
@@ -22,7 +23,7 @@ pub struct Builder {
     pub uuid: uuid::Uuid,
     pub security: Security,
     pub is_aead: bool,
-    pub is_udp: bool,
+    pub command: u8,
     pub dst: SocksAddr,
 }
 
@@ -56,7 +57,7 @@ impl Builder {
             uuid,
             security,
             is_aead: opt.alter_id == 0,
-            is_udp: opt.udp,
+            command: opt.command,
             dst: opt.dst.clone(),
         })
     }
@@ -69,7 +70,7 @@ impl Builder {
             &self.dst,
             &self.security,
             self.is_aead,
-            self.is_udp,
+            self.command,
         )
         .await?;
 
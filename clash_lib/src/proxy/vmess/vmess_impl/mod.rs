@@ -4,8 +4,10 @@ mod header;
 //pub mod http;
 mod datagram;
 mod kdf;
+mod server;
 mod stream;
 mod user;
+mod xudp;
 
 pub(crate) const VERSION: u8 = 1;
 
@@ -21,6 +23,7 @@ pub(crate) const SECURITY_NONE: Security = 5;
 
 pub(crate) const COMMAND_TCP: u8 = 1;
 pub(crate) const COMMAND_UDP: u8 = 2;
+pub(crate) const COMMAND_MUX: u8 = 3;
 
 const CHUNK_SIZE: usize = 1 << 14;
 const MAX_CHUNK_SIZE: usize = 17 * 1024;
@@ -28,6 +31,8 @@ const MAX_CHUNK_SIZE: usize = 17 * 1024;
 pub use client::Builder;
 pub use client::VmessOption;
 pub use datagram::OutboundDatagramVmess;
+pub use server::{accept, Users};
 pub use stream::VmessStream;
 pub use user::new_alter_id_list;
 pub use user::new_id;
+pub use xudp::{global_id, OutboundDatagramXudp};
@@ -0,0 +1,373 @@
+//! The server side of the VMess AEAD handshake. Legacy (alter id) headers
+//! and the chunk masking and global padding options are not supported.
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use bytes::Buf;
+use lru_time_cache::LruCache;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    common::{crypto, errors::map_io_error, utils},
+    session::SocksAddr,
+};
+
+use super::{
+    kdf::{
+        self, KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_IV, KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_KEY,
+        KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_IV, KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_KEY,
+        KDF_SALT_CONST_AUTH_ID_ENCRYPTION_KEY, KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV,
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY,
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
+    },
+    user::{new_id, ID},
+    Security, COMMAND_TCP, COMMAND_UDP, OPTION_CHUNK_STREAM, VERSION,
+};
+
+/// how far the clock of a client may be off
+const MAX_TIME_DIFF: u64 = 120;
+/// how long a client may take to send its request, an idle one would
+/// otherwise hold a connection of the listener forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const OPTION_CHUNK_MASKING: u8 = 4;
+const OPTION_GLOBAL_PADDING: u8 = 8;
+const OPTION_AUTHENTICATED_LENGTH: u8 = 16;
+
+/// version, body iv and key, response byte, option, padding and security,
+/// reserved and command
+const HEADER_FIXED_LEN: usize = 1 + 16 + 16 + 1 + 1 + 1 + 1 + 1;
+
+pub struct RequestHeader {
+    pub security: Security,
+    pub command: u8,
+    pub dst: SocksAddr,
+    pub req_body_key: Vec<u8>,
+    pub req_body_iv: Vec<u8>,
+    pub resp_v: u8,
+}
+
+impl RequestHeader {
+    pub fn resp_body_key(&self) -> Vec<u8> {
+        utils::sha256(&self.req_body_key)[..16].to_vec()
+    }
+
+    pub fn resp_body_iv(&self) -> Vec<u8> {
+        utils::sha256(&self.req_body_iv)[..16].to_vec()
+    }
+}
+
+pub struct Users {
+    ids: Vec<(String, ID)>,
+    /// the auth ids seen within the time window, a repeated one is a replay
+    seen: Mutex<LruCache<[u8; 16], ()>>,
+}
+
+impl Users {
+    pub fn new(users: Vec<(String, uuid::Uuid)>) -> Self {
+        Self {
+            ids: users
+                .into_iter()
+                .map(|(name, uuid)| (name, new_id(&uuid)))
+                .collect(),
+            seen: Mutex::new(LruCache::with_expiry_duration(Duration::from_secs(
+                MAX_TIME_DIFF * 2,
+            ))),
+        }
+    }
+
+    /// the user the auth id was sealed for, if it's fresh
+    fn authenticate(&self, auth_id: &[u8; 16]) -> Option<&(String, ID)> {
+        let now = now();
+        let user = self.ids.iter().find(|(_, id)| {
+            let key =
+                kdf::vmess_kdf_1_one_shot(&id.cmd_key[..], KDF_SALT_CONST_AUTH_ID_ENCRYPTION_KEY);
+            let mut block = *auth_id;
+            let mut aes_key = boring_sys::AES_KEY::default();
+            unsafe {
+                boring_sys::AES_set_decrypt_key(key.as_ptr() as _, 128, &mut aes_key);
+                boring_sys::AES_decrypt(block.as_ptr() as _, block.as_mut_ptr() as _, &aes_key);
+            }
+
+            let checksum = u32::from_be_bytes(block[12..16].try_into().unwrap());
+            let timestamp = u64::from_be_bytes(block[..8].try_into().unwrap());
+            crc32fast::hash(&block[..12]) == checksum && now.abs_diff(timestamp) <= MAX_TIME_DIFF
+        })?;
+
+        if self.seen.lock().unwrap().insert(*auth_id, ()).is_some() {
+            return None;
+        }
+        Some(user)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("check your system clock")
+        .as_secs()
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn parse_header(buf: &[u8]) -> io::Result<RequestHeader> {
+    if buf.len() < HEADER_FIXED_LEN + 4 {
+        return Err(invalid_data("vmess header too short"));
+    }
+    let (mut buf, checksum) = buf.split_at(buf.len() - 4);
+    let expected = unsafe { boring_sys::OPENSSL_hash32(buf.as_ptr() as _, buf.len()) };
+    if expected.to_be_bytes() != checksum {
+        return Err(invalid_data("vmess header checksum mismatch"));
+    }
+
+    if buf.get_u8() != VERSION {
+        return Err(invalid_data("unsupported vmess version"));
+    }
+    let mut req_body_iv = vec![0u8; 16];
+    buf.copy_to_slice(&mut req_body_iv);
+    let mut req_body_key = vec![0u8; 16];
+    buf.copy_to_slice(&mut req_body_key);
+    let resp_v = buf.get_u8();
+
+    let option = buf.get_u8();
+    if option & OPTION_CHUNK_STREAM == 0 {
+        return Err(invalid_data("vmess clients must use chunk stream"));
+    }
+    if option & (OPTION_CHUNK_MASKING | OPTION_GLOBAL_PADDING | OPTION_AUTHENTICATED_LENGTH) != 0 {
+        return Err(invalid_data(
+            "vmess chunk masking, global padding and authenticated length are not supported",
+        ));
+    }
+
+    let padding_and_security = buf.get_u8();
+    let padding = (padding_and_security >> 4) as usize;
+    let security = padding_and_security & 0x0f;
+    buf.advance(1);
+
+    let command = buf.get_u8();
+    if command != COMMAND_TCP && command != COMMAND_UDP {
+        return Err(invalid_data("unsupported vmess command"));
+    }
+
+    if buf.remaining() < 3 {
+        return Err(invalid_data("vmess header too short"));
+    }
+    let port = buf.get_u16();
+    let dst = match buf.get_u8() {
+        0x01 if buf.remaining() >= 4 => SocksAddr::Ip((Ipv4Addr::from(buf.get_u32()), port).into()),
+        0x02 if buf.remaining() >= 1 && buf.remaining() > buf[0] as usize => {
+            let len = buf.get_u8() as usize;
+            let domain = String::from_utf8(buf[..len].to_vec())
+                .map_err(|_| invalid_data("invalid vmess domain"))?;
+            buf.advance(len);
+            SocksAddr::Domain(domain, port)
+        }
+        0x03 if buf.remaining() >= 16 => {
+            SocksAddr::Ip((Ipv6Addr::from(buf.get_u128()), port).into())
+        }
+        _ => return Err(invalid_data("invalid vmess address")),
+    };
+
+    if buf.remaining() != padding {
+        return Err(invalid_data("invalid vmess header padding"));
+    }
+
+    Ok(RequestHeader {
+        security,
+        command,
+        dst,
+        req_body_key,
+        req_body_iv,
+        resp_v,
+    })
+}
+
+fn seal_response_header(header: &RequestHeader) -> io::Result<Vec<u8>> {
+    let resp_body_key = header.resp_body_key();
+    let resp_body_iv = header.resp_body_iv();
+    // no dynamic port or other command
+    let plain = [header.resp_v, 0, 0, 0];
+
+    let mut out = crypto::aes_gcm_seal(
+        &kdf::vmess_kdf_1_one_shot(&resp_body_key, KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_KEY)[..16],
+        &kdf::vmess_kdf_1_one_shot(&resp_body_iv, KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_IV)[..12],
+        &(plain.len() as u16).to_be_bytes(),
+        None,
+    )
+    .map_err(map_io_error)?;
+    out.extend(
+        crypto::aes_gcm_seal(
+            &kdf::vmess_kdf_1_one_shot(&resp_body_key, KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_KEY)
+                [..16],
+            &kdf::vmess_kdf_1_one_shot(&resp_body_iv, KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_IV)
+                [..12],
+            &plain,
+            None,
+        )
+        .map_err(map_io_error)?,
+    );
+    Ok(out)
+}
+
+/// reads and checks the request header, then answers it. returns the user
+/// the client authenticated as
+pub async fn accept<S>(stream: &mut S, users: &Users) -> io::Result<(String, ID, RequestHeader)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(stream, users))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))?
+}
+
+async fn handshake<S>(stream: &mut S, users: &Users) -> io::Result<(String, ID, RequestHeader)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // auth id, sealed header length and connection nonce
+    let mut buf = [0u8; 16 + 18 + 8];
+    stream.read_exact(&mut buf).await?;
+    let auth_id: [u8; 16] = buf[..16].try_into().unwrap();
+    let (name, id) = users
+        .authenticate(&auth_id)
+        .cloned()
+        .ok_or_else(|| invalid_data("unknown vmess user or replayed request"))?;
+    let nonce = &buf[34..];
+
+    let len = crypto::aes_gcm_open(
+        &kdf::vmess_kdf_3_one_shot(
+            &id.cmd_key[..],
+            KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
+            &auth_id,
+            nonce,
+        )[..16],
+        &kdf::vmess_kdf_3_one_shot(
+            &id.cmd_key[..],
+            KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
+            &auth_id,
+            nonce,
+        )[..12],
+        &buf[16..34],
+        Some(&auth_id),
+    )
+    .map_err(map_io_error)?;
+    if len.len() != 2 {
+        return Err(invalid_data("invalid vmess header length"));
+    }
+
+    let mut payload = vec![0u8; u16::from_be_bytes([len[0], len[1]]) as usize + 16];
+    stream.read_exact(&mut payload).await?;
+    let header = crypto::aes_gcm_open(
+        &kdf::vmess_kdf_3_one_shot(
+            &id.cmd_key[..],
+            KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY,
+            &auth_id,
+            nonce,
+        )[..16],
+        &kdf::vmess_kdf_3_one_shot(
+            &id.cmd_key[..],
+            KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV,
+            &auth_id,
+            nonce,
+        )[..12],
+        &payload,
+        Some(&auth_id),
+    )
+    .map_err(map_io_error)?;
+    let header = parse_header(&header)?;
+
+    stream.write_all(&seal_response_header(&header)?).await?;
+    Ok((name, id, header))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        proxy::vmess::vmess_impl::{
+            user::new_id, VmessStream, COMMAND_TCP, SECURITY_AES_128_GCM,
+            SECURITY_CHACHA20_POLY1305,
+        },
+        session::SocksAddr,
+    };
+
+    use super::{accept, Users};
+
+    #[tokio::test]
+    async fn test_accept_client() {
+        let uuid = uuid::Uuid::parse_str("b831381d-6324-4d53-ad4f-8cda48b30811").unwrap();
+        let users = Users::new(vec![("alice".to_owned(), uuid)]);
+        let dst = SocksAddr::Domain("example.com".to_owned(), 443);
+
+        for security in [SECURITY_AES_128_GCM, SECURITY_CHACHA20_POLY1305] {
+            let (client, mut server) = tokio::io::duplex(64 * 1024);
+            let mut client =
+                VmessStream::new(client, &new_id(&uuid), &dst, &security, true, COMMAND_TCP)
+                    .await
+                    .unwrap();
+
+            let (name, id, header) = accept(&mut server, &users).await.unwrap();
+            assert_eq!(name, "alice");
+            assert_eq!(header.dst, dst);
+            assert_eq!(header.command, COMMAND_TCP);
+            let mut server = VmessStream::accepted(server, &id, header).unwrap();
+
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            server.write_all(b"world").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reject_replay() {
+        let uuid = uuid::Uuid::new_v4();
+        let users = Users::new(vec![("alice".to_owned(), uuid)]);
+        let dst = SocksAddr::Domain("example.com".to_owned(), 443);
+
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let _client = VmessStream::new(
+            client,
+            &new_id(&uuid),
+            &dst,
+            &SECURITY_AES_128_GCM,
+            true,
+            COMMAND_TCP,
+        )
+        .await
+        .unwrap();
+        let mut request = [0u8; 256];
+        let n = server.read(&mut request).await.unwrap();
+
+        let (mut replay, mut server) = tokio::io::duplex(64 * 1024);
+        replay.write_all(&request[..n]).await.unwrap();
+        replay.write_all(&request[..n]).await.unwrap();
+        assert!(accept(&mut server, &users).await.is_ok());
+        assert!(accept(&mut server, &users).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let users = Users::new(vec![("alice".to_owned(), uuid::Uuid::new_v4())]);
+        let (_client, mut server) = tokio::io::duplex(1024);
+        assert_eq!(
+            accept(&mut server, &users).await.unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+}
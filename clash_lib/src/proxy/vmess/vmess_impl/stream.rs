@@ -11,7 +11,7 @@ use crate::{
     common::{
         crypto::{self, AeadCipherHelper},
         errors::map_io_error,
-        utils,
+        redact, utils,
     },
     proxy::vmess::vmess_impl::MAX_CHUNK_SIZE,
     session::SocksAddr,
@@ -25,8 +25,9 @@ use super::{
         self, KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_IV, KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_KEY,
         KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_IV, KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_KEY,
     },
+    server::RequestHeader,
     user::{ID, ID_BYTES_LEN},
-    Security, CHUNK_SIZE, COMMAND_TCP, COMMAND_UDP, OPTION_CHUNK_STREAM, SECURITY_AES_128_GCM,
+    Security, CHUNK_SIZE, COMMAND_MUX, OPTION_CHUNK_STREAM, SECURITY_AES_128_GCM,
     SECURITY_CHACHA20_POLY1305, SECURITY_NONE, VERSION,
 };
 
@@ -43,7 +44,7 @@ pub struct VmessStream<S> {
     resp_v: u8,
     security: u8,
     is_aead: bool,
-    is_udp: bool,
+    command: u8,
 
     read_state: ReadState,
     read_pos: usize,
@@ -58,7 +59,7 @@ impl<S> Debug for VmessStream<S> {
         f.debug_struct("VmessStream")
             .field("dst", &self.dst)
             .field("is_aead", &self.is_aead)
-            .field("is_udp", &self.is_udp)
+            .field("command", &self.command)
             .finish()
     }
 }
@@ -140,7 +141,7 @@ where
         dst: &SocksAddr,
         security: &Security,
         is_aead: bool,
-        is_udp: bool,
+        command: u8,
     ) -> std::io::Result<VmessStream<S>> {
         let mut rand_bytes = [0u8; 33];
         utils::rand_fill(&mut rand_bytes[..]);
@@ -160,44 +161,8 @@ where
             )
         };
 
-        let (aead_read_cipher, aead_write_cipher) = match security {
-            &SECURITY_NONE => (None, None),
-            &SECURITY_AES_128_GCM => {
-                let write_cipher =
-                    VmessSecurity::Aes128Gcm(Aes128Gcm::new_with_slice(&req_body_key));
-                let write_cipher = AeadCipher::new(&req_body_iv, write_cipher);
-                let reader_cipher =
-                    VmessSecurity::Aes128Gcm(Aes128Gcm::new_with_slice(&resp_body_key));
-                let read_cipher = AeadCipher::new(&resp_body_iv, reader_cipher);
-                (Some(read_cipher), Some(write_cipher))
-            }
-            &SECURITY_CHACHA20_POLY1305 => {
-                let mut key = [0u8; 32];
-                let tmp = utils::md5(&req_body_key);
-                key.copy_from_slice(&tmp);
-                let tmp = utils::md5(&key[..16]);
-                key[16..].copy_from_slice(&tmp);
-                let write_cipher =
-                    VmessSecurity::ChaCha20Poly1305(ChaCha20Poly1305::new_with_slice(&key));
-                let write_cipher = AeadCipher::new(&req_body_iv, write_cipher);
-
-                let tmp = utils::md5(&req_body_key);
-                key.copy_from_slice(&tmp);
-                let tmp = utils::md5(&key[..16]);
-                key[16..].copy_from_slice(&tmp);
-                let reader_cipher =
-                    VmessSecurity::ChaCha20Poly1305(ChaCha20Poly1305::new_with_slice(&key));
-                let read_cipher = AeadCipher::new(&resp_body_iv, reader_cipher);
-
-                (Some(read_cipher), Some(write_cipher))
-            }
-            _ => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "unsupported security",
-                ))
-            }
-        };
+        let aead_write_cipher = body_cipher(*security, &req_body_key, &req_body_iv)?;
+        let aead_read_cipher = body_cipher(*security, &resp_body_key, &resp_body_iv)?;
 
         let mut stream = Self {
             stream,
@@ -212,7 +177,7 @@ where
             resp_v,
             security: *security,
             is_aead,
-            is_udp,
+            command,
 
             read_state: ReadState::AeadWaitingHeaderSize,
             read_pos: 0,
@@ -228,6 +193,43 @@ where
     }
 }
 
+impl<S> VmessStream<S> {
+    /// the server side of a connection whose request header was read by
+    /// [`super::server::accept`]: the request body is read with the request
+    /// key and the response body written with the response key
+    pub(crate) fn accepted(stream: S, id: &ID, header: RequestHeader) -> std::io::Result<Self> {
+        let resp_body_key = header.resp_body_key();
+        let resp_body_iv = header.resp_body_iv();
+        let aead_read_cipher =
+            body_cipher(header.security, &header.req_body_key, &header.req_body_iv)?;
+        let aead_write_cipher = body_cipher(header.security, &resp_body_key, &resp_body_iv)?;
+
+        Ok(Self {
+            stream,
+            aead_read_cipher,
+            aead_write_cipher,
+            dst: header.dst,
+            id: id.to_owned(),
+            req_body_iv: header.req_body_iv,
+            req_body_key: header.req_body_key,
+            resp_body_iv,
+            resp_body_key,
+            resp_v: header.resp_v,
+            security: header.security,
+            is_aead: true,
+            command: header.command,
+
+            // the response header is sent by `server::accept`
+            read_state: ReadState::StreamWaitingLength,
+            read_pos: 0,
+            read_buf: BytesMut::new(),
+
+            write_state: WriteState::BuildingData,
+            write_buf: BytesMut::new(),
+        })
+    }
+}
+
 impl<S> VmessStream<S>
 where
     S: AsyncWrite + Unpin + Send + Sync,
@@ -241,7 +243,7 @@ where
             ref security,
             ref dst,
             ref is_aead,
-            ref is_udp,
+            ref command,
             ref id,
             ..
         } = self;
@@ -283,13 +285,12 @@ where
 
         buf.put_u8(0);
 
-        if *is_udp {
-            buf.put_u8(COMMAND_UDP);
-        } else {
-            buf.put_u8(COMMAND_TCP);
-        }
+        buf.put_u8(*command);
 
-        dst.write_to_buf_vmess(&mut buf);
+        // the mux command carries its destinations in the frames
+        if *command != COMMAND_MUX {
+            dst.write_to_buf_vmess(&mut buf);
+        }
 
         if p > 0 {
             let mut padding = vec![0u8; p as usize];
@@ -309,12 +310,18 @@ where
 
             mbuf.put_slice(data.as_slice());
             let out = mbuf.freeze();
-            vmess_debug!("send non aead handshake request for user {}", id.uuid);
+            vmess_debug!(
+                "send non aead handshake request for user {}",
+                redact::secret(&id.uuid.to_string())
+            );
             stream.write_all(&out).await?;
         } else {
             let out = header::seal_vmess_aead_header(id.cmd_key, buf.freeze().to_vec(), now)
                 .map_err(map_io_error)?;
-            vmess_debug!("send aead handshake request for user {}", id.uuid);
+            vmess_debug!(
+                "send aead handshake request for user {}",
+                redact::secret(&id.uuid.to_string())
+            );
 
             stream.write_all(&out).await?;
         }
@@ -595,6 +602,32 @@ where
     }
 }
 
+/// the cipher of one direction of the body, chacha20 stretches the 16 byte
+/// body key to 32 bytes
+fn body_cipher(security: Security, key: &[u8], iv: &[u8]) -> std::io::Result<Option<AeadCipher>> {
+    match security {
+        SECURITY_NONE => Ok(None),
+        SECURITY_AES_128_GCM => Ok(Some(AeadCipher::new(
+            iv,
+            VmessSecurity::Aes128Gcm(Aes128Gcm::new_with_slice(key)),
+        ))),
+        SECURITY_CHACHA20_POLY1305 => {
+            let mut stretched = [0u8; 32];
+            stretched[..16].copy_from_slice(&utils::md5(key));
+            let tmp = utils::md5(&stretched[..16]);
+            stretched[16..].copy_from_slice(&tmp);
+            Ok(Some(AeadCipher::new(
+                iv,
+                VmessSecurity::ChaCha20Poly1305(ChaCha20Poly1305::new_with_slice(&stretched)),
+            )))
+        }
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "unsupported security",
+        )),
+    }
+}
+
 fn hash_timestamp(timestamp: u64) -> [u8; 16] {
     unsafe {
         let mut ctx = boring_sys::MD5_CTX::default();
@@ -0,0 +1,324 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{Sink, SinkExt, Stream};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha2::Sha256;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::debug;
+
+use crate::{
+    common::utils,
+    proxy::{datagram::UdpPacket, AnyStream},
+    session::SocksAddr,
+};
+
+const SESSION_STATUS_NEW: u8 = 0x01;
+const SESSION_STATUS_KEEP: u8 = 0x02;
+const SESSION_STATUS_END: u8 = 0x03;
+const SESSION_STATUS_KEEP_ALIVE: u8 = 0x04;
+
+const OPTION_DATA: u8 = 0x01;
+
+const NETWORK_UDP: u8 = 0x02;
+
+const ADDR_IPV4: u8 = 0x01;
+const ADDR_DOMAIN: u8 = 0x02;
+const ADDR_IPV6: u8 = 0x03;
+
+/// the key global IDs are derived with, fresh for every run
+static GLOBAL_ID_KEY: Lazy<[u8; 32]> = Lazy::new(|| {
+    let mut key = [0u8; 32];
+    utils::rand_fill(&mut key);
+    key
+});
+
+/// the XUDP global ID of a UDP session, the server keeps using the same
+/// socket for sessions with the same ID, which makes UDP full cone.
+/// It's derived from the source address of the session, so it's stable
+/// for a client but tells the server nothing about it.
+pub fn global_id(source: &SocketAddr) -> [u8; 8] {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(GLOBAL_ID_KEY.as_ref()).expect("hmac takes any key size");
+    mac.update(source.to_string().as_bytes());
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&mac.finalize().into_bytes()[..8]);
+    id
+}
+
+/// returns the address and its encoded length, or None if `buf` is incomplete
+fn peek_addr(buf: &[u8]) -> io::Result<Option<(SocksAddr, usize)>> {
+    if buf.len() < 3 {
+        return Ok(None);
+    }
+    let port = u16::from_be_bytes([buf[0], buf[1]]);
+    match buf[2] {
+        ADDR_IPV4 => {
+            if buf.len() < 3 + 4 {
+                return Ok(None);
+            }
+            let ip = Ipv4Addr::new(buf[3], buf[4], buf[5], buf[6]);
+            Ok(Some(((ip, port).into(), 7)))
+        }
+        ADDR_IPV6 => {
+            if buf.len() < 3 + 16 {
+                return Ok(None);
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buf[3..19]);
+            Ok(Some(((Ipv6Addr::from(octets), port).into(), 19)))
+        }
+        ADDR_DOMAIN => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            let len = buf[3] as usize;
+            if buf.len() < 4 + len {
+                return Ok(None);
+            }
+            let domain = String::from_utf8(buf[4..4 + len].to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(Some((SocksAddr::Domain(domain, port), 4 + len)))
+        }
+        t => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid xudp address type: {}", t),
+        )),
+    }
+}
+
+/*
+XUDP is Mux.Cool with a single UDP session, every frame carries the
+address of its packet, the first one also the global ID.
++------------+------------+--------+--------+---------+------+-----------+
+| META LEN 2 | SESSION 2  | STATUS | OPTION | NETWORK | ADDR | GLOBAL ID |
++------------+------------+--------+--------+---------+------+-----------+
+followed by a 2 bytes length and the data if OPTION has the data bit.
+*/
+struct XudpCodec {
+    global_id: [u8; 8],
+    started: bool,
+}
+
+impl Encoder<UdpPacket> for XudpCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: UdpPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if item.data.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "udp packet too large",
+            ));
+        }
+
+        let mut meta = BytesMut::new();
+        meta.put_u16(0);
+        if self.started {
+            meta.put_u8(SESSION_STATUS_KEEP);
+        } else {
+            meta.put_u8(SESSION_STATUS_NEW);
+        }
+        meta.put_u8(OPTION_DATA);
+        meta.put_u8(NETWORK_UDP);
+        item.dst_addr.write_to_buf_vmess(&mut meta);
+        if !self.started {
+            meta.put_slice(&self.global_id);
+            self.started = true;
+        }
+
+        dst.reserve(2 + meta.len() + 2 + item.data.len());
+        dst.put_u16(meta.len() as u16);
+        dst.put_slice(&meta);
+        dst.put_u16(item.data.len() as u16);
+        dst.put_slice(&item.data);
+        Ok(())
+    }
+}
+
+impl Decoder for XudpCodec {
+    type Item = UdpPacket;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            if src.len() < 2 {
+                return Ok(None);
+            }
+            let meta_len = u16::from_be_bytes([src[0], src[1]]) as usize;
+            if meta_len < 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid xudp frame",
+                ));
+            }
+            if src.len() < 2 + meta_len {
+                src.reserve(2 + meta_len - src.len());
+                return Ok(None);
+            }
+
+            let meta = &src[2..2 + meta_len];
+            let status = meta[2];
+            let has_data = meta[3] & OPTION_DATA != 0;
+            let addr = match status {
+                // a keep frame without an address is from the session destination
+                SESSION_STATUS_KEEP if meta_len > 5 => match peek_addr(&meta[5..])? {
+                    Some((addr, _)) => Some(addr),
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "invalid xudp frame address",
+                        ))
+                    }
+                },
+                SESSION_STATUS_KEEP | SESSION_STATUS_KEEP_ALIVE => None,
+                SESSION_STATUS_END => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "xudp session ended",
+                    ))
+                }
+                s => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected xudp session status: {}", s),
+                    ))
+                }
+            };
+
+            let mut frame_len = 2 + meta_len;
+            let mut data_len = 0;
+            if has_data {
+                if src.len() < frame_len + 2 {
+                    return Ok(None);
+                }
+                data_len = u16::from_be_bytes([src[frame_len], src[frame_len + 1]]) as usize;
+                frame_len += 2;
+                if src.len() < frame_len + data_len {
+                    src.reserve(frame_len + data_len - src.len());
+                    return Ok(None);
+                }
+            }
+
+            src.advance(frame_len);
+            let data = src.split_to(data_len).to_vec();
+            if status == SESSION_STATUS_KEEP_ALIVE || !has_data {
+                continue;
+            }
+
+            return Ok(Some(UdpPacket {
+                data,
+                src_addr: addr.unwrap_or(SocksAddr::any_ipv4()),
+                dst_addr: SocksAddr::any_ipv4(),
+            }));
+        }
+    }
+}
+
+/// UDP over a VMess mux command stream, packets to any destination share
+/// the one stream.
+pub struct OutboundDatagramXudp {
+    inner: Framed<AnyStream, XudpCodec>,
+}
+
+impl OutboundDatagramXudp {
+    pub fn new(inner: AnyStream, global_id: [u8; 8]) -> Self {
+        Self {
+            inner: Framed::new(
+                inner,
+                XudpCodec {
+                    global_id,
+                    started: false,
+                },
+            ),
+        }
+    }
+}
+
+impl Sink<UdpPacket> for OutboundDatagramXudp {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+        self.inner.start_send_unpin(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+impl Stream for OutboundDatagramXudp {
+    type Item = UdpPacket;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match futures::ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+            Some(Ok(pkt)) => Poll::Ready(Some(pkt)),
+            Some(Err(e)) => {
+                debug!("failed to read xudp packet: {}", e);
+                Poll::Ready(None)
+            }
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::{proxy::datagram::UdpPacket, session::SocksAddr};
+
+    use super::{global_id, XudpCodec};
+
+    #[test]
+    fn test_xudp_codec() {
+        let mut codec = XudpCodec {
+            global_id: global_id(&"127.0.0.1:5353".parse().unwrap()),
+            started: false,
+        };
+        let dst: SocksAddr = "1.1.1.1:53".parse::<std::net::SocketAddr>().unwrap().into();
+
+        let mut buf = BytesMut::new();
+        for _ in 0..2 {
+            codec
+                .encode(
+                    UdpPacket {
+                        data: b"query".to_vec(),
+                        src_addr: SocksAddr::any_ipv4(),
+                        dst_addr: dst.clone(),
+                    },
+                    &mut buf,
+                )
+                .unwrap();
+        }
+        // new frame with the global id, then a keep frame
+        assert_eq!(buf[4], 0x01);
+        assert_eq!(u16::from_be_bytes([buf[0], buf[1]]), 4 + 1 + 7 + 8);
+
+        // the server answers with keep frames carrying the source
+        let mut resp = buf.split_off(2 + 20 + 2 + 5);
+        let pkt = codec.decode(&mut resp).unwrap().unwrap();
+        assert_eq!(pkt.src_addr, dst);
+        assert_eq!(pkt.data, b"query");
+        assert!(resp.is_empty());
+
+        assert_eq!(
+            global_id(&"127.0.0.1:5353".parse().unwrap()),
+            global_id(&"127.0.0.1:5353".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    fmt::Debug,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::{ready, Sink, Stream};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_util::sync::PollSender;
+use tracing::debug;
+
+use crate::{proxy::datagram::UdpPacket, session::SocksAddr};
+
+use super::{device::Device, stack::UdpSocket};
+
+/// the packets waiting for the sending task
+const SEND_QUEUE_SIZE: usize = 32;
+
+/// UDP over the stack inside the tunnel. The packets are sent by a task of
+/// their own as a domain may have to be resolved through the tunnel first,
+/// the ones that can't be sent are dropped as on any other UDP socket.
+#[must_use = "sinks do nothing unless polled"]
+pub struct OutboundDatagramWireguard {
+    socket: Arc<UdpSocket>,
+    tx: PollSender<UdpPacket>,
+    sending: JoinHandle<()>,
+}
+
+impl OutboundDatagramWireguard {
+    pub fn new(device: Arc<Device>, socket: UdpSocket) -> Self {
+        let socket = Arc::new(socket);
+        let (tx, rx) = mpsc::channel(SEND_QUEUE_SIZE);
+        Self {
+            socket: socket.clone(),
+            tx: PollSender::new(tx),
+            sending: tokio::spawn(send(device, socket, rx)),
+        }
+    }
+}
+
+/// sends the packets until the stack is down or the datagram is dropped
+async fn send(device: Arc<Device>, socket: Arc<UdpSocket>, mut rx: mpsc::Receiver<UdpPacket>) {
+    while let Some(pkt) = rx.recv().await {
+        let dst = match device.resolve_addr(&pkt.dst_addr).await {
+            Ok(dst) => dst,
+            Err(e) => {
+                debug!("wireguard dropping udp packet to {}: {}", pkt.dst_addr, e);
+                continue;
+            }
+        };
+        match socket.send_to(&pkt.data, dst).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => {
+                debug!("wireguard udp socket is closed: {}", e);
+                return;
+            }
+            Err(e) => debug!("wireguard dropping udp packet to {}: {}", dst, e),
+        }
+    }
+}
+
+impl Debug for OutboundDatagramWireguard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutboundDatagramWireguard")
+            .field("port", &self.socket.local_port())
+            .finish()
+    }
+}
+
+impl Drop for OutboundDatagramWireguard {
+    fn drop(&mut self) {
+        self.sending.abort();
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the wireguard tunnel is down")
+}
+
+impl Sink<UdpPacket> for OutboundDatagramWireguard {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.tx.poll_reserve(cx).map_err(|_| closed())
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+        self.tx.send_item(item).map_err(|_| closed())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.tx.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for OutboundDatagramWireguard {
+    type Item = UdpPacket;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match ready!(self.socket.poll_recv_from(cx)) {
+            Ok((data, src)) => Poll::Ready(Some(UdpPacket {
+                data,
+                src_addr: src.into(),
+                dst_addr: SocksAddr::any_ipv4(),
+            })),
+            Err(e) => {
+                debug!("wireguard udp socket is closed: {}", e);
+                Poll::Ready(None)
+            }
+        }
+    }
+}
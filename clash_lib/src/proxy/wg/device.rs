@@ -0,0 +1,610 @@
+//! A WireGuard interface: the stack, the tunnels to the peers and the tasks
+//! moving the packets between them.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use boringtun::noise::Tunn;
+use hickory_proto::{
+    op::{Message, Query},
+    rr::{Name, RecordType},
+};
+use tokio::task::JoinHandle;
+use tracing::{debug, trace, warn};
+
+use crate::{
+    app::dns::ThreadSafeDNSResolver,
+    common::{
+        errors::{map_io_error, new_io_error},
+        nat64,
+    },
+    proxy::utils::new_udp_socket,
+    session::SocksAddr,
+};
+
+use super::{
+    stack::{Stack, TcpStream, UdpSocket},
+    wireguard::{Endpoint, Tunnel},
+    Opts, PeerOpts,
+};
+
+/// how often the handshake and keepalive timers run, as boringtun expects
+const TIMER_INTERVAL: Duration = Duration::from_millis(250);
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Device {
+    stack: Arc<Stack>,
+    tunnels: Vec<Arc<Tunnel>>,
+    has_ipv6: bool,
+    resolver: ThreadSafeDNSResolver,
+    /// the servers asked through the tunnel with `remote-dns-resolve`
+    dns: Vec<SocketAddr>,
+    /// a tunnel is down, the next sessions bring up a new device
+    closed: Arc<AtomicBool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.stack.close();
+    }
+}
+
+/// the tunnel whose peer has the most specific allowed ip containing `ip`,
+/// the first one if several do
+fn peer_for(tunnels: &[Arc<Tunnel>], ip: IpAddr) -> Option<&Arc<Tunnel>> {
+    tunnels
+        .iter()
+        .filter_map(|t| t.peer.matches(ip).map(|len| (len, t)))
+        .rev()
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, t)| t)
+}
+
+/// the socket the tunnel packets to `peer` are sent over, through the
+/// `dialer-proxy` if there is one, e.g. wireguard over shadowsocks
+async fn connect_endpoint(
+    opts: &Opts,
+    peer: &PeerOpts,
+    resolver: ThreadSafeDNSResolver,
+) -> io::Result<Endpoint> {
+    if let Some(dialer) = opts.common_opts.dialer_proxy() {
+        let addr: SocksAddr = (peer.server.clone(), peer.port).try_into()?;
+        let datagram = dialer.connect_datagram(resolver, addr.clone()).await?;
+        return Ok(Endpoint::detour(datagram, addr));
+    }
+
+    let ip = match peer.server.parse() {
+        Ok(ip) => ip,
+        Err(_) => resolver
+            .resolve(&peer.server, false)
+            .await
+            .map_err(map_io_error)?
+            .ok_or_else(|| new_io_error(format!("failed to resolve {}", peer.server).as_str()))?,
+    };
+    let addr = nat64::translate(SocketAddr::new(ip, peer.port));
+    let local = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = new_udp_socket(
+        Some(&local),
+        opts.common_opts.iface.as_ref(),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        opts.common_opts.so_mark,
+    )
+    .await?;
+    Ok(Endpoint::Socket { socket, addr })
+}
+
+/// sends the packets of the stack to the peers they are routed to
+async fn route(stack: Arc<Stack>, tunnels: Vec<Arc<Tunnel>>) {
+    loop {
+        let (packets, delay) = stack.poll();
+        for packet in packets {
+            let dst = Tunn::dst_address(&packet);
+            match dst.and_then(|ip| peer_for(&tunnels, ip)) {
+                Some(tunnel) => {
+                    if let Err(e) = tunnel.send(&packet).await {
+                        debug!("wireguard failed to send to {}: {}", tunnel, e);
+                    }
+                }
+                None => trace!("wireguard has no peer for {:?}", dst),
+            }
+        }
+
+        match delay {
+            Some(delay) => {
+                tokio::select! {
+                    _ = stack.notified() => {}
+                    _ = tokio::time::sleep(delay) => {}
+                }
+            }
+            None => stack.notified().await,
+        }
+    }
+}
+
+/// starts routing the packets of the stack to the tunnels, receiving from
+/// them and running their timers, `closed` is set once a tunnel is down
+fn spawn(
+    stack: &Arc<Stack>,
+    tunnels: &[Arc<Tunnel>],
+    closed: &Arc<AtomicBool>,
+) -> Vec<JoinHandle<()>> {
+    let mut tasks = vec![tokio::spawn(route(stack.clone(), tunnels.to_vec()))];
+    for tunnel in tunnels {
+        let (t, stack, closed) = (tunnel.clone(), stack.clone(), closed.clone());
+        tasks.push(tokio::spawn(async move {
+            if let Err(e) = t.receive(&stack).await {
+                warn!("wireguard tunnel to {} is down: {}", t, e);
+            }
+            closed.store(true, Ordering::Relaxed);
+            stack.close();
+        }));
+
+        let t = tunnel.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TIMER_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = t.update_timers().await {
+                    debug!("wireguard failed to send to {}: {}", t, e);
+                }
+            }
+        }));
+    }
+    tasks
+}
+
+impl Device {
+    /// brings the tunnels to the peers up
+    pub async fn new(opts: &Opts, resolver: ThreadSafeDNSResolver) -> io::Result<Arc<Self>> {
+        let mut ips = vec![IpAddr::V4(opts.ip)];
+        ips.extend(opts.ipv6.map(IpAddr::V6));
+        let stack = Stack::new(&ips, opts.mtu as usize);
+
+        let mut tunnels = vec![];
+        for (i, peer) in opts.peers.iter().enumerate() {
+            let tunn = Tunn::new(
+                opts.private_key.clone(),
+                peer.public_key,
+                peer.preshared_key,
+                opts.persistent_keepalive,
+                i as u32,
+                None,
+            )
+            .map_err(new_io_error)?;
+            let endpoint = connect_endpoint(opts, peer, resolver.clone()).await?;
+            tunnels.push(Arc::new(Tunnel::new(tunn, endpoint, peer.clone())));
+        }
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let tasks = spawn(&stack, &tunnels, &closed);
+
+        Ok(Arc::new(Self {
+            stack,
+            tunnels,
+            has_ipv6: opts.ipv6.is_some(),
+            resolver,
+            dns: opts.dns.iter().map(|ip| SocketAddr::new(*ip, 53)).collect(),
+            closed,
+            tasks,
+        }))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// fails unless a peer takes `ip` and this end has an address of its
+    /// family
+    pub fn check(&self, ip: IpAddr) -> io::Result<()> {
+        if ip.is_ipv6() && !self.has_ipv6 {
+            return Err(new_io_error(
+                format!("{} is IPv6, which the tunnel has no address for", ip).as_str(),
+            ));
+        }
+        if peer_for(&self.tunnels, ip).is_none() {
+            return Err(new_io_error(
+                format!("no peer has an allowed ip containing {}", ip).as_str(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn connect(&self, dst: SocketAddr) -> io::Result<TcpStream> {
+        self.check(dst.ip())?;
+        self.stack.connect(dst).await
+    }
+
+    pub fn bind(&self) -> io::Result<UdpSocket> {
+        self.stack.bind()
+    }
+
+    /// the IP of `host`, asked of the `dns` servers through the tunnel with
+    /// `remote-dns-resolve`, of the resolver otherwise
+    pub async fn resolve(&self, host: &str) -> io::Result<IpAddr> {
+        if self.dns.is_empty() {
+            let ip = if self.has_ipv6 {
+                self.resolver.resolve(host, false).await
+            } else {
+                self.resolver
+                    .resolve_v4(host, false)
+                    .await
+                    .map(|x| x.map(IpAddr::V4))
+            };
+            return ip
+                .map_err(map_io_error)?
+                .ok_or_else(|| new_io_error(format!("failed to resolve {}", host).as_str()));
+        }
+
+        let mut err = None;
+        for server in &self.dns {
+            match tokio::time::timeout(DNS_TIMEOUT, self.query(host, *server)).await {
+                Ok(Ok(ip)) => return Ok(ip),
+                Ok(Err(e)) => err = Some(e),
+                Err(_) => {
+                    err = Some(new_io_error(
+                        format!("{} timed out resolving {}", server, host).as_str(),
+                    ))
+                }
+            }
+        }
+        Err(err.unwrap_or_else(|| new_io_error("no dns server")))
+    }
+
+    /// the address of `dst` inside the tunnel, failing unless a peer takes it
+    pub async fn resolve_addr(&self, dst: &SocksAddr) -> io::Result<SocketAddr> {
+        let addr = match dst {
+            SocksAddr::Ip(addr) => *addr,
+            SocksAddr::Domain(host, port) => SocketAddr::new(self.resolve(host).await?, *port),
+        };
+        self.check(addr.ip())?;
+        Ok(addr)
+    }
+
+    /// asks `server` through the tunnel for the A records of `host`, then
+    /// the AAAA ones if this end has an IPv6 address
+    async fn query(&self, host: &str, server: SocketAddr) -> io::Result<IpAddr> {
+        let name = Name::from_ascii(host).map_err(map_io_error)?;
+        let socket = self.stack.bind()?;
+
+        let types: &[RecordType] = if self.has_ipv6 {
+            &[RecordType::A, RecordType::AAAA]
+        } else {
+            &[RecordType::A]
+        };
+        for typ in types {
+            let mut query = Message::new();
+            query
+                .set_id(rand::random())
+                .set_recursion_desired(true)
+                .add_query(Query::query(name.clone(), *typ));
+            socket
+                .send_to(&query.to_vec().map_err(map_io_error)?, server)
+                .await?;
+
+            let answer = loop {
+                let (data, src) = socket.recv_from().await?;
+                match Message::from_vec(&data) {
+                    Ok(answer) if src == server && answer.id() == query.id() => break answer,
+                    _ => continue,
+                }
+            };
+            if let Some(ip) = answer
+                .answers()
+                .iter()
+                .find_map(|r| r.data().and_then(|d| d.ip_addr()))
+            {
+                return Ok(ip);
+            }
+        }
+        Err(new_io_error(
+            format!("{} has no address for {}", server, host).as_str(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, SocketAddr},
+        pin::Pin,
+        sync::{atomic::AtomicBool, Arc, Mutex},
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use boringtun::{
+        noise::Tunn,
+        x25519::{PublicKey, StaticSecret},
+    };
+
+    use futures::{Sink, Stream};
+    use tokio::sync::mpsc;
+    use tokio_util::sync::PollSender;
+
+    use super::{connect_endpoint, peer_for, spawn, Stack};
+    use crate::{
+        app::{dispatcher::ChainedDatagramWrapper, dns::MockClashResolver},
+        proxy::{
+            datagram::UdpPacket,
+            mocks::MockDummyOutboundHandler,
+            wg::{
+                wireguard::{Endpoint, Tunnel},
+                Opts, PeerOpts,
+            },
+            AnyOutboundHandler, CommonOption,
+        },
+        session::SocksAddr,
+    };
+
+    fn key() -> StaticSecret {
+        StaticSecret::from(rand::random::<[u8; 32]>())
+    }
+
+    fn peer(port: u16, allowed_ips: &[&str]) -> PeerOpts {
+        PeerOpts {
+            server: "127.0.0.1".to_owned(),
+            port,
+            public_key: PublicKey::from(&key()),
+            preshared_key: None,
+            allowed_ips: allowed_ips.iter().map(|x| x.parse().unwrap()).collect(),
+        }
+    }
+
+    /// a tunnel from `local` to `remote` over loopback sockets
+    async fn tunnel(
+        local: StaticSecret,
+        remote: &StaticSecret,
+        socket: tokio::net::UdpSocket,
+        addr: SocketAddr,
+        peer: PeerOpts,
+    ) -> Arc<Tunnel> {
+        let tunn = Tunn::new(local, PublicKey::from(remote), None, None, 0, None).unwrap();
+        Arc::new(Tunnel::new(tunn, Endpoint::Socket { socket, addr }, peer))
+    }
+
+    /// the datagram of a mock `dialer-proxy`, its packets go to and come
+    /// from the test
+    struct ChannelDatagram {
+        tx: PollSender<UdpPacket>,
+        rx: mpsc::Receiver<UdpPacket>,
+    }
+
+    impl Sink<UdpPacket> for ChannelDatagram {
+        type Error = std::io::Error;
+
+        fn poll_ready(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            self.tx
+                .poll_reserve(cx)
+                .map_err(|_| std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+            self.tx
+                .send_item(item)
+                .map_err(|_| std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Stream for ChannelDatagram {
+        type Item = UdpPacket;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.rx.poll_recv(cx)
+        }
+    }
+
+    /// sends a packet from `a` to `b` and the reply back over the tunnels
+    async fn ping(stack_a: &Arc<Stack>, stack_b: &Arc<Stack>, ip_a: IpAddr, ip_b: IpAddr) {
+        let a = stack_a.bind().unwrap();
+        let b = stack_b.bind().unwrap();
+        a.send_to(b"ping", SocketAddr::new(ip_b, b.local_port()))
+            .await
+            .unwrap();
+        let (data, src) = tokio::time::timeout(Duration::from_secs(5), b.recv_from())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(data, b"ping");
+        assert_eq!(src, SocketAddr::new(ip_a, a.local_port()));
+
+        b.send_to(b"pong", src).await.unwrap();
+        let (data, src) = tokio::time::timeout(Duration::from_secs(5), a.recv_from())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(data, b"pong");
+        assert_eq!(src, SocketAddr::new(ip_b, b.local_port()));
+    }
+
+    #[tokio::test]
+    async fn test_peer_for() {
+        let mut tunnels = vec![];
+        for (port, allowed_ips) in [
+            (1, &["10.0.0.0/8"][..]),
+            (2, &["10.1.0.0/16", "192.168.0.0/16"][..]),
+            (3, &["10.1.0.0/16"][..]),
+            (4, &[][..]),
+        ] {
+            let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            tunnels.push(tunnel(key(), &key(), socket, addr, peer(port, allowed_ips)).await);
+        }
+        let port_for = |ip: &str| peer_for(&tunnels, ip.parse().unwrap()).map(|t| t.peer.port);
+
+        // the most specific allowed ip wins
+        assert_eq!(port_for("10.2.0.1"), Some(1));
+        // ties go to the first peer
+        assert_eq!(port_for("10.1.0.1"), Some(2));
+        assert_eq!(port_for("192.168.1.1"), Some(2));
+        // a peer without allowed ips takes the rest
+        assert_eq!(port_for("1.1.1.1"), Some(4));
+
+        assert_eq!(
+            peer_for(&tunnels[..3], "1.1.1.1".parse().unwrap()).map(|t| t.peer.port),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_udp_round_trip() {
+        let (key_a, key_b) = (key(), key());
+        let socket_a = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket_b = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (addr_a, addr_b) = (
+            socket_a.local_addr().unwrap(),
+            socket_b.local_addr().unwrap(),
+        );
+        let (ip_a, ip_b): (IpAddr, IpAddr) =
+            ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        let stack_a = Stack::new(&[ip_a], 1420);
+        let stack_b = Stack::new(&[ip_b], 1420);
+        let tunnel_a = tunnel(
+            key_a.clone(),
+            &key_b,
+            socket_a,
+            addr_b,
+            peer(addr_b.port(), &[]),
+        )
+        .await;
+        let tunnel_b = tunnel(key_b, &key_a, socket_b, addr_a, peer(addr_a.port(), &[])).await;
+        let closed = Arc::new(AtomicBool::new(false));
+        let tasks: Vec<_> = spawn(&stack_a, &[tunnel_a], &closed)
+            .into_iter()
+            .chain(spawn(&stack_b, &[tunnel_b], &closed))
+            .collect();
+
+        ping(&stack_a, &stack_b, ip_a, ip_b).await;
+
+        for task in tasks {
+            task.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_round_trip_through_dialer_proxy() {
+        let (key_a, key_b) = (key(), key());
+        let relay_addr = SocksAddr::Domain("relay.test".to_owned(), 51820);
+        let (ip_a, ip_b): (IpAddr, IpAddr) =
+            ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        // the dialer-proxy hands its packets to the relay below
+        let (out_tx, mut out_rx) = mpsc::channel(32);
+        let (in_tx, in_rx) = mpsc::channel(32);
+        let datagram = Mutex::new(Some(ChannelDatagram {
+            tx: PollSender::new(out_tx),
+            rx: in_rx,
+        }));
+        let mut dialer = MockDummyOutboundHandler::new();
+        let expected = relay_addr.clone();
+        dialer
+            .expect_connect_datagram()
+            .times(1)
+            .returning(move |sess, _| {
+                assert_eq!(sess.destination, expected);
+                Ok(Box::new(ChainedDatagramWrapper::new(
+                    datagram.lock().unwrap().take().unwrap(),
+                )))
+            });
+        let dialer: AnyOutboundHandler = Arc::new(dialer);
+
+        let opts = Opts {
+            name: "wg".to_owned(),
+            common_opts: CommonOption::new(Some("detour".to_owned())),
+            ip: "10.0.0.1".parse().unwrap(),
+            ipv6: None,
+            private_key: key_a.clone(),
+            peers: vec![],
+            persistent_keepalive: None,
+            dns: vec![],
+            mtu: 1420,
+            udp: true,
+        };
+        opts.common_opts.dialer_proxy().unwrap().bind(&dialer);
+        let mut peer_a = peer(51820, &[]);
+        peer_a.server = "relay.test".to_owned();
+        let endpoint = connect_endpoint(&opts, &peer_a, Arc::new(MockClashResolver::new()))
+            .await
+            .unwrap();
+        assert!(matches!(endpoint, Endpoint::Detour { .. }));
+
+        let socket_b = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr_b = socket_b.local_addr().unwrap();
+        let relay = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_local = relay.local_addr().unwrap();
+        let relay_task = tokio::spawn(async move {
+            let mut buf = vec![0; 65535];
+            loop {
+                tokio::select! {
+                    Some(pkt) = out_rx.recv() => {
+                        // only the packets sent to the peer get through
+                        if pkt.dst_addr == relay_addr {
+                            relay.send_to(&pkt.data, addr_b).await.unwrap();
+                        }
+                    }
+                    Ok((n, _)) = relay.recv_from(&mut buf) => {
+                        in_tx
+                            .send(UdpPacket::new(
+                                buf[..n].to_vec(),
+                                relay_addr.clone(),
+                                SocksAddr::any_ipv4(),
+                            ))
+                            .await
+                            .unwrap();
+                    }
+                }
+            }
+        });
+
+        let stack_a = Stack::new(&[ip_a], 1420);
+        let stack_b = Stack::new(&[ip_b], 1420);
+        let tunn_a =
+            Tunn::new(key_a.clone(), PublicKey::from(&key_b), None, None, 0, None).unwrap();
+        let tunnel_a = Arc::new(Tunnel::new(tunn_a, endpoint, peer_a));
+        let tunnel_b = tunnel(
+            key_b,
+            &key_a,
+            socket_b,
+            relay_local,
+            peer(relay_local.port(), &[]),
+        )
+        .await;
+        let closed = Arc::new(AtomicBool::new(false));
+        let tasks: Vec<_> = spawn(&stack_a, &[tunnel_a], &closed)
+            .into_iter()
+            .chain(spawn(&stack_b, &[tunnel_b], &closed))
+            .collect();
+
+        ping(&stack_a, &stack_b, ip_a, ip_b).await;
+
+        relay_task.abort();
+        for task in tasks {
+            task.abort();
+        }
+    }
+}
@@ -1,48 +1,104 @@
+mod datagram;
+mod device;
+mod stack;
+mod wireguard;
+
 use std::{
+    collections::HashMap,
     io,
-    net::{Ipv4Addr, Ipv6Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     sync::Arc,
 };
 
+use async_trait::async_trait;
+use boringtun::x25519::{PublicKey, StaticSecret};
+use erased_serde::Serialize as ESerialize;
+use ipnet::IpNet;
+use tokio::sync::Mutex;
+
 use crate::{
-    app::{dispatcher::BoxedChainedStream, dns::ThreadSafeDNSResolver},
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram, ChainedDatagramWrapper,
+            ChainedStream, ChainedStreamWrapper,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    common::errors::new_io_error,
     session::{Session, SocksAddr},
 };
 
+use self::{datagram::OutboundDatagramWireguard, device::Device};
+
 use super::{
-    AnyOutboundDatagram, AnyOutboundHandler, AnyStream, CommonOption, OutboundHandler, OutboundType,
+    utils::DialerProxy, AnyOutboundHandler, AnyStream, CommonOption, OutboundHandler, OutboundType,
 };
 
-use async_trait::async_trait;
-pub use netstack_lwip as netstack;
+#[derive(Clone)]
+pub struct PeerOpts {
+    pub server: String,
+    pub port: u16,
+    pub public_key: PublicKey,
+    pub preshared_key: Option<[u8; 32]>,
+    /// destinations routed to this peer, all of them if empty
+    pub allowed_ips: Vec<IpNet>,
+}
+
+impl PeerOpts {
+    /// the prefix length of the most specific allowed ip containing `ip`
+    fn matches(&self, ip: IpAddr) -> Option<u8> {
+        if self.allowed_ips.is_empty() {
+            return Some(0);
+        }
+        self.allowed_ips
+            .iter()
+            .filter(|x| x.contains(&ip))
+            .map(|x| x.prefix_len())
+            .max()
+    }
+}
 
 pub struct Opts {
     pub name: String,
     pub common_opts: CommonOption,
-    pub server: String,
-    pub port: u16,
     pub ip: Ipv4Addr,
     pub ipv6: Option<Ipv6Addr>,
-    pub private_key: String,
-    pub public_key: String,
-    pub preshared_key: Option<String>,
-    pub remote_dns_resolve: bool,
-    pub dns: Option<Vec<String>>,
-    pub mtu: Option<u16>,
+    pub private_key: StaticSecret,
+    /// every peer has its own endpoint, the peer with the most specific
+    /// allowed ip for the destination is used
+    pub peers: Vec<PeerOpts>,
+    pub persistent_keepalive: Option<u16>,
+    /// the servers domains are resolved with through the tunnel, the
+    /// resolver is used if empty
+    pub dns: Vec<IpAddr>,
+    pub mtu: u16,
     pub udp: bool,
 }
 
 pub struct Handler {
     opts: Opts,
-
-    device: boringtun::device::Device,
+    /// brought up by the first session, and again once a tunnel is down
+    device: Mutex<Option<Arc<Device>>>,
 }
 
 impl Handler {
     pub fn new(opts: Opts) -> AnyOutboundHandler {
-        let device_cfg = boringtun::device::DeviceConfig::default();
-        let device = boringtun::device::Device::new("utun", device_cfg).unwrap();
-        Arc::new(Self { opts, device })
+        Arc::new(Self {
+            opts,
+            device: Mutex::new(None),
+        })
+    }
+
+    async fn device(&self, resolver: ThreadSafeDNSResolver) -> io::Result<Arc<Device>> {
+        let mut device = self.device.lock().await;
+        match device.as_ref() {
+            Some(d) if !d.is_closed() => Ok(d.clone()),
+            _ => {
+                let d = Device::new(&self.opts, resolver).await?;
+                *device = Some(d.clone());
+                Ok(d)
+            }
+        }
     }
 }
 
@@ -56,39 +112,98 @@ impl OutboundHandler for Handler {
         OutboundType::WireGuard
     }
 
+    fn dialer_proxy(&self) -> Option<&DialerProxy> {
+        self.opts.common_opts.dialer_proxy()
+    }
+
     async fn remote_addr(&self) -> Option<SocksAddr> {
-        None
+        self.opts
+            .peers
+            .first()
+            .map(|p| SocksAddr::Domain(p.server.clone(), p.port))
     }
 
     async fn support_udp(&self) -> bool {
         self.opts.udp
     }
 
-    /// connect to remote target via TCP
     async fn connect_stream(
         &self,
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        todo!()
+        let device = self.device(resolver).await?;
+        let dst = device.resolve_addr(&sess.destination).await?;
+        let s: AnyStream = Box::new(device.connect(dst).await?);
+        let chained = ChainedStreamWrapper::new(s);
+        chained.append_to_chain(self.name()).await;
+        Ok(Box::new(chained))
     }
 
-    /// wraps a stream with outbound handler
+    /// the sessions run on the stack inside the tunnel, there's no stream
+    /// to wrap
     async fn proxy_stream(
         &self,
-        s: AnyStream,
-        sess: &Session,
-        resolver: ThreadSafeDNSResolver,
+        _s: AnyStream,
+        _sess: &Session,
+        _resolver: ThreadSafeDNSResolver,
     ) -> io::Result<AnyStream> {
-        todo!()
+        Err(new_io_error(
+            format!(
+                "wireguard {} can't proxy a stream, e.g. in a relay",
+                self.name()
+            )
+            .as_str(),
+        ))
     }
 
-    /// connect to remote target via UDP
     async fn connect_datagram(
         &self,
-        sess: &Session,
+        _sess: &Session,
         resolver: ThreadSafeDNSResolver,
-    ) -> io::Result<AnyOutboundDatagram> {
-        todo!()
+    ) -> io::Result<BoxedChainedDatagram> {
+        let device = self.device(resolver).await?;
+        let socket = device.bind()?;
+        let d = OutboundDatagramWireguard::new(device, socket);
+        let chained = ChainedDatagramWrapper::new(d);
+        chained.append_to_chain(self.name()).await;
+        Ok(Box::new(chained))
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        let mut m = HashMap::new();
+        m.insert("type".to_string(), Box::new(self.proto()) as _);
+        m.insert("udp".to_string(), Box::new(self.opts.udp) as _);
+        m
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use boringtun::x25519::{PublicKey, StaticSecret};
+
+    use super::PeerOpts;
+
+    #[test]
+    fn test_peer_matches() {
+        let mut peer = PeerOpts {
+            server: "1.2.3.4".to_owned(),
+            port: 51820,
+            public_key: PublicKey::from(&StaticSecret::from([1; 32])),
+            preshared_key: None,
+            allowed_ips: vec![],
+        };
+        // no allowed ips means all of them, as the least specific match
+        assert_eq!(peer.matches("8.8.8.8".parse().unwrap()), Some(0));
+        assert_eq!(peer.matches("::1".parse().unwrap()), Some(0));
+
+        peer.allowed_ips = vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "10.1.0.0/16".parse().unwrap(),
+        ];
+        assert_eq!(peer.matches("10.1.2.3".parse().unwrap()), Some(16));
+        assert_eq!(peer.matches("10.2.2.3".parse().unwrap()), Some(8));
+        assert_eq!(peer.matches("8.8.8.8".parse().unwrap()), None);
+        assert_eq!(peer.matches("::1".parse().unwrap()), None);
     }
 }
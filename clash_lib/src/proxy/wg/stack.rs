@@ -0,0 +1,514 @@
+//! The userspace TCP/IP stack the sessions through the tunnel run on:
+//! smoltcp over a device whose IP packets go to and come from the peers.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::Debug,
+    future::poll_fn,
+    io,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::{self, DeviceCapabilities, Medium},
+    socket::{tcp, udp, Socket},
+    time::Instant,
+    wire::{HardwareAddress, IpCidr},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Notify,
+};
+
+use crate::common::errors::new_io_error;
+
+const TCP_BUFFER_SIZE: usize = 128 * 1024;
+const UDP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_PACKETS: usize = 64;
+/// the packets from the peers waiting for the stack, more are dropped
+const RX_QUEUE_SIZE: usize = 1024;
+/// how long a stream may go without its data acknowledged
+const TCP_TIMEOUT: Duration = Duration::from_secs(60);
+/// how long a dropped stream may take to close before it's reset
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// the IP packets in and out of the stack
+struct IpDevice {
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+    mtu: usize,
+}
+
+struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0; len];
+        let r = f(&mut packet);
+        self.0.push_back(packet);
+        r
+    }
+}
+
+impl phy::Device for IpDevice {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+struct Inner {
+    iface: Interface,
+    device: IpDevice,
+    sockets: SocketSet<'static>,
+    /// the local ports taken
+    ports: HashSet<u16>,
+    next_port: u16,
+    /// the streams dropped before they were closed, with when
+    closing: Vec<(SocketHandle, u16, std::time::Instant)>,
+    /// the tunnel is down
+    closed: bool,
+}
+
+impl Inner {
+    fn take_port(&mut self) -> io::Result<u16> {
+        for _ in EPHEMERAL_PORTS {
+            let port = self.next_port;
+            self.next_port = if port == *EPHEMERAL_PORTS.end() {
+                *EPHEMERAL_PORTS.start()
+            } else {
+                port + 1
+            };
+            if self.ports.insert(port) {
+                return Ok(port);
+            }
+        }
+        Err(new_io_error("no local port left in the tunnel"))
+    }
+
+    fn release(&mut self, handle: SocketHandle, port: u16) {
+        self.sockets.remove(handle);
+        self.ports.remove(&port);
+    }
+
+    /// frees the dropped streams done closing and resets the ones taking
+    /// too long, true if the interface has to run again to send the resets
+    fn sweep(&mut self) -> bool {
+        let mut reset = false;
+        let mut i = 0;
+        while i < self.closing.len() {
+            let (handle, port, since) = self.closing[i];
+            let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+            match socket.state() {
+                tcp::State::Closed | tcp::State::TimeWait => {
+                    self.release(handle, port);
+                    self.closing.swap_remove(i);
+                    continue;
+                }
+                _ if since.elapsed() > CLOSE_TIMEOUT => {
+                    socket.abort();
+                    reset = true;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        reset
+    }
+}
+
+pub struct Stack {
+    inner: Mutex<Inner>,
+    /// wakes the task running the interface once there's something to send
+    notify: Notify,
+}
+
+impl Stack {
+    /// a stack with the addresses of this end inside the tunnel
+    pub fn new(ips: &[IpAddr], mtu: usize) -> Arc<Self> {
+        let mut device = IpDevice {
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            mtu,
+        };
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = rand::random();
+        let mut iface = Interface::new(config, &mut device, Instant::now());
+        iface.update_ip_addrs(|addrs| {
+            for ip in ips {
+                let prefix = if ip.is_ipv4() { 32 } else { 128 };
+                addrs.push(IpCidr::new((*ip).into(), prefix)).ok();
+            }
+        });
+        // every destination is sent to the device, which routes by peer
+        for ip in ips {
+            match ip {
+                IpAddr::V4(ip) => iface.routes_mut().add_default_ipv4_route(*ip).ok(),
+                IpAddr::V6(ip) => iface.routes_mut().add_default_ipv6_route(*ip).ok(),
+            };
+        }
+
+        Arc::new(Self {
+            inner: Mutex::new(Inner {
+                iface,
+                device,
+                sockets: SocketSet::new(vec![]),
+                ports: HashSet::new(),
+                next_port: rand::random::<u16>() | *EPHEMERAL_PORTS.start(),
+                closing: vec![],
+                closed: false,
+            }),
+            notify: Notify::new(),
+        })
+    }
+
+    /// runs the interface, returning the packets for the peers and how long
+    /// until it has to run again if nothing happens meanwhile
+    pub fn poll(&self) -> (Vec<Vec<u8>>, Option<Duration>) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let Inner {
+            iface,
+            device,
+            sockets,
+            ..
+        } = &mut *inner;
+        iface.poll(now, device, sockets);
+        let packets = device.tx.drain(..).collect();
+
+        let delay = if inner.sweep() {
+            Some(Duration::ZERO)
+        } else {
+            let Inner { iface, sockets, .. } = &mut *inner;
+            iface.poll_delay(now, sockets).map(Into::into)
+        };
+        (packets, delay)
+    }
+
+    /// waits until a socket has something to send or a packet came
+    pub async fn notified(&self) {
+        self.notify.notified().await
+    }
+
+    /// an IP packet from a peer
+    pub fn push(&self, packet: Vec<u8>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.device.rx.len() < RX_QUEUE_SIZE {
+            inner.device.rx.push_back(packet);
+        }
+        drop(inner);
+        self.notify.notify_one();
+    }
+
+    /// fails every session, once the tunnel is down
+    pub fn close(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.closed = true;
+        for (_, socket) in inner.sockets.iter_mut() {
+            match socket {
+                Socket::Tcp(s) => s.abort(),
+                Socket::Udp(s) => s.close(),
+                #[allow(unreachable_patterns)]
+                _ => {}
+            }
+        }
+    }
+
+    fn check_open(inner: &Inner) -> io::Result<()> {
+        if inner.closed {
+            Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the wireguard tunnel is down",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn connect(self: &Arc<Self>, dst: SocketAddr) -> io::Result<TcpStream> {
+        let stream = {
+            let mut inner = self.inner.lock().unwrap();
+            Self::check_open(&inner)?;
+            let port = inner.take_port()?;
+
+            let mut socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+                tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            );
+            socket.set_nagle_enabled(false);
+            socket.set_timeout(Some(TCP_TIMEOUT.into()));
+            if let Err(e) = socket.connect(inner.iface.context(), dst, port) {
+                inner.ports.remove(&port);
+                return Err(new_io_error(
+                    format!("failed to connect to {} in the tunnel: {}", dst, e).as_str(),
+                ));
+            }
+
+            TcpStream {
+                stack: self.clone(),
+                handle: inner.sockets.add(socket),
+                port,
+            }
+        };
+        self.notify.notify_one();
+
+        poll_fn(|cx| stream.poll_established(cx)).await?;
+        Ok(stream)
+    }
+
+    pub fn bind(self: &Arc<Self>) -> io::Result<UdpSocket> {
+        let mut inner = self.inner.lock().unwrap();
+        Self::check_open(&inner)?;
+        let port = inner.take_port()?;
+
+        let mut socket = udp::Socket::new(
+            udp::PacketBuffer::new(
+                vec![udp::PacketMetadata::EMPTY; UDP_PACKETS],
+                vec![0; UDP_BUFFER_SIZE],
+            ),
+            udp::PacketBuffer::new(
+                vec![udp::PacketMetadata::EMPTY; UDP_PACKETS],
+                vec![0; UDP_BUFFER_SIZE],
+            ),
+        );
+        if let Err(e) = socket.bind(port) {
+            inner.ports.remove(&port);
+            return Err(new_io_error(
+                format!("failed to bind in the tunnel: {}", e).as_str(),
+            ));
+        }
+
+        Ok(UdpSocket {
+            stack: self.clone(),
+            handle: inner.sockets.add(socket),
+            port,
+        })
+    }
+}
+
+pub struct TcpStream {
+    stack: Arc<Stack>,
+    handle: SocketHandle,
+    port: u16,
+}
+
+impl Debug for TcpStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TcpStream")
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+impl TcpStream {
+    fn with_socket<R>(&self, f: impl FnOnce(&mut tcp::Socket<'static>) -> R) -> R {
+        let mut inner = self.stack.inner.lock().unwrap();
+        f(inner.sockets.get_mut::<tcp::Socket>(self.handle))
+    }
+
+    fn poll_established(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with_socket(|s| match s.state() {
+            _ if s.may_send() => Poll::Ready(Ok(())),
+            tcp::State::SynSent | tcp::State::SynReceived => {
+                s.register_send_waker(cx.waker());
+                Poll::Pending
+            }
+            _ => Poll::Ready(Err(io::ErrorKind::ConnectionRefused.into())),
+        })
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let read = self.with_socket(|s| {
+            if s.can_recv() {
+                let n = s
+                    .recv_slice(buf.initialize_unfilled())
+                    .map_err(|e| new_io_error(e.to_string().as_str()))?;
+                buf.advance(n);
+                Poll::Ready(Ok(true))
+            } else if s.may_recv() {
+                s.register_recv_waker(cx.waker());
+                Poll::Pending
+            } else {
+                // closed by the remote
+                Poll::Ready(Ok(false))
+            }
+        });
+        // the window opened up
+        if let Poll::Ready(Ok(true)) = read {
+            self.stack.notify.notify_one();
+        }
+        read.map_ok(|_| ())
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = self.with_socket(|s| {
+            if s.can_send() {
+                Poll::Ready(
+                    s.send_slice(buf)
+                        .map_err(|e| new_io_error(e.to_string().as_str())),
+                )
+            } else if s.may_send() {
+                s.register_send_waker(cx.waker());
+                Poll::Pending
+            } else {
+                Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+            }
+        });
+        if written.is_ready() {
+            self.stack.notify.notify_one();
+        }
+        written
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with_socket(|s| s.close());
+        self.stack.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut inner = self.stack.inner.lock().unwrap();
+        let socket = inner.sockets.get_mut::<tcp::Socket>(self.handle);
+        if socket.state() == tcp::State::Closed {
+            inner.release(self.handle, self.port);
+        } else {
+            socket.close();
+            inner
+                .closing
+                .push((self.handle, self.port, std::time::Instant::now()));
+        }
+        drop(inner);
+        self.stack.notify.notify_one();
+    }
+}
+
+pub struct UdpSocket {
+    stack: Arc<Stack>,
+    handle: SocketHandle,
+    port: u16,
+}
+
+impl UdpSocket {
+    fn with_socket<R>(&self, f: impl FnOnce(&mut udp::Socket<'static>, bool) -> R) -> R {
+        let mut inner = self.stack.inner.lock().unwrap();
+        let closed = inner.closed;
+        f(inner.sockets.get_mut::<udp::Socket>(self.handle), closed)
+    }
+
+    /// the port inside the tunnel
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn poll_recv_from(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Vec<u8>, SocketAddr)>> {
+        self.with_socket(|s, closed| match s.recv() {
+            Ok((data, meta)) => Poll::Ready(Ok((
+                data.to_vec(),
+                SocketAddr::new(meta.endpoint.addr.into(), meta.endpoint.port),
+            ))),
+            Err(_) if closed => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+            Err(_) => {
+                s.register_recv_waker(cx.waker());
+                Poll::Pending
+            }
+        })
+    }
+
+    pub fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        data: &[u8],
+        dst: SocketAddr,
+    ) -> Poll<io::Result<()>> {
+        let sent = self.with_socket(|s, closed| {
+            if closed {
+                return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+            }
+            match s.send_slice(data, dst) {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(udp::SendError::BufferFull) => {
+                    s.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+                Err(e) => Poll::Ready(Err(new_io_error(
+                    format!("failed to send to {} in the tunnel: {}", dst, e).as_str(),
+                ))),
+            }
+        });
+        if sent.is_ready() {
+            self.stack.notify.notify_one();
+        }
+        sent
+    }
+
+    pub async fn recv_from(&self) -> io::Result<(Vec<u8>, SocketAddr)> {
+        poll_fn(|cx| self.poll_recv_from(cx)).await
+    }
+
+    pub async fn send_to(&self, data: &[u8], dst: SocketAddr) -> io::Result<()> {
+        poll_fn(|cx| self.poll_send_to(cx, data, dst)).await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.stack
+            .inner
+            .lock()
+            .unwrap()
+            .release(self.handle, self.port);
+    }
+}
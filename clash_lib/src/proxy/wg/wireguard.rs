@@ -0,0 +1,208 @@
+//! The WireGuard tunnel to a peer, carrying the IP packets of the stack over
+//! UDP to its endpoint.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+};
+
+use boringtun::noise::{errors::WireGuardError, Tunn, TunnResult};
+use futures::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use tracing::{debug, trace};
+
+use crate::{
+    app::dispatcher::BoxedChainedDatagram, common::errors::new_io_error,
+    proxy::datagram::UdpPacket, session::SocksAddr,
+};
+
+use super::{stack::Stack, PeerOpts};
+
+/// the largest UDP payload, so the largest tunnel packet
+const MAX_PACKET: usize = 65535;
+
+/// where the tunnel packets to a peer go
+pub enum Endpoint {
+    Socket {
+        socket: tokio::net::UdpSocket,
+        addr: SocketAddr,
+    },
+    /// through the `dialer-proxy`
+    Detour {
+        sink: tokio::sync::Mutex<SplitSink<BoxedChainedDatagram, UdpPacket>>,
+        stream: tokio::sync::Mutex<SplitStream<BoxedChainedDatagram>>,
+        addr: SocksAddr,
+    },
+}
+
+impl Endpoint {
+    pub fn detour(datagram: BoxedChainedDatagram, addr: SocksAddr) -> Self {
+        let (sink, stream) = datagram.split();
+        Self::Detour {
+            sink: tokio::sync::Mutex::new(sink),
+            stream: tokio::sync::Mutex::new(stream),
+            addr,
+        }
+    }
+
+    async fn send(&self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Endpoint::Socket { socket, addr } => socket.send_to(data, addr).await.map(|_| ()),
+            Endpoint::Detour { sink, addr, .. } => {
+                sink.lock()
+                    .await
+                    .send(UdpPacket::new(
+                        data.to_vec(),
+                        SocksAddr::any_ipv4(),
+                        addr.clone(),
+                    ))
+                    .await
+            }
+        }
+    }
+
+    /// the next packet, from wherever it came as they are authenticated
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Endpoint::Socket { socket, .. } => socket.recv_from(buf).await.map(|(n, _)| n),
+            Endpoint::Detour { stream, .. } => {
+                let pkt = stream
+                    .lock()
+                    .await
+                    .next()
+                    .await
+                    .ok_or_else(|| new_io_error("the dialer-proxy closed"))?;
+                let n = pkt.data.len().min(buf.len());
+                buf[..n].copy_from_slice(&pkt.data[..n]);
+                Ok(n)
+            }
+        }
+    }
+}
+
+struct State {
+    tunn: Tunn,
+    buf: Vec<u8>,
+}
+
+pub struct Tunnel {
+    state: Mutex<State>,
+    endpoint: Endpoint,
+    pub peer: PeerOpts,
+}
+
+impl Tunnel {
+    pub fn new(tunn: Tunn, endpoint: Endpoint, peer: PeerOpts) -> Self {
+        Self {
+            state: Mutex::new(State {
+                tunn,
+                buf: vec![0; MAX_PACKET],
+            }),
+            endpoint,
+            peer,
+        }
+    }
+
+    /// runs `f` on the noise state, sending the packet it returns for the
+    /// network if any
+    async fn exchange(
+        &self,
+        f: impl FnOnce(&mut Tunn, &mut [u8]) -> Option<Vec<u8>>,
+    ) -> io::Result<()> {
+        let packet = {
+            let mut state = self.state.lock().unwrap();
+            let State { tunn, buf } = &mut *state;
+            f(tunn, buf)
+        };
+        match packet {
+            Some(packet) => self.endpoint.send(&packet).await,
+            None => Ok(()),
+        }
+    }
+
+    /// sends an IP packet of the stack, queued until the handshake is done
+    pub async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        self.exchange(|tunn, buf| match tunn.encapsulate(packet, buf) {
+            TunnResult::WriteToNetwork(p) => Some(p.to_vec()),
+            TunnResult::Err(e) => {
+                debug!("wireguard failed to encapsulate for {}: {:?}", self, e);
+                None
+            }
+            _ => None,
+        })
+        .await
+    }
+
+    /// runs the handshake and keepalive timers, called every 250ms or so
+    pub async fn update_timers(&self) -> io::Result<()> {
+        self.exchange(|tunn, buf| match tunn.update_timers(buf) {
+            TunnResult::WriteToNetwork(p) => Some(p.to_vec()),
+            // the session expired, encapsulating makes a new one
+            TunnResult::Err(WireGuardError::ConnectionExpired) => None,
+            TunnResult::Err(e) => {
+                debug!("wireguard timers of {} failed: {:?}", self, e);
+                None
+            }
+            _ => None,
+        })
+        .await
+    }
+
+    /// passes the packets of the peer to the stack until the endpoint fails
+    pub async fn receive(&self, stack: &Stack) -> io::Result<()> {
+        let mut datagram = vec![0; MAX_PACKET];
+        loop {
+            let n = self.endpoint.recv(&mut datagram).await?;
+
+            let mut replies = vec![];
+            {
+                let mut state = self.state.lock().unwrap();
+                let State { tunn, buf } = &mut *state;
+                let mut src = &datagram[..n];
+                loop {
+                    let ip = match tunn.decapsulate(None, src, buf) {
+                        // a handshake response or the queued packets, to be
+                        // followed by calls without a datagram until done
+                        TunnResult::WriteToNetwork(p) => {
+                            replies.push(p.to_vec());
+                            src = &[];
+                            continue;
+                        }
+                        TunnResult::WriteToTunnelV4(p, ip) => Some((p, IpAddr::from(ip))),
+                        TunnResult::WriteToTunnelV6(p, ip) => Some((p, IpAddr::from(ip))),
+                        TunnResult::Err(e) => {
+                            debug!("wireguard failed to decapsulate from {}: {:?}", self, e);
+                            None
+                        }
+                        TunnResult::Done => None,
+                    };
+                    match ip {
+                        // keepalives are empty
+                        Some((p, _)) if p.is_empty() => {}
+                        Some((p, src)) if self.peer.matches(src).is_some() => {
+                            stack.push(p.to_vec())
+                        }
+                        Some((_, src)) => {
+                            trace!("{} sent a packet from {} not in its allowed ips", self, src)
+                        }
+                        None => {}
+                    }
+                    break;
+                }
+            }
+
+            for reply in replies {
+                self.endpoint.send(&reply).await?;
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Tunnel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "peer {}:{}", self.peer.server, self.peer.port)
+    }
+}
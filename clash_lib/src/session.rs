@@ -1,10 +1,15 @@
 use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
 use std::{
     io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 
+use crate::common::dscp::DscpTable;
+use crate::common::mmdb::CountryCache;
+use crate::config::def::RunMode;
 use crate::proxy::utils::Interface;
 use bytes::{Buf, BufMut};
 use serde::Serialize;
@@ -12,7 +17,7 @@ use tokio::io::{AsyncRead, AsyncReadExt};
 
 use erased_serde::Serialize as ESerialize;
 
-#[derive(Debug, PartialEq, Eq, Serialize)]
+#[derive(Debug, PartialEq, Eq, Hash, Serialize)]
 pub enum SocksAddr {
     Ip(SocketAddr),
     Domain(String, u16),
@@ -342,18 +347,65 @@ impl TryFrom<SocksAddr> for SocketAddr {
     }
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug, Serialize)]
 pub enum Network {
     Tcp,
     Udp,
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug, Serialize)]
 pub enum Type {
     Http,
     HttpConnect,
+    Socks4,
     Socks5,
+    Redir,
+    Tproxy,
     Tun,
+    Shadowsocks,
+    Vmess,
+    Trojan,
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Type::Http => "HTTP",
+            Type::HttpConnect => "HTTPS",
+            Type::Socks4 => "SOCKS4",
+            Type::Socks5 => "SOCKS5",
+            Type::Redir => "REDIR",
+            Type::Tproxy => "TPROXY",
+            Type::Tun => "TUN",
+            Type::Shadowsocks => "SHADOWSOCKS",
+            Type::Vmess => "VMESS",
+            Type::Trojan => "TROJAN",
+        })
+    }
+}
+
+impl FromStr for Type {
+    type Err = io::Error;
+
+    /// the names `Display` gives, case insensitive
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "HTTP" => Ok(Type::Http),
+            "HTTPS" => Ok(Type::HttpConnect),
+            "SOCKS4" => Ok(Type::Socks4),
+            "SOCKS5" => Ok(Type::Socks5),
+            "REDIR" => Ok(Type::Redir),
+            "TPROXY" => Ok(Type::Tproxy),
+            "TUN" => Ok(Type::Tun),
+            "SHADOWSOCKS" => Ok(Type::Shadowsocks),
+            "VMESS" => Ok(Type::Vmess),
+            "TROJAN" => Ok(Type::Trojan),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown inbound type: {}", s),
+            )),
+        }
+    }
 }
 
 impl Display for Network {
@@ -365,6 +417,18 @@ impl Display for Network {
     }
 }
 
+/// The listener an inbound session arrived on
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Inbound {
+    /// `HTTP`, `SOCKS5`, `Mixed`, `Redir`, `TProxy` or `Tun` for the ones of
+    /// the top level options, the name of the entry for `listeners`
+    pub name: String,
+    /// The port it listens on, none for tun devices
+    pub port: Option<u16>,
+    /// The dispatch mode it forces, the global `mode` if unset
+    pub mode: Option<RunMode>,
+}
+
 #[derive(Serialize)]
 pub struct Session {
     /// The network type, representing either TCP or UDP.
@@ -379,9 +443,39 @@ pub struct Session {
     pub packet_mark: Option<u32>,
     /// The bind interface
     pub iface: Option<Interface>,
+    /// The name of the LAN device the connection comes from, if known
+    pub device: Option<String>,
+    /// The application protocol sniffed from the connection, e.g. `ws`
+    pub subprotocol: Option<String>,
+    /// The listener of the inbound connection, unnamed for the sessions
+    /// clash makes itself
+    pub inbound: Inbound,
+    /// The user the inbound authenticated, if it asks for one
+    pub user: Option<String>,
+    /// Whether clash makes it to probe the outbound, a health check or an
+    /// unlock test, which `max-connections` doesn't count
+    pub probe: bool,
+    /// The country of the destination IP, once a GEOIP rule looked it up
+    #[serde(skip)]
+    pub country: CountryCache,
+    /// The user owning the source socket, once a UID rule looked it up
+    #[serde(skip)]
+    pub uid: OnceLock<Option<u32>>,
+    /// The process owning the source socket, once a script needing it
+    /// looked it up
+    #[serde(skip)]
+    pub process: OnceLock<Option<String>>,
+    /// The DSCP marks tun reads, by source, see `dscp`
+    #[serde(skip)]
+    pub dscp_marks: Option<Arc<DscpTable>>,
 }
 
 impl Session {
+    /// the DSCP of the packets from the source, if it came through tun
+    pub fn dscp(&self) -> Option<u8> {
+        Some(self.dscp_marks.as_ref()?.get(self.source))
+    }
+
     pub fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send + Sync>> {
         let mut rv = HashMap::new();
         rv.insert("network".to_string(), Box::new(self.network) as _);
@@ -397,6 +491,27 @@ impl Session {
             Box::new(self.destination.port()) as _,
         );
         rv.insert("host".to_string(), Box::new(self.destination.host()) as _);
+        if let Some(device) = &self.device {
+            rv.insert("device".to_string(), Box::new(device.clone()) as _);
+        }
+        if let Some(subprotocol) = &self.subprotocol {
+            rv.insert(
+                "subprotocol".to_string(),
+                Box::new(subprotocol.clone()) as _,
+            );
+        }
+        if !self.inbound.name.is_empty() {
+            rv.insert(
+                "inboundName".to_string(),
+                Box::new(self.inbound.name.clone()) as _,
+            );
+        }
+        if let Some(port) = self.inbound.port {
+            rv.insert("inboundPort".to_string(), Box::new(port) as _);
+        }
+        if let Some(user) = &self.user {
+            rv.insert("inboundUser".to_string(), Box::new(user.clone()) as _);
+        }
 
         return rv;
     }
@@ -411,6 +526,15 @@ impl Default for Session {
             destination: SocksAddr::any_ipv4(),
             packet_mark: None,
             iface: None,
+            device: None,
+            subprotocol: None,
+            inbound: Inbound::default(),
+            user: None,
+            probe: false,
+            country: CountryCache::default(),
+            uid: OnceLock::new(),
+            process: OnceLock::new(),
+            dscp_marks: None,
         }
     }
 }
@@ -435,6 +559,10 @@ impl Debug for Session {
             .field("destination", &self.destination)
             .field("packet_mark", &self.packet_mark)
             .field("iface", &self.iface)
+            .field("device", &self.device)
+            .field("subprotocol", &self.subprotocol)
+            .field("inbound", &self.inbound)
+            .field("user", &self.user)
             .finish()
     }
 }
@@ -448,6 +576,15 @@ impl Clone for Session {
             destination: self.destination.clone(),
             packet_mark: self.packet_mark,
             iface: self.iface.as_ref().cloned(),
+            device: self.device.clone(),
+            subprotocol: self.subprotocol.clone(),
+            inbound: self.inbound.clone(),
+            user: self.user.clone(),
+            probe: self.probe,
+            country: self.country.clone(),
+            uid: self.uid.clone(),
+            process: self.process.clone(),
+            dscp_marks: self.dscp_marks.clone(),
         }
     }
 }
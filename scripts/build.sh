@@ -11,4 +11,9 @@ for TARGET in $1; do
   cargo build -p clash --target $TARGET --release
   ls -l ./target/$TARGET/release/
   mv ./target/$TARGET/release/clash ./target/artifacts/clash-$TARGET
+  # the ed25519 signature `POST /upgrade` checks before installing it
+  if [ -n "$SIGNING_KEY" ]; then
+    openssl pkeyutl -sign -rawin -inkey "$SIGNING_KEY" \
+      -in ./target/artifacts/clash-$TARGET -out ./target/artifacts/clash-$TARGET.sig
+  fi
 done